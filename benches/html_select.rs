@@ -0,0 +1,47 @@
+//! Benchmarks HTML selection on a release-archive sized page.
+//! Run with `cargo bench --bench html_select`.
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/utils/html_index.rs"]
+mod html_index;
+
+use criterion::{Criterion, black_box, criterion_group, criterion_main};
+use html_index::IndexedHtml;
+use scraper::{Html, Selector};
+
+fn archive_page(anchors: usize) -> String {
+    let mut page = String::from("<html><body><table>");
+    for i in 0..anchors {
+        page.push_str(&format!(
+            "<tr><td><a href=\"tool-1.{i}.0-linux-x64.tar.gz\">tool-1.{i}.0</a></td><td>2024-01-01</td></tr>"
+        ));
+    }
+    page.push_str("</table></body></html>");
+    page
+}
+
+fn bench_select(c: &mut Criterion) {
+    let page = archive_page(10_000);
+    let html = Html::parse_document(&page);
+    let mut indexed = IndexedHtml::parse(&page);
+    let root = indexed.root_id();
+
+    c.bench_function("scraper select a", |b| {
+        b.iter(|| {
+            let selector = Selector::parse("a").unwrap();
+            black_box(html.select(&selector).count())
+        })
+    });
+    c.bench_function("indexed select a", |b| {
+        b.iter(|| black_box(indexed.select(root, "a", None).unwrap().len()))
+    });
+    c.bench_function("indexed select a[href]", |b| {
+        b.iter(|| black_box(indexed.select(root, "a[href]", None).unwrap().len()))
+    });
+    c.bench_function("indexed select_first_n a[href] 10", |b| {
+        b.iter(|| black_box(indexed.select(root, "a[href]", Some(10)).unwrap().len()))
+    });
+}
+
+criterion_group!(benches, bench_select);
+criterion_main!(benches);
@@ -2,7 +2,7 @@ use std::process::Command;
 
 fn main() {
     let version = Command::new("git")
-        .args(&["describe", "--always", "--dirty", "--tags"])
+        .args(["describe", "--always", "--dirty", "--tags"])
         .output()
         .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
         .unwrap_or_else(|_| "unknown".to_string());
@@ -1,4 +1,11 @@
+use crate::commands::cave::report::ReportFormat;
+use crate::commands::cave::service::ServiceAction;
+use crate::commands::package::graph::GraphFormat;
+use crate::commands::package::info::InfoFormat;
+use crate::commands::cave::shell_hook::Shell;
+use crate::utils::theme::ColorChoice;
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(name = "pi")]
@@ -29,6 +36,64 @@ pub struct Cli {
     #[arg(long, global = true)]
     pub no_sync: bool,
 
+    /// Never use the network: only cached metadata and downloads
+    #[arg(long, global = true)]
+    pub offline: bool,
+
+    /// Worker threads for parallel work (default: settings `jobs`, else one per CPU)
+    #[arg(short, long, global = true)]
+    pub jobs: Option<usize>,
+
+    /// Resolve versions for another platform (os/arch, e.g. linux/aarch64)
+    #[arg(long, global = true)]
+    pub target: Option<String>,
+
+    /// Row order of tables: columns separated by commas, `-` for descending (e.g. `-date,package`)
+    #[arg(long, global = true)]
+    pub sort: Option<String>,
+
+    /// Keep the temporary home of build steps and print its path
+    #[arg(long, global = true)]
+    pub keep_build_home: bool,
+
+    /// Run the build steps of packages marked `requires_host_build` outside the sandbox
+    #[arg(long, global = true)]
+    pub allow_host_build: bool,
+
+    /// Fail builds with fetches lacking a checksum, unpinned packages or networked Run steps
+    #[arg(long, global = true)]
+    pub strict: bool,
+
+    /// Cave to act on: workspace, cave file or name (default: PI_CAVE_PATH, else the nearest pi.cave.json)
+    #[arg(long, global = true)]
+    pub cave: Option<String>,
+
+    /// When to use colors (auto honors NO_COLOR and CLICOLOR)
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Compact tables for narrow terminals (automatic below 80 columns)
+    #[arg(long, global = true)]
+    pub compact: bool,
+
+    /// Emit newline-delimited JSON progress events on stdout
+    #[arg(long, global = true)]
+    pub progress_json: bool,
+
+    /// Print the time spent per phase (repo loading, recipe evaluation,
+    /// network, extraction, sandbox execution) when the command finishes
+    #[arg(long, global = true)]
+    pub timings: bool,
+
+    /// Machine-readable output: tables as JSON rows and progress events on
+    /// stdout, log messages as JSON lines on stderr
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    /// Write a chrome trace of this command to the state directory
+    #[arg(long, global = true)]
+    pub trace: bool,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -37,12 +102,12 @@ pub struct Cli {
 pub enum Commands {
     /// Print version information
     Version,
-    /// {add, sync, list}       Repository management
+    /// {add, sync, list, restore} Repository management
     Repo {
         #[command(subcommand)]
         command: RepoCommands,
     },
-    /// {sync, list, resolve}   Package management
+    /// {sync, list, install, resolve, why} Package management
     Package {
         #[command(subcommand)]
         command: PackageCommands,
@@ -62,6 +127,50 @@ pub enum Commands {
         #[command(subcommand)]
         command: DevelCommands,
     },
+    /// {get, set}              Global settings (settings.toml)
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// Show which package provides a binary in the current cave
+    Which {
+        /// Binary name, e.g. cargo
+        binary: String,
+        /// List every cave whose last build exported it
+        #[arg(long)]
+        all: bool,
+    },
+    /// Keep resolution state warm for `package resolve` and `cave resolve`
+    Daemon {
+        /// Stop the running daemon
+        #[arg(long, conflicts_with = "status")]
+        stop: bool,
+        /// Show whether a daemon runs
+        #[arg(long)]
+        status: bool,
+    },
+    /// Serve resolution, packages and cave status over a local HTTP/JSON API
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:7878")]
+        listen: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+    /// Print a setting, or all settings when no key is given
+    Get {
+        /// Dotted key, e.g. `jobs` or `quota.downloads`
+        key: Option<String>,
+    },
+    /// Set a setting in settings.toml
+    Set {
+        /// Dotted key, e.g. `jobs` or `quota.downloads`
+        key: String,
+        /// New value
+        value: String,
+    },
 }
 
 #[derive(Subcommand)]
@@ -75,6 +184,9 @@ pub enum CaveCommands {
         /// Package queries (first one can be :variant)
         #[arg(required = true)]
         args: Vec<String>,
+        /// Replace selectors of the same package instead of asking
+        #[arg(long)]
+        replace: bool,
     },
     /// Remove packages from the cave or a variant
     Rem {
@@ -82,6 +194,13 @@ pub enum CaveCommands {
         #[arg(required = true)]
         args: Vec<String>,
     },
+    /// Check the cave file, packages, options, links and pi.cave.sums
+    Doctor,
+    /// Compare the declared packages with pi.cave.lock and what the last build put in the pilocal
+    Status {
+        /// Optional variant name (starts with :)
+        variant: Option<String>,
+    },
     /// Resolve all packages in the cave or a variant
     Resolve {
         /// Optional variant name (starts with :)
@@ -91,34 +210,148 @@ pub enum CaveCommands {
     Build {
         /// Optional variant name (starts with :)
         variant: Option<String>,
+        /// Rebuild whenever pi.cave.json or a resolved package's recipe changes
+        #[arg(long)]
+        watch: bool,
+        /// Only resolve, and list the downloads a build would make with their sizes
+        #[arg(long, conflicts_with = "watch")]
+        dry_run: bool,
+        /// Fail unless the packages resolve exactly as pi.cave.lock records
+        #[arg(long, conflicts_with_all = ["watch", "dry_run"])]
+        locked: bool,
+        /// Also write the build summary to --report-file, e.g. for CI artifacts
+        #[arg(long, value_enum)]
+        report: Option<ReportFormat>,
+        /// Where --report writes the summary
+        #[arg(long, default_value = "pi-build-report.json", requires = "report")]
+        report_file: PathBuf,
     },
     /// Run a command inside the cave sandbox
     Run {
         /// Optional variant name (starts with :)
         variant: Option<String>,
+        /// Only expose the current directory (plus a read-only pilocal) to the command
+        #[arg(long)]
+        bind_cwd_only: bool,
         /// The command to run
         #[arg(last = true)]
         command: Vec<String>,
     },
+    /// Open an interactive bash in the cave sandbox, keeping its history
+    Shell {
+        /// Variant to enter, e.g. dev or :dev
+        #[arg(long)]
+        variant: Option<String>,
+    },
+    /// Print shell statements applying the cave's built environment (for `eval`)
+    Env {
+        /// Optional variant name (starts with :)
+        variant: Option<String>,
+        /// Shell syntax to print
+        #[arg(long, value_enum, default_value = "bash")]
+        shell: Shell,
+        /// Undo the last activation instead
+        #[arg(long)]
+        deactivate: bool,
+        /// Run by the shell hook: only apply caves allowed with `pi cave allow`
+        #[arg(long, hide = true)]
+        hook: bool,
+    },
+    /// Allow the shell hook to apply this cave's environment, until pi.cave.json changes
+    Allow {
+        /// Withdraw the permission instead
+        #[arg(long)]
+        revoke: bool,
+    },
+    /// Print a shell hook that activates caves on entering their directory
+    ShellHook {
+        /// Shell to integrate with
+        #[arg(value_enum)]
+        shell: Shell,
+        /// Apply the cave environment automatically instead of printing a hint
+        #[arg(long)]
+        auto: bool,
+    },
+    /// Write .devcontainer/devcontainer.json reusing the cave's pi-managed toolchains
+    Devcontainer {
+        /// Optional variant name (starts with :)
+        variant: Option<String>,
+        /// Base image of the container
+        #[arg(long, default_value = crate::commands::cave::devcontainer::DEFAULT_IMAGE)]
+        image: String,
+    },
+    /// Write .pilocal/shims: scripts running each cave binary through `pi cave run`, for IDEs
+    Shims {
+        /// Optional variant name (starts with :)
+        variant: Option<String>,
+    },
+    /// Start, stop or list the daemons cave packages export (export_service)
+    Service {
+        #[arg(value_enum)]
+        action: ServiceAction,
+        /// Optional variant (starts with :), then service names; all services when none
+        args: Vec<String>,
+    },
+    /// Print the cave as a shareable bundle (settings, options and pins)
+    Export,
+    /// Recreate a cave in the current directory from an exported bundle
+    Import {
+        /// Bundle file written by `pi cave export` ("-" for stdin)
+        file: String,
+        /// Keep the original package queries instead of the exported pins
+        #[arg(long)]
+        no_pins: bool,
+        /// Also download every artifact the cave's packages fetch, so it builds without network
+        #[arg(long)]
+        fetch: bool,
+    },
 }
 
 #[derive(Subcommand)]
 pub enum RepoCommands {
     /// Add a new repository
     Add {
-        /// Path to the repository
+        /// Path to the repository, or a git or archive (.tar.gz, .tgz, .tar.xz, .zip) URL
         path: String,
+        /// sha256 the archive must match, on every download
+        #[arg(long)]
+        checksum: Option<String>,
+        /// Detached signature of the archive (default: the URL plus .asc or .minisig)
+        #[arg(long, requires = "public_key")]
+        signature_url: Option<String>,
+        /// File with the gpg or minisign public key the archive must be signed with
+        #[arg(long)]
+        public_key: Option<PathBuf>,
+        /// Only let the repo's recipes download from this host (repeatable, `*.domain` for subdomains)
+        #[arg(long = "allow-host", value_name = "HOST")]
+        allow_hosts: Vec<String>,
+    },
+    /// Set which hosts a repository's recipes may download from while they are evaluated
+    Trust {
+        /// Name of the repository
+        name: String,
+        /// Host the recipes may download from (repeatable, `*.domain` for subdomains); none blocks all downloads
+        #[arg(long = "allow-host", value_name = "HOST", conflicts_with = "any")]
+        allow_hosts: Vec<String>,
+        /// Trust the repository with every host again
+        #[arg(long)]
+        any: bool,
     },
     /// Sync repositories
     Sync {
         /// Optional name of the repository to sync
         name: Option<String>,
+        /// Give up on a recipe whose evaluation takes longer (e.g. `30s`, `2m`); `--jobs` bounds how many run at once
+        #[arg(long)]
+        recipe_timeout: Option<String>,
     },
     /// List repositories and their packages
     List {
         /// Optional name of the repository to list
         name: Option<String>,
     },
+    /// Replace repositories.json with its backup from before the last change
+    Restore,
 }
 
 #[derive(Subcommand)]
@@ -136,10 +369,41 @@ pub enum PackageCommands {
         #[arg(short, long)]
         all: bool,
     },
+    /// List the files each build step of a built package produced
+    Artifacts {
+        /// Package selector
+        selector: String,
+    },
+    /// Show the output the Run steps of a package's last build logged
+    Logs {
+        /// Package selector
+        selector: String,
+        /// Only the log of this step (its position in the pipeline)
+        #[arg(long)]
+        step: Option<usize>,
+    },
+    /// Find packages and managers by name across all repositories
+    Search {
+        /// Part of a name, or its letters in order (e.g. `tsc` for typescript)
+        query: String,
+    },
     /// Display detailed information for matching packages
     Info {
         /// Package selector
         selector: String,
+        /// Output format
+        #[arg(long, value_enum, default_value = "table")]
+        format: InfoFormat,
+    },
+    /// Build a package outside any cave and link its exports into the global prefix
+    Install {
+        /// Package selector
+        selector: String,
+    },
+    /// Remove the links `package install` made for a package
+    Uninstall {
+        /// Package name, or the selector it was installed with
+        name: String,
     },
     /// Resolve package selectors to specific versions
     Resolve {
@@ -147,12 +411,57 @@ pub enum PackageCommands {
         #[arg(required = true)]
         queries: Vec<String>,
     },
+    /// Show why a package is part of the current cave's build
+    Why {
+        /// Package selector
+        selector: String,
+        /// Optional variant name (starts with :)
+        variant: Option<String>,
+    },
+    /// Print the build-dependency graph of a package as DOT or Mermaid
+    Graph {
+        /// Package selector
+        selector: String,
+        /// Output format
+        #[arg(long, value_enum, default_value = "dot")]
+        format: GraphFormat,
+    },
+    /// Show the release notes of the versions an upgrade to the selector brings
+    Changelog {
+        /// Package selector of the target version
+        selector: String,
+        /// Current version (default: what the current cave builds)
+        #[arg(long)]
+        from: Option<String>,
+        /// Cave variant whose version is current (starts with :)
+        #[arg(long)]
+        variant: Option<String>,
+    },
 }
 
 #[derive(Subcommand)]
 pub enum DiskCommands {
     /// Show disk usage of pi directories
-    Info,
+    Info {
+        /// List downloads that `disk gc` would evict by age (quota.downloads_max_age)
+        #[arg(long)]
+        stale: bool,
+    },
+    /// Enforce cache quotas by evicting least recently used entries
+    Gc {
+        /// Only print what would be evicted
+        #[arg(long)]
+        dry_run: bool,
+        /// Instead remove extracted packages and downloads no cave references, and report the space reclaimed
+        #[arg(long)]
+        unreferenced: bool,
+    },
+    /// Check build cache entries, extracted packages and downloads for consistency
+    Verify {
+        /// Drop stale cache entries and remove orphaned or corrupt files, so builds recompute them
+        #[arg(long)]
+        repair: bool,
+    },
     /// Clean the cache and state directories (requires flags)
     Clean {
         /// Delete package list cache
@@ -173,6 +482,9 @@ pub enum DiskCommands {
         /// Delete state directory (CAUTION: deletes all cave homes)
         #[arg(long)]
         state: bool,
+        /// Forget the builds of one package (`name` or `name=version`) and delete their outputs
+        #[arg(long, value_name = "SELECTOR")]
+        builds: Option<String>,
         /// Confirmation flag for destructive operations (--config, --state)
         #[arg(long)]
         confirm: bool,
@@ -181,11 +493,61 @@ pub enum DiskCommands {
 
 #[derive(Subcommand)]
 pub enum DevelCommands {
+    /// Run the `test_*` functions of recipe files
+    Check {
+        /// Recipe files or directories (default: current directory)
+        paths: Vec<String>,
+    },
+    /// Lint the recipe files of a repository without evaluating them
+    Lint {
+        /// Repository directory or recipe file (default: current directory)
+        path: Option<String>,
+    },
+    /// Show which names and versions a selector matches, and why
+    Match {
+        /// Selector, e.g. npm:typescript=5.*
+        selector: String,
+        /// Candidates as [repo/][prefix:]package[=version[@release_type]]
+        #[arg(required = true)]
+        candidates: Vec<String>,
+    },
+    /// Rerun one Run step of a cave package in its build sandbox
+    RerunStep {
+        /// Package name
+        pkg: String,
+        /// Package version
+        version: String,
+        /// Step index, as printed when the step failed
+        index: usize,
+        /// Cave variant that built the package (starts with :)
+        #[arg(long)]
+        variant: Option<String>,
+        /// Open an interactive shell in the step's directory instead
+        #[arg(long)]
+        shell: bool,
+    },
+    /// Show or clear the values recipes saved with `state_set`
+    State {
+        /// Scope prefix, e.g. `main/` or `main/npm.star` (default: all)
+        scope: Option<String>,
+        /// Remove the matching entries
+        #[arg(long)]
+        clear: bool,
+    },
     /// Test a package
     Test {
         /// The filename to test
         filename: String,
         /// Optional package name
         pkg: Option<String>,
+        /// Version to exercise (prefix match), the newest by default
+        #[arg(long)]
+        version: Option<String>,
+        /// Build flag as key=value (repeatable)
+        #[arg(short = 'o', long = "options", value_name = "KEY=VALUE")]
+        options: Vec<String>,
+        /// Keep the scratch directory used for Fetch/Extract
+        #[arg(long)]
+        keep: bool,
     },
 }
@@ -2,10 +2,20 @@ use crate::models::config::Config;
 use crate::models::cave::{Cave, CaveSettings};
 use crate::models::selector::PackageSelector;
 use crate::models::repository::Repositories;
-use crate::commands::package::resolve;
+use crate::commands::package::resolve::{self, TargetVersion};
+use crate::models::version_entry::VersionEntry;
 use std::env;
+use std::io::{BufRead, IsTerminal, Write};
 
-pub fn run(config: &Config, args: Vec<String>) {
+/// What to do when a query names a package the cave already selects.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Conflict {
+    Replace,
+    Keep,
+    Abort,
+}
+
+pub fn run(config: &Config, args: Vec<String>, replace: bool) {
     if args.is_empty() {
         return;
     }
@@ -22,7 +32,7 @@ pub fn run(config: &Config, args: Vec<String>) {
     }
 
     let current_dir = env::current_dir().expect("Failed to get current directory");
-    let (path, mut cave) = match Cave::find_in_ancestry(&current_dir) {
+    let (path, mut cave) = match Cave::find(config, &current_dir) {
         Some(res) => res,
         None => {
             log::error!("no cave found");
@@ -43,11 +53,19 @@ pub fn run(config: &Config, args: Vec<String>) {
         let selector = PackageSelector::parse(&query).unwrap();
         
         log::info!("[{}] resolving", query);
-        if let Some((full_name, version, repo_name)) = resolve::resolve_query(config, repo_config, &selector) {
-            log::info!("[{}/{}] resolved: {} ({})", repo_name, full_name, version.version.to_string(), version.release_type.to_string());
+        let resolved = resolve::resolve_query(config, repo_config, &selector);
+        if let Some((full_name, version, repo_name)) = &resolved {
+            log::info!("[{}/{}] resolved: {} ({})", repo_name, full_name, version.version, version.release_type);
         } else {
             log::warn!("[{}] could not resolve, adding anyway", query);
         }
+        let query = match resolved.and_then(|(_, version, _)| stream_selector(&selector, &version)) {
+            Some(pinned) => {
+                log::info!("[{}] recorded as {} to stay on its release train", query, pinned);
+                pinned
+            }
+            None => query,
+        };
         
         let settings = if let Some(ref v_name) = variant {
             let v_name = v_name.strip_prefix(':').unwrap_or(v_name);
@@ -56,12 +74,107 @@ pub fn run(config: &Config, args: Vec<String>) {
             &mut cave.settings
         };
 
-        if !settings.packages.contains(&query) {
-            settings.packages.push(query.clone());
+        let existing = overlapping(&settings.packages, &selector, &query);
+        let conflict = if existing.is_empty() {
+            Conflict::Keep
+        } else if replace {
+            Conflict::Replace
+        } else if std::io::stdin().is_terminal() {
+            ask(&query, &existing)
+        } else {
+            log::warn!("[{}] the cave already has {}, keeping both (use --replace)", query, existing.join(", "));
+            Conflict::Keep
+        };
+        match conflict {
+            Conflict::Abort => {
+                log::info!("[{}] aborted, nothing changed", cave.name);
+                return;
+            }
+            Conflict::Replace => {
+                settings.packages.retain(|p| !existing.contains(p));
+                log::info!("[{}] replaced {} with {}", cave.name, existing.join(", "), query);
+            }
+            Conflict::Keep => {}
         }
+        settings.packages.push(query.clone());
+        normalize(&mut settings.packages);
         
         log::info!("[{}] added {} to {}", cave.name, query, variant.as_deref().unwrap_or("default"));
     }
 
     cave.save(&path).expect("Failed to save cave file");
 }
+
+/// `pkg@<stream>=<release type>` for a selector asking for a release type
+/// (`node=lts`) that resolved to a version of a named stream, so rebuilds
+/// stay on that stream instead of moving to the next one, and still only
+/// take that release type.
+fn stream_selector(selector: &PackageSelector, version: &VersionEntry) -> Option<String> {
+    let release_type = matches!(TargetVersion::parse(selector.version.as_deref()?), TargetVersion::Stream(_));
+    if !release_type || selector.stream.is_some() || version.stream.is_empty() {
+        return None;
+    }
+    let pinned = PackageSelector { stream: Some(version.stream.to_lowercase()), ..selector.clone() };
+    Some(pinned.to_string())
+}
+
+/// Entries of `packages` selecting the same package as `selector` (same
+/// manager prefix and name, any repository or version), other than `query` itself.
+fn overlapping(packages: &[String], selector: &PackageSelector, query: &str) -> Vec<String> {
+    packages.iter()
+        .filter(|p| p.as_str() != query)
+        .filter(|p| PackageSelector::parse(p).is_some_and(|s| s.prefix == selector.prefix && s.package == selector.package))
+        .cloned()
+        .collect()
+}
+
+fn ask(query: &str, existing: &[String]) -> Conflict {
+    eprint!("{} overlaps {}: [r]eplace, [k]eep both or [a]bort? ", query, existing.join(", "));
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer).is_err() {
+        return Conflict::Abort;
+    }
+    match answer.trim().to_lowercase().as_str() {
+        "r" | "replace" => Conflict::Replace,
+        "k" | "keep" => Conflict::Keep,
+        _ => Conflict::Abort,
+    }
+}
+
+/// Trims the queries and drops repeated ones, keeping the first.
+fn normalize(packages: &mut Vec<String>) {
+    let mut seen = std::collections::HashSet::new();
+    packages.retain_mut(|p| {
+        *p = p.trim().to_string();
+        !p.is_empty() && seen.insert(p.clone())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overlapping_selectors() {
+        let packages: Vec<String> = ["python=3.12", "main/python", "pip:python", "go=1.22", "python=3.11"]
+            .iter().map(|s| s.to_string()).collect();
+        let selector = PackageSelector::parse("python=3.11").unwrap();
+        assert_eq!(overlapping(&packages, &selector, "python=3.11"), vec!["python=3.12", "main/python"]);
+
+        let mut packages = vec!["go".to_string(), " go".to_string(), "node".to_string(), "go".to_string()];
+        normalize(&mut packages);
+        assert_eq!(packages, vec!["go", "node"]);
+    }
+
+    #[test]
+    fn test_stream_selector() {
+        let iron = VersionEntry { stream: "Iron".to_string(), ..Default::default() };
+        let pinned = |query: &str| stream_selector(&PackageSelector::parse(query).unwrap(), &iron);
+        assert_eq!(pinned("main/node=lts").as_deref(), Some("main/node@iron=lts"));
+        assert_eq!(pinned("node=20.*"), None);
+        assert_eq!(pinned("node"), None);
+        assert_eq!(pinned("node@Iron=lts"), None);
+        assert_eq!(stream_selector(&PackageSelector::parse("go=stable").unwrap(), &VersionEntry::default()), None);
+    }
+}
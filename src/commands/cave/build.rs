@@ -1,12 +1,32 @@
 use crate::models::config::Config;
-use crate::models::cave::Cave;
+use crate::models::cave::{Cave, CaveSettings};
+use crate::models::cave_registry::CaveRegistry;
+use crate::models::cave_sums::CaveSums;
+use crate::models::lockfile::{lock_differences, locked_packages, CaveLock, LockedPackage};
+use crate::models::context::CaveInfo;
 use std::env;
+use crate::commands::cave::report::{BuildReport, ReportFormat};
+use crate::commands::cave::shims::write_shims;
+use crate::commands::cave::status::BuiltState;
+use crate::commands::package::build::{download_plan, resolve_graph, BuildRequest, BuiltPackage, DependencyGraph, PlannedDownload};
+use crate::logging::init::warning_count;
+use crate::utils::fs::available_space;
+use crate::utils::size::format_size;
+use crate::utils::theme::Theme;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 use anyhow::{Context, Result};
 
-pub fn run(config: &Config, variant: Option<String>) {
+/// `--report` of `pi cave build`.
+pub struct ReportOptions {
+    pub format: Option<ReportFormat>,
+    pub file: PathBuf,
+}
+
+pub fn run(config: &Config, variant: Option<String>, watch: bool, dry_run: bool, locked: bool, report: ReportOptions) {
     let current_dir = env::current_dir().expect("Failed to get current directory");
-    let (path, cave) = match Cave::find_in_ancestry(&current_dir) {
+    let (path, cave) = match Cave::find(config, &current_dir) {
         Some(res) => res,
         None => {
             log::error!("no cave found");
@@ -16,54 +36,325 @@ pub fn run(config: &Config, variant: Option<String>) {
 
     let variant_str = variant.as_deref().and_then(|v| if v.starts_with(':') { Some(v) } else { None });
 
-    if let Err(e) = execute_build(config, &cave, variant_str) {
-        log::error!("build failed: {}", e);
-        std::process::exit(1);
+    if watch {
+        if let Err(e) = crate::commands::cave::watch::watch(config, &path, variant_str) {
+            log::error!("watch failed: {:#}", e);
+            std::process::exit(1);
+        }
+        return;
     }
+
+    if dry_run {
+        if let Err(e) = print_plan(config, &cave, variant_str) {
+            log::error!("build failed: {:#}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let start = Instant::now();
+    let warnings = warning_count();
+    let lock = if locked { LockPolicy::Enforce } else { LockPolicy::Update };
+    let packages = match build_cave(config, &cave, variant_str, lock) {
+        Ok(build) => build.packages,
+        Err(e) => {
+            log::error!("build failed: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let summary = BuildReport::new(
+        &cave.name,
+        variant_str,
+        packages.as_deref(),
+        start.elapsed().as_millis(),
+        warning_count() - warnings,
+    );
+    summary.print();
+    if let Some(format) = report.format {
+        if let Err(e) = summary.write(format, &report.file) {
+            log::error!("build report failed: {:#}", e);
+            std::process::exit(1);
+        }
+        log::info!("[{}] wrote {}", cave.name, report.file.display());
+    }
+}
+
+/// Result of `build_cave`.
+pub struct CaveBuild {
+    pub env: HashMap<String, String>,
+    /// The built packages; None when the cached environment was still valid.
+    pub packages: Option<Vec<BuiltPackage>>,
 }
 
+/// What a cave build does with `pi.cave.lock`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LockPolicy {
+    /// `pi cave build`: write what resolving gave.
+    Update,
+    /// `pi cave build --locked`: fail unless resolving gives exactly what the lock has.
+    Enforce,
+    /// Commands building on the way (run, shell, service, watch): leave the
+    /// lock alone, warn when it's stale.
+    Report,
+}
+
+/// Builds the cave for a command that runs in it; `pi.cave.lock` is only
+/// checked, `pi cave build` is what updates it.
 pub fn execute_build(config: &Config, cave: &Cave, variant: Option<&str>) -> Result<HashMap<String, String>> {
+    build_cave(config, cave, variant, LockPolicy::Report).map(|build| build.env)
+}
+
+#[tracing::instrument(skip_all, fields(cave = %cave.name))]
+fn build_cave(config: &Config, cave: &Cave, variant: Option<&str>, lock: LockPolicy) -> Result<CaveBuild> {
+    let locked = lock == LockPolicy::Enforce;
     let settings = cave.get_effective_settings(variant).context("Failed to get effective cave settings")?;
+    let strict_config;
+    let config = if settings.strict && !config.strict {
+        strict_config = Config { strict: true, ..config.clone() };
+        &strict_config
+    } else {
+        config
+    };
     
     let pilocal_dir = config.pilocal_path(&cave.name, variant);
+    config.touch(&pilocal_dir);
+    if let Err(e) = CaveRegistry::record(&config.state_dir, &cave.name, &cave.workspace) {
+        log::debug!("[{}] failed to record cave: {:#}", cave.name, e);
+    }
+    if let Err(e) = export_pi_binary(&pilocal_dir) {
+        log::warn!("[{}] failed to export pi binary: {:#}", cave.name, e);
+    }
     let env_cache_file = pilocal_dir.join("env.json");
 
-    if !config.force && !config.rebuild && env_cache_file.exists() {
+    // Strict and locked builds always resolve, so the policy and lock are checked every time.
+    if !config.force && !config.rebuild && !config.strict && !locked && env_cache_file.exists() {
         let mut cache_valid = true;
         
         // Invalidate if cave configuration changed
-        if let Ok(cave_meta) = std::fs::metadata(cave.workspace.join(Cave::FILENAME)) {
-            if let Ok(cache_meta) = std::fs::metadata(&env_cache_file) {
-                if cave_meta.modified().unwrap() > cache_meta.modified().unwrap() {
-                    cache_valid = false;
-                }
-            }
+        if let Ok(cave_meta) = std::fs::metadata(cave.workspace.join(Cave::FILENAME))
+            && let Ok(cache_meta) = std::fs::metadata(&env_cache_file)
+            && cave_meta.modified().unwrap() > cache_meta.modified().unwrap()
+        {
+            cache_valid = false;
+        }
+
+        // Invalidate if exported links point into evicted packages (see `pi disk gc`)
+        if cache_valid && has_dangling_links(&pilocal_dir.join("bin")) {
+            log::info!("[{}] exported links are stale, rebuilding", cave.name);
+            cache_valid = false;
         }
 
-        if cache_valid {
-            if let Ok(content) = std::fs::read_to_string(&env_cache_file) {
-                if let Ok(env_vars) = serde_json::from_str::<HashMap<String, String>>(&content) {
-                    log::info!("[{}] using cached environment", cave.name);
-                    return Ok(env_vars);
-                }
-            }
+        if cache_valid
+            && let Ok(content) = std::fs::read_to_string(&env_cache_file)
+            && let Ok(env_vars) = serde_json::from_str::<HashMap<String, String>>(&content)
+        {
+            log::info!("[{}] using cached environment", cave.name);
+            return Ok(CaveBuild { env: env_vars, packages: None });
         }
     }
 
     log::info!("[{}] building (var: {:?})", cave.name, variant);
 
-    let env_vars = crate::commands::package::build::build_packages(
-        config,
-        &settings.packages,
-        &settings.options,
-        &pilocal_dir,
-    )?;
+    let sums = CaveSums::load(&cave.workspace)?;
+    let graph = if locked {
+        Some(check_lock(config, cave, variant, &settings, &sums)?)
+    } else {
+        None
+    };
+    let output = crate::commands::package::build::build_packages(config, BuildRequest {
+        packages: &settings.packages,
+        all_options: &settings.options,
+        pilocal_dir: &pilocal_dir,
+        sums: Some(&sums),
+        host_build: &settings.host_build,
+        bin_prefix: &settings.bin_prefix,
+        cave: Some(&CaveInfo {
+            name: cave.name.clone(),
+            variant: variant.map(|v| v.to_string()),
+            packages: settings.packages.clone(),
+            workspace: cave.workspace.clone(),
+        }),
+        graph,
+    })?;
+    let env_vars = output.env;
+    if let Err(e) = record_binaries(config, &cave.name, variant, &pilocal_dir, &output.packages) {
+        log::warn!("[{}] failed to index binaries, `pi which` may be out of date: {:#}", cave.name, e);
+    }
+    if let Err(e) = write_shims(&pilocal_dir, &cave.workspace, variant) {
+        log::warn!("[{}] failed to update shims: {:#}", cave.name, e);
+    }
+    if let Err(e) = sums.save() {
+        log::warn!("[{}] failed to update {}: {:#}", cave.name, CaveSums::FILENAME, e);
+    }
+    let current = locked_packages(&output.graph, Some(&sums));
+    match lock {
+        LockPolicy::Update => match CaveLock::update(&cave.workspace, variant, current) {
+            Ok(true) => log::info!("[{}] updated {}", cave.name, CaveLock::FILENAME),
+            Ok(false) => {}
+            Err(e) => log::warn!("[{}] failed to update {}: {:#}", cave.name, CaveLock::FILENAME, e),
+        },
+        LockPolicy::Report => warn_stale_lock(cave, variant, &current),
+        LockPolicy::Enforce => {}
+    }
 
+    if let Err(e) = BuiltState::new(variant, &output.packages).save(&pilocal_dir) {
+        log::debug!("[{}] failed to record the built packages: {:#}", cave.name, e);
+    }
     // Cache the environment variables
     if let Ok(content) = serde_json::to_string_pretty(&env_vars) {
         let _ = std::fs::write(&env_cache_file, content);
     }
 
     log::info!("[{}] build success", cave.name);
-    Ok(env_vars)
+    Ok(CaveBuild { env: env_vars, packages: Some(output.packages) })
+}
+
+/// `--locked`: resolves the cave and fails when the lock is missing or
+/// differs; the locked hashes are then expected of the fetched artifacts.
+/// Returns the resolved graph for the build.
+fn check_lock(config: &Config, cave: &Cave, variant: Option<&str>, settings: &CaveSettings, sums: &CaveSums) -> Result<DependencyGraph> {
+    let lock = CaveLock::load(&cave.workspace)?
+        .with_context(|| format!("--locked needs {}, run `pi cave resolve` or `pi cave build` first", CaveLock::FILENAME))?;
+    let packages = lock.packages(variant)
+        .with_context(|| format!("{} has no entry for {}", CaveLock::FILENAME, variant.unwrap_or("the default variant")))?;
+    let graph = resolve_graph(config, &settings.packages, &settings.options)?;
+    let differences = lock_differences(packages, &locked_packages(&graph, Some(sums)));
+    for d in &differences {
+        log::error!("[lock] {}", d);
+    }
+    if !differences.is_empty() {
+        anyhow::bail!("{} is stale ({} difference(s), see above); rebuild without --locked to update it", CaveLock::FILENAME, differences.len());
+    }
+    for artifact in packages.iter().flat_map(|p| &p.artifacts) {
+        if let Some(hash) = &artifact.sha256 {
+            sums.expect(&artifact.url, hash)?;
+        }
+    }
+    Ok(graph)
+}
+
+/// Warns when the lock has the variant pinned to something else than what
+/// was just built; a cave without a lock says nothing.
+fn warn_stale_lock(cave: &Cave, variant: Option<&str>, current: &[LockedPackage]) {
+    let lock = match CaveLock::load(&cave.workspace) {
+        Ok(lock) => lock,
+        Err(e) => {
+            log::warn!("[{}] {:#}", cave.name, e);
+            return;
+        }
+    };
+    let Some(locked) = lock.as_ref().and_then(|l| l.packages(variant)) else { return };
+    let differences = lock_differences(locked, current);
+    if !differences.is_empty() {
+        log::warn!(
+            "[{}] {} is stale ({} difference(s)), run `pi cave build` to update it",
+            cave.name, CaveLock::FILENAME, differences.len()
+        );
+    }
+}
+
+/// `--dry-run`: the downloads a build would make, with their recorded sizes,
+/// and whether they fit in the free space of the download cache.
+fn print_plan(config: &Config, cave: &Cave, variant: Option<&str>) -> Result<()> {
+    let settings = cave.get_effective_settings(variant).context("Failed to get effective cave settings")?;
+    let graph = resolve_graph(config, &settings.packages, &settings.options)?;
+    let plan = download_plan(config, &graph);
+
+    let mut table = Theme::current().table(&["Package", "Version", "Size", "Status", "URL"]);
+    for d in &plan {
+        table.add_row(vec![
+            d.pkgname.clone(),
+            d.version.clone(),
+            d.size.map(format_size).unwrap_or_else(|| "?".to_string()),
+            if d.cached { "cached" } else { "download" }.to_string(),
+            d.url.clone(),
+        ]);
+    }
+    Theme::current().print(&table);
+
+    let pending: Vec<&PlannedDownload> = plan.iter().filter(|d| !d.cached).collect();
+    let known: u64 = pending.iter().filter_map(|d| d.size).sum();
+    let unknown = pending.iter().filter(|d| d.size.is_none()).count();
+    let free = available_space(&config.cache_download_dir).map(format_size).unwrap_or_else(|| "?".to_string());
+    let mut summary = format!("{} packages, {} downloads, {} to fetch", graph.len(), pending.len(), format_size(known));
+    if unknown > 0 {
+        summary.push_str(&format!(" (+{} of unknown size)", unknown));
+    }
+    Theme::current().line(&format!("{}, {} free", summary, free));
+    Ok(())
+}
+
+/// Records the binaries in `<pilocal>/bin` of the cave (variant) with the
+/// package each links into, for `pi which`. Entries that are not links into a
+/// built package are skipped.
+fn record_binaries(config: &Config, cave: &str, variant: Option<&str>, pilocal_dir: &Path, packages: &[BuiltPackage]) -> Result<()> {
+    let db = config.db().context("state database is unavailable")?;
+    let roots: Vec<(PathBuf, &BuiltPackage)> = packages.iter()
+        .filter_map(|p| p.root.canonicalize().ok().map(|root| (root, p)))
+        .collect();
+    let mut binaries = Vec::new();
+    for entry in std::fs::read_dir(pilocal_dir.join("bin"))?.filter_map(|e| e.ok()) {
+        let Ok(target) = entry.path().canonicalize() else { continue };
+        if let Some((_, pkg)) = roots.iter().find(|(root, _)| target.starts_with(root)) {
+            binaries.push((entry.file_name().to_string_lossy().into_owned(), pkg.pkgname.clone(), pkg.version.clone()));
+        }
+    }
+    db.set_cave_binaries(cave, variant.map(|v| v.trim_start_matches(':')), &binaries)
+}
+
+fn has_dangling_links(dir: &std::path::Path) -> bool {
+    std::fs::read_dir(dir)
+        .map(|entries| entries.filter_map(|e| e.ok()).any(|e| std::fs::metadata(e.path()).is_err()))
+        .unwrap_or(false)
+}
+
+/// Makes the running pi binary available as `.pilocal/bin/pi` so the commands
+/// allowed inside a cave work even when the host's pi is not mounted.
+/// Hard links when possible, otherwise copies; refreshed when the binary changes.
+fn export_pi_binary(pilocal_dir: &std::path::Path) -> Result<()> {
+    let exe = env::current_exe().context("Failed to locate the pi binary")?;
+    let bin_dir = pilocal_dir.join("bin");
+    let dest = bin_dir.join("pi");
+
+    let exe_meta = std::fs::metadata(&exe)?;
+    if let Ok(dest_meta) = std::fs::metadata(&dest)
+        && dest_meta.len() == exe_meta.len()
+        && dest_meta.modified()? >= exe_meta.modified()?
+    {
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(&bin_dir).context("Failed to create pilocal bin directory")?;
+    if std::fs::symlink_metadata(&dest).is_ok() {
+        std::fs::remove_file(&dest)?;
+    }
+    if std::fs::hard_link(&exe, &dest).is_err() {
+        std::fs::copy(&exe, &dest).with_context(|| format!("Failed to copy pi binary to {}", dest.display()))?;
+    }
+    log::debug!("exported pi binary to {}", dest.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_binaries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let root = config.cache_packages_dir.join("rust-1.80.0");
+        std::fs::create_dir_all(root.join("bin")).unwrap();
+        std::fs::write(root.join("bin").join("cargo"), "").unwrap();
+        let pilocal = config.pilocal_path("web", None);
+        std::fs::create_dir_all(pilocal.join("bin")).unwrap();
+        std::os::unix::fs::symlink(root.join("bin").join("cargo"), pilocal.join("bin").join("cargo")).unwrap();
+        std::fs::write(pilocal.join("bin").join("pi"), "").unwrap();
+
+        let packages = vec![BuiltPackage { pkgname: "rust".to_string(), version: "1.80.0".to_string(), root, stats: Default::default() }];
+        record_binaries(&config, "web", None, &pilocal, &packages).unwrap();
+        let providers = config.db().unwrap().binary_providers("cargo").unwrap();
+        assert_eq!((providers[0].cave.as_str(), providers[0].package.as_str(), providers[0].version.as_str()), ("web", "rust", "1.80.0"));
+        assert!(config.db().unwrap().binary_providers("pi").unwrap().is_empty());
+    }
 }
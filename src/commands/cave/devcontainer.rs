@@ -0,0 +1,126 @@
+use crate::commands::cave::shell_hook::{cached_package_envs, host_path_vars};
+use crate::models::cave::Cave;
+use crate::models::config::Config;
+use anyhow::{Context, Result};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::env;
+use std::path::Path;
+
+pub const DEFAULT_IMAGE: &str = "mcr.microsoft.com/devcontainers/base:ubuntu";
+
+pub fn run(config: &Config, variant: Option<String>, image: &str) {
+    if let Err(e) = execute(config, variant.as_deref(), image) {
+        log::error!("devcontainer export failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn execute(config: &Config, variant: Option<&str>, image: &str) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let (cave_file, cave) = Cave::find(config, &current_dir).context("no cave found")?;
+    let variant = variant.filter(|v| v.starts_with(':'));
+    let settings = cave.get_effective_settings(variant)?;
+
+    let package_envs = cached_package_envs(config, &cave_file, &cave, variant).unwrap_or_else(|e| {
+        log::warn!("[{}] {:#}; package env vars are left out, regenerate after building", cave.name, e);
+        Default::default()
+    });
+    let pilocal = config.pilocal_path(&cave.name, variant);
+    let home = config.get_host_home();
+    let paths = host_path_vars(config, &cave, &pilocal, &home);
+    let mut envs = BTreeMap::new();
+    for (k, v) in package_envs.into_iter().chain(settings.set) {
+        envs.insert(k, paths.value(&v));
+    }
+    for k in &settings.unset {
+        envs.remove(k);
+    }
+
+    let dest = cave.workspace.join(".devcontainer").join("devcontainer.json");
+    if dest.exists() && !config.force {
+        anyhow::bail!("{} exists, use --force to overwrite", dest.display());
+    }
+    let doc = devcontainer_json(config, &cave, variant, &pilocal, &envs, image);
+    std::fs::create_dir_all(dest.parent().unwrap()).context("Failed to create .devcontainer")?;
+    std::fs::write(&dest, serde_json::to_string_pretty(&doc)? + "\n")
+        .with_context(|| format!("Failed to write {}", dest.display()))?;
+    log::info!("[{}] wrote {}", cave.name, dest.display());
+    Ok(())
+}
+
+/// The devcontainer definition of a cave. The workspace, pi's cache (which
+/// `.pilocal` links point into) and the pilocal are mounted at their host
+/// paths, so links and resolved env values stay valid; the config directory
+/// is mounted read-only and the XDG variables point pi at all of them, so
+/// `pi cave build` in the container reuses the host's builds.
+fn devcontainer_json(
+    config: &Config,
+    cave: &Cave,
+    variant: Option<&str>,
+    pilocal: &Path,
+    envs: &BTreeMap<String, String>,
+    image: &str,
+) -> Value {
+    let bind = |path: &Path, readonly: bool| {
+        let mut mount = format!("source={0},target={0},type=bind", path.display());
+        if readonly {
+            mount.push_str(",readonly");
+        }
+        mount
+    };
+    let parent = |path: &Path| path.parent().unwrap_or(path).display().to_string();
+
+    let mut container_env = Map::new();
+    for (k, v) in envs {
+        container_env.insert(k.clone(), json!(v));
+    }
+    container_env.insert("XDG_CACHE_HOME".to_string(), json!(parent(&config.cache_dir)));
+    container_env.insert("XDG_CONFIG_HOME".to_string(), json!(parent(&config.config_dir)));
+    container_env.insert("XDG_STATE_HOME".to_string(), json!(parent(&config.state_dir)));
+
+    let build = match variant {
+        Some(v) => format!("pi cave build {}", v),
+        None => "pi cave build".to_string(),
+    };
+    json!({
+        "name": format!("{} (pi)", cave.name),
+        "image": image,
+        "workspaceFolder": cave.workspace,
+        "workspaceMount": bind(&cave.workspace, false),
+        "mounts": [
+            bind(&config.cache_dir, false),
+            bind(pilocal, false),
+            bind(&config.config_dir, true),
+            bind(&config.state_dir, false),
+        ],
+        "containerEnv": container_env,
+        "remoteEnv": {
+            "PATH": format!("{}:${{containerEnv:PATH}}", pilocal.join("bin").display()),
+        },
+        "postCreateCommand": format!("{}/{}", pilocal.join("bin").display(), build),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_devcontainer_json() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let cave = Cave::new(tmp.path().join("ws"), tmp.path().join("home"));
+        let pilocal = config.pilocal_path(&cave.name, None);
+        let mut envs = BTreeMap::new();
+        envs.insert("GOROOT".to_string(), format!("{}/go", pilocal.display()));
+
+        let doc = devcontainer_json(&config, &cave, Some(":dev"), &pilocal, &envs, DEFAULT_IMAGE);
+        assert_eq!(doc["containerEnv"]["GOROOT"], json!(format!("{}/go", pilocal.display())));
+        assert_eq!(doc["containerEnv"]["XDG_CACHE_HOME"], json!(tmp.path().display().to_string()));
+        let mounts: Vec<&str> = doc["mounts"].as_array().unwrap().iter().map(|m| m.as_str().unwrap()).collect();
+        assert!(mounts.contains(&format!("source={0},target={0},type=bind,readonly", config.config_dir.display()).as_str()));
+        assert_eq!(doc["postCreateCommand"], json!(format!("{}/pi cave build :dev", pilocal.join("bin").display())));
+        assert!(doc["remoteEnv"]["PATH"].as_str().unwrap().ends_with(":${containerEnv:PATH}"));
+    }
+}
@@ -0,0 +1,388 @@
+use crate::commands::package::build::{self, DependencyGraph};
+use crate::commands::package::resolve;
+use crate::models::cave::{Cave, CaveSettings};
+use crate::models::cave_sums::CaveSums;
+use crate::models::config::Config;
+use crate::models::lockfile::{lock_differences, locked_packages, CaveLock, LockedPackage};
+use crate::models::repository::Repositories;
+use crate::models::selector::PackageSelector;
+use crate::models::settings::QuotaSettings;
+use crate::utils::table::SortedTable;
+use crate::utils::theme::Theme;
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::env;
+use std::path::Path;
+use walkdir::WalkDir;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Severity {
+    Error,
+    Warning,
+}
+
+/// One problem found by `pi cave doctor`, with how to fix it.
+#[derive(Debug)]
+struct Finding {
+    severity: Severity,
+    check: &'static str,
+    problem: String,
+    fix: String,
+}
+
+impl Finding {
+    fn error(check: &'static str, problem: String, fix: String) -> Self {
+        Self { severity: Severity::Error, check, problem, fix }
+    }
+
+    fn warning(check: &'static str, problem: String, fix: String) -> Self {
+        Self { severity: Severity::Warning, check, problem, fix }
+    }
+}
+
+pub fn run(config: &Config) {
+    match execute(config) {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            log::error!("doctor failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Checks the cave of the current directory (or `--cave`) and prints the findings; false
+/// when any of them is an error.
+fn execute(config: &Config) -> Result<bool> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let cave_file = Cave::find_file(config, &current_dir)?;
+    let findings = diagnose(config, &cave_file)?;
+    if findings.is_empty() {
+        log::info!("[doctor] no problems found in {}", cave_file.display());
+        return Ok(true);
+    }
+
+    let errors = findings.iter().filter(|f| f.severity == Severity::Error).count();
+    let mut table = SortedTable::new(&["Severity", "Check", "Problem", "Fix"], "severity,check,problem");
+    for f in &findings {
+        let severity = match f.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        table.add_row(vec![severity.to_string(), f.check.to_string(), f.problem.clone(), f.fix.clone()]);
+    }
+    table.print(config.sort.as_ref());
+    Theme::current().line(&format!("{} error(s), {} warning(s)", errors, findings.len() - errors));
+    Ok(errors == 0)
+}
+
+fn diagnose(config: &Config, cave_file: &Path) -> Result<Vec<Finding>> {
+    let content = std::fs::read_to_string(cave_file)
+        .with_context(|| format!("Failed to read {}", cave_file.display()))?;
+    let raw: Value = match serde_json::from_str(&content) {
+        Ok(raw) => raw,
+        Err(e) => return Ok(vec![Finding::error("schema", format!("{} is not valid JSON: {}", Cave::FILENAME, e), "fix the syntax error".to_string())]),
+    };
+    let mut findings = schema_findings(&raw);
+    let mut cave: Cave = match serde_json::from_value(raw) {
+        Ok(cave) => cave,
+        Err(e) => {
+            findings.push(Finding::error("schema", format!("{} does not match the cave format: {}", Cave::FILENAME, e), "fix the value's type".to_string()));
+            return Ok(findings);
+        }
+    };
+    if let Err(e) = cave.load_shared_variants(cave_file, &config.config_dir) {
+        findings.push(Finding::error("variants", format!("{:#}", e), "fix or remove the variants_from entry".to_string()));
+    }
+
+    let names = cave.variant_names();
+    let mut variants: Vec<Option<&str>> = vec![None];
+    variants.extend(names.iter().map(|n| Some(n.as_str())));
+
+    let lock = CaveLock::load(&cave.workspace).unwrap_or_else(|e| {
+        findings.push(Finding::error("lock", format!("{:#}", e), format!("delete {} and run `pi cave resolve`", CaveLock::FILENAME)));
+        None
+    });
+    let sums = CaveSums::load(&cave.workspace)?;
+    let repo_config = Repositories::get_all(config);
+    let mut all_resolved = true;
+    for variant in variants {
+        let label = variant.map(|v| format!("variant :{}", v)).unwrap_or_else(|| "the cave".to_string());
+        let settings = match cave.get_effective_settings(variant) {
+            Ok(settings) => settings,
+            Err(e) => {
+                findings.push(Finding::error("variants", format!("{:#}", e), format!("fix the extends of {}", label)));
+                all_resolved = false;
+                continue;
+            }
+        };
+        let unresolved = selector_findings(config, repo_config, &settings, &label);
+        if !unresolved.is_empty() {
+            findings.extend(unresolved);
+            all_resolved = false;
+            continue;
+        }
+        match build::resolve_graph(config, &settings.packages, &settings.options) {
+            Ok(graph) => {
+                findings.extend(option_findings(&graph, &settings, &label));
+                if let Some(lock) = &lock {
+                    findings.extend(lock_findings(lock.packages(variant), &locked_packages(&graph, Some(&sums)), variant, &label));
+                }
+            }
+            Err(e) => {
+                all_resolved = false;
+                findings.push(Finding::error("packages", format!("{} does not resolve: {:#}", label, e), "fix the failing package or its dependencies".to_string()));
+            }
+        }
+    }
+
+    if all_resolved && let Some(lock) = &lock {
+        findings.extend(lock.locked_variants().flatten()
+            .filter(|v| !names.iter().any(|n| n == v))
+            .map(|v| Finding::warning(
+                "lock",
+                format!("{} locks variant :{}, which the cave doesn't define", CaveLock::FILENAME, v),
+                format!("remove \"{}\" from the variants of {}", v, CaveLock::FILENAME),
+            )));
+    }
+    findings.extend(link_findings(&config.pilocal_path(&cave.name, None)));
+    let devcontainer = cave.workspace.join(".devcontainer").join("devcontainer.json");
+    if let Ok(content) = std::fs::read_to_string(&devcontainer) {
+        match serde_json::from_str::<Value>(&content) {
+            Ok(definition) => findings.extend(variant_reference_findings(&cave, &definition)),
+            Err(e) => findings.push(Finding::warning(
+                "variants",
+                format!(".devcontainer/devcontainer.json is not plain JSON ({}), its variants were not checked", e),
+                "remove comments and trailing commas, or regenerate with `pi cave devcontainer --force`".to_string(),
+            )),
+        }
+    }
+    Ok(findings)
+}
+
+/// Keys pi doesn't know, usually misspellings that are silently ignored.
+fn schema_findings(raw: &Value) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut check = |value: Option<&Value>, known: &[&str], place: &str| {
+        let Some(object) = value.and_then(|v| v.as_object()) else { return };
+        for key in object.keys().filter(|k| !known.contains(&k.as_str())) {
+            findings.push(Finding::warning(
+                "schema",
+                format!("unknown key '{}' in {}", key, place),
+                format!("remove it or use one of: {}", known.join(", ")),
+            ));
+        }
+    };
+    check(Some(raw), Cave::KEYS, "the cave file");
+    check(raw.get("settings"), CaveSettings::KEYS, "settings");
+    check(raw.get("quota"), QuotaSettings::KEYS, "quota");
+    if let Some(variants) = raw.get("variants").and_then(|v| v.as_object()) {
+        for (name, settings) in variants {
+            check(Some(settings), CaveSettings::KEYS, &format!("variant :{}", name));
+        }
+    }
+    findings
+}
+
+fn selector_findings(config: &Config, repo_config: &Repositories, settings: &CaveSettings, label: &str) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    for query in &settings.packages {
+        let Some(selector) = PackageSelector::parse(query) else {
+            findings.push(Finding::error("packages", format!("'{}' in {} is not a valid selector", query, label), "use [repo/][manager:]package[=version]".to_string()));
+            continue;
+        };
+        if resolve::resolve_query(config, repo_config, &selector).is_none() {
+            findings.push(Finding::error(
+                "packages",
+                format!("{} in {} resolves to nothing", query, label),
+                format!("check the name and version with `pi package list {}`", selector.package),
+            ));
+        }
+    }
+    findings
+}
+
+/// Options and `host_build` entries for packages the build doesn't contain,
+/// and options that are not flags of their package.
+fn option_findings(graph: &DependencyGraph, settings: &CaveSettings, label: &str) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let mut packages: Vec<&String> = settings.options.keys().collect();
+    packages.sort();
+    for package in packages {
+        let Some((version, _)) = graph.values().find(|(v, _)| &v.pkgname == package) else {
+            findings.push(Finding::warning(
+                "options",
+                format!("options for {}, which {} doesn't build", package, label),
+                format!("remove options.{} or add {} to packages", package, package),
+            ));
+            continue;
+        };
+        let mut keys: Vec<&String> = settings.options[package].keys().collect();
+        keys.sort();
+        for key in keys.into_iter().filter(|k| !version.flags.iter().any(|f| &f.name == *k)) {
+            let declared: Vec<&str> = version.flags.iter().map(|f| f.name.as_str()).collect();
+            findings.push(Finding::warning(
+                "options",
+                format!("option '{}' is not a flag of {} {}", key, package, version.version),
+                if declared.is_empty() {
+                    format!("remove it, {} declares no flags", package)
+                } else {
+                    format!("use one of: {}", declared.join(", "))
+                },
+            ));
+        }
+    }
+    for package in &settings.host_build {
+        if !graph.values().any(|(v, _)| &v.pkgname == package) {
+            findings.push(Finding::warning(
+                "options",
+                format!("host_build lists {}, which {} doesn't build", package, label),
+                format!("remove {} from host_build", package),
+            ));
+        }
+    }
+    findings
+}
+
+/// How what the cave (variant) resolves to now differs from what
+/// `pi.cave.lock` has for it.
+fn lock_findings(locked: Option<&[LockedPackage]>, current: &[LockedPackage], variant: Option<&str>, label: &str) -> Vec<Finding> {
+    let fix = format!("run `pi cave resolve{}` to update {}", variant.map(|v| format!(" :{}", v)).unwrap_or_default(), CaveLock::FILENAME);
+    let Some(locked) = locked else {
+        return vec![Finding::warning("lock", format!("{} has nothing for {}", CaveLock::FILENAME, label), fix)];
+    };
+    lock_differences(locked, current).into_iter()
+        .map(|difference| Finding::warning("lock", format!("{}: {}", label, difference), fix.clone()))
+        .collect()
+}
+
+/// Links in the pilocal whose target is gone, e.g. evicted by `pi disk gc`.
+fn link_findings(pilocal: &Path) -> Vec<Finding> {
+    if !pilocal.exists() {
+        return vec![Finding::warning("links", "the cave is not built yet".to_string(), "run `pi cave build`".to_string())];
+    }
+    let broken: Vec<_> = WalkDir::new(pilocal).into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path_is_symlink() && !e.path().exists())
+        .map(|e| e.into_path())
+        .collect();
+    match broken.first() {
+        None => Vec::new(),
+        Some(first) => vec![Finding::error(
+            "links",
+            format!("{} broken link(s) in the pilocal, e.g. {}", broken.len(), first.display()),
+            "run `pi cave build --rebuild`".to_string(),
+        )],
+    }
+}
+
+/// Lifecycle commands of a devcontainer definition, each a string, an argv
+/// array, or an object of named commands.
+const DEVCONTAINER_COMMANDS: &[&str] =
+    &["initializeCommand", "onCreateCommand", "updateContentCommand", "postCreateCommand", "postStartCommand", "postAttachCommand"];
+
+/// Variants named by `pi cave build :variant` in the lifecycle commands of
+/// the devcontainer definition that the cave doesn't have.
+fn variant_reference_findings(cave: &Cave, devcontainer: &Value) -> Vec<Finding> {
+    let commands: Vec<String> = DEVCONTAINER_COMMANDS.iter()
+        .filter_map(|key| devcontainer.get(key))
+        .flat_map(command_lines)
+        .collect();
+    let referenced: BTreeSet<&str> = commands.iter().flat_map(|c| built_variants(c)).collect();
+    referenced.into_iter()
+        .filter(|name| cave.variant(name).is_none())
+        .map(|name| Finding::error(
+            "variants",
+            format!(".devcontainer/devcontainer.json builds variant :{}, which the cave doesn't define", name),
+            "add the variant or regenerate with `pi cave devcontainer --force`".to_string(),
+        ))
+        .collect()
+}
+
+/// The shell lines of a lifecycle command; argv arrays are joined with spaces.
+fn command_lines(command: &Value) -> Vec<String> {
+    match command {
+        Value::String(line) => vec![line.clone()],
+        Value::Array(argv) => vec![argv.iter().filter_map(Value::as_str).collect::<Vec<_>>().join(" ")],
+        Value::Object(named) => named.values().flat_map(command_lines).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The `:variant` arguments of the `cave build` invocations in `line`.
+fn built_variants(line: &str) -> Vec<&str> {
+    let words: Vec<&str> = line.split_whitespace().map(|w| w.trim_end_matches(';')).collect();
+    words.windows(2)
+        .enumerate()
+        .filter(|(_, pair)| pair == &["cave", "build"])
+        .filter_map(|(i, _)| words[i + 2..].iter().find(|w| !w.starts_with('-')))
+        .filter_map(|w| w.strip_prefix(':'))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_static_checks() {
+        let raw = json!({
+            "workspace": "/w", "homedir": "/h",
+            "settings": { "packages": ["go"], "pakages": ["node"] },
+            "variants": { "dev": { "sett": {} } },
+            "extra": 1,
+        });
+        let problems: Vec<String> = schema_findings(&raw).into_iter().map(|f| f.problem).collect();
+        assert_eq!(problems, vec![
+            "unknown key 'extra' in the cave file",
+            "unknown key 'pakages' in settings",
+            "unknown key 'sett' in variant :dev",
+        ]);
+
+        let mut cave = Cave::new("/w".into(), "/h".into());
+        cave.variants.insert("dev".to_string(), CaveSettings::default());
+        let devcontainer = json!({ "postCreateCommand": "/p/bin/pi cave build :ci" });
+        let findings = variant_reference_findings(&cave, &devcontainer);
+        assert_eq!(findings.len(), 1);
+        assert!(findings[0].problem.contains(":ci"));
+        assert!(variant_reference_findings(&cave, &json!({ "postCreateCommand": "/p/bin/pi cave build :dev" })).is_empty());
+
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("bin")).unwrap();
+        std::os::unix::fs::symlink(tmp.path().join("gone"), tmp.path().join("bin/go")).unwrap();
+        let links = link_findings(tmp.path());
+        assert_eq!(links[0].severity, Severity::Error);
+        assert!(links[0].problem.starts_with("1 broken link(s)"));
+    }
+
+    #[test]
+    fn test_devcontainer_command_forms() {
+        let cave = Cave::new("/w".into(), "/h".into());
+        let devcontainer = json!({
+            "postCreateCommand": ["/p/bin/pi", "cave", "build", "--locked", ":ci"],
+            "postStartCommand": { "tools": "pi cave build :gpu; echo ready" },
+            "containerEnv": { "NOTE": "pi cave build :ignored" },
+        });
+        let mut problems: Vec<String> = variant_reference_findings(&cave, &devcontainer).into_iter().map(|f| f.problem).collect();
+        problems.sort();
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].contains(":ci") && problems[1].contains(":gpu"));
+    }
+
+    #[test]
+    fn test_lock_findings() {
+        let package = |query: &str, version: &str| LockedPackage {
+            query: query.to_string(), pkgname: query.to_string(), version: version.to_string(), repo: "main".to_string(), artifacts: Vec::new(),
+        };
+        let locked = vec![package("go", "1.22.0"), package("jq", "1.7")];
+        let current = vec![package("go", "1.23.0"), package("jq", "1.7")];
+        let findings = lock_findings(Some(&locked), &current, Some("ci"), "variant :ci");
+        assert_eq!(findings.len(), 1);
+        assert!(findings[0].problem.contains("go is locked to main/go=1.22.0"));
+        assert!(findings[0].fix.contains("pi cave resolve :ci"));
+        assert!(lock_findings(Some(&current), &current, None, "the cave").is_empty());
+        assert!(lock_findings(None, &current, None, "the cave")[0].problem.contains("has nothing for the cave"));
+    }
+}
@@ -0,0 +1,56 @@
+use crate::models::config::Config;
+use crate::models::cave::Cave;
+use crate::models::cave_bundle::CaveBundle;
+use crate::models::lockfile::CaveLock;
+use crate::models::selector::PackageSelector;
+use crate::models::repository::Repositories;
+use crate::commands::package::resolve;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::env;
+
+/// Writes the current cave, with its lock, as a single shareable bundle to stdout.
+pub fn run(config: &Config) {
+    let current_dir = env::current_dir().expect("Failed to get current directory");
+    let (_path, cave) = match Cave::find(config, &current_dir) {
+        Some(res) => res,
+        None => {
+            log::error!("no cave found");
+            std::process::exit(1);
+        }
+    };
+
+    let lock = match CaveLock::load(&cave.workspace) {
+        Ok(lock) => lock,
+        Err(e) => {
+            log::warn!("[{}] exporting without {}: {:#}", cave.name, CaveLock::FILENAME, e);
+            None
+        }
+    };
+    let pins = resolve_pins(config, &cave);
+    let bundle = CaveBundle::from_cave(&cave, pins, lock);
+    match serde_json::to_string_pretty(&bundle) {
+        Ok(content) => println!("{}", content),
+        Err(e) => {
+            log::error!("[{}] export failed: {}", cave.name, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn resolve_pins(config: &Config, cave: &Cave) -> BTreeMap<String, String> {
+    let repo_config = Repositories::get_all(config);
+    CaveBundle::queries(cave)
+        .par_iter()
+        .filter_map(|query| {
+            let selector = PackageSelector::parse(query)?;
+            match resolve::resolve_query(config, repo_config, &selector) {
+                Some((full_name, _, _)) => Some((query.clone(), full_name)),
+                None => {
+                    log::warn!("[{}] could not resolve, exporting unpinned", query);
+                    None
+                }
+            }
+        })
+        .collect()
+}
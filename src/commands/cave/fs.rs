@@ -1,8 +1,68 @@
 use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
+/// The record of export rewrites in a pilocal, written by every build.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Links of a pilocal that were not created where the recipe asked, the
+/// modes exports set on their targets, and the services the cave exports.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct ExportManifest {
+    pub rewrites: Vec<ExportRewrite>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chmods: Vec<ExportChmod>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<ExportService>,
+}
+
+/// A `bin/` link moved by the cave's `bin_prefix`, paths relative to the pilocal.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ExportRewrite {
+    pub package: String,
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+/// A link whose target got the mode of `export_link(chmod=)`, path relative to the pilocal.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ExportChmod {
+    pub package: String,
+    pub path: PathBuf,
+    /// Octal, e.g. "755".
+    pub mode: String,
+}
+
+/// An `export_service()` of a built package, for `pi cave service`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ExportService {
+    pub package: String,
+    pub name: String,
+    pub command: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+}
+
+impl ExportManifest {
+    pub fn load(pilocal_dir: &Path) -> Result<Self> {
+        let path = pilocal_dir.join(MANIFEST_FILE);
+        match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).with_context(|| format!("Failed to parse {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, pilocal_dir: &Path) -> Result<()> {
+        let path = pilocal_dir.join(MANIFEST_FILE);
+        fs::write(&path, serde_json::to_string_pretty(self)? + "\n")
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
 /// Options for mapping files from a package directory to a cave's .pilocal directory.
 pub struct FileMapOptions<'a> {
     pub pkg_ctx: &'a str,
@@ -10,12 +70,32 @@ pub struct FileMapOptions<'a> {
     pub pilocal_dir: &'a Path,
     pub src_pattern: &'a str,
     pub dest_rel: &'a str,
+    /// Prepended to the names of links created directly in `bin/`.
+    pub bin_prefix: Option<&'a str>,
+    /// Mode set on the targets of the links.
+    pub chmod: Option<u32>,
+}
+
+/// What a mapping did besides linking, paths relative to the pilocal.
+#[derive(Debug, Default, PartialEq)]
+pub struct FileMapOutcome {
+    /// Links `bin_prefix` renamed, as (asked, created).
+    pub rewrites: Vec<(PathBuf, PathBuf)>,
+    /// Links whose target got the `chmod` mode, with the mode.
+    pub chmods: Vec<(PathBuf, u32)>,
+}
+
+impl FileMapOutcome {
+    pub fn extend(&mut self, other: FileMapOutcome) {
+        self.rewrites.extend(other.rewrites);
+        self.chmods.extend(other.chmods);
+    }
 }
 
 /// Applies a file mapping entry, creating symlinks for matched files.
 /// Example pkg_dir: "/home/user/.cache/pi/packages/rust-1.70.0"
 /// Example pilocal_dir: "/home/user/.cache/pi/pilocals/my-cave"
-pub fn apply_filemap_entry(opts: FileMapOptions) -> Result<()> {
+pub fn apply_filemap_entry(opts: FileMapOptions) -> Result<FileMapOutcome> {
     let is_glob = opts.src_pattern.contains('*');
     let base_pattern = if is_glob {
         opts.src_pattern.strip_suffix("*").unwrap_or(opts.src_pattern)
@@ -26,7 +106,7 @@ pub fn apply_filemap_entry(opts: FileMapOptions) -> Result<()> {
     let search_path = resolve_src_path(opts.pkg_dir, base_pattern);
     if !search_path.exists() {
         log::debug!("[{}] optional source missing: {}", opts.pkg_ctx, search_path.display());
-        return Ok(());
+        return Ok(FileMapOutcome::default());
     }
 
     if is_glob {
@@ -36,31 +116,67 @@ pub fn apply_filemap_entry(opts: FileMapOptions) -> Result<()> {
     }
 }
 
-fn apply_glob_filemap(opts: &FileMapOptions, search_path: &Path) -> Result<()> {
+fn apply_glob_filemap(opts: &FileMapOptions, search_path: &Path) -> Result<FileMapOutcome> {
+    let mut outcome = FileMapOutcome::default();
     let mut matched = false;
     if search_path.is_dir() {
         for entry in WalkDir::new(search_path).max_depth(1).into_iter().filter_map(|e| e.ok()) {
             if entry.path() == search_path { continue; }
-            let target_dest = opts.pilocal_dir.join(opts.dest_rel).join(entry.file_name());
-            create_symlink(entry.path(), &target_dest)?;
+            let target_dest = Path::new(opts.dest_rel).join(entry.file_name());
+            link(opts, entry.path(), target_dest, &mut outcome)?;
             matched = true;
         }
     }
     if !matched {
         log::debug!("[{}] pattern '{}' no match in {}", opts.pkg_ctx, opts.src_pattern, search_path.display());
     }
-    Ok(())
+    Ok(outcome)
 }
 
-fn apply_single_filemap(opts: &FileMapOptions, search_path: &Path) -> Result<()> {
+fn apply_single_filemap(opts: &FileMapOptions, search_path: &Path) -> Result<FileMapOutcome> {
     let dest_path = opts.pilocal_dir.join(opts.dest_rel);
     let final_dest = if opts.dest_rel.ends_with('/') || dest_path.is_dir() {
         let file_name = search_path.file_name().ok_or_else(|| anyhow::anyhow!("Invalid source filename"))?;
-        dest_path.join(file_name)
+        Path::new(opts.dest_rel).join(file_name)
     } else {
-        dest_path
+        PathBuf::from(opts.dest_rel)
     };
-    create_symlink(search_path, &final_dest)
+    let mut outcome = FileMapOutcome::default();
+    link(opts, search_path, final_dest, &mut outcome)?;
+    Ok(outcome)
+}
+
+/// Links `src` at the pilocal path `dest`, or at its prefixed name for
+/// direct `bin/` entries, and applies the mapping's mode to `src`.
+fn link(opts: &FileMapOptions, src: &Path, dest: PathBuf, outcome: &mut FileMapOutcome) -> Result<()> {
+    let rewritten = opts.bin_prefix.and_then(|prefix| prefixed_bin(&dest, prefix));
+    let created = rewritten.clone().unwrap_or_else(|| dest.clone());
+    // set_permissions follows symlinks, which could point anywhere on the host.
+    if opts.chmod.is_some() && fs::symlink_metadata(src).is_ok_and(|m| m.file_type().is_symlink()) {
+        anyhow::bail!("[{}] refusing to chmod {}: it is a symlink", opts.pkg_ctx, src.display());
+    }
+    create_symlink(src, &opts.pilocal_dir.join(&created))?;
+    if let Some(mode) = opts.chmod {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(src, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to chmod {:o} {}", mode, src.display()))?;
+        outcome.chmods.push((created, mode));
+    }
+    if let Some(to) = rewritten {
+        outcome.rewrites.push((dest, to));
+    }
+    Ok(())
+}
+
+/// `bin/<name>` as `bin/<prefix><name>`; None for other paths.
+fn prefixed_bin(dest: &Path, prefix: &str) -> Option<PathBuf> {
+    let mut components = dest.components().filter(|c| !matches!(c, std::path::Component::CurDir));
+    match (components.next(), components.next(), components.next()) {
+        (Some(dir), Some(name), None) if dir.as_os_str() == "bin" => {
+            Some(Path::new("bin").join(format!("{}{}", prefix, name.as_os_str().to_string_lossy())))
+        }
+        _ => None,
+    }
 }
 
 fn resolve_src_path(pkg_dir: &Path, pattern: &str) -> PathBuf {
@@ -99,3 +215,70 @@ fn ensure_destination_clear(dest: &Path) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bin_prefix() {
+        let tmp = tempfile::tempdir().unwrap();
+        let pkg = tmp.path().join("python-3.11");
+        let pilocal = tmp.path().join("pilocal");
+        fs::create_dir_all(pkg.join("bin")).unwrap();
+        fs::create_dir_all(pkg.join("lib")).unwrap();
+        fs::write(pkg.join("bin/python3"), "").unwrap();
+        fs::write(pkg.join("lib/libpython.so"), "").unwrap();
+        let opts = |src: &'static str, dest: &'static str| FileMapOptions {
+            pkg_ctx: "python", pkg_dir: &pkg, pilocal_dir: &pilocal, src_pattern: src, dest_rel: dest, bin_prefix: Some("py311-"),
+            chmod: None,
+        };
+
+        let rewrites = apply_filemap_entry(opts("bin/*", "bin")).unwrap().rewrites;
+        assert_eq!(rewrites, vec![(PathBuf::from("bin/python3"), PathBuf::from("bin/py311-python3"))]);
+        assert!(pilocal.join("bin/py311-python3").is_symlink() && !pilocal.join("bin/python3").exists());
+        assert_eq!(apply_filemap_entry(opts("lib/*", "lib")).unwrap(), FileMapOutcome::default());
+        assert!(pilocal.join("lib/libpython.so").is_symlink());
+        assert_eq!(apply_filemap_entry(opts("bin/python3", "bin/python")).unwrap().rewrites[0].1, PathBuf::from("bin/py311-python"));
+
+        let chmod = apply_filemap_entry(FileMapOptions { chmod: Some(0o750), ..opts("bin/python3", "bin/") }).unwrap();
+        assert_eq!(chmod.chmods, vec![(PathBuf::from("bin/py311-python3"), 0o750)]);
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(pkg.join("bin/python3")).unwrap().permissions().mode() & 0o7777, 0o750);
+
+        let manifest = ExportManifest {
+            rewrites: vec![ExportRewrite { package: "python".to_string(), from: "bin/python3".into(), to: "bin/py311-python3".into() }],
+            chmods: vec![ExportChmod { package: "python".to_string(), path: "bin/py311-python3".into(), mode: "750".to_string() }],
+            services: vec![ExportService {
+                package: "redis".to_string(),
+                name: "redis".to_string(),
+                command: "redis-server --port 6380".to_string(),
+                env: BTreeMap::new(),
+            }],
+        };
+        manifest.save(&pilocal).unwrap();
+        assert_eq!(ExportManifest::load(&pilocal).unwrap(), manifest);
+    }
+
+    #[test]
+    fn test_chmod_refuses_symlinks() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp = tempfile::tempdir().unwrap();
+        let pkg = tmp.path().join("tool-1.0");
+        let pilocal = tmp.path().join("pilocal");
+        let outside = tmp.path().join("outside");
+        fs::create_dir_all(pkg.join("bin")).unwrap();
+        fs::write(&outside, "").unwrap();
+        fs::set_permissions(&outside, fs::Permissions::from_mode(0o600)).unwrap();
+        std::os::unix::fs::symlink(&outside, pkg.join("bin/tool")).unwrap();
+        let opts = FileMapOptions {
+            pkg_ctx: "tool", pkg_dir: &pkg, pilocal_dir: &pilocal, src_pattern: "bin/tool", dest_rel: "bin", bin_prefix: None,
+            chmod: Some(0o755),
+        };
+
+        let err = apply_filemap_entry(opts).unwrap_err();
+        assert!(format!("{:#}", err).contains("symlink"), "{:#}", err);
+        assert_eq!(fs::metadata(&outside).unwrap().permissions().mode() & 0o7777, 0o600);
+        assert!(!pilocal.join("bin/tool").exists());
+    }
+}
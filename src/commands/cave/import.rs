@@ -0,0 +1,85 @@
+use crate::commands::package::build::{prefetch, resolve_graph};
+use crate::models::config::Config;
+use crate::models::cave::Cave;
+use crate::models::cave_bundle::CaveBundle;
+use crate::models::lockfile::CaveLock;
+use anyhow::Context;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Read;
+
+/// Recreates a cave in the current directory from a bundle written by `pi cave export`.
+/// Use "-" to read the bundle from stdin. With `fetch`, also downloads the
+/// artifacts of the cave and its variants.
+pub fn run(config: &Config, file: &str, no_pins: bool, fetch: bool) {
+    let current_dir = env::current_dir().expect("Failed to get current directory");
+    let cave_file = current_dir.join(Cave::FILENAME);
+    if cave_file.exists() && !config.force {
+        log::error!("cave exists in {} (use --force to overwrite)", current_dir.display());
+        std::process::exit(1);
+    }
+
+    let content = if file == "-" {
+        let mut buf = String::new();
+        std::io::stdin().read_to_string(&mut buf).map(|_| buf)
+    } else {
+        fs::read_to_string(file)
+    };
+    let bundle = match content.map_err(anyhow::Error::from).and_then(|c| CaveBundle::parse(&c)) {
+        Ok(b) => b,
+        Err(e) => {
+            log::error!("[{}] import failed: {:#}", file, e);
+            std::process::exit(1);
+        }
+    };
+
+    let homedir = config.state_home_dir.join(&bundle.name);
+    let pin_count = bundle.pins.len();
+    let lock = bundle.lock(!no_pins);
+    let cave = bundle.into_cave(current_dir.clone(), homedir, !no_pins);
+    cave.save(&cave_file).expect("Failed to save cave file");
+    if let Some(lock) = &lock
+        && let Err(e) = lock.save(&cave.workspace)
+    {
+        log::error!("[{}] import failed: {:#}", cave.name, e);
+        std::process::exit(1);
+    }
+
+    if no_pins {
+        log::info!("[{}] imported into {}", cave.name, current_dir.display());
+    } else {
+        log::info!("[{}] imported into {} ({} pinned)", cave.name, current_dir.display(), pin_count);
+    }
+
+    if fetch {
+        match fetch_artifacts(config, &cave, lock.as_ref()) {
+            Ok(count) => log::info!("[{}] {} artifact(s) downloaded", cave.name, count),
+            Err(e) => {
+                log::error!("[{}] fetching artifacts failed: {:#}", cave.name, e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Resolves the cave and each variant and downloads what their builds
+/// fetch, expecting the hashes the lock records where recipes have none.
+fn fetch_artifacts(config: &Config, cave: &Cave, lock: Option<&CaveLock>) -> anyhow::Result<usize> {
+    let hashes: HashMap<&str, &str> = lock.iter()
+        .flat_map(|l| l.variants.values().flatten())
+        .flat_map(|p| &p.artifacts)
+        .filter_map(|a| Some((a.url.as_str(), a.sha256.as_deref()?)))
+        .collect();
+    let mut variants: Vec<Option<String>> = vec![None];
+    variants.extend(cave.variants.keys().map(|v| Some(format!(":{}", v))));
+
+    let mut count = 0;
+    for variant in &variants {
+        let settings = cave.get_effective_settings(variant.as_deref())?;
+        let graph = resolve_graph(config, &settings.packages, &settings.options)
+            .with_context(|| format!("Failed to resolve {}{}", cave.name, variant.as_deref().unwrap_or("")))?;
+        count += prefetch(config, &graph, |url| hashes.get(url).map(|h| h.to_string()))?;
+    }
+    Ok(count)
+}
@@ -1,10 +1,11 @@
+use crate::commands::cave::fs::ExportManifest;
 use crate::models::config::Config;
 use crate::models::cave::Cave;
 use std::env;
 
 pub fn run(config: &Config) {
     let current_dir = env::current_dir().expect("Failed to get current directory");
-    if let Some((path, cave)) = Cave::find_in_ancestry(&current_dir) {
+    if let Some((path, cave)) = Cave::find(config, &current_dir) {
         let active_status = if config.is_inside_cave() { " (ACTIVE)" } else { "" };
         println!("name: {}{}", cave.name, active_status);
         println!("file: {}", path.display());
@@ -16,10 +17,35 @@ pub fn run(config: &Config) {
         println!("  set:  {:?}", cave.settings.set);
         println!("  uns:  {:?}", cave.settings.unset);
 
-        if !cave.variants.is_empty() {
+        // Recorded by the last build of the default variant.
+        match ExportManifest::load(&config.pilocal_path(&cave.name, None)) {
+            Ok(manifest) => {
+                if !manifest.rewrites.is_empty() {
+                    println!("\nrenamed links:");
+                    for rewrite in manifest.rewrites {
+                        println!("  {} -> {} ({})", rewrite.from.display(), rewrite.to.display(), rewrite.package);
+                    }
+                }
+                if !manifest.chmods.is_empty() {
+                    println!("\nlink modes:");
+                    for chmod in manifest.chmods {
+                        println!("  {} {} ({})", chmod.mode, chmod.path.display(), chmod.package);
+                    }
+                }
+            }
+            Err(e) => log::warn!("[{}] {:#}", cave.name, e),
+        }
+
+        let names = cave.variant_names();
+        if !names.is_empty() {
             println!("\nvariants:");
-            for (name, settings) in &cave.variants {
-                println!("  :{}", name);
+            for name in names {
+                let Some(settings) = cave.variant(&name) else { continue };
+                let shared = if cave.shared_variants.contains_key(&name) { " (shared)" } else { "" };
+                println!("  :{}{}", name, shared);
+                if !settings.extends.is_empty() {
+                    println!("    extends: {}", settings.extends.iter().map(|e| format!(":{}", e)).collect::<Vec<_>>().join(", "));
+                }
                 println!("    pkgs: {:?}", settings.packages);
                 println!("    set:  {:?}", settings.set);
                 println!("    uns:  {:?}", settings.unset);
@@ -4,5 +4,16 @@ pub mod add;
 pub mod rem;
 pub mod resolve;
 pub mod build;
+pub mod report;
+pub mod watch;
 pub mod run;
+pub mod shell;
+pub mod shell_hook;
+pub mod shims;
+pub mod service;
 pub mod fs;
+pub mod export;
+pub mod devcontainer;
+pub mod doctor;
+pub mod import;
+pub mod status;
@@ -2,7 +2,7 @@ use crate::models::config::Config;
 use crate::models::cave::Cave;
 use std::env;
 
-pub fn run(_config: &Config, args: Vec<String>) {
+pub fn run(config: &Config, args: Vec<String>) {
     if args.is_empty() {
         return;
     }
@@ -19,7 +19,7 @@ pub fn run(_config: &Config, args: Vec<String>) {
     }
 
     let current_dir = env::current_dir().expect("Failed to get current directory");
-    let (path, mut cave) = match Cave::find_in_ancestry(&current_dir) {
+    let (path, mut cave) = match Cave::find(config, &current_dir) {
         Some(res) => res,
         None => {
             log::error!("no cave found");
@@ -0,0 +1,128 @@
+use crate::commands::package::build::{BuiltPackage, PackageStats};
+use crate::utils::duration::format_duration;
+use crate::utils::size::format_size;
+use crate::utils::theme::Theme;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::Path;
+
+/// `pi cave build --report`: where the build summary also goes.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ReportFormat {
+    Json,
+}
+
+/// What a `pi cave build` did, printed as a table and written by `--report`.
+#[derive(Debug, Serialize)]
+pub struct BuildReport {
+    pub cave: String,
+    pub variant: Option<String>,
+    /// The cached environment was still valid, nothing was resolved.
+    pub up_to_date: bool,
+    pub packages: Vec<PackageReport>,
+    pub download_bytes: u64,
+    /// Total size of the packages' outputs.
+    pub install_bytes: u64,
+    pub duration_ms: u128,
+    pub warnings: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PackageReport {
+    pub package: String,
+    pub version: String,
+    #[serde(flatten)]
+    pub stats: PackageStats,
+}
+
+impl BuildReport {
+    pub fn new(cave: &str, variant: Option<&str>, packages: Option<&[BuiltPackage]>, duration_ms: u128, warnings: usize) -> Self {
+        let up_to_date = packages.is_none();
+        let packages: Vec<PackageReport> = packages.unwrap_or_default().iter()
+            .map(|p| PackageReport { package: p.pkgname.clone(), version: p.version.clone(), stats: p.stats.clone() })
+            .collect();
+        Self {
+            cave: cave.to_string(),
+            variant: variant.map(|v| v.to_string()),
+            up_to_date,
+            download_bytes: packages.iter().map(|p| p.stats.download_bytes).sum(),
+            install_bytes: packages.iter().map(|p| p.stats.install_bytes).sum(),
+            packages,
+            duration_ms,
+            warnings,
+        }
+    }
+
+    /// One row per package in build order, then the totals.
+    pub fn print(&self) {
+        if self.up_to_date {
+            Theme::current().line(&format!("{}: up to date ({} warnings)", self.cave, self.warnings));
+            return;
+        }
+        let mut table = Theme::current().table(&["Package", "Version", "Result", "Downloaded", "Installed", "Files", "Time", "Exports"]);
+        for p in &self.packages {
+            table.add_row(vec![
+                p.package.clone(),
+                p.version.clone(),
+                if p.stats.built { "built" } else { "cached" }.to_string(),
+                format_size(p.stats.download_bytes),
+                format_size(p.stats.install_bytes),
+                p.stats.install_files.to_string(),
+                format_duration(p.stats.duration_ms),
+                p.stats.exports.to_string(),
+            ]);
+        }
+        Theme::current().print(&table);
+        let built = self.packages.iter().filter(|p| p.stats.built).count();
+        Theme::current().line(&format!(
+            "{} built, {} cached, {} downloaded, {} installed in {}, {} warnings",
+            built,
+            self.packages.len() - built,
+            format_size(self.download_bytes),
+            format_size(self.install_bytes),
+            format_duration(self.duration_ms),
+            self.warnings,
+        ));
+    }
+
+    pub fn write(&self, format: ReportFormat, path: &Path) -> Result<()> {
+        let content = match format {
+            ReportFormat::Json => serde_json::to_string_pretty(self)? + "\n",
+        };
+        std::fs::write(path, content).with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_build_report() {
+        let package = |name: &str, built: bool, download_bytes: u64| BuiltPackage {
+            pkgname: name.to_string(),
+            version: "1.0".to_string(),
+            root: PathBuf::from("/p"),
+            stats: PackageStats { built, download_bytes, duration_ms: 1500, exports: 2, install_bytes: 4096, install_files: 3 },
+        };
+        let packages = vec![package("go", true, 2048), package("node", false, 0)];
+        let report = BuildReport::new("web", Some(":dev"), Some(&packages), 3000, 1);
+        assert!(!report.up_to_date);
+        assert_eq!(report.download_bytes, 2048);
+        assert_eq!(report.install_bytes, 8192);
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("report.json");
+        report.write(ReportFormat::Json, &path).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["packages"][0]["package"], "go");
+        assert_eq!(json["packages"][0]["built"], true);
+        assert_eq!(json["packages"][1]["download_bytes"], 0);
+        assert_eq!(json["warnings"], 1);
+
+        assert!(BuildReport::new("web", None, None, 5, 0).up_to_date);
+        assert_eq!(format_duration(61_000), "1m01s");
+        assert_eq!(format_duration(1500), "1.5s");
+    }
+}
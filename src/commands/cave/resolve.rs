@@ -1,16 +1,14 @@
 use crate::models::config::Config;
-use crate::models::cave::Cave;
-use crate::models::selector::PackageSelector;
-use crate::models::repository::Repositories;
+use crate::models::cave::{Cave, CaveSettings};
+use crate::commands::package::build::resolve_graph;
 use crate::commands::package::resolve;
+use crate::models::cave_sums::CaveSums;
+use crate::models::lockfile::{locked_packages, CaveLock};
 use std::env;
-use rayon::prelude::*;
-use comfy_table::presets::NOTHING;
-use comfy_table::Table;
 
 pub fn run(config: &Config, variant: Option<String>) {
     let current_dir = env::current_dir().expect("Failed to get current directory");
-    let (_path, cave) = match Cave::find_in_ancestry(&current_dir) {
+    let (_path, cave) = match Cave::find(config, &current_dir) {
         Some(res) => res,
         None => {
             log::error!("no cave found");
@@ -28,28 +26,20 @@ pub fn run(config: &Config, variant: Option<String>) {
 
     log::info!("resolving cave: {} (var: {:?})", cave.name, variant);
 
-    let repo_config = Repositories::get_all(config);
+    let results = resolve::resolve_rows(config, &settings.packages);
+    resolve::print_resolution_table(config, results);
 
-    let results: Vec<(String, String, String)> = settings.packages
-        .par_iter()
-        .map(|query| {
-            let selector = match PackageSelector::parse(query) {
-                Some(s) => s,
-                None => return (query.clone(), "Invalid selector".to_string(), "-".to_string()),
-            };
-
-            match resolve::resolve_query(config, repo_config, &selector) {
-                Some((full_name, version, _repo_name)) => (query.clone(), full_name, version.release_date),
-                None => (query.clone(), "Not found".to_string(), "-".to_string()),
-            }
-        })
-        .collect();
+    if let Err(e) = update_lock(config, &cave, variant.as_deref(), &settings) {
+        log::warn!("[{}] failed to update {}: {:#}", cave.name, CaveLock::FILENAME, e);
+    }
+}
 
-    let mut table = Table::new();
-    table.load_preset(NOTHING);
-    table.set_header(vec!["Query", "Resolved Full Name", "Release Date"]);
-    for (query, full_name, date) in results {
-        table.add_row(vec![query, full_name, date]);
+/// Writes what the cave and its build dependencies resolve to into `pi.cave.lock`.
+fn update_lock(config: &Config, cave: &Cave, variant: Option<&str>, settings: &CaveSettings) -> anyhow::Result<()> {
+    let graph = resolve_graph(config, &settings.packages, &settings.options)?;
+    let sums = CaveSums::load(&cave.workspace)?;
+    if CaveLock::update(&cave.workspace, variant, locked_packages(&graph, Some(&sums)))? {
+        log::info!("[{}] updated {}", cave.name, CaveLock::FILENAME);
     }
-    println!("{table}");
+    Ok(())
 }
@@ -1,18 +1,36 @@
 use crate::models::config::Config;
 use crate::models::cave::Cave;
+use crate::services::events::{self, Event};
 use crate::services::sandbox::{Bubblewrap, BindType};
+use crate::utils::subst::PathVars;
 use std::env;
 use std::path::{Path, PathBuf};
 use anyhow::{Context, Result};
 use std::collections::HashMap;
 
-pub fn run(config: &Config, variant: Option<String>, command: Vec<String>) {
-    if let Err(e) = execute_run(config, variant, command) {
-        log::error!("run failed: {}", e);
+pub fn run(config: &Config, variant: Option<String>, command: Vec<String>, bind_cwd_only: bool) {
+    let scope = if bind_cwd_only {
+        BindScope::CwdOnly(env::current_dir().expect("Failed to get current directory"))
+    } else {
+        BindScope::Workspace
+    };
+    if let Err(e) = execute_run(config, variant, command, scope) {
+        log::error!("run failed: {:#}", e);
         std::process::exit(1);
     }
 }
 
+/// How much of the host filesystem the sandbox exposes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindScope {
+    /// The whole workspace and the cave home are writable.
+    Workspace,
+    /// Minimal exposure for untrusted tooling: only this directory is writable.
+    /// Home is an empty tmpfs, the pilocal and packages are read-only, and
+    /// pi config, caches, custom binds and XDG_RUNTIME_DIR are absent.
+    CwdOnly(PathBuf),
+}
+
 /// Options for preparing the sandbox environment.
 pub struct SandboxOptions<'a> {
     pub config: &'a Config,
@@ -22,6 +40,7 @@ pub struct SandboxOptions<'a> {
     pub writable_pilocal: bool,
     pub readonly_home: bool,
     pub dependency_dirs: Vec<PathBuf>,
+    pub scope: BindScope,
 }
 
 /// Prepares the Bubblewrap sandbox with necessary binds and environment variables.
@@ -36,23 +55,81 @@ pub fn prepare_sandbox(opts: SandboxOptions) -> Result<Bubblewrap> {
 
     bind_system_paths(&mut b);
     bind_virtual_fs(&mut b);
-    bind_workspace_and_home(&mut b, opts.config, opts.cave, &host_home, opts.readonly_home)?;
-    bind_pilocal_and_caches(&mut b, opts.config, opts.cave, opts.variant, opts.writable_pilocal, &internal_pilocal)?;
-    setup_xdg_runtime(&mut b);
+    bind_workspace_and_home(&mut b, opts.cave, &host_home, opts.readonly_home, &opts.scope)?;
+    bind_pilocal_and_caches(&mut b, opts.config, opts.cave, opts.variant, opts.writable_pilocal, &internal_pilocal, &opts.scope)?;
 
     bind_dependencies(&mut b, &opts.dependency_dirs);
 
-    apply_custom_binds(&mut b, &settings.binds);
+    if opts.scope == BindScope::Workspace {
+        setup_xdg_runtime(&mut b);
+        apply_custom_binds(&mut b, &settings.binds);
+    } else if !settings.binds.is_empty() {
+        log::info!("[{}] cwd-only mode, skipping {} custom binds", opts.cave.name, settings.binds.len());
+    }
 
-    setup_environment(&mut b, opts.config, opts.cave, &host_home, &internal_pilocal);
+    setup_environment(&mut b, opts.config, opts.cave, opts.variant, &host_home, &internal_pilocal);
 
-    apply_custom_envs(&mut b, opts.package_envs, &settings.set, &host_home, &internal_pilocal);
+    let paths = PathVars {
+        pilocal: Some(&internal_pilocal),
+        home: Some(&host_home),
+        workspace: Some(&opts.cave.workspace),
+        packages: Some(&opts.config.cache_packages_dir),
+    };
+    apply_custom_envs(&mut b, opts.package_envs, &settings.set, &paths);
 
     set_sandbox_hostname(&mut b, opts.config, opts.cave, opts.variant);
 
+    bind_hosts_file(&mut b, opts.config, opts.cave, opts.variant, &settings.hosts)?;
+
     Ok(b)
 }
 
+/// Binds a synthetic /etc/hosts carrying the cave's `hosts` overrides ahead of the
+/// host's own entries. The host file itself is never modified.
+fn bind_hosts_file(
+    b: &mut Bubblewrap,
+    config: &Config,
+    cave: &Cave,
+    variant: Option<&str>,
+    hosts: &HashMap<String, String>,
+) -> Result<()> {
+    if hosts.is_empty() {
+        return Ok(());
+    }
+    let content = render_hosts(hosts, &std::fs::read_to_string("/etc/hosts").unwrap_or_default())
+        .with_context(|| format!("invalid hosts entry in cave {}", cave.name))?;
+
+    let name = match variant {
+        Some(v) => format!("{}{}", cave.name, v),
+        None => cave.name.clone(),
+    };
+    let hosts_dir = config.state_dir.join("hosts");
+    std::fs::create_dir_all(&hosts_dir).context("Failed to create hosts directory")?;
+    let hosts_file = hosts_dir.join(name);
+    std::fs::write(&hosts_file, content)
+        .with_context(|| format!("Failed to write hosts file: {}", hosts_file.display()))?;
+
+    b.add_map_bind(BindType::RoBind, &hosts_file, "/etc/hosts");
+    Ok(())
+}
+
+fn render_hosts(hosts: &HashMap<String, String>, host_etc_hosts: &str) -> Result<String> {
+    let mut entries: Vec<_> = hosts.iter().collect();
+    entries.sort();
+
+    let mut out = String::from("# generated by pi: cave hosts overrides\n");
+    for (host, addr) in entries {
+        let ip: std::net::IpAddr = addr.parse().with_context(|| format!("{}: bad address {:?}", host, addr))?;
+        if host.is_empty() || host.contains(char::is_whitespace) {
+            anyhow::bail!("bad hostname {:?}", host);
+        }
+        out.push_str(&format!("{}\t{}\n", ip, host));
+    }
+    out.push_str("\n# from host /etc/hosts\n");
+    out.push_str(host_etc_hosts);
+    Ok(out)
+}
+
 fn bind_dependencies(b: &mut Bubblewrap, dependency_dirs: &[PathBuf]) {
     for dir in dependency_dirs {
         if dir.exists() {
@@ -109,7 +186,14 @@ fn bind_virtual_fs(b: &mut Bubblewrap) {
     b.add_virtual(BindType::Tmpfs, "/run");
 }
 
-fn bind_workspace_and_home(b: &mut Bubblewrap, _config: &Config, cave: &Cave, host_home: &Path, readonly_home: bool) -> Result<()> {
+fn bind_workspace_and_home(b: &mut Bubblewrap, cave: &Cave, host_home: &Path, readonly_home: bool, scope: &BindScope) -> Result<()> {
+    if let BindScope::CwdOnly(cwd) = scope {
+        b.add_virtual(BindType::Tmpfs, host_home);
+        b.add_bind(BindType::Bind, cwd);
+        b.set_cwd(cwd);
+        return Ok(());
+    }
+
     b.add_bind(BindType::Bind, &cave.workspace);
 
     if !cave.homedir.exists() {
@@ -143,7 +227,8 @@ fn bind_pilocal_and_caches(
     cave: &Cave,
     variant: Option<&str>,
     writable: bool,
-    internal_pilocal: &Path
+    internal_pilocal: &Path,
+    scope: &BindScope,
 ) -> Result<()> {
     let host_pilocal = config.pilocal_path(&cave.name, variant);
     if !host_pilocal.exists() {
@@ -152,6 +237,12 @@ fn bind_pilocal_and_caches(
     let bind_type = if writable { BindType::Bind } else { BindType::RoBind };
     b.add_map_bind(bind_type, &host_pilocal, internal_pilocal);
 
+    if matches!(scope, BindScope::CwdOnly(_)) {
+        // Exported links point into the packages dir, nothing else is needed.
+        b.add_bind(BindType::RoBind, &config.cache_packages_dir);
+        return Ok(());
+    }
+
     if config.cache_dir.exists() {
         b.add_bind(bind_type, &config.cache_dir);
     }
@@ -168,11 +259,15 @@ fn setup_xdg_runtime(b: &mut Bubblewrap) {
     }
 }
 
-fn setup_environment(b: &mut Bubblewrap, config: &Config, cave: &Cave, host_home: &Path, internal_pilocal: &Path) {
+fn setup_environment(b: &mut Bubblewrap, config: &Config, cave: &Cave, variant: Option<&str>, host_home: &Path, internal_pilocal: &Path) {
     b.set_env("HOME", host_home.to_str().unwrap());
     b.set_env("USER", &config.get_user());
     b.set_env("PI_WORKSPACE", cave.workspace.to_str().unwrap());
     b.set_env("PI_CAVE", &cave.name);
+    // pi inside the cave acts on the variant it was entered with.
+    if let Some(variant) = variant {
+        b.set_env("PI_CAVE_VARIANT", variant);
+    }
 
     let pilocal_bin = internal_pilocal.join("bin");
     b.add_env_first("PATH", "/usr/bin:/bin");
@@ -190,35 +285,28 @@ fn apply_custom_envs(
     b: &mut Bubblewrap,
     pkg_envs: HashMap<String, String>,
     cave_envs: &HashMap<String, String>,
-    host_home: &Path,
-    internal_pilocal: &Path
+    paths: &PathVars,
 ) {
-    let resolve = |v: String| {
-        v.replace("$/", &format!("{}/", internal_pilocal.display()))
-         .replace("$", internal_pilocal.to_str().unwrap())
-         .replace("@HOME", host_home.to_str().unwrap())
-    };
-
     for (k, v) in pkg_envs {
-        b.set_env(&k, &resolve(v));
+        b.set_env(&k, &paths.value(&v));
     }
     for (k, v) in cave_envs {
-        b.set_env(k, &resolve(v.clone()));
+        b.set_env(k, &paths.value(v));
     }
 }
 
-fn execute_run(config: &Config, variant_opt: Option<String>, command: Vec<String>) -> Result<()> {
+fn execute_run(config: &Config, variant_opt: Option<String>, command: Vec<String>, scope: BindScope) -> Result<()> {
     let current_dir = env::current_dir().expect("Failed to get current directory");
-    let (_path, cave) = Cave::find_in_ancestry(&current_dir).context("no cave found")?;
+    let (_path, cave) = Cave::find(config, &current_dir).context("no cave found")?;
 
     let (variant, final_command) = match variant_opt {
         Some(v) if v.starts_with(':') => (Some(v), command),
         Some(v) => {
             let mut new_cmd = vec![v];
             new_cmd.extend(command);
-            (None, new_cmd)
+            (config.cave_variant.clone(), new_cmd)
         }
-        None => (None, command),
+        None => (config.cave_variant.clone(), command),
     };
 
     let package_envs = crate::commands::cave::build::execute_build(config, &cave, variant.as_deref())?;
@@ -233,8 +321,13 @@ fn execute_run(config: &Config, variant_opt: Option<String>, command: Vec<String
         writable_pilocal: false,
         readonly_home: false,
         dependency_dirs: Vec::new(),
+        scope,
     })?;
 
+    events::publish(Event::RunStarted {
+        cave: cave.name.clone(),
+        command: final_command.clone(),
+    });
     log::info!("entering cave");
     if log::log_enabled!(log::Level::Info) {
         crate::commands::cave::info::run(config);
@@ -256,3 +349,62 @@ fn execute_run(config: &Config, variant_opt: Option<String>, command: Vec<String
 
     b.exec()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandbox_args(scope: BindScope, config: &Config, cave: &Cave) -> Vec<String> {
+        let b = prepare_sandbox(SandboxOptions {
+            config,
+            cave,
+            variant: None,
+            package_envs: HashMap::new(),
+            writable_pilocal: false,
+            readonly_home: false,
+            dependency_dirs: Vec::new(),
+            scope,
+        }).unwrap();
+        b.build_command().get_args().map(|a| a.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn test_bind_cwd_only_hides_workspace() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let workspace = tmp.path().join("ws");
+        let cwd = workspace.join("sub");
+        let mut cave = Cave::new(workspace.clone(), tmp.path().join("home"));
+        cave.settings.binds.push("/opt/secret".to_string());
+
+        let full = sandbox_args(BindScope::Workspace, &config, &cave).join(" ");
+        assert!(full.contains(&format!("--bind {} {}", workspace.display(), workspace.display())));
+
+        let minimal = sandbox_args(BindScope::CwdOnly(cwd.clone()), &config, &cave).join(" ");
+        assert!(minimal.contains(&format!("--bind {} {}", cwd.display(), cwd.display())));
+        assert!(minimal.contains(&format!("--chdir {}", cwd.display())));
+        assert!(!minimal.contains(&format!("--bind {} {}", workspace.display(), workspace.display())));
+        assert!(!minimal.contains(&config.config_dir.display().to_string()));
+        assert!(!minimal.contains("/opt/secret"));
+    }
+
+    #[test]
+    fn test_hosts_overrides() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let mut cave = Cave::new(tmp.path().join("ws"), tmp.path().join("home"));
+        assert!(!sandbox_args(BindScope::Workspace, &config, &cave).join(" ").contains(" /etc/hosts"));
+
+        cave.settings.hosts.insert("api.example.com".to_string(), "10.0.0.5".to_string());
+        let args = sandbox_args(BindScope::Workspace, &config, &cave).join(" ");
+        assert!(args.contains(" /etc/hosts"));
+
+        let rendered = render_hosts(&cave.settings.hosts, "127.0.0.1\tlocalhost\n").unwrap();
+        let first_entry = rendered.lines().nth(1).unwrap();
+        assert_eq!(first_entry, "10.0.0.5\tapi.example.com");
+        assert!(rendered.ends_with("127.0.0.1\tlocalhost\n"));
+
+        cave.settings.hosts.insert("bad".to_string(), "not-an-ip".to_string());
+        assert!(render_hosts(&cave.settings.hosts, "").is_err());
+    }
+}
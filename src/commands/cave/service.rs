@@ -0,0 +1,257 @@
+use crate::commands::cave::fs::{ExportManifest, ExportService};
+use crate::commands::cave::run::{prepare_sandbox, BindScope, SandboxOptions};
+use crate::models::cave::Cave;
+use crate::models::config::Config;
+use crate::utils::theme::Theme;
+use anyhow::{Context, Result};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+/// How long `stop` waits after SIGTERM before it sends SIGKILL.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `pi cave service <action>`.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ServiceAction {
+    /// Build the cave and start its services in the background
+    Start,
+    /// Stop running services
+    Stop,
+    /// List the services with their pid and log
+    Status,
+}
+
+pub fn run(config: &Config, action: ServiceAction, args: Vec<String>) {
+    if let Err(e) = execute(config, action, args) {
+        log::error!("service failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn execute(config: &Config, action: ServiceAction, args: Vec<String>) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let (_path, cave) = Cave::find(config, &current_dir).context("no cave found")?;
+    let (variant, names) = match args.split_first() {
+        Some((first, rest)) if first.starts_with(':') => (Some(first.as_str()), rest),
+        _ => (None, args.as_slice()),
+    };
+
+    let package_envs = match action {
+        ServiceAction::Start => crate::commands::cave::build::execute_build(config, &cave, variant)?,
+        _ => Default::default(),
+    };
+    let manifest = ExportManifest::load(&config.pilocal_path(&cave.name, variant))?;
+    let services = select(&manifest.services, names)?;
+    if services.is_empty() {
+        log::info!("[{}] no package of the cave exports a service", cave.name);
+        return Ok(());
+    }
+    let dir = services_dir(config, &cave.name);
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    match action {
+        ServiceAction::Start => {
+            for service in services {
+                if let Some(pid) = running_pid(&dir, &service.name) {
+                    log::info!("[{}] {} is already running (pid {})", cave.name, service.name, pid);
+                    continue;
+                }
+                let mut envs = package_envs.clone();
+                envs.extend(service.env.clone());
+                let pid = start(config, &cave, variant, service, envs, &dir)
+                    .with_context(|| format!("failed to start {}", service.name))?;
+                log::info!("[{}] started {} (pid {}), log {}", cave.name, service.name, pid, log_file(&dir, &service.name).display());
+            }
+        }
+        ServiceAction::Stop => {
+            for service in services {
+                match running_pid(&dir, &service.name) {
+                    Some(pid) => {
+                        stop(pid).with_context(|| format!("failed to stop {}", service.name))?;
+                        log::info!("[{}] stopped {}", cave.name, service.name);
+                    }
+                    None => log::info!("[{}] {} is not running", cave.name, service.name),
+                }
+                let _ = fs::remove_file(pid_file(&dir, &service.name));
+            }
+        }
+        ServiceAction::Status => {
+            let mut table = Theme::current().table(&["Service", "Package", "State", "Pid", "Log"]);
+            for service in services {
+                let pid = running_pid(&dir, &service.name);
+                table.add_row(vec![
+                    service.name.clone(),
+                    service.package.clone(),
+                    if pid.is_some() { "running" } else { "stopped" }.to_string(),
+                    pid.map_or_else(|| "-".to_string(), |p| p.to_string()),
+                    log_file(&dir, &service.name).display().to_string(),
+                ]);
+            }
+            Theme::current().print(&table);
+        }
+    }
+    Ok(())
+}
+
+/// The services named in `names`, all of them when empty.
+fn select<'a>(services: &'a [ExportService], names: &[String]) -> Result<Vec<&'a ExportService>> {
+    if names.is_empty() {
+        return Ok(services.iter().collect());
+    }
+    names.iter()
+        .map(|name| services.iter().find(|s| &s.name == name)
+            .with_context(|| format!("unknown service '{}', see `pi cave service status`", name)))
+        .collect()
+}
+
+/// Pid files and logs of a cave's services.
+fn services_dir(config: &Config, cave: &str) -> PathBuf {
+    config.state_dir.join("services").join(cave)
+}
+
+fn pid_file(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.pid", name))
+}
+
+fn log_file(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.log", name))
+}
+
+/// The pid of the service when its pid file names a live process that
+/// started when the service did: a pid reused by another process after the
+/// service died is never signalled.
+fn running_pid(dir: &Path, name: &str) -> Option<i32> {
+    let content = fs::read_to_string(pid_file(dir, name)).ok()?;
+    let (pid, started) = content.trim().split_once(' ')?;
+    let pid: i32 = pid.parse().ok()?;
+    let started: u64 = started.parse().ok()?;
+    (pid > 0 && unsafe { libc::kill(pid, 0) } == 0 && start_time(pid) == Some(started)).then_some(pid)
+}
+
+/// Writes the pid file: the pid and the process's start time.
+fn record_pid(dir: &Path, name: &str, pid: i32) -> Result<()> {
+    let started = start_time(pid).with_context(|| format!("Failed to read the start time of pid {}", pid))?;
+    fs::write(pid_file(dir, name), format!("{} {}\n", pid, started))?;
+    Ok(())
+}
+
+/// When `pid` started, in clock ticks after boot (field 22 of
+/// /proc/<pid>/stat); the pair (pid, start time) is unique.
+fn start_time(pid: i32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may hold spaces and parentheses; fields resume after the last ')'.
+    let fields = stat.rsplit_once(')')?.1;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// Spawns the service in the cave sandbox, detached in a process group of
+/// its own and with output appended to its log; returns its pid.
+fn start(
+    config: &Config,
+    cave: &Cave,
+    variant: Option<&str>,
+    service: &ExportService,
+    package_envs: std::collections::HashMap<String, String>,
+    dir: &Path,
+) -> Result<i32> {
+    let mut b = prepare_sandbox(SandboxOptions {
+        config,
+        cave,
+        variant,
+        package_envs,
+        writable_pilocal: false,
+        readonly_home: false,
+        dependency_dirs: Vec::new(),
+        scope: BindScope::Workspace,
+    })?;
+    // The service outlives this pi process.
+    b.remove_flag("--die-with-parent");
+    b.set_cwd(&cave.workspace);
+    b.set_command("sh", &["-c".to_string(), service.command.clone()]);
+
+    let log_path = log_file(dir, &service.name);
+    let mut log = fs::OpenOptions::new().create(true).append(true).open(&log_path)
+        .with_context(|| format!("Failed to open {}", log_path.display()))?;
+    writeln!(log, "--- {} started {} ---", service.name, chrono::Local::now().to_rfc3339())?;
+
+    let mut cmd = b.build_command();
+    cmd.stdin(Stdio::null()).stdout(log.try_clone()?).stderr(log).process_group(0);
+    log::debug!("Spawning service: {:?}", cmd);
+    let mut child = cmd.spawn().context("Failed to spawn bubblewrap process")?;
+    let pid = child.id() as i32;
+    record_pid(dir, &service.name, pid)?;
+
+    // Catch commands failing right away, rather than reporting them as started.
+    std::thread::sleep(Duration::from_millis(300));
+    if let Some(status) = child.try_wait()? {
+        let _ = fs::remove_file(pid_file(dir, &service.name));
+        anyhow::bail!("exited with {}, see {}", status, log_path.display());
+    }
+    Ok(pid)
+}
+
+/// Sends SIGTERM to the service's process group, then SIGKILL when it is
+/// still alive after STOP_TIMEOUT.
+fn stop(pid: i32) -> Result<()> {
+    if unsafe { libc::kill(-pid, libc::SIGTERM) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("SIGTERM");
+    }
+    let start = Instant::now();
+    while unsafe { libc::kill(pid, 0) } == 0 {
+        if start.elapsed() > STOP_TIMEOUT {
+            unsafe { libc::kill(-pid, libc::SIGKILL) };
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_and_running_pid() {
+        let service = |name: &str| ExportService {
+            package: "db".to_string(),
+            name: name.to_string(),
+            command: "true".to_string(),
+            env: Default::default(),
+        };
+        let services = vec![service("postgres"), service("redis")];
+        assert_eq!(select(&services, &[]).unwrap().len(), 2);
+        assert_eq!(select(&services, &["redis".to_string()]).unwrap()[0].name, "redis");
+        assert!(select(&services, &["mysql".to_string()]).is_err());
+
+        let tmp = tempfile::tempdir().unwrap();
+        assert_eq!(running_pid(tmp.path(), "redis"), None);
+        record_pid(tmp.path(), "redis", std::process::id() as i32).unwrap();
+        assert_eq!(running_pid(tmp.path(), "redis"), Some(std::process::id() as i32));
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        fs::write(pid_file(tmp.path(), "redis"), child.id().to_string()).unwrap();
+        assert_eq!(running_pid(tmp.path(), "redis"), None);
+    }
+
+    #[test]
+    fn test_reused_pid_is_not_the_service() {
+        let tmp = tempfile::tempdir().unwrap();
+        let pid = std::process::id() as i32;
+        let started = start_time(pid).unwrap();
+        assert!(started > 0);
+        // A live pid that started at another time belongs to another process.
+        fs::write(pid_file(tmp.path(), "redis"), format!("{} {}\n", pid, started + 1)).unwrap();
+        assert_eq!(running_pid(tmp.path(), "redis"), None);
+        // Pid files without a start time can't be trusted either.
+        fs::write(pid_file(tmp.path(), "redis"), format!("{}\n", pid)).unwrap();
+        assert_eq!(running_pid(tmp.path(), "redis"), None);
+        fs::write(pid_file(tmp.path(), "redis"), format!("{} {}\n", pid, started)).unwrap();
+        assert_eq!(running_pid(tmp.path(), "redis"), Some(pid));
+    }
+}
@@ -0,0 +1,87 @@
+use crate::commands::cave::build::execute_build;
+use crate::commands::cave::run::{prepare_sandbox, BindScope, SandboxOptions};
+use crate::models::cave::Cave;
+use crate::models::config::Config;
+use crate::services::events::{self, Event};
+use anyhow::{Context, Result};
+use std::env;
+use std::path::Path;
+
+/// Commands kept in the history file of a cave shell.
+const HISTORY_SIZE: usize = 10000;
+
+pub fn run(config: &Config, variant: Option<String>) {
+    // `--variant dev` and `--variant :dev` name the same variant.
+    let variant = variant.map(|v| if v.starts_with(':') { v } else { format!(":{}", v) });
+    if let Err(e) = execute(config, variant.as_deref()) {
+        log::error!("shell failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Builds the cave, then replaces pi with an interactive bash in its sandbox.
+/// The rc file and history live in the cave's homedir, which is the sandbox
+/// home, so history survives between sessions.
+fn execute(config: &Config, variant: Option<&str>) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let (_, cave) = Cave::find(config, &current_dir).context("no cave found")?;
+    let package_envs = execute_build(config, &cave, variant)?;
+
+    let mut b = prepare_sandbox(SandboxOptions {
+        config,
+        cave: &cave,
+        variant,
+        package_envs,
+        writable_pilocal: false,
+        readonly_home: false,
+        dependency_dirs: Vec::new(),
+        scope: BindScope::Workspace,
+    })?;
+
+    let host_home = config.get_host_home();
+    let suffix = variant.map(|v| format!(".{}", v.trim_start_matches(':'))).unwrap_or_default();
+    let history = format!(".pi_history{}", suffix);
+    let rc_name = format!(".pi_shellrc{}", suffix);
+    let rc = shell_rc(&cave.name, variant, &host_home.join(&history));
+    std::fs::write(cave.homedir.join(&rc_name), rc)
+        .with_context(|| format!("Failed to write {}", cave.homedir.join(&rc_name).display()))?;
+
+    let rc_path = host_home.join(&rc_name).display().to_string();
+    b.set_command("/bin/bash", &["--rcfile".to_string(), rc_path, "-i".to_string()]);
+    events::publish(Event::RunStarted { cave: cave.name.clone(), command: vec!["shell".to_string()] });
+    log::info!("[{}] entering shell, history in {}", cave.name, cave.homedir.join(&history).display());
+    b.exec()
+}
+
+/// The bash rc of a cave shell: the cave home's own `.bashrc` first, then
+/// history settings and a prompt naming the cave, which the user's rc can't
+/// override by accident.
+fn shell_rc(cave: &str, variant: Option<&str>, history: &Path) -> String {
+    let label = format!("{}{}", cave, variant.unwrap_or(""));
+    format!(
+        "[ -f ~/.bashrc ] && . ~/.bashrc\n\
+         HISTFILE='{history}'\n\
+         HISTSIZE={size}\n\
+         HISTFILESIZE={size}\n\
+         shopt -s histappend\n\
+         PROMPT_COMMAND=\"history -a${{PROMPT_COMMAND:+; $PROMPT_COMMAND}}\"\n\
+         PS1='\\[\\e[1;35m\\](pi:{label})\\[\\e[0m\\] \\w \\$ '\n",
+        history = history.display(),
+        size = HISTORY_SIZE,
+        label = label,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_rc() {
+        let rc = shell_rc("web", Some(":dev"), Path::new("/home/me/.pi_history.dev"));
+        assert!(rc.starts_with("[ -f ~/.bashrc ] && . ~/.bashrc\n"));
+        assert!(rc.contains("HISTFILE='/home/me/.pi_history.dev'\n"));
+        assert!(rc.contains("PROMPT_COMMAND=\"history -a${PROMPT_COMMAND:+; $PROMPT_COMMAND}\""));
+        assert!(rc.contains("(pi:web:dev)"));
+    }
+}
@@ -0,0 +1,324 @@
+use crate::models::cave::Cave;
+use crate::models::cave_trust::CaveTrust;
+use crate::models::config::Config;
+use crate::utils::subst::PathVars;
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::path::Path;
+
+/// Values the current activation replaced (None: was unset), JSON-encoded, so
+/// `pi cave env --deactivate` can put them back.
+const RESTORE_VAR: &str = "_PI_CAVE_RESTORE";
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// Variable assignments to emit, None meaning unset.
+type EnvChanges = Vec<(String, Option<String>)>;
+
+/// `pi cave shell-hook <shell>`: prints a hook that notices entering and
+/// leaving a directory with `pi.cave.json`. With `auto` it applies the cave's
+/// cached environment to the shell once `pi cave allow` allowed the cave,
+/// otherwise it only prints a hint.
+pub fn run_hook(shell: Shell, auto: bool) {
+    let pi = env::current_exe()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "pi".to_string());
+    print!("{}", hook_script(shell, &pi, auto));
+}
+
+/// `pi cave env`: prints statements applying the cave's environment to the
+/// calling shell, or undoing the last activation with `deactivate`. From the
+/// hook, a cave not allowed by `pi cave allow` changes nothing.
+pub fn run_env(config: &Config, variant: Option<String>, shell: Shell, deactivate: bool, hook: bool) {
+    let saved = saved_values();
+    let result = if deactivate {
+        Ok(deactivation(&saved))
+    } else {
+        activate(config, variant.as_deref(), &saved, hook)
+    };
+    match result {
+        Ok(changes) => print!("{}", render(shell, &changes)),
+        Err(e) => {
+            log::error!("cave env failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `pi cave allow`: lets the shell hook apply the environment of the cave in
+/// the current directory, as its `pi.cave.json` is now.
+pub fn run_allow(config: &Config, revoke: bool) {
+    let result = env::current_dir()
+        .context("Failed to get current directory")
+        .and_then(|dir| Cave::find(config, &dir).context("no cave found"))
+        .and_then(|(cave_file, cave)| {
+            if !revoke {
+                CaveTrust::allow(&config.state_dir, &cave_file)?;
+                log::info!("[{}] allowed {}", cave.name, cave_file.display());
+            } else if CaveTrust::revoke(&config.state_dir, &cave_file)? {
+                log::info!("[{}] no longer allowed", cave.name);
+            } else {
+                log::info!("[{}] was not allowed", cave.name);
+            }
+            Ok(())
+        });
+    if let Err(e) = result {
+        log::error!("cave allow failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn activate(config: &Config, variant: Option<&str>, saved: &BTreeMap<String, Option<String>>, hook: bool) -> Result<EnvChanges> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let (cave_file, cave) = Cave::find(config, &current_dir).context("no cave found")?;
+    if hook && !CaveTrust::is_allowed(&config.state_dir, &cave_file) {
+        // Its `set` would reach the host shell (BASH_ENV, PROMPT_COMMAND, LD_PRELOAD...).
+        log::warn!("[{}] {} is not allowed to change this shell; review it, then run `pi cave allow`", cave.name, cave_file.display());
+        return Ok(Vec::new());
+    }
+    let variant = variant.filter(|v| v.starts_with(':'));
+    let settings = cave.get_effective_settings(variant)?;
+
+    let pilocal = config.pilocal_path(&cave.name, variant);
+    let package_envs = cached_package_envs(config, &cave_file, &cave, variant)?;
+
+    let home = config.get_host_home();
+    let paths = host_path_vars(config, &cave, &pilocal, &home);
+    let mut vars = BTreeMap::new();
+    for (k, v) in package_envs.into_iter().chain(settings.set) {
+        vars.insert(k, Some(paths.value(&v)));
+    }
+    for k in settings.unset {
+        vars.insert(k, None);
+    }
+    vars.insert("PI_ACTIVE_CAVE".to_string(), Some(cave.name.clone()));
+
+    let original = |k: &str| saved.get(k).cloned().unwrap_or_else(|| env::var(k).ok());
+    let path = match original("PATH") {
+        Some(p) if !p.is_empty() => format!("{}:{}", pilocal.join("bin").display(), p),
+        _ => pilocal.join("bin").display().to_string(),
+    };
+    vars.insert("PATH".to_string(), Some(path));
+
+    Ok(activation(vars, original, saved))
+}
+
+/// Package env exports of the last `pi cave build` (its `env.json`), without
+/// building; warns when `pi.cave.json` changed since.
+pub fn cached_package_envs(config: &Config, cave_file: &Path, cave: &Cave, variant: Option<&str>) -> Result<HashMap<String, String>> {
+    let env_file = config.pilocal_path(&cave.name, variant).join("env.json");
+    let content = std::fs::read_to_string(&env_file)
+        .with_context(|| format!("cave {} is not built yet, run `pi cave build`", cave.name))?;
+    let package_envs = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse {}", env_file.display()))?;
+    let modified = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+    if modified(cave_file) > modified(&env_file) {
+        log::warn!("[{}] {} changed since the last build, run `pi cave build`", cave.name, Cave::FILENAME);
+    }
+    Ok(package_envs)
+}
+
+/// The paths env values name, like in the sandbox but against the host
+/// pilocal, for use outside the cave.
+pub fn host_path_vars<'a>(config: &'a Config, cave: &'a Cave, pilocal: &'a Path, home: &'a Path) -> PathVars<'a> {
+    PathVars {
+        pilocal: Some(pilocal),
+        home: Some(home),
+        workspace: Some(&cave.workspace),
+        packages: Some(&config.cache_packages_dir),
+    }
+}
+
+/// Changes applying `vars` on top of the original environment: variables
+/// only the previous activation touched go back first, and the restore
+/// record is replaced by the original values of `vars`.
+fn activation(
+    vars: BTreeMap<String, Option<String>>,
+    original: impl Fn(&str) -> Option<String>,
+    saved: &BTreeMap<String, Option<String>>,
+) -> EnvChanges {
+    let vars: BTreeMap<String, Option<String>> = vars.into_iter()
+        .filter(|(k, _)| {
+            let valid = is_valid_name(k) && k != RESTORE_VAR;
+            if !valid {
+                log::warn!("[cave env] skipping invalid variable name '{}'", k);
+            }
+            valid
+        })
+        .collect();
+
+    let mut changes: EnvChanges = saved.iter()
+        .filter(|(k, _)| !vars.contains_key(*k))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    let restore: BTreeMap<&String, Option<String>> = vars.keys().map(|k| (k, original(k))).collect();
+    changes.extend(vars.clone());
+    changes.push((RESTORE_VAR.to_string(), serde_json::to_string(&restore).ok()));
+    changes
+}
+
+fn deactivation(saved: &BTreeMap<String, Option<String>>) -> EnvChanges {
+    if saved.is_empty() && env::var(RESTORE_VAR).is_err() {
+        return Vec::new();
+    }
+    let mut changes: EnvChanges = saved.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+    changes.push((RESTORE_VAR.to_string(), None));
+    changes
+}
+
+fn saved_values() -> BTreeMap<String, Option<String>> {
+    env::var(RESTORE_VAR).ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn render(shell: Shell, changes: &EnvChanges) -> String {
+    let mut out = String::new();
+    for (k, v) in changes {
+        let line = match (shell, v) {
+            (Shell::Bash | Shell::Zsh, Some(v)) => format!("export {}={}", k, quote_posix(v)),
+            (Shell::Bash | Shell::Zsh, None) => format!("unset {}", k),
+            // fish keeps PATH as a list
+            (Shell::Fish, Some(v)) if k == "PATH" => {
+                let parts: Vec<String> = v.split(':').filter(|p| !p.is_empty()).map(quote_fish).collect();
+                format!("set -gx PATH {}", parts.join(" "))
+            }
+            (Shell::Fish, Some(v)) => format!("set -gx {} {}", k, quote_fish(v)),
+            (Shell::Fish, None) => format!("set -e {}", k),
+        };
+        out.push_str(&line);
+        out.push_str(";\n");
+    }
+    out
+}
+
+fn quote_posix(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+fn quote_fish(s: &str) -> String {
+    format!("'{}'", s.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+fn hook_script(shell: Shell, pi: &str, auto: bool) -> String {
+    let file = Cave::FILENAME;
+    match shell {
+        Shell::Bash | Shell::Zsh => {
+            let name = if shell == Shell::Bash { "bash" } else { "zsh" };
+            let pi = quote_posix(pi);
+            let enter = if auto {
+                format!(r#"eval "$({pi} cave env --shell {name} --hook)""#)
+            } else {
+                format!(r#"printf 'pi: cave at %s, activate with: eval "$(pi cave env --shell {name})"\n' "${{cave%/*}}" >&2"#)
+            };
+            let register = if shell == Shell::Bash {
+                r#"if [[ ";${PROMPT_COMMAND:-};" != *";_pi_hook;"* ]]; then
+  PROMPT_COMMAND="_pi_hook${PROMPT_COMMAND:+;$PROMPT_COMMAND}"
+fi"#
+            } else {
+                "autoload -Uz add-zsh-hook\nadd-zsh-hook chpwd _pi_hook\n_pi_hook"
+            };
+            format!(r#"_pi_hook() {{
+  [ -n "${{PI_CAVE:-}}" ] && return
+  local dir="$PWD" cave=""
+  while :; do
+    if [ -f "$dir/{file}" ]; then cave="$dir/{file}"; break; fi
+    [ -z "$dir" ] && break
+    dir="${{dir%/*}}"
+  done
+  [ "$cave" = "${{_PI_HOOK_CAVE:-}}" ] && return
+  if [ -n "${{_PI_CAVE_RESTORE:-}}" ]; then
+    eval "$({pi} cave env --shell {name} --deactivate)"
+  fi
+  _PI_HOOK_CAVE="$cave"
+  if [ -n "$cave" ]; then
+    {enter}
+  fi
+}}
+{register}
+"#)
+        }
+        Shell::Fish => {
+            let pi = quote_fish(pi);
+            let enter = if auto {
+                format!("{pi} cave env --shell fish --hook | source")
+            } else {
+                r#"echo "pi: cave at "(dirname $cave)", activate with: pi cave env --shell fish | source" >&2"#.to_string()
+            };
+            format!(r#"function _pi_hook --on-variable PWD
+    set -q PI_CAVE; and return
+    set -l dir $PWD
+    set -l cave ""
+    while true
+        if test -f "$dir/{file}"
+            set cave "$dir/{file}"
+            break
+        end
+        test "$dir" = "/"; and break
+        set dir (dirname "$dir")
+    end
+    test "$cave" = "$_PI_HOOK_CAVE"; and return
+    if set -q _PI_CAVE_RESTORE
+        {pi} cave env --shell fish --deactivate | source
+    end
+    set -g _PI_HOOK_CAVE $cave
+    if test -n "$cave"
+        {enter}
+    end
+end
+_pi_hook
+"#)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_activation_round_trip() {
+        let mut vars = BTreeMap::new();
+        vars.insert("GOROOT".to_string(), Some("/p/go".to_string()));
+        vars.insert("PATH".to_string(), Some("/p/bin:/usr/bin".to_string()));
+        vars.insert("bad-name".to_string(), Some("x".to_string()));
+        let original = |k: &str| (k == "PATH").then(|| "/usr/bin".to_string());
+
+        // Switching from a cave that had set NODE_ENV restores it first.
+        let mut previous = BTreeMap::new();
+        previous.insert("NODE_ENV".to_string(), Some("dev".to_string()));
+        let changes = activation(vars, original, &previous);
+        assert_eq!(changes[0], ("NODE_ENV".to_string(), Some("dev".to_string())));
+        assert!(!changes.iter().any(|(k, _)| k == "bad-name"));
+        let (_, restore) = changes.last().unwrap();
+        let restore: BTreeMap<String, Option<String>> = serde_json::from_str(restore.as_deref().unwrap()).unwrap();
+        assert_eq!(restore["GOROOT"], None);
+        assert_eq!(restore["PATH"].as_deref(), Some("/usr/bin"));
+
+        let bash = render(Shell::Bash, &deactivation(&restore));
+        assert_eq!(bash, "unset GOROOT;\nexport PATH='/usr/bin';\nunset _PI_CAVE_RESTORE;\n");
+
+        let quoted = vec![("A".to_string(), Some("it's $x".to_string()))];
+        assert_eq!(render(Shell::Zsh, &quoted), "export A='it'\\''s $x';\n");
+        assert_eq!(render(Shell::Fish, &quoted), "set -gx A 'it\\'s $x';\n");
+        let path = vec![("PATH".to_string(), Some("/a:/b".to_string()))];
+        assert_eq!(render(Shell::Fish, &path), "set -gx PATH '/a' '/b';\n");
+
+        let config = Config::new_test(std::env::temp_dir());
+        let cave = Cave::new("/w".into(), "/h".into());
+        let paths = host_path_vars(&config, &cave, Path::new("/pl"), Path::new("/h"));
+        assert_eq!(paths.value("$/bin:@HOME/x:${WORKSPACE}"), "/pl/bin:/h/x:/w");
+    }
+}
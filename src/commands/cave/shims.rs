@@ -0,0 +1,121 @@
+use crate::models::cave::Cave;
+use crate::models::config::Config;
+use crate::utils::theme::Theme;
+use anyhow::{Context, Result};
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// First line after the shebang of every shim, so stale shims can be told
+/// apart from files someone else put in `shims/`.
+const SHIM_MARKER: &str = "# pi shim";
+
+pub fn run(config: &Config, variant: Option<String>) {
+    if let Err(e) = execute(config, variant.as_deref()) {
+        log::error!("shims failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn execute(config: &Config, variant: Option<&str>) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let (_path, cave) = Cave::find(config, &current_dir).context("no cave found")?;
+    let variant = variant.filter(|v| v.starts_with(':'));
+    let pilocal = config.pilocal_path(&cave.name, variant);
+    if !pilocal.join("bin").exists() {
+        anyhow::bail!("{} has no binaries yet, run `pi cave build` first", cave.name);
+    }
+    let shims = write_shims(&pilocal, &cave.workspace, variant)?;
+    let mut table = Theme::current().table(&["Shim", "Path"]);
+    for shim in &shims {
+        let name = shim.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        table.add_row(vec![name, shim.display().to_string()]);
+    }
+    Theme::current().print(&table);
+    log::info!("[{}] point your IDE at {}", cave.name, pilocal.join("shims").display());
+    Ok(())
+}
+
+/// Writes `<pilocal>/shims/<name>` for every binary in `<pilocal>/bin` (but
+/// pi itself): a script running the binary through `pi cave run` of the cave
+/// in `workspace`, from any directory. Inside the cave it runs the binary
+/// directly. Shims of binaries that are gone are removed; returns the shims.
+pub fn write_shims(pilocal: &Path, workspace: &Path, variant: Option<&str>) -> Result<Vec<PathBuf>> {
+    let pi = env::current_exe().context("Failed to locate the pi binary")?;
+    let shims_dir = pilocal.join("shims");
+    fs::create_dir_all(&shims_dir).with_context(|| format!("Failed to create {}", shims_dir.display()))?;
+
+    let mut names: Vec<String> = fs::read_dir(pilocal.join("bin"))
+        .with_context(|| format!("Failed to read {}", pilocal.join("bin").display()))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .filter(|name| name != "pi")
+        .collect();
+    names.sort();
+
+    let mut shims = Vec::with_capacity(names.len());
+    for name in &names {
+        let path = shims_dir.join(name);
+        let script = shim_script(&pi, workspace, variant, name);
+        if fs::read_to_string(&path).ok().as_deref() != Some(script.as_str()) {
+            fs::write(&path, &script).with_context(|| format!("Failed to write {}", path.display()))?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+        }
+        shims.push(path);
+    }
+    for entry in fs::read_dir(&shims_dir)?.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_shim = fs::read_to_string(entry.path()).is_ok_and(|s| s.lines().nth(1).is_some_and(|l| l.starts_with(SHIM_MARKER)));
+        if is_shim && !names.contains(&name) {
+            fs::remove_file(entry.path()).with_context(|| format!("Failed to remove {}", entry.path().display()))?;
+            log::debug!("removed stale shim {}", entry.path().display());
+        }
+    }
+    Ok(shims)
+}
+
+fn shim_script(pi: &Path, workspace: &Path, variant: Option<&str>, name: &str) -> String {
+    let variant = variant.map(|v| format!("{} ", quote(v))).unwrap_or_default();
+    format!(
+        "#!/bin/sh\n{marker}, regenerated by `pi cave build` and `pi cave shims`\n\
+         if [ -n \"$PI_CAVE\" ]; then exec \"$HOME/.pilocal/bin/\"{name} \"$@\"; fi\n\
+         exec {pi} -q --cave {workspace} cave run {variant}-- {name} \"$@\"\n",
+        marker = SHIM_MARKER,
+        name = quote(name),
+        pi = quote(&pi.to_string_lossy()),
+        workspace = quote(&workspace.to_string_lossy()),
+    )
+}
+
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_shims() {
+        let tmp = tempfile::tempdir().unwrap();
+        let pilocal = tmp.path().join("pilocal");
+        fs::create_dir_all(pilocal.join("bin")).unwrap();
+        for name in ["cargo", "pi", "rust-analyzer"] {
+            fs::write(pilocal.join("bin").join(name), "").unwrap();
+        }
+        fs::create_dir_all(pilocal.join("shims")).unwrap();
+        fs::write(pilocal.join("shims/notes.txt"), "mine").unwrap();
+        fs::write(pilocal.join("shims/gone"), shim_script(Path::new("/pi"), tmp.path(), None, "gone")).unwrap();
+
+        let workspace = Path::new("/home/me/it's");
+        let shims = write_shims(&pilocal, workspace, Some(":dev")).unwrap();
+        assert_eq!(shims, vec![pilocal.join("shims/cargo"), pilocal.join("shims/rust-analyzer")]);
+        let script = fs::read_to_string(&shims[0]).unwrap();
+        assert!(script.ends_with(" -q --cave '/home/me/it'\\''s' cave run ':dev' -- 'cargo' \"$@\"\n"), "{}", script);
+        assert!(script.contains("exec \"$HOME/.pilocal/bin/\"'cargo' \"$@\""), "{}", script);
+        assert_eq!(fs::metadata(&shims[0]).unwrap().permissions().mode() & 0o777, 0o755);
+        assert!(!pilocal.join("shims/gone").exists());
+        assert!(pilocal.join("shims/notes.txt").exists());
+    }
+}
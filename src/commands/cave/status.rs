@@ -0,0 +1,226 @@
+use crate::commands::package::build::BuiltPackage;
+use crate::commands::package::resolve;
+use crate::models::cave::Cave;
+use crate::models::config::Config;
+use crate::models::lockfile::CaveLock;
+use crate::models::repository::Repositories;
+use crate::models::selector::PackageSelector;
+use crate::utils::table::SortedTable;
+use crate::utils::theme::Theme;
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// What the last `pi cave build` put into the pilocal, kept there as
+/// `packages.json` for `pi cave status`.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct BuiltState {
+    /// The variant built (":dev"), None for the cave itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    pub packages: Vec<BuiltEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BuiltEntry {
+    pub pkgname: String,
+    pub version: String,
+    /// Package directory the pilocal's links and env point into.
+    pub root: PathBuf,
+}
+
+impl BuiltState {
+    pub const FILENAME: &'static str = "packages.json";
+
+    pub fn new(variant: Option<&str>, packages: &[BuiltPackage]) -> Self {
+        let mut entries: Vec<BuiltEntry> = packages.iter()
+            .map(|p| BuiltEntry { pkgname: p.pkgname.clone(), version: p.version.clone(), root: p.root.clone() })
+            .collect();
+        entries.sort_by(|a, b| (&a.pkgname, &a.version, &a.root).cmp(&(&b.pkgname, &b.version, &b.root)));
+        entries.dedup();
+        Self { variant: variant.map(str::to_string), packages: entries }
+    }
+
+    pub fn load(pilocal: &Path) -> Option<Self> {
+        let content = fs::read_to_string(pilocal.join(Self::FILENAME)).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    pub fn save(&self, pilocal: &Path) -> Result<()> {
+        let path = pilocal.join(Self::FILENAME);
+        fs::write(&path, serde_json::to_string_pretty(self)? + "\n")
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// How a declared package of the cave compares to its pilocal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PackageState {
+    /// Built as the cave and its lock ask.
+    Ok,
+    /// Built as resolved, but `pi.cave.json` changed since the build.
+    NeedsBuild,
+    /// Not in the pilocal: never built, added since, or its files were evicted.
+    Missing,
+    /// The pilocal has another version than the selector resolves to or the lock pins.
+    VersionDrift,
+    /// The selector doesn't resolve to any version.
+    Unresolved,
+}
+
+impl PackageState {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::NeedsBuild => "needs-build",
+            Self::Missing => "missing",
+            Self::VersionDrift => "version-drift",
+            Self::Unresolved => "unresolved",
+        }
+    }
+}
+
+/// What is known of one declared selector.
+struct PackageStatus {
+    query: String,
+    locked: Option<String>,
+    resolved: Option<(String, String)>,
+    built: Option<String>,
+    state: PackageState,
+}
+
+pub fn run(config: &Config, variant: Option<String>) {
+    if let Err(e) = execute(config, variant.as_deref()) {
+        log::error!("status failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn execute(config: &Config, variant: Option<&str>) -> Result<()> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let (_path, cave) = Cave::find(config, &current_dir).context("no cave found")?;
+    let variant = variant.filter(|v| v.starts_with(':'));
+    let settings = cave.get_effective_settings(variant)?;
+    let pilocal = config.pilocal_path(&cave.name, variant);
+
+    let lock = CaveLock::load(&cave.workspace)?;
+    let locked = lock.as_ref().and_then(|l| l.packages(variant)).unwrap_or_default();
+    let built = BuiltState::load(&pilocal).filter(|b| b.variant.as_deref() == variant);
+    let built_at = fs::metadata(pilocal.join("env.json")).and_then(|m| m.modified()).ok();
+    let changed_at = fs::metadata(cave.workspace.join(Cave::FILENAME)).and_then(|m| m.modified()).ok();
+    let changed = matches!((built_at, changed_at), (Some(b), Some(c)) if c > b);
+
+    let repo_config = Repositories::get_all(config);
+    let statuses: Vec<PackageStatus> = settings.packages.par_iter()
+        .map(|query| {
+            let resolved = PackageSelector::parse(query)
+                .and_then(|selector| resolve::resolve_query(config, repo_config, &selector))
+                .map(|(_, version, _)| (version.pkgname.clone(), version.version.to_string()));
+            let locked = locked.iter().find(|l| &l.query == query).map(|l| l.version.clone());
+            let pkgname = resolved.as_ref().map(|(name, _)| name.as_str())
+                .or_else(|| locked_pkgname(&lock, variant, query));
+            let entries: Vec<&BuiltEntry> = built.iter().flat_map(|b| &b.packages)
+                .filter(|e| Some(e.pkgname.as_str()) == pkgname)
+                .collect();
+            let present = !entries.is_empty() && entries.iter().all(|e| e.root.exists());
+            let built = entries.first().map(|e| e.version.clone());
+            let in_pilocal = if present { built.as_deref() } else { None };
+            let state = package_state(resolved.as_ref().map(|(_, v)| v.as_str()), locked.as_deref(), in_pilocal, changed);
+            PackageStatus { query: query.clone(), locked, resolved, built, state }
+        })
+        .collect();
+
+    let mut table = SortedTable::new(&["Package", "Locked", "Resolved", "Built", "State"], "package");
+    for s in &statuses {
+        table.add_row(vec![
+            s.query.clone(),
+            s.locked.clone().unwrap_or_else(|| "-".to_string()),
+            s.resolved.as_ref().map(|(_, v)| v.clone()).unwrap_or_else(|| "-".to_string()),
+            s.built.clone().unwrap_or_else(|| "-".to_string()),
+            s.state.as_str().to_string(),
+        ]);
+    }
+    table.print(config.sort.as_ref());
+    Theme::current().line(&summary(&cave.name, variant, built_at, changed, &statuses));
+    Ok(())
+}
+
+fn locked_pkgname<'a>(lock: &'a Option<CaveLock>, variant: Option<&str>, query: &str) -> Option<&'a str> {
+    lock.as_ref()?.packages(variant)?.iter().find(|l| l.query == query).map(|l| l.pkgname.as_str())
+}
+
+/// The state of a package from the version it resolves to, the one the
+/// lock pins, the one in the pilocal (None when absent) and whether
+/// `pi.cave.json` changed since the build.
+fn package_state(resolved: Option<&str>, locked: Option<&str>, built: Option<&str>, changed: bool) -> PackageState {
+    let Some(built) = built else {
+        return if resolved.is_none() && locked.is_none() { PackageState::Unresolved } else { PackageState::Missing };
+    };
+    let Some(resolved) = resolved else {
+        return PackageState::Unresolved;
+    };
+    if built != resolved || locked.is_some_and(|l| l != built) {
+        PackageState::VersionDrift
+    } else if changed {
+        PackageState::NeedsBuild
+    } else {
+        PackageState::Ok
+    }
+}
+
+fn summary(cave: &str, variant: Option<&str>, built_at: Option<SystemTime>, changed: bool, statuses: &[PackageStatus]) -> String {
+    let label = format!("{}{}", cave, variant.unwrap_or(""));
+    let Some(built_at) = built_at else {
+        return format!("{} was never built, run `pi cave build`", label);
+    };
+    let when = chrono::DateTime::<chrono::Local>::from(built_at).format("%Y-%m-%d %H:%M");
+    let behind = statuses.iter().filter(|s| s.state != PackageState::Ok).count();
+    match (behind, changed) {
+        (0, _) => format!("{} is up to date (built {})", label, when),
+        (n, true) => format!("{} package(s) of {} need attention, pi.cave.json changed since the build ({})", n, label, when),
+        (n, false) => format!("{} package(s) of {} need attention (built {})", n, label, when),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_package_state() {
+        assert_eq!(package_state(Some("1.2"), None, Some("1.2"), false), PackageState::Ok);
+        assert_eq!(package_state(Some("1.2"), Some("1.2"), Some("1.2"), true), PackageState::NeedsBuild);
+        assert_eq!(package_state(Some("1.2"), None, None, false), PackageState::Missing);
+        assert_eq!(package_state(Some("1.3"), None, Some("1.2"), false), PackageState::VersionDrift);
+        assert_eq!(package_state(Some("1.2"), Some("1.1"), Some("1.2"), false), PackageState::VersionDrift);
+        assert_eq!(package_state(None, None, None, false), PackageState::Unresolved);
+        assert_eq!(package_state(None, Some("1.2"), Some("1.2"), false), PackageState::Unresolved);
+    }
+
+    #[test]
+    fn test_built_state_per_variant() {
+        let built = |pkgname: &str, version: &str| BuiltPackage {
+            pkgname: pkgname.to_string(),
+            version: version.to_string(),
+            root: PathBuf::from(format!("/pkgs/{}-{}", pkgname, version)),
+            stats: Default::default(),
+        };
+        let state = BuiltState::new(Some(":dev"), &[built("node", "22.1.0"), built("go", "1.22.1"), built("node", "22.1.0")]);
+        let names: Vec<&str> = state.packages.iter().map(|e| e.pkgname.as_str()).collect();
+        assert_eq!(names, vec!["go", "node"]);
+
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let (base, dev) = (config.pilocal_path("web", None), config.pilocal_path("web", Some(":dev")));
+        assert_ne!(base, dev);
+        assert!(!dev.to_string_lossy().contains(':'));
+        fs::create_dir_all(&dev).unwrap();
+        state.save(&dev).unwrap();
+        assert!(BuiltState::load(&base).is_none());
+        assert_eq!(BuiltState::load(&dev), Some(state));
+    }
+}
@@ -0,0 +1,264 @@
+use crate::commands::cave::build::execute_build;
+use crate::commands::package::build::{self, DependencyGraph};
+use crate::models::cave::Cave;
+use crate::models::config::Config;
+use crate::models::package_entry::PackageList;
+use crate::models::selector::PackageSelector;
+use crate::models::repository::{Repositories, Repository};
+use crate::utils::crypto::hash_to_string;
+use anyhow::{Context, Result};
+use notify::{RecursiveMode, Watcher};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Quiet period after the last file event before rebuilding.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Per-package pipeline fingerprint: query -> (version, hash of pipeline and exports).
+type Fingerprints = BTreeMap<String, (String, String)>;
+
+/// Rebuilds the cave whenever `pi.cave.json` or a recipe file of a resolved
+/// package changes. Runs until interrupted; failed builds are reported and
+/// the watch continues.
+pub fn watch(config: &Config, cave_file: &Path, variant: Option<&str>) -> Result<()> {
+    let repo_config = Repositories::get_all(config);
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).context("Failed to create file watcher")?;
+
+    // Watch the directory rather than the file so editors that save by rename are seen.
+    let workspace = cave_file.parent().context("cave file has no parent directory")?;
+    watcher.watch(workspace, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Failed to watch {}", workspace.display()))?;
+    for repo in &repo_config.repositories {
+        if let Err(e) = watcher.watch(Path::new(&repo.path), RecursiveMode::Recursive) {
+            log::warn!("[{}] cannot watch {}: {}", repo.name, repo.path, e);
+        }
+    }
+
+    let mut previous: Option<Fingerprints> = None;
+    let mut recipes: HashMap<PathBuf, &Repository> = HashMap::new();
+    loop {
+        match rebuild(config, cave_file, variant, repo_config, &mut recipes) {
+            Ok(fingerprints) => {
+                if let Some(prev) = &previous {
+                    let changes = diff_fingerprints(prev, &fingerprints);
+                    if changes.is_empty() {
+                        println!("no pipeline changes");
+                    }
+                    for line in changes {
+                        println!("{}", line);
+                    }
+                }
+                previous = Some(fingerprints);
+            }
+            Err(e) => log::error!("build failed: {:#}", e),
+        }
+        log::info!("watching {} and {} recipe files", cave_file.display(), recipes.len());
+
+        let changed = wait_for_changes(&rx, cave_file, &recipes)?;
+        let stale: HashMap<&str, &Repository> = changed.iter()
+            .filter_map(|p| recipes.get(p).map(|r| (r.name.as_str(), *r)))
+            .collect();
+        for repo in stale.into_values() {
+            if let Err(e) = crate::services::sync::sync_repo(config, repo) {
+                log::error!("[{}] sync failed: {:#}", repo.name, e);
+            }
+        }
+    }
+}
+
+/// Resolves and builds the cave, returning the pipeline fingerprints.
+/// `recipes` is set to the recipe files (with their repository) of every
+/// resolved package before building, so a failed build still watches them;
+/// when resolving fails, the recipes of the cave's own selectors are added,
+/// so fixing the recipe that broke it triggers a rebuild.
+fn rebuild<'a>(
+    config: &Config,
+    cave_file: &Path,
+    variant: Option<&str>,
+    repo_config: &'a Repositories,
+    recipes: &mut HashMap<PathBuf, &'a Repository>,
+) -> Result<Fingerprints> {
+    let cave = Cave::load(cave_file, &config.config_dir)?;
+    let settings = cave.get_effective_settings(variant).context("Failed to get effective cave settings")?;
+    let graph = match build::resolve_graph(config, &settings.packages, &settings.options) {
+        Ok(graph) => graph,
+        Err(e) => {
+            recipes.extend(selector_files(config, repo_config, &settings.packages));
+            return Err(e);
+        }
+    };
+    *recipes = recipe_files(config, repo_config, &graph);
+
+    // Recipe edits don't touch pi.cave.json, so drop the cached environment explicitly.
+    let _ = std::fs::remove_file(config.pilocal_path(&cave.name, variant).join("env.json"));
+    execute_build(config, &cave, variant)?;
+    Ok(fingerprints(&graph))
+}
+
+fn fingerprints(graph: &DependencyGraph) -> Fingerprints {
+    graph.iter()
+        .map(|(query, (version, _))| {
+            let hash = hash_to_string(&(&version.pipeline, &version.exports));
+            (query.clone(), (version.version.to_string(), hash))
+        })
+        .collect()
+}
+
+fn recipe_files<'a>(config: &Config, repo_config: &'a Repositories, graph: &DependencyGraph) -> HashMap<PathBuf, &'a Repository> {
+    let mut files = HashMap::new();
+    for (version, repo_name) in graph.values() {
+        let Some(repo) = repo_config.repositories.iter().find(|r| &r.name == repo_name) else { continue };
+        let Some(list) = PackageList::get_for_repo(config, repo, false) else { continue };
+        if let Some(entry) = list.entry_for(&version.pkgname) {
+            files.insert(Path::new(&repo.path).join(&entry.filename), repo);
+        }
+    }
+    files
+}
+
+/// The recipe files registering the packages `selectors` name, in the
+/// selector's repository or in any of them.
+fn selector_files<'a>(config: &Config, repo_config: &'a Repositories, selectors: &[String]) -> HashMap<PathBuf, &'a Repository> {
+    let mut files = HashMap::new();
+    for selector in selectors.iter().filter_map(|s| PackageSelector::parse(s)) {
+        let pkgname = match &selector.prefix {
+            Some(prefix) => format!("{}:{}", prefix, selector.package),
+            None => selector.package.clone(),
+        };
+        for repo in &repo_config.repositories {
+            if selector.recipe.as_ref().is_some_and(|r| *r != repo.name) {
+                continue;
+            }
+            let Some(list) = PackageList::get_for_repo(config, repo, false) else { continue };
+            if let Some(entry) = list.entry_for(&pkgname) {
+                files.insert(Path::new(&repo.path).join(&entry.filename), repo);
+            }
+        }
+    }
+    files
+}
+
+/// Blocks until a relevant file changes, then collects further events until
+/// `DEBOUNCE` passes without one. Returns the changed recipe files.
+fn wait_for_changes(
+    rx: &mpsc::Receiver<notify::Result<notify::Event>>,
+    cave_file: &Path,
+    recipes: &HashMap<PathBuf, &Repository>,
+) -> Result<HashSet<PathBuf>> {
+    let mut changed = HashSet::new();
+    let mut triggered = false;
+    loop {
+        let event = if triggered {
+            match rx.recv_timeout(DEBOUNCE) {
+                Ok(event) => event,
+                Err(mpsc::RecvTimeoutError::Timeout) => return Ok(changed),
+                Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("file watcher stopped"),
+            }
+        } else {
+            rx.recv().context("file watcher stopped")?
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                log::warn!("watch error: {}", e);
+                continue;
+            }
+        };
+        if event.kind.is_access() {
+            continue;
+        }
+        for path in event.paths {
+            if path == cave_file {
+                log::debug!("changed: {}", path.display());
+                triggered = true;
+            } else if recipes.contains_key(&path) {
+                log::debug!("changed: {}", path.display());
+                triggered = true;
+                changed.insert(path);
+            }
+        }
+    }
+}
+
+/// One line per package whose resolved version or pipeline differs, e.g.
+/// `~ go=1.22.1 (was 1.22.0)`, `+ ripgrep=14.1.0`, `- jq`.
+fn diff_fingerprints(old: &Fingerprints, new: &Fingerprints) -> Vec<String> {
+    let mut lines = Vec::new();
+    for (query, (version, hash)) in new {
+        match old.get(query) {
+            None => lines.push(format!("+ {}={}", query, version)),
+            Some((old_version, _)) if old_version != version => {
+                lines.push(format!("~ {}={} (was {})", query, version, old_version))
+            }
+            Some((_, old_hash)) if old_hash != hash => lines.push(format!("~ {}={} (pipeline changed)", query, version)),
+            _ => {}
+        }
+    }
+    for query in old.keys().filter(|q| !new.contains_key(*q)) {
+        lines.push(format!("- {}", query));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_fingerprints() {
+        let entry = |v: &str, h: &str| (v.to_string(), h.to_string());
+        let old: Fingerprints = [
+            ("go".to_string(), entry("1.22.0", "a")),
+            ("jq".to_string(), entry("1.7", "b")),
+            ("node".to_string(), entry("20.0.0", "c")),
+        ].into();
+        let new: Fingerprints = [
+            ("go".to_string(), entry("1.22.1", "a")),
+            ("node".to_string(), entry("20.0.0", "d")),
+            ("rg".to_string(), entry("14.1.0", "e")),
+        ].into();
+
+        assert_eq!(diff_fingerprints(&old, &new), vec![
+            "~ go=1.22.1 (was 1.22.0)",
+            "~ node=20.0.0 (pipeline changed)",
+            "+ rg=14.1.0",
+            "- jq",
+        ]);
+        assert!(diff_fingerprints(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_selector_files() {
+        use crate::models::package_entry::RegistryEntry;
+        use std::sync::Arc;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let entry = |name: &str, filename: &str| RegistryEntry {
+            name: name.to_string(), function_name: "install".to_string(), filename: filename.to_string(), dir: None,
+        };
+        let repos = Repositories { repositories: vec![
+            Repository::new("/repos/main".to_string(), "main".to_string()),
+            Repository::new("/repos/extra".to_string(), "extra".to_string()),
+        ] };
+        for repo in &repos.repositories {
+            let list = PackageList {
+                packages: [("go".to_string(), entry("go", "go.star"))].into(),
+                managers: [("npm".to_string(), entry("npm", "managers/npm.star"))].into(),
+            };
+            config.state.package_lists.insert(repo.name.clone(), Arc::new(list));
+        }
+
+        let files = selector_files(&config, &repos, &["extra/go=1.22".to_string(), "npm:left-pad".to_string(), "jq".to_string()]);
+        let mut names: Vec<_> = files.iter().map(|(path, repo)| format!("{} {}", repo.name, path.display())).collect();
+        names.sort();
+        assert_eq!(names, vec![
+            "extra /repos/extra/go.star",
+            "extra /repos/extra/managers/npm.star",
+            "main /repos/main/managers/npm.star",
+        ]);
+    }
+}
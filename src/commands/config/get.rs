@@ -0,0 +1,24 @@
+use crate::models::config::Config;
+use crate::models::settings::{self, Settings};
+
+pub fn run(config: &Config, key: Option<&str>) {
+    if let Err(e) = execute_get(config, key) {
+        log::error!("config get failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Prints values from settings.toml (environment and CLI overrides are not applied).
+fn execute_get(config: &Config, key: Option<&str>) -> anyhow::Result<()> {
+    let file_settings = Settings::load(&config.config_dir)?;
+    let Some(key) = key else {
+        print!("{}", toml::to_string(&file_settings)?);
+        return Ok(());
+    };
+    match settings::get_key(&file_settings, key)? {
+        Some(toml::Value::String(s)) => println!("{}", s),
+        Some(value) => println!("{}", value),
+        None => log::info!("[config] {} is not set", key),
+    }
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use crate::models::config::Config;
+use crate::models::settings::{self, Settings};
+use anyhow::Context;
+use std::fs;
+
+pub fn run(config: &Config, key: &str, value: &str) {
+    if let Err(e) = execute_set(config, key, value) {
+        log::error!("config set failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Edits one key of settings.toml, keeping the rest of the file's entries.
+/// The new document is validated before it atomically replaces the old one.
+fn execute_set(config: &Config, key: &str, value: &str) -> anyhow::Result<()> {
+    let path = config.config_dir.join(Settings::FILENAME);
+    let mut doc: toml::Table = if path.exists() {
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read settings: {}", path.display()))?;
+        content.parse()
+            .with_context(|| format!("Failed to parse settings: {}", path.display()))?
+    } else {
+        toml::Table::new()
+    };
+
+    settings::set_key(&mut doc, key, value)?;
+
+    fs::create_dir_all(&config.config_dir).context("Failed to create config directory")?;
+    let tmp = path.with_extension("toml.tmp");
+    fs::write(&tmp, toml::to_string(&doc)?)
+        .with_context(|| format!("Failed to write settings: {}", tmp.display()))?;
+    fs::rename(&tmp, &path)
+        .with_context(|| format!("Failed to replace settings: {}", path.display()))?;
+
+    log::info!("[config] {} = {}", key, value);
+    Ok(())
+}
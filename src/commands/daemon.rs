@@ -0,0 +1,289 @@
+use crate::build::BUILD_VERSION;
+use crate::commands::package::resolve::{repo_generation, resolve_row, ResolutionRow};
+use crate::models::config::{Config, State};
+use crate::models::repository::Repositories;
+use crate::models::types::Platform;
+use crate::utils::crypto::hash_to_string;
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Instant, UNIX_EPOCH};
+
+/// Warm state older than this is dropped, so package lists synced by other
+/// pi processes and remembered misses are picked up again.
+const MAX_STATE_AGE: std::time::Duration = std::time::Duration::from_secs(600);
+
+/// A request line sent by the CLI.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    Resolve { queries: Vec<String>, no_sync: bool },
+    Stop,
+}
+
+/// The response line to a request.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum Response {
+    Status { pid: u32, uptime_secs: u64, repositories: usize },
+    Rows { rows: Vec<ResolutionRow> },
+    Stopping,
+    Error { message: String },
+}
+
+/// Requests carry the client's version; a daemon of another build refuses them.
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    version: String,
+    #[serde(flatten)]
+    request: Request,
+}
+
+/// The daemon's warm config, replaced when its repositories change or it ages.
+struct Warm {
+    config: Config,
+    generation: String,
+    since: Instant,
+}
+
+struct Daemon {
+    base: Config,
+    warm: Mutex<Warm>,
+    started: Instant,
+}
+
+pub fn socket_path(config: &Config) -> PathBuf {
+    config.state_dir.join("daemon.sock")
+}
+
+pub fn run(config: &Config, stop: bool, status: bool) {
+    let result = if stop || status {
+        let request = if stop { Request::Stop } else { Request::Status };
+        request_daemon(&socket_path(config), request).map(print_response)
+    } else {
+        serve(config, &socket_path(config))
+    };
+    if let Err(e) = result {
+        log::error!("daemon failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn print_response(response: Response) {
+    match response {
+        Response::Status { pid, uptime_secs, repositories } => {
+            println!("pid {}, up {}s, {} repositories", pid, uptime_secs, repositories);
+        }
+        Response::Stopping => log::info!("[daemon] stopping"),
+        Response::Rows { .. } | Response::Error { .. } => {}
+    }
+}
+
+/// Resolves `queries` through a running daemon. None when there is no
+/// daemon, it can't serve this invocation (`--force`, `--target`, and
+/// `--offline`, as the daemon may sync) or it fails; the caller then
+/// resolves locally.
+pub fn resolve(config: &Config, queries: &[String]) -> Option<Vec<ResolutionRow>> {
+    if config.force || config.offline || config.target != Platform::default() {
+        return None;
+    }
+    let path = socket_path(config);
+    if !path.exists() {
+        return None;
+    }
+    let request = Request::Resolve { queries: queries.to_vec(), no_sync: config.no_sync };
+    match request_daemon(&path, request) {
+        Ok(Response::Rows { rows }) => {
+            log::debug!("[daemon] resolved {} queries", rows.len());
+            Some(rows)
+        }
+        Ok(other) => {
+            log::debug!("[daemon] unexpected response {:?}, resolving locally", other);
+            None
+        }
+        Err(e) => {
+            log::debug!("[daemon] {:#}, resolving locally", e);
+            None
+        }
+    }
+}
+
+/// Sends one request line and reads the response line.
+fn request_daemon(path: &Path, request: Request) -> Result<Response> {
+    let mut stream = UnixStream::connect(path).with_context(|| format!("no daemon listening on {}", path.display()))?;
+    let envelope = Envelope { version: BUILD_VERSION.to_string(), request };
+    writeln!(stream, "{}", serde_json::to_string(&envelope)?)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).context("Failed to read the daemon response")?;
+    match serde_json::from_str(&line).context("Invalid daemon response")? {
+        Response::Error { message } => anyhow::bail!("daemon: {}", message),
+        response => Ok(response),
+    }
+}
+
+/// Listens on `path` until stopped, one thread per connection.
+fn serve(config: &Config, path: &Path) -> Result<()> {
+    let listener = bind(path)?;
+    let daemon = Daemon {
+        base: config.clone(),
+        warm: Mutex::new(warm(config)),
+        started: Instant::now(),
+    };
+    log::info!("[daemon] listening on {}", path.display());
+
+    std::thread::scope(|s| {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let daemon = &daemon;
+                    s.spawn(move || {
+                        if let Err(e) = handle(daemon, stream, path) {
+                            log::debug!("[daemon] connection failed: {:#}", e);
+                        }
+                    });
+                }
+                Err(e) => log::warn!("[daemon] accept failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Binds the socket, replacing a stale one left by a daemon that died.
+fn bind(path: &Path) -> Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            anyhow::bail!("a daemon is already listening on {}", path.display());
+        }
+        std::fs::remove_file(path).with_context(|| format!("Failed to remove stale {}", path.display()))?;
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let listener = UnixListener::bind(path).with_context(|| format!("Failed to listen on {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+fn handle(daemon: &Daemon, stream: UnixStream, path: &Path) -> Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let envelope: Envelope = serde_json::from_str(&line).context("Invalid request")?;
+    let stopping = envelope.request == Request::Stop;
+    let response = if envelope.version != BUILD_VERSION {
+        Response::Error { message: format!("daemon runs v{}, not v{}", BUILD_VERSION, envelope.version) }
+    } else {
+        respond(daemon, envelope.request)
+    };
+    writeln!(&stream, "{}", serde_json::to_string(&response)?)?;
+    if stopping {
+        let _ = std::fs::remove_file(path);
+        log::info!("[daemon] stopped");
+        std::process::exit(0);
+    }
+    Ok(())
+}
+
+fn respond(daemon: &Daemon, request: Request) -> Response {
+    match request {
+        Request::Status => Response::Status {
+            pid: std::process::id(),
+            uptime_secs: daemon.started.elapsed().as_secs(),
+            repositories: Repositories::get_all(&daemon.config()).repositories.len(),
+        },
+        Request::Resolve { queries, no_sync } => {
+            let mut config = daemon.config();
+            config.no_sync |= no_sync;
+            let repo_config = Repositories::get_all(&config);
+            let rows = queries.par_iter().map(|query| resolve_row(&config, repo_config, query)).collect();
+            Response::Rows { rows }
+        }
+        Request::Stop => Response::Stopping,
+    }
+}
+
+impl Daemon {
+    /// The warm config, rebuilt first when the repositories changed or it aged.
+    fn config(&self) -> Config {
+        let mut warm = self.warm.lock();
+        let generation = generation(&self.base);
+        if warm.generation != generation || warm.since.elapsed() > MAX_STATE_AGE {
+            log::debug!("[daemon] repositories changed or state aged, reloading");
+            *warm = self::warm(&self.base);
+        }
+        warm.config.clone()
+    }
+}
+
+/// A config with fresh in-memory caches. The state databases are shared
+/// with CLI commands, since each operation opens and closes them.
+fn warm(base: &Config) -> Warm {
+    let mut config = base.clone();
+    config.state = Arc::new(State::default());
+    Warm { generation: generation(&config), config, since: Instant::now() }
+}
+
+/// Changes with repositories.json, the recipes of each repository and the
+/// package lists cached for them.
+fn generation(config: &Config) -> String {
+    let mtime = |p: &Path| {
+        p.metadata().and_then(|m| m.modified()).ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    };
+    let repos = Repositories::load(config).unwrap_or_else(|_| Repositories { repositories: Vec::new() });
+    let lists: Vec<u64> = repos.repositories.iter().map(|r| mtime(&config.package_cache_file(&r.name))).collect();
+    hash_to_string(&(mtime(&config.repositories_file()), repo_generation(&repos), lists))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_daemon_requests() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let path = socket_path(&config);
+        let listener = bind(&path).unwrap();
+        let daemon = Daemon { base: config.clone(), warm: Mutex::new(warm(&config)), started: Instant::now() };
+        assert!(daemon.config().db().is_some());
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..2 {
+                    let (stream, _) = listener.accept().unwrap();
+                    let _ = handle(&daemon, stream, &path);
+                }
+            });
+            let status = request_daemon(&path, Request::Status).unwrap();
+            assert!(matches!(status, Response::Status { repositories: 0, .. }));
+            let rows = request_daemon(&path, Request::Resolve { queries: vec!["nope".to_string()], no_sync: true }).unwrap();
+            assert_eq!(rows, Response::Rows { rows: vec![resolve_row(&config, Repositories::get_all(&config), "nope")] });
+        });
+        assert!(bind(&path).is_err());
+
+        let json = serde_json::to_string(&Envelope { version: "0".to_string(), request: Request::Stop }).unwrap();
+        assert_eq!(json, r#"{"version":"0","request":"stop"}"#);
+    }
+
+    #[test]
+    fn test_offline_resolves_locally() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut config = Config::new_test(tmp.path().to_path_buf());
+        let listener = bind(&socket_path(&config)).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        config.offline = true;
+
+        assert!(resolve(&config, &["go".to_string()]).is_none());
+        assert_eq!(listener.accept().unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+    }
+}
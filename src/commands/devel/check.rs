@@ -0,0 +1,119 @@
+use crate::models::config::Config;
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+pub fn run(config: &Config, paths: &[String]) {
+    match execute_check(config, paths) {
+        Ok(0) => {}
+        Ok(failed) => {
+            log::error!("{} recipe test(s) failed", failed);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            log::error!("check failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Runs the `test_*` functions of every recipe under `paths` (the current
+/// directory by default) and returns the number of failures. A file that
+/// fails to evaluate counts as one failure.
+fn execute_check(config: &Config, paths: &[String]) -> Result<usize> {
+    let roots: Vec<PathBuf> = if paths.is_empty() {
+        vec![PathBuf::from(".")]
+    } else {
+        paths.iter().map(PathBuf::from).collect()
+    };
+    let files = recipe_files(&roots)?;
+    if files.is_empty() {
+        anyhow::bail!("no .star files found");
+    }
+
+    let (mut passed, mut failed) = (0, 0);
+    for file in &files {
+        match crate::starlark::runtime::run_recipe_tests(file, config) {
+            Ok(outcomes) => {
+                log::debug!("[check] {}: {} tests", file.display(), outcomes.len());
+                for (name, result) in outcomes {
+                    match result {
+                        Ok(()) => {
+                            println!("ok    {}::{}", file.display(), name);
+                            passed += 1;
+                        }
+                        Err(e) => {
+                            println!("FAIL  {}::{}\n      {}", file.display(), name, e.trim_end().replace('\n', "\n      "));
+                            failed += 1;
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                println!("FAIL  {} (evaluation)\n      {:#}", file.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    log::info!("[check] {} files, {} passed, {} failed", files.len(), passed, failed);
+    Ok(failed)
+}
+
+/// The `.star` files under `roots`, sorted per root.
+pub fn recipe_files(roots: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for root in roots {
+        if !root.exists() {
+            anyhow::bail!("{} does not exist", root.display());
+        }
+        let mut found: Vec<PathBuf> = WalkDir::new(root)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .map(|e| e.into_path())
+            .filter(|p| is_recipe(p))
+            .collect();
+        found.sort();
+        files.extend(found);
+    }
+    Ok(files)
+}
+
+fn is_recipe(path: &Path) -> bool {
+    path.is_file() && path.extension().is_some_and(|ext| ext == "star")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_runs_recipe_tests() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let recipe = tmp.path().join("tool.star");
+        std::fs::write(&recipe, r#"pi_api_version(2)
+
+def parse_tag(tag):
+    return tag.removeprefix("v")
+
+def test_parse_tag():
+    assert_eq(parse_tag("v1.2"), "1.2")
+    assert_true(parse_tag("1.2") == "1.2", msg = "no prefix")
+
+def test_broken():
+    assert_eq(parse_tag("v2"), "3", "tag")
+
+def helper_not_a_test():
+    fail("not run")
+"#).unwrap();
+
+        let outcomes = crate::starlark::runtime::run_recipe_tests(&recipe, &config).unwrap();
+        assert_eq!(outcomes.len(), 2);
+        assert_eq!(outcomes[0].0, "test_broken");
+        assert!(outcomes[0].1.as_ref().unwrap_err().contains(r#"tag: "2" != "3""#), "{:?}", outcomes[0]);
+        assert_eq!(outcomes[1], ("test_parse_tag".to_string(), Ok(())));
+
+        let dir = tmp.path().to_string_lossy().into_owned();
+        assert_eq!(execute_check(&config, &[dir]).unwrap(), 1);
+    }
+}
@@ -0,0 +1,36 @@
+use crate::commands::devel::check::recipe_files;
+use crate::starlark::runtime::lint::{lint_file, Problem};
+use anyhow::Result;
+use rayon::prelude::*;
+use std::path::PathBuf;
+
+/// `pi devel lint`: lints every recipe under `path` (the current directory
+/// by default) and exits non-zero when any has a problem, for recipe-repo CI.
+pub fn run(path: Option<&str>) {
+    match execute(path) {
+        Ok(0) => {}
+        Ok(problems) => {
+            log::error!("{} lint problem(s)", problems);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            log::error!("lint failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn execute(path: Option<&str>) -> Result<usize> {
+    let root = PathBuf::from(path.unwrap_or("."));
+    let files = recipe_files(std::slice::from_ref(&root))?;
+    if files.is_empty() {
+        anyhow::bail!("no .star files found");
+    }
+    let problems: Vec<Vec<Problem>> = files.par_iter().map(|file| lint_file(&root, file)).collect();
+    for problem in problems.iter().flatten() {
+        println!("{}: {}: {}", problem.location, problem.check, problem.message);
+    }
+    let count = problems.iter().map(Vec::len).sum();
+    log::info!("[lint] {} file(s), {} problem(s)", files.len(), count);
+    Ok(count)
+}
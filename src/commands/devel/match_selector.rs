@@ -0,0 +1,133 @@
+use crate::commands::package::resolve::TargetVersion;
+use crate::models::config::Config;
+use crate::models::selector::PackageSelector;
+use crate::models::version_entry::ReleaseType;
+use crate::utils::table::SortedTable;
+use crate::utils::theme::Theme;
+use anyhow::{Context, Result};
+
+/// Release types a candidate may name after `@`.
+const STREAMS: &[&str] = &["stable", "lts", "testing", "unstable"];
+
+/// A name a recipe produces: `[repo/][prefix:]package[=version[@stream]]`.
+#[derive(Debug)]
+struct Candidate {
+    name: PackageSelector,
+    release_type: ReleaseType,
+}
+
+impl Candidate {
+    fn parse(s: &str) -> Result<Self> {
+        let (s, stream) = match s.rsplit_once('@') {
+            Some((rest, stream)) if s.contains('=') => (rest, Some(stream)),
+            _ => (s, None),
+        };
+        let name = PackageSelector::parse(s).with_context(|| format!("invalid candidate: {}", s))?;
+        let release_type = match stream {
+            Some(stream) if !STREAMS.contains(&stream) => {
+                anyhow::bail!("unknown release type {} in {}, use one of {}", stream, s, STREAMS.join(", "))
+            }
+            Some(stream) => stream.parse()?,
+            None => ReleaseType::default(),
+        };
+        Ok(Self { name, release_type })
+    }
+}
+
+pub fn run(config: &Config, selector: &str, candidates: &[String]) {
+    match execute(config, selector, candidates) {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            log::error!("match failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints whether each candidate matches `selector` and why; false when none does.
+fn execute(config: &Config, selector: &str, candidates: &[String]) -> Result<bool> {
+    let selector = PackageSelector::parse(selector).with_context(|| format!("invalid selector: {}", selector))?;
+    let or_any = |part: &Option<String>| part.clone().unwrap_or_else(|| "-".to_string());
+    Theme::current().line(&format!(
+        "repo {}, prefix {}, package {}, stream {}, version {}",
+        or_any(&selector.recipe), or_any(&selector.prefix), selector.package, or_any(&selector.stream),
+        selector.version.clone().unwrap_or_else(|| format!("{} (default)", selector.target_version())),
+    ));
+
+    let mut any = false;
+    let mut table = SortedTable::new(&["Candidate", "Match", "Why"], "candidate");
+    for candidate in candidates {
+        let (matched, why) = explain(&selector, &Candidate::parse(candidate)?);
+        any |= matched;
+        table.add_row(vec![candidate.clone(), if matched { "yes" } else { "no" }.to_string(), why]);
+    }
+    table.print(config.sort.as_ref());
+    Ok(any)
+}
+
+/// Applies the checks of `resolve_query` in order: repo, prefix, package
+/// name (exact), version. Returns the first failing check, or all passed ones.
+fn explain(selector: &PackageSelector, candidate: &Candidate) -> (bool, String) {
+    let name = &candidate.name;
+    let mut passed = Vec::new();
+
+    match (&selector.recipe, &name.recipe) {
+        (Some(want), Some(repo)) if want != repo => return (false, format!("repo {} is not {}", repo, want)),
+        (Some(want), _) => passed.push(format!("repo {}", want)),
+        (None, _) => {}
+    }
+    match (&selector.prefix, &name.prefix) {
+        (None, None) => passed.push("direct package".to_string()),
+        (Some(want), Some(prefix)) if want == prefix => passed.push(format!("manager {}", prefix)),
+        (Some(want), Some(prefix)) => return (false, format!("manager {} is not {}", prefix, want)),
+        (Some(want), None) => return (false, format!("direct package, selector wants manager {}", want)),
+        (None, Some(prefix)) => return (false, format!("manager {} package, selector has no prefix", prefix)),
+    }
+    if name.package != selector.package {
+        return (false, format!("name {} is not {} (names match exactly)", name.package, selector.package));
+    }
+    passed.push(format!("name {}", name.package));
+
+    let target = selector.target_version();
+    let Some(version) = &name.version else {
+        passed.push("no version given".to_string());
+        return (true, passed.join(", "));
+    };
+    let target_version = TargetVersion::parse(target);
+    let matched = target_version.matches(version, &candidate.release_type);
+    let why = match (target_version, matched) {
+        (TargetVersion::Latest, _) => "latest takes any version".to_string(),
+        (TargetVersion::Stream(stream), true) => format!("{} is {}", version, stream),
+        (TargetVersion::Stream(stream), false) => format!("{} is {}, not {}", version, candidate.release_type, stream),
+        (TargetVersion::Wildcard(pattern), true) => format!("{} matches {}", version, pattern),
+        (TargetVersion::Wildcard(pattern), false) => format!("{} doesn't match {}", version, pattern),
+        (TargetVersion::Exact(_), true) => format!("version {}", version),
+        (TargetVersion::Exact(exact), false) => format!("version {} is not {}", version, exact),
+    };
+    if !matched {
+        return (false, why);
+    }
+    passed.push(why);
+    (true, passed.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_explain() {
+        let check = |selector: &str, candidate: &str| {
+            explain(&PackageSelector::parse(selector).unwrap(), &Candidate::parse(candidate).unwrap())
+        };
+        assert_eq!(check("go", "go=1.22.0"), (true, "direct package, name go, 1.22.0 is stable".to_string()));
+        assert_eq!(check("go", "go=1.23rc1@unstable"), (false, "1.23rc1 is unstable, not stable".to_string()));
+        assert!(!check("main/go=1.*", "other/go=1.22.0").0);
+        assert_eq!(check("go=1.*", "go=1.22.0@lts"), (true, "direct package, name go, 1.22.0 matches 1.*".to_string()));
+        assert_eq!(check("npm:typescript", "typescript").1, "direct package, selector wants manager npm");
+        assert!(check("npm:typescript=latest", "npm:typescript=5.0.0-beta@testing").0);
+        assert_eq!(check("node", "nodejs").1, "name nodejs is not node (names match exactly)");
+        assert!(Candidate::parse("go=1.22@nightly").is_err());
+    }
+}
@@ -1 +1,6 @@
+pub mod check;
+pub mod lint;
+pub mod match_selector;
+pub mod rerun_step;
+pub mod state;
 pub mod test;
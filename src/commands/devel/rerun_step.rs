@@ -0,0 +1,28 @@
+use crate::models::cave::Cave;
+use crate::models::config::Config;
+use anyhow::{Context, Result};
+
+pub fn run(config: &Config, pkg: &str, version: &str, index: usize, variant: Option<&str>, shell: bool) {
+    if let Err(e) = execute(config, pkg, version, index, variant, shell) {
+        log::error!("rerun-step failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Reruns a step of a package of the current cave, with the options and
+/// `.pilocal` of the cave (variant), like `pi cave build` ran it, so the
+/// earlier steps are found under the same build key.
+fn execute(config: &Config, pkg: &str, version: &str, index: usize, variant: Option<&str>, shell: bool) -> Result<()> {
+    let current_dir = std::env::current_dir().context("Failed to get current directory")?;
+    let (_, cave) = Cave::find(config, &current_dir).context("no cave found")?;
+    let settings = cave.get_effective_settings(variant)?;
+    let pilocal_dir = config.pilocal_path(&cave.name, variant);
+    crate::commands::package::build::rerun_step(
+        config,
+        &format!("{}={}", pkg, version),
+        index,
+        &settings.options,
+        &pilocal_dir,
+        shell,
+    )
+}
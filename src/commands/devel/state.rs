@@ -0,0 +1,48 @@
+use crate::models::config::Config;
+use crate::utils::table::SortedTable;
+use anyhow::{Context, Result};
+
+/// Longest value shown in the table; longer ones are cut with "...".
+const VALUE_WIDTH: usize = 60;
+
+pub fn run(config: &Config, scope: Option<&str>, clear: bool) {
+    if let Err(e) = execute(config, scope.unwrap_or(""), clear) {
+        log::error!("state failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Lists (or with `clear` removes) the `state_set` entries of recipes whose
+/// scope starts with `prefix`.
+fn execute(config: &Config, prefix: &str, clear: bool) -> Result<()> {
+    let db = config.db().context("state database is unavailable")?;
+    if clear {
+        if prefix.is_empty() {
+            anyhow::bail!("--clear needs a scope, e.g. `pi devel state main/npm.star --clear`");
+        }
+        let removed = db.state_clear(prefix)?;
+        log::info!("[state] removed {} entries of {}", removed, prefix);
+        return Ok(());
+    }
+
+    let entries = db.state_scan(prefix)?;
+    if entries.is_empty() {
+        log::info!("[state] no entries");
+        return Ok(());
+    }
+    let mut table = SortedTable::new(&["Scope", "Key", "Bytes", "Value"], "scope");
+    for e in entries {
+        let bytes = e.value.len().to_string();
+        table.add_row(vec![e.scope, e.key, bytes, shorten(&e.value)]);
+    }
+    table.print(config.sort.as_ref());
+    Ok(())
+}
+
+fn shorten(value: &str) -> String {
+    let value = value.replace('\n', " ");
+    match value.char_indices().nth(VALUE_WIDTH) {
+        Some((i, _)) => format!("{}...", &value[..i]),
+        None => value,
+    }
+}
@@ -1,107 +1,196 @@
 use crate::models::config::Config;
-use crate::models::version_entry::VersionEntry;
-use log::{error, info};
-use std::path::Path;
-
-pub fn run(config: &Config, filename: &str, pkg: Option<&str>) {
-    info!("testing file: {}", filename);
-
-    let path = Path::new(filename);
-    match crate::starlark::runtime::evaluate_file(path, config) {
-        Ok((packages, managers)) => {
-            info!("registered {} pkgs, {} mgrs", packages.len(), managers.len());
-            if let Some(package_name) = pkg {
-                // Try manager first if it's a manager:package format
-                if let Some(colon_idx) = package_name.find(':') {
-                    let mgr_name = &package_name[..colon_idx];
-                    let pkg_inner = &package_name[colon_idx + 1..];
-
-                    if let Some(mgr) = managers.iter().find(|m| m.name == mgr_name) {
-                        run_manager_function(config, mgr_name, pkg_inner, mgr);
-                        return;
-                    }
-                }
-
-                // Try exact package name match
-                if let Some(pkg_entry) = packages.iter().find(|p| p.name == package_name) {
-                    run_package_function(config, package_name, pkg_entry);
-                    return;
-                }
+use crate::models::package_entry::RegistryEntry;
+use crate::models::version_entry::{InstallStep, VersionEntry};
+use crate::services::downloader::Downloader;
+use crate::services::git::{is_commit_id, GitMirror};
+use crate::services::patcher::Patcher;
+use crate::services::unarchiver::{ArchiveFormat, Unarchiver};
+use crate::starlark::runtime::ExecutionOptions;
+use crate::utils::theme::Theme;
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Arguments of `pi devel test`.
+pub struct TestArgs<'a> {
+    pub filename: &'a str,
+    pub pkg: Option<&'a str>,
+    /// Version to exercise (prefix of the raw version), the newest by default.
+    pub version: Option<&'a str>,
+    /// Build flags as `key=value`.
+    pub options: &'a [String],
+    /// Keep the scratch directory instead of deleting it.
+    pub keep: bool,
+}
 
-                error!("pkg/mgr {} not found", package_name);
-            }
-        }
-        Err(e) => error!("eval failed: {}", e),
+pub fn run(config: &Config, args: TestArgs) {
+    if let Err(e) = execute_test(config, &args) {
+        error!("test failed: {:#}", e);
+        std::process::exit(1);
     }
 }
 
-fn run_manager_function(config: &Config, manager_name: &str, package_name: &str, entry: &crate::models::package_entry::ManagerEntry) {
-    info!(
-        "matched mgr: {} calling {} for {} in {}",
-        manager_name, entry.function_name, package_name, entry.filename
-    );
-
-    let star_path = Path::new(&entry.filename);
-    match crate::starlark::runtime::execute_manager_function(
-        crate::starlark::runtime::ExecutionOptions {
-            path: &star_path,
-            function_name: &entry.function_name,
-            config,
-            options: None,
-        },
-        manager_name,
-        package_name,
-    ) {
-        Ok(mut versions) => {
-            info!("found {} versions", versions.len());
-            versions.sort_by(|a, b| {
-                b.release_date
-                    .cmp(&a.release_date)
-                    .then_with(|| b.version.raw.cmp(&a.version.raw))
-            });
-
-            print_versions_table(&versions);
-
-            if let Some(v) = versions.first() {
-                info!("testing pipeline for version {}", v.version.to_string());
-            }
+fn execute_test(config: &Config, args: &TestArgs) -> Result<()> {
+    info!("testing file: {}", args.filename);
+    let options = parse_options(args.options)?;
+
+    let path = Path::new(args.filename);
+    let (packages, managers) = crate::starlark::runtime::evaluate_file(path, config).context("eval failed")?;
+    info!("registered {} pkgs, {} mgrs", packages.len(), managers.len());
+    let Some(package_name) = args.pkg else { return Ok(()) };
+
+    // Try manager first if it's a manager:package format
+    let manager = package_name.split_once(':')
+        .and_then(|(mgr, pkg)| managers.iter().find(|m| m.name == mgr || m.name == package_name).map(|m| (m, mgr, pkg)));
+    let mut versions = if let Some((entry, mgr_name, pkg_inner)) = manager {
+        info!("matched mgr: {} calling {} for {}", mgr_name, entry.function_name, pkg_inner);
+        match &entry.dir {
+            Some(source) => crate::starlark::runtime::execute_dir_function(exec(path, entry, config, &options), source, pkg_inner),
+            None => crate::starlark::runtime::execute_manager_function(exec(path, entry, config, &options), mgr_name, pkg_inner),
+        }.context("mgr function failed")?
+    } else if let Some(entry) = packages.iter().find(|p| p.name == package_name) {
+        info!("matched pkg: {} calling {}", package_name, entry.function_name);
+        crate::starlark::runtime::execute_function(exec(path, entry, config, &options), package_name).context("function failed")?
+    } else {
+        anyhow::bail!("pkg/mgr {} not found", package_name);
+    };
+
+    info!("found {} versions", versions.len());
+    versions.sort_by(|a, b| {
+        b.release_date
+            .cmp(&a.release_date)
+            .then_with(|| b.version.raw.cmp(&a.version.raw))
+    });
+    print_versions_table(&versions);
+
+    let version = versions.iter()
+        .filter(|v| v.supports(&config.target))
+        .find(|v| args.version.is_none_or(|want| v.version.raw.starts_with(want)))
+        .with_context(|| match args.version {
+            Some(want) => format!("no version matching {} for {}", want, config.target),
+            None => format!("no version for {}", config.target),
+        })?;
+
+    for key in options.keys() {
+        if !version.flags.iter().any(|f| &f.name == key) {
+            warn!("[{}] option '{}' is not a declared flag", version.pkgname, key);
         }
-        Err(e) => error!("mgr function failed: {}", e),
     }
+
+    info!("testing pipeline for version {}", version.version);
+    let scratch = tempfile::Builder::new().prefix("pi-devel-").tempdir()
+        .context("Failed to create scratch directory")?;
+    let local_dir = manager.and_then(|(entry, _, _)| entry.dir.as_ref())
+        .and_then(|source| source.dir(path.parent().unwrap_or(Path::new("."))).canonicalize().ok());
+    crate::commands::package::build::check_local_urls(version, local_dir.as_deref())?;
+    let result = exercise_pipeline(version, scratch.path());
+    if args.keep {
+        info!("scratch dir kept: {}", scratch.keep().display());
+    }
+    result
+}
+
+fn exec<'a>(path: &'a Path, entry: &'a RegistryEntry, config: &'a Config, options: &HashMap<String, String>) -> ExecutionOptions<'a> {
+    ExecutionOptions { path, function_name: &entry.function_name, config, options: Some(options.clone()), known: None, cave: None }
 }
 
-fn run_package_function(config: &Config, package_name: &str, entry: &crate::models::package_entry::PackageEntry) {
-    info!(
-        "matched pkg: {} calling {} from {}",
-        package_name, entry.function_name, entry.filename
-    );
-
-    let star_path = Path::new(&entry.filename);
-    match crate::starlark::runtime::execute_function(
-        crate::starlark::runtime::ExecutionOptions {
-            path: &star_path,
-            function_name: &entry.function_name,
-            config,
-            options: None,
-        },
-        package_name,
-    ) {
-        Ok(mut versions) => {
-            info!("found {} versions", versions.len());
-            versions.sort_by(|a, b| {
-                b.release_date
-                    .cmp(&a.release_date)
-                    .then_with(|| b.version.raw.cmp(&a.version.raw))
-            });
-
-            print_versions_table(&versions);
-
-            if let Some(v) = versions.first() {
-                info!("testing pipeline for version {}", v.version.to_string());
+/// Runs the Fetch, Extract, GitClone and Patch steps of `version` inside `scratch`. Run and
+/// plugin steps need the build sandbox and dependencies, so they are only listed.
+fn exercise_pipeline(version: &VersionEntry, scratch: &Path) -> Result<()> {
+    let mut current: Option<PathBuf> = None;
+    for (i, step) in version.pipeline.iter().enumerate() {
+        let label = format!("[{}] step {} {}", version.pkgname, i, step.kind());
+        match step {
+            InstallStep::Fetch { url, mirrors, checksum, filename, stream: true, .. } => {
+                let dest = scratch.join("extracted");
+                let name = filename.clone().unwrap_or_else(|| url.split('/').next_back().unwrap_or("download").to_string());
+                let urls: Vec<&str> = std::iter::once(url.as_str()).chain(mirrors.iter().map(|m| m.as_str())).collect();
+                let mut spooled = None;
+                let streamed = Downloader::stream_from_mirrors(&urls, checksum.as_deref(), version.sizes.get(url).copied(), &mut |body| {
+                    if dest.exists() {
+                        std::fs::remove_dir_all(&dest)?;
+                    }
+                    spooled = Unarchiver::unarchive_stream(body, &name, &dest, None)?;
+                    Ok(())
+                }).with_context(|| format!("{} failed", label))?;
+                if let Some(spooled) = spooled {
+                    spooled.unarchive(&dest).with_context(|| format!("{} failed", label))?;
+                }
+                if checksum.is_none() {
+                    warn!("{}: no checksum declared", label);
+                }
+                let entries = std::fs::read_dir(&dest)?.count();
+                info!("{}: ok ({}, {} bytes streamed, {} top-level entries)", label, streamed.source, streamed.bytes, entries);
+                current = Some(dest);
+            }
+            InstallStep::Fetch { url, mirrors, checksum, filename, .. } => {
+                let fname = filename.clone().unwrap_or_else(|| url.split('/').next_back().unwrap_or("download").to_string());
+                let dest = scratch.join("downloads").join(fname);
+                std::fs::create_dir_all(scratch.join("downloads"))?;
+                let urls: Vec<&str> = std::iter::once(url.as_str()).chain(mirrors.iter().map(|m| m.as_str())).collect();
+                let source = Downloader::download_from_mirrors(&urls, &dest, checksum.as_deref(), version.sizes.get(url).copied())
+                    .with_context(|| format!("{} failed", label))?;
+                if checksum.is_none() {
+                    warn!("{}: no checksum declared", label);
+                }
+                info!("{}: ok ({}, {} bytes)", label, source, std::fs::metadata(&dest)?.len());
+                current = Some(dest);
+            }
+            InstallStep::Extract { format, .. } => {
+                let src = current.as_ref().with_context(|| format!("{}: requires a Fetch step", label))?;
+                let dest = scratch.join("extracted");
+                let format = format.as_deref().map(str::parse::<ArchiveFormat>).transpose()?;
+                Unarchiver::unarchive_as(src, &dest, format).with_context(|| format!("{} failed", label))?;
+                let entries = std::fs::read_dir(&dest)?.count();
+                info!("{}: ok ({} top-level entries)", label, entries);
+                current = Some(dest);
+            }
+            InstallStep::GitClone { url, rev, depth, .. } => {
+                let mirror = GitMirror::for_url(&scratch.join("downloads"), &version.pkgname, url);
+                let commit = mirror.fetch(url, rev, *depth, true).with_context(|| format!("{} failed", label))?;
+                let dest = scratch.join("checkout");
+                mirror.checkout(url, &commit, &dest).with_context(|| format!("{} failed", label))?;
+                if !is_commit_id(rev) {
+                    warn!("{}: {} is not a commit id, it may move", label, rev);
+                }
+                info!("{}: ok ({} at {})", label, url, commit);
+                current = Some(dest);
+            }
+            InstallStep::Patch { url_or_inline, strip, checksum, .. } => {
+                let dir = current.as_ref().filter(|p| p.is_dir())
+                    .with_context(|| format!("{}: requires a source tree", label))?;
+                let diff = if InstallStep::is_inline_patch(url_or_inline) {
+                    url_or_inline.clone()
+                } else {
+                    let dest = scratch.join("downloads").join(url_or_inline.split('/').next_back().unwrap_or("patch"));
+                    std::fs::create_dir_all(scratch.join("downloads"))?;
+                    Downloader::download_from_mirrors(&[url_or_inline], &dest, checksum.as_deref(), None)
+                        .with_context(|| format!("{} failed", label))?;
+                    std::fs::read_to_string(&dest)?
+                };
+                let files = Patcher::apply(&diff, dir, *strip).with_context(|| format!("{} failed", label))?;
+                info!("{}: ok ({} file(s) patched)", label, files);
+            }
+            InstallStep::Run { command, .. } => {
+                info!("{}: skipped ({})", label, command);
+            }
+            InstallStep::Plugin { step, .. } => {
+                info!("{}: skipped ({} plugin)", label, step);
             }
         }
-        Err(e) => error!("function failed: {}", e),
     }
+    Ok(())
+}
+
+/// Parses `key=value` build options.
+fn parse_options(options: &[String]) -> Result<HashMap<String, String>> {
+    options.iter()
+        .map(|o| {
+            let (k, v) = o.split_once('=').with_context(|| format!("Invalid option '{}', expected key=value", o))?;
+            Ok((k.trim().to_string(), v.to_string()))
+        })
+        .collect()
 }
 
 fn print_versions_table(versions: &[VersionEntry]) {
@@ -109,16 +198,7 @@ fn print_versions_table(versions: &[VersionEntry]) {
         return;
     }
 
-    let mut table = comfy_table::Table::new();
-    table.load_preset(comfy_table::presets::NOTHING);
-    table.set_header(vec![
-        "Package",
-        "Version",
-        "Stream",
-        "Release Date",
-        "Type",
-        "Steps",
-    ]);
+    let mut table = Theme::current().table(&["Package", "Version", "Stream", "Release Date", "Type", "Steps"]);
 
     for v in versions.iter().take(5) {
         let stream = if v.stream.is_empty() { "-" } else { &v.stream };
@@ -134,5 +214,18 @@ fn print_versions_table(versions: &[VersionEntry]) {
         ]);
     }
 
-    println!("{}", table);
+    Theme::current().print(&table);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_options() {
+        let opts = parse_options(&["shared=true".to_string(), "prefix=/opt/a=b".to_string()]).unwrap();
+        assert_eq!(opts["shared"], "true");
+        assert_eq!(opts["prefix"], "/opt/a=b");
+        assert!(parse_options(&["shared".to_string()]).is_err());
+    }
 }
@@ -1,8 +1,24 @@
 use crate::models::config::Config;
+use crate::models::selector::PackageSelector;
+use crate::services::cache::BuildCache;
+use anyhow::{Context, Result};
 use std::fs;
 
-pub fn run(config: &Config, meta: bool, pilocals: bool, packages: bool, downloads: bool, config_flag: bool, state: bool, confirm: bool) {
-    if !meta && !pilocals && !packages && !downloads && !config_flag && !state {
+/// Selects which directories `pi disk clean` removes.
+pub struct CleanTargets {
+    pub meta: bool,
+    pub pilocals: bool,
+    pub packages: bool,
+    pub downloads: bool,
+    pub config: bool,
+    pub state: bool,
+    /// Selector of the package whose builds to forget.
+    pub builds: Option<String>,
+}
+
+pub fn run(config: &Config, targets: CleanTargets, confirm: bool) {
+    let CleanTargets { meta, pilocals, packages, downloads, config: config_flag, state, builds } = targets;
+    if !meta && !pilocals && !packages && !downloads && !config_flag && !state && builds.is_none() {
         println!("No cleaning flags provided. Specify what to clean:");
         println!("  --meta      Delete package list cache");
         println!("  --pilocals  Delete pilocal cave environments");
@@ -10,6 +26,7 @@ pub fn run(config: &Config, meta: bool, pilocals: bool, packages: bool, download
         println!("  --downloads Delete original downloads");
         println!("  --config    Delete config directory (requires --confirm)");
         println!("  --state     Delete state directory (requires --confirm)");
+        println!("  --builds S  Forget the builds of package S and delete their outputs");
         return;
     }
 
@@ -18,6 +35,12 @@ pub fn run(config: &Config, meta: bool, pilocals: bool, packages: bool, download
         return;
     }
 
+    if let Some(selector) = builds
+        && let Err(e) = clean_builds(config, &selector)
+    {
+        log::error!("clean --builds failed: {:#}", e);
+        std::process::exit(1);
+    }
     if meta {
         clean_dir("meta", &config.cache_meta_dir);
     }
@@ -38,6 +61,30 @@ pub fn run(config: &Config, meta: bool, pilocals: bool, packages: bool, download
     }
 }
 
+/// Drops the build cache entries of the package `selector` names (of its
+/// version only, when it has one) and the step outputs they recorded. The
+/// downloads stay, they are shared and checksummed.
+fn clean_builds(config: &Config, selector: &str) -> Result<()> {
+    let selector = PackageSelector::parse(selector).with_context(|| format!("Invalid selector: {}", selector))?;
+    let pkgname = match &selector.prefix {
+        Some(prefix) => format!("{}:{}", prefix, selector.package),
+        None => selector.package.clone(),
+    };
+    let build_cache = BuildCache::new(config.cache_dir.clone());
+    let outputs = build_cache.purge(&pkgname, selector.version.as_deref())?;
+    let mut removed = 0;
+    for path in outputs.iter().filter(|p| p.starts_with(&config.cache_packages_dir) && p.exists()) {
+        let result = if path.is_dir() { fs::remove_dir_all(path) } else { fs::remove_file(path) };
+        result.with_context(|| format!("Failed to remove {}", path.display()))?;
+        if let Some(db) = config.db() {
+            let _ = db.forget(path);
+        }
+        removed += 1;
+    }
+    log::info!("[{}] forgot {} build output(s), removed {}", pkgname, outputs.len(), removed);
+    Ok(())
+}
+
 fn clean_dir(name: &str, path: &std::path::Path) {
     if path.exists() {
         match fs::remove_dir_all(path) {
@@ -0,0 +1,471 @@
+use crate::commands::disk::info::calculate_dir_size;
+use crate::models::cave::Cave;
+use crate::models::config::Config;
+use crate::models::settings::QuotaSettings;
+use crate::services::cache::BuildCache;
+use crate::services::git::GitMirror;
+use crate::utils::duration::format_age;
+use crate::utils::fs::lock_file;
+use crate::utils::size::format_size;
+use crate::utils::theme::Theme;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+/// Entries used more recently than this are kept by `--unreferenced`, so a
+/// build running alongside doesn't lose outputs it hasn't linked yet.
+const UNREFERENCED_GRACE_SECS: u64 = 3600;
+
+/// A top-level entry of a cache section, candidate for eviction.
+pub struct CacheEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Unix seconds of the last use (DB), falling back to the mtime.
+    pub last_use: u64,
+}
+
+/// Cache sections subject to quotas, with their directories.
+pub fn quota_sections(config: &Config) -> [(&'static str, &Path); 3] {
+    [
+        ("pilocals", config.cache_pilocals_dir.as_path()),
+        ("packages", config.cache_packages_dir.as_path()),
+        ("downloads", config.cache_download_dir.as_path()),
+    ]
+}
+
+/// Global quotas from settings, overridden by the quotas of the cave in the current directory.
+pub fn effective_quotas(config: &Config) -> QuotaSettings {
+    let mut quotas = config.settings.quota.clone();
+    if let Ok(current_dir) = std::env::current_dir()
+        && let Some((_, cave)) = Cave::find(config, &current_dir)
+    {
+        quotas.merge(&cave.quota);
+    }
+    quotas
+}
+
+/// Enforces the configured quotas by evicting least recently used cache
+/// entries, or with `unreferenced` removes what no cave references. Running
+/// builds are waited for, so nothing they use is removed under them.
+pub fn run(config: &Config, dry_run: bool, unreferenced: bool) {
+    let _lock = if dry_run {
+        None
+    } else {
+        match lock_file(&config.build_lock_file(), false, "[gc] waiting for running builds to finish") {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                log::error!("gc failed: {:#}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+    if unreferenced {
+        collect_unreferenced(config, dry_run);
+        return;
+    }
+    let quotas = effective_quotas(config);
+    if quotas.is_empty() {
+        log::info!("[gc] no quotas configured (see [quota] in {})", config.config_dir.join("settings.toml").display());
+        return;
+    }
+
+    match quotas.downloads_max_age() {
+        Ok(Some(max_age)) => evict_stale_downloads(config, max_age, dry_run),
+        Ok(None) => {}
+        Err(e) => log::error!("[gc] {:#}", e),
+    }
+
+    let refs = References::collect(config);
+    for (section, dir) in quota_sections(config) {
+        let limit = match quotas.limit(section) {
+            Ok(Some(limit)) => limit,
+            Ok(None) => continue,
+            Err(e) => {
+                log::error!("[gc] {:#}", e);
+                continue;
+            }
+        };
+        enforce_quota(config, &refs, section, dir, limit, dry_run);
+    }
+}
+
+/// Evicts least recently used entries of a section until it fits `limit`,
+/// never those a pilocal still uses (see `References`).
+fn enforce_quota(config: &Config, refs: &References, section: &str, dir: &Path, limit: u64, dry_run: bool) {
+    let mut entries = if section == "downloads" { download_entries(config) } else { list_entries(config, dir) };
+    let mut total: u64 = entries.iter().map(|e| e.size).sum();
+    if total <= limit {
+        log::info!("[gc] {}: {} of {}, ok", section, format_size(total), format_size(limit));
+        return;
+    }
+
+    entries.sort_by_key(|e| e.last_use);
+    let mut evicted = 0;
+    let mut freed = 0;
+    for entry in entries {
+        if total <= limit {
+            break;
+        }
+        if refs.protects(section, &entry.path) || !evict(config, &entry, dry_run) {
+            continue;
+        }
+        total -= entry.size;
+        freed += entry.size;
+        evicted += 1;
+    }
+
+    let verb = if dry_run { "would evict" } else { "evicted" };
+    log::info!(
+        "[gc] {}: {} {} entries, freed {}, now {} of {}",
+        section, verb, evicted, format_size(freed), format_size(total), format_size(limit)
+    );
+}
+
+/// Evicts downloads unused for longer than `max_age` seconds, keeping pinned ones.
+fn evict_stale_downloads(config: &Config, max_age: u64, dry_run: bool) {
+    let stale = stale_downloads(config, max_age);
+    let mut evicted = 0;
+    let mut freed = 0;
+    for entry in stale {
+        if evict(config, &entry, dry_run) {
+            evicted += 1;
+            freed += entry.size;
+        }
+    }
+    let verb = if dry_run { "would evict" } else { "evicted" };
+    log::info!(
+        "[gc] downloads: {} {} entries unused for over {}, freed {}",
+        verb, evicted, format_age(max_age), format_size(freed)
+    );
+}
+
+/// Downloads unused for longer than `max_age` seconds, oldest first, excluding
+/// those a cached build still depends on (see `pinned_downloads`).
+pub fn stale_downloads(config: &Config, max_age: u64) -> Vec<CacheEntry> {
+    let cutoff = now_secs().saturating_sub(max_age);
+    let pinned = pinned_downloads(config);
+    let mut stale: Vec<CacheEntry> = download_entries(config)
+        .into_iter()
+        .filter(|e| e.last_use < cutoff && !pinned.contains(&e.path))
+        .collect();
+    stale.sort_by_key(|e| e.last_use);
+    stale
+}
+
+/// Downloads referenced by a Fetch step of the build cache whose build is still
+/// materialized: a later step's output exists, or the fetched file is itself the
+/// package (Fetch is the last step).
+fn pinned_downloads(config: &Config) -> HashSet<PathBuf> {
+    let build_cache = BuildCache::new(config.cache_dir.clone());
+    let mut pinned = HashSet::new();
+    for cache in build_cache.load_all() {
+        for steps in cache.versions.values() {
+            for (i, step) in steps.iter().enumerate() {
+                let Some(path) = &step.output_path else { continue };
+                if !path.starts_with(&config.cache_download_dir) {
+                    continue;
+                }
+                let later = &steps[i + 1..];
+                if later.is_empty() || later.iter().any(|s| s.output_path.as_ref().is_some_and(|p| p.exists())) {
+                    pinned.insert(path.clone());
+                }
+            }
+        }
+    }
+    pinned
+}
+
+/// Extracted packages and downloads still in use: the package directories
+/// linked from any pilocal (or named in its `env.json`), plus every output
+/// of the cached builds producing them, their downloads, intermediate step
+/// directories and git mirrors included.
+pub struct References {
+    /// Top-level names in `cache_packages_dir`.
+    pub packages: HashSet<OsString>,
+    /// Download files, and git mirrors by directory name.
+    pub downloads: HashSet<PathBuf>,
+    pub mirrors: HashSet<OsString>,
+}
+
+impl References {
+    pub fn collect(config: &Config) -> Self {
+        let roots = linked_packages(config);
+        let mut refs = Self { packages: roots.clone(), downloads: HashSet::new(), mirrors: HashSet::new() };
+        for cache in BuildCache::new(config.cache_dir.clone()).load_all() {
+            for steps in cache.versions.values() {
+                let live = steps.iter()
+                    .filter_map(|s| s.output_path.as_deref())
+                    .filter_map(|p| package_name(config, p))
+                    .any(|name| roots.contains(&name));
+                if !live {
+                    continue;
+                }
+                for step in steps {
+                    if let Some(path) = &step.output_path {
+                        match package_name(config, path) {
+                            Some(name) => { refs.packages.insert(name); }
+                            None => { refs.downloads.insert(path.clone()); }
+                        }
+                    }
+                    // Git checkouts record `<url>@<commit>`; the mirror name doesn't depend on the package.
+                    if let Some((url, _)) = step.source.as_deref().and_then(|s| s.rsplit_once('@'))
+                        && let Some(name) = GitMirror::for_url(&config.cache_download_dir, "", url).path().file_name()
+                    {
+                        refs.mirrors.insert(name.to_os_string());
+                    }
+                }
+            }
+        }
+        refs
+    }
+
+    /// Whether an entry of the cache `section` is referenced; pilocals are
+    /// the roots and never are.
+    fn protects(&self, section: &str, path: &Path) -> bool {
+        match section {
+            "packages" => self.contains_package(path),
+            "downloads" => self.contains_download(path),
+            _ => false,
+        }
+    }
+
+    fn contains_package(&self, path: &Path) -> bool {
+        path.file_name().is_some_and(|name| self.packages.contains(name))
+    }
+
+    fn contains_download(&self, path: &Path) -> bool {
+        self.downloads.contains(path) || path.file_name().is_some_and(|name| self.mirrors.contains(name))
+    }
+}
+
+/// The top-level entry of `cache_packages_dir` holding `path`, if inside it.
+fn package_name(config: &Config, path: &Path) -> Option<OsString> {
+    let rel = path.strip_prefix(&config.cache_packages_dir).ok()?;
+    rel.components().next().map(|c| c.as_os_str().to_os_string())
+}
+
+/// Package directories targeted by a symlink of any pilocal or of the global
+/// prefix, or named in an exported environment value.
+fn linked_packages(config: &Config) -> HashSet<OsString> {
+    let mut linked = HashSet::new();
+    let pilocals = fs::read_dir(&config.cache_pilocals_dir).into_iter().flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .chain(std::iter::once(config.global_dir.clone()));
+    let prefix = format!("{}/", config.cache_packages_dir.display());
+    for pilocal in pilocals {
+        for entry in WalkDir::new(&pilocal).into_iter().filter_map(|e| e.ok()).filter(|e| e.path_is_symlink()) {
+            let Ok(target) = fs::read_link(entry.path()) else { continue };
+            let target = entry.path().parent().map_or(target.clone(), |parent| parent.join(&target));
+            if let Some(name) = package_name(config, &target) {
+                linked.insert(name);
+            }
+        }
+        let Ok(env) = fs::read_to_string(pilocal.join("env.json")) else { continue };
+        for (_, rest) in env.match_indices(&prefix).map(|(i, m)| env.split_at(i + m.len())) {
+            let name: String = rest.chars().take_while(|c| !matches!(c, '/' | '"' | ':')).collect();
+            if !name.is_empty() {
+                linked.insert(name.into());
+            }
+        }
+    }
+    linked
+}
+
+/// Removes the extracted packages and downloads no cave references (see
+/// `References`) and reports what was reclaimed per section.
+fn collect_unreferenced(config: &Config, dry_run: bool) {
+    let refs = References::collect(config);
+    let cutoff = now_secs().saturating_sub(UNREFERENCED_GRACE_SECS);
+    let sections = [
+        ("packages", list_entries(config, &config.cache_packages_dir)),
+        ("downloads", download_entries(config)),
+    ];
+
+    let header = if dry_run { "Would reclaim" } else { "Reclaimed" };
+    let mut table = Theme::current().table(&["Section", "Kept", "Kept size", "Removed", header]);
+    for (section, entries) in sections {
+        let (mut kept, mut kept_size, mut removed, mut freed) = (0, 0, 0, 0);
+        for entry in entries {
+            let referenced = if section == "packages" { refs.contains_package(&entry.path) } else { refs.contains_download(&entry.path) };
+            if referenced || entry.last_use >= cutoff || !evict(config, &entry, dry_run) {
+                kept += 1;
+                kept_size += entry.size;
+            } else {
+                removed += 1;
+                freed += entry.size;
+            }
+        }
+        table.add_row(vec![
+            section.to_string(),
+            kept.to_string(),
+            format_size(kept_size),
+            removed.to_string(),
+            format_size(freed),
+        ]);
+    }
+    Theme::current().print(&table);
+}
+
+/// Removes a cache entry (or only reports it with `dry_run`). Returns false if removal failed.
+fn evict(config: &Config, entry: &CacheEntry, dry_run: bool) -> bool {
+    if dry_run {
+        Theme::current().line(&format!("would evict {} ({})", entry.path.display(), format_size(entry.size)));
+        return true;
+    }
+    let res = if entry.path.is_dir() { fs::remove_dir_all(&entry.path) } else { fs::remove_file(&entry.path) };
+    if let Err(e) = res {
+        log::error!("[gc] failed to remove {}: {}", entry.path.display(), e);
+        return false;
+    }
+    if let Some(db) = config.db() {
+        let _ = db.forget(&entry.path);
+    }
+    log::debug!("[gc] evicted {}", entry.path.display());
+    true
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn list_entries(config: &Config, dir: &Path) -> Vec<CacheEntry> {
+    let Ok(read_dir) = fs::read_dir(dir) else { return Vec::new() };
+    read_dir
+        .filter_map(|e| e.ok())
+        .map(|e| {
+            let path = e.path();
+            let size = if path.is_dir() {
+                calculate_dir_size(&path)
+            } else {
+                e.metadata().map(|m| m.len()).unwrap_or(0)
+            };
+            let last_use = config.db()
+                .and_then(|db| db.last_use(&path).ok().flatten())
+                .unwrap_or_else(|| modified_secs(&path));
+            CacheEntry { path, size, last_use }
+        })
+        .collect()
+}
+
+/// Downloads are evicted file by file: those in the per-package directories
+/// and those left at the top level by the old flat layout.
+fn download_entries(config: &Config) -> Vec<CacheEntry> {
+    list_entries(config, &config.cache_download_dir)
+        .into_iter()
+        .flat_map(|e| if e.path.is_dir() && !e.path.is_symlink() { list_entries(config, &e.path) } else { vec![e] })
+        .collect()
+}
+
+fn modified_secs(path: &Path) -> u64 {
+    fs::symlink_metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::cache::StepResult;
+    use std::time::Duration;
+
+    #[test]
+    fn test_stale_downloads_skip_pinned() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let old = SystemTime::now() - Duration::from_secs(10 * 86400);
+        let download = |name: &str| {
+            let path = config.cache_download_dir.join(name);
+            fs::File::create(&path).unwrap().set_modified(old).unwrap();
+            path
+        };
+        fs::create_dir_all(config.cache_download_dir.join("gone")).unwrap();
+        let used = download("used.tar.gz");
+        let orphan = download("gone/orphan.tar.gz");
+        let extracted = config.cache_packages_dir.join("used-extracted");
+        fs::create_dir_all(&extracted).unwrap();
+
+        let build_cache = BuildCache::new(config.cache_dir.clone());
+        let step = |path: &Path| StepResult { output_path: Some(path.to_path_buf()), ..Default::default() };
+        build_cache.update_step_result("used", "1.0", 0, step(&used)).unwrap();
+        build_cache.update_step_result("used", "1.0", 1, step(&extracted)).unwrap();
+        build_cache.update_step_result("gone", "1.0", 0, step(&orphan)).unwrap();
+        build_cache.update_step_result("gone", "1.0", 1, step(&config.cache_packages_dir.join("gone"))).unwrap();
+
+        let stale: Vec<PathBuf> = stale_downloads(&config, 86400).into_iter().map(|e| e.path).collect();
+        assert_eq!(stale, vec![orphan]);
+        assert!(stale_downloads(&config, 30 * 86400).is_empty());
+    }
+
+    #[test]
+    fn test_references_follow_linked_builds() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let dir = |path: PathBuf| {
+            fs::create_dir_all(&path).unwrap();
+            path
+        };
+        let packages = &config.cache_packages_dir;
+        let downloads = &config.cache_download_dir;
+        let (go, go_step1) = (dir(packages.join("go-1.22")), dir(packages.join("go-1.22-step1")));
+        let (jdk, old) = (dir(packages.join("jdk-21")), dir(packages.join("go-1.21")));
+        dir(downloads.join("go"));
+        let (tarball, old_tarball) = (downloads.join("go/go1.22.tar.gz"), downloads.join("go/go1.21.tar.gz"));
+        fs::write(&tarball, "").unwrap();
+        fs::write(&old_tarball, "").unwrap();
+        let mirror = GitMirror::for_url(downloads, "go", "https://go.dev/x/tools.git");
+        dir(mirror.path().to_path_buf());
+
+        let pilocal = dir(config.cache_pilocals_dir.join("app/bin"));
+        dir(go.join("bin"));
+        std::os::unix::fs::symlink(go.join("bin/go"), pilocal.join("go")).unwrap();
+        fs::write(pilocal.parent().unwrap().join("env.json"), format!("{{\"JAVA_HOME\":\"{}\"}}", jdk.display())).unwrap();
+
+        let build_cache = BuildCache::new(config.cache_dir.clone());
+        let step = |path: &Path, source: Option<&str>| StepResult {
+            output_path: Some(path.to_path_buf()),
+            source: source.map(str::to_string),
+            ..Default::default()
+        };
+        build_cache.update_step_result("go", "1.22", 0, step(&tarball, None)).unwrap();
+        build_cache.update_step_result("go", "1.22", 1, step(&go_step1, Some("https://go.dev/x/tools.git@abc"))).unwrap();
+        build_cache.update_step_result("go", "1.22", 2, step(&go, None)).unwrap();
+        build_cache.update_step_result("go", "1.21", 0, step(&old_tarball, None)).unwrap();
+        build_cache.update_step_result("go", "1.21", 1, step(&old, None)).unwrap();
+
+        let refs = References::collect(&config);
+        for path in [&go, &go_step1, &jdk] {
+            assert!(refs.contains_package(path), "{}", path.display());
+        }
+        assert!(!refs.contains_package(&old));
+        assert!(refs.contains_download(&tarball) && refs.contains_download(mirror.path()));
+        assert!(!refs.contains_download(&old_tarball));
+    }
+
+    #[test]
+    fn test_quota_keeps_referenced_packages() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let (used, unused) = (config.cache_packages_dir.join("go-1.22"), config.cache_packages_dir.join("go-1.21"));
+        for (dir, age) in [(&used, 20), (&unused, 10)] {
+            fs::create_dir_all(dir.join("bin")).unwrap();
+            fs::write(dir.join("bin/go"), "binary").unwrap();
+            fs::File::open(dir).unwrap().set_modified(SystemTime::now() - Duration::from_secs(age * 86400)).unwrap();
+        }
+        let pilocal = config.cache_pilocals_dir.join("app/bin");
+        fs::create_dir_all(&pilocal).unwrap();
+        std::os::unix::fs::symlink(used.join("bin/go"), pilocal.join("go")).unwrap();
+
+        // The least recently used entry is linked, so the other one goes.
+        let refs = References::collect(&config);
+        enforce_quota(&config, &refs, "packages", &config.cache_packages_dir, 0, false);
+        assert!(used.exists());
+        assert!(!unused.exists());
+    }
+}
@@ -1,26 +1,103 @@
+use crate::commands::disk::gc::{effective_quotas, now_secs, quota_sections, stale_downloads};
 use crate::models::config::Config;
-use comfy_table::presets::NOTHING;
+use crate::services::cache::BuildCache;
+use crate::utils::duration::format_age;
+use crate::utils::size::format_size;
+use crate::utils::theme::Theme;
 use comfy_table::Table;
 use std::fs;
 use std::path::Path;
 use walkdir::WalkDir;
 
-pub fn run(config: &Config) {
-    let mut table = Table::new();
-    table.load_preset(NOTHING);
-    table.set_header(vec!["Directory", "Path", "Size"]);
+pub fn run(config: &Config, stale: bool) {
+    let quotas = effective_quotas(config);
+    if stale {
+        show_stale(config, quotas.downloads_max_age());
+        return;
+    }
+    let mut table = Theme::current().table(&["Directory", "Path", "Size", "Quota"]);
+
+    add_row(&mut table, "Config", &config.config_dir, None);
+    add_row(&mut table, "State", &config.state_dir, None);
+    add_row(&mut table, "Cache (Meta)", &config.cache_meta_dir, None);
+
+    let mut exceeded = Vec::new();
+    for (section, dir) in quota_sections(config) {
+        let limit = quotas.limit(section).unwrap_or_else(|e| {
+            log::error!("{:#}", e);
+            None
+        });
+        let name = format!("Cache ({}{})", section[..1].to_uppercase(), &section[1..]);
+        let size = add_row(&mut table, &name, dir, limit);
+        if let Some(limit) = limit.filter(|l| size > *l) {
+            exceeded.push((section, size, limit));
+        }
+    }
+
+    Theme::current().print(&table);
+    show_largest_packages(config);
 
-    add_row(&mut table, "Config", &config.config_dir);
-    add_row(&mut table, "State", &config.state_dir);
-    add_row(&mut table, "Cache (Meta)", &config.cache_meta_dir);
-    add_row(&mut table, "Cache (Pilocals)", &config.cache_pilocals_dir);
-    add_row(&mut table, "Cache (Packages)", &config.cache_packages_dir);
-    add_row(&mut table, "Cache (Downloads)", &config.cache_download_dir);
+    for (section, size, limit) in exceeded {
+        log::warn!("[{}] {} exceeds quota {}, run `pi disk gc`", section, format_size(size), format_size(limit));
+    }
+}
+
+/// Built packages `disk info` lists, largest first.
+const LARGEST_PACKAGES: usize = 10;
 
-    println!("{table}");
+/// The built packages taking the most space in the package store, from the
+/// footprints builds record.
+fn show_largest_packages(config: &Config) {
+    let mut builds: Vec<(String, String, u64, usize)> = BuildCache::new(config.cache_dir.clone()).packages().into_iter()
+        .flat_map(|(name, cache)| {
+            let package = if cache.pkgname.is_empty() { name } else { cache.pkgname.clone() };
+            cache.existing_footprints().into_iter()
+                .map(|(key, fp)| (package.clone(), key.to_string(), fp.bytes, fp.files))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    if builds.is_empty() {
+        return;
+    }
+    builds.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| (&a.0, &a.1).cmp(&(&b.0, &b.1))));
+    let total: u64 = builds.iter().map(|b| b.2).sum();
+    let mut table = Theme::current().table(&["Package", "Build", "Installed", "Files"]);
+    for (package, key, bytes, files) in builds.iter().take(LARGEST_PACKAGES) {
+        table.add_row(vec![package.clone(), key.clone(), format_size(*bytes), files.to_string()]);
+    }
+    Theme::current().print(&table);
+    Theme::current().line(&format!("{} builds, {} installed", builds.len(), format_size(total)));
 }
 
-fn add_row(table: &mut Table, name: &str, path: &Path) {
+fn show_stale(config: &Config, max_age: anyhow::Result<Option<u64>>) {
+    let max_age = match max_age {
+        Ok(Some(age)) => age,
+        Ok(None) => {
+            log::info!("[gc] no downloads_max_age configured (see [quota] in {})", config.config_dir.join("settings.toml").display());
+            return;
+        }
+        Err(e) => {
+            log::error!("[gc] {:#}", e);
+            return;
+        }
+    };
+
+    let entries = stale_downloads(config, max_age);
+    let now = now_secs();
+    let mut table = Theme::current().table(&["Download", "Size", "Unused For"]);
+    for entry in &entries {
+        table.add_row(vec![
+            entry.path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default(),
+            format_size(entry.size),
+            format_age(now.saturating_sub(entry.last_use)),
+        ]);
+    }
+    Theme::current().print(&table);
+    let total: u64 = entries.iter().map(|e| e.size).sum();
+    log::info!("[gc] {} stale downloads, {} (older than {})", entries.len(), format_size(total), format_age(max_age));
+}
+
+fn add_row(table: &mut Table, name: &str, path: &Path, quota: Option<u64>) -> u64 {
     let size = if path.exists() {
         calculate_dir_size(path)
     } else {
@@ -31,10 +108,12 @@ fn add_row(table: &mut Table, name: &str, path: &Path) {
         name.to_string(),
         path.to_string_lossy().to_string(),
         format_size(size),
+        quota.map(format_size).unwrap_or_else(|| "-".to_string()),
     ]);
+    size
 }
 
-fn calculate_dir_size(path: &Path) -> u64 {
+pub fn calculate_dir_size(path: &Path) -> u64 {
     WalkDir::new(path)
         .into_iter()
         .filter_map(|entry| entry.ok())
@@ -43,19 +122,3 @@ fn calculate_dir_size(path: &Path) -> u64 {
         .map(|metadata| metadata.len())
         .sum()
 }
-
-fn format_size(size: u64) -> String {
-    const KB: u64 = 1024;
-    const MB: u64 = KB * 1024;
-    const GB: u64 = MB * 1024;
-
-    if size >= GB {
-        format!("{:.2} GB", size as f64 / GB as f64)
-    } else if size >= MB {
-        format!("{:.2} MB", size as f64 / MB as f64)
-    } else if size >= KB {
-        format!("{:.2} KB", size as f64 / KB as f64)
-    } else {
-        format!("{} B", size)
-    }
-}
@@ -1,2 +1,4 @@
 pub mod clean;
+pub mod gc;
 pub mod info;
+pub mod verify;
@@ -0,0 +1,214 @@
+use crate::models::config::Config;
+use crate::services::cache::BuildCache;
+use crate::utils::crypto::calculate_file_checksum;
+use crate::utils::table::SortedTable;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// An inconsistency between the build cache and the files it points at.
+#[derive(Debug, PartialEq)]
+pub enum Issue {
+    /// A successful step whose output is gone.
+    MissingOutput { package: String, version: String, index: usize, path: PathBuf },
+    /// A downloaded file that no longer matches the hash recorded after fetching it.
+    Corrupt { package: String, version: String, index: usize, path: PathBuf },
+    /// An extracted package no step result refers to.
+    Orphaned { path: PathBuf },
+}
+
+impl Issue {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::MissingOutput { .. } => "missing output",
+            Self::Corrupt { .. } => "hash mismatch",
+            Self::Orphaned { .. } => "orphaned",
+        }
+    }
+
+    fn path(&self) -> &Path {
+        match self {
+            Self::MissingOutput { path, .. } | Self::Corrupt { path, .. } | Self::Orphaned { path } => path,
+        }
+    }
+
+    /// The build cache entry that has to go, as (package, version, step).
+    fn step(&self) -> Option<(&str, &str, usize)> {
+        match self {
+            Self::MissingOutput { package, version, index, .. } | Self::Corrupt { package, version, index, .. } => {
+                Some((package, version, *index))
+            }
+            Self::Orphaned { .. } => None,
+        }
+    }
+}
+
+/// `pi disk verify`: exits with 1 when it finds issues and isn't told to repair them.
+pub fn run(config: &Config, repair: bool) {
+    let result = find_issues(config).and_then(|issues| {
+        print_issues(config, &issues);
+        if !repair {
+            return Ok(issues.is_empty());
+        }
+        // Dropping steps can orphan the directories they produced.
+        let mut issues = issues;
+        while !issues.is_empty() {
+            repair_issues(config, &issues)?;
+            issues = find_issues(config)?;
+        }
+        Ok(true)
+    });
+    match result {
+        Ok(true) => {}
+        Ok(false) => {
+            log::error!("[verify] run `pi disk verify --repair` to drop stale entries");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            log::error!("verify failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Walks every step result of the build cache, then the extracted packages.
+pub fn find_issues(config: &Config) -> Result<Vec<Issue>> {
+    let build_cache = BuildCache::new(config.cache_dir.clone());
+    let mut issues = Vec::new();
+    let mut outputs = Vec::new();
+    for (package, cache) in build_cache.packages() {
+        for (version, steps) in &cache.versions {
+            for (index, step) in steps.iter().enumerate() {
+                let Some(path) = step.output_path.clone().filter(|_| step.status == "Success") else { continue };
+                if !path.exists() {
+                    issues.push(Issue::MissingOutput { package: package.clone(), version: version.clone(), index, path });
+                    continue;
+                }
+                if path.is_file() && is_corrupt(&build_cache, &package, version, index, &step.step_hash, &path)? {
+                    issues.push(Issue::Corrupt { package: package.clone(), version: version.clone(), index, path });
+                    continue;
+                }
+                outputs.push(path);
+            }
+        }
+    }
+
+    if let Ok(read_dir) = fs::read_dir(&config.cache_packages_dir) {
+        let mut orphaned: Vec<PathBuf> = read_dir
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_dir() && p.to_string_lossy().ends_with("-extracted"))
+            .filter(|p| !outputs.iter().any(|o| o.starts_with(p)))
+            .collect();
+        orphaned.sort();
+        issues.extend(orphaned.into_iter().map(|path| Issue::Orphaned { path }));
+    }
+    Ok(issues)
+}
+
+/// Whether the file output of a step differs from its inventory. Files
+/// without an inventory (built before inventories existed) pass.
+fn is_corrupt(build_cache: &BuildCache, package: &str, version: &str, index: usize, step_hash: &str, path: &Path) -> Result<bool> {
+    let Some(inventory) = build_cache.load_inventory(package, version, index, step_hash) else { return Ok(false) };
+    let Some(recorded) = inventory.files.iter().find(|f| f.path.is_empty()).and_then(|f| f.sha256.as_deref()) else {
+        return Ok(false);
+    };
+    let actual = calculate_file_checksum(path, 64).with_context(|| format!("Failed to hash {}", path.display()))?;
+    Ok(actual != recorded)
+}
+
+fn print_issues(config: &Config, issues: &[Issue]) {
+    if issues.is_empty() {
+        log::info!("[verify] build cache, packages and downloads are consistent");
+        return;
+    }
+    let mut table = SortedTable::new(&["Problem", "Package", "Step", "Path"], "package");
+    for issue in issues {
+        let (package, step) = match issue.step() {
+            Some((package, version, index)) => (format!("{} {}", package, version), index.to_string()),
+            None => ("-".to_string(), "-".to_string()),
+        };
+        table.add_row(vec![issue.kind().to_string(), package, step, issue.path().display().to_string()]);
+    }
+    table.print(config.sort.as_ref());
+}
+
+/// Truncates the cached steps of each version at its first bad step (later
+/// steps consumed its output) and removes corrupt downloads and orphaned
+/// directories; the next build recomputes them.
+pub fn repair_issues(config: &Config, issues: &[Issue]) -> Result<()> {
+    let mut first_bad: BTreeMap<(&str, &str), usize> = BTreeMap::new();
+    for (package, version, index) in issues.iter().filter_map(Issue::step) {
+        let first = first_bad.entry((package, version)).or_insert(index);
+        *first = (*first).min(index);
+    }
+
+    let build_cache = BuildCache::new(config.cache_dir.clone());
+    for ((package, version), index) in first_bad {
+        let mut cache = build_cache.load(package);
+        if let Some(steps) = cache.versions.get_mut(version) {
+            steps.truncate(index);
+        }
+        build_cache.save(package, &cache)?;
+        log::info!("[verify] {} {}: dropped cached steps from {}", package, version, index);
+    }
+
+    for issue in issues {
+        let path = issue.path();
+        let removed = match issue {
+            Issue::Corrupt { .. } => fs::remove_file(path),
+            Issue::Orphaned { .. } => fs::remove_dir_all(path),
+            Issue::MissingOutput { .. } => continue,
+        };
+        removed.with_context(|| format!("Failed to remove {}", path.display()))?;
+        if let Some(db) = config.db() {
+            let _ = db.forget(path);
+        }
+        log::info!("[verify] removed {}", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::cache::build::{scan_inventory, StepInventory};
+    use crate::services::cache::StepResult;
+
+    #[test]
+    fn test_verify_and_repair() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let build_cache = BuildCache::new(config.cache_dir.clone());
+        let step = |path: &Path, hash: &str| StepResult {
+            output_path: Some(path.to_path_buf()), step_hash: hash.to_string(), status: "Success".to_string(), ..Default::default()
+        };
+
+        let download = config.cache_download_dir.join("go.tgz");
+        fs::write(&download, "go").unwrap();
+        let inventory = StepInventory { step_hash: "f".to_string(), kind: "Fetch".to_string(), files: scan_inventory(&download, &[]).unwrap() };
+        build_cache.save_inventory("go", "1.22.0", 0, &inventory).unwrap();
+        let extracted = config.cache_packages_dir.join("go-1.22.0-extracted");
+        fs::create_dir_all(extracted.join("bin")).unwrap();
+        build_cache.update_step_result("go", "1.22.0", 0, step(&download, "f")).unwrap();
+        build_cache.update_step_result("go", "1.22.0", 1, step(&extracted, "e")).unwrap();
+        build_cache.update_step_result("go", "1.22.0", 2, step(&extracted.join("bin"), "r")).unwrap();
+        let orphan = config.cache_packages_dir.join("node-20-extracted");
+        fs::create_dir_all(&orphan).unwrap();
+        build_cache.update_step_result("zig", "0.13", 0, step(&config.cache_download_dir.join("zig.tar.xz"), "z")).unwrap();
+        assert_eq!(find_issues(&config).unwrap().len(), 2);
+
+        fs::write(&download, "tampered").unwrap();
+        let issues = find_issues(&config).unwrap();
+        let kinds: Vec<&str> = issues.iter().map(|i| i.kind()).collect();
+        assert_eq!(kinds, vec!["hash mismatch", "missing output", "orphaned"]);
+
+        repair_issues(&config, &issues).unwrap();
+        assert!(!download.exists() && !orphan.exists() && extracted.exists());
+        assert!(build_cache.load("go").versions["1.22.0"].is_empty());
+        assert!(build_cache.load("zig").versions["0.13"].is_empty());
+        // The extracted directory is orphaned now; `run` repairs again until clean.
+        assert_eq!(find_issues(&config).unwrap(), vec![Issue::Orphaned { path: extracted }]);
+    }
+}
@@ -1,5 +1,9 @@
+pub mod config;
+pub mod daemon;
 pub mod devel;
 pub mod disk;
 pub mod package;
 pub mod repo;
 pub mod cave;
+pub mod serve;
+pub mod which;
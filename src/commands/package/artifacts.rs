@@ -0,0 +1,124 @@
+use crate::commands::package::resolve;
+use crate::models::config::Config;
+use crate::models::repository::Repositories;
+use crate::models::selector::PackageSelector;
+use crate::models::version_entry::VersionEntry;
+use crate::services::cache::build::{split_build_key, ArtifactFile};
+use crate::services::cache::{BuildCache, StepResult};
+use crate::utils::size::format_size;
+use crate::utils::theme::Theme;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Hash prefix shown per file; enough to tell artifacts apart.
+const HASH_WIDTH: usize = 16;
+
+pub fn run(config: &Config, selector_str: &str) {
+    if let Err(e) = execute(config, selector_str) {
+        log::error!("artifacts failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Prints, per cached step of the resolved version (once per set of build
+/// options it was built with), its output and the files the step added or
+/// changed there, from the inventories recorded at build time.
+fn execute(config: &Config, selector_str: &str) -> Result<()> {
+    let selector = PackageSelector::parse(selector_str).context("invalid selector")?;
+    let (_, version, _) = resolve::resolve_query(config, Repositories::get_all(config), &selector)
+        .with_context(|| format!("package not found: {}", selector_str))?;
+    let version_str = version.version.to_string();
+    let build_cache = BuildCache::new(config.cache_dir.clone());
+    let cache = build_cache.load(&version.pkgname);
+    // One entry per set of build options (see `build_key`).
+    let mut keys: Vec<&String> = cache.versions.keys()
+        .filter(|k| split_build_key(k).0 == version_str)
+        .collect();
+    keys.sort();
+    if keys.is_empty() {
+        let mut built: Vec<&str> = cache.versions.keys().map(|k| split_build_key(k).0).collect();
+        built.sort();
+        built.dedup();
+        if built.is_empty() {
+            anyhow::bail!("{}={} is not built", version.pkgname, version_str);
+        }
+        anyhow::bail!("{}={} is not built, built versions: {}", version.pkgname, version_str, built.join(", "));
+    }
+    for key in keys {
+        if *key != version_str {
+            Theme::current().line(&format!("== {} built with options {}\n", version.pkgname, key));
+        }
+        print_build(&build_cache, &version, key, &cache.versions[key]);
+    }
+    Ok(())
+}
+
+fn print_build(build_cache: &BuildCache, version: &VersionEntry, key: &str, steps: &[StepResult]) {
+    let mut previous: Option<(PathBuf, Vec<ArtifactFile>)> = None;
+    for (i, step) in steps.iter().enumerate() {
+        if step.status != "Success" {
+            continue;
+        }
+        let inventory = build_cache.load_inventory(&version.pkgname, key, i, &step.step_hash);
+        let kind = inventory.as_ref().map(|inv| inv.kind.clone())
+            .or_else(|| version.pipeline.get(i).map(|s| s.kind().to_string()))
+            .unwrap_or_else(|| "-".to_string());
+        let name = step.name.as_ref().map(|n| format!(" ({})", n)).unwrap_or_default();
+        let output = step.output_path.clone().unwrap_or_default();
+        Theme::current().line(&format!("step {} {}{}: {}", i, kind, name, output.display()));
+
+        let Some(inventory) = inventory else {
+            Theme::current().line("  no inventory recorded, rebuild the package with --rebuild to list its files\n");
+            continue;
+        };
+        let before = match &previous {
+            Some((root, files)) if *root == output => files.as_slice(),
+            _ => &[],
+        };
+        let changed = changed_files(&inventory.files, before);
+        if changed.is_empty() {
+            Theme::current().line("  no files added or changed\n");
+        } else {
+            let size: u64 = changed.iter().map(|f| f.size).sum();
+            let mut table = Theme::current().table(&["File", "Size", "SHA-256"]);
+            for f in &changed {
+                let path = if f.path.is_empty() { output.file_name().unwrap_or_default().to_string_lossy().into_owned() } else { f.path.clone() };
+                let hash = match &f.sha256 {
+                    Some(h) => h[..HASH_WIDTH.min(h.len())].to_string(),
+                    None => "symlink".to_string(),
+                };
+                table.add_row(vec![path, format_size(f.size), hash]);
+            }
+            Theme::current().print(&table);
+            Theme::current().line(&format!("  {} files, {}\n", changed.len(), format_size(size)));
+        }
+        previous = Some((output, inventory.files));
+    }
+}
+
+/// Files of `files` that are new or differ from `before`, the inventory of
+/// the previous step when it wrote to the same output.
+fn changed_files<'a>(files: &'a [ArtifactFile], before: &[ArtifactFile]) -> Vec<&'a ArtifactFile> {
+    let before: HashMap<&str, &ArtifactFile> = before.iter().map(|f| (f.path.as_str(), f)).collect();
+    files.iter()
+        .filter(|f| before.get(f.path.as_str()).is_none_or(|b| b.size != f.size || b.sha256 != f.sha256))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changed_files() {
+        let file = |path: &str, hash: &str| ArtifactFile {
+            path: path.to_string(), size: 1, modified: 0, sha256: Some(hash.to_string()),
+        };
+        let extracted = vec![file("configure", "a"), file("src/main.c", "b")];
+        let built = vec![file("configure", "a"), file("src/main.c", "b2"), file("src/main.o", "c")];
+        let paths = |files: Vec<&ArtifactFile>| files.iter().map(|f| f.path.clone()).collect::<Vec<_>>();
+        assert_eq!(paths(changed_files(&built, &extracted)), vec!["src/main.c", "src/main.o"]);
+        assert_eq!(paths(changed_files(&extracted, &[])), vec!["configure", "src/main.c"]);
+    }
+}
@@ -1,20 +1,54 @@
 use crate::models::config::Config;
+use crate::models::context::CaveInfo;
+use crate::models::cave_sums::CaveSums;
 use crate::models::selector::PackageSelector;
 use crate::models::repository::Repositories;
+use crate::models::package_entry::{PackageList, DIR_MANAGER};
 use crate::commands::package::resolve;
 use crate::services::downloader::Downloader;
-use crate::services::unarchiver::Unarchiver;
+use crate::services::unarchiver::{ArchiveFormat, Unarchiver};
 use crate::services::cache::{BuildCache, StepResult};
-use crate::models::version_entry::{InstallStep, Export, VersionEntry, QualifiedVersion};
-use crate::commands::cave::fs::apply_filemap_entry;
-use crate::utils::fs::sanitize_name;
-use crate::utils::crypto::hash_to_string;
+use crate::services::cache::build::{scan_inventory, ArtifactFile, Footprint, StepInventory, OPTIONS_SEPARATOR};
+use crate::services::events::{self, Event};
+use crate::services::git::{is_commit_id, GitMirror};
+use crate::services::patcher::Patcher;
+use crate::services::plugins::{Executors, StepRequest, PLUGIN_PREFIX, PROTOCOL_VERSION};
+use crate::services::sandbox::diagnostics::{self, StepFailure, TAIL_LINES};
+use crate::services::sandbox::builder::spawn_command_with_tail;
+use crate::services::step_logs;
+use crate::services::venv::{VenvSpec, BUILTIN_REPO};
+use crate::utils::permissions::{self, Normalized, PermissionPolicy};
+use crate::models::version_entry::{InstallStep, Interpreter, Export, Signature, VersionEntry, QualifiedVersion};
+use crate::commands::cave::fs::{apply_filemap_entry, ExportChmod, ExportManifest, ExportRewrite, ExportService, FileMapOutcome};
+use crate::utils::subst::{expand_package_refs, PathVars};
+use crate::utils::fs::{available_space, copy_tree, lock_file, sanitize_name};
+use crate::utils::size::format_size;
+use crate::utils::crypto::{calculate_file_checksum, hash_to_string};
+use crate::utils::timings::Phase;
 use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
 use anyhow::{Context, Result};
 use chrono;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use serde::Serialize;
+use rayon::prelude::*;
+use std::time::Instant;
+use sha2::Digest;
+
+/// Placeholder for the recipe's repository in Run commands, as returned by
+/// the `repo_files()` builtin.
+pub const REPO_PLACEHOLDER: &str = "@REPO_DIR";
+/// Where the recipe's repository is mounted read-only in build sandboxes.
+pub const REPO_MOUNT: &str = "/pi/repo";
+
+/// Exports of a built package: (pkg_ctx, source root, exports).
+type PackageExports = Vec<(String, PathBuf, Vec<Export>)>;
+
+/// Resolved packages keyed by query: (version, repo name). A version's
+/// `build_dependencies` names are keys of the same map.
+pub type DependencyGraph = HashMap<String, (VersionEntry, String)>;
 
 pub struct BuildContext<'a> {
     pub config: &'a Config,
@@ -22,6 +56,92 @@ pub struct BuildContext<'a> {
     pub build_cache: &'a BuildCache,
     pub all_options: &'a HashMap<String, HashMap<String, serde_json::Value>>,
     pub pilocal_dir: &'a Path,
+    /// Artifact hashes of the cave being built, checked after every Fetch.
+    pub sums: Option<&'a CaveSums>,
+    /// Packages the cave lets build outside the sandbox (`host_build`).
+    pub host_build: &'a [String],
+    /// The cave being built, exposed to recipes as `cave_info()`.
+    pub cave: Option<&'a CaveInfo>,
+    /// Prefixes for the `bin/` links of packages, by package name (`bin_prefix`).
+    pub bin_prefix: &'a HashMap<String, String>,
+    pub stats: BuildStats,
+}
+
+/// What `build_packages` builds, and into which pilocal.
+pub struct BuildRequest<'a> {
+    pub packages: &'a [String],
+    pub all_options: &'a HashMap<String, HashMap<String, serde_json::Value>>,
+    pub pilocal_dir: &'a Path,
+    pub sums: Option<&'a CaveSums>,
+    pub host_build: &'a [String],
+    pub bin_prefix: &'a HashMap<String, String>,
+    pub cave: Option<&'a CaveInfo>,
+    /// `packages` already resolved with `all_options` (by `--locked`), so
+    /// they aren't resolved again.
+    pub graph: Option<DependencyGraph>,
+}
+
+/// Result of `build_packages`: the merged env exports and the built packages.
+pub struct BuildOutput {
+    pub env: HashMap<String, String>,
+    pub packages: Vec<BuiltPackage>,
+    /// What the requested packages and their dependencies resolved to.
+    pub graph: DependencyGraph,
+}
+
+/// A built package and the directory its exports link into.
+pub struct BuiltPackage {
+    pub pkgname: String,
+    pub version: String,
+    pub root: PathBuf,
+    pub stats: PackageStats,
+}
+
+/// What building one package took, for the `pi cave build` summary.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PackageStats {
+    /// Some step ran; false when every step came from the cache.
+    pub built: bool,
+    pub download_bytes: u64,
+    pub duration_ms: u128,
+    /// Exports (links and env vars) applied to the pilocal.
+    pub exports: usize,
+    /// Size and regular files of the package's output.
+    pub install_bytes: u64,
+    pub install_files: usize,
+}
+
+/// Step counters collected while executing pipelines.
+#[derive(Default)]
+pub struct BuildStats {
+    pub steps_run: AtomicUsize,
+    pub steps_cached: AtomicUsize,
+    /// Bytes fetched by Fetch steps that downloaded.
+    pub download_bytes: AtomicU64,
+}
+
+impl BuildStats {
+    fn add(&self, other: &BuildStats) {
+        self.steps_run.fetch_add(other.steps_run.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.steps_cached.fetch_add(other.steps_cached.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.download_bytes.fetch_add(other.download_bytes.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+/// Outcome of a single executed pipeline step.
+pub struct StepOutput {
+    pub path: PathBuf,
+    /// URL a Fetch step actually downloaded from (primary or mirror).
+    pub source: Option<String>,
+    /// sha256 and size of the archive a streamed Fetch extracted, which
+    /// leaves no file to hash.
+    pub streamed: Option<(String, u64)>,
+}
+
+impl From<PathBuf> for StepOutput {
+    fn from(path: PathBuf) -> Self {
+        Self { path, source: None, streamed: None }
+    }
 }
 
 pub struct StepContext<'a> {
@@ -30,36 +150,185 @@ pub struct StepContext<'a> {
     pub dependency_dirs: Vec<PathBuf>,
     pub pkgname: &'a str,
     pub version: &'a str,
+    /// `build_key` of the version: names its directories.
+    pub build_key: &'a str,
+    /// Cave variant being built (":dev"), for the rerun-step hint.
+    pub variant: Option<&'a str>,
+    /// Position of the step in the pipeline.
+    pub index: usize,
     pub pilocal_dir: &'a Path,
+    /// Repository of the recipe, mounted read-only at `REPO_MOUNT` for Run steps.
+    pub repo_dir: Option<&'a Path>,
+    /// Run steps execute on the host instead of in the sandbox.
+    pub host_build: bool,
+    /// Artifact sizes the recipe recorded, for download progress.
+    pub sizes: &'a BTreeMap<String, u64>,
 }
 
-pub fn build_packages(
-    config: &Config,
-    packages: &[String],
-    all_options: &HashMap<String, HashMap<String, serde_json::Value>>,
-    pilocal_dir: &Path,
-) -> Result<HashMap<String, String>> {
+#[tracing::instrument(skip_all)]
+pub fn build_packages(config: &Config, request: BuildRequest) -> Result<BuildOutput> {
+    let BuildRequest { packages, all_options, pilocal_dir, sums, host_build, bin_prefix, cave, graph } = request;
+    if let Some((pkg, prefix)) = bin_prefix.iter().find(|(_, p)| p.is_empty() || p.contains('/')) {
+        anyhow::bail!("bin_prefix of {} must be a non-empty file name prefix, not '{}'", pkg, prefix);
+    }
+    let start = Instant::now();
+    let _lock = lock_file(&config.build_lock_file(), true, "[build] waiting for pi disk gc to finish")?;
+    cleanup_orphaned_build_homes(config);
     let repo_config = Repositories::get_all(config);
     let build_cache = BuildCache::new(config.cache_dir.clone());
 
     let ctx = BuildContext {
         config,
-        repo_config: &repo_config,
+        repo_config,
         build_cache: &build_cache,
         all_options,
         pilocal_dir,
+        sums,
+        host_build,
+        cave,
+        bin_prefix,
+        stats: BuildStats::default(),
     };
 
-    let resolved_packages = resolve_dependencies(&ctx, packages)?;
-    let sorted_packages = topological_sort(&resolved_packages)?;
+    let resolved_packages = match graph {
+        Some(graph) => graph,
+        None => resolve_dependencies(&ctx, packages)?,
+    };
+    check_graph_local_urls(config, repo_config, &resolved_packages)?;
+    migrate_flat_downloads(config, &resolved_packages);
+    if config.strict {
+        check_strict(&resolved_packages, sums)?;
+    }
+    check_disk_space(config, &download_plan(config, &resolved_packages))?;
+    let waves = build_waves(&resolved_packages)?;
+    let package_count = waves.iter().map(Vec::len).sum();
+
+    let mut output = execute_sorted_pipelines(&ctx, waves, &resolved_packages)?;
+    output.graph = resolved_packages;
+
+    events::publish(Event::BuildSummary {
+        packages: package_count,
+        steps_run: ctx.stats.steps_run.load(Ordering::Relaxed),
+        steps_cached: ctx.stats.steps_cached.load(Ordering::Relaxed),
+        duration_ms: start.elapsed().as_millis(),
+    });
+    Ok(output)
+}
+
+/// A download a build of the resolved packages would make.
+#[derive(Debug, Clone)]
+pub struct PlannedDownload {
+    pub pkgname: String,
+    pub version: String,
+    pub url: String,
+    /// Recorded by the recipe (`fetch(..., size=)`), if known.
+    pub size: Option<u64>,
+    /// Already in the download cache.
+    pub cached: bool,
+}
+
+/// Every Fetch of the resolved packages, sorted by package.
+pub fn download_plan(config: &Config, resolved: &DependencyGraph) -> Vec<PlannedDownload> {
+    let mut plan: Vec<PlannedDownload> = resolved.values()
+        .flat_map(|(version, _)| version.fetch_sizes().filter_map(move |(step, size)| match step {
+            InstallStep::Fetch { url, filename, .. } => {
+                let (dest, _) = locate_download(config, &version.pkgname, url, &fetch_filename(url, filename));
+                Some(PlannedDownload {
+                    pkgname: version.pkgname.clone(),
+                    version: version.version.to_string(),
+                    url: url.clone(),
+                    size,
+                    cached: dest.exists(),
+                })
+            }
+            _ => None,
+        }))
+        .collect();
+    plan.sort_by(|a, b| (&a.pkgname, &a.url).cmp(&(&b.pkgname, &b.url)));
+    plan
+}
+
+/// Downloads every Fetch of the resolved packages that isn't cached yet,
+/// where their builds will look for it, checked against the recipe's
+/// checksum or else the one `expected` knows for the URL. Returns how many
+/// files were downloaded.
+pub fn prefetch(config: &Config, resolved: &DependencyGraph, expected: impl Fn(&str) -> Option<String> + Sync) -> Result<usize> {
+    check_graph_local_urls(config, Repositories::get_all(config), resolved)?;
+    migrate_flat_downloads(config, resolved);
+    let mut fetches: Vec<(&VersionEntry, &InstallStep)> = resolved.values()
+        .flat_map(|(version, _)| version.pipeline.iter().map(move |step| (version, step)))
+        .filter(|(_, step)| matches!(step, InstallStep::Fetch { .. }))
+        .collect();
+    fetches.sort_by_cached_key(|(version, step)| (version.pkgname.clone(), hash_to_string(step)));
+    fetches.dedup_by(|a, b| a.0.pkgname == b.0.pkgname && a.1 == b.1);
+    let downloaded = AtomicUsize::new(0);
+    fetches.par_iter().try_for_each(|(version, step)| -> Result<()> {
+        let InstallStep::Fetch { url, mirrors, checksum, filename, .. } = step else { return Ok(()) };
+        let dest = download_path(config, &version.pkgname, url, &fetch_filename(url, filename));
+        if dest.exists() {
+            return Ok(());
+        }
+        if config.offline && !url.starts_with("file://") {
+            anyhow::bail!("offline and {} is not in the download cache", url);
+        }
+        let checksum = checksum.clone().or_else(|| expected(url));
+        let urls: Vec<&str> = std::iter::once(url.as_str()).chain(mirrors.iter().map(|m| m.as_str())).collect();
+        Downloader::download_from_mirrors(&urls, &dest, checksum.as_deref(), version.sizes.get(url).copied())
+            .with_context(|| format!("[{}] failed to fetch {}", version.pkgname, url))?;
+        if let Some(db) = config.db() {
+            let _ = db.record_download(&dest, url);
+        }
+        downloaded.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    })?;
+    Ok(downloaded.into_inner())
+}
+
+/// Fails before building when the downloads with a known size don't fit in
+/// the free space of the download cache's filesystem.
+fn check_disk_space(config: &Config, plan: &[PlannedDownload]) -> Result<()> {
+    let needed: u64 = plan.iter().filter(|d| !d.cached).filter_map(|d| d.size).sum();
+    if needed == 0 {
+        return Ok(());
+    }
+    match available_space(&config.cache_download_dir) {
+        Some(free) if free < needed => anyhow::bail!(
+            "downloads need {} but only {} is free in {}",
+            format_size(needed), format_size(free), config.cache_download_dir.display()
+        ),
+        _ => Ok(()),
+    }
+}
 
-    execute_sorted_pipelines(&ctx, sorted_packages, &resolved_packages)
+/// Resolves `packages` and their build dependencies (re-evaluated with the cave
+/// options) without executing any pipeline. Used by `package why` and `package graph`.
+pub fn resolve_graph(
+    config: &Config,
+    packages: &[String],
+    all_options: &HashMap<String, HashMap<String, serde_json::Value>>,
+) -> Result<DependencyGraph> {
+    let build_cache = BuildCache::new(config.cache_dir.clone());
+    let ctx = BuildContext {
+        config,
+        repo_config: Repositories::get_all(config),
+        build_cache: &build_cache,
+        all_options,
+        // Not used while resolving.
+        pilocal_dir: Path::new(""),
+        sums: None,
+        host_build: &[],
+        cave: None,
+        bin_prefix: &HashMap::new(),
+        stats: BuildStats::default(),
+    };
+    resolve_dependencies(&ctx, packages)
 }
 
+#[tracing::instrument(skip_all)]
 fn resolve_dependencies(
     ctx: &BuildContext,
     initial_packages: &[String]
-) -> Result<HashMap<String, (VersionEntry, String)>> {
+) -> Result<DependencyGraph> {
     let mut resolved = HashMap::new();
     let mut to_resolve = VecDeque::from(initial_packages.to_vec());
 
@@ -86,10 +355,86 @@ fn resolve_dependencies(
 
         resolved.insert(query, (dynamic_version, repo_name));
     }
+    check_dependency_policy(ctx.repo_config, &resolved)?;
     Ok(resolved)
 }
 
-fn topological_sort(resolved_packages: &HashMap<String, (VersionEntry, String)>) -> Result<Vec<String>> {
+/// Rejects build dependency edges that cross into a repo the depending
+/// package's repo does not allow (`Repository::dependency_repos`).
+fn check_dependency_policy(repo_config: &Repositories, resolved: &DependencyGraph) -> Result<()> {
+    let mut queries: Vec<&String> = resolved.keys().collect();
+    queries.sort();
+    for query in queries {
+        let (version, repo_name) = &resolved[query];
+        let Some(repo) = repo_config.repositories.iter().find(|r| &r.name == repo_name) else { continue };
+        for dep in &version.build_dependencies {
+            let Some((_, dep_repo)) = resolved.get(&dep.name) else { continue };
+            if !repo.allows_dependency_from(dep_repo) {
+                anyhow::bail!(
+                    "{} (repo {}) may not depend on {} from repo {}: {} allows dependencies only from {}",
+                    version.pkgname, repo_name, dep.name, dep_repo, repo_name,
+                    std::iter::once(repo_name.as_str())
+                        .chain(repo.dependency_repos.iter().flatten().map(|r| r.as_str()))
+                        .collect::<Vec<_>>().join(", ")
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `--strict`: reports every package that resolves by wildcard or release
+/// type, Fetch without a checksum (in the recipe or `pi.cave.sums`) and
+/// host build, then fails if there was any. Run steps of strict builds get
+/// no network (see `prepare_run_step`).
+fn check_strict(resolved: &DependencyGraph, sums: Option<&CaveSums>) -> Result<()> {
+    let violations = strict_violations(resolved, sums);
+    for v in &violations {
+        log::error!("[strict] {}", v);
+    }
+    if !violations.is_empty() {
+        anyhow::bail!("{} strict violation(s), see above", violations.len());
+    }
+    Ok(())
+}
+
+fn strict_violations(resolved: &DependencyGraph, sums: Option<&CaveSums>) -> Vec<String> {
+    let mut queries: Vec<&String> = resolved.keys().collect();
+    queries.sort();
+    let mut violations = Vec::new();
+    for query in queries {
+        let (version, _) = &resolved[query];
+        let pinned = PackageSelector::parse(query)
+            .and_then(|s| s.version)
+            .is_some_and(|v| !v.contains('*') && !["latest", "stable", "lts", "testing", "unstable"].contains(&v.as_str()));
+        if !pinned {
+            violations.push(format!("{} is not pinned, it resolved to {}; use {}={}", query, version.version, version.pkgname, version.version));
+        }
+        for (i, step) in version.pipeline.iter().enumerate() {
+            if let InstallStep::Fetch { url, checksum: None, .. } = step
+                && !sums.is_some_and(|s| s.contains(url))
+            {
+                violations.push(format!("{}={} step {} fetches {} without a checksum", version.pkgname, version.version, i, url));
+            }
+            if let InstallStep::Patch { url_or_inline, checksum: None, .. } = step
+                && !InstallStep::is_inline_patch(url_or_inline)
+            {
+                violations.push(format!("{}={} step {} patches from {} without a checksum", version.pkgname, version.version, i, url_or_inline));
+            }
+            if let InstallStep::GitClone { url, rev, .. } = step
+                && !is_commit_id(rev)
+            {
+                violations.push(format!("{}={} step {} clones {} at {}, not at a commit id", version.pkgname, version.version, i, url, rev));
+            }
+        }
+        if version.requires_host_build {
+            violations.push(format!("{}={} builds outside the sandbox, where the network cannot be cut", version.pkgname, version.version));
+        }
+    }
+    violations
+}
+
+fn topological_sort(resolved_packages: &DependencyGraph) -> Result<Vec<String>> {
     let mut sorted = Vec::new();
     let mut visited = HashSet::new();
     let mut temp_visited = HashSet::new();
@@ -102,7 +447,7 @@ fn topological_sort(resolved_packages: &HashMap<String, (VersionEntry, String)>)
 
 fn topo_sort_dfs(
     query: &str,
-    resolved: &HashMap<String, (VersionEntry, String)>,
+    resolved: &DependencyGraph,
     visited: &mut HashSet<String>,
     temp_visited: &mut HashSet<String>,
     sorted: &mut Vec<String>,
@@ -122,56 +467,179 @@ fn topo_sort_dfs(
     Ok(())
 }
 
+/// Groups the packages into waves: the build dependencies of a package are
+/// all in earlier waves, so the packages of a wave can build concurrently.
+/// Waves are sorted by query, for a stable export order.
+pub fn build_waves(resolved_packages: &DependencyGraph) -> Result<Vec<Vec<String>>> {
+    let mut levels: HashMap<String, usize> = HashMap::new();
+    let mut waves: Vec<Vec<String>> = Vec::new();
+    for query in topological_sort(resolved_packages)? {
+        let Some((version, _)) = resolved_packages.get(&query) else { continue };
+        let level = version.build_dependencies.iter()
+            .filter_map(|dep| levels.get(&dep.name))
+            .map(|l| l + 1)
+            .max()
+            .unwrap_or(0);
+        if waves.len() <= level {
+            waves.resize_with(level + 1, Vec::new);
+        }
+        waves[level].push(query.clone());
+        levels.insert(query, level);
+    }
+    for wave in &mut waves {
+        wave.sort();
+    }
+    Ok(waves)
+}
+
+/// A package whose pipeline ran, waiting for its exports to be applied.
+struct PipelineOutput {
+    env: HashMap<String, String>,
+    exports: PackageExports,
+    stats: PackageStats,
+}
+
+/// Runs the pipelines of each wave in parallel (`--jobs` threads), then
+/// applies their exports in wave order before the next wave starts, so
+/// dependents see their dependencies' links.
 fn execute_sorted_pipelines(
     ctx: &BuildContext,
-    sorted_packages: Vec<String>,
-    resolved_packages: &HashMap<String, (VersionEntry, String)>
-) -> Result<HashMap<String, String>> {
+    waves: Vec<Vec<String>>,
+    resolved_packages: &DependencyGraph
+) -> Result<BuildOutput> {
     let mut all_env = HashMap::new();
+    let mut packages = Vec::new();
+    let mut manifest = ExportManifest::default();
     fs::create_dir_all(ctx.pilocal_dir).context("Failed to create .pilocal dir")?;
 
-    for query in sorted_packages {
-        let (dyn_version, repo_name) = resolved_packages.get(&query).unwrap();
-        let qv = QualifiedVersion::new(repo_name, dyn_version);
+    for wave in waves {
+        if wave.len() > 1 {
+            log::debug!("building {} packages concurrently: {}", wave.len(), wave.join(", "));
+        }
+        let outputs = wave.par_iter()
+            .map(|query| {
+                let (dyn_version, repo_name) = resolved_packages.get(query).unwrap();
+                execute_package(ctx, dyn_version, repo_name)
+            })
+            .collect::<Result<Vec<PipelineOutput>>>()?;
 
-        let (_, env, exports) = execute_pipeline(ctx, &qv.pkg_ctx(), dyn_version, repo_name)?;
-        all_env.extend(env);
+        for (query, output) in wave.iter().zip(outputs) {
+            let (dyn_version, _) = resolved_packages.get(query).unwrap();
+            all_env.extend(output.env);
+            packages.extend(output.exports.iter().map(|(_, root, _)| BuiltPackage {
+                pkgname: dyn_version.pkgname.clone(),
+                version: dyn_version.version.to_string(),
+                root: root.clone(),
+                stats: output.stats.clone(),
+            }));
+            for (_, _, pkg_exports) in &output.exports {
+                for export in pkg_exports {
+                    if let Export::Service { name, command, env } = export {
+                        if let Some(other) = manifest.services.iter().find(|s| &s.name == name) {
+                            log::warn!("[{}] service {} replaces the one of {}", dyn_version.pkgname, name, other.package);
+                        }
+                        manifest.services.retain(|s| &s.name != name);
+                        manifest.services.push(ExportService {
+                            package: dyn_version.pkgname.clone(),
+                            name: name.clone(),
+                            command: command.clone(),
+                            env: env.clone(),
+                        });
+                    }
+                }
+            }
+            let bin_prefix = ctx.bin_prefix.get(&dyn_version.pkgname).map(String::as_str);
+            let outcome = apply_exports(ctx, output.exports, bin_prefix, &mut all_env)?;
+            for (from, to) in outcome.rewrites {
+                manifest.rewrites.push(ExportRewrite { package: dyn_version.pkgname.clone(), from, to });
+            }
+            for (path, mode) in outcome.chmods {
+                manifest.chmods.push(ExportChmod { package: dyn_version.pkgname.clone(), path, mode: format!("{:o}", mode) });
+            }
+        }
+    }
+    manifest.save(ctx.pilocal_dir)?;
 
-        apply_exports(ctx, exports, ctx.pilocal_dir, &mut all_env)?;
+    let mut roots = HashMap::new();
+    for package in &packages {
+        roots.entry(package.pkgname.clone()).or_insert_with(|| package.root.clone());
+    }
+    for (key, val) in all_env.iter_mut() {
+        *val = expand_package_refs(val, &ctx.config.cache_packages_dir, None, Some(&roots))
+            .with_context(|| format!("export_env {}", key))?;
     }
 
-    Ok(all_env)
+    Ok(BuildOutput { env: all_env, packages, graph: DependencyGraph::new() })
 }
 
+/// Runs one package's pipeline, counting its steps apart from the others of
+/// the wave. Pipelines of the same package never run at once: they share
+/// its build cache file and, for equal versions, their step directories.
+fn execute_package(ctx: &BuildContext, dyn_version: &VersionEntry, repo_name: &str) -> Result<PipelineOutput> {
+    let qv = QualifiedVersion::new(repo_name, dyn_version);
+    let lock = ctx.config.state.build_locks
+        .entry(dyn_version.pkgname.clone())
+        .or_insert_with(|| std::sync::Arc::new(parking_lot::Mutex::new(())))
+        .clone();
+    let _guard = lock.lock();
+
+    let start = Instant::now();
+    let package_ctx = BuildContext { stats: BuildStats::default(), ..*ctx };
+    let result = execute_pipeline(&package_ctx, &qv.pkg_ctx(), dyn_version, repo_name);
+    ctx.stats.add(&package_ctx.stats);
+    let (env, exports, footprint) = result?;
+
+    let stats = PackageStats {
+        built: package_ctx.stats.steps_run.load(Ordering::Relaxed) > 0,
+        download_bytes: package_ctx.stats.download_bytes.load(Ordering::Relaxed),
+        duration_ms: start.elapsed().as_millis(),
+        exports: exports.iter().map(|(_, _, e)| e.len()).sum(),
+        install_bytes: footprint.bytes,
+        install_files: footprint.files,
+    };
+    Ok(PipelineOutput { env, exports, stats })
+}
+
+/// Links, creates and sets the exports of a package; returns the renamed links.
 fn apply_exports(
     ctx: &BuildContext,
-    exports: Vec<(String, PathBuf, Vec<Export>)>,
-    pilocal_dir: &Path,
+    exports: PackageExports,
+    bin_prefix: Option<&str>,
     all_env: &mut HashMap<String, String>
-) -> Result<()> {
+) -> Result<FileMapOutcome> {
+    let pilocal_dir = ctx.pilocal_dir;
+    let mut outcome = FileMapOutcome::default();
     for (pkg_ctx, source_root, pkg_exports) in exports {
         for export in pkg_exports {
             match export {
-                Export::Link { src, dest } => {
+                Export::Link { src, dest, chmod } => {
                     let src = ctx.config.resolve_packages_dir(&src);
-                    apply_filemap_entry(crate::commands::cave::fs::FileMapOptions {
+                    let chmod = chmod.as_deref().map(permissions::parse_mode).transpose()
+                        .with_context(|| format!("[{}] export_link {}", pkg_ctx, src))?;
+                    outcome.extend(apply_filemap_entry(crate::commands::cave::fs::FileMapOptions {
                         pkg_ctx: &pkg_ctx,
                         pkg_dir: &source_root,
                         pilocal_dir,
                         src_pattern: &src,
                         dest_rel: &dest,
-                    })?;
+                        bin_prefix,
+                        chmod,
+                    })?);
                 }
                 Export::Path(rel_path) => {
                     fs::create_dir_all(pilocal_dir.join(&rel_path)).ok();
                 }
                 Export::Env { key, val } => {
+                    let val = expand_package_refs(&val, &ctx.config.cache_packages_dir, Some(&source_root), None)
+                        .with_context(|| format!("[{}] export_env {}", pkg_ctx, key))?;
                     all_env.insert(key, val);
                 }
+                // Recorded in the manifest for `pi cave service`.
+                Export::Service { .. } => {}
             }
         }
     }
-    Ok(())
+    Ok(outcome)
 }
 
 fn re_evaluate_version(
@@ -180,6 +648,10 @@ fn re_evaluate_version(
     version: &VersionEntry,
     selector: &PackageSelector,
 ) -> Result<VersionEntry> {
+    if repo_name == BUILTIN_REPO {
+        let package = version.pkgname.split_once(':').map_or(version.pkgname.as_str(), |(_, p)| p);
+        return Ok(VenvSpec::parse(package)?.version_entry(package, &extract_options(ctx.all_options, &version.pkgname)));
+    }
     if let Some(res) = re_evaluate_version_internal(ctx, repo_name, version, selector, false)? {
         return Ok(res);
     }
@@ -224,6 +696,8 @@ fn re_evaluate_version_internal(
                 function_name,
                 config: ctx.config,
                 options: Some(options),
+                known: None,
+                cave: ctx.cave,
             },
             prefix,
             pkg_name,
@@ -235,6 +709,8 @@ fn re_evaluate_version_internal(
                 function_name,
                 config: ctx.config,
                 options: Some(options),
+                known: None,
+                cave: ctx.cave,
             },
             &version.pkgname,
         )?
@@ -251,9 +727,9 @@ fn get_manager_entry<'a>(
 ) -> Option<&'a crate::models::package_entry::ManagerEntry> {
     if !is_none { return None; }
     if let Some(prefix) = &selector.prefix {
-        pkg_list.managers.get(prefix)
-    } else if pkgname.contains(':') {
-        pkg_list.managers.get(pkgname.split(':').next().unwrap())
+        pkg_list.manager(prefix, &selector.package)
+    } else if let Some((prefix, package)) = pkgname.split_once(':') {
+        pkg_list.manager(prefix, package)
     } else {
         pkg_list.managers.get(pkgname)
     }
@@ -273,60 +749,282 @@ fn extract_options(all_options: &HashMap<String, HashMap<String, serde_json::Val
     options
 }
 
+#[tracing::instrument(skip_all, fields(package = pkg_ctx))]
 fn execute_pipeline(
     ctx: &BuildContext,
     pkg_ctx: &str,
     version: &VersionEntry,
-    _repo_name: &str,
-) -> Result<(String, HashMap<String, String>, Vec<(String, PathBuf, Vec<Export>)>)> {
+    repo_name: &str,
+) -> Result<(HashMap<String, String>, PackageExports, Footprint)> {
     let mut current_path: Option<PathBuf> = None;
     let mut env = HashMap::new();
     let dependency_dirs = resolve_build_dependencies(ctx, version, pkg_ctx)?;
 
+    let version_str = version.version.to_string();
+    let options = build_options(ctx.all_options, &version.pkgname);
+    let key = build_key(&version_str, &options);
+    let repo_dir = repo_dir(ctx.repo_config, repo_name);
+    let recipe_hash = recipe_hash(version);
     let mut recomputed = false;
+    let mut host_build = None;
+    let mut inventory = Vec::new();
     for (i, step) in version.pipeline.iter().enumerate() {
         let mut resolved_step = step.clone();
         if let InstallStep::Run { ref mut command, .. } = resolved_step {
             *command = ctx.config.resolve_packages_dir(command);
         }
 
-        let step_hash = hash_to_string(&resolved_step);
+        let step_hash = step_hash(ctx.config, &resolved_step, &options, repo_dir);
         let skip_cache = match step {
             InstallStep::Fetch { .. } => false, // Fetch handles its own "exists" check
             _ => ctx.config.rebuild,
         };
 
-        if !ctx.config.force && !recomputed && !skip_cache {
-            if let Some(cached) = ctx.build_cache.get_step_result(&version.pkgname, &version.version.to_string(), i, &step_hash) {
-                current_path = cached.output_path;
-                continue;
+        if !ctx.config.force && !recomputed && !skip_cache
+            && let Some(cached) = ctx.build_cache.get_step_result(&version.pkgname, &key, i, &step_hash, &recipe_hash)
+        {
+            if let Some(path) = &cached.output_path {
+                ctx.config.touch(path);
             }
+            current_path = cached.output_path;
+            inventory = ctx.build_cache.load_inventory(&version.pkgname, &key, i, &step_hash)
+                .map(|inv| inv.files)
+                .unwrap_or_default();
+            verify_step(ctx, &version.pkgname, step, current_path.as_deref())?;
+            ctx.stats.steps_cached.fetch_add(1, Ordering::Relaxed);
+            events::publish(Event::StepSucceeded {
+                package: version.pkgname.clone(), version: version_str.clone(), index: i, cached: true,
+            });
+            continue;
         }
 
         recomputed = true;
+        if matches!(step, InstallStep::Run { .. }) && host_build.is_none() {
+            host_build = Some(host_build_allowed(ctx, version)?);
+        }
         let step_ctx = StepContext {
             config: ctx.config,
             env: &env,
             dependency_dirs: dependency_dirs.clone(),
             pkgname: &version.pkgname,
-            version: &version.version.to_string(),
+            version: &version_str,
+            build_key: &key,
+            variant: ctx.cave.and_then(|c| c.variant.as_deref()),
+            index: i,
             pilocal_dir: ctx.pilocal_dir,
+            repo_dir,
+            host_build: host_build.unwrap_or(false),
+            sizes: &version.sizes,
         };
 
-        let result_path = execute_step(&step_ctx, &resolved_step, &current_path)?;
-        update_step_cache(ctx.build_cache, version, i, step_hash, &resolved_step, result_path.clone())?;
-        current_path = Some(result_path);
+        events::publish(Event::StepStarted {
+            package: version.pkgname.clone(), version: version_str.clone(), index: i,
+            step: step.kind().to_string(), name: step.name().map(|n| n.to_string()),
+        });
+        let output = match execute_step(&step_ctx, &resolved_step, &current_path) {
+            Ok(output) => output,
+            Err(e) => {
+                events::publish(Event::StepFailed {
+                    package: version.pkgname.clone(), version: version_str.clone(), index: i, error: format!("{:#}", e),
+                });
+                return Err(e);
+            }
+        };
+        if let (Some(sums), InstallStep::Fetch { url, .. }, Some((sha256, _))) = (ctx.sums, step, &output.streamed)
+            && let Err(e) = sums.verify_hash(url, sha256, "the streamed download")
+        {
+            let _ = fs::remove_dir_all(&output.path);
+            return Err(e);
+        }
+        if let Some((_, size)) = output.streamed.as_ref().filter(|_| output.source.is_some()) {
+            ctx.stats.download_bytes.fetch_add(*size, Ordering::Relaxed);
+        } else if output.source.is_some() {
+            // Checkouts of git_clone() are directories; only files count.
+            let size = fs::metadata(&output.path).ok().filter(|m| m.is_file()).map(|m| m.len()).unwrap_or(0);
+            ctx.stats.download_bytes.fetch_add(size, Ordering::Relaxed);
+        }
+        // Checked before it's cached: a cache hit is trusted as it was recorded.
+        verify_step(ctx, &version.pkgname, step, Some(&output.path))?;
+        inventory = record_inventory(ctx.build_cache, &step_ctx, step, &step_hash, &output.path, &inventory);
+        update_step_cache(ctx.build_cache, &step_ctx, &recipe_hash, step_hash, &resolved_step, &output)?;
+        ctx.config.touch(&output.path);
+        current_path = Some(output.path);
+        ctx.stats.steps_run.fetch_add(1, Ordering::Relaxed);
+        events::publish(Event::StepSucceeded {
+            package: version.pkgname.clone(), version: version_str.clone(), index: i, cached: false,
+        });
     }
 
     let source_root = current_path.unwrap_or_else(|| {
         ctx.config.cache_packages_dir.join(version.pkg_dir_name())
     });
+    let footprint = if inventory.is_empty() { Footprint::scan(&source_root) } else { Footprint::of(&inventory) };
+    if let Err(e) = ctx.build_cache.record_footprint(&version.pkgname, &key, footprint) {
+        log::debug!("[{}] footprint: {:#}", version.pkgname, e);
+    }
 
     for export in &version.exports {
         if let Export::Env { key, val } = export { env.insert(key.clone(), val.clone()); }
     }
 
-    Ok((pkg_ctx.to_string(), env, vec![(pkg_ctx.to_string(), source_root, version.exports.clone())]))
+    Ok((env, vec![(pkg_ctx.to_string(), source_root, version.exports.clone())], footprint))
+}
+
+/// Records the files of a step's output for `pi package artifacts`, reusing
+/// hashes of the previous step's inventory. Failures only lose the listing.
+fn record_inventory(
+    cache: &BuildCache,
+    ctx: &StepContext,
+    step: &InstallStep,
+    step_hash: &str,
+    output: &Path,
+    previous: &[ArtifactFile],
+) -> Vec<ArtifactFile> {
+    let files = match scan_inventory(output, previous) {
+        Ok(files) => files,
+        Err(e) => {
+            log::debug!("[{}] inventory of step {}: {:#}", ctx.pkgname, ctx.index, e);
+            return Vec::new();
+        }
+    };
+    let inventory = StepInventory { step_hash: step_hash.to_string(), kind: step.kind().to_string(), files };
+    if let Err(e) = cache.save_inventory(ctx.pkgname, ctx.build_key, ctx.index, &inventory) {
+        log::debug!("[{}] inventory of step {}: {:#}", ctx.pkgname, ctx.index, e);
+    }
+    inventory.files
+}
+
+/// Build options of a package (`options` in the cave), in a stable order.
+type BuildOptions = BTreeMap<String, String>;
+
+fn build_options(all_options: &HashMap<String, HashMap<String, serde_json::Value>>, pkgname: &str) -> BuildOptions {
+    extract_options(all_options, pkgname).into_iter().collect()
+}
+
+/// Key of a version's BuildCache entry and of its build directories: the
+/// version, followed by `#` and a hash of its options when it has any, so
+/// variants building the same version with different options keep separate
+/// outputs.
+pub fn build_key(version: &str, options: &BuildOptions) -> String {
+    if options.is_empty() {
+        version.to_string()
+    } else {
+        format!("{}{}{}", version, OPTIONS_SEPARATOR, hash_to_string(options))
+    }
+}
+
+/// Cache key of a step with resolved paths and the package's options. Strict
+/// builds don't reuse the outputs of Run steps that had network access, Run
+/// steps using repository files (`repo_files()`) rerun when they change, and
+/// plugin steps when their executable does.
+fn step_hash(config: &Config, step: &InstallStep, options: &BuildOptions, repo_dir: Option<&Path>) -> String {
+    let hash = match (step, options.is_empty()) {
+        (InstallStep::Run { .. }, true) if config.strict => hash_to_string(&(step, "strict")),
+        (InstallStep::Run { .. }, false) if config.strict => hash_to_string(&(step, options, "strict")),
+        (_, true) => hash_to_string(step),
+        (_, false) => hash_to_string(&(step, options)),
+    };
+    match (step, repo_dir) {
+        (InstallStep::Run { command, .. }, Some(dir)) if command.contains(REPO_PLACEHOLDER) => {
+            hash_to_string(&(hash, repo_files_digest(command, dir)))
+        }
+        (InstallStep::Plugin { step: kind, .. }, _) => {
+            let program = plugin_path(config, kind);
+            hash_to_string(&(hash, calculate_file_checksum(&program, 64).unwrap_or_default()))
+        }
+        _ => hash,
+    }
+}
+
+/// Directory of the repository named `repo_name`.
+fn repo_dir<'a>(repo_config: &'a Repositories, repo_name: &str) -> Option<&'a Path> {
+    repo_config.repositories.iter().find(|r| r.name == repo_name).map(|r| Path::new(&r.path))
+}
+
+/// Hash of the entry `version` evaluated to, so editing a recipe (its
+/// exports, say) invalidates the steps built from it, but editing another
+/// package of the same `.star` file does not.
+fn recipe_hash(version: &VersionEntry) -> String {
+    hash_to_string(&serde_json::to_string(version).unwrap_or_default())
+}
+
+/// SHA-256 of the repository files `command` refers to with `@REPO_DIR/...`;
+/// directories and missing files count by name only.
+fn repo_files_digest(command: &str, repo_dir: &Path) -> Vec<(String, String)> {
+    command.match_indices(REPO_PLACEHOLDER)
+        .map(|(i, _)| {
+            let rest = command[i + REPO_PLACEHOLDER.len()..].trim_start_matches('/');
+            let end = rest.find(|c: char| c.is_whitespace() || "'\";|&<>)".contains(c)).unwrap_or(rest.len());
+            let rel = rest[..end].to_string();
+            let file = repo_dir.join(&rel);
+            let digest = if file.is_file() { calculate_file_checksum(&file, 64).unwrap_or_default() } else { String::new() };
+            (rel, digest)
+        })
+        .collect()
+}
+
+/// `command` with `@REPO_DIR` pointing at the repository: its mount in the
+/// sandbox, or its real path for host builds.
+fn resolve_repo_dir(ctx: &StepContext, command: &str) -> String {
+    match ctx.repo_dir {
+        Some(dir) if ctx.host_build => command.replace(REPO_PLACEHOLDER, &dir.display().to_string()),
+        _ => command.replace(REPO_PLACEHOLDER, REPO_MOUNT),
+    }
+}
+
+/// Whether the `Run` steps of `version` execute on the host: only when the
+/// recipe asks for it and the cave (`host_build`) or `--allow-host-build`
+/// consents. Without consent the build fails rather than trying the sandbox.
+fn host_build_allowed(ctx: &BuildContext, version: &VersionEntry) -> Result<bool> {
+    if !version.requires_host_build {
+        return Ok(false);
+    }
+    if !ctx.config.allow_host_build && !ctx.host_build.contains(&version.pkgname) {
+        anyhow::bail!(
+            "{} {} must be built outside the sandbox; allow it with \"host_build\": [\"{}\"] in {} or --allow-host-build",
+            version.pkgname, version.version, version.pkgname, crate::models::cave::Cave::FILENAME
+        );
+    }
+    log::warn!("[{}] ********************************************************", version.pkgname);
+    log::warn!("[{}] building {} OUTSIDE the sandbox, its steps can change the host", version.pkgname, version.version);
+    log::warn!("[{}] ********************************************************", version.pkgname);
+    Ok(true)
+}
+
+/// Checks a step's artifact, cached or not: a Fetch output against the
+/// cave's `pi.cave.sums`, since the download directory is shared between
+/// caves, and a downloaded patch against the recipe's checksum.
+fn verify_step(ctx: &BuildContext, pkgname: &str, step: &InstallStep, output: Option<&Path>) -> Result<()> {
+    match (step, output) {
+        // A streamed fetch was checked as it ran, and left no file.
+        (InstallStep::Fetch { url, stream: false, .. }, Some(path)) => match ctx.sums {
+            Some(sums) => sums.verify(url, path),
+            None => Ok(()),
+        },
+        (InstallStep::Patch { url_or_inline, checksum: Some(expected), .. }, _)
+            if !InstallStep::is_inline_patch(url_or_inline) =>
+        {
+            let patch = download_path(ctx.config, pkgname, url_or_inline, &fetch_filename(url_or_inline, &None));
+            verify_patch(&patch, url_or_inline, expected)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks a patch in the download cache against `expected`, removing it
+/// when it doesn't match so the next build fetches it again. A patch that
+/// was cleaned up since is fine: it was checked when it was applied.
+fn verify_patch(patch: &Path, url: &str, expected: &str) -> Result<()> {
+    if !patch.exists() {
+        return Ok(());
+    }
+    let actual = calculate_file_checksum(patch, expected.len())
+        .with_context(|| format!("Failed to hash {}", patch.display()))?;
+    if actual != expected {
+        let _ = fs::remove_file(patch);
+        anyhow::bail!("[{}] checksum mismatch of the cached patch: got {}, want {}", url, actual, expected);
+    }
+    Ok(())
 }
 
 fn resolve_build_dependencies(ctx: &BuildContext, version: &VersionEntry, pkg_ctx: &str) -> Result<Vec<PathBuf>> {
@@ -346,11 +1044,11 @@ fn resolve_build_dependencies(ctx: &BuildContext, version: &VersionEntry, pkg_ct
                 if let Export::Link { src, .. } = export {
                     let resolved_src = ctx.config.resolve_packages_dir(src);
                     let p = Path::new(&resolved_src);
-                    if p.is_absolute() {
-                        if let Some(parent) = p.parent() {
-                            let parent_buf = parent.to_path_buf();
-                            if !dirs.contains(&parent_buf) { dirs.push(parent_buf); }
-                        }
+                    if p.is_absolute()
+                        && let Some(parent) = p.parent()
+                    {
+                        let parent_buf = parent.to_path_buf();
+                        if !dirs.contains(&parent_buf) { dirs.push(parent_buf); }
                     }
                 }
             }
@@ -363,18 +1061,16 @@ fn resolve_build_dependencies(ctx: &BuildContext, version: &VersionEntry, pkg_ct
 
 fn update_step_cache(
     cache: &BuildCache,
-    version: &VersionEntry,
-    i: usize,
+    step_ctx: &StepContext,
+    recipe_hash: &str,
     hash: String,
     step: &InstallStep,
-    result_path: PathBuf
+    output: &StepOutput,
 ) -> Result<()> {
-    let name = match step {
-        InstallStep::Fetch { name, .. } | InstallStep::Extract { name, .. } | InstallStep::Run { name, .. } => name.clone(),
-    };
-    cache.update_step_result(&version.pkgname, &version.version.to_string(), i, StepResult {
-        name, step_hash: hash, timestamp: chrono::Utc::now().to_rfc3339(),
-        output_path: Some(result_path), status: "Success".to_string(),
+    cache.update_step_result(step_ctx.pkgname, step_ctx.build_key, step_ctx.index, StepResult {
+        name: step.name().map(|n| n.to_string()), step_hash: hash, timestamp: chrono::Utc::now().to_rfc3339(),
+        output_path: Some(output.path.clone()), status: "Success".to_string(),
+        source: output.source.clone(), recipe_hash: Some(recipe_hash.to_string()),
     })
 }
 
@@ -458,13 +1154,14 @@ fn prepare_build_sandbox(
     b.add_env_first("PATH", pilocal_bin.to_str().unwrap());
 
     // Custom envs
-    let resolve = |v: String| {
-        v.replace("$/", &format!("{}/", internal_pilocal.display()))
-         .replace("$", internal_pilocal.to_str().unwrap())
-         .replace("@HOME", host_home.to_str().unwrap())
+    let paths = PathVars {
+        pilocal: Some(&internal_pilocal),
+        home: Some(&host_home),
+        workspace: None,
+        packages: Some(&config.cache_packages_dir),
     };
     for (k, v) in env_vars {
-        b.set_env(k, &resolve(v.clone()));
+        b.set_env(k, &paths.value(v));
     }
 
     b.set_hostname(&format!("build-{}-{}", pkgname, version));
@@ -472,63 +1169,1045 @@ fn prepare_build_sandbox(
     Ok(b)
 }
 
-fn execute_step(ctx: &StepContext, step: &InstallStep, current_path: &Option<PathBuf>) -> Result<PathBuf> {
+/// Temporary home of a `Run` step. It is journaled in the state DB while it
+/// exists, so homes leaked by a killed process are removed by the next build.
+struct BuildHome<'a> {
+    config: &'a Config,
+    dir: Option<tempfile::TempDir>,
+}
+
+impl<'a> BuildHome<'a> {
+    fn create(config: &'a Config) -> Result<Self> {
+        let dir = tempfile::Builder::new()
+            .prefix("pi-home-")
+            .tempdir()
+            .context("Failed to create temporary home directory")?;
+        if let Some(db) = config.db()
+            && let Err(e) = db.record_build_home(dir.path(), std::process::id())
+        {
+            log::debug!("[db] journal {}: {:#}", dir.path().display(), e);
+        }
+        Ok(Self { config, dir: Some(dir) })
+    }
+
+    fn path(&self) -> &Path {
+        self.dir.as_ref().expect("build home already released").path()
+    }
+
+    /// Persists the home for debugging; it is no longer journaled or removed.
+    fn keep(mut self) -> PathBuf {
+        let dir = self.dir.take().expect("build home already released");
+        self.forget(dir.path());
+        dir.keep()
+    }
+
+    fn forget(&self, path: &Path) {
+        if let Some(db) = self.config.db() {
+            let _ = db.forget_build_home(path);
+        }
+    }
+}
+
+impl Drop for BuildHome<'_> {
+    fn drop(&mut self) {
+        if let Some(dir) = self.dir.take() {
+            self.forget(dir.path());
+        }
+    }
+}
+
+/// Removes journaled build homes whose owning process is gone.
+fn cleanup_orphaned_build_homes(config: &Config) {
+    let Some(db) = config.db() else { return };
+    let homes = match db.build_homes() {
+        Ok(h) => h,
+        Err(e) => {
+            log::debug!("[db] build homes: {:#}", e);
+            return;
+        }
+    };
+    for (path, pid) in homes {
+        if Path::new("/proc").join(pid.to_string()).exists() {
+            continue;
+        }
+        log::info!("[build] removing orphaned build home {}", path.display());
+        if path.exists()
+            && let Err(e) = fs::remove_dir_all(&path)
+        {
+            log::warn!("[build] failed to remove {}: {}", path.display(), e);
+            continue;
+        }
+        let _ = db.forget_build_home(&path);
+    }
+}
+
+#[tracing::instrument(skip_all, fields(package = ctx.pkgname, version = ctx.version))]
+fn execute_step(ctx: &StepContext, step: &InstallStep, current_path: &Option<PathBuf>) -> Result<StepOutput> {
     match step {
-        InstallStep::Fetch { url, checksum, filename, .. } => {
-            let fname = filename.clone().unwrap_or_else(|| url.split('/').last().unwrap_or("download").to_string());
-            let dest = ctx.config.cache_download_dir.join(fname);
-            
+        InstallStep::Fetch { url, mirrors, checksum, filename, stream: true, .. } => {
+            stream_fetch(ctx, url, mirrors, checksum.as_deref(), filename)
+        }
+        InstallStep::Fetch { url, mirrors, checksum, filename, signature, .. } => {
+            let fname = fetch_filename(url, filename);
+            let dest = download_path(ctx.config, ctx.pkgname, url, &fname);
+
             if dest.exists() {
-                if let Some(cs) = checksum {
-                    log::debug!("skipping download, file exists: {}", dest.display());
-                    return Ok(dest);
-                } else {
-                    log::debug!("skipping download, file exists: {}", dest.display());
-                    return Ok(dest);
+                log::debug!("skipping download, file exists: {}", dest.display());
+                if let Some(db) = ctx.config.db()
+                    && db.download_source(&dest).ok().flatten().is_none()
+                {
+                    let _ = db.record_download(&dest, url);
                 }
+                ctx.config.touch(&dest);
+                if let Some(signature) = signature {
+                    verify_signature(ctx, signature, &dest)?;
+                }
+                return Ok(dest.into());
+            }
+            if ctx.config.offline && !url.starts_with("file://") {
+                anyhow::bail!("offline and {} is not in the download cache (fetched from {})", fname, url);
+            }
+            let urls: Vec<&str> = std::iter::once(url.as_str()).chain(mirrors.iter().map(|m| m.as_str())).collect();
+            let source = {
+                let _timer = ctx.config.state.timings.scope(Phase::Network);
+                Downloader::download_from_mirrors(&urls, &dest, checksum.as_deref(), ctx.sizes.get(url).copied())?
+            };
+            if let Some(db) = ctx.config.db()
+                && let Err(e) = db.record_download(&dest, url)
+            {
+                log::debug!("[db] record {}: {:#}", dest.display(), e);
             }
-            Downloader::download_to_file(url, &dest, checksum.as_deref())?;
-            Ok(dest)
+            if source != *url {
+                log::info!("[{}] fetched from mirror {}", ctx.pkgname, source);
+            }
+            if let Some(signature) = signature {
+                verify_signature(ctx, signature, &dest)?;
+            }
+            Ok(StepOutput { path: dest, source: Some(source), streamed: None })
         }
-        InstallStep::Extract { .. } => {
+        InstallStep::Extract { format, .. } => {
             let src = current_path.as_ref().context("Extract requires a Fetch step")?;
-            let pkg_dir = format!("{}-extracted", sanitize_name(&format!("{}-{}", ctx.pkgname, ctx.version)));
-            let dest = ctx.config.cache_packages_dir.join(pkg_dir);
+            let format = format.as_deref().map(str::parse::<ArchiveFormat>).transpose()?;
+            let dest = extract_dir(ctx);
 
             if dest.exists() && !ctx.config.rebuild && !ctx.config.force {
                 log::debug!("skipping extraction, directory exists: {}", dest.display());
-                return Ok(dest);
+                return Ok(dest.into());
             }
 
             if dest.exists() {
                 let _ = fs::remove_dir_all(&dest);
             }
-            Unarchiver::unarchive(src, &dest)?;
-            Ok(dest)
+            let _timer = ctx.config.state.timings.scope(Phase::Extraction);
+            Unarchiver::unarchive_as(src, &dest, format)?;
+            normalize_permissions(ctx, &dest)?;
+            Ok(dest.into())
         }
-        InstallStep::Run { command, cwd, .. } => {
-            let default_base = ctx.config.cache_packages_dir.join(sanitize_name(&format!("{}-{}", ctx.pkgname, ctx.version)));
-            let base_dir = cwd.as_ref().map(|c| current_path.as_ref().unwrap_or(&default_base).join(c)).unwrap_or_else(|| current_path.clone().unwrap_or(default_base));
-            fs::create_dir_all(&base_dir).ok();
-
+        InstallStep::GitClone { url, rev, depth, .. } => {
+            let mirror = GitMirror::for_url(&ctx.config.cache_download_dir, ctx.pkgname, url);
+            let commit = {
+                let _timer = ctx.config.state.timings.scope(Phase::Network);
+                mirror.fetch(url, rev, *depth, ctx.config.force)?
+            };
+            ctx.config.touch(mirror.path());
+            let dest = ctx.config.cache_packages_dir
+                .join(format!("{}-step{}", sanitize_name(&format!("{}-{}", ctx.pkgname, ctx.build_key)), ctx.index));
+            mirror.checkout(url, &commit, &dest)?;
+            log::info!("[{}] checked out {} at {}", ctx.pkgname, url, &commit[..12.min(commit.len())]);
+            Ok(StepOutput { path: dest, source: Some(format!("{}@{}", url, commit)), streamed: None })
+        }
+        InstallStep::Run { command, cwd, shell, .. } => {
+            let command = &resolve_repo_dir(ctx, command);
+            let _timer = ctx.config.state.timings.scope(Phase::Execution);
             // Create a temporary home directory for manager execution
-            let tmp_home = tempfile::tempdir().context("Failed to create temporary home directory")?;
-
-            let mut b = prepare_build_sandbox(
-                ctx.config,
-                ctx.pkgname,
-                ctx.version,
-                tmp_home.path(),
-                ctx.pilocal_dir,
-                ctx.env,
-                &ctx.dependency_dirs,
-            )?;
-            
-            b.set_cwd(&base_dir);
-            b.set_command("/bin/bash", &[String::from("-c"), command.clone()]);
-            b.spawn().with_context(|| format!("Failed to execute command: {}", command))?;
-
-            Ok(base_dir)
+            let tmp_home = BuildHome::create(ctx.config)?;
+            if ctx.host_build {
+                return run_on_host(ctx, command, shell, cwd.as_deref(), current_path, tmp_home);
+            }
+            let (mut b, base_dir) = prepare_run_step(ctx, cwd.as_deref(), current_path, tmp_home.path())?;
+            set_step_command(&mut b, shell, command)?;
+
+            let result = b.spawn_with_tail(TAIL_LINES, step_log(ctx, command));
+            if ctx.config.keep_build_home {
+                log::info!("[{}] build home kept: {}", ctx.pkgname, tmp_home.keep().display());
+            }
+            let outcome = result.with_context(|| format!("Failed to execute command: {}", command))?;
+            if !outcome.status.success() {
+                print_failure_tail(ctx, &outcome.tail);
+                let failure = StepFailure {
+                    package: ctx.pkgname,
+                    version: ctx.version,
+                    index: ctx.index,
+                    command,
+                    status: outcome.status.to_string(),
+                    tail: &outcome.tail,
+                };
+                match diagnostics::write_bundle(&ctx.config.state_dir, &failure, &b) {
+                    Ok(dir) => log::error!(
+                        "[{}] step {} failed, diagnostics in {} (rerun with `pi devel rerun-step {} {} {}{}`)",
+                        ctx.pkgname, ctx.index, dir.display(), ctx.pkgname, ctx.version, ctx.index,
+                        ctx.variant.map(|v| format!(" --variant {}", v)).unwrap_or_default()
+                    ),
+                    Err(e) => log::warn!("[{}] failed to write diagnostics: {:#}", ctx.pkgname, e),
+                }
+                anyhow::bail!("Failed to execute command: {} ({})", command, outcome.status);
+            }
+
+            Ok(base_dir.into())
+        }
+        InstallStep::Patch { url_or_inline, strip, checksum, .. } => {
+            let src = current_path.as_ref().filter(|p| p.is_dir())
+                .context("Patch requires a source tree from an Extract, GitClone or Run step")?;
+            let diff = if InstallStep::is_inline_patch(url_or_inline) {
+                url_or_inline.clone()
+            } else {
+                let dest = download_path(ctx.config, ctx.pkgname, url_or_inline, &fetch_filename(url_or_inline, &None));
+                if let Some(expected) = checksum
+                    && let Err(e) = verify_patch(&dest, url_or_inline, expected)
+                {
+                    log::warn!("[{}] fetching the patch again: {:#}", ctx.pkgname, e);
+                }
+                if !dest.exists() {
+                    if ctx.config.offline && !url_or_inline.starts_with("file://") {
+                        anyhow::bail!("offline and the patch {} is not in the download cache", url_or_inline);
+                    }
+                    let _timer = ctx.config.state.timings.scope(Phase::Network);
+                    Downloader::download_from_mirrors(&[url_or_inline], &dest, checksum.as_deref(), None)?;
+                    if let Some(db) = ctx.config.db() {
+                        let _ = db.record_download(&dest, url_or_inline);
+                    }
+                }
+                ctx.config.touch(&dest);
+                fs::read_to_string(&dest).with_context(|| format!("Failed to read patch {}", dest.display()))?
+            };
+            // Patched in a copy: the tree before it may be a cached extraction.
+            let output = ctx.config.cache_packages_dir
+                .join(format!("{}-step{}", sanitize_name(&format!("{}-{}", ctx.pkgname, ctx.build_key)), ctx.index));
+            if output.exists() {
+                fs::remove_dir_all(&output).with_context(|| format!("Failed to clear {}", output.display()))?;
+            }
+            copy_tree(src, &output)?;
+            let files = Patcher::apply(&diff, &output, *strip)
+                .with_context(|| format!("patch of step {} does not apply", ctx.index))?;
+            log::info!("[{}] step {}: patched {} file(s)", ctx.pkgname, ctx.index, files);
+            Ok(output.into())
+        }
+        InstallStep::Plugin { step: kind, params, .. } => {
+            let executors = Executors::discover(&ctx.config.plugins_dir());
+            let executor = executors.get(kind).with_context(|| {
+                format!("no plugin runs '{}' steps, install {}", kind, plugin_path(ctx.config, kind).display())
+            })?;
+            let output = ctx.config.cache_packages_dir
+                .join(format!("{}-step{}", sanitize_name(&format!("{}-{}", ctx.pkgname, ctx.build_key)), ctx.index));
+            if output.exists() {
+                fs::remove_dir_all(&output).with_context(|| format!("Failed to clear {}", output.display()))?;
+            }
+            fs::create_dir_all(&output).with_context(|| format!("Failed to create {}", output.display()))?;
+
+            let tmp_home = BuildHome::create(ctx.config)?;
+            let (b, _) = prepare_run_step(ctx, None, current_path, tmp_home.path())?;
+            let request = StepRequest {
+                protocol: PROTOCOL_VERSION,
+                step: kind,
+                params,
+                package: ctx.pkgname,
+                version: ctx.version,
+                input: current_path.as_deref(),
+                output: &output,
+            };
+            log::info!("[{}] step {}: {} plugin", ctx.pkgname, ctx.index, kind);
+            let path = {
+                let _timer = ctx.config.state.timings.scope(Phase::Execution);
+                executor.execute(&request, b)?
+            };
+            if !path.exists() {
+                anyhow::bail!("{} step output {} does not exist", kind, path.display());
+            }
+            Ok(path.into())
+        }
+    }
+}
+
+/// The executable running `kind` plugin steps.
+fn plugin_path(config: &Config, kind: &str) -> PathBuf {
+    config.plugins_dir().join(format!("{}{}", PLUGIN_PREFIX, kind))
+}
+
+/// Downloads the detached signature of `file` next to it (once, never
+/// offline) and checks it; a file that doesn't verify is removed so the next build fetches it again.
+fn verify_signature(ctx: &StepContext, signature: &Signature, file: &Path) -> Result<()> {
+    let sig_path = signature_path(file, &signature.url);
+    if !sig_path.exists() {
+        if Downloader::is_offline() && !signature.url.starts_with("file://") {
+            anyhow::bail!("offline and the signature of {} is not in the download cache", file.display());
+        }
+        let _timer = ctx.config.state.timings.scope(Phase::Network);
+        Downloader::download_from_mirrors(&[signature.url.as_str()], &sig_path, None, None)
+            .with_context(|| format!("Failed to fetch the signature of {}", file.display()))?;
+    }
+    if let Err(e) = crate::services::signature::verify(file, &sig_path, &signature.public_key) {
+        let _ = fs::remove_file(file);
+        let _ = fs::remove_file(&sig_path);
+        return Err(e.context(format!("{} {} was not signed by the recipe's key", ctx.pkgname, ctx.version)));
+    }
+    log::info!("[{}] signature of {} verified", ctx.pkgname, file.display());
+    Ok(())
+}
+
+/// Where the signature of `file` fetched from `url` is kept:
+/// `<url hash>-<file>.<ext>` next to it, the extension (`asc`, `minisig`, ...)
+/// from the URL's path. Keyed by URL, so a changed `signature_url` is fetched
+/// anew rather than checking a stale signature.
+fn signature_path(file: &Path, url: &str) -> PathBuf {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let extension = path.rsplit('/').next()
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, ext)| ext)
+        .filter(|ext| !ext.is_empty())
+        .unwrap_or("sig");
+    let name = format!("{}.{}", file.file_name().unwrap_or_default().to_string_lossy(), extension);
+    url_hashed_path(file.parent().unwrap_or(Path::new(".")), url, &name)
+}
+
+/// Where Extract steps and streamed fetches unpack: `<pkg>-<key>-extracted`.
+fn extract_dir(ctx: &StepContext) -> PathBuf {
+    let pkg_dir = format!("{}-extracted", sanitize_name(&format!("{}-{}", ctx.pkgname, ctx.build_key)));
+    ctx.config.cache_packages_dir.join(pkg_dir)
+}
+
+fn normalize_permissions(ctx: &StepContext, dest: &Path) -> Result<()> {
+    if ctx.config.settings.permission_policy() == PermissionPolicy::Normalize {
+        let normalized = permissions::normalize(dest)?;
+        if normalized != Normalized::default() {
+            log::debug!(
+                "[{}] {} file(s) made executable, {} no longer group/world writable",
+                ctx.pkgname, normalized.made_executable, normalized.write_removed
+            );
+        }
+    }
+    Ok(())
+}
+
+/// A Fetch with `stream`: extracts the download into the extract directory
+/// as it arrives, so large archives never sit in the download cache too.
+/// An archive already there (`pi cave import --fetch`) is extracted from
+/// the cache instead.
+fn stream_fetch(ctx: &StepContext, url: &str, mirrors: &[String], checksum: Option<&str>, filename: &Option<String>) -> Result<StepOutput> {
+    let fname = fetch_filename(url, filename);
+    let dest = extract_dir(ctx);
+    if dest.exists() && !ctx.config.rebuild && !ctx.config.force {
+        log::debug!("skipping streamed fetch, directory exists: {}", dest.display());
+        return Ok(dest.into());
+    }
+    if dest.exists() {
+        let _ = fs::remove_dir_all(&dest);
+    }
+
+    let archive = download_path(ctx.config, ctx.pkgname, url, &fname);
+    if archive.exists() {
+        log::debug!("[{}] extracting the cached {} instead of streaming", ctx.pkgname, archive.display());
+        ctx.config.touch(&archive);
+        let sha256 = calculate_file_checksum(&archive, 64)?;
+        let _timer = ctx.config.state.timings.scope(Phase::Extraction);
+        Unarchiver::unarchive_as(&archive, &dest, None)?;
+        normalize_permissions(ctx, &dest)?;
+        let size = fs::metadata(&archive)?.len();
+        return Ok(StepOutput { path: dest, source: None, streamed: Some((sha256, size)) });
+    }
+    if ctx.config.offline && !url.starts_with("file://") {
+        anyhow::bail!("offline and {} is not in the download cache (fetched from {})", fname, url);
+    }
+
+    let urls: Vec<&str> = std::iter::once(url).chain(mirrors.iter().map(|m| m.as_str())).collect();
+    let mut spooled = None;
+    let streamed = {
+        let _timer = ctx.config.state.timings.scope(Phase::Network);
+        Downloader::stream_from_mirrors(&urls, checksum, ctx.sizes.get(url).copied(), &mut |body| {
+            if dest.exists() {
+                fs::remove_dir_all(&dest)?;
+            }
+            spooled = Unarchiver::unarchive_stream(body, &fname, &dest, None)?;
+            Ok(())
+        })
+    };
+    // Zip and 7z archives are only extracted once their checksum matched.
+    let streamed = streamed.and_then(|streamed| {
+        if let Some(spooled) = spooled.take() {
+            let _timer = ctx.config.state.timings.scope(Phase::Extraction);
+            spooled.unarchive(&dest)?;
+        }
+        Ok(streamed)
+    });
+    let streamed = streamed.inspect_err(|_| {
+        let _ = fs::remove_dir_all(&dest);
+    })?;
+    normalize_permissions(ctx, &dest)?;
+    if streamed.source != url {
+        log::info!("[{}] fetched from mirror {}", ctx.pkgname, streamed.source);
+    }
+    Ok(StepOutput { path: dest, source: Some(streamed.source), streamed: Some((streamed.sha256, streamed.bytes)) })
+}
+
+/// Name of a Fetch step's file: its `filename`, or the URL's last segment.
+fn fetch_filename(url: &str, filename: &Option<String>) -> String {
+    filename.clone().unwrap_or_else(|| url.split('/').next_back().unwrap_or("download").to_string())
+}
+
+/// Where a Fetch step stores its file: `<downloads>/<package>/<filename>`, so
+/// equally named artifacts of different packages don't collide. When the DB
+/// says that file came from another URL, it is kept and this one is stored as
+/// `<package>/<url hash>-<filename>` instead; so is every file while the DB
+/// is unavailable, since nothing could tell whose the plain name is.
+fn download_path(config: &Config, pkgname: &str, url: &str, fname: &str) -> PathBuf {
+    let (dest, reason) = locate_download(config, pkgname, url, fname);
+    if let Some(reason) = reason {
+        log::warn!("[{}] {}, storing {} as {}", pkgname, reason, url, dest.display());
+    }
+    dest
+}
+
+/// `download_path` without the warning: the path and, when it isn't the
+/// plain file name, why not.
+fn locate_download(config: &Config, pkgname: &str, url: &str, fname: &str) -> (PathBuf, Option<String>) {
+    let dir = config.cache_download_dir.join(sanitize_name(pkgname));
+    let dest = dir.join(fname);
+    let Some(db) = config.db() else {
+        return (url_hashed_path(&dir, url, fname), Some("state database in use by another pi".to_string()));
+    };
+    match db.download_source(&dest).ok().flatten() {
+        Some(source) if source != url => (url_hashed_path(&dir, url, fname), Some(format!("{} was fetched from {}", fname, source))),
+        _ => (dest, None),
+    }
+}
+
+/// Refuses `file://` URLs of `version` (fetches, their mirrors, signatures
+/// and patches) unless they resolve under `dir`, the directory of its `dir:`
+/// package, so a recipe can't copy arbitrary host files into the store.
+pub fn check_local_urls(version: &VersionEntry, dir: Option<&Path>) -> Result<()> {
+    let urls = version.pipeline.iter().flat_map(|step| match step {
+        InstallStep::Fetch { url, mirrors, signature, .. } => {
+            std::iter::once(url).chain(mirrors).chain(signature.as_ref().map(|s| &s.url)).collect()
+        }
+        InstallStep::Patch { url_or_inline, .. } => vec![url_or_inline],
+        _ => Vec::new(),
+    });
+    for url in urls {
+        let Some(path) = url.strip_prefix("file://") else { continue };
+        let Some(dir) = dir else {
+            anyhow::bail!("[{}] {} reads a local file, only dir: packages may", version.pkgname, url);
+        };
+        let path = Path::new(path).canonicalize().with_context(|| format!("[{}] {} is missing", version.pkgname, url))?;
+        if !path.starts_with(dir) {
+            anyhow::bail!("[{}] {} is outside the package's directory {}", version.pkgname, url, dir.display());
+        }
+    }
+    Ok(())
+}
+
+/// `check_local_urls` for every version of a resolved graph.
+fn check_graph_local_urls(config: &Config, repo_config: &Repositories, resolved: &DependencyGraph) -> Result<()> {
+    for (version, repo_name) in resolved.values() {
+        check_local_urls(version, dir_package_dir(config, repo_config, repo_name, &version.pkgname).as_deref())?;
+    }
+    Ok(())
+}
+
+/// The canonical artifact directory of `pkgname` when it is a `dir:` package.
+fn dir_package_dir(config: &Config, repo_config: &Repositories, repo_name: &str, pkgname: &str) -> Option<PathBuf> {
+    let name = pkgname.strip_prefix(DIR_MANAGER)?.strip_prefix(':')?;
+    let repo = repo_config.repositories.iter().find(|r| r.name == repo_name)?;
+    let list = PackageList::get_for_repo(config, repo, false)?;
+    let manager = list.manager(DIR_MANAGER, name)?;
+    let recipe = Path::new(&repo.path).join(&manager.filename);
+    manager.dir.as_ref()?.dir(recipe.parent()?).canonicalize().ok()
+}
+
+/// `<dir>/<url hash>-<filename>`: the name of a download no other URL claims.
+fn url_hashed_path(dir: &Path, url: &str, fname: &str) -> PathBuf {
+    let digest = hex::encode(sha2::Sha256::digest(url.as_bytes()));
+    dir.join(format!("{}-{}", &digest[..12], fname))
+}
+
+/// Moves downloads of the old flat layout (`<downloads>/<filename>`) into the
+/// directory of the package fetching them, leaving a symlink so cached step
+/// results pointing at them resolve. A file only moves when exactly one Fetch
+/// of `resolved` claims its name (and it matches that Fetch's checksum, if
+/// any); otherwise there is no telling whose it is and it stays.
+fn migrate_flat_downloads(config: &Config, resolved: &DependencyGraph) {
+    let mut claims: HashMap<String, Vec<(&str, Option<&str>)>> = HashMap::new();
+    for (version, _) in resolved.values() {
+        for step in &version.pipeline {
+            if let InstallStep::Fetch { url, checksum, filename, .. } = step {
+                let claim = (version.pkgname.as_str(), checksum.as_deref());
+                let claimants = claims.entry(fetch_filename(url, filename)).or_default();
+                if !claimants.contains(&claim) {
+                    claimants.push(claim);
+                }
+            }
         }
     }
+    for (fname, claimants) in claims {
+        let legacy = config.cache_download_dir.join(&fname);
+        if legacy.is_symlink() || !legacy.is_file() {
+            continue;
+        }
+        let [(pkgname, checksum)] = claimants[..] else {
+            log::debug!("[download] {} is claimed by {} fetches, leaving it", legacy.display(), claimants.len());
+            continue;
+        };
+        if let Some(expected) = checksum
+            && calculate_file_checksum(&legacy, expected.len()).ok().as_deref() != Some(expected)
+        {
+            log::debug!("[download] {} doesn't match the checksum of {}, leaving it", legacy.display(), pkgname);
+            continue;
+        }
+        let dest = config.cache_download_dir.join(sanitize_name(pkgname)).join(&fname);
+        if dest.exists() {
+            continue;
+        }
+        let moved = dest.parent().map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::rename(&legacy, &dest))
+            .and_then(|_| std::os::unix::fs::symlink(&dest, &legacy));
+        match moved {
+            Ok(()) => log::info!("[download] moved {} to {}", legacy.display(), dest.display()),
+            Err(e) => log::warn!("[download] failed to move {} to {}: {}", legacy.display(), dest.display(), e),
+        }
+    }
+}
+
+/// Working directory of a `Run` step, created if missing.
+fn step_dir(ctx: &StepContext, cwd: Option<&str>, current_path: &Option<PathBuf>) -> PathBuf {
+    let default_base = ctx.config.cache_packages_dir.join(sanitize_name(&format!("{}-{}", ctx.pkgname, ctx.build_key)));
+    let base_dir = cwd.map(|c| current_path.as_ref().unwrap_or(&default_base).join(c)).unwrap_or_else(|| current_path.clone().unwrap_or(default_base));
+    fs::create_dir_all(&base_dir).ok();
+    base_dir
+}
+
+/// Runs a `Run` step of a consented host build without the sandbox. HOME is
+/// the step's temporary home with `.pilocal` linking to the cave's pilocal,
+/// so commands written for the sandbox (`--prefix ~/.pilocal`) still work.
+fn run_on_host(
+    ctx: &StepContext,
+    command: &str,
+    shell: &Interpreter,
+    cwd: Option<&str>,
+    current_path: &Option<PathBuf>,
+    home: BuildHome,
+) -> Result<StepOutput> {
+    let base_dir = step_dir(ctx, cwd, current_path);
+    fs::create_dir_all(ctx.pilocal_dir).ok();
+    std::os::unix::fs::symlink(ctx.pilocal_dir, home.path().join(".pilocal"))
+        .context("Failed to link .pilocal into the build home")?;
+
+    let mut path = vec![ctx.pilocal_dir.join("bin").display().to_string()];
+    path.extend(ctx.dependency_dirs.iter().map(|d| d.join("bin")).filter(|d| d.exists()).map(|d| d.display().to_string()));
+    path.extend(env::var("PATH").ok());
+
+    let mut cmd = std::process::Command::new(shell.program());
+    cmd.args(shell.args(command))
+        .current_dir(&base_dir)
+        .env("HOME", home.path())
+        .env("PATH", path.join(":"))
+        .env("PI_HOST_BUILD", "1");
+    let paths = PathVars {
+        pilocal: Some(ctx.pilocal_dir),
+        home: Some(home.path()),
+        workspace: None,
+        packages: Some(&ctx.config.cache_packages_dir),
+    };
+    for (k, v) in ctx.env {
+        cmd.env(k, paths.value(v));
+    }
+
+    log::warn!("[{}] host step {}: {} (in {})", ctx.pkgname, ctx.index, command, base_dir.display());
+    let result = spawn_command_with_tail(cmd, TAIL_LINES, step_log(ctx, command));
+    if ctx.config.keep_build_home {
+        log::info!("[{}] build home kept: {}", ctx.pkgname, home.keep().display());
+    }
+    let outcome = result.with_context(|| format!("Failed to execute command: {}", command))?;
+    if !outcome.status.success() {
+        print_failure_tail(ctx, &outcome.tail);
+        anyhow::bail!("Failed to execute command on the host: {} ({})", command, outcome.status);
+    }
+    Ok(base_dir.into())
+}
+
+/// The log the output of a Run step goes to; None (output only on the
+/// terminal) when it can't be created.
+fn step_log(ctx: &StepContext, command: &str) -> Option<fs::File> {
+    step_logs::create(ctx.config, ctx.pkgname, ctx.build_key, ctx.index, command)
+        .map_err(|e| log::warn!("[{}] step {} is not logged: {:#}", ctx.pkgname, ctx.index, e))
+        .ok()
+}
+
+/// The last output lines of a failed Run step, on stderr where builds
+/// usually stay quiet.
+fn print_failure_tail(ctx: &StepContext, tail: &[String]) {
+    let lines = &tail[tail.len().saturating_sub(step_logs::FAILURE_LINES)..];
+    let log = step_logs::path(ctx.config, ctx.pkgname, ctx.build_key, ctx.index);
+    log::error!(
+        "[{}] step {} output (last {} lines, all in {}):\n{}",
+        ctx.pkgname, ctx.index, lines.len(), log.display(), lines.join("\n")
+    );
+}
+
+/// Runs `command` with the step's interpreter, which must exist in the sandbox.
+fn set_step_command(b: &mut crate::services::sandbox::Bubblewrap, shell: &Interpreter, command: &str) -> Result<()> {
+    let program = b.find_program(shell.program())
+        .with_context(|| format!("interpreter '{}' is not available in the sandbox", shell.program()))?;
+    b.set_command(&program.to_string_lossy(), &shell.args(command));
+    Ok(())
+}
+
+/// The sandbox of a `Run` step, without its command, and its working directory.
+fn prepare_run_step(
+    ctx: &StepContext,
+    cwd: Option<&str>,
+    current_path: &Option<PathBuf>,
+    home: &Path,
+) -> Result<(crate::services::sandbox::Bubblewrap, PathBuf)> {
+    let base_dir = step_dir(ctx, cwd, current_path);
+
+    let mut b = prepare_build_sandbox(
+        ctx.config,
+        ctx.pkgname,
+        ctx.version,
+        home,
+        ctx.pilocal_dir,
+        ctx.env,
+        &ctx.dependency_dirs,
+    )?;
+    if ctx.config.strict {
+        b.add_flag("--unshare-net");
+    }
+    if let Some(dir) = ctx.repo_dir {
+        b.add_map_bind(crate::services::sandbox::BindType::RoBind, dir, REPO_MOUNT);
+    }
+    b.set_cwd(&base_dir);
+    Ok((b, base_dir))
+}
+
+/// Re-executes the `Run` step `index` of `pkg=version` in the sandbox a
+/// cave build would use, attached to the terminal, e.g. after a failure.
+/// Earlier steps must be cached. With `shell`, opens an interactive shell in
+/// the step's directory instead, the command in `PI_STEP_COMMAND`.
+pub fn rerun_step(
+    config: &Config,
+    query: &str,
+    index: usize,
+    all_options: &HashMap<String, HashMap<String, serde_json::Value>>,
+    pilocal_dir: &Path,
+    shell: bool,
+) -> Result<()> {
+    let repo_config = Repositories::get_all(config);
+    let build_cache = BuildCache::new(config.cache_dir.clone());
+    let ctx = BuildContext {
+        config,
+        repo_config,
+        build_cache: &build_cache,
+        all_options,
+        pilocal_dir,
+        sums: None,
+        host_build: &[],
+        cave: None,
+        bin_prefix: &HashMap::new(),
+        stats: BuildStats::default(),
+    };
+    let selector = PackageSelector::parse(query).with_context(|| format!("Invalid selector: {}", query))?;
+    let (_, found, repo_name) = resolve::resolve_query(config, repo_config, &selector)
+        .with_context(|| format!("Package not found: {}", query))?;
+    let version = re_evaluate_version(&ctx, &repo_name, &found, &selector)?;
+    let version_str = version.version.to_string();
+    if version.requires_host_build {
+        anyhow::bail!("{} {} builds outside the sandbox, rerun it with `pi cave build --rebuild`", version.pkgname, version_str);
+    }
+
+    let Some(step) = version.pipeline.get(index) else {
+        anyhow::bail!("{} {} has {} steps (0..{})", version.pkgname, version_str, version.pipeline.len(), version.pipeline.len());
+    };
+    let InstallStep::Run { command, cwd, shell: interpreter, .. } = step else {
+        anyhow::bail!("step {} of {} {} is a {} step, only Run steps can be rerun", index, version.pkgname, version_str, step.kind());
+    };
+    let command = config.resolve_packages_dir(command);
+    let options = build_options(all_options, &version.pkgname);
+    let key = build_key(&version_str, &options);
+    let repo_dir = repo_dir(repo_config, &repo_name);
+    let recipe_hash = recipe_hash(&version);
+
+    // The output of the previous step is the input of this one.
+    let mut current_path = None;
+    for (i, previous) in version.pipeline[..index].iter().enumerate() {
+        let mut resolved = previous.clone();
+        if let InstallStep::Run { ref mut command, .. } = resolved {
+            *command = config.resolve_packages_dir(command);
+        }
+        let cached = build_cache.get_step_result(&version.pkgname, &key, i, &step_hash(config, &resolved, &options, repo_dir), &recipe_hash)
+            .with_context(|| format!("step {} of {} {} is not built, run `pi cave build` first", i, version.pkgname, version_str))?;
+        current_path = cached.output_path;
+    }
+
+    let env = HashMap::new();
+    let step_ctx = StepContext {
+        config,
+        env: &env,
+        dependency_dirs: resolve_build_dependencies(&ctx, &version, &version.pkgname)?,
+        pkgname: &version.pkgname,
+        version: &version_str,
+        build_key: &key,
+        variant: None,
+        index,
+        pilocal_dir,
+        repo_dir,
+        host_build: false,
+        sizes: &version.sizes,
+    };
+    let command = resolve_repo_dir(&step_ctx, &command);
+    let home = BuildHome::create(config)?;
+    let (mut b, base_dir) = prepare_run_step(&step_ctx, cwd.as_deref(), &current_path, home.path())?;
+    if shell {
+        b.set_env("PI_STEP_COMMAND", &command);
+        b.set_command("/bin/bash", &[String::from("-i")]);
+        log::info!("[{}] shell in {}, the step runs: {}", version.pkgname, base_dir.display(), command);
+    } else {
+        set_step_command(&mut b, interpreter, &command)?;
+        log::info!("[{}] rerunning step {}: {}", version.pkgname, index, command);
+    }
+    let result = b.spawn();
+    if config.keep_build_home {
+        log::info!("[{}] build home kept: {}", version.pkgname, home.keep().display());
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::repository::Repository;
+    use crate::models::version_entry::Dependency;
+
+    #[test]
+    fn test_dependency_policy() {
+        let mut internal = Repository::new("/repos/internal".to_string(), "internal".to_string());
+        internal.dependency_repos = Some(vec!["main".to_string()]);
+        let repos = Repositories {
+            repositories: vec![
+                internal,
+                Repository::new("/repos/main".to_string(), "main".to_string()),
+                Repository::new("/repos/community".to_string(), "community".to_string()),
+            ],
+        };
+        let entry = |name: &str, deps: &[&str]| VersionEntry {
+            pkgname: name.to_string(),
+            build_dependencies: deps.iter().map(|d| Dependency { name: d.to_string(), optional: false }).collect(),
+            ..Default::default()
+        };
+
+        let mut graph: DependencyGraph = HashMap::new();
+        graph.insert("app".to_string(), (entry("app", &["lib", "go"]), "internal".to_string()));
+        graph.insert("lib".to_string(), (entry("lib", &[]), "internal".to_string()));
+        graph.insert("go".to_string(), (entry("go", &["zlib"]), "main".to_string()));
+        graph.insert("zlib".to_string(), (entry("zlib", &[]), "community".to_string()));
+        assert!(check_dependency_policy(&repos, &graph).is_ok());
+
+        graph.insert("app".to_string(), (entry("app", &["zlib"]), "internal".to_string()));
+        let err = check_dependency_policy(&repos, &graph).unwrap_err().to_string();
+        assert!(err.contains("app (repo internal) may not depend on zlib from repo community"), "{}", err);
+    }
+
+    #[test]
+    fn test_build_waves() {
+        let entry = |name: &str, deps: &[&str]| (VersionEntry {
+            pkgname: name.to_string(),
+            build_dependencies: deps.iter().map(|d| Dependency { name: d.to_string(), optional: false }).collect(),
+            ..Default::default()
+        }, "main".to_string());
+        let mut graph: DependencyGraph = HashMap::new();
+        graph.insert("app".to_string(), entry("app", &["lib", "go"]));
+        graph.insert("lib".to_string(), entry("lib", &["zlib"]));
+        graph.insert("go".to_string(), entry("go", &[]));
+        graph.insert("zlib".to_string(), entry("zlib", &[]));
+        graph.insert("jq".to_string(), entry("jq", &[]));
+        assert_eq!(build_waves(&graph).unwrap(), vec![vec!["go", "jq", "zlib"], vec!["lib"], vec!["app"]]);
+
+        graph.insert("zlib".to_string(), entry("zlib", &["app"]));
+        assert!(build_waves(&graph).unwrap_err().to_string().contains("Circular dependency"));
+    }
+
+    #[test]
+    fn test_strict_violations() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join(CaveSums::FILENAME), format!("{}  https://x/b.tgz\n", "0".repeat(64))).unwrap();
+        let sums = CaveSums::load(tmp.path()).unwrap();
+        let fetch = |url: &str, checksum: Option<&str>| InstallStep::Fetch {
+            name: None, url: url.to_string(), mirrors: Vec::new(),
+            checksum: checksum.map(|c| c.to_string()), filename: None, signature: None, stream: false,
+        };
+        let entry = |name: &str, pipeline: Vec<InstallStep>| VersionEntry {
+            pkgname: name.to_string(),
+            version: crate::utils::inspect::inspect_version("1.2.0").version,
+            pipeline,
+            ..Default::default()
+        };
+
+        let mut graph: DependencyGraph = HashMap::new();
+        graph.insert("a=1.2.0".to_string(), (entry("a", vec![fetch("https://x/a.tgz", Some("abc"))]), "main".to_string()));
+        graph.insert("b=1.2.0".to_string(), (entry("b", vec![fetch("https://x/b.tgz", None)]), "main".to_string()));
+        assert!(strict_violations(&graph, Some(&sums)).is_empty());
+
+        graph.insert("c".to_string(), (entry("c", vec![fetch("https://x/c.tgz", None)]), "main".to_string()));
+        graph.insert("d=1.*".to_string(), (VersionEntry { requires_host_build: true, ..entry("d", Vec::new()) }, "main".to_string()));
+        let violations = strict_violations(&graph, Some(&sums));
+        assert_eq!(violations, vec![
+            "c is not pinned, it resolved to 1.2.0; use c=1.2.0",
+            "c=1.2.0 step 0 fetches https://x/c.tgz without a checksum",
+            "d=1.* is not pinned, it resolved to 1.2.0; use d=1.2.0",
+            "d=1.2.0 builds outside the sandbox, where the network cannot be cut",
+        ]);
+    }
+
+    #[test]
+    fn test_download_plan() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let fetch = |url: &str| InstallStep::Fetch {
+            name: None, url: url.to_string(), mirrors: Vec::new(), checksum: None, filename: None, signature: None, stream: false,
+        };
+        let version = VersionEntry {
+            pkgname: "go".to_string(),
+            pipeline: vec![fetch("https://x/go.tgz"), fetch("https://x/extra.tgz")],
+            sizes: BTreeMap::from([("https://x/go.tgz".to_string(), 1000)]),
+            ..Default::default()
+        };
+        assert_eq!(version.download_size(), None);
+
+        let cached = config.cache_download_dir.join("go").join("extra.tgz");
+        fs::create_dir_all(cached.parent().unwrap()).unwrap();
+        fs::write(&cached, "").unwrap();
+        let mut graph: DependencyGraph = HashMap::new();
+        graph.insert("go".to_string(), (version, "main".to_string()));
+        let plan = download_plan(&config, &graph);
+        let summary: Vec<(&str, Option<u64>, bool)> = plan.iter().map(|d| (d.url.as_str(), d.size, d.cached)).collect();
+        assert_eq!(summary, vec![("https://x/extra.tgz", None, true), ("https://x/go.tgz", Some(1000), false)]);
+        assert!(check_disk_space(&config, &plan).is_ok());
+
+        let huge = vec![PlannedDownload { size: Some(u64::MAX), ..plan[1].clone() }];
+        assert!(check_disk_space(&config, &huge).unwrap_err().to_string().contains("only"));
+    }
+
+    #[test]
+    fn test_options_separate_builds() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let mut all_options = HashMap::new();
+        all_options.insert("python".to_string(), HashMap::from([("shared".to_string(), serde_json::json!(true))]));
+        let shared = build_options(&all_options, "python");
+        let plain = build_options(&all_options, "go");
+        assert_eq!(shared.get("shared").map(|s| s.as_str()), Some("true"));
+
+        // Without options keys and hashes stay as before, so existing caches remain valid.
+        assert_eq!(build_key("3.12.1", &plain), "3.12.1");
+        assert!(build_key("3.12.1", &shared).starts_with("3.12.1#"));
+        let run = InstallStep::Run { name: None, command: "make".to_string(), cwd: None, shell: Interpreter::default() };
+        assert_eq!(step_hash(&config, &run, &plain, None), hash_to_string(&run));
+        assert_ne!(step_hash(&config, &run, &shared, None), step_hash(&config, &run, &plain, None));
+    }
+
+    #[test]
+    fn test_run_interpreter() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let plain = build_options(&HashMap::new(), "go");
+
+        // The default interpreter adds nothing to the hash; others change it.
+        assert_eq!(hash_to_string(&("make", Interpreter::default())), hash_to_string(&"make"));
+        let run = InstallStep::Run { name: None, command: "make".to_string(), cwd: None, shell: Interpreter::default() };
+        let python = InstallStep::Run { name: None, command: "make".to_string(), cwd: None, shell: Interpreter::named("python3") };
+        assert_ne!(step_hash(&config, &python, &plain, None), step_hash(&config, &run, &plain, None));
+
+        // Host steps run the interpreter's argv with the command appended.
+        let pilocal = config.pilocal_path("cave", None);
+        let env = HashMap::new();
+        let step_ctx = StepContext {
+            config: &config, env: &env, dependency_dirs: Vec::new(), pkgname: "kmod", version: "1.0", build_key: "1.0", variant: None,
+            index: 0, pilocal_dir: &pilocal, repo_dir: None, host_build: true, sizes: &BTreeMap::new(),
+        };
+        let step = InstallStep::Run {
+            command: "echo $0 > out".to_string(), cwd: None, name: None,
+            shell: Interpreter::argv(vec!["sh".to_string(), "-c".to_string()]).unwrap(),
+        };
+        let output = execute_step(&step_ctx, &step, &Some(tmp.path().join("src"))).unwrap();
+        assert_eq!(fs::read_to_string(output.path.join("out")).unwrap(), "sh\n");
+
+        let mut sandbox = crate::services::sandbox::Bubblewrap::new();
+        sandbox.add_map_bind(crate::services::sandbox::BindType::RoBind, tmp.path(), "/opt/tools");
+        fs::write(tmp.path().join("python3"), "").unwrap();
+        sandbox.set_env("PATH", "/usr/local/bin:/opt/tools");
+        assert_eq!(sandbox.find_program("python3"), Some(PathBuf::from("/opt/tools/python3")));
+        assert_eq!(sandbox.find_program("/opt/tools/ruby"), None);
+        assert!(set_step_command(&mut sandbox, &Interpreter::named("ruby"), "1").unwrap_err().to_string().contains("'ruby'"));
+    }
+
+    #[test]
+    fn test_repo_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let repo = tmp.path().join("repo");
+        fs::create_dir_all(repo.join("patches")).unwrap();
+        fs::write(repo.join("patches/fix.patch"), "v1").unwrap();
+        let options = BuildOptions::new();
+        let run = InstallStep::Run { name: None, command: "patch -p1 < @REPO_DIR/patches/fix.patch".to_string(), cwd: None, shell: Interpreter::default() };
+
+        let before = step_hash(&config, &run, &options, Some(&repo));
+        assert_eq!(before, step_hash(&config, &run, &options, Some(&repo)));
+        fs::write(repo.join("patches/fix.patch"), "v2").unwrap();
+        assert_ne!(before, step_hash(&config, &run, &options, Some(&repo)));
+
+        // Plugin steps rerun when their executable changes.
+        let plugin = InstallStep::Plugin { name: None, step: "notarize".to_string(), params: BTreeMap::new() };
+        let before = step_hash(&config, &plugin, &options, None);
+        fs::create_dir_all(config.plugins_dir()).unwrap();
+        fs::write(plugin_path(&config, "notarize"), "#!/bin/sh\n").unwrap();
+        assert_ne!(before, step_hash(&config, &plugin, &options, None));
+
+        let env = HashMap::new();
+        let mut step_ctx = StepContext {
+            config: &config, env: &env, dependency_dirs: Vec::new(), pkgname: "tool", version: "1.0", build_key: "1.0", variant: None,
+            index: 0, pilocal_dir: &repo, repo_dir: Some(&repo), host_build: false, sizes: &BTreeMap::new(),
+        };
+        assert_eq!(resolve_repo_dir(&step_ctx, "cat @REPO_DIR/a"), "cat /pi/repo/a");
+        step_ctx.host_build = true;
+        assert_eq!(resolve_repo_dir(&step_ctx, "cat @REPO_DIR/a"), format!("cat {}/a", repo.display()));
+    }
+
+    #[test]
+    fn test_host_build_needs_consent() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let build_cache = BuildCache::new(config.cache_dir.clone());
+        let options = HashMap::new();
+        let pilocal = config.pilocal_path("cave", None);
+        let version = VersionEntry { pkgname: "kmod".to_string(), requires_host_build: true, ..Default::default() };
+        let allowed = vec!["kmod".to_string()];
+        let repos = Repositories { repositories: Vec::new() };
+        let no_prefix = HashMap::new();
+        let ctx = |config, host_build| BuildContext {
+            config, repo_config: &repos, build_cache: &build_cache, all_options: &options,
+            pilocal_dir: &pilocal, sums: None, host_build, cave: None, bin_prefix: &no_prefix, stats: BuildStats::default(),
+        };
+        assert!(host_build_allowed(&ctx(&config, &[]), &version).unwrap_err().to_string().contains("--allow-host-build"));
+        assert!(host_build_allowed(&ctx(&config, &allowed), &version).unwrap());
+        let consenting = Config { allow_host_build: true, ..config.clone() };
+        assert!(host_build_allowed(&ctx(&consenting, &[]), &version).unwrap());
+
+        let mut env = HashMap::new();
+        env.insert("TOOL_ROOT".to_string(), "$/tool".to_string());
+        let step_ctx = StepContext {
+            config: &config, env: &env, dependency_dirs: Vec::new(), pkgname: "kmod", version: "1.0", build_key: "1.0", variant: None,
+            index: 0, pilocal_dir: &pilocal, repo_dir: None, host_build: true, sizes: &BTreeMap::new(),
+        };
+        let step = InstallStep::Run {
+            command: "echo $PI_HOST_BUILD $TOOL_ROOT > out && readlink ~/.pilocal >> out".to_string(), cwd: None, name: None,
+            shell: Interpreter::default(),
+        };
+        let output = execute_step(&step_ctx, &step, &Some(tmp.path().join("src"))).unwrap();
+        assert_eq!(output.path, tmp.path().join("src"));
+        let out = fs::read_to_string(output.path.join("out")).unwrap();
+        assert_eq!(out, format!("1 {}/tool\n{}\n", pilocal.display(), pilocal.display()));
+    }
+
+    #[test]
+    fn test_signature_path() {
+        let file = Path::new("/cache/downloads/tool/tool-1.0.tar.gz");
+        let asc = signature_path(file, "https://example.com/tool-1.0.tar.gz.asc?token=a.b");
+        let name = asc.file_name().unwrap().to_string_lossy().to_string();
+        assert_eq!(asc.parent(), file.parent());
+        assert!(name.ends_with("-tool-1.0.tar.gz.asc"), "{}", name);
+        let minisig = signature_path(file, "https://example.com/tool-1.0.tar.gz.minisig#frag");
+        assert!(minisig.to_string_lossy().ends_with(".tar.gz.minisig"), "{}", minisig.display());
+        assert!(signature_path(file, "https://example.com/sign/latest").to_string_lossy().ends_with(".tar.gz.sig"));
+        // Another signature URL gets its own file.
+        assert_ne!(asc, signature_path(file, "https://mirror.example.com/tool-1.0.tar.gz.asc"));
+    }
+
+    #[test]
+    fn test_cached_patch_is_verified() {
+        let tmp = tempfile::tempdir().unwrap();
+        let patch = tmp.path().join("fix.patch");
+        let url = "https://example.com/fix.patch";
+        // Gone from the download cache: it was checked when it was applied.
+        verify_patch(&patch, url, "00").unwrap();
+        fs::write(&patch, "--- a/x\n+++ b/x\n").unwrap();
+        let sum = calculate_file_checksum(&patch, 64).unwrap();
+        verify_patch(&patch, url, &sum).unwrap();
+        fs::write(&patch, "tampered").unwrap();
+        let err = verify_patch(&patch, url, &sum).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{:#}", err);
+        assert!(!patch.exists());
+    }
+
+    #[test]
+    fn test_download_path_layout() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let legacy = config.cache_download_dir.join("node-v20.tar.gz");
+        fs::write(&legacy, "old").unwrap();
+        let mut graph: DependencyGraph = HashMap::new();
+        graph.insert("node".to_string(), (fetching("node", "https://nodejs.org/node-v20.tar.gz"), "main".to_string()));
+        migrate_flat_downloads(&config, &graph);
+
+        let dest = download_path(&config, "node", "https://nodejs.org/node-v20.tar.gz", "node-v20.tar.gz");
+        assert_eq!(dest, config.cache_download_dir.join("node").join("node-v20.tar.gz"));
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "old");
+        assert_eq!(fs::read_link(&legacy).unwrap(), dest);
+
+        config.db().unwrap().record_download(&dest, "https://nodejs.org/node-v20.tar.gz").unwrap();
+        let same = download_path(&config, "node", "https://nodejs.org/node-v20.tar.gz", "node-v20.tar.gz");
+        assert_eq!(same, dest);
+        let other = download_path(&config, "node", "https://mirror.example/node-v20.tar.gz", "node-v20.tar.gz");
+        assert_ne!(other, dest);
+        assert!(other.file_name().unwrap().to_string_lossy().ends_with("-node-v20.tar.gz"));
+        let elsewhere = download_path(&config, "npm:node", "https://registry.example/node-v20.tar.gz", "node-v20.tar.gz");
+        assert_eq!(elsewhere, config.cache_download_dir.join("npm_node").join("node-v20.tar.gz"));
+    }
+
+    fn fetching(pkgname: &str, url: &str) -> VersionEntry {
+        VersionEntry {
+            pkgname: pkgname.to_string(),
+            pipeline: vec![InstallStep::Fetch {
+                url: url.to_string(), mirrors: Vec::new(), checksum: None, filename: None, name: None, signature: None, stream: false,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_local_urls_only_from_the_package_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let vendor = tmp.path().join("vendor");
+        fs::create_dir(&vendor).unwrap();
+        fs::write(vendor.join("tool-1.0.tgz"), "x").unwrap();
+        fs::write(tmp.path().join("secret"), "x").unwrap();
+        let vendor = vendor.canonicalize().unwrap();
+
+        let inside = fetching("dir:tool", &format!("file://{}/tool-1.0.tgz", vendor.display()));
+        assert!(check_local_urls(&inside, Some(&vendor)).is_ok());
+        assert!(check_local_urls(&inside, None).unwrap_err().to_string().contains("only dir: packages"));
+        let escaping = fetching("dir:tool", &format!("file://{}/../secret", vendor.display()));
+        assert!(check_local_urls(&escaping, Some(&vendor)).unwrap_err().to_string().contains("outside"));
+        assert!(check_local_urls(&fetching("go", "https://go.dev/go.tgz"), None).is_ok());
+    }
+
+    #[test]
+    fn test_flat_download_with_two_claimants_stays() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let legacy = config.cache_download_dir.join("v1.0.tar.gz");
+        fs::write(&legacy, "old").unwrap();
+        let mut graph: DependencyGraph = HashMap::new();
+        graph.insert("jq".to_string(), (fetching("jq", "https://example.com/jq/v1.0.tar.gz"), "main".to_string()));
+        graph.insert("yq".to_string(), (fetching("yq", "https://example.com/yq/v1.0.tar.gz"), "main".to_string()));
+        migrate_flat_downloads(&config, &graph);
+        assert!(legacy.is_file() && !legacy.is_symlink());
+        assert!(!config.cache_download_dir.join("jq").exists() && !config.cache_download_dir.join("yq").exists());
+    }
 }
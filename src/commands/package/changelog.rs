@@ -0,0 +1,185 @@
+use crate::commands::package::build;
+use crate::commands::package::resolve;
+use crate::models::cave::Cave;
+use crate::models::config::Config;
+use crate::models::lockfile::{CaveLock, LockedPackage};
+use crate::models::repository::Repositories;
+use crate::models::selector::PackageSelector;
+use crate::models::version_entry::{StructuredVersion, VersionEntry, VersionList};
+use crate::services::downloader::Downloader;
+use crate::utils::inspect::inspect_version;
+use crate::utils::markdown;
+use crate::utils::theme::Theme;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::env;
+
+pub fn run(config: &Config, selector_str: &str, from: Option<String>, variant: Option<String>) {
+    if let Err(e) = execute(config, selector_str, from.as_deref(), variant.as_deref()) {
+        log::error!("changelog failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Prints the release notes of the versions after the current one up to the
+/// one `selector_str` resolves to, newest first. The current version is
+/// `from`, or what the nearest cave builds; without either only the target's
+/// notes are shown.
+fn execute(config: &Config, selector_str: &str, from: Option<&str>, variant: Option<&str>) -> Result<()> {
+    let selector = PackageSelector::parse(selector_str).with_context(|| format!("invalid selector: {}", selector_str))?;
+    let repo_config = Repositories::get_all(config);
+    let (full_name, target, repo_name) = resolve::resolve_query(config, repo_config, &selector)
+        .with_context(|| format!("package not found: {}", selector_str))?;
+
+    let current = match from {
+        Some(version) => Some(inspect_version(version).version),
+        None => cave_version(config, &target, &repo_name, variant),
+    };
+    if let Some(current) = &current {
+        if *current >= target.version {
+            println!("{} is at {}, which is not older than {}", target.pkgname, current, full_name);
+            return Ok(());
+        }
+        log::info!("[{}] notes from {} to {}", target.pkgname, current, target.version);
+    }
+
+    let list = version_list(config, &repo_name, &target.pkgname)?;
+    let versions = versions_between(&list, current.as_ref(), &target);
+    let theme = Theme::current();
+    let mut fetched = HashSet::new();
+    for version in versions {
+        let title = format!("{} {}", version.pkgname, version.version);
+        let date = if version.release_date.is_empty() { String::new() } else { format!(" ({})", version.release_date) };
+        print!("{}", markdown::render(&format!("## {}{}", title, date), theme.color));
+        match notes(&version, &mut fetched) {
+            Some(notes) => println!("{}", markdown::render(&notes, theme.color)),
+            None => println!("no release notes\n"),
+        }
+    }
+    Ok(())
+}
+
+/// The version of the target's package the cave builds, if it has it: the
+/// one pinned in pi.cave.lock, else what the cave resolves to now.
+fn cave_version(config: &Config, target: &VersionEntry, repo_name: &str, variant: Option<&str>) -> Option<StructuredVersion> {
+    let current_dir = env::current_dir().ok()?;
+    let (_, cave) = Cave::find(config, &current_dir)?;
+    let lock = CaveLock::load(&cave.workspace).unwrap_or_else(|e| {
+        log::warn!("[{}] ignoring {}: {:#}", cave.name, CaveLock::FILENAME, e);
+        None
+    });
+    if let Some(packages) = lock.as_ref().and_then(|l| l.packages(variant)) {
+        return locked_version(packages, &target.pkgname, repo_name);
+    }
+    let settings = cave.get_effective_settings(variant).ok()?;
+    let graph = match build::resolve_graph(config, &settings.packages, &settings.options) {
+        Ok(graph) => graph,
+        Err(e) => {
+            log::warn!("[{}] can't resolve the cave, showing only the target's notes: {:#}", cave.name, e);
+            return None;
+        }
+    };
+    graph.into_values()
+        .filter(|(v, repo)| v.pkgname == target.pkgname && repo == repo_name)
+        .map(|(v, _)| v.version)
+        .min()
+}
+
+/// The lowest locked version of `pkgname` from `repo_name`.
+fn locked_version(packages: &[LockedPackage], pkgname: &str, repo_name: &str) -> Option<StructuredVersion> {
+    packages.iter()
+        .filter(|p| p.pkgname == pkgname && p.repo == repo_name)
+        .map(|p| inspect_version(&p.version).version)
+        .min()
+}
+
+fn version_list(config: &Config, repo_name: &str, pkgname: &str) -> Result<VersionList> {
+    if let Some(list) = config.state.version_lists.get(&format!("{}:{}", repo_name, pkgname)) {
+        return Ok((**list).clone());
+    }
+    VersionList::load(config, repo_name, pkgname)
+}
+
+/// Versions newer than `current` up to `target`, of the target's release
+/// type (the target itself always), newest first and one per version.
+fn versions_between(list: &VersionList, current: Option<&StructuredVersion>, target: &VersionEntry) -> Vec<VersionEntry> {
+    let mut versions: Vec<VersionEntry> = list.versions.iter()
+        .filter(|v| v.version <= target.version && current.is_none_or(|c| v.version > *c))
+        .filter(|v| v.release_type == target.release_type || v.version == target.version)
+        .filter(|v| current.is_some() || v.version == target.version)
+        .cloned()
+        .collect();
+    versions.sort_by(|a, b| b.version.cmp(&a.version));
+    versions.dedup_by(|a, b| a.version == b.version);
+    versions
+}
+
+/// Inline notes, then the fetched changelog; a URL already printed for a
+/// newer version is only referred to.
+fn notes(version: &VersionEntry, fetched: &mut HashSet<String>) -> Option<String> {
+    let mut parts = Vec::new();
+    if let Some(notes) = &version.notes {
+        parts.push(notes.clone());
+    }
+    if let Some(url) = &version.changelog_url {
+        if !fetched.insert(url.clone()) {
+            parts.push(format!("see the changelog above ({})", url));
+        } else {
+            match Downloader::download_text(url, None).map(|bytes| String::from_utf8_lossy(&bytes).into_owned()) {
+                Ok(text) => parts.push(text),
+                Err(e) => {
+                    log::warn!("[{}] failed to fetch {}: {:#}", version.pkgname, url, e);
+                    parts.push(format!("release notes: {}", url));
+                }
+            }
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("\n\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::version_entry::ReleaseType;
+
+    #[test]
+    fn test_versions_between() {
+        let entry = |raw: &str, release_type: ReleaseType| VersionEntry {
+            pkgname: "go".to_string(),
+            version: inspect_version(raw).version,
+            release_type,
+            notes: Some(format!("notes of {}", raw)),
+            ..Default::default()
+        };
+        let list = VersionList {
+            versions: vec![
+                entry("1.21.0", ReleaseType::Stable), entry("1.22.0", ReleaseType::Stable), entry("1.22.0", ReleaseType::Stable),
+                entry("1.23rc1", ReleaseType::Testing), entry("1.23.0", ReleaseType::Stable), entry("1.24.0", ReleaseType::Stable),
+            ],
+        };
+        let target = entry("1.23.0", ReleaseType::Stable);
+        let raw = |vs: Vec<VersionEntry>| vs.into_iter().map(|v| v.version.raw).collect::<Vec<_>>();
+        assert_eq!(raw(versions_between(&list, Some(&inspect_version("1.21.0").version), &target)), vec!["1.23.0", "1.22.0"]);
+        assert_eq!(raw(versions_between(&list, None, &target)), vec!["1.23.0"]);
+
+        let mut fetched = HashSet::new();
+        assert_eq!(notes(&target, &mut fetched).unwrap(), "notes of 1.23.0");
+        assert!(notes(&VersionEntry::default(), &mut fetched).is_none());
+    }
+
+    #[test]
+    fn test_locked_version() {
+        let locked = |query: &str, pkgname: &str, version: &str, repo: &str| LockedPackage {
+            query: query.to_string(), pkgname: pkgname.to_string(), version: version.to_string(), repo: repo.to_string(), artifacts: Vec::new(),
+        };
+        let packages = vec![
+            locked("go", "go", "1.22.1", "main"),
+            locked("go=1.21", "go", "1.21.5", "main"),
+            locked("go@fork", "go", "1.20.0", "fork"),
+            locked("node", "node", "20.1.0", "main"),
+        ];
+        assert_eq!(locked_version(&packages, "go", "main").unwrap().raw, "1.21.5");
+        assert_eq!(locked_version(&packages, "go", "fork").unwrap().raw, "1.20.0");
+        assert!(locked_version(&packages, "ruby", "main").is_none());
+    }
+}
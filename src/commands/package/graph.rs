@@ -0,0 +1,208 @@
+use crate::commands::package::build::{self, DependencyGraph};
+use crate::models::cave::Cave;
+use crate::models::config::Config;
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::env;
+
+/// `pi package graph --format`.
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz, e.g. `pi package graph erlang | dot -Tsvg > erlang.svg`
+    #[default]
+    Dot,
+    /// A Mermaid flowchart, for Markdown that renders it
+    Mermaid,
+}
+
+pub fn run(config: &Config, selector: &str, format: GraphFormat) {
+    if let Err(e) = execute_graph(config, selector, format) {
+        log::error!("graph failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn execute_graph(config: &Config, selector: &str, format: GraphFormat) -> Result<()> {
+    // Build dependencies can depend on options, so use the cave's when there is one.
+    let options = env::current_dir().ok()
+        .and_then(|dir| Cave::find(config, &dir))
+        .and_then(|(_, cave)| cave.get_effective_settings(config.cave_variant.as_deref()).ok())
+        .map(|settings| settings.options)
+        .unwrap_or_default();
+    let graph = build::resolve_graph(config, &[selector.to_string()], &options)?;
+
+    let levels = match build::build_waves(&graph) {
+        Ok(waves) => waves.into_iter().enumerate()
+            .flat_map(|(level, wave)| wave.into_iter().map(move |query| (query, level)))
+            .collect(),
+        Err(e) => {
+            log::warn!("[graph] {:#}; edges of the cycle are marked", e);
+            HashMap::new()
+        }
+    };
+    let edges = edges(&graph);
+    log::info!("[graph] {} pulls in {} package(s) over {} edge(s)", selector, graph.len() - 1, edges.len());
+    print!("{}", match format {
+        GraphFormat::Dot => render_dot(&graph, &edges, &levels),
+        GraphFormat::Mermaid => render_mermaid(&graph, &edges),
+    });
+    Ok(())
+}
+
+/// A build dependency edge of the graph.
+#[derive(Debug, PartialEq)]
+struct Edge {
+    from: String,
+    to: String,
+    optional: bool,
+    /// `to` leads back to `from`: the edge is part of a cycle.
+    cyclic: bool,
+}
+
+/// The edges between resolved packages, sorted.
+fn edges(graph: &DependencyGraph) -> Vec<Edge> {
+    let mut edges: Vec<Edge> = graph.iter()
+        .flat_map(|(query, (version, _))| {
+            version.build_dependencies.iter()
+                .filter(|dep| graph.contains_key(&dep.name))
+                .map(|dep| Edge {
+                    from: query.clone(),
+                    to: dep.name.clone(),
+                    optional: dep.optional,
+                    cyclic: reaches(graph, &dep.name, query),
+                })
+        })
+        .collect();
+    edges.sort_by(|a, b| (&a.from, &a.to).cmp(&(&b.from, &b.to)));
+    edges
+}
+
+fn reaches(graph: &DependencyGraph, from: &str, target: &str) -> bool {
+    let mut seen = HashSet::new();
+    let mut stack = vec![from];
+    while let Some(query) = stack.pop() {
+        if query == target {
+            return true;
+        }
+        if !seen.insert(query) {
+            continue;
+        }
+        if let Some((version, _)) = graph.get(query) {
+            stack.extend(version.build_dependencies.iter().map(|d| d.name.as_str()));
+        }
+    }
+    false
+}
+
+fn sorted_queries(graph: &DependencyGraph) -> Vec<&String> {
+    let mut queries: Vec<&String> = graph.keys().collect();
+    queries.sort();
+    queries
+}
+
+fn label(graph: &DependencyGraph, query: &str) -> (String, String) {
+    match graph.get(query) {
+        Some((version, repo)) => (format!("{}={}", version.pkgname, version.version), repo.clone()),
+        None => (query.to_string(), String::new()),
+    }
+}
+
+/// Packages of one build wave share a rank, so dependencies sit below their dependents.
+fn render_dot(graph: &DependencyGraph, edges: &[Edge], levels: &HashMap<String, usize>) -> String {
+    let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+    let quote = |s: &str| format!("\"{}\"", escape(s));
+    let mut out = String::from("digraph pi {\n  rankdir=TB;\n  node [shape=box];\n");
+    for query in sorted_queries(graph) {
+        let (name, repo) = label(graph, query);
+        out.push_str(&format!("  {} [label=\"{}\\n{}\"];\n", quote(query), escape(&name), escape(&repo)));
+    }
+    let mut ranks: Vec<Vec<&String>> = Vec::new();
+    for (query, level) in levels {
+        if ranks.len() <= *level {
+            ranks.resize_with(level + 1, Vec::new);
+        }
+        ranks[*level].push(query);
+    }
+    for rank in ranks.iter_mut().filter(|r| r.len() > 1) {
+        rank.sort();
+        let names: Vec<String> = rank.iter().map(|q| quote(q)).collect();
+        out.push_str(&format!("  {{ rank=same; {}; }}\n", names.join("; ")));
+    }
+    for edge in edges {
+        let mut attrs = Vec::new();
+        if edge.optional {
+            attrs.push("style=dashed");
+        }
+        if edge.cyclic {
+            attrs.push("color=red");
+        }
+        let attrs = if attrs.is_empty() { String::new() } else { format!(" [{}]", attrs.join(", ")) };
+        out.push_str(&format!("  {} -> {}{};\n", quote(&edge.from), quote(&edge.to), attrs));
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn render_mermaid(graph: &DependencyGraph, edges: &[Edge]) -> String {
+    let queries = sorted_queries(graph);
+    let ids: HashMap<&str, String> = queries.iter().enumerate().map(|(i, q)| (q.as_str(), format!("n{}", i))).collect();
+    let mut out = String::from("flowchart TD\n");
+    for query in &queries {
+        let (name, repo) = label(graph, query);
+        out.push_str(&format!("  {}[\"{}<br/>{}\"]\n", ids[query.as_str()], name.replace('"', "#quot;"), repo));
+    }
+    for edge in edges {
+        let arrow = if edge.optional { "-.->" } else { "-->" };
+        out.push_str(&format!("  {} {} {}\n", ids[edge.from.as_str()], arrow, ids[edge.to.as_str()]));
+    }
+    for (i, _) in edges.iter().enumerate().filter(|(_, e)| e.cyclic) {
+        out.push_str(&format!("  linkStyle {} stroke:red\n", i));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::version_entry::{Dependency, VersionEntry};
+    use crate::utils::inspect::inspect_version;
+
+    fn entry(name: &str, deps: &[(&str, bool)]) -> (String, (VersionEntry, String)) {
+        let version = VersionEntry {
+            pkgname: name.to_string(),
+            version: inspect_version("1.0").version,
+            build_dependencies: deps.iter().map(|(n, optional)| Dependency { name: n.to_string(), optional: *optional }).collect(),
+            ..Default::default()
+        };
+        (name.to_string(), (version, "main".to_string()))
+    }
+
+    #[test]
+    fn test_render() {
+        let graph: DependencyGraph = [
+            entry("elixir", &[("erlang", false)]),
+            entry("erlang", &[("openssl", false), ("ncurses", true)]),
+            entry("openssl", &[]),
+            entry("ncurses", &[]),
+        ].into_iter().collect();
+        let levels = build::build_waves(&graph).unwrap().into_iter().enumerate()
+            .flat_map(|(level, wave)| wave.into_iter().map(move |q| (q, level)))
+            .collect();
+        let graph_edges = edges(&graph);
+        assert!(graph_edges.iter().all(|e| !e.cyclic));
+        let dot = render_dot(&graph, &graph_edges, &levels);
+        assert!(dot.contains("  \"elixir\" [label=\"elixir=1.0\\nmain\"];\n"), "{}", dot);
+        assert!(dot.contains("  { rank=same; \"ncurses\"; \"openssl\"; }\n"), "{}", dot);
+        assert!(dot.contains("  \"erlang\" -> \"ncurses\" [style=dashed];\n"), "{}", dot);
+
+        let mermaid = render_mermaid(&graph, &graph_edges);
+        assert!(mermaid.starts_with("flowchart TD\n  n0[\"elixir=1.0<br/>main\"]\n"), "{}", mermaid);
+        assert!(mermaid.contains("  n1 -.-> n2\n"), "{}", mermaid);
+
+        let cyclic: DependencyGraph = [entry("a", &[("b", false)]), entry("b", &[("a", false)]), entry("c", &[("a", false)])].into_iter().collect();
+        assert!(build::build_waves(&cyclic).is_err());
+        let cyclic_edges = edges(&cyclic);
+        assert_eq!(cyclic_edges.iter().map(|e| e.cyclic).collect::<Vec<_>>(), vec![true, true, false]);
+        assert!(render_mermaid(&cyclic, &cyclic_edges).ends_with("  linkStyle 0 stroke:red\n  linkStyle 1 stroke:red\n"));
+    }
+}
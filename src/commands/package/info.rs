@@ -3,8 +3,35 @@ use crate::models::repository::Repositories;
 use crate::models::selector::PackageSelector;
 use crate::models::version_entry::VersionEntry;
 use crate::commands::package::resolve;
-use comfy_table::presets::UTF8_FULL;
-use comfy_table::{Cell, Color, Table};
+use crate::services::cache::build::{split_build_key, Footprint};
+use crate::services::cache::BuildCache;
+use crate::utils::size::format_size;
+use crate::utils::theme::Theme;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// `pi package info --format`.
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum InfoFormat {
+    /// Tables, for people
+    #[default]
+    Table,
+    /// The re-evaluated version entry as JSON, for scripts and editors
+    Json,
+    /// The same as YAML
+    Yaml,
+}
+
+/// What `--format json|yaml` prints: the version entry as the recipe
+/// produced it, and the footprints of its builds by build key.
+#[derive(Debug, Serialize)]
+struct InfoReport<'a> {
+    package: &'a str,
+    repo: &'a str,
+    entry: &'a VersionEntry,
+    installed: BTreeMap<&'a str, Footprint>,
+}
 
 /// Options for re-evaluating a package version.
 struct ReEvalOptions<'a> {
@@ -15,7 +42,7 @@ struct ReEvalOptions<'a> {
     selector: &'a PackageSelector,
 }
 
-pub fn run(config: &Config, selector_str: &str) {
+pub fn run(config: &Config, selector_str: &str, format: InfoFormat) {
     let selector = match PackageSelector::parse(selector_str) {
         Some(s) => s,
         None => {
@@ -24,22 +51,54 @@ pub fn run(config: &Config, selector_str: &str) {
         }
     };
 
+    let format = effective_format(format, Theme::current().json);
     let repo_config = Repositories::get_all(config);
-    let resolved = resolve::resolve_query(config, &repo_config, &selector);
+    let resolved = resolve::resolve_query(config, repo_config, &selector);
 
     match resolved {
         Some((full_name, version, repo_name)) => {
             let opts = ReEvalOptions {
-                config, repo_config: &repo_config, repo_name: &repo_name,
+                config, repo_config, repo_name: &repo_name,
                 version: &version, selector: &selector,
             };
             let dynamic_version = re_evaluate_version(opts);
-            print_package_info(&full_name, &dynamic_version.unwrap_or(version), &repo_name);
+            let version = dynamic_version.unwrap_or(version);
+            let installed = installed(config, &version);
+            if format == InfoFormat::Table {
+                print_package_info(&full_name, &version, &repo_name, &installed);
+                return;
+            }
+            let report = InfoReport {
+                package: &full_name,
+                repo: &repo_name,
+                entry: &version,
+                installed: installed.iter().map(|(key, fp)| (key.as_str(), *fp)).collect(),
+            };
+            match render_report(&report, format) {
+                Ok(text) => print!("{}", text),
+                Err(e) => {
+                    log::error!("info failed: {:#}", e);
+                    std::process::exit(1);
+                }
+            }
         }
         None => log::error!("package not found: {}", selector_str),
     }
 }
 
+/// `--format`, as JSON under the global `--json`, which keeps stdout JSON.
+fn effective_format(format: InfoFormat, json: bool) -> InfoFormat {
+    if json { InfoFormat::Json } else { format }
+}
+
+/// The report as YAML for `--format yaml`, else as JSON.
+fn render_report(report: &InfoReport, format: InfoFormat) -> Result<String> {
+    Ok(match format {
+        InfoFormat::Yaml => serde_norway::to_string(report)?,
+        _ => serde_json::to_string_pretty(report)? + "\n",
+    })
+}
+
 fn re_evaluate_version(opts: ReEvalOptions) -> Option<VersionEntry> {
     let repo = opts.repo_config.repositories.iter().find(|r| r.name == opts.repo_name)?;
     let pkg_list = crate::models::package_entry::PackageList::get_for_repo(opts.config, repo, false)?;
@@ -48,7 +107,8 @@ fn re_evaluate_version(opts: ReEvalOptions) -> Option<VersionEntry> {
     let star_path = std::path::Path::new(&repo.path).join(&star_file);
     
     let exec_opts = crate::starlark::runtime::ExecutionOptions {
-        path: &star_path, function_name: &func, config: opts.config, options: None,
+        path: &star_path, function_name: &func, config: opts.config, options: None, known: None,
+        cave: None,
     };
 
     let dynamic_versions = if opts.version.pkgname.contains(':') {
@@ -70,29 +130,38 @@ fn find_entry_details(
         return Some((pkg.filename.clone(), pkg.function_name.clone(), pkg.name.clone()));
     }
     
-    if let Some(prefix) = selector.prefix.as_ref() {
-        if let Some(mgr) = pkg_list.managers.get(prefix) {
-            let inner = if version.pkgname.contains(':') {
-                version.pkgname.split(':').nth(1).unwrap().to_string()
-            } else {
-                version.pkgname.clone()
-            };
-            return Some((mgr.filename.clone(), mgr.function_name.clone(), inner));
-        }
+    if let Some(prefix) = selector.prefix.as_ref()
+        && let Some(mgr) = pkg_list.manager(prefix, &selector.package)
+    {
+        let inner = if version.pkgname.contains(':') {
+            version.pkgname.split(':').nth(1).unwrap().to_string()
+        } else {
+            version.pkgname.clone()
+        };
+        return Some((mgr.filename.clone(), mgr.function_name.clone(), inner));
     }
 
-    if version.pkgname.contains(':') {
-        let mgr_name = version.pkgname.split(':').next()?;
-        if let Some(mgr) = pkg_list.managers.get(mgr_name) {
-            let inner = version.pkgname.split(':').nth(1)?;
-            return Some((mgr.filename.clone(), mgr.function_name.clone(), inner.to_string()));
-        }
+    if let Some((mgr_name, inner)) = version.pkgname.split_once(':')
+        && let Some(mgr) = pkg_list.manager(mgr_name, inner)
+    {
+        return Some((mgr.filename.clone(), mgr.function_name.clone(), inner.to_string()));
     }
     None
 }
 
-fn print_package_info(full_name: &str, v: &VersionEntry, repo_name: &str) {
-    print_base_info(full_name, v, repo_name);
+/// Footprints of the builds of `v` in the package store, by build key
+/// (the version, with `#<hash>` for builds with options).
+fn installed(config: &Config, v: &VersionEntry) -> Vec<(String, Footprint)> {
+    let version = v.version.to_string();
+    let cache = BuildCache::new(config.cache_dir.clone()).load(&v.pkgname);
+    cache.existing_footprints().into_iter()
+        .filter(|(key, _)| split_build_key(key).0 == version)
+        .map(|(key, fp)| (key.to_string(), fp))
+        .collect()
+}
+
+fn print_package_info(full_name: &str, v: &VersionEntry, repo_name: &str, installed: &[(String, Footprint)]) {
+    print_base_info(full_name, v, repo_name, installed);
     
     if !v.build_dependencies.is_empty() {
         print_dependencies(&v.build_dependencies);
@@ -105,13 +174,8 @@ fn print_package_info(full_name: &str, v: &VersionEntry, repo_name: &str) {
     }
 }
 
-fn print_base_info(full_name: &str, v: &VersionEntry, repo_name: &str) {
-    let mut table = Table::new();
-    table.load_preset(UTF8_FULL);
-    table.set_header(vec![
-        Cell::new("Property").fg(Color::Yellow),
-        Cell::new("Value").fg(Color::Yellow),
-    ]);
+fn print_base_info(full_name: &str, v: &VersionEntry, repo_name: &str, installed: &[(String, Footprint)]) {
+    let mut table = Theme::current().framed_table(&["Property", "Value"]);
 
     table.add_row(vec!["Package", full_name]);
     table.add_row(vec!["Repository", repo_name]);
@@ -119,48 +183,99 @@ fn print_base_info(full_name: &str, v: &VersionEntry, repo_name: &str) {
     if !v.stream.is_empty() { table.add_row(vec!["Stream", &v.stream]); }
     table.add_row(vec!["Release Date", &v.release_date]);
     table.add_row(vec!["Release Type", &v.release_type.to_string()]);
-    println!("{}", table);
+    for (key, fp) in installed {
+        let label = match split_build_key(key).1 {
+            Some(options) => format!("Installed (options {})", options),
+            None => "Installed".to_string(),
+        };
+        table.add_row(vec![&label, &format!("{} in {} files", format_size(fp.bytes), fp.files)]);
+    }
+    Theme::current().print(&table);
 }
 
 fn print_dependencies(deps: &[crate::models::version_entry::Dependency]) {
     println!("\nBuild Dependencies:");
-    let mut table = Table::new();
-    table.load_preset(UTF8_FULL);
-    table.set_header(vec!["Package", "Optional"]);
+    let mut table = Theme::current().framed_table(&["Package", "Optional"]);
     for dep in deps {
         table.add_row(vec![&dep.name, &dep.optional.to_string()]);
     }
-    println!("{}", table);
+    Theme::current().print(&table);
 }
 
 fn print_pipeline(steps: &[crate::models::version_entry::InstallStep]) {
     println!("\nPipeline Steps:");
-    let mut table = Table::new();
-    table.load_preset(UTF8_FULL);
-    table.set_header(vec!["#", "Name", "Type", "Details"]);
+    let mut table = Theme::current().framed_table(&["#", "Name", "Type", "Details"]);
     for (i, step) in steps.iter().enumerate() {
         let (typ, details, name) = match step {
-            crate::models::version_entry::InstallStep::Fetch { url, name, .. } => ("Fetch", url.clone(), name.as_deref().unwrap_or("-")),
+            crate::models::version_entry::InstallStep::Fetch { url, mirrors, name, signature, stream, .. } => {
+                let mut details = if mirrors.is_empty() { url.clone() } else { format!("{} (+{} mirrors)", url, mirrors.len()) };
+                if let Some(signature) = signature {
+                    details.push_str(&format!(" (signed, {})", signature.url));
+                }
+                if *stream {
+                    details.push_str(" (streamed into extract)");
+                }
+                ("Fetch", details, name.as_deref().unwrap_or("-"))
+            }
             crate::models::version_entry::InstallStep::Extract { name, .. } => ("Extract", "-".to_string(), name.as_deref().unwrap_or("-")),
-            crate::models::version_entry::InstallStep::Run { command, name, .. } => ("Run", command.clone(), name.as_deref().unwrap_or("-")),
+            crate::models::version_entry::InstallStep::GitClone { url, rev, name, .. } => {
+                ("GitClone", format!("{} @ {}", url, rev), name.as_deref().unwrap_or("-"))
+            }
+            crate::models::version_entry::InstallStep::Run { command, name, shell, .. } => {
+                let details = if shell.is_default() { command.clone() } else { format!("[{}] {}", shell, command) };
+                ("Run", details, name.as_deref().unwrap_or("-"))
+            }
+            crate::models::version_entry::InstallStep::Patch { url_or_inline, strip, name, .. } => {
+                let source = if crate::models::version_entry::InstallStep::is_inline_patch(url_or_inline) {
+                    format!("inline, {} lines", url_or_inline.lines().count())
+                } else {
+                    url_or_inline.clone()
+                };
+                ("Patch", format!("{} (-p{})", source, strip), name.as_deref().unwrap_or("-"))
+            }
+            crate::models::version_entry::InstallStep::Plugin { step, params, name } => {
+                let params: Vec<String> = params.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                ("Plugin", format!("{} {}", step, params.join(" ")).trim_end().to_string(), name.as_deref().unwrap_or("-"))
+            }
         };
         table.add_row(vec![&i.to_string(), name, typ, &details]);
     }
-    println!("{}", table);
+    Theme::current().print(&table);
 }
 
 fn print_exports(exports: &[crate::models::version_entry::Export]) {
     println!("\nExports:");
-    let mut table = Table::new();
-    table.load_preset(UTF8_FULL);
-    table.set_header(vec!["Type", "Source", "Destination/Value"]);
+    let mut table = Theme::current().framed_table(&["Type", "Source", "Destination/Value"]);
     for export in exports {
         let (typ, src, dest) = match export {
-            crate::models::version_entry::Export::Link { src, dest } => ("Link", src.clone(), dest.clone()),
+            crate::models::version_entry::Export::Link { src, dest, chmod } => match chmod {
+                Some(mode) => ("Link", src.clone(), format!("{} (chmod {})", dest, mode)),
+                None => ("Link", src.clone(), dest.clone()),
+            },
             crate::models::version_entry::Export::Env { key, val } => ("Env", key.clone(), val.clone()),
             crate::models::version_entry::Export::Path(p) => ("Path", p.clone(), "-".to_string()),
+            crate::models::version_entry::Export::Service { name, command, .. } => ("Service", name.clone(), command.clone()),
         };
         table.add_row(vec![typ, &src, &dest]);
     }
-    println!("{}", table);
+    Theme::current().print(&table);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_report() {
+        assert_eq!(effective_format(InfoFormat::Table, true), InfoFormat::Json);
+        assert_eq!(effective_format(InfoFormat::Yaml, true), InfoFormat::Json);
+        assert_eq!(effective_format(InfoFormat::Yaml, false), InfoFormat::Yaml);
+
+        let entry = VersionEntry { pkgname: "tool".to_string(), ..Default::default() };
+        let report = InfoReport { package: "tool", repo: "main", entry: &entry, installed: BTreeMap::new() };
+        let yaml = render_report(&report, InfoFormat::Yaml).unwrap();
+        assert!(yaml.starts_with("package: tool\nrepo: main\n"), "{}", yaml);
+        let json: serde_json::Value = serde_json::from_str(&render_report(&report, InfoFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["entry"]["pkgname"], "tool");
+    }
 }
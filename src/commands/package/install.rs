@@ -0,0 +1,194 @@
+use crate::commands::package::build::{build_packages, BuildRequest};
+use crate::models::config::Config;
+use crate::utils::fs::lock_file;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// The packages `pi package install` put into the global prefix, kept there
+/// as `installed.json` for `pi package uninstall`.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct GlobalInstalls {
+    /// By package name.
+    pub packages: BTreeMap<String, GlobalInstall>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct GlobalInstall {
+    /// The selector it was installed with.
+    pub query: String,
+    pub version: String,
+    /// Links into it and its dependencies, relative to the prefix.
+    pub links: Vec<PathBuf>,
+}
+
+impl GlobalInstalls {
+    pub const FILENAME: &'static str = "installed.json";
+    const LOCK_FILENAME: &'static str = "installed.json.lock";
+
+    /// Locks the installs of `prefix` until the returned file is dropped;
+    /// hold it from `load` to `save` so concurrent installs don't lose one.
+    pub fn lock(prefix: &Path) -> Result<fs::File> {
+        lock_file(&prefix.join(Self::LOCK_FILENAME), false, "[install] waiting for another install into the prefix")
+    }
+
+    pub fn load(prefix: &Path) -> Result<Self> {
+        let path = prefix.join(Self::FILENAME);
+        match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).with_context(|| format!("Failed to parse {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, prefix: &Path) -> Result<()> {
+        let path = prefix.join(Self::FILENAME);
+        fs::write(&path, serde_json::to_string_pretty(self)? + "\n")
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Removes the links of `pkgname` no other install has, and the
+    /// directories left empty; returns how many were removed.
+    pub fn remove_links(&self, prefix: &Path, pkgname: &str, keep: &[PathBuf]) -> Result<usize> {
+        let Some(install) = self.packages.get(pkgname) else { return Ok(0) };
+        let mut removed = 0;
+        for link in &install.links {
+            let shared = keep.contains(link) || self.packages.iter()
+                .any(|(name, other)| name != pkgname && other.links.contains(link));
+            let path = prefix.join(link);
+            if shared || !path.is_symlink() {
+                continue;
+            }
+            fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+            removed += 1;
+            let mut dir = path.parent();
+            while let Some(d) = dir.filter(|d| *d != prefix) {
+                if fs::remove_dir(d).is_err() {
+                    break;
+                }
+                dir = d.parent();
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// `pi package install`: builds a package outside any cave and links its
+/// exports into the global prefix.
+pub fn run(config: &Config, selector: &str) {
+    if let Err(e) = execute(config, selector) {
+        log::error!("install failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn execute(config: &Config, selector: &str) -> Result<()> {
+    let prefix = &config.global_dir;
+    fs::create_dir_all(prefix).with_context(|| format!("Failed to create {}", prefix.display()))?;
+
+    let output = build_packages(config, BuildRequest {
+        packages: &[selector.to_string()],
+        all_options: &HashMap::new(),
+        pilocal_dir: prefix,
+        sums: None,
+        host_build: &[],
+        bin_prefix: &HashMap::new(),
+        cave: None,
+        graph: None,
+    })?;
+    let (version, _) = output.graph.get(selector).context("the build resolved nothing")?;
+    let pkgname = version.pkgname.clone();
+
+    let roots: Vec<PathBuf> = output.packages.iter()
+        .filter_map(|p| p.root.canonicalize().ok())
+        .collect();
+    let _lock = GlobalInstalls::lock(prefix)?;
+    let mut installs = GlobalInstalls::load(prefix)?;
+    let links = links_into(prefix, &roots);
+    let stale = installs.remove_links(prefix, &pkgname, &links)?;
+    if stale > 0 {
+        log::debug!("[{}] removed {} link(s) of the previous install", pkgname, stale);
+    }
+    let mut keys: Vec<&String> = output.env.keys().collect();
+    keys.sort();
+    if !keys.is_empty() {
+        let keys: Vec<&str> = keys.into_iter().map(String::as_str).collect();
+        log::warn!("[{}] global installs don't set {}, use a cave for them", pkgname, keys.join(", "));
+    }
+
+    log::info!("[{}] installed {} into {} ({} link(s))", pkgname, version.version, prefix.display(), links.len());
+    installs.packages.insert(pkgname, GlobalInstall {
+        query: selector.to_string(),
+        version: version.version.to_string(),
+        links,
+    });
+    installs.save(prefix)?;
+
+    let bin = prefix.join("bin");
+    let on_path = std::env::var_os("PATH").is_some_and(|path| std::env::split_paths(&path).any(|p| p == bin));
+    if !on_path {
+        log::info!("add {} to PATH to use it", bin.display());
+    }
+    Ok(())
+}
+
+/// The symlinks under `prefix` pointing into one of `roots`, relative to it.
+fn links_into(prefix: &Path, roots: &[PathBuf]) -> Vec<PathBuf> {
+    let mut links: Vec<PathBuf> = WalkDir::new(prefix).into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path_is_symlink())
+        .filter(|e| e.path().canonicalize().is_ok_and(|target| roots.iter().any(|r| target.starts_with(r))))
+        .filter_map(|e| e.path().strip_prefix(prefix).ok().map(Path::to_path_buf))
+        .collect();
+    links.sort();
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_links_and_removal() {
+        let tmp = tempfile::tempdir().unwrap();
+        let prefix = tmp.path().join("global");
+        let (tool, lib) = (tmp.path().join("tool-1.0"), tmp.path().join("lib-2.0"));
+        for dir in [&tool, &lib, &prefix.join("bin"), &prefix.join("share/tool")] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(tool.join("tool"), "").unwrap();
+        fs::write(lib.join("lib.so"), "").unwrap();
+        std::os::unix::fs::symlink(tool.join("tool"), prefix.join("bin/tool")).unwrap();
+        std::os::unix::fs::symlink(&tool, prefix.join("share/tool/root")).unwrap();
+        std::os::unix::fs::symlink(lib.join("lib.so"), prefix.join("bin/lib.so")).unwrap();
+
+        let links = links_into(&prefix, &[tool.clone(), lib.clone()]);
+        assert_eq!(links, vec![PathBuf::from("bin/lib.so"), PathBuf::from("bin/tool"), PathBuf::from("share/tool/root")]);
+        assert_eq!(links_into(&prefix, std::slice::from_ref(&lib)), vec![PathBuf::from("bin/lib.so")]);
+
+        let mut installs = GlobalInstalls::default();
+        installs.packages.insert("tool".to_string(), GlobalInstall { query: "tool".to_string(), version: "1.0".to_string(), links });
+        installs.packages.insert("lib".to_string(), GlobalInstall { query: "lib".to_string(), version: "2.0".to_string(), links: vec![PathBuf::from("bin/lib.so")] });
+        installs.save(&prefix).unwrap();
+        let installs = GlobalInstalls::load(&prefix).unwrap();
+
+        // The link lib shares stays, as do the directories still holding something.
+        assert_eq!(installs.remove_links(&prefix, "tool", &[]).unwrap(), 2);
+        assert!(prefix.join("bin/lib.so").is_symlink());
+        assert!(!prefix.join("bin/tool").exists());
+        assert!(!prefix.join("share").exists());
+    }
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let tmp = tempfile::tempdir().unwrap();
+        let lock = GlobalInstalls::lock(tmp.path()).unwrap();
+        let other = fs::File::open(tmp.path().join(GlobalInstalls::LOCK_FILENAME)).unwrap();
+        assert!(matches!(other.try_lock(), Err(fs::TryLockError::WouldBlock)));
+        drop(lock);
+        other.try_lock().unwrap();
+    }
+}
@@ -1,12 +1,11 @@
+use crate::commands::package::resolve::in_stream;
 use crate::models::config::Config;
-use crate::models::package_entry::{PackageList, PackageEntry};
+use crate::models::package_entry::{PackageList, DIR_MANAGER};
 use crate::models::repository::{Repositories, Repository};
 use crate::models::selector::PackageSelector;
 use crate::models::version_entry::VersionList;
 use crate::utils::version::match_version_with_wildcard;
-use comfy_table::presets::NOTHING;
-use comfy_table::Table;
-use std::sync::Arc;
+use crate::utils::table::SortedTable;
 
 /// Context for listing packages.
 struct ListContext<'a> {
@@ -31,7 +30,7 @@ pub fn run(config: &Config, selector_str: Option<&str>, all: bool) {
         truncate,
     };
 
-    let mut table = create_list_table();
+    let mut table = SortedTable::new(&["Repo", "Package", "Version", "Stream", "Date", "Type"], "repo,package,-version");
 
     for repo in &repo_config.repositories {
         if should_skip_repo(repo, &ctx.selector) {
@@ -43,7 +42,7 @@ pub fn run(config: &Config, selector_str: Option<&str>, all: bool) {
         }
     }
 
-    println!("{table}");
+    table.print(config.sort.as_ref());
 }
 
 fn determine_listing_mode(all: bool, selector: &Option<PackageSelector>) -> (String, bool) {
@@ -55,25 +54,22 @@ fn determine_listing_mode(all: bool, selector: &Option<PackageSelector>) -> (Str
         (
             selector
                 .as_ref()
-                .and_then(|s| s.version.clone())
+                .map(|s| match s.target_version() {
+                    // Listing "latest" shows every version, as `--all`.
+                    "latest" => "all".to_string(),
+                    target => target.to_string(),
+                })
                 .unwrap_or_else(|| "stable".to_string()),
             false,
         )
     }
 }
 
-fn create_list_table() -> Table {
-    let mut table = Table::new();
-    table.load_preset(NOTHING);
-    table.set_header(vec!["Repo", "Package", "Version", "Stream", "Date", "Type"]);
-    table
-}
-
 fn should_skip_repo(repo: &Repository, selector: &Option<PackageSelector>) -> bool {
-    if let Some(s) = selector {
-        if let Some(r_name) = &s.recipe {
-            return repo.name != *r_name;
-        }
+    if let Some(s) = selector
+        && let Some(r_name) = &s.recipe
+    {
+        return repo.name != *r_name;
     }
     false
 }
@@ -82,7 +78,7 @@ fn process_repo_packages(
     ctx: &ListContext,
     repo: &Repository,
     pkg_list: &PackageList,
-    table: &mut Table,
+    table: &mut SortedTable,
 ) {
     if ctx.selector.is_none() {
         list_cached_packages(ctx, repo, pkg_list, table);
@@ -91,10 +87,10 @@ fn process_repo_packages(
     }
 }
 
-fn list_cached_packages(ctx: &ListContext, repo: &Repository, pkg_list: &PackageList, table: &mut Table) {
+fn list_cached_packages(ctx: &ListContext, repo: &Repository, pkg_list: &PackageList, table: &mut SortedTable) {
     for pkg in pkg_list.packages.values() {
         if let Ok(v_list) = VersionList::load(ctx.config, &repo.name, &pkg.name) {
-            add_versions_to_table(table, &repo.name, v_list, &ctx.target_version, ctx.truncate);
+            add_versions_to_table(table, &repo.name, v_list, &ctx.target_version, None, ctx.truncate);
         } else if !ctx.all {
             table.add_row(vec![
                 repo.name.clone(),
@@ -108,7 +104,7 @@ fn list_cached_packages(ctx: &ListContext, repo: &Repository, pkg_list: &Package
     }
 }
 
-fn list_filtered_packages(ctx: &ListContext, repo: &Repository, pkg_list: &PackageList, table: &mut Table) {
+fn list_filtered_packages(ctx: &ListContext, repo: &Repository, pkg_list: &PackageList, table: &mut SortedTable) {
     let s = ctx.selector.as_ref().unwrap();
 
     // Filter packages if a package name is provided
@@ -126,7 +122,7 @@ fn list_filtered_packages(ctx: &ListContext, repo: &Repository, pkg_list: &Packa
                 manager_entry: None,
                 force: false,
             }) {
-                add_versions_to_table(table, &repo.name, (*v_list).clone(), &ctx.target_version, ctx.truncate);
+                add_versions_to_table(table, &repo.name, (*v_list).clone(), &ctx.target_version, s.stream.as_deref(), ctx.truncate);
             }
         }
     }
@@ -142,10 +138,21 @@ fn handle_manager_listing(
     repo: &Repository,
     pkg_list: &PackageList,
     prefix: &str,
-    table: &mut Table,
+    table: &mut SortedTable,
 ) {
-    if let Some(mgr) = pkg_list.managers.get(prefix) {
-        let s = ctx.selector.as_ref().unwrap();
+    let s = ctx.selector.as_ref().unwrap();
+    if prefix == DIR_MANAGER && s.package.is_empty() {
+        for mgr in pkg_list.managers.values().filter(|m| m.dir.is_some()) {
+            table.add_row(vec![
+                repo.name.clone(),
+                mgr.name.clone(),
+                "-".to_string(),
+                "-".to_string(),
+                "-".to_string(),
+                "dir".to_string(),
+            ]);
+        }
+    } else if let Some(mgr) = pkg_list.manager(prefix, &s.package) {
         if s.package.is_empty() {
             table.add_row(vec![
                 repo.name.clone(),
@@ -165,20 +172,23 @@ fn handle_manager_listing(
                 manager_entry: Some((mgr, &s.package)),
                 force: false,
             }) {
-                add_versions_to_table(table, &repo.name, (*v_list).clone(), &ctx.target_version, ctx.truncate);
+                add_versions_to_table(table, &repo.name, (*v_list).clone(), &ctx.target_version, s.stream.as_deref(), ctx.truncate);
             }
         }
     }
 }
 
 fn add_versions_to_table(
-    table: &mut Table,
+    table: &mut SortedTable,
     repo_name: &str,
     v_list: VersionList,
     target_version: &str,
+    stream: Option<&str>,
     truncate: bool,
 ) {
-    let mut filtered_versions: Vec<_> = v_list.versions.into_iter().filter(|v| match_version(v, target_version)).collect();
+    let mut filtered_versions: Vec<_> = v_list.versions.into_iter()
+        .filter(|v| in_stream(v, stream) && match_version(v, target_version))
+        .collect();
 
     filtered_versions.sort_by(|a, b| {
         b.version.cmp(&a.version).then_with(|| b.release_date.cmp(&a.release_date))
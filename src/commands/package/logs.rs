@@ -0,0 +1,54 @@
+use crate::commands::package::resolve;
+use crate::models::config::Config;
+use crate::models::repository::Repositories;
+use crate::models::selector::PackageSelector;
+use crate::services::step_logs;
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::PathBuf;
+
+pub fn run(config: &Config, selector_str: &str, step: Option<usize>) {
+    if let Err(e) = execute(config, selector_str, step) {
+        log::error!("logs failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Prints the logs of the Run steps of the resolved version's last builds,
+/// one per build key (options), or of only `step`.
+fn execute(config: &Config, selector_str: &str, step: Option<usize>) -> Result<()> {
+    let selector = PackageSelector::parse(selector_str).context("invalid selector")?;
+    let (_, version, _) = resolve::resolve_query(config, Repositories::get_all(config), &selector)
+        .with_context(|| format!("package not found: {}", selector_str))?;
+    let version_str = version.version.to_string();
+    let logs: Vec<(usize, PathBuf)> = step_logs::build_keys(&config.cache_dir, &version.pkgname, Some(&version_str))
+        .iter()
+        .flat_map(|key| step_logs::list(config, &version.pkgname, key))
+        .collect();
+    if logs.is_empty() {
+        anyhow::bail!("no step logs for {}={}, only Run steps of a build are logged", version.pkgname, version_str);
+    }
+    let logs: Vec<_> = match step {
+        Some(index) => {
+            let available: Vec<String> = logs.iter().map(|(i, _)| i.to_string()).collect();
+            let logs: Vec<_> = logs.into_iter().filter(|(i, _)| *i == index).collect();
+            if logs.is_empty() {
+                anyhow::bail!("step {} of {}={} has no log, logged steps: {}", index, version.pkgname, version_str, available.join(", "));
+            }
+            logs
+        }
+        None => logs,
+    };
+    let headers = logs.len() > 1;
+    for (index, path) in logs {
+        let content = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        if headers {
+            println!("== step {} ({})", index, path.display());
+        }
+        print!("{}", content);
+        if headers && !content.ends_with('\n') {
+            println!();
+        }
+    }
+    Ok(())
+}
@@ -1,5 +1,13 @@
+pub mod artifacts;
+pub mod changelog;
+pub mod graph;
 pub mod info;
+pub mod install;
 pub mod list;
+pub mod logs;
 pub mod resolve;
+pub mod search;
 pub mod sync;
+pub mod uninstall;
 pub mod build;
+pub mod why;
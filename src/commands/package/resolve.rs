@@ -1,55 +1,92 @@
+use crate::commands::daemon;
 use crate::models::config::Config;
-use crate::models::package_entry::PackageList;
+use crate::models::package_entry::{PackageList, VENV_MANAGER};
 use crate::models::repository::{Repositories, Repository};
 use crate::models::selector::PackageSelector;
-use crate::models::version_entry::{VersionEntry, VersionList};
+use crate::models::types::Platform;
+use crate::models::version_entry::{ReleaseType, VersionEntry, VersionList};
+use crate::services::events::{self, Event};
+use crate::services::venv::{VenvSpec, BUILTIN_REPO};
 use crate::utils::version::match_version_with_wildcard;
-use comfy_table::presets::NOTHING;
-use comfy_table::Table;
+use crate::utils::size::format_size;
+use crate::utils::table::SortedTable;
+use crate::utils::crypto::hash_to_string;
 use rayon::prelude::*;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+/// How long a selector that a sync couldn't resolve is not synced again.
+const UNRESOLVED_TTL: Duration = Duration::from_secs(600);
 
 /// Runs the package resolution for multiple queries in parallel.
 pub fn run(config: &Config, queries: Vec<String>) {
-    let repo_config = Repositories::get_all(config);
+    let results = resolve_rows(config, &queries);
+    print_resolution_table(config, results);
+}
 
-    let results: Vec<(String, String, String)> = queries
+/// Resolves `queries` through `pi daemon` when one is running, else here.
+pub fn resolve_rows(config: &Config, queries: &[String]) -> Vec<ResolutionRow> {
+    if let Some(rows) = daemon::resolve(config, queries) {
+        return rows;
+    }
+    let repo_config = Repositories::get_all(config);
+    queries
         .par_iter()
-        .map(|query| resolve_single_query(config, &repo_config, query))
-        .collect();
-
-    print_resolution_table(results);
+        .map(|query| resolve_row(config, repo_config, query))
+        .collect()
 }
 
-fn resolve_single_query(config: &Config, repo_config: &Repositories, query: &str) -> (String, String, String) {
+/// Query, resolved full name, release date and download size of a
+/// resolution, as printed by `package resolve` and `cave resolve`.
+pub type ResolutionRow = (String, String, String, String);
+
+/// Resolves `query` into a table row; failures become rows too.
+pub fn resolve_row(config: &Config, repo_config: &Repositories, query: &str) -> ResolutionRow {
+    let failed = |what: &str| (query.to_string(), what.to_string(), "-".to_string(), "-".to_string());
     let selector = match PackageSelector::parse(query) {
         Some(s) => s,
-        None => return (query.to_string(), "Invalid selector".to_string(), "-".to_string()),
+        None => return failed("Invalid selector"),
     };
 
     match resolve_query(config, repo_config, &selector) {
         Some((full_qualified_name, version, _repo_name)) => {
-            (query.to_string(), full_qualified_name, version.release_date)
+            let size = version.download_size().filter(|s| *s > 0).map(format_size).unwrap_or_else(|| "-".to_string());
+            (query.to_string(), full_qualified_name, version.release_date, size)
         }
-        None => (query.to_string(), "Not found".to_string(), "-".to_string()),
+        None => failed("Not found"),
     }
 }
 
-fn print_resolution_table(results: Vec<(String, String, String)>) {
-    let mut table = Table::new();
-    table.load_preset(NOTHING);
-    table.set_header(vec!["Query", "Resolved Full Name", "Release Date"]);
-    for (query, full_name, date) in results {
-        table.add_row(vec![query, full_name, date]);
+pub fn print_resolution_table(config: &Config, results: Vec<ResolutionRow>) {
+    let mut table = SortedTable::new(&["Query", "Resolved Full Name", "Release Date", "Download"], "query");
+    for (query, full_name, date, size) in results {
+        table.add_row(vec![query, full_name, date, size]);
     }
-    println!("{table}");
+    table.print(config.sort.as_ref());
 }
 
 /// Resolves a single query against available repositories.
 /// Example selector: "pi:rust/cargo=1.70.0"
+#[tracing::instrument(skip_all, fields(package = %selector.package))]
 pub fn resolve_query(
     config: &Config,
     repo_config: &Repositories,
     selector: &PackageSelector,
+) -> Option<(String, VersionEntry, String)> {
+    events::publish(Event::ResolveStarted { query: selector.package.clone() });
+    let res = resolve_query_cached_or_synced(config, repo_config, selector);
+    events::publish(Event::ResolveFinished {
+        query: selector.package.clone(),
+        resolved: res.as_ref().map(|(full_name, _, _)| full_name.clone()),
+    });
+    res
+}
+
+fn resolve_query_cached_or_synced(
+    config: &Config,
+    repo_config: &Repositories,
+    selector: &PackageSelector,
 ) -> Option<(String, VersionEntry, String)> {
     // Try cached first
     if let Some(res) = resolve_query_internal(config, repo_config, selector, false) {
@@ -58,21 +95,77 @@ pub fn resolve_query(
 
     // Attempt sync if allowed
     if !config.force && !config.no_sync {
+        let key = selector.to_string();
+        let generation = config.state.repo_generation.get_or_init(|| repo_generation(repo_config));
+        if recently_unresolved(config, &key, generation) {
+            log::debug!("[{}] not found by a recent sync, skipping it (--force retries)", key);
+            return None;
+        }
         log::debug!("[{}] not found in cache, attempting sync", selector.package);
-        return resolve_query_internal(config, repo_config, selector, true);
+        let res = resolve_query_internal(config, repo_config, selector, true);
+        if res.is_none() {
+            remember_unresolved(config, &key, generation);
+        }
+        return res;
     }
 
     None
 }
 
+/// Whether a sync failed to resolve `key` within `UNRESOLVED_TTL`, against
+/// the same repositories. `--force` never gets here.
+fn recently_unresolved(config: &Config, key: &str, generation: &str) -> bool {
+    if config.state.unresolved.contains(key) {
+        return true;
+    }
+    config.db().is_some_and(|db| db.is_unresolved(key, generation, now_secs()).unwrap_or(false))
+}
+
+fn remember_unresolved(config: &Config, key: &str, generation: &str) {
+    config.state.unresolved.insert(key.to_string());
+    if let Some(db) = config.db()
+        && let Err(e) = db.record_unresolved(key, generation, now_secs() + UNRESOLVED_TTL.as_secs())
+    {
+        log::debug!("[{}] failed to record the failed resolution: {:#}", key, e);
+    }
+}
+
+/// Changes when repositories are added, removed or their recipes edited,
+/// which invalidates remembered failures. Walks every file of every
+/// repository; resolution keeps it in `State.repo_generation`.
+pub fn repo_generation(repo_config: &Repositories) -> String {
+    let repos: Vec<(&str, &str, u64)> = repo_config.repositories.iter()
+        .map(|r| (r.name.as_str(), r.path.as_str(), newest_mtime(Path::new(&r.path))))
+        .collect();
+    hash_to_string(&repos)
+}
+
+fn newest_mtime(dir: &Path) -> u64 {
+    WalkDir::new(dir).into_iter()
+        .filter_entry(|e| e.file_name() != ".git")
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.metadata().ok()?.modified().ok())
+        .filter_map(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .max()
+        .unwrap_or(0)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
 fn resolve_query_internal(
     config: &Config,
     repo_config: &Repositories,
     selector: &PackageSelector,
     force: bool,
 ) -> Option<(String, VersionEntry, String)> {
-    let target_version = selector.version.as_deref().unwrap_or("stable");
+    let target_version = selector.target_version();
     log::debug!("Resolving: {} (version: {})", selector.package, target_version);
+    if let Some(res) = resolve_builtin(config, selector, target_version, force) {
+        return Some(res);
+    }
 
     for repo in &repo_config.repositories {
         if should_skip_repo(repo, selector) { 
@@ -91,8 +184,29 @@ fn resolve_query_internal(
     None
 }
 
+/// `venv:` packages are generated by pi, unless the selector names another
+/// repository; no repository is synced for them.
+fn resolve_builtin(config: &Config, selector: &PackageSelector, target_version: &str, force: bool) -> Option<(String, VersionEntry, String)> {
+    if selector.prefix.as_deref() != Some(VENV_MANAGER) || selector.recipe.as_ref().is_some_and(|r| r != BUILTIN_REPO) {
+        return None;
+    }
+    let spec = match VenvSpec::parse(&selector.package) {
+        Ok(spec) => spec,
+        Err(e) => {
+            // Once: a failed lookup is retried with a sync.
+            if !force {
+                log::warn!("[{}] {:#}", selector, e);
+            }
+            return None;
+        }
+    };
+    let entry = spec.version_entry(&selector.package, &Default::default());
+    let version = find_best_version(VersionList { versions: vec![entry] }, target_version, None, &config.target)?;
+    Some((format!("{}/{}={}", BUILTIN_REPO, version.pkgname, version.version), version, BUILTIN_REPO.to_string()))
+}
+
 fn should_skip_repo(repo: &Repository, selector: &PackageSelector) -> bool {
-    selector.recipe.as_ref().map_or(false, |r| repo.name != *r)
+    selector.recipe.as_ref().is_some_and(|r| repo.name != *r)
 }
 
 struct ResolveOptions<'a> {
@@ -102,6 +216,7 @@ struct ResolveOptions<'a> {
     pkg_entry: Option<&'a crate::models::package_entry::PackageEntry>,
     mgr_entry: Option<(&'a crate::models::package_entry::ManagerEntry, &'a str)>,
     target_version: &'a str,
+    stream: Option<&'a str>,
     force: bool,
 }
 
@@ -114,31 +229,31 @@ fn try_resolve_in_repo(
     force: bool,
 ) -> Option<(String, VersionEntry, String)> {
     // 1. Direct package resolution
-    if selector.prefix.is_none() {
-        if let Some(pkg) = pkg_list.packages.get(&selector.package) {
-            let res = resolve_version(ResolveOptions {
-                config, repo, package_name: &pkg.name, pkg_entry: Some(pkg),
-                mgr_entry: None, target_version, force,
-            });
-            if let Some(v) = res {
-                let full_qualified = format!("{}/{}={}", repo.name, pkg.name, v.version);
-                return Some((full_qualified, v, repo.name.clone()));
-            }
+    if selector.prefix.is_none()
+        && let Some(pkg) = pkg_list.packages.get(&selector.package)
+    {
+        let res = resolve_version(ResolveOptions {
+            config, repo, package_name: &pkg.name, pkg_entry: Some(pkg),
+            mgr_entry: None, target_version, stream: selector.stream.as_deref(), force,
+        });
+        if let Some(v) = res {
+            let full_qualified = format!("{}/{}={}", repo.name, pkg.name, v.version);
+            return Some((full_qualified, v, repo.name.clone()));
         }
     }
 
     // 2. Manager-based resolution
-    if let Some(ref prefix) = selector.prefix {
-        if let Some(mgr) = pkg_list.managers.get(prefix) {
-            let full_name = format!("{}:{}", prefix, selector.package);
-            let res = resolve_version(ResolveOptions {
-                config, repo, package_name: &full_name, pkg_entry: None,
-                mgr_entry: Some((mgr, &selector.package)), target_version, force,
-            });
-            if let Some(v) = res {
-                let full_qualified = format!("{}/{}={}", repo.name, full_name, v.version);
-                return Some((full_qualified, v, repo.name.clone()));
-            }
+    if let Some(ref prefix) = selector.prefix
+        && let Some(mgr) = pkg_list.manager(prefix, &selector.package)
+    {
+        let full_name = format!("{}:{}", prefix, selector.package);
+        let res = resolve_version(ResolveOptions {
+            config, repo, package_name: &full_name, pkg_entry: None,
+            mgr_entry: Some((mgr, &selector.package)), target_version, stream: selector.stream.as_deref(), force,
+        });
+        if let Some(v) = res {
+            let full_qualified = format!("{}/{}={}", repo.name, full_name, v.version);
+            return Some((full_qualified, v, repo.name.clone()));
         }
     }
     None
@@ -153,11 +268,20 @@ fn resolve_version(opts: ResolveOptions) -> Option<VersionEntry> {
         manager_entry: opts.mgr_entry,
         force: opts.force,
     })?;
-    find_best_version((*v_list).clone(), opts.target_version)
+    find_best_version((*v_list).clone(), opts.target_version, opts.stream, &opts.config.target)
 }
 
-pub fn find_best_version(v_list: VersionList, target_version: &str) -> Option<VersionEntry> {
-    let mut filtered_versions: Vec<_> = v_list.versions.into_iter().filter(|v| match_target_version(v, target_version)).collect();
+pub fn find_best_version(v_list: VersionList, target_version: &str, stream: Option<&str>, platform: &Platform) -> Option<VersionEntry> {
+    let (mut filtered_versions, other_platforms): (Vec<_>, Vec<_>) = v_list.versions
+        .into_iter()
+        .filter(|v| in_stream(v, stream) && match_target_version(v, target_version))
+        .partition(|v| v.supports(platform));
+    if filtered_versions.is_empty() && !other_platforms.is_empty() {
+        log::debug!(
+            "{} matching versions of {} skipped, none available for {}",
+            other_platforms.len(), other_platforms[0].pkgname, platform
+        );
+    }
 
     filtered_versions.sort_by(|a, b| {
         b.version.cmp(&a.version).then_with(|| b.release_date.cmp(&a.release_date))
@@ -166,16 +290,92 @@ pub fn find_best_version(v_list: VersionList, target_version: &str) -> Option<Ve
     filtered_versions.into_iter().next()
 }
 
+/// Whether `v` belongs to the release train a selector names (`node@iron`);
+/// any version does when it names none.
+pub fn in_stream(v: &VersionEntry, stream: Option<&str>) -> bool {
+    stream.is_none_or(|s| s.eq_ignore_ascii_case(&v.stream))
+}
+
 fn match_target_version(v: &VersionEntry, target: &str) -> bool {
-    match target {
-        "latest" => true,
-        "stable" | "lts" | "testing" | "unstable" => v.release_type.to_string().to_lowercase() == target,
-        _ => {
-            if target.contains('*') {
-                match_version_with_wildcard(&v.version.to_string(), target)
-            } else {
-                v.version.to_string() == target
-            }
+    TargetVersion::parse(target).matches(&v.version.to_string(), &v.release_type)
+}
+
+/// The version part of a selector (`stable` when it has none).
+#[derive(Debug, PartialEq)]
+pub enum TargetVersion<'a> {
+    /// `latest`: any version.
+    Latest,
+    /// `stable`, `lts`, `testing` or `unstable`: versions of that release type.
+    Stream(&'a str),
+    /// A pattern with `*`, see `match_version_with_wildcard`.
+    Wildcard(&'a str),
+    Exact(&'a str),
+}
+
+impl<'a> TargetVersion<'a> {
+    pub fn parse(target: &'a str) -> Self {
+        match target {
+            "latest" => Self::Latest,
+            "stable" | "lts" | "testing" | "unstable" => Self::Stream(target),
+            _ if target.contains('*') => Self::Wildcard(target),
+            _ => Self::Exact(target),
+        }
+    }
+
+    pub fn matches(&self, version: &str, release_type: &ReleaseType) -> bool {
+        match self {
+            Self::Latest => true,
+            Self::Stream(stream) => release_type.to_string() == *stream,
+            Self::Wildcard(pattern) => match_version_with_wildcard(version, pattern),
+            Self::Exact(exact) => version == *exact,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::inspect::inspect_version;
+
+    #[test]
+    fn test_find_best_version_skips_other_platforms() {
+        let entry = |v: &str, platforms: &[&str]| VersionEntry {
+            pkgname: "tool".to_string(),
+            version: inspect_version(v).version,
+            platforms: platforms.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        };
+        let list = VersionList {
+            versions: vec![
+                entry("2.0.0", &["linux/x86_64"]),
+                entry("1.0.0", &[]),
+            ],
+        };
+        let x64: Platform = "linux/x86_64".parse().unwrap();
+        let arm: Platform = "linux/aarch64".parse().unwrap();
+        assert_eq!(find_best_version(list.clone(), "latest", None, &x64).unwrap().version.raw, "2.0.0");
+        assert_eq!(find_best_version(list.clone(), "latest", None, &arm).unwrap().version.raw, "1.0.0");
+        assert!(find_best_version(list, "2.0.0", None, &arm).is_none());
+    }
+
+    #[test]
+    fn test_find_best_version_in_stream() {
+        let entry = |v: &str, stream: &str| VersionEntry {
+            pkgname: "node".to_string(),
+            version: inspect_version(v).version,
+            release_type: ReleaseType::LTS,
+            stream: stream.to_string(),
+            ..Default::default()
+        };
+        let list = VersionList { versions: vec![entry("22.1.0", "Jod"), entry("20.9.0", "Iron"), entry("20.8.0", "Iron")] };
+        let host = Platform::default();
+        let selector = PackageSelector::parse("node@iron").unwrap();
+        assert_eq!(selector.target_version(), "stable");
+        assert!(find_best_version(list.clone(), "stable", selector.stream.as_deref(), &host).is_none());
+        let best = |target: &str| find_best_version(list.clone(), target, selector.stream.as_deref(), &host).map(|v| v.version.raw);
+        assert_eq!(best("latest").as_deref(), Some("20.9.0"));
+        assert_eq!(best("20.8.*").as_deref(), Some("20.8.0"));
+        assert_eq!(best("22.*"), None);
+        assert_eq!(find_best_version(list, "lts", None, &host).unwrap().version.raw, "22.1.0");
+    }
+}
@@ -0,0 +1,109 @@
+use crate::models::config::Config;
+use crate::models::package_entry::PackageList;
+use crate::models::repository::Repositories;
+use crate::models::version_entry::VersionList;
+use crate::utils::table::SortedTable;
+use std::collections::BTreeMap;
+
+/// A package or manager matching the query.
+struct Hit {
+    score: u32,
+    repo: String,
+    name: String,
+    kind: &'static str,
+    recipe: String,
+}
+
+/// Searches the package and manager names of every repository, and the
+/// managed packages (`npm:typescript`) with a cached version list.
+pub fn run(config: &Config, query: &str) {
+    let hits = search(config, query);
+    if hits.is_empty() {
+        log::error!("no package matches '{}'", query);
+        std::process::exit(1);
+    }
+    let mut table = SortedTable::new(&["Score", "Repo", "Package", "Kind", "Latest", "Recipe"], "-score,package,repo");
+    for hit in hits {
+        let latest = VersionList::load(config, &hit.repo, &hit.name).ok()
+            .and_then(|list| list.latest().map(|v| v.version.to_string()))
+            .unwrap_or_else(|| "-".to_string());
+        table.add_row(vec![hit.score.to_string(), hit.repo, hit.name, hit.kind.to_string(), latest, hit.recipe]);
+    }
+    table.print(config.sort.as_ref());
+}
+
+fn search(config: &Config, query: &str) -> Vec<Hit> {
+    let mut hits = Vec::new();
+    for repo in &Repositories::get_all(config).repositories {
+        let Some(list) = PackageList::get_for_repo(config, repo, false) else { continue };
+        // Name -> (kind, recipe file), so a cached list never repeats a package.
+        let mut names: BTreeMap<String, (&'static str, String)> = BTreeMap::new();
+        for pkg in list.packages.values() {
+            names.insert(pkg.name.clone(), ("package", recipe(&pkg.filename, &pkg.function_name)));
+        }
+        for (key, mgr) in &list.managers {
+            let name = if mgr.dir.is_some() { key.clone() } else { format!("{}:*", key) };
+            names.insert(name, ("manager", recipe(&mgr.filename, &mgr.function_name)));
+        }
+        for name in VersionList::cached_names(config, &repo.name) {
+            let Some((prefix, package)) = name.split_once(':') else { continue };
+            if let Some(mgr) = list.manager(prefix, package) {
+                names.entry(name.clone()).or_insert(("managed", recipe(&mgr.filename, &mgr.function_name)));
+            }
+        }
+        hits.extend(names.into_iter().filter_map(|(name, (kind, recipe))| {
+            Some(Hit { score: score(query, &name)?, repo: repo.name.clone(), name, kind, recipe })
+        }));
+    }
+    hits
+}
+
+fn recipe(filename: &str, function_name: &str) -> String {
+    format!("{}:{}", filename, function_name)
+}
+
+/// How well `name` matches `query`, case-insensitively: exact, then prefix,
+/// then substring (earlier is better), then the query's characters in order
+/// (fewer gaps is better). None when it doesn't match at all.
+fn score(query: &str, name: &str) -> Option<u32> {
+    let (query, name) = (query.to_lowercase(), name.to_lowercase());
+    if query.is_empty() {
+        return None;
+    }
+    if name == query {
+        return Some(100);
+    }
+    if name.starts_with(&query) {
+        return Some(90);
+    }
+    if let Some(at) = name.find(&query) {
+        return Some(80u32.saturating_sub(at as u32).max(50));
+    }
+    let mut rest = name.chars().peekable();
+    let mut gaps = 0u32;
+    for c in query.chars() {
+        loop {
+            match rest.next() {
+                Some(n) if n == c => break,
+                Some(_) => gaps += 1,
+                None => return None,
+            }
+        }
+    }
+    Some(40u32.saturating_sub(gaps).max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score() {
+        assert_eq!(score("go", "go"), Some(100));
+        assert_eq!(score("Go", "golang"), Some(90));
+        assert_eq!(score("type", "npm:typescript"), Some(76));
+        assert!(score("tsc", "typescript") > score("tsc", "the-super-c"));
+        assert_eq!(score("rust", "ruby"), None);
+        assert_eq!(score("", "go"), None);
+    }
+}
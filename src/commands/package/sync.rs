@@ -20,24 +20,22 @@ pub fn sync_all(config: &Config, selector: Option<PackageSelector>) {
     repo_config.repositories.par_iter().for_each(|repo| {
         log::debug!("processing repository: {}", repo.name);
         // If recipe is specified, it must match repo name exactly
-        if let Some(ref s) = selector {
-            if let Some(ref r_name) = s.recipe {
-                if repo.name != *r_name {
-                    return;
-                }
-            }
+        if let Some(ref s) = selector
+            && let Some(ref r_name) = s.recipe
+            && repo.name != *r_name
+        {
+            return;
         }
 
         if let Some(pkg_list) = PackageList::get_for_repo(config, repo, false) {
             let packages: Vec<_> = pkg_list.packages.values().collect();
             packages.par_iter().for_each(|pkg| {
                 // Match package name exactly
-                if let Some(ref s) = selector {
-                    if !s.package.is_empty() && s.package != "*" {
-                        if pkg.name != s.package {
-                            return;
-                        }
-                    }
+                if let Some(ref s) = selector
+                    && !s.package.is_empty() && s.package != "*"
+                    && pkg.name != s.package
+                {
+                    return;
                 }
 
                 if let Err(e) = crate::services::sync::sync_package(config, repo, pkg) {
@@ -45,22 +43,19 @@ pub fn sync_all(config: &Config, selector: Option<PackageSelector>) {
                 }
             });
 
-            if let Some(ref s) = selector {
-                if let Some(ref prefix) = s.prefix {
-                    if let Some(mgr) = pkg_list.managers.get(prefix) {
-                        if !s.package.is_empty() && s.package != "*" {
-                            if let Err(e) = crate::services::sync::sync_manager_package(
-                                config,
-                                repo,
-                                mgr,
-                                prefix,
-                                &s.package,
-                            ) {
-                                log::error!("[{}/{}:{}] sync failed: {:#}", repo.name, prefix, s.package, e);
-                            }
-                        }
-                    }
-                }
+            if let Some(ref s) = selector
+                && let Some(ref prefix) = s.prefix
+                && let Some(mgr) = pkg_list.manager(prefix, &s.package)
+                && !s.package.is_empty() && s.package != "*"
+                && let Err(e) = crate::services::sync::sync_manager_package(
+                    config,
+                    repo,
+                    mgr,
+                    prefix,
+                    &s.package,
+                )
+            {
+                log::error!("[{}/{}:{}] sync failed: {:#}", repo.name, prefix, s.package, e);
             }
         }
     });
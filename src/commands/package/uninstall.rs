@@ -0,0 +1,28 @@
+use crate::commands::package::install::GlobalInstalls;
+use crate::models::config::Config;
+use anyhow::{Context, Result};
+
+/// `pi package uninstall`: removes the links `pi package install` made for
+/// a package, keeping those other installs still use. The built package
+/// stays in the cache until `pi disk gc` collects it.
+pub fn run(config: &Config, name: &str) {
+    if let Err(e) = execute(config, name) {
+        log::error!("uninstall failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn execute(config: &Config, name: &str) -> Result<()> {
+    let prefix = &config.global_dir;
+    let _lock = GlobalInstalls::lock(prefix)?;
+    let mut installs = GlobalInstalls::load(prefix)?;
+    let pkgname = installs.packages.iter()
+        .find(|(pkgname, install)| *pkgname == name || install.query == name)
+        .map(|(pkgname, _)| pkgname.clone())
+        .with_context(|| format!("{} is not installed", name))?;
+    let removed = installs.remove_links(prefix, &pkgname, &[])?;
+    let install = installs.packages.remove(&pkgname).expect("found above");
+    installs.save(prefix)?;
+    log::info!("[{}] uninstalled {} ({} link(s) removed)", pkgname, install.version, removed);
+    Ok(())
+}
@@ -0,0 +1,155 @@
+use crate::commands::package::build::{self, DependencyGraph};
+use crate::commands::package::resolve::TargetVersion;
+use crate::models::cave::Cave;
+use crate::models::config::Config;
+use crate::models::selector::PackageSelector;
+use crate::models::version_entry::VersionEntry;
+use crate::utils::version::match_version_with_wildcard;
+use anyhow::{Context, Result};
+use std::env;
+
+pub fn run(config: &Config, selector_str: &str, variant: Option<String>) {
+    if let Err(e) = execute_why(config, selector_str, variant.as_deref()) {
+        log::error!("why failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn execute_why(config: &Config, selector_str: &str, variant: Option<&str>) -> Result<()> {
+    let selector = PackageSelector::parse(selector_str).context("invalid selector")?;
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let (_path, cave) = Cave::find(config, &current_dir).context("no cave found")?;
+    let settings = cave.get_effective_settings(variant).context("failed to get cave settings")?;
+
+    let graph = build::resolve_graph(config, &settings.packages, &settings.options)?;
+    let chains = find_chains(&graph, &settings.packages, &selector);
+    if chains.is_empty() {
+        anyhow::bail!("{} is not part of cave {}", selector_str, cave.name);
+    }
+    for chain in chains {
+        println!("{}", render_chain(&graph, &chain));
+    }
+    Ok(())
+}
+
+/// All dependency paths from the cave-declared `roots` to packages matching `selector`.
+/// A path stops at the first match; a declared package that matches is a path of one.
+fn find_chains(graph: &DependencyGraph, roots: &[String], selector: &PackageSelector) -> Vec<Vec<String>> {
+    let mut chains = Vec::new();
+    for root in roots {
+        let mut path = vec![root.clone()];
+        walk(graph, selector, &mut path, &mut chains);
+    }
+    chains
+}
+
+fn walk(graph: &DependencyGraph, selector: &PackageSelector, path: &mut Vec<String>, chains: &mut Vec<Vec<String>>) {
+    let query = path.last().unwrap();
+    let Some((version, repo)) = graph.get(query) else { return };
+    if matches(selector, version, repo) {
+        chains.push(path.clone());
+        return;
+    }
+    for dep in &version.build_dependencies {
+        if path.contains(&dep.name) {
+            continue;
+        }
+        path.push(dep.name.clone());
+        walk(graph, selector, path, chains);
+        path.pop();
+    }
+}
+
+/// Whether `version` is what `selector` names. A plain version also names
+/// the versions it is a prefix of, component-wise (`1.2` is `1.2.3`, not `1.20.0`).
+fn matches(selector: &PackageSelector, version: &VersionEntry, repo: &str) -> bool {
+    let name = match &selector.prefix {
+        Some(prefix) => format!("{}:{}", prefix, selector.package),
+        None => selector.package.clone(),
+    };
+    let raw = &version.version.raw;
+    version.pkgname == name
+        && selector.recipe.as_deref().is_none_or(|r| r == repo)
+        && selector.version.as_deref().is_none_or(|target| match TargetVersion::parse(target) {
+            TargetVersion::Exact(v) => match_version_with_wildcard(raw, &format!("{}.*", v)),
+            target => target.matches(raw, &version.release_type),
+        })
+}
+
+/// Renders a chain as `a=1.0 -> b=2.0 (optional) -> c=3.0`.
+fn render_chain(graph: &DependencyGraph, chain: &[String]) -> String {
+    let mut parts = Vec::with_capacity(chain.len());
+    for (i, query) in chain.iter().enumerate() {
+        let mut part = match graph.get(query) {
+            Some((version, _)) => format!("{}={}", version.pkgname, version.version),
+            None => query.clone(),
+        };
+        let optional = i > 0
+            && graph.get(&chain[i - 1]).is_some_and(|(parent, _)| {
+                parent.build_dependencies.iter().any(|d| d.name == *query && d.optional)
+            });
+        if optional {
+            part.push_str(" (optional)");
+        }
+        parts.push(part);
+    }
+    if chain.len() == 1 {
+        parts.push("declared in cave".to_string());
+    }
+    parts.join(" -> ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::version_entry::Dependency;
+    use crate::utils::inspect::inspect_version;
+
+    fn entry(name: &str, version: &str, deps: &[(&str, bool)]) -> (String, (VersionEntry, String)) {
+        let version = VersionEntry {
+            pkgname: name.to_string(),
+            version: inspect_version(version).version,
+            build_dependencies: deps
+                .iter()
+                .map(|(n, optional)| Dependency { name: n.to_string(), optional: *optional })
+                .collect(),
+            ..Default::default()
+        };
+        (name.to_string(), (version, "main".to_string()))
+    }
+
+    #[test]
+    fn test_find_chains() {
+        let graph: DependencyGraph = [
+            entry("erlang", "27.0", &[("openssl", false), ("ncurses", true)]),
+            entry("elixir", "1.17.0", &[("erlang", false)]),
+            entry("openssl", "3.3.1", &[]),
+            entry("ncurses", "6.5", &[]),
+        ]
+        .into_iter()
+        .collect();
+        let roots = vec!["elixir".to_string(), "openssl".to_string()];
+
+        let chains = find_chains(&graph, &roots, &PackageSelector::parse("openssl").unwrap());
+        assert_eq!(chains, vec![
+            vec!["elixir".to_string(), "erlang".to_string(), "openssl".to_string()],
+            vec!["openssl".to_string()],
+        ]);
+        assert_eq!(render_chain(&graph, &chains[1]), "openssl=3.3.1 -> declared in cave");
+
+        let optional = find_chains(&graph, &roots, &PackageSelector::parse("ncurses").unwrap());
+        assert_eq!(render_chain(&graph, &optional[0]), "elixir=1.17.0 -> erlang=27.0 -> ncurses=6.5 (optional)");
+
+        assert!(find_chains(&graph, &roots, &PackageSelector::parse("openssl=1.1").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_version_prefix_matches_components() {
+        let graph: DependencyGraph = [entry("openssl", "3.20.1", &[])].into_iter().collect();
+        let roots = vec!["openssl".to_string()];
+        let found = |query: &str| !find_chains(&graph, &roots, &PackageSelector::parse(query).unwrap()).is_empty();
+        assert!(found("openssl=3.20") && found("openssl=3.20.1") && found("openssl=3.*"));
+        assert!(!found("openssl=3.2"));
+        assert!(!found("openssl=3.20.10"));
+    }
+}
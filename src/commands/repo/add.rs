@@ -1,9 +1,12 @@
 use crate::commands::repo::sync;
 use crate::models::config::Config;
 use crate::models::repository::{Repositories, Repository};
+use crate::models::version_entry::Signature;
+use crate::services::repo_origin::{self, ArchiveCheck, OriginKind};
+use crate::services::signature::KeyKind;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use anyhow::{Context, Result};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -11,21 +14,77 @@ struct RepoMetadata {
     /// Name of the repository defined in pi.repo.json
     /// Example: "pi-main"
     name: String,
+    /// Initial dependency policy, see `Repository::dependency_repos`.
+    #[serde(default)]
+    dependency_repos: Option<Vec<String>>,
 }
 
 /// Adds a new repository to the pi configuration and performs an initial sync.
 /// 
 /// Example path: "./my-custom-repo" -> "/home/user/my-custom-repo"
 /// Example metadata file: "/home/user/my-custom-repo/pi.repo.json"
-pub fn run(config: &Config, path: &str) {
-    if let Err(e) = execute_repo_add(config, path) {
-        log::error!("failed to add repo: {}", e);
+/// A git or tarball URL is cloned or downloaded under `<cache_dir>/repos`
+/// first and recorded as the repo's origin.
+pub fn run(config: &Config, path: &str, verify: &VerifyOptions, allow_hosts: &[String]) {
+    if let Err(e) = execute_repo_add(config, path, verify, allow_hosts) {
+        log::error!("failed to add repo: {:#}", e);
         std::process::exit(1);
     }
 }
 
-fn execute_repo_add(config: &Config, path: &str) -> Result<()> {
-    let abs_path = fs::canonicalize(path).context("Failed to get absolute path")?;
+/// What an archive URL must match, now and on every `repo sync`.
+#[derive(Debug, Default)]
+pub struct VerifyOptions {
+    pub checksum: Option<String>,
+    /// Detached signature; defaults to the URL plus `.asc` (gpg key) or
+    /// `.minisig` (minisign key).
+    pub signature_url: Option<String>,
+    /// File with the public key the signature must be made with.
+    pub public_key: Option<PathBuf>,
+}
+
+impl VerifyOptions {
+    fn signature(&self, url: &str) -> Result<Option<Signature>> {
+        let Some(key_file) = &self.public_key else {
+            return Ok(None);
+        };
+        let public_key = fs::read_to_string(key_file)
+            .with_context(|| format!("Failed to read {}", key_file.display()))?;
+        let url = match (&self.signature_url, KeyKind::detect(&public_key)?) {
+            (Some(sig), _) => sig.clone(),
+            (None, KeyKind::Gpg) => format!("{}.asc", url),
+            (None, KeyKind::Minisign) => format!("{}.minisig", url),
+        };
+        Ok(Some(Signature { url, public_key }))
+    }
+}
+
+fn execute_repo_add(config: &Config, path: &str, verify: &VerifyOptions, allow_hosts: &[String]) -> Result<()> {
+    let origin = OriginKind::of(path).map(|_| path.to_string());
+    if origin.is_none() && (verify.checksum.is_some() || verify.public_key.is_some()) {
+        anyhow::bail!("--checksum and --public-key only apply to archive URLs");
+    }
+    let checksum = verify.checksum.as_ref().map(|c| c.trim().to_ascii_lowercase());
+    let signature = origin.as_deref().map(|url| verify.signature(url)).transpose()?.flatten();
+    let abs_path = match &origin {
+        Some(url) => {
+            let fresh = !repo_origin::checkout_dir(config, url).exists();
+            let check = ArchiveCheck { checksum: checksum.as_deref(), signature: signature.as_ref() };
+            let dir = repo_origin::fetch(config, url, check)?;
+            let added = load_repo_metadata(&dir).and_then(|metadata| {
+                let repo_config = Repositories::load(config).context("Failed to load repositories")?;
+                validate_new_repo(&repo_config, &metadata.name, &dir.to_string_lossy())
+            });
+            if let Err(e) = added {
+                if fresh {
+                    let _ = fs::remove_dir_all(&dir);
+                }
+                return Err(e);
+            }
+            dir
+        }
+        None => fs::canonicalize(path).context("Failed to get absolute path")?,
+    };
     let metadata = load_repo_metadata(&abs_path)?;
     
     let mut repo_config = Repositories::load(config).context("Failed to load repositories")?;
@@ -33,14 +92,19 @@ fn execute_repo_add(config: &Config, path: &str) -> Result<()> {
 
     validate_new_repo(&repo_config, &metadata.name, &path_str)?;
 
-    let repo = Repository::new(path_str, metadata.name.clone());
+    let mut repo = Repository::new(path_str, metadata.name.clone());
+    repo.dependency_repos = metadata.dependency_repos;
+    repo.origin = origin;
+    repo.origin_checksum = checksum;
+    repo.origin_signature = signature;
+    repo.allowed_hosts = (!allow_hosts.is_empty()).then(|| allow_hosts.to_vec());
     repo_config.repositories.push(repo);
     repo_config.save(config).context("Failed to save repositories")?;
 
     log::info!("added repo: {} at {}", metadata.name, abs_path.display());
 
     // Automatically sync the newly added repository
-    sync::run(config, Some(&metadata.name));
+    sync::run(config, Some(&metadata.name), None);
     Ok(())
 }
 
@@ -85,11 +149,11 @@ mod tests {
         
         let config = Config::new_test(tmp.path().to_path_buf());
         
-        let metadata = RepoMetadata { name: "test-repo".to_string() };
+        let metadata = RepoMetadata { name: "test-repo".to_string(), dependency_repos: None };
         let metadata_content = serde_json::to_string(&metadata).unwrap();
         fs::write(repo_dir.join("pi.repo.json"), &metadata_content).unwrap();
 
-        let result = execute_repo_add(&config, repo_dir.to_str().unwrap());
+        let result = execute_repo_add(&config, repo_dir.to_str().unwrap(), &VerifyOptions::default(), &[]);
         assert!(result.is_ok());
 
         let repo_config = Repositories::load(&config).unwrap();
@@ -105,7 +169,7 @@ mod tests {
         
         let config = Config::new_test(tmp.path().to_path_buf());
 
-        let result = execute_repo_add(&config, repo_dir.to_str().unwrap());
+        let result = execute_repo_add(&config, repo_dir.to_str().unwrap(), &VerifyOptions::default(), &[]);
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("pi.repo.json missing"));
     }
@@ -118,15 +182,15 @@ mod tests {
         
         let config = Config::new_test(tmp.path().to_path_buf());
         
-        let metadata = RepoMetadata { name: "test-repo".to_string() };
+        let metadata = RepoMetadata { name: "test-repo".to_string(), dependency_repos: None };
         let metadata_content = serde_json::to_string(&metadata).unwrap();
         fs::write(repo_dir.join("pi.repo.json"), &metadata_content).unwrap();
 
         // First add
-        execute_repo_add(&config, repo_dir.to_str().unwrap()).unwrap();
+        execute_repo_add(&config, repo_dir.to_str().unwrap(), &VerifyOptions::default(), &[]).unwrap();
 
         // Second add (duplicate path)
-        let result = execute_repo_add(&config, repo_dir.to_str().unwrap());
+        let result = execute_repo_add(&config, repo_dir.to_str().unwrap(), &VerifyOptions::default(), &[]);
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("repository already exists at path"));
 
@@ -135,8 +199,48 @@ mod tests {
         fs::create_dir_all(&repo_dir2).unwrap();
         fs::write(repo_dir2.join("pi.repo.json"), &metadata_content).unwrap();
         
-        let result = execute_repo_add(&config, repo_dir2.to_str().unwrap());
+        let result = execute_repo_add(&config, repo_dir2.to_str().unwrap(), &VerifyOptions::default(), &[]);
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("repository with name 'test-repo' already exists"));
     }
+
+    #[test]
+    fn test_execute_repo_add_git_url() {
+        let tmp = tempdir().unwrap();
+        let upstream = tmp.path().join("upstream");
+        fs::create_dir_all(&upstream).unwrap();
+        fs::write(upstream.join("pi.repo.json"), r#"{"name": "remote"}"#).unwrap();
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git").current_dir(&upstream).args(args).status().unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "--quiet"]);
+        git(&["add", "."]);
+        git(&["-c", "user.name=pi", "-c", "user.email=pi@example.com", "commit", "--quiet", "-m", "init"]);
+
+        let config = Config::new_test(tmp.path().join("pi"));
+        let url = format!("file://{}", upstream.display());
+        execute_repo_add(&config, &url, &VerifyOptions::default(), &[]).unwrap();
+
+        let repo = &Repositories::load(&config).unwrap().repositories[0];
+        assert_eq!((repo.name.as_str(), repo.origin.as_deref()), ("remote", Some(url.as_str())));
+        assert!(Path::new(&repo.path).starts_with(config.cache_dir.join("repos")));
+        assert!(Path::new(&repo.path).join("pi.repo.json").exists());
+    }
+
+    #[test]
+    fn test_execute_repo_add_checks_need_an_archive() {
+        let tmp = tempdir().unwrap();
+        let upstream = tmp.path().join("upstream");
+        fs::create_dir_all(&upstream).unwrap();
+        fs::write(upstream.join("pi.repo.json"), r#"{"name": "remote"}"#).unwrap();
+        let config = Config::new_test(tmp.path().join("pi"));
+        let pinned = VerifyOptions { checksum: Some("0".repeat(64)), ..Default::default() };
+
+        let err = execute_repo_add(&config, &format!("file://{}", upstream.display()), &pinned, &[]).unwrap_err();
+        assert!(format!("{:#}", err).contains("only applies to archive URLs"));
+        let err = execute_repo_add(&config, upstream.to_str().unwrap(), &pinned, &[]).unwrap_err();
+        assert!(err.to_string().contains("only apply to archive URLs"));
+        assert!(Repositories::load(&config).unwrap().repositories.is_empty());
+    }
 }
@@ -1,21 +1,18 @@
 use crate::models::config::Config;
 use crate::models::package_entry::PackageList;
 use crate::models::repository::Repositories;
-use comfy_table::presets::NOTHING;
-use comfy_table::Table;
+use crate::utils::table::SortedTable;
 
 pub fn run(config: &Config, name: Option<&str>) {
     let repo_config = Repositories::get_all(config);
 
-    let mut table = Table::new();
-    table.load_preset(NOTHING);
-    table.set_header(vec!["Repo Name", "Type", "Name", "Discover Fn"]);
+    let mut table = SortedTable::new(&["Repo Name", "Type", "Name", "Discover Fn"], "repo,type,name");
 
     for repo in &repo_config.repositories {
-        if let Some(target_name) = name {
-            if repo.name != target_name {
-                continue;
-            }
+        if let Some(target_name) = name
+            && repo.name != target_name
+        {
+            continue;
         }
 
         if let Some(package_list) = PackageList::get_for_repo(config, repo, false) {
@@ -39,6 +36,6 @@ pub fn run(config: &Config, name: Option<&str>) {
         }
     }
 
-    println!("{table}");
+    table.print(config.sort.as_ref());
 }
 
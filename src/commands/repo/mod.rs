@@ -1,3 +1,5 @@
 pub mod add;
 pub mod list;
+pub mod restore;
 pub mod sync;
+pub mod trust;
@@ -0,0 +1,15 @@
+use crate::models::config::Config;
+use crate::models::repository::Repositories;
+
+/// `pi repo restore`: puts back the repositories.json saved before the last change.
+pub fn run(config: &Config) {
+    match Repositories::restore(config) {
+        Ok((backup, repos)) => {
+            log::info!("restored {} repositories from {}", repos.repositories.len(), backup.display());
+        }
+        Err(e) => {
+            log::error!("repo restore failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
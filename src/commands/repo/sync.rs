@@ -1,27 +1,154 @@
 use crate::models::repository::Repositories;
 use crate::commands::repo::list;
 use crate::models::config::Config;
+use crate::services::sync::{RecipeSync, SyncLimits};
+use crate::utils::duration::{format_duration, parse_duration};
+use crate::utils::table::SortedTable;
+use crate::utils::theme::Theme;
+use parking_lot::Mutex;
 use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::time::{Duration, Instant};
 
-pub fn run(config: &Config, name: Option<&str>) {
-    sync_all(config, name);
+/// Recipes that evaluated fine the sync summary lists, slowest first; every
+/// failed one is listed as well.
+const SLOWEST: usize = 10;
+
+pub fn run(config: &Config, name: Option<&str>, recipe_timeout: Option<&str>) {
+    let recipe_timeout = match recipe_timeout.map(parse_duration).transpose() {
+        Ok(secs) => secs.map(Duration::from_secs),
+        Err(e) => {
+            log::error!("invalid --recipe-timeout: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let start = Instant::now();
+    let recipes = sync_all(config, name, &SyncLimits::new(rayon::current_num_threads(), recipe_timeout));
     if log::log_enabled!(log::Level::Info) {
         list::run(config, name);
     }
+    let skipped: Vec<&RecipeSync> = recipes.iter().filter(|r| r.error.is_some()).collect();
+    if !skipped.is_empty() {
+        log::warn!("[sync] {} recipes left out of the package lists", skipped.len());
+        let mut table = SortedTable::new(&["Repo", "Recipe", "Reason"], "repo,recipe");
+        for s in &skipped {
+            table.add_row(vec![s.repo.clone(), s.recipe.clone(), s.error.clone().unwrap_or_default()]);
+        }
+        table.print(config.sort.as_ref());
+    }
+    print_summary(config, &recipes, start.elapsed());
+}
+
+/// The recipes the summary lists: every failed one and the `SLOWEST`
+/// slowest of the others, slowest first; and how many others are left out.
+fn summary_rows(recipes: &[RecipeSync]) -> (Vec<&RecipeSync>, usize) {
+    let mut sorted: Vec<&RecipeSync> = recipes.iter().collect();
+    sorted.sort_by_key(|r| Reverse(r.duration));
+    let mut shown_ok = 0;
+    let rows: Vec<&RecipeSync> = sorted.into_iter()
+        .filter(|r| {
+            if r.error.is_some() {
+                return true;
+            }
+            shown_ok += 1;
+            shown_ok <= SLOWEST
+        })
+        .collect();
+    let hidden = recipes.len() - rows.len();
+    (rows, hidden)
+}
+
+/// The recipes of a sync with their evaluation times (see `summary_rows`)
+/// and totals: files evaluated, failures and evaluation time against
+/// wall-clock time. Printed whatever the log level, as command output.
+fn print_summary(config: &Config, recipes: &[RecipeSync], wall: Duration) {
+    if recipes.is_empty() {
+        return;
+    }
+    let (rows, hidden) = summary_rows(recipes);
+    let mut table = SortedTable::new(&["Repo", "Recipe", "Packages", "Eval ms", "Status"], "-eval_ms,repo,recipe");
+    for r in rows {
+        table.add_row(vec![
+            r.repo.clone(),
+            r.recipe.clone(),
+            (r.packages + r.managers).to_string(),
+            r.duration.as_millis().to_string(),
+            if r.error.is_some() { "failed" } else { "ok" }.to_string(),
+        ]);
+    }
+    table.print(config.sort.as_ref());
+    if hidden > 0 {
+        Theme::current().line(&format!("+{} more", hidden));
+    }
+    let evaluation: Duration = recipes.iter().map(|r| r.duration).sum();
+    let failed = recipes.iter().filter(|r| r.error.is_some()).count();
+    Theme::current().line(&format!(
+        "{} recipe(s) evaluated in {}, {} failed ({} of evaluation on {} thread(s))",
+        recipes.len(),
+        format_duration(wall.as_millis()),
+        failed,
+        format_duration(evaluation.as_millis()),
+        rayon::current_num_threads()
+    ));
 }
 
-pub fn sync_all(config: &Config, name: Option<&str>) {
+/// Syncs the repositories, or the one named, evaluating at most `limits`
+/// recipes at once across all of them. Returns how each recipe went.
+pub fn sync_all(config: &Config, name: Option<&str>, limits: &SyncLimits) -> Vec<RecipeSync> {
     let repo_config = Repositories::get_all(config);
+    let recipes = Mutex::new(Vec::new());
 
     repo_config.repositories.par_iter().for_each(|repo| {
-        if let Some(target_name) = name {
-            if repo.name != target_name {
-                return;
+        if let Some(target_name) = name
+            && repo.name != target_name
+        {
+            return;
+        }
+
+        if let Some(origin) = &repo.origin {
+            if config.offline {
+                log::info!("[{}] offline, syncing the last checkout of {}", repo.name, origin);
+            } else if let Err(e) = crate::services::repo_origin::fetch(config, origin, repo.archive_check()) {
+                log::error!("[{}] update from {} failed, syncing the last checkout: {:#}", repo.name, origin, e);
             }
         }
 
-        if let Err(e) = crate::services::sync::sync_repo(config, repo) {
-            log::error!("[{}] sync failed: {:#}", repo.name, e);
+        match crate::services::sync::sync_repo_with(config, repo, limits) {
+            Ok(synced) => recipes.lock().extend(synced),
+            Err(e) => log::error!("[{}] sync failed: {:#}", repo.name, e),
         }
     });
+    recipes.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_rows() {
+        let recipe = |name: &str, ms: u64, failed: bool| RecipeSync {
+            repo: "main".to_string(),
+            recipe: name.to_string(),
+            duration: Duration::from_millis(ms),
+            packages: 1,
+            managers: 0,
+            error: failed.then(|| "boom".to_string()),
+        };
+        let mut recipes: Vec<RecipeSync> = (0..SLOWEST as u64 + 3).map(|i| recipe(&format!("ok{}.star", i), 100 + i, false)).collect();
+        recipes.push(recipe("broken.star", 1, true));
+        recipes.push(recipe("slow-broken.star", 5000, true));
+
+        let (rows, hidden) = summary_rows(&recipes);
+        let names: Vec<&str> = rows.iter().map(|r| r.recipe.as_str()).collect();
+        assert_eq!(names.len(), SLOWEST + 2);
+        assert_eq!(names[0], "slow-broken.star");
+        assert_eq!(names[1], format!("ok{}.star", SLOWEST + 2));
+        assert!(names.contains(&"broken.star"), "failures are always listed: {:?}", names);
+        assert!(!names.contains(&"ok0.star"));
+        assert_eq!(hidden, 3);
+
+        let (rows, hidden) = summary_rows(&recipes[..2]);
+        assert_eq!((rows.len(), hidden), (2, 0));
+    }
 }
@@ -0,0 +1,25 @@
+use crate::models::config::Config;
+use crate::models::repository::Repositories;
+use anyhow::{Context, Result};
+
+/// `pi repo trust`: sets the hosts the recipes of repo `name` may download
+/// from while they are evaluated; None trusts it with every host.
+pub fn run(config: &Config, name: &str, allowed_hosts: Option<Vec<String>>) {
+    if let Err(e) = execute(config, name, allowed_hosts) {
+        log::error!("repo trust failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+fn execute(config: &Config, name: &str, allowed_hosts: Option<Vec<String>>) -> Result<()> {
+    let mut repos = Repositories::load(config).context("Failed to load repositories")?;
+    let repo = repos.repositories.iter_mut().find(|r| r.name == name)
+        .with_context(|| format!("no repository named '{}'", name))?;
+    match &allowed_hosts {
+        None => log::info!("[{}] recipes may download from any host", name),
+        Some(hosts) if hosts.is_empty() => log::info!("[{}] recipes may not download anything", name),
+        Some(hosts) => log::info!("[{}] recipes may download from {}", name, hosts.join(", ")),
+    }
+    repo.allowed_hosts = allowed_hosts;
+    repos.save(config).context("Failed to save repositories")
+}
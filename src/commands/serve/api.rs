@@ -0,0 +1,100 @@
+use crate::commands::package::resolve::resolve_query;
+use crate::models::cave::Cave;
+use crate::models::cave_registry::CaveRegistry;
+use crate::models::config::Config;
+use crate::models::package_entry::PackageList;
+use crate::models::repository::Repositories;
+use crate::models::selector::PackageSelector;
+use crate::models::version_entry::VersionList;
+use anyhow::Context;
+use rayon::prelude::*;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+
+/// `GET /packages[?repo=name]`: packages and managers with their latest cached version.
+pub fn packages(config: &Config, repo_filter: Option<&str>) -> Value {
+    let mut out = Vec::new();
+    for repo in &Repositories::get_all(config).repositories {
+        if repo_filter.is_some_and(|r| r != repo.name) {
+            continue;
+        }
+        let Some(list) = PackageList::get_for_repo(config, repo, false) else { continue };
+        let mut names: Vec<&String> = list.packages.keys().collect();
+        names.sort();
+        for name in names {
+            let latest = VersionList::load(config, &repo.name, name).ok()
+                .and_then(|l| l.latest().map(|v| v.version.to_string()));
+            out.push(json!({ "repo": repo.name, "name": name, "kind": "package", "latest": latest }));
+        }
+        let mut managers: Vec<&String> = list.managers.keys().collect();
+        managers.sort();
+        for name in managers {
+            out.push(json!({ "repo": repo.name, "name": name, "kind": "manager" }));
+        }
+    }
+    Value::Array(out)
+}
+
+/// `GET /resolve?q=<query>&q=...`: resolves each query like `pi package resolve`.
+pub fn resolve(config: &Config, queries: &[String]) -> Value {
+    let repo_config = Repositories::get_all(config);
+    let results: Vec<Value> = queries.par_iter()
+        .map(|query| {
+            let Some(selector) = PackageSelector::parse(query) else {
+                return json!({ "query": query, "error": "invalid selector" });
+            };
+            match resolve_query(config, repo_config, &selector) {
+                Some((full_name, version, repo)) => json!({
+                    "query": query,
+                    "resolved": full_name,
+                    "version": version.version.to_string(),
+                    "release_date": version.release_date,
+                    "repo": repo,
+                }),
+                None => json!({ "query": query, "error": "not found" }),
+            }
+        })
+        .collect();
+    Value::Array(results)
+}
+
+/// `GET /caves`: caves known from previous builds.
+pub fn caves(config: &Config) -> Value {
+    let caves: Vec<Value> = CaveRegistry::load(&config.state_dir)
+        .into_iter()
+        .map(|(name, workspace)| json!({ "name": name, "workspace": workspace }))
+        .collect();
+    Value::Array(caves)
+}
+
+/// Workspace of a known cave, None if pi never built it.
+pub fn cave_workspace(config: &Config, name: &str) -> Option<PathBuf> {
+    CaveRegistry::load(&config.state_dir).remove(name)
+}
+
+/// `GET /caves/<name>/status`: declared packages and whether the cached
+/// environment is current.
+pub fn cave_status(config: &Config, name: &str) -> anyhow::Result<Value> {
+    let workspace = cave_workspace(config, name).with_context(|| format!("unknown cave: {}", name))?;
+    let cave_file = workspace.join(Cave::FILENAME);
+    let cave = Cave::load(&cave_file, &config.config_dir)?;
+
+    let env_file = config.pilocal_path(&cave.name, None).join("env.json");
+    let built_at = std::fs::metadata(&env_file).and_then(|m| m.modified()).ok();
+    let changed_at = std::fs::metadata(&cave_file).and_then(|m| m.modified()).ok();
+    let stale = match (built_at, changed_at) {
+        (Some(built), Some(changed)) => changed > built,
+        _ => true,
+    };
+    let variants = cave.variant_names();
+
+    Ok(json!({
+        "name": cave.name,
+        "workspace": cave.workspace,
+        "packages": cave.settings.packages,
+        "variants": variants,
+        "built": built_at.is_some(),
+        "built_at": built_at.map(|t| chrono::DateTime::<chrono::Utc>::from(t).to_rfc3339()),
+        "stale": stale,
+    }))
+}
@@ -0,0 +1,198 @@
+pub mod api;
+pub mod sse;
+
+use crate::models::config::Config;
+use anyhow::Result;
+use serde_json::{json, Value};
+use sse::Broadcaster;
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Requests handled at once; SSE streams count for as long as they are open.
+const MAX_CONNECTIONS: usize = 32;
+
+/// Host names a request may be addressed to. Browsers send the name they
+/// resolved, so a page rebinding its own name to 127.0.0.1 is refused.
+const ALLOWED_HOSTS: [&str; 3] = ["localhost", "127.0.0.1", "[::1]"];
+
+/// State shared by the request handlers.
+struct ServeContext<'a> {
+    config: &'a Config,
+    broadcaster: Arc<Broadcaster>,
+}
+
+type JsonResponse = Response<Cursor<Vec<u8>>>;
+
+#[derive(Debug, PartialEq)]
+enum Route {
+    Packages,
+    Resolve,
+    Caves,
+    CaveStatus(String),
+    Events,
+    NotFound,
+}
+
+pub fn run(config: &Config, listen: &str) {
+    if let Err(e) = serve(config, listen) {
+        log::error!("serve failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Serves the read-only HTTP/JSON API until interrupted. Every request runs
+/// on its own thread, up to MAX_CONNECTIONS, so long-lived SSE streams don't
+/// block other clients.
+fn serve(config: &Config, listen: &str) -> Result<()> {
+    let server = Server::http(listen).map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", listen, e))?;
+    if !listen.starts_with("127.") && !listen.starts_with("localhost") && !listen.starts_with("[::1]") {
+        log::warn!("[serve] {} is not a loopback address and the API has no authentication", listen);
+    }
+    let ctx = ServeContext { config, broadcaster: Broadcaster::install() };
+    let active = AtomicUsize::new(0);
+    log::info!("[serve] listening on http://{}", listen);
+
+    std::thread::scope(|s| {
+        for request in server.incoming_requests() {
+            if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                active.fetch_sub(1, Ordering::SeqCst);
+                log::debug!("[serve] too many connections, refusing {}", request.url());
+                let _ = request.respond(error_response(503, "too many connections"));
+                continue;
+            }
+            let (ctx, active) = (&ctx, &active);
+            s.spawn(move || {
+                handle(ctx, request);
+                active.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+    Ok(())
+}
+
+fn handle(ctx: &ServeContext, request: Request) {
+    if let Err(e) = check_origin(&request) {
+        log::warn!("[serve] refused {} {}: {}", request.method(), request.url(), e);
+        let _ = request.respond(error_response(403, &e));
+        return;
+    }
+    let (route, params) = route(request.method(), request.url());
+    log::debug!("[serve] {} {} -> {:?}", request.method(), request.url(), route);
+    let param = |key: &str| params.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+
+    if route == Route::Events {
+        // tiny_http buffers streamed bodies, so write the event stream on the raw connection.
+        let stream = ctx.broadcaster.stream();
+        if let Err(e) = stream.write_to(request.into_writer()) {
+            log::debug!("[serve] event stream closed: {}", e);
+        }
+        return;
+    }
+
+    let response = match route {
+        Route::Packages => json_response(200, &api::packages(ctx.config, param("repo").as_deref())),
+        Route::Resolve => {
+            let queries: Vec<String> = params.iter().filter(|(k, _)| k == "q").map(|(_, v)| v.clone()).collect();
+            if queries.is_empty() {
+                error_response(400, "missing query parameter q")
+            } else {
+                json_response(200, &api::resolve(ctx.config, &queries))
+            }
+        }
+        Route::Caves => json_response(200, &api::caves(ctx.config)),
+        Route::CaveStatus(name) => match api::cave_status(ctx.config, &name) {
+            Ok(status) => json_response(200, &status),
+            Err(e) => error_response(404, &format!("{:#}", e)),
+        },
+        Route::Events | Route::NotFound => error_response(404, "not found"),
+    };
+    if let Err(e) = request.respond(response) {
+        log::debug!("[serve] failed to respond: {}", e);
+    }
+}
+
+/// Refuses requests not addressed to a loopback name (DNS rebinding) and
+/// those a web page of another origin makes.
+fn check_origin(request: &Request) -> Result<(), String> {
+    let value = |name: &'static str| request.headers().iter().find(|h| h.field.equiv(name)).map(|h| h.value.as_str());
+    let host = value("Host").ok_or("missing Host header")?;
+    if !is_allowed_host(host) {
+        return Err(format!("host {} is not allowed", host));
+    }
+    if let Some(origin) = value("Origin") {
+        let authority = origin.split_once("://").map_or(origin, |(_, rest)| rest);
+        if !is_allowed_host(authority) {
+            return Err(format!("origin {} is not allowed", origin));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `authority` (`host[:port]`) names one of ALLOWED_HOSTS.
+fn is_allowed_host(authority: &str) -> bool {
+    let host = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => host,
+        _ => authority,
+    };
+    ALLOWED_HOSTS.iter().any(|allowed| host.eq_ignore_ascii_case(allowed))
+}
+
+/// Maps a request to its route and decoded query parameters.
+fn route(method: &Method, url: &str) -> (Route, Vec<(String, String)>) {
+    let Ok(parsed) = url::Url::parse(&format!("http://localhost{}", url)) else {
+        return (Route::NotFound, Vec::new());
+    };
+    let params = parsed.query_pairs().map(|(k, v)| (k.into_owned(), v.into_owned())).collect();
+    let segments: Vec<&str> = parsed.path().trim_matches('/').split('/').collect();
+
+    let route = match (method, segments.as_slice()) {
+        (Method::Get, ["packages"]) => Route::Packages,
+        (Method::Get, ["resolve"]) => Route::Resolve,
+        (Method::Get, ["caves"]) => Route::Caves,
+        (Method::Get, ["caves", name, "status"]) => Route::CaveStatus(name.to_string()),
+        (Method::Get, ["events"]) => Route::Events,
+        _ => Route::NotFound,
+    };
+    (route, params)
+}
+
+fn json_response(status: u16, value: &Value) -> JsonResponse {
+    Response::from_data(value.to_string().into_bytes())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn error_response(status: u16, message: &str) -> JsonResponse {
+    json_response(status, &json!({ "error": message }))
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route() {
+        let (r, params) = route(&Method::Get, "/resolve?q=go%3D1.22&q=node");
+        assert_eq!(r, Route::Resolve);
+        assert_eq!(params, vec![("q".to_string(), "go=1.22".to_string()), ("q".to_string(), "node".to_string())]);
+        assert_eq!(route(&Method::Get, "/caves/web/status").0, Route::CaveStatus("web".to_string()));
+        assert_eq!(route(&Method::Post, "/caves/web/build?variant=:dev").0, Route::NotFound);
+        assert_eq!(route(&Method::Get, "/events").0, Route::Events);
+    }
+
+    #[test]
+    fn test_is_allowed_host() {
+        for host in ["localhost", "localhost:7878", "127.0.0.1:7878", "LOCALHOST", "[::1]:7878", "[::1]"] {
+            assert!(is_allowed_host(host), "{}", host);
+        }
+        for host in ["evil.example:7878", "127.0.0.1.evil.example", "localhost.evil.example:80", "10.0.0.1", ""] {
+            assert!(!is_allowed_host(host), "{}", host);
+        }
+    }
+}
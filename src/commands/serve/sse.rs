@@ -0,0 +1,117 @@
+use crate::services::events::{self, Event};
+use parking_lot::Mutex;
+use std::io::Write;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Interval of SSE comments sent while idle, so closed connections are noticed.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Fans events of the process-wide bus out to connected SSE clients.
+#[derive(Default)]
+pub struct Broadcaster {
+    clients: Mutex<Vec<Sender<String>>>,
+}
+
+impl Broadcaster {
+    /// Creates a broadcaster subscribed to the event bus.
+    pub fn install() -> Arc<Self> {
+        let broadcaster = Arc::new(Self::default());
+        let sink = broadcaster.clone();
+        events::subscribe(move |event| sink.publish(event));
+        broadcaster
+    }
+
+    fn publish(&self, event: &Event) {
+        let Some(frame) = format_event(event) else { return };
+        // Clients whose stream was dropped are removed on the first failed send.
+        self.clients.lock().retain(|tx| tx.send(frame.clone()).is_ok());
+    }
+
+    /// A new client stream, receiving every event published from now on.
+    pub fn stream(&self) -> EventStream {
+        let (tx, rx) = mpsc::channel();
+        self.clients.lock().push(tx);
+        EventStream { rx }
+    }
+}
+
+/// Pending SSE frames of one client.
+pub struct EventStream {
+    rx: Receiver<String>,
+}
+
+impl EventStream {
+    /// Blocks for the next frame, a keepalive comment when idle; None once
+    /// the broadcaster is gone.
+    fn next_frame(&self) -> Option<String> {
+        match self.rx.recv_timeout(KEEPALIVE) {
+            Ok(frame) => Some(frame),
+            Err(RecvTimeoutError::Timeout) => Some(": keepalive\n\n".to_string()),
+            Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    /// Writes the response head and then frames as they arrive, flushing each
+    /// one. Returns when the client goes away, noticed at the latest when the
+    /// next keepalive fails to write, or when the broadcaster does.
+    pub fn write_to(self, mut out: impl Write) -> std::io::Result<()> {
+        out.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n")?;
+        out.flush()?;
+        while let Some(frame) = self.next_frame() {
+            out.write_all(frame.as_bytes())?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// One SSE frame named after the event kind, with the JSON event as data.
+fn format_event(event: &Event) -> Option<String> {
+    let value = serde_json::to_value(event).ok()?;
+    let kind = value.get("event")?.as_str()?.to_string();
+    Some(format!("event: {}\ndata: {}\n\n", kind, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_receives_frames() {
+        let broadcaster = Broadcaster::default();
+        let stream = broadcaster.stream();
+        broadcaster.publish(&Event::SyncStarted { repo: "main".to_string() });
+
+        let frame = stream.next_frame().unwrap();
+        assert!(frame.starts_with("event: sync_started\ndata: {"));
+        assert!(frame.ends_with("}\n\n"));
+
+        drop(stream);
+        broadcaster.publish(&Event::SyncStarted { repo: "main".to_string() });
+        assert!(broadcaster.clients.lock().is_empty());
+    }
+
+    #[test]
+    fn test_stream_ends_with_the_client() {
+        struct Closed;
+        impl Write for Closed {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let broadcaster = Broadcaster::default();
+        assert!(broadcaster.stream().write_to(Closed).is_err());
+
+        // Once the broadcaster is gone, the stream ends after the head.
+        let stream = broadcaster.stream();
+        drop(broadcaster);
+        let mut out = Vec::new();
+        stream.write_to(&mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().ends_with("\r\n\r\n"));
+    }
+}
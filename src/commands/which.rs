@@ -0,0 +1,75 @@
+use crate::models::cave::Cave;
+use crate::models::cave_registry::CaveRegistry;
+use crate::models::config::Config;
+use crate::services::db::BinaryProvider;
+use crate::utils::table::SortedTable;
+use crate::utils::theme::Theme;
+use anyhow::{Context, Result};
+use std::env;
+
+pub fn run(config: &Config, binary: &str, all: bool) {
+    let result = if all {
+        execute_all(config, binary)
+    } else {
+        execute_current(config, binary)
+    };
+    match result {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            log::error!("which failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn providers(config: &Config, binary: &str) -> Result<Vec<BinaryProvider>> {
+    let db = config.db().context("state database is unavailable")?;
+    db.binary_providers(binary)
+}
+
+/// Where the current cave's `binary` comes from; points at other caves
+/// exporting it when this one doesn't.
+fn execute_current(config: &Config, binary: &str) -> Result<bool> {
+    let current_dir = env::current_dir().context("Failed to get current directory")?;
+    let (_, cave) = Cave::find(config, &current_dir).context("no cave found, use --all to search every cave")?;
+    let providers = providers(config, binary)?;
+
+    let link = config.pilocal_path(&cave.name, None).join("bin").join(binary);
+    match (providers.iter().find(|p| p.cave == cave.name && p.variant.is_none()), link.canonicalize()) {
+        (Some(p), Ok(target)) => {
+            Theme::current().line(&format!("{} {} ({})", p.package, p.version, target.display()));
+            Ok(true)
+        }
+        (None, Ok(target)) => {
+            Theme::current().line(&target.display().to_string());
+            Ok(true)
+        }
+        (_, Err(_)) => {
+            let others: Vec<String> = providers.iter().map(BinaryProvider::owner).collect();
+            if others.is_empty() {
+                log::error!("[{}] {} is not exported by any cave", cave.name, binary);
+            } else {
+                log::error!("[{}] {} is not exported here, but by: {} (see --all)", cave.name, binary, others.join(", "));
+            }
+            Ok(false)
+        }
+    }
+}
+
+/// Every cave and variant whose last build exported `binary`.
+fn execute_all(config: &Config, binary: &str) -> Result<bool> {
+    let providers = providers(config, binary)?;
+    if providers.is_empty() {
+        log::error!("{} is not exported by any cave", binary);
+        return Ok(false);
+    }
+    let workspaces = CaveRegistry::load(&config.state_dir);
+    let mut table = SortedTable::new(&["Cave", "Package", "Version", "Workspace"], "cave");
+    for p in providers {
+        let workspace = workspaces.get(&p.cave).map(|w| w.display().to_string()).unwrap_or_default();
+        table.add_row(vec![p.owner(), p.package, p.version, workspace]);
+    }
+    table.print(config.sort.as_ref());
+    Ok(true)
+}
@@ -1,19 +1,82 @@
-use log::LevelFilter;
+use crate::utils::theme::ColorChoice;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-pub fn init_logging(quiet: bool, verbose: bool, debug: bool) {
-    let log_level = if debug {
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+/// Picks the log level: -d, -v and -q win over the configured `log_level`.
+pub fn log_level(quiet: bool, verbose: bool, debug: bool, configured: Option<&str>) -> LevelFilter {
+    if debug {
         LevelFilter::Trace
     } else if verbose {
         LevelFilter::Debug
     } else if quiet {
         LevelFilter::Error
     } else {
-        LevelFilter::Info
-    };
-
-    env_logger::Builder::new()
-        .filter_level(log_level)
-        .format_timestamp_millis()
-        .format_target(false)
-        .init();
+        configured.and_then(|l| l.parse().ok()).unwrap_or(LevelFilter::Info)
+    }
+}
+
+/// Installs the logger. With `json` (`--json`) each record is written to
+/// stderr as `{"event": "log", "level": "warn", "message": "...", "ts": ...}`.
+pub fn init_logging(log_level: LevelFilter, color: ColorChoice, json: bool) {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(log_level).write_style(color.write_style()).format_timestamp_millis().format_target(false);
+    if json {
+        builder.write_style(env_logger::WriteStyle::Never).format(|buf, record| writeln!(buf, "{}", json_record(record)));
+    }
+    let inner = builder.build();
+    // Warnings are counted even when -q hides them.
+    log::set_max_level(inner.filter().max(LevelFilter::Warn));
+    let _ = log::set_boxed_logger(Box::new(CountingLogger { inner }));
+}
+
+fn json_record(record: &Record) -> serde_json::Value {
+    serde_json::json!({
+        "event": "log",
+        "level": record.level().as_str().to_ascii_lowercase(),
+        "message": record.args().to_string(),
+        "ts": chrono::Utc::now().to_rfc3339(),
+    })
+}
+
+/// Warnings logged so far, for the summary of `pi cave build`.
+pub fn warning_count() -> usize {
+    WARNINGS.load(Ordering::Relaxed)
+}
+
+struct CountingLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for CountingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn || self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() == Level::Warn {
+            WARNINGS.fetch_add(1, Ordering::Relaxed);
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_record() {
+        let args = format_args!("[cave] {} failed", "build");
+        let record = Record::builder().level(Level::Error).args(args).build();
+        let value = json_record(&record);
+        assert_eq!((value["event"].as_str(), value["level"].as_str()), (Some("log"), Some("error")));
+        assert_eq!(value["message"], "[cave] build failed");
+    }
 }
@@ -0,0 +1,40 @@
+use std::path::{Path, PathBuf};
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::prelude::*;
+
+/// Installs a chrome-trace subscriber recording all spans of this command.
+/// The trace is written to `<state_dir>/traces/<timestamp>-<command>.json` and
+/// can be opened in chrome://tracing or https://ui.perfetto.dev.
+/// The returned guard must be kept alive until the command finishes.
+pub fn init_tracing(state_dir: &Path) -> Option<(FlushGuard, PathBuf)> {
+    let trace_dir = state_dir.join("traces");
+    if let Err(e) = std::fs::create_dir_all(&trace_dir) {
+        log::error!("[trace] failed to create {}: {}", trace_dir.display(), e);
+        return None;
+    }
+
+    let timestamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    let trace_file = trace_dir.join(format!("{}-{}.json", timestamp, command_label()));
+
+    let (layer, guard) = ChromeLayerBuilder::new()
+        .file(&trace_file)
+        .include_args(true)
+        .build();
+
+    if let Err(e) = tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer)) {
+        log::error!("[trace] failed to install subscriber: {}", e);
+        return None;
+    }
+
+    Some((guard, trace_file))
+}
+
+/// Short label for the running command, e.g. "cave-build".
+pub fn command_label() -> String {
+    let words: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|a| !a.starts_with('-'))
+        .take(2)
+        .collect();
+    if words.is_empty() { "pi".to_string() } else { words.join("-") }
+}
@@ -7,38 +7,108 @@ mod services;
 mod starlark;
 mod utils;
 
-use crate::cli::parser::{Cli, Commands, DevelCommands, CaveCommands, RepoCommands, PackageCommands, DiskCommands};
-use crate::logging::init::init_logging;
+use crate::cli::parser::{Cli, Commands, DevelCommands, CaveCommands, RepoCommands, PackageCommands, DiskCommands, ConfigCommands};
+use crate::logging::init::{init_logging, log_level};
+use crate::logging::trace::{command_label, init_tracing};
+use crate::models::cave_policy::{self, CavePolicy};
 use crate::models::config::Config;
-use clap::Parser;
+use crate::models::settings::Settings;
+use crate::services::downloader::Downloader;
+use crate::services::sandbox::Bubblewrap;
+use crate::utils::theme::Theme;
+use clap::{CommandFactory, FromArgMatches};
 
 fn main() {
-    let cli = Cli::parse();
-    let config = Config::new(cli.force, cli.rebuild, cli.no_sync);
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
-    init_logging(cli.quiet, cli.verbose, cli.debug);
+    // Precedence: CLI flags > PI_* environment > settings.toml > defaults.
+    let (mut settings, settings_err) = match Settings::load(&Config::config_home()) {
+        Ok(s) => (s, None),
+        Err(e) => (Settings::default(), Some(e)),
+    };
+    settings.apply_env();
+    init_logging(log_level(cli.quiet, cli.verbose, cli.debug, settings.log_level.as_deref()), cli.color, cli.json);
+    Theme::detect(cli.color, cli.compact, cli.json).init();
+    if let Some(e) = settings_err {
+        log::warn!("[settings] {:#}, using defaults", e);
+    }
+
+    if let Some(jobs) = cli.jobs.or(settings.jobs)
+        && let Err(e) = rayon::ThreadPoolBuilder::new().num_threads(jobs).build_global()
+    {
+        log::warn!("[settings] failed to set jobs: {}", e);
+    }
+
+    Downloader::set_retry_policy(settings.retry_policy());
+    Bubblewrap::set_backend(settings.sandbox_backend());
+    Downloader::set_offline(cli.offline);
+    let mut config = Config::new(settings);
+    config.force = cli.force;
+    config.rebuild = cli.rebuild;
+    config.no_sync |= cli.no_sync || cli.offline;
+    config.offline = cli.offline;
+    config.keep_build_home |= cli.keep_build_home;
+    config.allow_host_build = cli.allow_host_build;
+    config.strict = cli.strict;
+    config.cave = cli.cave.clone().or_else(|| std::env::var("PI_CAVE_PATH").ok().filter(|p| !p.is_empty()));
+    if let Some(target) = &cli.target {
+        match target.parse() {
+            Ok(platform) => config.target = platform,
+            Err(e) => {
+                log::error!("invalid --target: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(sort) = &cli.sort {
+        match sort.parse() {
+            Ok(spec) => config.sort = Some(spec),
+            Err(e) => {
+                log::error!("invalid --sort: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if config.is_inside_cave() {
+        let var = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+        config.apply_cave_defaults(var("PI_WORKSPACE"), var("PI_CAVE_VARIANT"));
+    }
 
     if config.is_inside_cave() {
-        validate_command_in_cave(&cli.command);
+        validate_command_in_cave(&config, &cave_policy::capability(&matches));
+    }
+
+    if cli.progress_json || cli.json {
+        services::events::subscribe_json_stdout();
     }
 
-    route_command(cli.command, &config);
+    let trace = if cli.trace { init_tracing(&config.state_dir) } else { None };
+    let started = std::time::Instant::now();
+    {
+        let _span = tracing::info_span!("command", name = %command_label()).entered();
+        route_command(cli.command, &config);
+    }
+    services::manager_cache::wait_for_refreshes(&config);
+    if cli.timings {
+        config.state.timings.print(started.elapsed());
+    }
+    if let Some((guard, trace_file)) = trace {
+        drop(guard);
+        log::info!("[trace] written to {}", trace_file.display());
+    }
 }
 
-/// Validates that the command is allowed to run when PI_CAVE is set.
-fn validate_command_in_cave(command: &Commands) {
-    let is_allowed = match command {
-        Commands::Version | 
-        Commands::Repo { command: RepoCommands::List { .. } } |
-        Commands::Package { command: PackageCommands::List { .. } } |
-        Commands::Package { command: PackageCommands::Info { .. } } |
-        Commands::Package { command: PackageCommands::Resolve { .. } } |
-        Commands::Cave { command: CaveCommands::Info } => true,
-        _ => false,
-    };
+/// Validates that the command is allowed to run when PI_CAVE is set,
+/// according to the cave policy (see `CavePolicy`).
+fn validate_command_in_cave(config: &Config, capability: &str) {
+    let policy = CavePolicy::load(&config.config_dir).unwrap_or_else(|e| {
+        log::warn!("[policy] {:#}, using defaults", e);
+        CavePolicy::default()
+    });
 
-    if !is_allowed {
-        log::error!("command not allowed inside cave");
+    if !policy.is_allowed(capability) {
+        log::error!("command not allowed inside cave: {} (see {})", capability, config.config_dir.join(CavePolicy::FILENAME).display());
         std::process::exit(1);
     }
 }
@@ -55,14 +125,30 @@ fn route_command(command: Commands, config: &Config) {
         Commands::Cave { command } => handle_cave_command(command, config),
         Commands::Disk { command } => handle_disk_command(command, config),
         Commands::Devel { command } => handle_devel_command(command, config),
+        Commands::Config { command } => handle_config_command(command, config),
+        Commands::Which { binary, all } => commands::which::run(config, &binary, all),
+        Commands::Daemon { stop, status } => commands::daemon::run(config, stop, status),
+        Commands::Serve { listen } => commands::serve::run(config, &listen),
+    }
+}
+
+fn handle_config_command(command: ConfigCommands, config: &Config) {
+    match command {
+        ConfigCommands::Get { key } => commands::config::get::run(config, key.as_deref()),
+        ConfigCommands::Set { key, value } => commands::config::set::run(config, &key, &value),
     }
 }
 
 fn handle_repo_command(command: RepoCommands, config: &Config) {
     match command {
-        RepoCommands::Add { path } => commands::repo::add::run(config, &path),
-        RepoCommands::Sync { name } => commands::repo::sync::run(config, name.as_deref()),
+        RepoCommands::Add { path, checksum, signature_url, public_key, allow_hosts } => {
+            let verify = commands::repo::add::VerifyOptions { checksum, signature_url, public_key };
+            commands::repo::add::run(config, &path, &verify, &allow_hosts)
+        }
+        RepoCommands::Trust { name, allow_hosts, any } => commands::repo::trust::run(config, &name, (!any).then_some(allow_hosts)),
+        RepoCommands::Sync { name, recipe_timeout } => commands::repo::sync::run(config, name.as_deref(), recipe_timeout.as_deref()),
         RepoCommands::List { name } => commands::repo::list::run(config, name.as_deref()),
+        RepoCommands::Restore => commands::repo::restore::run(config),
     }
 }
 
@@ -70,8 +156,16 @@ fn handle_package_command(command: PackageCommands, config: &Config) {
     match command {
         PackageCommands::Sync { selector } => commands::package::sync::run(config, selector.as_deref()),
         PackageCommands::List { selector, all } => commands::package::list::run(config, selector.as_deref(), all),
-        PackageCommands::Info { selector } => commands::package::info::run(config, &selector),
+        PackageCommands::Artifacts { selector } => commands::package::artifacts::run(config, &selector),
+        PackageCommands::Logs { selector, step } => commands::package::logs::run(config, &selector, step),
+        PackageCommands::Search { query } => commands::package::search::run(config, &query),
+        PackageCommands::Info { selector, format } => commands::package::info::run(config, &selector, format),
+        PackageCommands::Install { selector } => commands::package::install::run(config, &selector),
+        PackageCommands::Uninstall { name } => commands::package::uninstall::run(config, &name),
         PackageCommands::Resolve { queries } => commands::package::resolve::run(config, queries),
+        PackageCommands::Why { selector, variant } => commands::package::why::run(config, &selector, config.variant_or_default(variant)),
+        PackageCommands::Graph { selector, format } => commands::package::graph::run(config, &selector, format),
+        PackageCommands::Changelog { selector, from, variant } => commands::package::changelog::run(config, &selector, from, config.variant_or_default(variant)),
     }
 }
 
@@ -79,25 +173,57 @@ fn handle_cave_command(command: CaveCommands, config: &Config) {
     match command {
         CaveCommands::Init => commands::cave::init::run(config),
         CaveCommands::Info => commands::cave::info::run(config),
-        CaveCommands::Add { args } => commands::cave::add::run(config, args),
+        CaveCommands::Add { args, replace } => commands::cave::add::run(config, args, replace),
         CaveCommands::Rem { args } => commands::cave::rem::run(config, args),
-        CaveCommands::Resolve { variant } => commands::cave::resolve::run(config, variant),
-        CaveCommands::Build { variant } => commands::cave::build::run(config, variant),
-        CaveCommands::Run { variant, command } => commands::cave::run::run(config, variant, command),
+        CaveCommands::Doctor => commands::cave::doctor::run(config),
+        CaveCommands::Status { variant } => commands::cave::status::run(config, config.variant_or_default(variant)),
+        CaveCommands::Resolve { variant } => commands::cave::resolve::run(config, config.variant_or_default(variant)),
+        CaveCommands::Build { variant, watch, dry_run, locked, report, report_file } => {
+            let report = commands::cave::build::ReportOptions { format: report, file: report_file };
+            commands::cave::build::run(config, config.variant_or_default(variant), watch, dry_run, locked, report)
+        }
+        CaveCommands::Run { variant, bind_cwd_only, command } => commands::cave::run::run(config, variant, command, bind_cwd_only),
+        CaveCommands::Shell { variant } => commands::cave::shell::run(config, config.variant_or_default(variant)),
+        CaveCommands::Env { variant, shell, deactivate, hook } => commands::cave::shell_hook::run_env(config, config.variant_or_default(variant), shell, deactivate, hook),
+        CaveCommands::Allow { revoke } => commands::cave::shell_hook::run_allow(config, revoke),
+        CaveCommands::ShellHook { shell, auto } => commands::cave::shell_hook::run_hook(shell, auto),
+        CaveCommands::Devcontainer { variant, image } => commands::cave::devcontainer::run(config, config.variant_or_default(variant), &image),
+        CaveCommands::Shims { variant } => commands::cave::shims::run(config, config.variant_or_default(variant)),
+        CaveCommands::Service { action, args } => commands::cave::service::run(config, action, args),
+        CaveCommands::Export => commands::cave::export::run(config),
+        CaveCommands::Import { file, no_pins, fetch } => commands::cave::import::run(config, &file, no_pins, fetch),
     }
 }
 
 fn handle_disk_command(command: DiskCommands, config: &Config) {
     match command {
-        DiskCommands::Info => commands::disk::info::run(config),
-        DiskCommands::Clean { meta, pilocals, packages, downloads, config: config_flag, state, confirm } => {
-            commands::disk::clean::run(config, meta, pilocals, packages, downloads, config_flag, state, confirm);
+        DiskCommands::Info { stale } => commands::disk::info::run(config, stale),
+        DiskCommands::Gc { dry_run, unreferenced } => commands::disk::gc::run(config, dry_run, unreferenced),
+        DiskCommands::Verify { repair } => commands::disk::verify::run(config, repair),
+        DiskCommands::Clean { meta, pilocals, packages, downloads, config: config_flag, state, builds, confirm } => {
+            let targets = commands::disk::clean::CleanTargets {
+                meta, pilocals, packages, downloads, config: config_flag, state, builds,
+            };
+            commands::disk::clean::run(config, targets, confirm);
         }
     }
 }
 
 fn handle_devel_command(command: DevelCommands, config: &Config) {
     match command {
-        DevelCommands::Test { filename, pkg } => commands::devel::test::run(config, &filename, pkg.as_deref()),
+        DevelCommands::Check { paths } => commands::devel::check::run(config, &paths),
+        DevelCommands::Lint { path } => commands::devel::lint::run(path.as_deref()),
+        DevelCommands::Match { selector, candidates } => commands::devel::match_selector::run(config, &selector, &candidates),
+        DevelCommands::RerunStep { pkg, version, index, variant, shell } => {
+            commands::devel::rerun_step::run(config, &pkg, &version, index, config.variant_or_default(variant).as_deref(), shell)
+        }
+        DevelCommands::State { scope, clear } => commands::devel::state::run(config, scope.as_deref(), clear),
+        DevelCommands::Test { filename, pkg, version, options, keep } => commands::devel::test::run(config, commands::devel::test::TestArgs {
+            filename: &filename,
+            pkg: pkg.as_deref(),
+            version: version.as_deref(),
+            options: &options,
+            keep,
+        }),
     }
 }
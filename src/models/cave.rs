@@ -1,8 +1,12 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
 use std::fs;
 use anyhow::Context;
+use crate::models::cave_registry::CaveRegistry;
+use crate::models::config::Config;
+use crate::models::repository::{Repositories, REPOSITORIES_FILE};
+use crate::models::settings::QuotaSettings;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct CaveSettings {
@@ -18,31 +22,73 @@ pub struct CaveSettings {
     pub binds: Vec<String>,
     #[serde(default)]
     pub command: Option<Vec<String>>,
+    /// Extra /etc/hosts entries inside the cave, hostname -> address.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub hosts: HashMap<String, String>,
+    /// Packages whose `requires_host_build` steps may run outside the sandbox.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub host_build: Vec<String>,
+    /// Build as with `--strict`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub strict: bool,
+    /// Prefix for the `bin/` links of a package, by package name, so two
+    /// packages exporting the same binaries can share a cave.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub bin_prefix: HashMap<String, String>,
+    /// Variants this variant builds on, merged first in order; only read in
+    /// `variants`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extends: Vec<String>,
 }
 
 impl CaveSettings {
+    /// JSON keys of the settings, for `pi cave doctor`.
+    pub const KEYS: &'static [&'static str] = &[
+        "packages", "set", "unset", "options", "binds", "command", "hosts", "host_build", "strict", "bin_prefix", "extends",
+    ];
+
     pub fn merge(&mut self, other: &CaveSettings) {
-        self.packages.extend(other.packages.clone());
-        self.packages.dedup();
+        extend_unique(&mut self.packages, &other.packages);
         for (k, v) in &other.set {
             self.set.insert(k.clone(), v.clone());
         }
         for u in &other.unset {
-            self.unset.push(u.clone());
             self.set.remove(u);
         }
-        self.unset.dedup();
+        extend_unique(&mut self.unset, &other.unset);
         for (pkg, opts) in &other.options {
             let target_opts = self.options.entry(pkg.clone()).or_default();
             for (k, v) in opts {
                 target_opts.insert(k.clone(), v.clone());
             }
         }
-        self.binds.extend(other.binds.clone());
-        self.binds.dedup();
+        extend_unique(&mut self.binds, &other.binds);
         if other.command.is_some() {
             self.command = other.command.clone();
         }
+        for (host, addr) in &other.hosts {
+            self.hosts.insert(host.clone(), addr.clone());
+        }
+        extend_unique(&mut self.host_build, &other.host_build);
+        self.strict |= other.strict;
+        for (pkg, prefix) in &other.bin_prefix {
+            self.bin_prefix.insert(pkg.clone(), prefix.clone());
+        }
+        for parent in &other.extends {
+            if !self.extends.contains(parent) {
+                self.extends.push(parent.clone());
+            }
+        }
+    }
+}
+
+/// Appends the `items` not in `list` yet, keeping the order of both.
+fn extend_unique(list: &mut Vec<String>, items: &[String]) {
+    let mut seen: HashSet<String> = list.iter().cloned().collect();
+    for item in items {
+        if seen.insert(item.clone()) {
+            list.push(item.clone());
+        }
     }
 }
 
@@ -56,10 +102,47 @@ pub struct Cave {
     pub settings: CaveSettings,
     #[serde(default)]
     pub variants: HashMap<String, CaveSettings>,
+    /// Cache quotas overriding the global ones while working in this cave.
+    #[serde(default, skip_serializing_if = "QuotaSettings::is_empty")]
+    pub quota: QuotaSettings,
+    /// Variant sets to include: `repo:<name>` (`variants/<name>.json` of the
+    /// first repository having it) or a file relative to the cave file.
+    #[serde(default, skip_serializing_if = "Vec::is_empty", deserialize_with = "one_or_many")]
+    pub variants_from: Vec<String>,
+    /// Variants of `variants_from`, read by `load`; never saved.
+    #[serde(skip)]
+    pub shared_variants: HashMap<String, CaveSettings>,
+}
+
+/// A file of shared variant definitions, which may include others.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VariantSet {
+    #[serde(default)]
+    variants: HashMap<String, CaveSettings>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    variants_from: Vec<String>,
+}
+
+const REPO_VARIANTS_PREFIX: &str = "repo:";
+
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(v) => v,
+    })
 }
 
 impl Cave {
     pub const FILENAME: &'static str = "pi.cave.json";
+    /// Top-level JSON keys of a cave file.
+    pub const KEYS: &'static [&'static str] = &["name", "workspace", "homedir", "settings", "variants", "quota", "variants_from"];
 
     pub fn new(path: PathBuf, homedir: PathBuf) -> Self {
         let name = path.file_name()
@@ -72,33 +155,117 @@ impl Cave {
             homedir,
             settings: CaveSettings::default(),
             variants: HashMap::new(),
+            quota: QuotaSettings::default(),
+            variants_from: Vec::new(),
+            shared_variants: HashMap::new(),
         }
     }
 
-    pub fn find_in_ancestry(start_path: &Path) -> Option<(PathBuf, Self)> {
-        let mut current = start_path.to_path_buf();
-        loop {
-            let cave_file = current.join(Self::FILENAME);
-            if cave_file.exists() {
-                match Self::load(&cave_file) {
-                    Ok(cave) => return Some((cave_file, cave)),
-                    Err(e) => {
-                        log::error!("failed to load cave {}: {}", cave_file.display(), e);
-                    }
-                }
+    /// The cave commands act on: the one `--cave` or PI_CAVE_PATH names
+    /// (`config.cave`), else the nearest in the ancestry of `start`.
+    pub fn find(config: &Config, start: &Path) -> Option<(PathBuf, Self)> {
+        let Some(selection) = &config.cave else {
+            return Self::find_in_ancestry(start, &config.config_dir);
+        };
+        let found = Self::cave_file(selection, &config.state_dir)
+            .and_then(|file| Ok((file.clone(), Self::load(&file, &config.config_dir)?)));
+        found.map_err(|e| log::error!("--cave {}: {:#}", selection, e)).ok()
+    }
+
+    /// The file of the cave `find` would pick, without loading it, for
+    /// commands that must cope with a cave file that doesn't parse.
+    pub fn find_file(config: &Config, start: &Path) -> anyhow::Result<PathBuf> {
+        match &config.cave {
+            Some(selection) => Self::cave_file(selection, &config.state_dir).with_context(|| format!("--cave {}", selection)),
+            None => Self::ancestor_files(start).into_iter().next().context("no cave found"),
+        }
+    }
+
+    /// The cave file of a `--cave` selection: a workspace, a cave file or
+    /// the name of a cave pi has built.
+    fn cave_file(selection: &str, state_dir: &Path) -> anyhow::Result<PathBuf> {
+        let path = Path::new(selection);
+        if selection.contains('/') || path.exists() {
+            return Ok(if path.is_dir() { path.join(Self::FILENAME) } else { path.to_path_buf() });
+        }
+        CaveRegistry::load(state_dir)
+            .get(selection)
+            .map(|workspace| workspace.join(Self::FILENAME))
+            .with_context(|| format!("no cave named {} has been built, give its path", selection))
+    }
+
+    /// The nearest cave in the ancestry of `start`. Caves further up are
+    /// only reported: a warning names them so nested caves can be told apart
+    /// with `--cave`.
+    pub fn find_in_ancestry(start_path: &Path, config_dir: &Path) -> Option<(PathBuf, Self)> {
+        let mut found: Option<(PathBuf, Self)> = None;
+        for cave_file in Self::ancestor_files(start_path) {
+            if let Some((nearest, _)) = &found {
+                log::warn!(
+                    "[cave] {} is nested in {}, using the nearest; pick one with --cave or PI_CAVE_PATH",
+                    nearest.display(), cave_file.display()
+                );
+                continue;
             }
-            if !current.pop() {
-                break;
+            match Self::load(&cave_file, config_dir) {
+                Ok(cave) => found = Some((cave_file, cave)),
+                Err(e) => {
+                    log::error!("failed to load cave {}: {}", cave_file.display(), e);
+                }
             }
         }
-        None
+        found
+    }
+
+    /// Every cave file from `start` up to the root, nearest first.
+    pub fn ancestor_files(start: &Path) -> Vec<PathBuf> {
+        start.ancestors()
+            .map(|dir| dir.join(Self::FILENAME))
+            .filter(|file| file.exists())
+            .collect()
     }
 
-    pub fn load(path: &Path) -> anyhow::Result<Self> {
+    /// Reads a cave file; `config_dir` holds the repositories.json that
+    /// `repo:` entries of `variants_from` are looked up in.
+    pub fn load(path: &Path, config_dir: &Path) -> anyhow::Result<Self> {
         let content = fs::read_to_string(path)
             .with_context(|| format!("Failed to read cave file: {:?}", path))?;
-        serde_json::from_str(&content)
-            .with_context(|| format!("Failed to parse cave file: {:?}", path))
+        let mut cave: Self = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse cave file: {:?}", path))?;
+        cave.load_shared_variants(path, config_dir)?;
+        Ok(cave)
+    }
+
+    /// Reads the variants of `variants_from` into `shared_variants`. Sets are
+    /// merged in order, their own includes first, so later definitions of a
+    /// variant extend and override earlier ones; the cave's own `variants`
+    /// go on top when looked up with `variant`.
+    pub fn load_shared_variants(&mut self, cave_file: &Path, config_dir: &Path) -> anyhow::Result<()> {
+        let base = cave_file.parent().unwrap_or(Path::new("."));
+        let mut chain = vec![cave_file.canonicalize().unwrap_or_else(|_| cave_file.to_path_buf())];
+        self.shared_variants = collect_variants(&self.variants_from, base, config_dir, &mut chain)?;
+        Ok(())
+    }
+
+    /// The effective definition of variant `name`: the shared one, if any,
+    /// merged with the cave's own.
+    pub fn variant(&self, name: &str) -> Option<CaveSettings> {
+        match (self.shared_variants.get(name), self.variants.get(name)) {
+            (Some(shared), Some(own)) => {
+                let mut settings = shared.clone();
+                settings.merge(own);
+                Some(settings)
+            }
+            (shared, own) => shared.or(own).cloned(),
+        }
+    }
+
+    /// Names of the cave's own and shared variants, sorted.
+    pub fn variant_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.variants.keys().chain(self.shared_variants.keys()).cloned().collect();
+        names.sort();
+        names.dedup();
+        names
     }
 
     pub fn save(&self, path: &Path) -> anyhow::Result<()> {
@@ -108,16 +275,89 @@ impl Cave {
             .with_context(|| format!("Failed to write cave file: {:?}", path))
     }
 
+    /// The cave settings with variant `variant_name` and everything it
+    /// `extends` merged on top.
     pub fn get_effective_settings(&self, variant_name: Option<&str>) -> anyhow::Result<CaveSettings> {
         let mut settings = self.settings.clone();
         if let Some(v_name) = variant_name {
             let v_name = v_name.strip_prefix(':').unwrap_or(v_name);
-            let v_settings = self.variants.get(v_name)
-                .context(format!("Variant '{}' not found in cave", v_name))?;
-            settings.merge(v_settings);
+            let mut layers = Vec::new();
+            self.variant_layers(v_name, &mut Vec::new(), &mut layers)?;
+            for (_, layer) in &layers {
+                settings.merge(layer);
+            }
+            settings.extends.clear();
         }
         Ok(settings)
     }
+
+    /// Appends the definitions `name` is made of to `layers`, ancestors first
+    /// (depth-first, in `extends` order), each variant once even when reached
+    /// twice. `chain` is the path from the requested variant, for cycles.
+    fn variant_layers(&self, name: &str, chain: &mut Vec<String>, layers: &mut Vec<(String, CaveSettings)>) -> anyhow::Result<()> {
+        if chain.iter().any(|n| n == name) {
+            chain.push(name.to_string());
+            anyhow::bail!("variant extends cycle: {}", chain.iter().map(|n| format!(":{}", n)).collect::<Vec<_>>().join(" -> "));
+        }
+        if layers.iter().any(|(n, _)| n == name) {
+            return Ok(());
+        }
+        let settings = match self.variant(name) {
+            Some(settings) => settings,
+            None if chain.is_empty() => anyhow::bail!("Variant '{}' not found in cave", name),
+            None => anyhow::bail!("Variant '{}' not found in cave, extended by :{}", name, chain.last().unwrap()),
+        };
+        chain.push(name.to_string());
+        for parent in &settings.extends {
+            self.variant_layers(parent, chain, layers)?;
+        }
+        chain.pop();
+        layers.push((name.to_string(), settings));
+        Ok(())
+    }
+}
+
+fn collect_variants(sources: &[String], base: &Path, config_dir: &Path, chain: &mut Vec<PathBuf>) -> anyhow::Result<HashMap<String, CaveSettings>> {
+    let mut variants: HashMap<String, CaveSettings> = HashMap::new();
+    for source in sources {
+        let path = variant_set_path(source, base, config_dir)?;
+        let canonical = path.canonicalize()
+            .with_context(|| format!("variants_from '{}': {} not found", source, path.display()))?;
+        if chain.contains(&canonical) {
+            let cycle: Vec<String> = chain.iter().chain([&canonical]).map(|p| p.display().to_string()).collect();
+            anyhow::bail!("variants_from cycle: {}", cycle.join(" -> "));
+        }
+        let content = fs::read_to_string(&canonical)
+            .with_context(|| format!("Failed to read variant set {}", canonical.display()))?;
+        let set: VariantSet = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse variant set {}", canonical.display()))?;
+
+        chain.push(canonical.clone());
+        let included = collect_variants(&set.variants_from, canonical.parent().unwrap_or(base), config_dir, chain)?;
+        chain.pop();
+        for (name, settings) in included.into_iter().chain(set.variants) {
+            variants.entry(name).or_default().merge(&settings);
+        }
+    }
+    Ok(variants)
+}
+
+/// The file behind a `variants_from` entry. `repo:<name>` names a set in
+/// some repository's `variants/`, so it must be a plain file name.
+fn variant_set_path(source: &str, base: &Path, config_dir: &Path) -> anyhow::Result<PathBuf> {
+    let Some(name) = source.strip_prefix(REPO_VARIANTS_PREFIX) else {
+        return Ok(base.join(source));
+    };
+    let mut components = Path::new(name).components();
+    if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+        anyhow::bail!("variants_from '{}': a repository variant set is named by a plain name", source);
+    }
+    let repositories_file = config_dir.join(REPOSITORIES_FILE);
+    let repos = Repositories::load_file(&repositories_file)?;
+    repos.repositories.iter()
+        .map(|r| Path::new(&r.path).join("variants").join(format!("{}.json", name)))
+        .find(|p| p.exists())
+        .with_context(|| format!("variants_from '{}': no repository has variants/{}.json", source, name))
 }
 
 #[cfg(test)]
@@ -152,6 +392,24 @@ mod tests {
         assert_eq!(base.command, Some(vec!["base_cmd".to_string()]));
     }
 
+    #[test]
+    fn test_settings_keys() {
+        let settings = CaveSettings {
+            packages: vec!["go".to_string()],
+            command: Some(vec!["zsh".to_string()]),
+            hosts: HashMap::from([("db".to_string(), "10.0.0.1".to_string())]),
+            host_build: vec!["kmod".to_string()],
+            strict: true,
+            bin_prefix: HashMap::from([("python".to_string(), "py311-".to_string())]),
+            extends: vec!["dev".to_string()],
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&settings).unwrap();
+        let keys: Vec<&String> = json.as_object().unwrap().keys().collect();
+        assert_eq!(keys.len(), CaveSettings::KEYS.len());
+        assert!(keys.iter().all(|k| CaveSettings::KEYS.contains(&k.as_str())));
+    }
+
     #[test]
     fn test_cave_load_with_command() {
         let json = r#"{
@@ -164,4 +422,134 @@ mod tests {
         let cave: Cave = serde_json::from_str(json).unwrap();
         assert_eq!(cave.settings.command, Some(vec!["tmux".to_string(), "new-session".to_string()]));
     }
+
+    #[test]
+    fn test_merge_keeps_first_occurrences() {
+        let list = |items: &[&str]| items.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let mut base = CaveSettings { host_build: list(&["kmod", "cuda"]), binds: list(&["/a", "/b"]), ..Default::default() };
+        let variant = CaveSettings { host_build: list(&["nvidia", "kmod", "nvidia"]), binds: list(&["/a", "/c"]), ..Default::default() };
+        base.merge(&variant);
+        assert_eq!(base.host_build, list(&["kmod", "cuda", "nvidia"]));
+        assert_eq!(base.binds, list(&["/a", "/b", "/c"]));
+    }
+
+    #[test]
+    fn test_find_nested_and_selected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut config = Config::new_test(tmp.path().join("pi"));
+        let outer = tmp.path().join("mono");
+        let inner = outer.join("services/api");
+        fs::create_dir_all(inner.join("src")).unwrap();
+        for (dir, name) in [(&outer, "mono"), (&inner, "api")] {
+            let json = format!(r#"{{"name": "{}", "workspace": "{}", "homedir": "/h"}}"#, name, dir.display());
+            fs::write(dir.join(Cave::FILENAME), json).unwrap();
+        }
+        assert_eq!(Cave::ancestor_files(&inner.join("src")), vec![inner.join(Cave::FILENAME), outer.join(Cave::FILENAME)]);
+        assert_eq!(Cave::find(&config, &inner.join("src")).unwrap().1.name, "api");
+
+        config.cave = Some(outer.display().to_string());
+        assert_eq!(Cave::find(&config, &inner).unwrap().1.name, "mono");
+        config.cave = Some("mono".to_string());
+        assert!(Cave::find(&config, &inner).is_none());
+        CaveRegistry::record(&config.state_dir, "mono", &outer).unwrap();
+        assert_eq!(Cave::find(&config, &inner).unwrap().0, outer.join(Cave::FILENAME));
+    }
+
+    #[test]
+    fn test_find_file_without_parsing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut config = Config::new_test(tmp.path().join("pi"));
+        let outer = tmp.path().join("mono");
+        let inner = outer.join("api");
+        fs::create_dir_all(&inner).unwrap();
+        fs::write(outer.join(Cave::FILENAME), "{ not json").unwrap();
+        fs::write(inner.join(Cave::FILENAME), "{}").unwrap();
+
+        assert_eq!(Cave::find_file(&config, &inner).unwrap(), inner.join(Cave::FILENAME));
+        config.cave = Some(outer.display().to_string());
+        assert_eq!(Cave::find_file(&config, &inner).unwrap(), outer.join(Cave::FILENAME));
+        config.cave = Some("unknown".to_string());
+        assert!(Cave::find_file(&config, &inner).is_err());
+        config.cave = None;
+        assert!(Cave::find_file(&config, tmp.path()).is_err());
+    }
+
+    #[test]
+    fn test_variant_extends() {
+        let json = r#"{
+            "workspace": "/w", "homedir": "/h",
+            "settings": {"packages": ["git"]},
+            "variants": {
+                "dev": {"packages": ["go"], "set": {"MODE": "dev"}},
+                "gpu": {"extends": ["dev"], "packages": ["cuda"]},
+                "dev-gpu": {"extends": ["dev", "gpu"], "set": {"MODE": "dev-gpu"}},
+                "ci": {"extends": ["dev-gpu"], "unset": ["MODE"]},
+                "loop-a": {"extends": ["loop-b"]},
+                "loop-b": {"extends": ["loop-a"]},
+                "broken": {"extends": ["missing"]}
+            }
+        }"#;
+        let cave: Cave = serde_json::from_str(json).unwrap();
+        let dev_gpu = cave.get_effective_settings(Some(":dev-gpu")).unwrap();
+        // dev is reached twice but merged once.
+        assert_eq!(dev_gpu.packages, vec!["git", "go", "cuda"]);
+        assert_eq!(dev_gpu.set["MODE"], "dev-gpu");
+        assert!(dev_gpu.extends.is_empty());
+        let ci = cave.get_effective_settings(Some("ci")).unwrap();
+        assert_eq!(ci.packages, vec!["git", "go", "cuda"]);
+        assert!(!ci.set.contains_key("MODE"));
+
+        let err = format!("{:#}", cave.get_effective_settings(Some("loop-a")).unwrap_err());
+        assert_eq!(err, "variant extends cycle: :loop-a -> :loop-b -> :loop-a");
+        let err = format!("{:#}", cave.get_effective_settings(Some("broken")).unwrap_err());
+        assert!(err.contains("'missing' not found in cave, extended by :broken"), "{}", err);
+    }
+
+    #[test]
+    fn test_shared_variants() {
+        let tmp = tempfile::tempdir().unwrap();
+        let shared = tmp.path().join("shared");
+        fs::create_dir_all(&shared).unwrap();
+        fs::write(shared.join("base.json"), r#"{"variants": {"ci": {"packages": ["go"], "set": {"CI": "1", "LEVEL": "base"}}}}"#).unwrap();
+        fs::write(shared.join("ci.json"), r#"{"variants_from": "base.json", "variants": {"ci": {"set": {"LEVEL": "ci"}}, "lint": {}}}"#).unwrap();
+        let cave_file = tmp.path().join(Cave::FILENAME);
+        fs::write(&cave_file, r#"{
+            "workspace": "/w", "homedir": "/h",
+            "variants_from": ["shared/ci.json"],
+            "variants": {"ci": {"packages": ["node"]}}
+        }"#).unwrap();
+
+        let cave = Cave::load(&cave_file, tmp.path()).unwrap();
+        assert_eq!(cave.variant_names(), vec!["ci", "lint"]);
+        let ci = cave.get_effective_settings(Some(":ci")).unwrap();
+        assert_eq!(ci.packages, vec!["go", "node"]);
+        assert_eq!(ci.set["LEVEL"], "ci");
+        // Shared variants stay out of the saved file.
+        cave.save(&cave_file).unwrap();
+        assert!(!fs::read_to_string(&cave_file).unwrap().contains("lint"));
+
+        fs::write(shared.join("base.json"), r#"{"variants_from": ["ci.json"]}"#).unwrap();
+        let err = format!("{:#}", Cave::load(&cave_file, tmp.path()).unwrap_err());
+        assert!(err.contains("variants_from cycle"), "{}", err);
+    }
+
+    #[test]
+    fn test_repo_variant_sets() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_dir = tmp.path().join("config");
+        let repo = tmp.path().join("repo");
+        fs::create_dir_all(repo.join("variants")).unwrap();
+        fs::create_dir_all(&config_dir).unwrap();
+        fs::write(repo.join("variants").join("ci.json"), r#"{"variants": {"ci": {"packages": ["go"]}}}"#).unwrap();
+        let repos = format!(r#"{{"repositories": [{{"name": "main", "path": "{}"}}]}}"#, repo.display());
+        fs::write(config_dir.join(REPOSITORIES_FILE), repos).unwrap();
+        let base = tmp.path();
+
+        assert_eq!(variant_set_path("repo:ci", base, &config_dir).unwrap(), repo.join("variants").join("ci.json"));
+        for name in ["repo:../ci", "repo:a/ci", "repo:/etc/ci", "repo:", "repo:.."] {
+            let err = variant_set_path(name, base, &config_dir).unwrap_err().to_string();
+            assert!(err.contains("plain name"), "{}: {}", name, err);
+        }
+        assert!(variant_set_path("repo:lint", base, &config_dir).is_err());
+    }
 }
@@ -0,0 +1,150 @@
+use crate::models::cave::{Cave, CaveSettings};
+use crate::models::lockfile::CaveLock;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+/// A shareable, machine-independent snapshot of a cave.
+/// Produced by `pi cave export` and consumed by `pi cave import`.
+/// Carries settings, variants (including option overrides), the resolved
+/// pin of every package query and the cave's lock, but no build artifacts.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CaveBundle {
+    pub format: u32,
+    pub name: String,
+    #[serde(default)]
+    pub settings: CaveSettings,
+    #[serde(default)]
+    pub variants: HashMap<String, CaveSettings>,
+    /// Fully qualified selector (repo/name=version) for each package query.
+    #[serde(default)]
+    pub pins: BTreeMap<String, String>,
+    /// The cave's `pi.cave.lock`, with the artifact hashes it records.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock: Option<CaveLock>,
+}
+
+impl CaveBundle {
+    pub const FORMAT: u32 = 1;
+
+    pub fn from_cave(cave: &Cave, pins: BTreeMap<String, String>, lock: Option<CaveLock>) -> Self {
+        Self {
+            format: Self::FORMAT,
+            name: cave.name.clone(),
+            settings: cave.settings.clone(),
+            variants: cave.variants.clone(),
+            pins,
+            lock,
+        }
+    }
+
+    /// All package queries of the cave and its variants, deduplicated.
+    pub fn queries(cave: &Cave) -> Vec<String> {
+        let mut queries: Vec<String> = cave.settings.packages.clone();
+        for settings in cave.variants.values() {
+            queries.extend(settings.packages.iter().cloned());
+        }
+        queries.sort();
+        queries.dedup();
+        queries
+    }
+
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let bundle: Self = serde_json::from_str(content).context("Failed to parse cave bundle")?;
+        if bundle.format > Self::FORMAT {
+            anyhow::bail!("Unsupported cave bundle format {} (max {})", bundle.format, Self::FORMAT);
+        }
+        Ok(bundle)
+    }
+
+    /// The exported lock for the recreated cave: when `pinned`, locked
+    /// queries become their pins, as the cave's selectors do.
+    pub fn lock(&self, pinned: bool) -> Option<CaveLock> {
+        let mut lock = self.lock.clone()?;
+        if pinned {
+            for package in lock.variants.values_mut().flatten() {
+                if let Some(pinned) = self.pins.get(&package.query) {
+                    package.query = pinned.clone();
+                }
+            }
+        }
+        Some(lock)
+    }
+
+    /// Recreates a cave rooted at `workspace`.
+    /// When `pinned` is set, package queries are replaced by their pins.
+    pub fn into_cave(self, workspace: PathBuf, homedir: PathBuf, pinned: bool) -> Cave {
+        let mut cave = Cave::new(workspace, homedir);
+        cave.name = self.name;
+        cave.settings = self.settings;
+        cave.variants = self.variants;
+
+        if pinned {
+            let pin = |packages: &mut Vec<String>| {
+                for query in packages.iter_mut() {
+                    if let Some(pinned) = self.pins.get(query) {
+                        *query = pinned.clone();
+                    }
+                }
+            };
+            pin(&mut cave.settings.packages);
+            cave.variants.values_mut().for_each(|s| pin(&mut s.packages));
+        }
+        cave
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::lockfile::LockedPackage;
+
+    fn sample_cave() -> Cave {
+        let mut cave = Cave::new(PathBuf::from("/src/proj"), PathBuf::from("/home/proj"));
+        cave.settings.packages = vec!["nodejs".to_string(), "go".to_string()];
+        cave.variants.insert("dev".to_string(), CaveSettings {
+            packages: vec!["go".to_string(), "npm:tsc".to_string()],
+            ..Default::default()
+        });
+        cave
+    }
+
+    #[test]
+    fn test_bundle_roundtrip_applies_pins() {
+        let cave = sample_cave();
+        assert_eq!(CaveBundle::queries(&cave), vec!["go", "nodejs", "npm:tsc"]);
+
+        let mut pins = BTreeMap::new();
+        pins.insert("go".to_string(), "pi/go=1.22.0".to_string());
+        pins.insert("nodejs".to_string(), "pi/nodejs=20.1.0".to_string());
+        let lock = CaveLock {
+            variants: BTreeMap::from([("default".to_string(), vec![LockedPackage {
+                query: "go".to_string(),
+                pkgname: "go".to_string(),
+                version: "1.22.0".to_string(),
+                repo: "pi".to_string(),
+                artifacts: Vec::new(),
+            }])]),
+        };
+        let content = serde_json::to_string(&CaveBundle::from_cave(&cave, pins, Some(lock))).unwrap();
+
+        let bundle = CaveBundle::parse(&content).unwrap();
+        assert_eq!(bundle.lock(true).unwrap().packages(None).unwrap()[0].query, "pi/go=1.22.0");
+        assert_eq!(bundle.lock(false).unwrap().packages(None).unwrap()[0].query, "go");
+        let imported = bundle.clone().into_cave(PathBuf::from("/other"), PathBuf::from("/h"), true);
+        assert_eq!(imported.name, "proj");
+        assert_eq!(imported.workspace, PathBuf::from("/other"));
+        assert_eq!(imported.settings.packages, vec!["pi/nodejs=20.1.0", "pi/go=1.22.0"]);
+        assert_eq!(imported.variants["dev"].packages, vec!["pi/go=1.22.0", "npm:tsc"]);
+
+        let unpinned = bundle.into_cave(PathBuf::from("/other"), PathBuf::from("/h"), false);
+        assert_eq!(unpinned.settings.packages, vec!["nodejs", "go"]);
+    }
+
+    #[test]
+    fn test_bundle_rejects_newer_format() {
+        let content = r#"{"format": 99, "name": "x"}"#;
+        assert!(CaveBundle::parse(content).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::Context;
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Which pi commands may run inside a cave (PI_CAVE set), loaded from
+/// `<config_dir>/cave_policy.toml`.
+///
+/// Commands are named by capability strings: the subcommand path joined by
+/// dots, e.g. `package.sync` or `cave.info`. Patterns may end in `.*` to
+/// cover a whole group, and `*` matches everything.
+///
+/// Security rationale: a cave runs untrusted project tooling with pi's
+/// caches and config mounted read-only. Commands that write those (sync,
+/// build, add, disk clean, config set) would either fail half-way or, if an
+/// admin mounted things writable, let code inside the cave change the host
+/// setup of every other cave. The defaults therefore only allow commands
+/// that read metadata. The policy file lives in the config dir, which caves
+/// see read-only, so a cave cannot widen its own policy. PI_CAVE itself is
+/// set by `pi cave run` and can be unset by the sandboxed process, so this
+/// is a guard against accidents, not a sandbox boundary.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct CavePolicy {
+    /// Capabilities allowed in addition to `DEFAULT_ALLOW`.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Capabilities denied even if allowed; deny wins over allow.
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl CavePolicy {
+    pub const FILENAME: &'static str = "cave_policy.toml";
+
+    /// Read-only commands allowed inside a cave unless denied.
+    pub const DEFAULT_ALLOW: &'static [&'static str] = &[
+        "version",
+        "repo.list",
+        "package.list",
+        "package.info",
+        "package.search",
+        "package.resolve",
+        "package.why",
+        "package.graph",
+        "cave.info",
+        "cave.status",
+    ];
+
+    /// Loads the policy, an empty policy (defaults only) if the file is missing.
+    pub fn load(config_dir: &Path) -> anyhow::Result<Self> {
+        let path = config_dir.join(Self::FILENAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read cave policy: {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("Failed to parse cave policy: {}", path.display()))
+    }
+
+    pub fn is_allowed(&self, capability: &str) -> bool {
+        if self.deny.iter().any(|p| matches_capability(p, capability)) {
+            return false;
+        }
+        Self::DEFAULT_ALLOW.iter().any(|p| matches_capability(p, capability))
+            || self.allow.iter().any(|p| matches_capability(p, capability))
+    }
+}
+
+/// Capability string of the parsed command line, e.g. `package.sync`.
+pub fn capability(matches: &ArgMatches) -> String {
+    let mut parts = Vec::new();
+    let mut current = matches;
+    while let Some((name, sub)) = current.subcommand() {
+        parts.push(name);
+        current = sub;
+    }
+    parts.join(".")
+}
+
+fn matches_capability(pattern: &str, capability: &str) -> bool {
+    if pattern == "*" || pattern == capability {
+        return true;
+    }
+    pattern.strip_suffix(".*")
+        .is_some_and(|group| capability.strip_prefix(group).is_some_and(|rest| rest.starts_with('.')))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_defaults_allow_and_deny() {
+        let default = CavePolicy::default();
+        assert!(default.is_allowed("package.info"));
+        assert!(!default.is_allowed("package.sync"));
+        assert!(!default.is_allowed("cave.build"));
+
+        let policy: CavePolicy = toml::from_str(r#"
+            allow = ["package.sync", "config.*"]
+            deny = ["package.resolve"]
+        "#).unwrap();
+        assert!(policy.is_allowed("package.sync"));
+        assert!(policy.is_allowed("config.get"));
+        assert!(!policy.is_allowed("configure.get"));
+        assert!(!policy.is_allowed("package.resolve"));
+        assert!(policy.is_allowed("cave.info"));
+
+        use clap::CommandFactory;
+        let matches = crate::cli::parser::Cli::command()
+            .try_get_matches_from(["pi", "-j", "2", "package", "sync", "go"]).unwrap();
+        assert_eq!(capability(&matches), "package.sync");
+
+        let locked = CavePolicy { deny: vec!["*".to_string()], ..Default::default() };
+        assert!(!locked.is_allowed("version"));
+    }
+}
@@ -0,0 +1,35 @@
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Caves pi has built on this machine (name -> workspace), kept in
+/// `<state_dir>/caves.json` so they can be found by name, e.g. by `pi serve`.
+pub struct CaveRegistry;
+
+impl CaveRegistry {
+    pub const FILENAME: &'static str = "caves.json";
+
+    pub fn load(state_dir: &Path) -> BTreeMap<String, PathBuf> {
+        fs::read_to_string(state_dir.join(Self::FILENAME))
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    /// Records the workspace of `name`, rewriting the file only when it changed.
+    pub fn record(state_dir: &Path, name: &str, workspace: &Path) -> anyhow::Result<()> {
+        let mut caves = Self::load(state_dir);
+        if caves.get(name).is_some_and(|w| w == workspace) {
+            return Ok(());
+        }
+        caves.insert(name.to_string(), workspace.to_path_buf());
+
+        fs::create_dir_all(state_dir).context("Failed to create state directory")?;
+        let path = state_dir.join(Self::FILENAME);
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&caves)?)
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &path).with_context(|| format!("Failed to replace {}", path.display()))
+    }
+}
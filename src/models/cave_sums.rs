@@ -0,0 +1,153 @@
+use crate::utils::crypto::calculate_file_checksum;
+use anyhow::Context;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// SHA-256 of every artifact fetched for a cave, kept next to `pi.cave.json`
+/// as `pi.cave.sums` and meant to be committed with the project. The first
+/// fetch of a URL records its hash; later builds fail when the artifact no
+/// longer matches, e.g. because upstream re-tagged a release.
+pub struct CaveSums {
+    path: PathBuf,
+    entries: Mutex<BTreeMap<String, String>>,
+    changed: AtomicBool,
+}
+
+impl CaveSums {
+    pub const FILENAME: &'static str = "pi.cave.sums";
+    const HEADER: &'static str = "# sha256 of artifacts fetched for this cave (pi.cave.sums); commit this file\n";
+
+    /// Loads the sums of the cave in `workspace`; a missing file is empty.
+    pub fn load(workspace: &Path) -> anyhow::Result<Self> {
+        let path = workspace.join(Self::FILENAME);
+        let entries = match fs::read_to_string(&path) {
+            Ok(content) => parse(&content).with_context(|| format!("Invalid {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        Ok(Self { path, entries: Mutex::new(entries), changed: AtomicBool::new(false) })
+    }
+
+    /// Checks the artifact fetched from `url` against its recorded hash,
+    /// recording it when the URL is new.
+    pub fn verify(&self, url: &str, file: &Path) -> anyhow::Result<()> {
+        let actual = calculate_file_checksum(file, 64)
+            .with_context(|| format!("Failed to hash {}", file.display()))?;
+        self.verify_hash(url, &actual, &file.display().to_string())
+    }
+
+    /// `verify` for an artifact hashed elsewhere (a streamed download);
+    /// `what` names it in the error.
+    pub fn verify_hash(&self, url: &str, actual: &str, what: &str) -> anyhow::Result<()> {
+        let mut entries = self.entries.lock();
+        match entries.get(url) {
+            Some(expected) if expected == actual => Ok(()),
+            Some(expected) => anyhow::bail!(
+                "checksum drift for {}: {} records sha256 {}, fetched artifact {} has {}. \
+                 If the change is expected, remove the line from {} and build again",
+                url, Self::FILENAME, expected, what, actual, Self::FILENAME
+            ),
+            None => {
+                log::info!("[sums] recording {}", url);
+                entries.insert(url.to_string(), actual.to_string());
+                self.changed.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+
+    /// The recorded hash of `url`.
+    pub fn get(&self, url: &str) -> Option<String> {
+        self.entries.lock().get(url).cloned()
+    }
+
+    /// Expects `hash` for `url`, as a lockfile records it: fetches are then
+    /// verified against it even when this file doesn't have the URL yet.
+    pub fn expect(&self, url: &str, hash: &str) -> anyhow::Result<()> {
+        let mut entries = self.entries.lock();
+        match entries.get(url) {
+            Some(recorded) if recorded != hash => anyhow::bail!(
+                "{} records sha256 {} for {}, the lock {}", Self::FILENAME, recorded, url, hash
+            ),
+            Some(_) => Ok(()),
+            None => {
+                entries.insert(url.to_string(), hash.to_string());
+                self.changed.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether the artifact of `url` has a recorded hash.
+    pub fn contains(&self, url: &str) -> bool {
+        self.entries.lock().contains_key(url)
+    }
+
+    /// Writes the file when new artifacts were recorded.
+    pub fn save(&self) -> anyhow::Result<()> {
+        if !self.changed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let tmp = self.path.with_extension("sums.tmp");
+        fs::write(&tmp, render(&self.entries.lock()))
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path).with_context(|| format!("Failed to replace {}", self.path.display()))?;
+        self.changed.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Lines of `<sha256>  <url>`, like `sha256sum` output; `#` starts a comment.
+fn parse(content: &str) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut entries = BTreeMap::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (hash, url) = line.split_once(char::is_whitespace)
+            .with_context(|| format!("line {}: expected '<sha256>  <url>'", n + 1))?;
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            anyhow::bail!("line {}: '{}' is not a sha256", n + 1, hash);
+        }
+        entries.insert(url.trim().to_string(), hash.to_ascii_lowercase());
+    }
+    Ok(entries)
+}
+
+fn render(entries: &BTreeMap<String, String>) -> String {
+    let mut out = CaveSums::HEADER.to_string();
+    for (url, hash) in entries {
+        out.push_str(&format!("{}  {}\n", hash, url));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sums_record_and_drift() {
+        let tmp = tempfile::tempdir().unwrap();
+        let artifact = tmp.path().join("go.tar.gz");
+        fs::write(&artifact, "v1").unwrap();
+
+        let sums = CaveSums::load(tmp.path()).unwrap();
+        sums.verify("https://example.com/go.tar.gz", &artifact).unwrap();
+        sums.save().unwrap();
+        let content = fs::read_to_string(tmp.path().join(CaveSums::FILENAME)).unwrap();
+        assert!(content.ends_with("  https://example.com/go.tar.gz\n"), "{}", content);
+
+        let sums = CaveSums::load(tmp.path()).unwrap();
+        sums.verify("https://example.com/go.tar.gz", &artifact).unwrap();
+        fs::write(&artifact, "v1 retagged").unwrap();
+        let err = sums.verify("https://example.com/go.tar.gz", &artifact).unwrap_err().to_string();
+        assert!(err.contains("checksum drift"), "{}", err);
+
+        assert!(parse("abc https://x").is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use crate::utils::crypto::calculate_file_checksum;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Caves whose environment the shell hook may apply to the host shell, like
+/// direnv's `allow`: the canonical path of each `pi.cave.json` with the
+/// sha256 of the content allowed, kept in `<state_dir>/allowed_caves.json`.
+/// Any edit of the file needs a new `pi cave allow`.
+pub struct CaveTrust;
+
+impl CaveTrust {
+    pub const FILENAME: &'static str = "allowed_caves.json";
+
+    fn load(state_dir: &Path) -> BTreeMap<PathBuf, String> {
+        fs::read_to_string(state_dir.join(Self::FILENAME))
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(state_dir: &Path, allowed: &BTreeMap<PathBuf, String>) -> anyhow::Result<()> {
+        fs::create_dir_all(state_dir).context("Failed to create state directory")?;
+        let path = state_dir.join(Self::FILENAME);
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(allowed)?)
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &path).with_context(|| format!("Failed to replace {}", path.display()))
+    }
+
+    /// Allows the current content of `cave_file`.
+    pub fn allow(state_dir: &Path, cave_file: &Path) -> anyhow::Result<()> {
+        let (path, digest) = identify(cave_file)?;
+        let mut allowed = Self::load(state_dir);
+        allowed.insert(path, digest);
+        Self::save(state_dir, &allowed)
+    }
+
+    /// Forgets `cave_file`; false if it wasn't allowed.
+    pub fn revoke(state_dir: &Path, cave_file: &Path) -> anyhow::Result<bool> {
+        let path = cave_file.canonicalize().with_context(|| format!("Failed to resolve {}", cave_file.display()))?;
+        let mut allowed = Self::load(state_dir);
+        if allowed.remove(&path).is_none() {
+            return Ok(false);
+        }
+        Self::save(state_dir, &allowed)?;
+        Ok(true)
+    }
+
+    /// Whether `cave_file` was allowed with its current content.
+    pub fn is_allowed(state_dir: &Path, cave_file: &Path) -> bool {
+        let Ok((path, digest)) = identify(cave_file) else { return false };
+        Self::load(state_dir).get(&path) == Some(&digest)
+    }
+}
+
+/// The canonical path of `cave_file` and the sha256 of its content.
+fn identify(cave_file: &Path) -> anyhow::Result<(PathBuf, String)> {
+    let path = cave_file.canonicalize().with_context(|| format!("Failed to resolve {}", cave_file.display()))?;
+    let digest = calculate_file_checksum(&path, 64).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok((path, digest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allow_follows_content() {
+        let tmp = tempfile::tempdir().unwrap();
+        let state = tmp.path().join("state");
+        let cave_file = tmp.path().join("pi.cave.json");
+        fs::write(&cave_file, r#"{"name": "web"}"#).unwrap();
+        assert!(!CaveTrust::is_allowed(&state, &cave_file));
+
+        CaveTrust::allow(&state, &cave_file).unwrap();
+        assert!(CaveTrust::is_allowed(&state, &cave_file));
+        assert!(CaveTrust::is_allowed(&state, &tmp.path().join(".").join("pi.cave.json")));
+
+        fs::write(&cave_file, r#"{"name": "web", "settings": {"set": {"BASH_ENV": "/tmp/x"}}}"#).unwrap();
+        assert!(!CaveTrust::is_allowed(&state, &cave_file));
+
+        CaveTrust::allow(&state, &cave_file).unwrap();
+        assert!(CaveTrust::revoke(&state, &cave_file).unwrap());
+        assert!(!CaveTrust::is_allowed(&state, &cave_file));
+        assert!(!CaveTrust::revoke(&state, &cave_file).unwrap());
+    }
+}
@@ -1,8 +1,16 @@
+use crate::models::cave::Cave;
 use crate::models::package_entry::PackageList;
 use crate::models::repository::Repositories;
+use crate::models::settings::Settings;
+use crate::models::types::Platform;
+use crate::utils::fs::sanitize_name;
+use crate::utils::table::SortSpec;
+use crate::utils::timings::Timings;
 use crate::models::version_entry::VersionList;
+use crate::services::cache::meta::MetaStore;
+use crate::services::db::Db;
 use dashmap::DashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, OnceLock};
 
 #[derive(Debug, Clone)]
@@ -15,9 +23,33 @@ pub struct Config {
     pub cache_download_dir: PathBuf,
     pub cache_packages_dir: PathBuf,
     pub cache_pilocals_dir: PathBuf,
+    /// Prefix `pi package install` links packages into.
+    pub global_dir: PathBuf,
     pub force: bool,
     pub rebuild: bool,
     pub no_sync: bool,
+    /// No network access (`--offline`): metadata comes from the cache only
+    /// and fetches must find their artifact in the download cache.
+    pub offline: bool,
+    /// Keep the temporary home of each `Run` step for debugging.
+    pub keep_build_home: bool,
+    /// Consent to run the `Run` steps of `requires_host_build` versions
+    /// outside the sandbox (`--allow-host-build`), for every package.
+    pub allow_host_build: bool,
+    /// Refuse builds with unchecked fetches, unpinned packages or Run steps
+    /// that could reach the network (`--strict`, or `strict` in a cave).
+    pub strict: bool,
+    /// Platform versions are resolved for (`--target`), the host by default.
+    pub target: Platform,
+    /// User row order for tables (`--sort`), applied before each table's canonical order.
+    pub sort: Option<SortSpec>,
+    /// Cave chosen with `--cave` or PI_CAVE_PATH: a workspace, cave file or
+    /// cave name, used instead of looking up from the current directory.
+    pub cave: Option<String>,
+    /// Variant commands act on when not given one: inside a cave, the one
+    /// it was entered with (PI_CAVE_VARIANT).
+    pub cave_variant: Option<String>,
+    pub settings: Settings,
     pub state: Arc<State>,
 }
 
@@ -34,14 +66,54 @@ pub struct State {
     /// The Mutex is only held during the actual network transfer.
     /// Keyed by resource URL.
     pub download_locks: DashMap<String, Arc<parking_lot::Mutex<()>>>,
+    /// Per-package locks held while a pipeline of the package runs, so
+    /// parallel builds never share its build cache file or step directories.
+    /// Keyed by package name.
+    pub build_locks: DashMap<String, Arc<parking_lot::Mutex<()>>>,
+    /// Selectors that failed to resolve after a sync during this run.
+    pub unresolved: dashmap::DashSet<String>,
+    /// `resolve::repo_generation` of the repositories, computed on the
+    /// first miss: it walks every recipe file.
+    pub repo_generation: OnceLock<String>,
+    /// Background syncs of stale managed package lists, keyed like
+    /// `version_lists` (see `services::manager_cache`).
+    pub refreshes: parking_lot::Mutex<std::collections::HashMap<String, std::thread::JoinHandle<()>>>,
+    /// State database, created lazily on first use (None if unusable); it
+    /// opens the file per operation, so other pi processes share it.
+    pub db: OnceLock<Option<Db>>,
+    /// Compact package/version list store, opened lazily (None with the file format).
+    pub meta_db: OnceLock<Option<Db>>,
+    /// Time spent per phase, printed by `--timings`.
+    pub timings: Timings,
+}
+
+impl State {
+    /// The state database in `state_dir`, opened on first use.
+    pub fn db(&self, state_dir: &Path) -> Option<&Db> {
+        self.db.get_or_init(|| match Db::open(state_dir) {
+            Ok(db) => Some(db),
+            Err(e) => {
+                log::debug!("[db] unavailable: {:#}", e);
+                None
+            }
+        }).as_ref()
+    }
 }
 
 impl Config {
-    pub fn new(force: bool, rebuild: bool, no_sync: bool) -> Self {
+    /// Directory holding repositories.json and settings.toml.
+    pub fn config_home() -> PathBuf {
+        xdg::BaseDirectories::with_prefix("pi")
+            .get_config_home()
+            .expect("Failed to get config home")
+    }
+
+    /// Creates the config with flags defaulted from `settings`; CLI flags are applied by the caller.
+    pub fn new(settings: Settings) -> Self {
         let xdg = xdg::BaseDirectories::with_prefix("pi");
 
         let cache_dir = xdg.get_cache_home().expect("Failed to get cache home");
-        let config_dir = xdg.get_config_home().expect("Failed to get config home");
+        let config_dir = Self::config_home();
         let state_dir = xdg.get_state_home().expect("Failed to get state home");
 
         let state_home_dir = xdg.create_state_directory("home")
@@ -55,6 +127,7 @@ impl Config {
 	    .expect("Failed to create packages directory");
         let cache_pilocals_dir = xdg.create_cache_directory("pilocals")
 	    .expect("Failed to create pilocals directory");
+        let global_dir = xdg.get_data_home().expect("Failed to get data home").join("global");
 
         Self {
             cache_dir,
@@ -65,13 +138,24 @@ impl Config {
             cache_download_dir,
             cache_packages_dir,
             cache_pilocals_dir,
-            force,
-            rebuild,
-            no_sync,
+            global_dir,
+            force: false,
+            rebuild: false,
+            no_sync: settings.no_sync,
+            offline: false,
+            keep_build_home: settings.keep_build_home,
+            allow_host_build: false,
+            strict: false,
+            target: Platform::default(),
+            sort: None,
+            cave: None,
+            cave_variant: None,
+            settings,
             state: Arc::new(State::default()),
         }
     }
 
+    #[cfg(test)]
     pub fn new_test(base_dir: PathBuf) -> Self {
         let cache_dir = base_dir.join("cache");
         let config_dir = base_dir.join("config");
@@ -100,15 +184,30 @@ impl Config {
             cache_download_dir: download_dir,
             cache_packages_dir: packages_dir,
             cache_pilocals_dir: pilocals_dir,
+            global_dir: base_dir.join("data/global"),
             force: false,
             rebuild: false,
             no_sync: false,
+            offline: false,
+            keep_build_home: false,
+            allow_host_build: false,
+            strict: false,
+            target: Platform::default(),
+            sort: None,
+            cave: None,
+            cave_variant: None,
+            settings: Settings::default(),
             state: Arc::new(State::default()),
         }
     }
 
+    /// Executables implementing custom pipeline steps (`pi-step-<kind>`).
+    pub fn plugins_dir(&self) -> PathBuf {
+        self.config_dir.join("plugins")
+    }
+
     pub fn repositories_file(&self) -> PathBuf {
-        self.config_dir.join("repositories.json")
+        self.config_dir.join(crate::models::repository::REPOSITORIES_FILE)
     }
 
     pub fn package_cache_file(&self, repo_name: &str) -> PathBuf {
@@ -116,7 +215,20 @@ impl Config {
     }
 
     pub fn version_cache_file(&self, repo_name: &str, safe_name: &str) -> PathBuf {
-        self.cache_meta_dir.join(format!("version-{}-{}.json", repo_name, safe_name))
+        self.cache_meta_dir.join(format!("{}{}.json", Self::version_cache_prefix(repo_name), safe_name))
+    }
+
+    /// Start of the names of `repo_name`'s version lists. A `-` in the repo
+    /// name is escaped, so the first `-` after it ends the repo: `main` and
+    /// `main-extra` can't claim each other's lists.
+    pub fn version_cache_prefix(repo_name: &str) -> String {
+        format!("version-{}-", repo_name.replace('%', "%25").replace('-', "%2D"))
+    }
+
+    /// Held shared by running builds and exclusively by `pi disk gc`, so
+    /// nothing a build is using gets evicted under it.
+    pub fn build_lock_file(&self) -> PathBuf {
+        self.state_dir.join("builds.lock")
     }
 
     pub fn get_user(&self) -> String {
@@ -135,11 +247,89 @@ impl Config {
         std::env::var("PI_CAVE").is_ok()
     }
 
-    pub fn pilocal_path(&self, cave_name: &str, _variant: Option<&str>) -> PathBuf {
-        self.cache_pilocals_dir.join(cave_name)
+    /// Defaults of commands run inside a cave: the caches are read-only, so
+    /// never sync; act on the cave of `workspace` (PI_WORKSPACE) unless
+    /// `--cave` or PI_CAVE_PATH chose one, as long as the sandbox shows its
+    /// file; and on `variant` (PI_CAVE_VARIANT), the one the cave was
+    /// entered with.
+    pub fn apply_cave_defaults(&mut self, workspace: Option<String>, variant: Option<String>) {
+        self.no_sync = true;
+        if self.cave.is_none() {
+            self.cave = workspace.filter(|w| Path::new(w).join(Cave::FILENAME).is_file());
+        }
+        self.cave_variant = variant.filter(|v| v.starts_with(':'));
+    }
+
+    /// `variant`, else the one commands default to (see `cave_variant`).
+    pub fn variant_or_default(&self, variant: Option<String>) -> Option<String> {
+        variant.or_else(|| self.cave_variant.clone())
     }
 
+    /// The pilocal of a cave, or of one of its variants (`<cave>_dev` for
+    /// ":dev"; no ':' since its bin dir goes into PATH).
+    pub fn pilocal_path(&self, cave_name: &str, variant: Option<&str>) -> PathBuf {
+        match variant {
+            Some(variant) => self.cache_pilocals_dir.join(sanitize_name(&format!("{}{}", cave_name, variant))),
+            None => self.cache_pilocals_dir.join(cave_name),
+        }
+    }
+
+    /// The state database. Returns None when it cannot be used at all;
+    /// bookkeeping is then skipped.
+    pub fn db(&self) -> Option<&Db> {
+        self.state.db(&self.state_dir)
+    }
+
+    /// The compact list store (`meta_format = "compact"`), None when lists
+    /// are kept as files or the store can't be used.
+    pub fn meta_db(&self) -> Option<&Db> {
+        self.state.meta_db.get_or_init(|| MetaStore::open(self)).as_ref()
+    }
+
+    /// Records the use of a cache entry for LRU eviction by `disk gc`.
+    pub fn touch(&self, path: &std::path::Path) {
+        if let Some(db) = self.db()
+            && let Err(e) = db.touch(path)
+        {
+            log::debug!("[db] touch {} failed: {:#}", path.display(), e);
+        }
+    }
+
+    /// Substitutes `${PKG_DIR}` (`@PACKAGES_DIR`) in Run commands and Link
+    /// sources; see `PathVars::command`.
     pub fn resolve_packages_dir(&self, s: &str) -> String {
-        s.replace("@PACKAGES_DIR", self.cache_packages_dir.to_str().unwrap_or(""))
+        crate::utils::subst::PathVars { packages: Some(&self.cache_packages_dir), ..Default::default() }.command(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cave_defaults() {
+        let tmp = tempfile::tempdir().unwrap();
+        let workspace = tmp.path().join("app");
+        std::fs::create_dir_all(&workspace).unwrap();
+        let workspace = workspace.to_string_lossy().to_string();
+
+        // A workspace whose cave file the sandbox doesn't show is left to the lookup from the cwd.
+        let mut config = Config::new_test(tmp.path().to_path_buf());
+        config.apply_cave_defaults(Some(workspace.clone()), Some(":dev".to_string()));
+        assert!(config.no_sync);
+        assert_eq!(config.cave, None);
+        assert_eq!(config.variant_or_default(None).as_deref(), Some(":dev"));
+        assert_eq!(config.variant_or_default(Some(":ci".to_string())).as_deref(), Some(":ci"));
+
+        std::fs::write(Path::new(&workspace).join(Cave::FILENAME), "{}").unwrap();
+        let mut config = Config::new_test(tmp.path().to_path_buf());
+        config.apply_cave_defaults(Some(workspace.clone()), Some("dev".to_string()));
+        assert_eq!(config.cave.as_deref(), Some(workspace.as_str()));
+        assert_eq!(config.variant_or_default(None), None);
+
+        // `--cave` wins.
+        let mut config = Config { cave: Some("other".to_string()), ..Config::new_test(tmp.path().to_path_buf()) };
+        config.apply_cave_defaults(Some(workspace), None);
+        assert_eq!(config.cave.as_deref(), Some("other"));
     }
 }
@@ -1,6 +1,7 @@
 use crate::models::config::State;
 use crate::models::package_entry::{ManagerEntry, PackageEntry};
-use crate::models::version_entry::VersionEntry;
+use crate::models::repository::HostPolicy;
+use crate::models::version_entry::{VersionEntry, VersionList};
 use crate::models::types::{OS, Arch};
 use allocative::{Allocative, Key, Visitor};
 use parking_lot::RwLock;
@@ -10,8 +11,20 @@ use starlark::values::{AllocValue, Heap, StarlarkValue, Value, starlark_value};
 use std::fmt::{self, Display};
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 use std::collections::HashMap;
 
+/// The cave a recipe is evaluated for, as seen by `cave_info()`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CaveInfo {
+    pub name: String,
+    /// The variant being built (":dev"), if any.
+    pub variant: Option<String>,
+    /// Package selectors the cave (variant) declares, as written.
+    pub packages: Vec<String>,
+    pub workspace: PathBuf,
+}
+
 /// The Context struct serves as the bridge between the Rust host and the Starlark guest environment.
 ///
 /// It is used for:
@@ -40,6 +53,23 @@ pub struct Context {
     /// Collected version entries for a package during its discover function.
     pub versions: RwLock<Vec<VersionEntry>>,
     pub options: HashMap<String, String>,
+    /// Versions already in the saved list, offered to discover functions for delta syncs.
+    pub known_versions: Vec<String>,
+    /// High-water mark of the saved list (its newest version), if any.
+    pub known_latest: Option<String>,
+    /// Set by `set_incremental()`: collected versions are merged into the saved list.
+    pub incremental: AtomicBool,
+    /// Where the state database lives, for `state_get`/`state_set`.
+    pub state_dir: PathBuf,
+    /// Namespace of the recipe's `state_set` keys: "<repo>/<recipe path>".
+    pub state_scope: String,
+    /// Set while a cave build re-evaluates the recipe, read by `cave_info()`.
+    pub cave: Option<CaveInfo>,
+    /// Hosts `download()` and the like may reach; None for trusted repos.
+    pub host_policy: Option<HostPolicy>,
+    /// Set when a download of the recipe failed, so finding no versions is
+    /// an error rather than a miss worth caching.
+    pub fetch_failed: AtomicBool,
     #[serde(skip)]
     pub state: Arc<State>,
 }
@@ -58,6 +88,14 @@ impl Context {
             managers: RwLock::new(Vec::new()),
             versions: RwLock::new(Vec::new()),
             options: HashMap::new(),
+            known_versions: Vec::new(),
+            known_latest: None,
+            incremental: AtomicBool::new(false),
+            state_dir: PathBuf::new(),
+            state_scope: String::new(),
+            cave: None,
+            host_policy: None,
+            fetch_failed: AtomicBool::new(false),
             state,
         }
     }
@@ -67,6 +105,26 @@ impl Context {
         self
     }
 
+    pub fn with_known(mut self, known: &VersionList) -> Self {
+        self.known_versions = known.versions.iter().map(|v| v.version.raw.clone()).collect();
+        self.known_latest = known.latest().map(|v| v.version.raw.clone());
+        self
+    }
+
+    pub fn with_state_scope(mut self, state_dir: PathBuf, scope: String) -> Self {
+        self.state_dir = state_dir;
+        self.state_scope = scope;
+        self
+    }
+
+    /// Fails when the recipe's repo may not download `url`.
+    pub fn check_host(&self, url: &str) -> anyhow::Result<()> {
+        match &self.host_policy {
+            Some(policy) => policy.check(url),
+            None => Ok(()),
+        }
+    }
+
     pub fn display_name(&self) -> String {
         let p = self.filename.split(':').next().unwrap_or(&self.filename);
         PathBuf::from(p)
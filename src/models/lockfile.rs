@@ -0,0 +1,198 @@
+use crate::models::cave_sums::CaveSums;
+use crate::models::version_entry::{InstallStep, VersionEntry};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// What the selectors of a cave resolved to, kept next to `pi.cave.json` as
+/// `pi.cave.lock` and meant to be committed with the project. `cave resolve`
+/// and `cave build` write it; `cave build --locked` fails when resolving
+/// gives anything else, so every machine builds the same versions.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct CaveLock {
+    /// Locked packages per variant (`default` for the cave itself), sorted by query.
+    pub variants: BTreeMap<String, Vec<LockedPackage>>,
+}
+
+/// A resolved selector, one per package of the build graph.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LockedPackage {
+    /// The selector, from the cave or a build dependency.
+    pub query: String,
+    pub pkgname: String,
+    pub version: String,
+    pub repo: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub artifacts: Vec<LockedArtifact>,
+}
+
+/// A Fetch of a locked package, with its sha256 once `pi.cave.sums` has it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LockedArtifact {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+impl CaveLock {
+    pub const FILENAME: &'static str = "pi.cave.lock";
+
+    /// The lock of the cave in `workspace`; None when there is none.
+    pub fn load(workspace: &Path) -> anyhow::Result<Option<Self>> {
+        let path = workspace.join(Self::FILENAME);
+        match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map(Some)
+                .with_context(|| format!("Invalid {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, workspace: &Path) -> anyhow::Result<()> {
+        let path = workspace.join(Self::FILENAME);
+        let tmp = path.with_extension("lock.tmp");
+        let content = serde_json::to_string_pretty(self)? + "\n";
+        fs::write(&tmp, content).with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &path).with_context(|| format!("Failed to replace {}", path.display()))
+    }
+
+    /// Replaces the packages of `variant` and saves the lock when they changed.
+    /// Returns whether it did.
+    pub fn update(workspace: &Path, variant: Option<&str>, packages: Vec<LockedPackage>) -> anyhow::Result<bool> {
+        let mut lock = Self::load(workspace)?.unwrap_or_default();
+        if lock.variants.get(&variant_key(variant)) == Some(&packages) {
+            return Ok(false);
+        }
+        lock.variants.insert(variant_key(variant), packages);
+        lock.save(workspace)?;
+        Ok(true)
+    }
+
+    pub fn packages(&self, variant: Option<&str>) -> Option<&[LockedPackage]> {
+        self.variants.get(&variant_key(variant)).map(Vec::as_slice)
+    }
+
+    /// The variants the lock has packages for, None for the cave itself.
+    pub fn locked_variants(&self) -> impl Iterator<Item = Option<&str>> {
+        self.variants.keys().map(|k| (k != DEFAULT_VARIANT).then_some(k.as_str()))
+    }
+}
+
+/// Key of the cave's own packages in `CaveLock::variants`.
+const DEFAULT_VARIANT: &str = "default";
+
+fn variant_key(variant: Option<&str>) -> String {
+    variant.unwrap_or(DEFAULT_VARIANT).to_string()
+}
+
+/// Lock entries of a build graph (query -> version and repo), with the
+/// hashes `sums` recorded for their artifacts.
+pub fn locked_packages<'a>(
+    graph: impl IntoIterator<Item = (&'a String, &'a (VersionEntry, String))>,
+    sums: Option<&CaveSums>,
+) -> Vec<LockedPackage> {
+    let mut packages: Vec<LockedPackage> = graph.into_iter()
+        .map(|(query, (version, repo))| LockedPackage {
+            query: query.clone(),
+            pkgname: version.pkgname.clone(),
+            version: version.version.to_string(),
+            repo: repo.clone(),
+            artifacts: version.pipeline.iter()
+                .filter_map(|step| match step {
+                    InstallStep::Fetch { url, .. } => Some(LockedArtifact {
+                        url: url.clone(),
+                        sha256: sums.and_then(|s| s.get(url)),
+                    }),
+                    _ => None,
+                })
+                .collect(),
+        })
+        .collect();
+    packages.sort_by(|a, b| a.query.cmp(&b.query));
+    packages
+}
+
+/// How `current` differs from `locked`, one line per difference. Hashes
+/// only differ when both are known.
+pub fn lock_differences(locked: &[LockedPackage], current: &[LockedPackage]) -> Vec<String> {
+    let mut differences = Vec::new();
+    let find = |list: &'_ [LockedPackage], query: &str| list.iter().find(|p| p.query == query).cloned();
+    for now in current {
+        let Some(was) = find(locked, &now.query) else {
+            differences.push(format!("{} is not locked", now.query));
+            continue;
+        };
+        if (&was.pkgname, &was.version, &was.repo) != (&now.pkgname, &now.version, &now.repo) {
+            differences.push(format!(
+                "{} is locked to {}/{}={} but resolves to {}/{}={}",
+                now.query, was.repo, was.pkgname, was.version, now.repo, now.pkgname, now.version
+            ));
+            continue;
+        }
+        let urls = |p: &LockedPackage| p.artifacts.iter().map(|a| a.url.clone()).collect::<Vec<_>>();
+        if urls(&was) != urls(now) {
+            differences.push(format!("{} fetches {:?}, locked {:?}", now.query, urls(now), urls(&was)));
+        }
+        for (a, b) in was.artifacts.iter().zip(&now.artifacts) {
+            if let (Some(locked), Some(actual)) = (&a.sha256, &b.sha256)
+                && locked != actual
+            {
+                differences.push(format!("{} is locked with sha256 {}, {} records {}", a.url, locked, CaveSums::FILENAME, actual));
+            }
+        }
+    }
+    for was in locked.iter().filter(|p| find(current, &p.query).is_none()) {
+        differences.push(format!("{} is locked but no longer selected", was.query));
+    }
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::inspect::inspect_version;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_lock_roundtrip_and_differences() {
+        let tmp = tempfile::tempdir().unwrap();
+        let entry = |version: &str| VersionEntry {
+            pkgname: "go".to_string(),
+            version: inspect_version(version).version,
+            pipeline: vec![InstallStep::Fetch {
+                name: None,
+                url: format!("https://go.dev/go{}.tgz", version),
+                mirrors: Vec::new(),
+                checksum: None,
+                filename: None,
+                signature: None,
+                stream: false,
+            }],
+            ..Default::default()
+        };
+        let graph = HashMap::from([("go".to_string(), (entry("1.22.0"), "pi".to_string()))]);
+        let locked = locked_packages(&graph, None);
+        assert_eq!(locked[0].artifacts[0], LockedArtifact { url: "https://go.dev/go1.22.0.tgz".to_string(), sha256: None });
+
+        assert!(CaveLock::load(tmp.path()).unwrap().is_none());
+        assert!(CaveLock::update(tmp.path(), None, locked.clone()).unwrap());
+        assert!(!CaveLock::update(tmp.path(), None, locked.clone()).unwrap());
+        let lock = CaveLock::load(tmp.path()).unwrap().unwrap();
+        assert_eq!(lock.packages(None).unwrap(), locked.as_slice());
+        assert!(lock.packages(Some(":dev")).is_none());
+        assert!(lock_differences(&locked, &locked).is_empty());
+
+        let newer = HashMap::from([
+            ("go".to_string(), (entry("1.23.0"), "pi".to_string())),
+            ("zig".to_string(), (VersionEntry { pkgname: "zig".to_string(), ..Default::default() }, "pi".to_string())),
+        ]);
+        let differences = lock_differences(&locked, &locked_packages(&newer, None));
+        assert_eq!(differences, vec![
+            "go is locked to pi/go=1.22.0 but resolves to pi/go=1.23.0".to_string(),
+            "zig is not locked".to_string(),
+        ]);
+    }
+}
@@ -6,3 +6,10 @@ pub mod selector;
 pub mod version_entry;
 pub mod cave;
 pub mod types;
+pub mod cave_bundle;
+pub mod settings;
+pub mod cave_policy;
+pub mod cave_registry;
+pub mod cave_trust;
+pub mod cave_sums;
+pub mod lockfile;
@@ -1,9 +1,12 @@
 use crate::models::config::Config;
+use crate::utils::timings::Phase;
 use allocative::Allocative;
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use std::fs;
+use crate::services::cache::meta::MetaStore;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use regex::Regex;
 use std::collections::HashMap;
 
 #[derive(Debug, Clone, Allocative, Serialize, Deserialize)]
@@ -11,6 +14,74 @@ pub struct RegistryEntry {
     pub name: String,
     pub function_name: String,
     pub filename: String,
+    /// Set for `dir:` packages (`add_dir_package`), registered as `dir:<name>`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<DirSource>,
+}
+
+/// Prefix of packages whose versions are files in a local directory.
+pub const DIR_MANAGER: &str = "dir";
+
+/// Prefix of the built-in virtualenv/node_modules packages, see `services::venv`.
+pub const VENV_MANAGER: &str = "venv";
+
+/// A local directory of tarballs or binaries serving a `dir:` package: every
+/// file whose name matches `pattern` is a version, taken from the pattern's
+/// `version` group (or its first group).
+#[derive(Debug, Clone, Allocative, Serialize, Deserialize)]
+pub struct DirSource {
+    /// Absolute, or relative to the recipe file.
+    pub path: String,
+    pub pattern: String,
+}
+
+/// A file of a `DirSource` and the version parsed from its name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirArtifact {
+    pub version: String,
+    pub path: PathBuf,
+    /// Modification date, "YYYY-MM-DD".
+    pub date: String,
+}
+
+impl DirSource {
+    /// The filename regex, anchored; fails without a capture group.
+    pub fn regex(&self) -> anyhow::Result<Regex> {
+        let regex = Regex::new(&format!("^(?:{})$", self.pattern))
+            .with_context(|| format!("invalid pattern '{}'", self.pattern))?;
+        if regex.captures_len() < 2 {
+            anyhow::bail!("pattern '{}' has no group capturing the version", self.pattern);
+        }
+        Ok(regex)
+    }
+
+    /// The source directory, resolving a relative `path` against `recipe_dir`.
+    pub fn dir(&self, recipe_dir: &Path) -> PathBuf {
+        recipe_dir.join(&self.path)
+    }
+
+    /// Files of the directory matching the pattern, sorted by name.
+    pub fn scan(&self, recipe_dir: &Path) -> anyhow::Result<Vec<DirArtifact>> {
+        let regex = self.regex()?;
+        let dir = self.dir(recipe_dir);
+        let entries = std::fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+        let mut artifacts = Vec::new();
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Some(captures) = regex.captures(&name) else { continue };
+            let Some(version) = captures.name("version").or_else(|| captures.get(1)) else { continue };
+            let Ok(meta) = entry.metadata() else { continue };
+            if !meta.is_file() {
+                continue;
+            }
+            let date = meta.modified().ok()
+                .map(|t| chrono::DateTime::<chrono::Local>::from(t).format("%Y-%m-%d").to_string())
+                .unwrap_or_default();
+            artifacts.push(DirArtifact { version: version.as_str().to_string(), path: entry.path(), date });
+        }
+        artifacts.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(artifacts)
+    }
 }
 
 // Aliases for compatibility
@@ -26,9 +97,11 @@ pub struct PackageList {
 impl PackageList {
     pub fn get_for_repo(config: &Config, repo: &crate::models::repository::Repository, force: bool) -> Option<Arc<Self>> {
         use dashmap::mapref::entry::Entry;
+        // Offline, the cached list is all there is.
+        let force = (config.force || force) && !config.offline;
 
         // Check cache first using DashMap for thread-safe concurrent access.
-        if !config.force && !force {
+        if !force {
             if let Entry::Occupied(occupied) = config.state.package_lists.entry(repo.name.clone()) {
                 let arc_list: Arc<PackageList> = occupied.get().clone();
                 return Some(arc_list);
@@ -42,9 +115,13 @@ impl PackageList {
         }
 
         // If force is true, or if not found on disk, sync
-        log::info!("[{}] {}syncing", repo.name, if config.force || force { "force " } else { "" });
+        if config.offline {
+            log::warn!("[{}] no cached package list, not syncing offline", repo.name);
+            return None;
+        }
+        log::info!("[{}] {}syncing", repo.name, if force { "force " } else { "" });
         if let Err(e) = crate::services::sync::sync_repo(config, repo) {
-            log::error!("[{}] sync failed: {}", repo.name, e);
+            log::error!("[{}] sync failed: {:#}", repo.name, e);
             return None;
         }
 
@@ -57,20 +134,54 @@ impl PackageList {
         None
     }
 
+    /// The manager serving `<prefix>:<package>`; every `dir:` package has its own entry.
+    pub fn manager(&self, prefix: &str, package: &str) -> Option<&ManagerEntry> {
+        if prefix == DIR_MANAGER {
+            self.managers.get(&format!("{}:{}", prefix, package))
+        } else {
+            self.managers.get(prefix)
+        }
+    }
+
+    /// The entry of the recipe registering `pkgname`, a package or `<prefix>:<package>`.
+    pub fn entry_for(&self, pkgname: &str) -> Option<&RegistryEntry> {
+        let (manager, package) = pkgname.split_once(':').unwrap_or((pkgname, ""));
+        self.packages.get(pkgname).or_else(|| self.manager(manager, package))
+    }
+
     pub fn load(config: &Config, repo_name: &str) -> anyhow::Result<Self> {
-        let cache_file = config.package_cache_file(repo_name);
-        let content = fs::read_to_string(&cache_file)
-            .with_context(|| format!("Failed to read package cache file: {:?}", cache_file))?;
-        serde_json::from_str(&content)
-            .with_context(|| format!("Failed to parse package cache file: {:?}", cache_file))
+        let _timer = config.state.timings.scope(Phase::RepoLoading);
+        MetaStore::read(config, &config.package_cache_file(repo_name))
     }
 
     pub fn save(&self, config: &Config, repo_name: &str) -> anyhow::Result<()> {
-        fs::create_dir_all(&config.cache_meta_dir).context("Failed to create meta directory")?;
-        let cache_file = config.package_cache_file(repo_name);
-        let content =
-            serde_json::to_string_pretty(self).context("Failed to serialize package list")?;
-        fs::write(&cache_file, content)
-            .with_context(|| format!("Failed to write package cache file: {:?}", cache_file))
+        MetaStore::write(config, &config.package_cache_file(repo_name), self)
+            .context("Failed to save package list")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dir_source_scan() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("vendor");
+        std::fs::create_dir_all(dir.join("tool-9.9.9.tar.gz")).unwrap();
+        for name in ["tool-1.2.0.tar.gz", "tool-1.10.1.tar.gz", "tool-1.2.0.tar.gz.sig", "README"] {
+            std::fs::write(dir.join(name), "x").unwrap();
+        }
+
+        let source = DirSource { path: "vendor".to_string(), pattern: r"tool-(?P<version>[0-9.]+)\.tar\.gz".to_string() };
+        let found = source.scan(tmp.path()).unwrap();
+        let versions: Vec<&str> = found.iter().map(|a| a.version.as_str()).collect();
+        assert_eq!(versions, ["1.10.1", "1.2.0"]);
+        assert_eq!(found[0].path, dir.join("tool-1.10.1.tar.gz"));
+        assert_eq!(found[0].date.len(), 10);
+
+        let absolute = DirSource { path: dir.display().to_string(), pattern: r"tool-([0-9.]+)\.tar\.gz".to_string() };
+        assert_eq!(absolute.scan(Path::new("/elsewhere")).unwrap().len(), 2);
+        assert!(DirSource { path: ".".to_string(), pattern: "tool".to_string() }.regex().is_err());
     }
 }
@@ -1,17 +1,92 @@
 use crate::models::config::Config;
+use crate::models::version_entry::Signature;
+use crate::services::cache::meta::MetaStore;
+use crate::services::repo_origin::ArchiveCheck;
+use crate::utils::timings::Phase;
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const REPOSITORIES_FILE: &str = "repositories.json";
+const BACKUP_SUFFIX: &str = ".bak";
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Repository {
     pub path: String,
     pub name: String,
+    /// Repos this repo's packages may take build dependencies from, besides
+    /// itself. None allows every repo.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dependency_repos: Option<Vec<String>>,
+    /// The git or archive URL the repo was added from; `path` is then its
+    /// checkout under `<cache_dir>/repos`, refreshed by `repo sync`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    /// Checksum every download of an archive origin must match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_checksum: Option<String>,
+    /// Detached signature every download of an archive origin must carry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_signature: Option<Signature>,
+    /// Hosts the recipes may reach with `download()` and the like while
+    /// they are evaluated; `*.example.com` covers subdomains. None trusts
+    /// the repo with every host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_hosts: Option<Vec<String>>,
 }
 
 impl Repository {
     pub fn new(path: String, name: String) -> Self {
-        Self { path, name }
+        Self { path, name, dependency_repos: None, origin: None, origin_checksum: None, origin_signature: None, allowed_hosts: None }
+    }
+
+    /// What recipe evaluation of this repo may download, None when it is trusted.
+    pub fn host_policy(&self) -> Option<HostPolicy> {
+        self.allowed_hosts.as_ref().map(|hosts| HostPolicy { repo: self.name.clone(), hosts: hosts.clone() })
+    }
+
+    pub fn archive_check(&self) -> ArchiveCheck<'_> {
+        ArchiveCheck { checksum: self.origin_checksum.as_deref(), signature: self.origin_signature.as_ref() }
+    }
+
+    /// Whether packages of this repo may depend on packages of `repo_name`.
+    pub fn allows_dependency_from(&self, repo_name: &str) -> bool {
+        repo_name == self.name
+            || self.dependency_repos.as_ref().is_none_or(|allowed| allowed.iter().any(|r| r == repo_name))
+    }
+}
+
+/// The hosts an untrusted repo's recipes may download from.
+#[derive(Debug, Clone, Serialize)]
+pub struct HostPolicy {
+    pub repo: String,
+    pub hosts: Vec<String>,
+}
+
+impl HostPolicy {
+    /// Fails unless the host of `url` is allowed; URLs without a host
+    /// (`file://`) never are.
+    pub fn check(&self, url: &str) -> anyhow::Result<()> {
+        let host = url::Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_ascii_lowercase));
+        let allowed = host.as_deref().is_some_and(|host| self.hosts.iter().any(|pattern| host_matches(pattern, host)));
+        if !allowed {
+            anyhow::bail!(
+                "{} blocked: repo '{}' may only download from {} (pi repo trust {} --allow-host <host>)",
+                url, self.repo, if self.hosts.is_empty() { "no host".to_string() } else { self.hosts.join(", ") }, self.repo
+            );
+        }
+        Ok(())
+    }
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.')),
+        None => host == pattern,
     }
 }
 
@@ -23,33 +98,155 @@ pub struct Repositories {
 impl Repositories {
     pub fn get_all(config: &Config) -> &Self {
         config.state.repositories.get_or_init(|| {
-            Self::load(config).unwrap_or_else(|e| {
+            let _timer = config.state.timings.scope(Phase::RepoLoading);
+            let repos = Self::load(config).unwrap_or_else(|e| {
                 log::warn!("failed to load repos: {}", e);
                 Self {
                     repositories: Vec::new(),
                 }
-            })
+            });
+            let names: Vec<&str> = repos.repositories.iter().map(|r| r.name.as_str()).collect();
+            if let Err(e) = MetaStore::migrate_version_keys(config, &names) {
+                log::warn!("failed to rename cached version lists: {:#}", e);
+            }
+            repos
         })
     }
 
     pub fn load(config: &Config) -> anyhow::Result<Self> {
-        let config_file = config.repositories_file();
+        Self::load_file(&config.repositories_file())
+    }
+
+    /// Reads `config_file`; no repositories when it doesn't exist.
+    pub fn load_file(config_file: &Path) -> anyhow::Result<Self> {
         if !config_file.exists() {
             return Ok(Self {
                 repositories: Vec::new(),
             });
         }
-        let content = fs::read_to_string(&config_file)
+        let content = fs::read_to_string(config_file)
             .with_context(|| format!("Failed to read config file: {:?}", config_file))?;
         serde_json::from_str(&content)
             .with_context(|| format!("Failed to parse config file: {:?}", config_file))
     }
 
+    /// Validates and writes the repositories: the new content goes to a
+    /// synced temp file renamed over `repositories.json`, and the previous
+    /// file is kept as the single `repositories.json.<time>.bak`.
     pub fn save(&self, config: &Config) -> anyhow::Result<()> {
+        self.validate()?;
         fs::create_dir_all(&config.config_dir).context("Failed to create config directory")?;
         let config_file = config.repositories_file();
         let content = serde_json::to_string_pretty(self).context("Failed to serialize config")?;
-        fs::write(&config_file, content)
-            .with_context(|| format!("Failed to write config file: {:?}", config_file))
+
+        let mut tmp = tempfile::NamedTempFile::new_in(&config.config_dir)
+            .context("Failed to create temporary config file")?;
+        tmp.write_all(content.as_bytes())?;
+        tmp.as_file().sync_all()?;
+
+        if config_file.exists() {
+            let previous = Self::backup_file(config);
+            let backup = config.config_dir.join(format!(
+                "{}.{}{}", REPOSITORIES_FILE, chrono::Local::now().format("%Y%m%d-%H%M%S"), BACKUP_SUFFIX
+            ));
+            fs::copy(&config_file, &backup)
+                .with_context(|| format!("Failed to back up {:?}", config_file))?;
+            if let Some(previous) = previous.filter(|p| *p != backup) {
+                let _ = fs::remove_file(previous);
+            }
+        }
+        tmp.persist(&config_file)
+            .map_err(|e| anyhow::anyhow!("Failed to write config file {:?}: {}", config_file, e.error))?;
+        if let Ok(dir) = fs::File::open(&config.config_dir) {
+            let _ = dir.sync_all();
+        }
+        Ok(())
+    }
+
+    /// Names and paths must be non-empty and unique.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut names = HashSet::new();
+        let mut paths = HashSet::new();
+        for repo in &self.repositories {
+            if repo.name.is_empty() || repo.path.is_empty() {
+                anyhow::bail!("repository with an empty name or path: {:?}", repo);
+            }
+            if !names.insert(&repo.name) {
+                anyhow::bail!("duplicate repository name '{}'", repo.name);
+            }
+            if !paths.insert(&repo.path) {
+                anyhow::bail!("duplicate repository path '{}'", repo.path);
+            }
+        }
+        Ok(())
+    }
+
+    /// The backup of the previous `repositories.json`, if there is one.
+    pub fn backup_file(config: &Config) -> Option<PathBuf> {
+        let prefix = format!("{}.", REPOSITORIES_FILE);
+        fs::read_dir(&config.config_dir).ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.file_name().and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(&prefix) && n.ends_with(BACKUP_SUFFIX)))
+            .max()
+    }
+
+    /// Replaces `repositories.json` with its backup; the replaced content
+    /// becomes the new backup, so a restore can be undone by another one.
+    pub fn restore(config: &Config) -> anyhow::Result<(PathBuf, Self)> {
+        let backup = Self::backup_file(config).context("no backup of repositories.json found")?;
+        let content = fs::read_to_string(&backup)
+            .with_context(|| format!("Failed to read {}", backup.display()))?;
+        let repos: Self = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", backup.display()))?;
+        repos.save(config)?;
+        Ok((backup, repos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_restore() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let repos = |names: &[&str]| Repositories {
+            repositories: names.iter().map(|n| Repository::new(format!("/r/{}", n), n.to_string())).collect(),
+        };
+
+        repos(&["main"]).save(&config).unwrap();
+        assert!(Repositories::backup_file(&config).is_none());
+        repos(&["main", "extra"]).save(&config).unwrap();
+        repos(&["main", "extra", "third"]).save(&config).unwrap();
+        let backups: Vec<_> = fs::read_dir(&config.config_dir).unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().ends_with(BACKUP_SUFFIX))
+            .collect();
+        assert_eq!(backups.len(), 1);
+
+        let duplicate = Repositories { repositories: vec![Repository::new("/a".into(), "x".into()), Repository::new("/b".into(), "x".into())] };
+        assert!(duplicate.save(&config).unwrap_err().to_string().contains("duplicate repository name 'x'"));
+        assert_eq!(Repositories::load(&config).unwrap().repositories.len(), 3);
+
+        let (_, restored) = Repositories::restore(&config).unwrap();
+        assert_eq!(restored.repositories.len(), 2);
+        assert_eq!(Repositories::load(&config).unwrap().repositories.len(), 2);
+    }
+
+    #[test]
+    fn test_host_policy() {
+        let mut repo = Repository::new("/r/third".into(), "third".into());
+        assert!(repo.host_policy().is_none());
+        repo.allowed_hosts = Some(vec!["api.github.com".into(), "*.example.org".into()]);
+        let policy = repo.host_policy().unwrap();
+        policy.check("https://api.github.com/repos/x/y/releases").unwrap();
+        policy.check("https://dl.EXAMPLE.org/index.json").unwrap();
+        for url in ["https://example.org/", "https://evil-example.org/", "https://github.com/", "file:///etc/passwd"] {
+            let err = policy.check(url).unwrap_err().to_string();
+            assert!(err.contains("repo 'third' may only download from api.github.com, *.example.org"), "{}", err);
+        }
     }
 }
@@ -3,14 +3,19 @@ pub struct PackageSelector {
     pub recipe: Option<String>,
     pub prefix: Option<String>,
     pub package: String,
+    /// Release train the version must belong to (`node@iron`), matched
+    /// against the stream recipes set, ignoring case.
+    pub stream: Option<String>,
     pub version: Option<String>,
 }
 
 impl PackageSelector {
-    /// Parses a selector string in the format: [recipe]/[prefix]:package[=version]
+    /// Parses a selector string in the format: [recipe]/[prefix]:package[@stream][=version].
+    /// Managed package names keep their `@` (`npm:@types/node`), so only
+    /// direct packages take a stream.
     pub fn parse(s: &str) -> Option<Self> {
         let mut prefix = None;
-        let package;
+        let mut package;
         let mut version = None;
 
         let rest = if let Some(idx) = s.find('=') {
@@ -44,6 +49,17 @@ impl PackageSelector {
             package = rest.to_string();
         }
 
+        let mut stream = None;
+        if prefix.is_none()
+            && let Some((name, train)) = package.split_once('@')
+        {
+            if name.is_empty() || train.is_empty() {
+                return None;
+            }
+            stream = Some(train.to_string());
+            package = name.to_string();
+        }
+
         if package.is_empty() && prefix.is_none() {
             return None;
         }
@@ -52,7 +68,34 @@ impl PackageSelector {
             recipe,
             prefix,
             package,
+            stream,
             version,
         })
     }
+
+    /// The version to resolve: the selector's, else `stable`, within its
+    /// stream when it names one.
+    pub fn target_version(&self) -> &str {
+        self.version.as_deref().unwrap_or("stable")
+    }
+}
+
+/// The canonical `[recipe/][prefix:]package[@stream][=version]` form.
+impl std::fmt::Display for PackageSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(recipe) = &self.recipe {
+            write!(f, "{}/", recipe)?;
+        }
+        if let Some(prefix) = &self.prefix {
+            write!(f, "{}:", prefix)?;
+        }
+        write!(f, "{}", self.package)?;
+        if let Some(stream) = &self.stream {
+            write!(f, "@{}", stream)?;
+        }
+        if let Some(version) = &self.version {
+            write!(f, "={}", version)?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,380 @@
+use crate::services::downloader::RetryPolicy;
+use crate::services::sandbox::SandboxBackend;
+use crate::utils::duration::parse_duration;
+use crate::utils::permissions::PermissionPolicy;
+use crate::utils::size::parse_size;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Default of `manager_cache_ttl`: one day.
+const DEFAULT_MANAGER_CACHE_TTL: u64 = 24 * 3600;
+
+/// Maximum sizes of the cache sections, as human sizes (e.g. "10G").
+/// Unset sections are unlimited. Downloads can also expire by age.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct QuotaSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downloads: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub packages: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pilocals: Option<String>,
+    /// Downloads unused for longer than this (e.g. "30d") are evicted,
+    /// unless a cached build still depends on them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downloads_max_age: Option<String>,
+}
+
+impl QuotaSettings {
+    /// JSON keys of a `quota` block.
+    pub const KEYS: &'static [&'static str] = &["downloads", "packages", "pilocals", "downloads_max_age"];
+
+    pub fn is_empty(&self) -> bool {
+        self.downloads.is_none() && self.packages.is_none() && self.pilocals.is_none()
+            && self.downloads_max_age.is_none()
+    }
+
+    /// Overrides the quotas set in `other`.
+    pub fn merge(&mut self, other: &QuotaSettings) {
+        if other.downloads.is_some() { self.downloads = other.downloads.clone(); }
+        if other.packages.is_some() { self.packages = other.packages.clone(); }
+        if other.pilocals.is_some() { self.pilocals = other.pilocals.clone(); }
+        if other.downloads_max_age.is_some() { self.downloads_max_age = other.downloads_max_age.clone(); }
+    }
+
+    /// Returns the quota of a section ("downloads", "packages", "pilocals") in bytes.
+    pub fn limit(&self, section: &str) -> anyhow::Result<Option<u64>> {
+        let value = match section {
+            "downloads" => &self.downloads,
+            "packages" => &self.packages,
+            "pilocals" => &self.pilocals,
+            _ => anyhow::bail!("Unknown quota section: {}", section),
+        };
+        value.as_deref()
+            .map(|v| parse_size(v).with_context(|| format!("Invalid {} quota", section)))
+            .transpose()
+    }
+
+    /// Returns the maximum age of unused downloads in seconds.
+    pub fn downloads_max_age(&self) -> anyhow::Result<Option<u64>> {
+        self.downloads_max_age.as_deref()
+            .map(|v| parse_duration(v).context("Invalid downloads_max_age"))
+            .transpose()
+    }
+}
+
+/// User settings loaded from `<config_dir>/settings.toml`.
+///
+/// Precedence, highest first: CLI flags, `PI_*` environment variables,
+/// this file, built-in defaults.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Settings {
+    /// Default log level: error, warn, info, debug or trace (`PI_LOG_LEVEL`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<String>,
+    /// Worker threads for parallel syncs and builds, default one per CPU (`PI_JOBS`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jobs: Option<usize>,
+    /// Never sync repositories or packages automatically (`PI_NO_SYNC`).
+    #[serde(default)]
+    pub no_sync: bool,
+    /// Keep the temporary home of build steps (`PI_KEEP_BUILD_HOME`).
+    #[serde(default)]
+    pub keep_build_home: bool,
+    /// Storage of cached package/version lists: "json" (one file each, default)
+    /// or "compact" (one redb table, see `MetaStore`) (`PI_META_FORMAT`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta_format: Option<String>,
+    /// Modes of extracted files: "normalize" (default, see `PermissionPolicy`)
+    /// or "keep" (`PI_PERMISSIONS`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<String>,
+    /// Retries of a download failing with a network or server error,
+    /// default 3 (`PI_DOWNLOAD_RETRIES`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_retries: Option<u32>,
+    /// Wait before the first retry, doubled for each next one, e.g. "2s"
+    /// (default "1s").
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_retry_delay: Option<String>,
+    /// How long a managed package lookup (`go:`, `npm:`) is trusted, found or
+    /// not, e.g. "12h" (default "1d", `PI_MANAGER_CACHE_TTL`). Stale lists are
+    /// still used while a background sync refreshes them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manager_cache_ttl: Option<String>,
+    /// What runs sandboxed commands: "bubblewrap" (default) or "none", which
+    /// runs them on the host without isolation (`PI_SANDBOX`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<String>,
+    #[serde(default, skip_serializing_if = "QuotaSettings::is_empty")]
+    pub quota: QuotaSettings,
+}
+
+impl Settings {
+    pub const FILENAME: &'static str = "settings.toml";
+
+    /// Keys accepted by `pi config get/set`.
+    pub const KEYS: &'static [&'static str] = &[
+        "log_level",
+        "jobs",
+        "no_sync",
+        "keep_build_home",
+        "meta_format",
+        "permissions",
+        "download_retries",
+        "download_retry_delay",
+        "manager_cache_ttl",
+        "sandbox",
+        "quota.downloads",
+        "quota.packages",
+        "quota.pilocals",
+        "quota.downloads_max_age",
+    ];
+
+    pub fn load(config_dir: &Path) -> anyhow::Result<Self> {
+        let path = config_dir.join(Self::FILENAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read settings: {}", path.display()))?;
+        let settings: Self = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse settings: {}", path.display()))?;
+        settings.validate()
+            .with_context(|| format!("Invalid settings: {}", path.display()))?;
+        Ok(settings)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(level) = &self.log_level {
+            level.parse::<log::LevelFilter>()
+                .map_err(|_| anyhow::anyhow!("log_level must be one of error, warn, info, debug, trace"))?;
+        }
+        if self.jobs == Some(0) {
+            anyhow::bail!("jobs must be at least 1");
+        }
+        if let Some(format) = &self.meta_format
+            && format != "json" && format != "compact"
+        {
+            anyhow::bail!("meta_format must be json or compact");
+        }
+        if let Some(policy) = &self.permissions {
+            policy.parse::<PermissionPolicy>()?;
+        }
+        if let Some(delay) = &self.download_retry_delay {
+            parse_duration(delay).context("Invalid download_retry_delay")?;
+        }
+        if let Some(ttl) = &self.manager_cache_ttl {
+            parse_duration(ttl).context("Invalid manager_cache_ttl")?;
+        }
+        if let Some(backend) = &self.sandbox {
+            backend.parse::<SandboxBackend>()?;
+        }
+        for section in ["downloads", "packages", "pilocals"] {
+            self.quota.limit(section)?;
+        }
+        self.quota.downloads_max_age()?;
+        Ok(())
+    }
+
+    pub fn compact_meta(&self) -> bool {
+        self.meta_format.as_deref() == Some("compact")
+    }
+
+    pub fn permission_policy(&self) -> PermissionPolicy {
+        self.permissions.as_deref().and_then(|p| p.parse().ok()).unwrap_or_default()
+    }
+
+    /// Seconds a managed package lookup is trusted, see `manager_cache_ttl`.
+    pub fn manager_cache_ttl(&self) -> u64 {
+        self.manager_cache_ttl.as_deref()
+            .and_then(|ttl| parse_duration(ttl).ok())
+            .unwrap_or(DEFAULT_MANAGER_CACHE_TTL)
+    }
+
+    pub fn sandbox_backend(&self) -> SandboxBackend {
+        self.sandbox.as_deref().and_then(|b| b.parse().ok()).unwrap_or_default()
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        let default = RetryPolicy::default();
+        RetryPolicy {
+            retries: self.download_retries.unwrap_or(default.retries),
+            delay: self.download_retry_delay.as_deref()
+                .and_then(|d| parse_duration(d).ok())
+                .map_or(default.delay, Duration::from_secs),
+        }
+    }
+
+    /// Overrides settings from `PI_*` environment variables. Invalid values are ignored.
+    pub fn apply_env(&mut self) {
+        self.apply_env_from(|name| std::env::var(name).ok());
+    }
+
+    fn apply_env_from(&mut self, var: impl Fn(&str) -> Option<String>) {
+        if let Some(level) = var("PI_LOG_LEVEL") {
+            self.log_level = Some(level);
+        }
+        if let Some(jobs) = var("PI_JOBS") {
+            match jobs.parse() {
+                Ok(n) if n > 0 => self.jobs = Some(n),
+                _ => log::warn!("[settings] ignoring invalid PI_JOBS={}", jobs),
+            }
+        }
+        if let Some(v) = var("PI_NO_SYNC") {
+            self.no_sync = parse_env_bool(&v);
+        }
+        if let Some(v) = var("PI_KEEP_BUILD_HOME") {
+            self.keep_build_home = parse_env_bool(&v);
+        }
+        if let Some(format) = var("PI_META_FORMAT") {
+            match format.as_str() {
+                "json" | "compact" => self.meta_format = Some(format),
+                _ => log::warn!("[settings] ignoring invalid PI_META_FORMAT={}", format),
+            }
+        }
+        if let Some(retries) = var("PI_DOWNLOAD_RETRIES") {
+            match retries.parse() {
+                Ok(n) => self.download_retries = Some(n),
+                _ => log::warn!("[settings] ignoring invalid PI_DOWNLOAD_RETRIES={}", retries),
+            }
+        }
+        if let Some(ttl) = var("PI_MANAGER_CACHE_TTL") {
+            match parse_duration(&ttl) {
+                Ok(_) => self.manager_cache_ttl = Some(ttl),
+                Err(_) => log::warn!("[settings] ignoring invalid PI_MANAGER_CACHE_TTL={}", ttl),
+            }
+        }
+        if let Some(backend) = var("PI_SANDBOX") {
+            match backend.parse::<SandboxBackend>() {
+                Ok(_) => self.sandbox = Some(backend),
+                Err(_) => log::warn!("[settings] ignoring invalid PI_SANDBOX={}", backend),
+            }
+        }
+        if let Some(policy) = var("PI_PERMISSIONS") {
+            match policy.parse::<PermissionPolicy>() {
+                Ok(_) => self.permissions = Some(policy),
+                Err(_) => log::warn!("[settings] ignoring invalid PI_PERMISSIONS={}", policy),
+            }
+        }
+    }
+}
+
+fn parse_env_bool(value: &str) -> bool {
+    matches!(value.to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on")
+}
+
+/// Sets a dotted `key` in a settings document, keeping other entries.
+/// `value` is read as a TOML scalar (bool, integer) and falls back to a string;
+/// the result must still be valid settings.
+pub fn set_key(doc: &mut toml::Table, key: &str, value: &str) -> anyhow::Result<()> {
+    if !Settings::KEYS.contains(&key) {
+        anyhow::bail!("Unknown setting '{}' (known: {})", key, Settings::KEYS.join(", "));
+    }
+    let typed = format!("v = {}", value).parse::<toml::Table>()
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .filter(|v| matches!(v, toml::Value::Boolean(_) | toml::Value::Integer(_)));
+
+    let mut last_err = None;
+    for candidate in typed.into_iter().chain(std::iter::once(toml::Value::String(value.to_string()))) {
+        let mut next = doc.clone();
+        insert_dotted(&mut next, key, candidate)?;
+        match toml::Value::Table(next.clone()).try_into::<Settings>().map_err(anyhow::Error::from)
+            .and_then(|s| s.validate().map(|_| s)) {
+            Ok(_) => {
+                *doc = next;
+                return Ok(());
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap()).with_context(|| format!("Invalid value for {}: {}", key, value))
+}
+
+/// Looks up a dotted `key` in the settings, None if it is unset.
+pub fn get_key(settings: &Settings, key: &str) -> anyhow::Result<Option<toml::Value>> {
+    if !Settings::KEYS.contains(&key) {
+        anyhow::bail!("Unknown setting '{}' (known: {})", key, Settings::KEYS.join(", "));
+    }
+    let mut value = toml::Value::try_from(settings).context("Failed to serialize settings")?;
+    for part in key.split('.') {
+        match value.get(part) {
+            Some(v) => value = v.clone(),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(value))
+}
+
+fn insert_dotted(doc: &mut toml::Table, key: &str, value: toml::Value) -> anyhow::Result<()> {
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let leaf = parts.pop().context("empty key")?;
+    let mut table = doc;
+    for part in parts {
+        table = table
+            .entry(part.to_string())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .with_context(|| format!("'{}' is not a table", part))?;
+    }
+    table.insert(leaf.to_string(), value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_get_key() {
+        let mut doc = toml::Table::new();
+        set_key(&mut doc, "jobs", "4").unwrap();
+        set_key(&mut doc, "no_sync", "true").unwrap();
+        set_key(&mut doc, "quota.downloads", "10G").unwrap();
+        set_key(&mut doc, "log_level", "debug").unwrap();
+        assert!(set_key(&mut doc, "jobs", "many").is_err());
+        assert!(set_key(&mut doc, "log_level", "loud").is_err());
+        assert!(set_key(&mut doc, "quota.packages", "lots").is_err());
+        assert!(set_key(&mut doc, "quota.downloads_max_age", "30d").is_ok());
+        assert!(set_key(&mut doc, "quota.downloads_max_age", "forever").is_err());
+        assert!(set_key(&mut doc, "colour", "yes").is_err());
+        assert!(set_key(&mut doc, "meta_format", "binary").is_err());
+        assert!(set_key(&mut doc, "permissions", "loose").is_err());
+        set_key(&mut doc, "permissions", "keep").unwrap();
+        assert!(set_key(&mut doc, "download_retry_delay", "soon").is_err());
+        set_key(&mut doc, "download_retry_delay", "5s").unwrap();
+        assert!(set_key(&mut doc, "manager_cache_ttl", "a while").is_err());
+        set_key(&mut doc, "manager_cache_ttl", "12h").unwrap();
+        assert!(set_key(&mut doc, "sandbox", "docker").is_err());
+
+        let settings: Settings = toml::Value::Table(doc).try_into().unwrap();
+        assert_eq!(settings.jobs, Some(4));
+        assert!(settings.no_sync);
+        assert_eq!(get_key(&settings, "quota.downloads").unwrap(), Some(toml::Value::String("10G".into())));
+        assert_eq!(get_key(&settings, "quota.pilocals").unwrap(), None);
+        assert_eq!(settings.permission_policy(), PermissionPolicy::Keep);
+        assert_eq!(settings.retry_policy().delay, Duration::from_secs(5));
+        assert_eq!(settings.manager_cache_ttl(), 12 * 3600);
+        assert_eq!(Settings::default().manager_cache_ttl(), DEFAULT_MANAGER_CACHE_TTL);
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let mut settings = Settings { jobs: Some(2), no_sync: true, ..Default::default() };
+        settings.apply_env_from(|name| match name {
+            "PI_JOBS" => Some("8".to_string()),
+            "PI_NO_SYNC" => Some("0".to_string()),
+            "PI_DOWNLOAD_RETRIES" => Some("0".to_string()),
+            "PI_SANDBOX" => Some("none".to_string()),
+            _ => None,
+        });
+        assert_eq!(settings.sandbox_backend(), SandboxBackend::None);
+        assert_eq!(settings.jobs, Some(8));
+        assert!(!settings.no_sync);
+        assert_eq!(settings.retry_policy(), RetryPolicy { retries: 0, delay: Duration::from_secs(1) });
+    }
+}
@@ -5,6 +5,7 @@ use std::str::FromStr;
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, Allocative, PartialEq, Hash)]
 #[serde(rename_all = "lowercase")]
+#[allow(clippy::enum_variant_names)]
 pub enum OS {
     Linux,
     MacOS,
@@ -88,3 +89,62 @@ impl Default for Arch {
         return Self::X86_64;
     }
 }
+
+/// An OS/architecture pair, written `os/arch` (e.g. `linux/x86_64`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Hash)]
+pub struct Platform {
+    pub os: OS,
+    pub arch: Arch,
+}
+
+impl Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.arch)
+    }
+}
+
+impl FromStr for Platform {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (os, arch) = s.split_once('/')
+            .ok_or_else(|| anyhow::anyhow!("Platform must be os/arch, e.g. linux/x86_64: {}", s))?;
+        Ok(Self { os: os.parse()?, arch: arch.parse()? })
+    }
+}
+
+impl Platform {
+    /// Checks a version's platform constraint: `linux/x86_64`, `linux/*` or `linux`.
+    pub fn matches(&self, constraint: &str) -> bool {
+        match parse_constraint(constraint) {
+            Ok((os, arch)) => os.is_none_or(|o| o == self.os) && arch.is_none_or(|a| a == self.arch),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Parses a platform constraint; `*` or a missing part matches anything.
+pub fn parse_constraint(constraint: &str) -> anyhow::Result<(Option<OS>, Option<Arch>)> {
+    let (os, arch) = constraint.split_once('/').unwrap_or((constraint, "*"));
+    let os = if os == "*" { None } else { Some(os.parse()?) };
+    let arch = if arch == "*" { None } else { Some(arch.parse()?) };
+    Ok((os, arch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_platform_matches() {
+        let linux_x64: Platform = "linux/amd64".parse().unwrap();
+        assert_eq!(linux_x64.to_string(), "linux/x86_64");
+        assert!(linux_x64.matches("linux/x86_64"));
+        assert!(linux_x64.matches("linux/*"));
+        assert!(linux_x64.matches("linux"));
+        assert!(linux_x64.matches("*/amd64"));
+        assert!(!linux_x64.matches("linux/aarch64"));
+        assert!(!linux_x64.matches("macos"));
+        assert!(parse_constraint("plan9/x86_64").is_err());
+        assert!("linux".parse::<Platform>().is_err());
+    }
+}
@@ -1,10 +1,14 @@
 use crate::models::config::Config;
 use crate::models::repository::Repository;
 use crate::models::package_entry::{PackageEntry, ManagerEntry};
+use crate::models::types::Platform;
+use crate::utils::timings::Phase;
 use allocative::Allocative;
 use anyhow::Context as _;
 use serde::{Deserialize, Serialize};
-use std::fs;
+use crate::services::cache::meta::MetaStore;
+use crate::services::manager_cache::{self, Lookup};
+use std::collections::BTreeMap;
 use std::fmt::{self, Display};
 use std::str::FromStr;
 use std::sync::Arc;
@@ -13,6 +17,7 @@ use std::sync::Arc;
 /// Example: ReleaseType::Stable
 #[derive(Debug, Clone, Serialize, Deserialize, Allocative, PartialEq, Hash, Default)]
 #[serde(rename_all = "lowercase")]
+#[allow(clippy::upper_case_acronyms)]
 pub enum ReleaseType {
     #[default]
     Stable,
@@ -77,33 +82,180 @@ impl Display for StructuredVersion {
     }
 }
 
+/// Where a Fetch's detached signature is and the key it must be made with
+/// (`fetch(signature_url=, public_key=)`): an ASCII-armored OpenPGP key or a
+/// minisign public key.
+#[derive(Debug, Clone, Serialize, Deserialize, Allocative, PartialEq, Hash)]
+pub struct Signature {
+    pub url: String,
+    pub public_key: String,
+}
+
 /// A single step in an installation pipeline.
 #[derive(Debug, Clone, Serialize, Deserialize, Allocative, PartialEq, Hash)]
 pub enum InstallStep {
     Fetch {
         name: Option<String>,
         url: String,
+        /// Alternate URLs tried in order when `url` fails.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        mirrors: Vec<String>,
         checksum: Option<String>,
         filename: Option<String>,
+        /// Detached signature the download must verify against.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<Signature>,
+        /// Extracts the body as it downloads instead of keeping the archive:
+        /// the step's output is then the extracted tree.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        stream: bool,
     },
     Extract {
         name: Option<String>,
         format: Option<String>,
     },
+    /// A checkout of `rev` (tag, branch or commit) of a git repository.
+    GitClone {
+        name: Option<String>,
+        url: String,
+        rev: String,
+        /// Fetch only this many commits of history.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        depth: Option<u32>,
+    },
     Run {
         name: Option<String>,
         command: String,
         cwd: Option<String>,
+        /// Runs `command`; bash when empty.
+        #[serde(default, skip_serializing_if = "Interpreter::is_default")]
+        shell: Interpreter,
+    },
+    /// A unified diff applied to the source tree; `url_or_inline` is a URL
+    /// to download it from or the diff itself.
+    Patch {
+        name: Option<String>,
+        url_or_inline: String,
+        /// Path components dropped from the diff's file names, like `patch -p`.
+        strip: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        checksum: Option<String>,
     },
+    /// A step of a kind pi doesn't know (`step("notarize", ...)`), run by the
+    /// `pi-step-<kind>` plugin executable.
+    Plugin {
+        name: Option<String>,
+        step: String,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        params: BTreeMap<String, String>,
+    },
+}
+
+/// The program a `Run` step's command is passed to, as an argv prefix the
+/// command is appended to, e.g. `["python3", "-c"]`. Empty means
+/// `/bin/bash -c`; it then hashes to nothing, so steps without an interpreter
+/// keep their hashes.
+#[derive(Debug, Clone, Serialize, Deserialize, Allocative, PartialEq, Default)]
+#[serde(transparent)]
+pub struct Interpreter(Vec<String>);
+
+impl Interpreter {
+    /// `name -c`, which `sh`, `bash`, `python3` and most interpreters take.
+    pub fn named(name: &str) -> Self {
+        Self(vec![name.to_string(), "-c".to_string()])
+    }
+
+    pub fn argv(argv: Vec<String>) -> anyhow::Result<Self> {
+        if argv.first().is_none_or(|p| p.is_empty()) {
+            anyhow::bail!("interpreter argv must start with a program");
+        }
+        Ok(Self(argv))
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn program(&self) -> &str {
+        self.0.first().map(|p| p.as_str()).unwrap_or("/bin/bash")
+    }
+
+    /// Arguments running `command`.
+    pub fn args(&self, command: &str) -> Vec<String> {
+        let mut args = match self.0.split_first() {
+            Some((_, rest)) => rest.to_vec(),
+            None => vec!["-c".to_string()],
+        };
+        args.push(command.to_string());
+        args
+    }
+}
+
+impl std::hash::Hash for Interpreter {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        if !self.0.is_empty() {
+            self.0.hash(state);
+        }
+    }
+}
+
+impl Display for Interpreter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "bash")
+        } else {
+            write!(f, "{}", self.0.join(" "))
+        }
+    }
+}
+
+impl InstallStep {
+    /// Whether a Patch step's `url_or_inline` is the diff itself.
+    pub fn is_inline_patch(url_or_inline: &str) -> bool {
+        url_or_inline.contains('\n')
+    }
+
+    /// Step type as shown to users, e.g. "Fetch".
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Fetch { .. } => "Fetch",
+            Self::Extract { .. } => "Extract",
+            Self::GitClone { .. } => "GitClone",
+            Self::Run { .. } => "Run",
+            Self::Patch { .. } => "Patch",
+            Self::Plugin { .. } => "Plugin",
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Self::Fetch { name, .. } | Self::Extract { name, .. } | Self::GitClone { name, .. } | Self::Run { name, .. }
+            | Self::Patch { name, .. } | Self::Plugin { name, .. } => name.as_deref(),
+        }
+    }
 }
 
 /// Defines environment or file system links exported by a package.
 /// Example: Export::Path("bin")
 #[derive(Debug, Clone, Serialize, Deserialize, Allocative, PartialEq, Hash)]
 pub enum Export {
-    Link { src: String, dest: String },
+    Link {
+        src: String,
+        dest: String,
+        /// Octal mode set on the linked files, e.g. "755".
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        chmod: Option<String>,
+    },
     Env { key: String, val: String },
     Path(String),
+    /// A daemon of the package run by `pi cave service start`: `command` in
+    /// `sh -c`, inside the cave, with `env` on top of the cave's.
+    Service {
+        name: String,
+        command: String,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        env: BTreeMap<String, String>,
+    },
 }
 
 /// A configurable flag for building the package.
@@ -139,9 +291,43 @@ pub struct VersionEntry {
     pub flags: Vec<BuildFlag>,
     #[serde(default)]
     pub build_dependencies: Vec<Dependency>,
+    /// Platforms this version is available for (`os/arch`, `os/*` or `os`); empty means all.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub platforms: Vec<String>,
+    /// `Run` steps must execute outside the sandbox; needs the cave's consent.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub requires_host_build: bool,
+    /// Artifact sizes in bytes by Fetch URL, when the recipe knows them
+    /// (`fetch(..., size=)`). Kept out of the steps so step hashes don't change.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sizes: BTreeMap<String, u64>,
+    /// Where the release notes of this version are, fetched by `pi package changelog`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changelog_url: Option<String>,
+    /// Release notes given by the recipe itself, in markdown.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
 }
 
 impl VersionEntry {
+    pub fn supports(&self, platform: &Platform) -> bool {
+        self.platforms.is_empty() || self.platforms.iter().any(|p| platform.matches(p))
+    }
+
+    /// Recorded size of each Fetch step's artifact, in pipeline order.
+    pub fn fetch_sizes(&self) -> impl Iterator<Item = (&InstallStep, Option<u64>)> {
+        self.pipeline.iter()
+            .filter_map(|step| match step {
+                InstallStep::Fetch { url, .. } => Some((step, self.sizes.get(url).copied())),
+                _ => None,
+            })
+    }
+
+    /// Total size of the version's downloads; None when some size is unknown.
+    pub fn download_size(&self) -> Option<u64> {
+        self.fetch_sizes().map(|(_, size)| size).sum()
+    }
+
     pub fn pkg_dir_name(&self) -> String {
         format!("{}-{}", crate::utils::fs::sanitize_name(&self.pkgname), crate::utils::fs::sanitize_name(&self.version.to_string()))
     }
@@ -182,60 +368,101 @@ pub struct GetVersionOptions<'a> {
 
 impl VersionList {
     /// Retrieves the version list for a package, using cache if available.
-    pub fn get_for_package(opts: GetVersionOptions) -> Option<Arc<Self>> {
+    pub fn get_for_package(mut opts: GetVersionOptions) -> Option<Arc<Self>> {
+        // Offline, the cached list is all there is.
+        opts.force = (opts.config.force || opts.force) && !opts.config.offline;
         let key = format!("{}:{}", opts.repo.name, opts.package_name);
         use dashmap::mapref::entry::Entry;
 
         // Check cache first using DashMap for thread-safe concurrent access.
-        if !opts.config.force && !opts.force {
-            if let Entry::Occupied(occupied) = opts.config.state.version_lists.entry(key.clone()) {
-                let arc_list: Arc<VersionList> = occupied.get().clone();
-                return Some(arc_list);
-            }
+        if !opts.force
+            && let Entry::Occupied(occupied) = opts.config.state.version_lists.entry(key.clone())
+        {
+            let arc_list: Arc<VersionList> = occupied.get().clone();
+            return Some(arc_list);
         }
 
+        // A recent miss holds against the retry of a failed resolution too; only --force syncs again.
+        let lookup = match opts.manager_entry {
+            Some(_) if !opts.config.force => manager_cache::lookup(opts.config, &key),
+            _ => Lookup::Unknown,
+        };
         if let Some(list) = try_load_from_disk(opts.config, opts.repo, opts.package_name, opts.force, &key) {
+            if let (Lookup::Found { stale: true }, Some((mgr, pkg_name))) = (lookup, opts.manager_entry)
+                && !opts.config.no_sync
+            {
+                let manager_name = opts.package_name.split(':').next().unwrap_or("");
+                manager_cache::refresh_in_background(opts.config, opts.repo, mgr, manager_name, pkg_name);
+            }
             return Some(list);
         }
+        if lookup == Lookup::NotFound {
+            log::debug!("[{}] not found by a recent sync, skipping it (--force retries)", key);
+            return None;
+        }
 
         sync_and_load(opts, &key)
     }
 
     pub fn load(config: &Config, repo_name: &str, package_name: &str) -> anyhow::Result<Self> {
+        let _timer = config.state.timings.scope(Phase::RepoLoading);
         let safe_name = package_name.replace('/', "#");
-        let cache_file = config.version_cache_file(repo_name, &safe_name);
-        let content = fs::read_to_string(&cache_file)
-            .with_context(|| format!("Failed to read version cache file: {:?}", cache_file))?;
-        serde_json::from_str(&content)
-            .with_context(|| format!("Failed to parse version cache file: {:?}", cache_file))
+        MetaStore::read(config, &config.version_cache_file(repo_name, &safe_name))
+    }
+
+    /// Names of the packages of `repo_name` with a cached list, managed ones
+    /// (`npm:typescript`) included.
+    pub fn cached_names(config: &Config, repo_name: &str) -> Vec<String> {
+        let prefix = Config::version_cache_prefix(repo_name);
+        MetaStore::names(config, &prefix).into_iter()
+            .filter_map(|name| name.strip_prefix(&prefix)?.strip_suffix(".json").map(|n| n.replace('#', "/")))
+            .collect()
+    }
+
+    /// The newest version in the list, used as the high-water mark for delta syncs.
+    pub fn latest(&self) -> Option<&VersionEntry> {
+        self.versions.iter().max_by(|a, b| a.version.cmp(&b.version))
+    }
+
+    /// Merges newly discovered versions into the list. Entries from `delta` replace
+    /// existing entries with the same version and stream; new ones go first.
+    pub fn merge(&mut self, delta: Vec<VersionEntry>) {
+        let replaced: std::collections::HashSet<(String, String)> = delta
+            .iter()
+            .map(|v| (v.version.raw.clone(), v.stream.clone()))
+            .collect();
+        let kept = std::mem::take(&mut self.versions)
+            .into_iter()
+            .filter(|v| !replaced.contains(&(v.version.raw.clone(), v.stream.clone())));
+        self.versions = delta.into_iter().chain(kept).collect();
     }
 
     pub fn save(&self, config: &Config, repo_name: &str, package_name: &str) -> anyhow::Result<()> {
-        fs::create_dir_all(&config.cache_meta_dir).context("Failed to create meta directory")?;
         let safe_name = package_name.replace('/', "#");
-        let cache_file = config.version_cache_file(repo_name, &safe_name);
-        let content =
-            serde_json::to_string_pretty(self).context("Failed to serialize version list")?;
-        fs::write(&cache_file, content)
-            .with_context(|| format!("Failed to write version cache file: {:?}", cache_file))
+        MetaStore::write(config, &config.version_cache_file(repo_name, &safe_name), self)
+            .context("Failed to save version list")
     }
 }
 
-fn try_load_from_disk(config: &Config, repo: &Repository, name: &str, force_opt: bool, key: &str) -> Option<Arc<VersionList>> {
-    if !config.force && !force_opt {
-        if let Ok(list) = VersionList::load(config, &repo.name, name) {
-            let arc_list = Arc::new(list);
-            config.state.version_lists.insert(key.to_string(), arc_list.clone());
-            return Some(arc_list);
-        }
+fn try_load_from_disk(config: &Config, repo: &Repository, name: &str, force: bool, key: &str) -> Option<Arc<VersionList>> {
+    if !force
+        && let Ok(list) = VersionList::load(config, &repo.name, name)
+    {
+        let arc_list = Arc::new(list);
+        config.state.version_lists.insert(key.to_string(), arc_list.clone());
+        return Some(arc_list);
     }
     None
 }
 
 fn sync_and_load(opts: GetVersionOptions, key: &str) -> Option<Arc<VersionList>> {
+    if opts.config.offline {
+        log::debug!("[{}] no cached version list, not syncing offline", key);
+        return None;
+    }
     if let Some(pkg) = opts.package_entry {
         if let Err(e) = crate::services::sync::sync_package(opts.config, opts.repo, pkg) {
-            log::error!("[{}/{}] sync failed: {}", opts.repo.name, pkg.name, e);
+            log::error!("[{}/{}] sync failed: {:#}", opts.repo.name, pkg.name, e);
         }
     } else if let Some((mgr, pkg_name)) = opts.manager_entry {
         let manager_name = opts.package_name.split(':').next().unwrap_or("");
@@ -246,7 +473,7 @@ fn sync_and_load(opts: GetVersionOptions, key: &str) -> Option<Arc<VersionList>>
             manager_name,
             pkg_name,
         ) {
-            log::error!("[{}/{}:{}] sync failed: {}", opts.repo.name, manager_name, pkg_name, e);
+            log::error!("[{}/{}:{}] sync failed: {:#}", opts.repo.name, manager_name, pkg_name, e);
         }
     }
 
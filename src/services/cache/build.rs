@@ -1,21 +1,114 @@
+use crate::utils::crypto::calculate_file_checksum;
+use crate::utils::fs::sanitize_name;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use walkdir::WalkDir;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Separates the version from the options hash in a build key (see
+/// `package::build::build_key`); unlike `+`, versions don't use it.
+pub const OPTIONS_SEPARATOR: char = '#';
+
+/// The version and the options hash of a build key.
+pub fn split_build_key(key: &str) -> (&str, Option<&str>) {
+    match key.split_once(OPTIONS_SEPARATOR) {
+        Some((version, options)) => (version, Some(options)),
+        None => (key, None),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct StepResult {
     pub name: Option<String>,
     pub step_hash: String,
     pub timestamp: String,
     pub output_path: Option<PathBuf>,
     pub status: String,
+    /// Provenance: the URL a Fetch step actually downloaded from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Hash of the recipe file and the version entry the step was built
+    /// from; steps of another recipe are stale even when the step is the same.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipe_hash: Option<String>,
+}
+
+/// A file in the output of a step, as found right after the step ran.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ArtifactFile {
+    /// Relative to the step output; empty when the output is a single file.
+    pub path: String,
+    pub size: u64,
+    /// Modification time (unix seconds), to reuse hashes of unchanged files.
+    pub modified: u64,
+    /// None for symlinks.
+    pub sha256: Option<String>,
+}
+
+/// The files of a step output, recorded after the step ran (`pi package artifacts`).
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct StepInventory {
+    /// Hash of the step the inventory belongs to, like `StepResult::step_hash`.
+    pub step_hash: String,
+    pub kind: String,
+    pub files: Vec<ArtifactFile>,
+}
+
+/// What the output of a built version takes on disk, recorded after every
+/// build of it.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct Footprint {
+    pub bytes: u64,
+    /// Regular files; symlinks and directories don't count.
+    pub files: usize,
+}
+
+impl Footprint {
+    /// The footprint of a step output from its inventory.
+    pub fn of(files: &[ArtifactFile]) -> Self {
+        files.iter().filter(|f| f.sha256.is_some())
+            .fold(Self::default(), |fp, f| Self { bytes: fp.bytes + f.size, files: fp.files + 1 })
+    }
+
+    /// The footprint of `root` (a directory or a single file), by walking it.
+    pub fn scan(root: &Path) -> Self {
+        WalkDir::new(root).into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .filter_map(|e| e.metadata().ok())
+            .fold(Self::default(), |fp, m| Self { bytes: fp.bytes + m.len(), files: fp.files + 1 })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct PackageBuildCache {
+    /// The package, for listings (file names are sanitized); set with the first footprint.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub pkgname: String,
     pub versions: HashMap<String, Vec<StepResult>>,
+    /// Footprint of the output of each build key of `versions`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub footprints: HashMap<String, Footprint>,
+}
+
+impl PackageBuildCache {
+    /// Footprints of build keys whose output still exists, sorted by key.
+    pub fn existing_footprints(&self) -> Vec<(&str, Footprint)> {
+        let mut footprints: Vec<(&str, Footprint)> = self.footprints.iter()
+            .filter(|(key, _)| {
+                self.versions.get(*key)
+                    .and_then(|steps| steps.last())
+                    .and_then(|step| step.output_path.as_ref())
+                    .is_some_and(|path| path.exists())
+            })
+            .map(|(key, fp)| (key.as_str(), *fp))
+            .collect();
+        footprints.sort_by(|a, b| a.0.cmp(b.0));
+        footprints
+    }
 }
 
 pub struct BuildCache {
@@ -38,10 +131,10 @@ impl BuildCache {
 
     pub fn load(&self, pkgname: &str) -> PackageBuildCache {
         let path = self.get_file_path(pkgname);
-        if let Ok(content) = fs::read_to_string(path) {
-            if let Ok(cache) = serde_json::from_str(&content) {
-                return cache;
-            }
+        if let Ok(content) = fs::read_to_string(path)
+            && let Ok(cache) = serde_json::from_str(&content)
+        {
+            return cache;
         }
         PackageBuildCache::default()
     }
@@ -53,25 +146,113 @@ impl BuildCache {
         Ok(())
     }
 
-    pub fn get_step_result(&self, pkgname: &str, version: &str, step_index: usize, step_hash: &str) -> Option<StepResult> {
+    /// Loads the build cache of every package.
+    pub fn load_all(&self) -> Vec<PackageBuildCache> {
+        let Ok(read_dir) = fs::read_dir(&self.cache_dir) else { return Vec::new() };
+        read_dir
+            .filter_map(|e| e.ok())
+            .filter_map(|e| fs::read_to_string(e.path()).ok())
+            .filter_map(|content| serde_json::from_str(&content).ok())
+            .collect()
+    }
+
+    /// The build cache of every package, keyed by its sanitized name (which
+    /// `load`, `save` and the inventory functions accept as well).
+    pub fn packages(&self) -> Vec<(String, PackageBuildCache)> {
+        let Ok(read_dir) = fs::read_dir(&self.cache_dir) else { return Vec::new() };
+        let mut packages: Vec<(String, PackageBuildCache)> = read_dir
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let name = e.path().file_name()?.to_str()?.strip_suffix(".json")?.to_string();
+                let cache = serde_json::from_str(&fs::read_to_string(e.path()).ok()?).ok()?;
+                Some((name, cache))
+            })
+            .collect();
+        packages.sort_by(|a, b| a.0.cmp(&b.0));
+        packages
+    }
+
+    pub fn get_step_result(&self, pkgname: &str, version: &str, step_index: usize, step_hash: &str, recipe_hash: &str) -> Option<StepResult> {
         let cache = self.load(pkgname);
-        if let Some(steps) = cache.versions.get(version) {
-            if let Some(result) = steps.get(step_index) {
-                if result.step_hash == step_hash && result.status == "Success" {
-                    // Check if the output path still exists
-                    if let Some(ref path) = result.output_path {
-                        if !path.exists() {
-                            log::debug!("cached output path for {} (step {}) does not exist: {:?}", pkgname, step_index, path);
-                            return None;
-                        }
-                    }
-                    return Some(result.clone());
-                }
+        if let Some(steps) = cache.versions.get(version)
+            && let Some(result) = steps.get(step_index)
+            && result.step_hash == step_hash && result.status == "Success"
+            && result.recipe_hash.as_deref().is_none_or(|hash| hash == recipe_hash)
+        {
+            // Check if the output path still exists
+            if let Some(ref path) = result.output_path
+                && !path.exists()
+            {
+                log::debug!("cached output path for {} (step {}) does not exist: {:?}", pkgname, step_index, path);
+                return None;
             }
+            return Some(result.clone());
         }
         None
     }
 
+    fn inventory_path(&self, pkgname: &str, version: &str, step_index: usize) -> PathBuf {
+        self.cache_dir.join("inventory").join(sanitize_name(pkgname)).join(format!("{}-step{}.json", sanitize_name(version), step_index))
+    }
+
+    pub fn save_inventory(&self, pkgname: &str, version: &str, step_index: usize, inventory: &StepInventory) -> Result<()> {
+        let path = self.inventory_path(pkgname, version, step_index);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, serde_json::to_string(inventory)?)?;
+        Ok(())
+    }
+
+    /// The inventory of step `step_index`, if one was recorded for `step_hash`.
+    pub fn load_inventory(&self, pkgname: &str, version: &str, step_index: usize, step_hash: &str) -> Option<StepInventory> {
+        let content = fs::read_to_string(self.inventory_path(pkgname, version, step_index)).ok()?;
+        serde_json::from_str::<StepInventory>(&content).ok().filter(|i| i.step_hash == step_hash)
+    }
+
+    /// Records the footprint of build `key` of the package, saving only when it changed.
+    pub fn record_footprint(&self, pkgname: &str, key: &str, footprint: Footprint) -> Result<()> {
+        let mut cache = self.load(pkgname);
+        if cache.pkgname == pkgname && cache.footprints.get(key) == Some(&footprint) {
+            return Ok(());
+        }
+        cache.pkgname = pkgname.to_string();
+        cache.footprints.insert(key.to_string(), footprint);
+        self.save(pkgname, &cache)
+    }
+
+    /// Forgets the builds of a package, only those of `version` when given
+    /// (every build key of it, whatever the options), with their step logs.
+    /// Returns the step outputs the forgotten builds recorded, for the
+    /// caller to remove.
+    pub fn purge(&self, pkgname: &str, version: Option<&str>) -> Result<Vec<PathBuf>> {
+        let mut cache = self.load(pkgname);
+        let keys: Vec<String> = cache.versions.keys().chain(cache.footprints.keys())
+            .filter(|key| version.is_none_or(|v| split_build_key(key).0 == v))
+            .cloned()
+            .collect();
+        let mut outputs = Vec::new();
+        for key in &keys {
+            cache.footprints.remove(key);
+            let Some(steps) = cache.versions.remove(key) else { continue };
+            for (index, step) in steps.into_iter().enumerate() {
+                let _ = fs::remove_file(self.inventory_path(pkgname, key, index));
+                outputs.extend(step.output_path);
+            }
+        }
+        crate::services::step_logs::remove(&self.cache_dir, pkgname, version);
+        if cache.versions.is_empty() {
+            let _ = fs::remove_dir(self.cache_dir.join("inventory").join(sanitize_name(pkgname)));
+            let path = self.get_file_path(pkgname);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        } else {
+            self.save(pkgname, &cache)?;
+        }
+        outputs.sort();
+        outputs.dedup();
+        Ok(outputs)
+    }
+
     pub fn update_step_result(&self, pkgname: &str, version: &str, step_index: usize, result: StepResult) -> Result<()> {
         let mut cache = self.load(pkgname);
         let steps = cache.versions.entry(version.to_string()).or_default();
@@ -86,11 +267,9 @@ impl BuildCache {
         } else {
             while steps.len() < step_index {
                 steps.push(StepResult {
-                    name: None,
                     step_hash: "unknown".to_string(),
-                    timestamp: "".to_string(),
-                    output_path: None,
                     status: "Skipped".to_string(),
+                    ..Default::default()
                 });
             }
             steps.push(result);
@@ -99,3 +278,126 @@ impl BuildCache {
         self.save(pkgname, &cache)
     }
 }
+
+/// Lists the files under `root` (or `root` itself when it is a file) with
+/// their SHA-256. Hashes from `previous` are reused for files whose size and
+/// modification time did not change.
+pub fn scan_inventory(root: &Path, previous: &[ArtifactFile]) -> Result<Vec<ArtifactFile>> {
+    let known: HashMap<&str, &ArtifactFile> = previous.iter().map(|f| (f.path.as_str(), f)).collect();
+    let mut files = Vec::new();
+    for entry in WalkDir::new(root).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let meta = entry.path().symlink_metadata()?;
+        let path = entry.path().strip_prefix(root)?.to_string_lossy().into_owned();
+        let modified = meta.modified().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let sha256 = if entry.path_is_symlink() {
+            None
+        } else {
+            match known.get(path.as_str()) {
+                Some(f) if f.size == meta.len() && f.modified == modified && f.sha256.is_some() => f.sha256.clone(),
+                _ => Some(calculate_file_checksum(entry.path(), 64)?),
+            }
+        };
+        files.push(ArtifactFile { path, size: meta.len(), modified, sha256 });
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inventory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("go-extracted");
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::write(root.join("bin/go"), "go").unwrap();
+        std::os::unix::fs::symlink("bin/go", root.join("go")).unwrap();
+
+        let files = scan_inventory(&root, &[]).unwrap();
+        let paths: Vec<&str> = files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, vec!["bin/go", "go"]);
+        assert_eq!(files[0].size, 2);
+        assert_eq!(files[1].sha256, None);
+        let single = scan_inventory(&root.join("bin/go"), &[]).unwrap();
+        assert_eq!(single[0].path, "");
+        assert_eq!(single[0].sha256, files[0].sha256);
+
+        let cache = BuildCache::new(tmp.path().join("cache"));
+        let inventory = StepInventory { step_hash: "h1".to_string(), kind: "Extract".to_string(), files };
+        cache.save_inventory("go", "1.22.0", 1, &inventory).unwrap();
+        assert!(cache.load_inventory("go", "1.22.0", 1, "h1").is_some());
+        assert!(cache.load_inventory("go", "1.22.0", 1, "h2").is_none());
+        assert_eq!(cache.load_all().len(), 0);
+
+        let footprint = Footprint::of(&inventory.files);
+        assert_eq!(footprint, Footprint { bytes: 2, files: 1 });
+        assert_eq!(Footprint::scan(&root), footprint);
+        cache.record_footprint("go", "1.22.0", footprint).unwrap();
+        cache.update_step_result("go", "1.22.0", 0, StepResult { output_path: Some(root.clone()), ..Default::default() }).unwrap();
+        let loaded = cache.load("go");
+        assert_eq!(loaded.pkgname, "go");
+        assert_eq!(loaded.existing_footprints(), vec![("1.22.0", footprint)]);
+        fs::remove_dir_all(&root).unwrap();
+        assert!(cache.load("go").existing_footprints().is_empty());
+    }
+
+    #[test]
+    fn test_recipe_hash_and_purge() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = BuildCache::new(tmp.path().to_path_buf());
+        let step = |path: &str| StepResult {
+            step_hash: "s1".to_string(), status: "Success".to_string(), recipe_hash: Some("r1".to_string()),
+            output_path: Some(tmp.path().join(path)), ..Default::default()
+        };
+        fs::create_dir_all(tmp.path().join("go-1.22-extracted")).unwrap();
+        cache.update_step_result("go", "1.22", 0, step("go-1.22-extracted")).unwrap();
+        cache.update_step_result("go", "1.22#abc", 0, step("go-1.22#abc-extracted")).unwrap();
+        cache.update_step_result("go", "1.23", 0, step("go-1.23-extracted")).unwrap();
+        assert!(cache.get_step_result("go", "1.22", 0, "s1", "r1").is_some());
+        // Another recipe is a miss.
+        assert!(cache.get_step_result("go", "1.22", 0, "s1", "r2").is_none());
+
+        let outputs = cache.purge("go", Some("1.22")).unwrap();
+        assert_eq!(outputs, vec![tmp.path().join("go-1.22#abc-extracted"), tmp.path().join("go-1.22-extracted")]);
+        let mut left: Vec<String> = cache.load("go").versions.into_keys().collect();
+        left.sort();
+        assert_eq!(left, vec!["1.23"]);
+        assert_eq!(cache.purge("go", None).unwrap().len(), 1);
+        assert!(cache.packages().is_empty());
+    }
+
+    #[test]
+    fn test_purge_keeps_build_metadata_versions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = BuildCache::new(tmp.path().to_path_buf());
+        let step = StepResult { step_hash: "s1".to_string(), status: "Success".to_string(), ..Default::default() };
+        for key in ["1.0.0", "1.0.0+build5", "1.0.0+build5#abc"] {
+            cache.update_step_result("tool", key, 0, step.clone()).unwrap();
+        }
+        assert_eq!(split_build_key("1.0.0+build5#abc"), ("1.0.0+build5", Some("abc")));
+
+        cache.purge("tool", Some("1.0.0")).unwrap();
+        let mut left: Vec<String> = cache.load("tool").versions.into_keys().collect();
+        left.sort();
+        assert_eq!(left, vec!["1.0.0+build5", "1.0.0+build5#abc"]);
+    }
+
+    #[test]
+    fn test_entries_without_recipe_hash_hit() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = BuildCache::new(tmp.path().to_path_buf());
+        // Recorded before steps were keyed by recipe.
+        let step = StepResult { step_hash: "s1".to_string(), status: "Success".to_string(), ..Default::default() };
+        cache.update_step_result("go", "1.22", 0, step).unwrap();
+        assert!(cache.get_step_result("go", "1.22", 0, "s1", "r1").is_some());
+        assert!(cache.get_step_result("go", "1.22", 0, "s2", "r1").is_none());
+    }
+}
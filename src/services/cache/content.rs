@@ -24,7 +24,8 @@ impl Cache {
         self.dir.join(sanitized)
     }
 
-    pub fn read(&self, url: &str) -> Result<Option<String>> {
+    /// Reads the raw cached content of `url`, None if missing or expired.
+    pub fn read(&self, url: &str) -> Result<Option<Vec<u8>>> {
         let path = self.get_path(url);
         if !path.exists() {
             return Ok(None);
@@ -36,11 +37,11 @@ impl Cache {
             return Ok(None);
         }
 
-        let content = fs::read_to_string(path)?;
+        let content = fs::read(path)?;
         Ok(Some(content))
     }
 
-    pub fn write(&self, url: &str, content: &str) -> Result<()> {
+    pub fn write(&self, url: &str, content: &[u8]) -> Result<()> {
         if !self.dir.exists() {
             fs::create_dir_all(&self.dir)?;
         }
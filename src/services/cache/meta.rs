@@ -0,0 +1,346 @@
+use crate::models::config::Config;
+use crate::services::db::Db;
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+/// Storage of the cached package and version lists in `cache_meta_dir`.
+///
+/// By default every list is a pretty-printed JSON file. With
+/// `meta_format = "compact"` they are compact JSON values in a single redb
+/// table (`meta.redb`), keyed by the file name they would otherwise have;
+/// thousands of small files become one. Switching the setting migrates the
+/// existing entries on first use. When the table can't be opened in time
+/// (another pi keeps it busy), lists are written as files and the names
+/// and prefixes they replace or remove are recorded in `PENDING`; until a
+/// later access removes those from the table and imports the files, the
+/// table isn't used, so its stale lists are never served.
+pub struct MetaStore;
+
+impl MetaStore {
+    pub const FILENAME: &'static str = "meta.redb";
+    /// Names and prefixes to remove from the table, one per line.
+    const PENDING: &'static str = "meta.redb.pending";
+    /// Written once `migrate_version_keys` ran.
+    const VERSION_KEYS_MARKER: &'static str = "version-keys-escaped";
+
+    /// Reads the list cached at `path` (see `Config::version_cache_file`).
+    pub fn read<T: DeserializeOwned>(config: &Config, path: &Path) -> Result<T> {
+        if let Some(db) = table(config)
+            && let Some(bytes) = db.meta_get(&key(path))?
+        {
+            return serde_json::from_slice(&bytes)
+                .with_context(|| format!("Failed to parse cached {}", key(path)));
+        }
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read cache file: {:?}", path))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse cache file: {:?}", path))
+    }
+
+    pub fn write<T: Serialize>(config: &Config, path: &Path, value: &T) -> Result<()> {
+        if let Some(db) = table(config) {
+            let bytes = serde_json::to_vec(value).context("Failed to serialize cache entry")?;
+            match db.meta_put(&[(key(path), bytes)]) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    log::debug!("[meta] write {}: {:#}, writing a file", key(path), e);
+                    record_pending(config, &key(path))?;
+                }
+            }
+        } else if config.meta_db().is_some() {
+            record_pending(config, &key(path))?;
+        }
+        fs::create_dir_all(&config.cache_meta_dir).context("Failed to create meta directory")?;
+        let content = serde_json::to_string_pretty(value).context("Failed to serialize cache entry")?;
+        fs::write(path, content).with_context(|| format!("Failed to write cache file: {:?}", path))
+    }
+
+    /// Removes every cached list whose file name starts with `prefix`.
+    pub fn remove_prefix(config: &Config, prefix: &str) {
+        let removed = match table(config) {
+            Some(db) => db.meta_remove_prefix(prefix)
+                .inspect_err(|e| log::debug!("[meta] remove {}*: {:#}", prefix, e))
+                .is_ok(),
+            None => config.meta_db().is_none(),
+        };
+        if !removed && let Err(e) = record_pending(config, prefix) {
+            log::warn!("[meta] failed to record the removal of {}*: {:#}", prefix, e);
+        }
+        if let Ok(entries) = fs::read_dir(&config.cache_meta_dir) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                if let Some(name) = entry.file_name().to_str()
+                    && name.starts_with(prefix) && name.ends_with(".json")
+                {
+                    let _ = fs::remove_file(entry.path());
+                }
+            }
+        }
+    }
+
+    /// File names of the cached lists starting with `prefix`, sorted.
+    pub fn names(config: &Config, prefix: &str) -> Vec<String> {
+        let mut names = std::collections::BTreeSet::new();
+        if let Some(db) = table(config) {
+            match db.meta_scan(prefix) {
+                Ok(entries) => names.extend(entries.into_iter().map(|(name, _)| name)),
+                Err(e) => log::debug!("[meta] scan {}*: {:#}", prefix, e),
+            }
+        }
+        if let Ok(entries) = fs::read_dir(&config.cache_meta_dir) {
+            names.extend(entries.filter_map(|e| e.ok())
+                .filter_map(|e| e.file_name().to_str().map(str::to_string))
+                .filter(|name| name.starts_with(prefix) && name.ends_with(".json")));
+        }
+        names.into_iter().collect()
+    }
+
+    /// Moves the version lists cached before `-` was escaped in repo names
+    /// (`version-main-extra-tool.json`) to their current names, once: the
+    /// marker `VERSION_KEYS_MARKER` records it is done. A list that could
+    /// also be another configured repo's (`main` with a package `extra-tool`)
+    /// is removed instead, to be synced again.
+    pub fn migrate_version_keys(config: &Config, repo_names: &[&str]) -> Result<()> {
+        let marker = config.cache_meta_dir.join(Self::VERSION_KEYS_MARKER);
+        if marker.exists() {
+            return Ok(());
+        }
+        let mut dashed: Vec<&str> = repo_names.iter().copied().filter(|name| name.contains('-')).collect();
+        // Longest first, so `a-b-c`'s lists aren't taken for `a-b`'s.
+        dashed.sort_by_key(|name| std::cmp::Reverse(name.len()));
+        let mut moved = 0;
+        for name in dashed {
+            let legacy = format!("version-{}-", name);
+            let owner = name.split('-').next().unwrap_or_default();
+            let ambiguous = repo_names.contains(&owner);
+            for old in Self::names(config, &legacy) {
+                let path = config.cache_meta_dir.join(&old);
+                if !ambiguous {
+                    let list: serde_json::Value = Self::read(config, &path)?;
+                    let new = format!("{}{}", Config::version_cache_prefix(name), &old[legacy.len()..]);
+                    Self::write(config, &config.cache_meta_dir.join(new), &list)?;
+                    moved += 1;
+                }
+                Self::remove_prefix(config, &old);
+            }
+        }
+        if moved > 0 {
+            log::info!("[meta] renamed {} cached version lists", moved);
+        }
+        fs::create_dir_all(&config.cache_meta_dir).context("Failed to create meta directory")?;
+        fs::write(&marker, "").with_context(|| format!("Failed to write {}", marker.display()))
+    }
+
+    /// Opens the table when the compact format is selected, first importing
+    /// lists left as files (later, through `PENDING`, when the table is busy).
+    /// With the file format, a table left from an earlier setting is
+    /// exported back to files and deleted.
+    pub fn open(config: &Config) -> Option<Db> {
+        let path = config.cache_meta_dir.join(Self::FILENAME);
+        let result = if config.settings.compact_meta() {
+            Db::open_file(&path).and_then(|db| {
+                if let Err(e) = sync_files(&config.cache_meta_dir, &db) {
+                    log::debug!("[meta] importing files later: {:#}", e);
+                    record_pending(config, "")?;
+                }
+                Ok(Some(db))
+            })
+        } else if path.exists() {
+            export_table(&config.cache_meta_dir, &path).map(|_| None)
+        } else {
+            Ok(None)
+        };
+        result.unwrap_or_else(|e| {
+            log::debug!("[meta] compact store unavailable: {:#}", e);
+            None
+        })
+    }
+}
+
+/// The compact store, once removals recorded in `PENDING` are applied
+/// (and the files written meanwhile imported); None while that fails.
+fn table(config: &Config) -> Option<&Db> {
+    let db = config.meta_db()?;
+    if !config.cache_meta_dir.join(MetaStore::PENDING).exists() {
+        return Some(db);
+    }
+    match sync_files(&config.cache_meta_dir, db) {
+        Ok(()) => Some(db),
+        Err(e) => {
+            log::debug!("[meta] pending removals not applied yet: {:#}", e);
+            None
+        }
+    }
+}
+
+/// Applies the removals recorded in `PENDING`, then imports the list files,
+/// which are newer than what they removed.
+fn sync_files(meta_dir: &Path, db: &Db) -> Result<()> {
+    let pending = meta_dir.join(MetaStore::PENDING);
+    let content = match fs::read_to_string(&pending) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", pending.display())),
+    };
+    for prefix in content.lines().filter(|l| !l.is_empty()) {
+        db.meta_remove_prefix(prefix)?;
+    }
+    import_files(meta_dir, db)?;
+    match fs::remove_file(&pending) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e).with_context(|| format!("Failed to remove {}", pending.display())),
+        _ => Ok(()),
+    }
+}
+
+fn record_pending(config: &Config, prefix: &str) -> Result<()> {
+    use std::io::Write;
+    fs::create_dir_all(&config.cache_meta_dir).context("Failed to create meta directory")?;
+    let path = config.cache_meta_dir.join(MetaStore::PENDING);
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    writeln!(file, "{}", prefix).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn key(path: &Path) -> String {
+    path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+fn is_list_file(name: &str) -> bool {
+    (name.starts_with("packages-") || name.starts_with("version-")) && name.ends_with(".json")
+}
+
+fn import_files(meta_dir: &Path, db: &Db) -> Result<()> {
+    let mut entries = Vec::new();
+    let mut files = Vec::new();
+    for entry in fs::read_dir(meta_dir)?.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !is_list_file(&name) {
+            continue;
+        }
+        // Re-encode compactly; unreadable files are dropped and resynced later.
+        if let Ok(value) = fs::read(entry.path()).map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(serde_json::from_slice::<serde_json::Value>(&bytes)?)) {
+            entries.push((name, serde_json::to_vec(&value)?));
+        }
+        files.push(entry.path());
+    }
+    if files.is_empty() {
+        return Ok(());
+    }
+    db.meta_put(&entries)?;
+    for file in &files {
+        let _ = fs::remove_file(file);
+    }
+    log::info!("[meta] moved {} cached lists into {}", entries.len(), MetaStore::FILENAME);
+    Ok(())
+}
+
+fn export_table(meta_dir: &Path, path: &Path) -> Result<()> {
+    let db = Db::open_file(path)?;
+    let entries = db.meta_scan("")?;
+    for (name, bytes) in &entries {
+        let file = meta_dir.join(name);
+        // A file written while the table was busy is newer.
+        if file.exists() {
+            continue;
+        }
+        let value: serde_json::Value = serde_json::from_slice(bytes)?;
+        fs::write(&file, serde_json::to_string_pretty(&value)?)
+            .with_context(|| format!("Failed to write cache file: {:?}", file))?;
+    }
+    drop(db);
+    fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+    log::info!("[meta] moved {} cached lists back to files", entries.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::version_entry::VersionList;
+
+    #[test]
+    fn test_migration_between_formats() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut config = Config::new_test(tmp.path().to_path_buf());
+        let file = config.version_cache_file("main", "go");
+        MetaStore::write(&config, &file, &VersionList { versions: Vec::new() }).unwrap();
+        assert!(file.exists());
+
+        config.settings.meta_format = Some("compact".to_string());
+        let compact = Config { state: Default::default(), ..config.clone() };
+        let list: VersionList = MetaStore::read(&compact, &file).unwrap();
+        assert!(list.versions.is_empty());
+        assert!(!file.exists());
+        assert!(config.cache_meta_dir.join(MetaStore::FILENAME).exists());
+        MetaStore::remove_prefix(&compact, "version-main-");
+        assert!(MetaStore::read::<VersionList>(&compact, &file).is_err());
+        MetaStore::write(&compact, &file, &VersionList { versions: Vec::new() }).unwrap();
+        drop(compact);
+
+        config.settings.meta_format = None;
+        let files = Config { state: Default::default(), ..config };
+        assert!(MetaStore::read::<VersionList>(&files, &file).is_ok());
+        assert!(file.exists());
+        assert!(!files.cache_meta_dir.join(MetaStore::FILENAME).exists());
+    }
+
+    #[test]
+    fn test_cached_names_per_repo() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let empty = VersionList { versions: Vec::new() };
+        for (repo, name) in [("main", "go"), ("main", "extra-tool"), ("main-extra", "tool"), ("main-extra", "npm:@types#node")] {
+            MetaStore::write(&config, &config.version_cache_file(repo, name), &empty).unwrap();
+        }
+        let sorted = |mut names: Vec<String>| { names.sort(); names };
+        assert_eq!(sorted(VersionList::cached_names(&config, "main")), vec!["extra-tool", "go"]);
+        assert_eq!(sorted(VersionList::cached_names(&config, "main-extra")), vec!["npm:@types/node", "tool"]);
+        assert_eq!(config.version_cache_file("main", "go"), config.cache_meta_dir.join("version-main-go.json"));
+    }
+
+    #[test]
+    fn test_removals_while_busy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut config = Config::new_test(tmp.path().to_path_buf());
+        config.settings.meta_format = Some("compact".to_string());
+        let (go, tool) = (config.version_cache_file("main", "go"), config.version_cache_file("main", "tool"));
+        MetaStore::write(&config, &go, &VersionList { versions: Vec::new() }).unwrap();
+        MetaStore::write(&config, &tool, &VersionList { versions: Vec::new() }).unwrap();
+
+        // A sync that couldn't get the table: its removal is pending and
+        // the list it wrote is a file.
+        record_pending(&config, "version-main-").unwrap();
+        fs::write(&tool, r#"{"versions": []}"#).unwrap();
+        let other = Config { state: Default::default(), ..config.clone() };
+        assert!(MetaStore::read::<VersionList>(&other, &go).is_err(), "stale list served");
+        assert!(MetaStore::read::<VersionList>(&other, &tool).is_ok());
+        assert!(!config.cache_meta_dir.join(MetaStore::PENDING).exists());
+        assert!(!tool.exists(), "imported into the table");
+        assert_eq!(MetaStore::names(&other, "version-main-"), vec!["version-main-tool.json"]);
+    }
+
+    #[test]
+    fn test_migrate_version_keys() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        fs::create_dir_all(&config.cache_meta_dir).unwrap();
+        for name in ["version-main-go.json", "version-main-extra-tool.json", "version-my-repo-node.json"] {
+            fs::write(config.cache_meta_dir.join(name), r#"{"versions": []}"#).unwrap();
+        }
+
+        MetaStore::migrate_version_keys(&config, &["main", "main-extra", "my-repo"]).unwrap();
+        let mut names = MetaStore::names(&config, "version-");
+        names.sort();
+        // main-extra's list could be main's `extra-tool`, so it goes.
+        assert_eq!(names, vec!["version-main-go.json", "version-my%2Drepo-node.json"]);
+        assert_eq!(VersionList::cached_names(&config, "my-repo"), vec!["node"]);
+
+        // Once only.
+        fs::write(config.cache_meta_dir.join("version-my-repo-late.json"), r#"{"versions": []}"#).unwrap();
+        MetaStore::migrate_version_keys(&config, &["my-repo"]).unwrap();
+        assert!(config.cache_meta_dir.join("version-my-repo-late.json").exists());
+    }
+}
@@ -1,5 +1,6 @@
 pub mod build;
 pub mod content;
+pub mod meta;
 
 pub use build::{BuildCache, StepResult};
 pub use content::Cache;
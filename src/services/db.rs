@@ -0,0 +1,652 @@
+use anyhow::{Context, Result};
+use redb::{Database, Durability, TableDefinition};
+use redb::ReadableTable;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Last-use timestamps (unix seconds) of cache entries, keyed by absolute path.
+const LAST_USE: TableDefinition<&str, u64> = TableDefinition::new("last_use");
+
+/// Journal of temporary build homes (path -> owning pid), so homes leaked by a
+/// killed process can be removed later.
+const BUILD_HOMES: TableDefinition<&str, u64> = TableDefinition::new("build_homes");
+
+/// Cached package and version lists in compact form (cache file name -> JSON),
+/// used instead of one file per list when `meta_format = "compact"`.
+const META: TableDefinition<&str, &[u8]> = TableDefinition::new("meta");
+
+/// URL each download was fetched from (absolute path -> primary URL), to
+/// detect two URLs competing for the same file name.
+const DOWNLOADS: TableDefinition<&str, &str> = TableDefinition::new("downloads");
+
+/// Binaries exported by cave builds: (binary, cave, variant) ->
+/// "package=version", the variant "" for the cave itself.
+const BINARIES: TableDefinition<(&str, &str, &str), &str> = TableDefinition::new("cave_binaries");
+
+/// BINARIES before variants had their own entries, dropped on the next write.
+const LEGACY_BINARIES: TableDefinition<(&str, &str), &str> = TableDefinition::new("binaries");
+
+/// Values recipes keep between syncs with `state_set`: (scope, key) -> value,
+/// the scope being "<repo>/<recipe path>".
+const RECIPE_STATE: TableDefinition<(&str, &str), &str> = TableDefinition::new("recipe_state");
+
+/// Selectors that recently failed to resolve: selector -> (repository
+/// generation, unix seconds until which the failure is trusted).
+const UNRESOLVED: TableDefinition<&str, (&str, u64)> = TableDefinition::new("unresolved");
+
+/// Lookups of managed packages (`go:`, `npm:`): "<repo>:<manager>:<package>"
+/// -> (whether the sync found versions, unix seconds of the sync).
+const MANAGER_LOOKUPS: TableDefinition<&str, (bool, u64)> = TableDefinition::new("manager_lookups");
+
+/// How long an operation waits for another pi process to close the database.
+const OPEN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest value a recipe may store under one key.
+pub const MAX_STATE_VALUE: usize = 64 * 1024;
+/// Total size of keys and values one recipe scope may hold.
+pub const MAX_STATE_SCOPE: usize = 1024 * 1024;
+
+/// One `state_set` entry of a recipe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateEntry {
+    pub scope: String,
+    pub key: String,
+    pub value: String,
+}
+
+/// A cave (variant) exporting a binary, as recorded by its last build.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BinaryProvider {
+    pub cave: String,
+    /// Variant name without the ':', None for the cave itself.
+    pub variant: Option<String>,
+    pub package: String,
+    pub version: String,
+}
+
+impl BinaryProvider {
+    /// The cave as users name it: "web", or "web:dev" for a variant.
+    pub fn owner(&self) -> String {
+        match &self.variant {
+            Some(v) => format!("{}:{}", self.cave, v),
+            None => self.cave.clone(),
+        }
+    }
+}
+
+/// Small embedded key-value store for bookkeeping that must survive across runs.
+/// Stored at `<state_dir>/pi.redb`.
+///
+/// redb locks the file for as long as it is open, so the file is opened for
+/// each operation and closed after it: a long `pi cave shell` or `pi serve`
+/// holding a `Db` doesn't lock other pi processes out.
+pub struct Db {
+    path: PathBuf,
+    /// Serializes this process's operations, which would otherwise find
+    /// the file locked by each other.
+    lock: parking_lot::Mutex<()>,
+}
+
+impl std::fmt::Debug for Db {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Db")
+    }
+}
+
+/// The database opened for one operation.
+struct Connection<'a> {
+    db: Database,
+    _lock: parking_lot::MutexGuard<'a, ()>,
+}
+
+impl std::ops::Deref for Connection<'_> {
+    type Target = Database;
+    fn deref(&self) -> &Database {
+        &self.db
+    }
+}
+
+impl Db {
+    pub const FILENAME: &'static str = "pi.redb";
+
+    pub fn open(state_dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(state_dir).context("Failed to create state directory")?;
+        Self::open_file(&state_dir.join(Self::FILENAME))
+    }
+
+    /// A handle on the database at `path`, created if missing. Fails when
+    /// the file can't be used at all; one another process has open is
+    /// waited for by each operation instead.
+    pub fn open_file(path: &Path) -> Result<Self> {
+        match create(path, Duration::ZERO) {
+            Ok(_) | Err(redb::DatabaseError::DatabaseAlreadyOpen) => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to open database: {}", path.display())),
+        }
+        Ok(Self { path: path.to_path_buf(), lock: parking_lot::Mutex::new(()) })
+    }
+
+    fn connect(&self) -> Result<Connection<'_>> {
+        let lock = self.lock.lock();
+        let db = create(&self.path, OPEN_TIMEOUT)
+            .with_context(|| format!("Failed to open database: {}", self.path.display()))?;
+        Ok(Connection { db, _lock: lock })
+    }
+
+    /// Records that `path` was just used.
+    pub fn touch(&self, path: &Path) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let key = path.to_string_lossy();
+        let db = self.connect()?;
+        let mut txn = db.begin_write()?;
+        // Timestamps are advisory, skip the fsync.
+        txn.set_durability(Durability::Eventual);
+        {
+            let mut table = txn.open_table(LAST_USE)?;
+            table.insert(key.as_ref(), now)?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub fn last_use(&self, path: &Path) -> Result<Option<u64>> {
+        let db = self.connect()?;
+        let txn = db.begin_read()?;
+        let table = match txn.open_table(LAST_USE) {
+            Ok(t) => t,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let key = path.to_string_lossy();
+        Ok(table.get(key.as_ref())?.map(|v| v.value()))
+    }
+
+    /// Forgets the last use and download source of an evicted `path`.
+    pub fn forget(&self, path: &Path) -> Result<()> {
+        let key = path.to_string_lossy();
+        let db = self.connect()?;
+        let txn = db.begin_write()?;
+        {
+            let mut table = txn.open_table(LAST_USE)?;
+            table.remove(key.as_ref())?;
+            let mut table = txn.open_table(DOWNLOADS)?;
+            table.remove(key.as_ref())?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub fn record_download(&self, path: &Path, url: &str) -> Result<()> {
+        let key = path.to_string_lossy();
+        let db = self.connect()?;
+        let txn = db.begin_write()?;
+        {
+            let mut table = txn.open_table(DOWNLOADS)?;
+            table.insert(key.as_ref(), url)?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// The URL `path` was downloaded from, if recorded.
+    pub fn download_source(&self, path: &Path) -> Result<Option<String>> {
+        let db = self.connect()?;
+        let txn = db.begin_read()?;
+        let table = match txn.open_table(DOWNLOADS) {
+            Ok(t) => t,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let key = path.to_string_lossy();
+        Ok(table.get(key.as_ref())?.map(|v| v.value().to_string()))
+    }
+
+    pub fn record_build_home(&self, path: &Path, pid: u32) -> Result<()> {
+        let key = path.to_string_lossy();
+        let db = self.connect()?;
+        let txn = db.begin_write()?;
+        {
+            let mut table = txn.open_table(BUILD_HOMES)?;
+            table.insert(key.as_ref(), u64::from(pid))?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub fn forget_build_home(&self, path: &Path) -> Result<()> {
+        let key = path.to_string_lossy();
+        let db = self.connect()?;
+        let txn = db.begin_write()?;
+        {
+            let mut table = txn.open_table(BUILD_HOMES)?;
+            table.remove(key.as_ref())?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// All journaled build homes with the pid that created them.
+    pub fn build_homes(&self) -> Result<Vec<(PathBuf, u64)>> {
+        let db = self.connect()?;
+        let txn = db.begin_read()?;
+        let table = match txn.open_table(BUILD_HOMES) {
+            Ok(t) => t,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut homes = Vec::new();
+        for entry in table.iter()? {
+            let (path, pid) = entry?;
+            homes.push((PathBuf::from(path.value()), pid.value()));
+        }
+        Ok(homes)
+    }
+
+    pub fn meta_get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let db = self.connect()?;
+        let txn = db.begin_read()?;
+        let table = match txn.open_table(META) {
+            Ok(t) => t,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(table.get(key)?.map(|v| v.value().to_vec()))
+    }
+
+    /// Stores all `entries` in one transaction.
+    pub fn meta_put(&self, entries: &[(String, Vec<u8>)]) -> Result<()> {
+        let db = self.connect()?;
+        let mut txn = db.begin_write()?;
+        // A cache: losing the latest writes on a crash only costs a resync.
+        txn.set_durability(Durability::Eventual);
+        {
+            let mut table = txn.open_table(META)?;
+            for (key, value) in entries {
+                table.insert(key.as_str(), value.as_slice())?;
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// All entries whose key starts with `prefix`.
+    pub fn meta_scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let db = self.connect()?;
+        let txn = db.begin_read()?;
+        let table = match txn.open_table(META) {
+            Ok(t) => t,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut entries = Vec::new();
+        for entry in table.range(prefix..)? {
+            let (key, value) = entry?;
+            if !key.value().starts_with(prefix) {
+                break;
+            }
+            entries.push((key.value().to_string(), value.value().to_vec()));
+        }
+        Ok(entries)
+    }
+
+    /// Removes all entries whose key starts with `prefix`.
+    pub fn meta_remove_prefix(&self, prefix: &str) -> Result<()> {
+        let keys: Vec<String> = self.meta_scan(prefix)?.into_iter().map(|(k, _)| k).collect();
+        let db = self.connect()?;
+        let txn = db.begin_write()?;
+        {
+            let mut table = txn.open_table(META)?;
+            for key in &keys {
+                table.remove(key.as_str())?;
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Replaces the binaries recorded for `cave` built as `variant` (without
+    /// the ':', None for the cave itself) with `binaries` (binary, package,
+    /// version). Other variants of the cave keep theirs.
+    pub fn set_cave_binaries(&self, cave: &str, variant: Option<&str>, binaries: &[(String, String, String)]) -> Result<()> {
+        let variant = variant.unwrap_or("");
+        let db = self.connect()?;
+        let txn = db.begin_write()?;
+        txn.delete_table(LEGACY_BINARIES)?;
+        {
+            let mut table = txn.open_table(BINARIES)?;
+            let mut stale = Vec::new();
+            for entry in table.iter()? {
+                let (key, _) = entry?;
+                let (binary, owner, built) = key.value();
+                if owner == cave && built == variant {
+                    stale.push(binary.to_string());
+                }
+            }
+            for binary in &stale {
+                table.remove((binary.as_str(), cave, variant))?;
+            }
+            for (binary, package, version) in binaries {
+                table.insert((binary.as_str(), cave, variant), format!("{}={}", package, version).as_str())?;
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Caves (variants) whose last build exported `binary`, ordered by cave
+    /// and variant name.
+    pub fn binary_providers(&self, binary: &str) -> Result<Vec<BinaryProvider>> {
+        let db = self.connect()?;
+        let txn = db.begin_read()?;
+        let table = match txn.open_table(BINARIES) {
+            Ok(t) => t,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut providers = Vec::new();
+        for entry in table.range((binary, "", "")..)? {
+            let (key, value) = entry?;
+            let (name, cave, variant) = key.value();
+            if name != binary {
+                break;
+            }
+            let value = value.value();
+            let (package, version) = value.split_once('=').unwrap_or((value, ""));
+            providers.push(BinaryProvider {
+                cave: cave.to_string(),
+                variant: (!variant.is_empty()).then(|| variant.to_string()),
+                package: package.to_string(),
+                version: version.to_string(),
+            });
+        }
+        Ok(providers)
+    }
+
+    /// Remembers that `selector` didn't resolve against repositories of
+    /// `generation`, until unix second `until`.
+    pub fn record_unresolved(&self, selector: &str, generation: &str, until: u64) -> Result<()> {
+        let db = self.connect()?;
+        let mut txn = db.begin_write()?;
+        // Losing an entry only costs a retry.
+        txn.set_durability(Durability::Eventual);
+        {
+            let mut table = txn.open_table(UNRESOLVED)?;
+            table.insert(selector, (generation, until))?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Whether `selector` failed to resolve against repositories of
+    /// `generation` and the failure hasn't expired by `now`.
+    pub fn is_unresolved(&self, selector: &str, generation: &str, now: u64) -> Result<bool> {
+        let db = self.connect()?;
+        let txn = db.begin_read()?;
+        let table = match txn.open_table(UNRESOLVED) {
+            Ok(t) => t,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(table.get(selector)?.is_some_and(|v| {
+            let (recorded, until) = v.value();
+            recorded == generation && now < until
+        }))
+    }
+
+    /// Records the outcome of syncing the managed package `key` at unix second `at`.
+    pub fn record_manager_lookup(&self, key: &str, found: bool, at: u64) -> Result<()> {
+        let db = self.connect()?;
+        let mut txn = db.begin_write()?;
+        // Losing an entry only costs a sync.
+        txn.set_durability(Durability::Eventual);
+        {
+            let mut table = txn.open_table(MANAGER_LOOKUPS)?;
+            table.insert(key, (found, at))?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// The last recorded lookup of the managed package `key`: whether it was
+    /// found, and when.
+    pub fn manager_lookup(&self, key: &str) -> Result<Option<(bool, u64)>> {
+        let db = self.connect()?;
+        let txn = db.begin_read()?;
+        let table = match txn.open_table(MANAGER_LOOKUPS) {
+            Ok(t) => t,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(table.get(key)?.map(|v| v.value()))
+    }
+
+    pub fn state_get(&self, scope: &str, key: &str) -> Result<Option<String>> {
+        let db = self.connect()?;
+        let txn = db.begin_read()?;
+        let table = match txn.open_table(RECIPE_STATE) {
+            Ok(t) => t,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(table.get((scope, key))?.map(|v| v.value().to_string()))
+    }
+
+    /// Stores `value` under `key` of `scope`, or removes the key for None.
+    /// Fails when the value or the scope would exceed its quota.
+    pub fn state_set(&self, scope: &str, key: &str, value: Option<&str>) -> Result<()> {
+        let db = self.connect()?;
+        let txn = db.begin_write()?;
+        {
+            let mut table = txn.open_table(RECIPE_STATE)?;
+            match value {
+                Some(value) => {
+                    if value.len() > MAX_STATE_VALUE {
+                        anyhow::bail!("value of '{}' is {} bytes, the limit is {}", key, value.len(), MAX_STATE_VALUE);
+                    }
+                    let mut used = key.len() + value.len();
+                    for entry in table.range((scope, "")..)? {
+                        let (k, v) = entry?;
+                        let (s, k) = k.value();
+                        if s != scope {
+                            break;
+                        }
+                        if k != key {
+                            used += k.len() + v.value().len();
+                        }
+                    }
+                    if used > MAX_STATE_SCOPE {
+                        anyhow::bail!("state of {} would take {} bytes, the limit is {}", scope, used, MAX_STATE_SCOPE);
+                    }
+                    table.insert((scope, key), value)?;
+                }
+                None => {
+                    table.remove((scope, key))?;
+                }
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Entries of all scopes starting with `prefix`, ordered by scope and key.
+    pub fn state_scan(&self, prefix: &str) -> Result<Vec<StateEntry>> {
+        let db = self.connect()?;
+        let txn = db.begin_read()?;
+        let table = match txn.open_table(RECIPE_STATE) {
+            Ok(t) => t,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut entries = Vec::new();
+        for entry in table.range((prefix, "")..)? {
+            let (key, value) = entry?;
+            let (scope, key) = key.value();
+            if !scope.starts_with(prefix) {
+                break;
+            }
+            entries.push(StateEntry {
+                scope: scope.to_string(),
+                key: key.to_string(),
+                value: value.value().to_string(),
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Removes the entries of all scopes starting with `prefix`; returns how many.
+    pub fn state_clear(&self, prefix: &str) -> Result<usize> {
+        let entries = self.state_scan(prefix)?;
+        let db = self.connect()?;
+        let txn = db.begin_write()?;
+        {
+            let mut table = txn.open_table(RECIPE_STATE)?;
+            for e in &entries {
+                table.remove((e.scope.as_str(), e.key.as_str()))?;
+            }
+        }
+        txn.commit()?;
+        Ok(entries.len())
+    }
+}
+
+/// Opens (creating) the database at `path`, retrying for up to `timeout`
+/// while another process has it open.
+fn create(path: &Path, timeout: Duration) -> Result<Database, redb::DatabaseError> {
+    let started = Instant::now();
+    let mut wait = Duration::from_millis(5);
+    loop {
+        match Database::create(path) {
+            Err(redb::DatabaseError::DatabaseAlreadyOpen) if started.elapsed() < timeout => {
+                std::thread::sleep(wait);
+                wait = (wait * 2).min(Duration::from_millis(100));
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_touch_and_forget() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Db::open(tmp.path()).unwrap();
+        let entry = tmp.path().join("downloads").join("go.tar.gz");
+
+        assert_eq!(db.last_use(&entry).unwrap(), None);
+        db.touch(&entry).unwrap();
+        assert!(db.last_use(&entry).unwrap().is_some());
+        db.forget(&entry).unwrap();
+        assert_eq!(db.last_use(&entry).unwrap(), None);
+    }
+
+    #[test]
+    fn test_build_home_journal() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Db::open(tmp.path()).unwrap();
+        let home = tmp.path().join("pi-home-1");
+
+        assert!(db.build_homes().unwrap().is_empty());
+        db.record_build_home(&home, 42).unwrap();
+        assert_eq!(db.build_homes().unwrap(), vec![(home.clone(), 42)]);
+        db.forget_build_home(&home).unwrap();
+        assert!(db.build_homes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_binary_index() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Db::open(tmp.path()).unwrap();
+        let bin = |b: &str, p: &str, v: &str| (b.to_string(), p.to_string(), v.to_string());
+
+        db.set_cave_binaries("web", None, &[bin("cargo", "rust", "1.80.0"), bin("node", "node", "20.5.0")]).unwrap();
+        db.set_cave_binaries("cli", None, &[bin("cargo", "rust", "1.75.0"), bin("cargo-nextest", "rust:cargo-nextest", "0.9.0")]).unwrap();
+        let caves = |b: &str| db.binary_providers(b).unwrap().into_iter().map(|p| format!("{} {}={}", p.cave, p.package, p.version)).collect::<Vec<_>>();
+        assert_eq!(caves("cargo"), vec!["cli rust=1.75.0", "web rust=1.80.0"]);
+
+        // A rebuild replaces the cave's entries.
+        db.set_cave_binaries("web", None, &[bin("node", "node", "22.0.0")]).unwrap();
+        assert_eq!(caves("cargo"), vec!["cli rust=1.75.0"]);
+        assert_eq!(caves("node"), vec!["web node=22.0.0"]);
+        assert!(caves("go").is_empty());
+    }
+
+    #[test]
+    fn test_binary_index_per_variant() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Db::open(tmp.path()).unwrap();
+        let bin = |b: &str, p: &str, v: &str| (b.to_string(), p.to_string(), v.to_string());
+        let owners = |b: &str| db.binary_providers(b).unwrap().into_iter().map(|p| format!("{} {}", p.owner(), p.version)).collect::<Vec<_>>();
+
+        db.set_cave_binaries("web", None, &[bin("node", "node", "20.5.0")]).unwrap();
+        db.set_cave_binaries("web", Some("next"), &[bin("node", "node", "22.0.0")]).unwrap();
+        assert_eq!(owners("node"), vec!["web 20.5.0", "web:next 22.0.0"]);
+
+        // Building one variant leaves the others' entries alone.
+        db.set_cave_binaries("web", None, &[]).unwrap();
+        assert_eq!(owners("node"), vec!["web:next 22.0.0"]);
+    }
+
+    #[test]
+    fn test_unresolved() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Db::open(tmp.path()).unwrap();
+        assert!(!db.is_unresolved("npm:nope", "g1", 100).unwrap());
+        db.record_unresolved("npm:nope", "g1", 200).unwrap();
+        assert!(db.is_unresolved("npm:nope", "g1", 100).unwrap());
+        assert!(!db.is_unresolved("npm:nope", "g1", 200).unwrap());
+        assert!(!db.is_unresolved("npm:nope", "g2", 100).unwrap());
+    }
+
+    #[test]
+    fn test_recipe_state() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Db::open(tmp.path()).unwrap();
+
+        assert_eq!(db.state_get("main/npm.star", "cursor").unwrap(), None);
+        db.state_set("main/npm.star", "cursor", Some("42")).unwrap();
+        db.state_set("main/npm.star", "cursor", Some("43")).unwrap();
+        db.state_set("other/go.star", "etag", Some("x")).unwrap();
+        assert_eq!(db.state_get("main/npm.star", "cursor").unwrap().as_deref(), Some("43"));
+        assert_eq!(db.state_scan("main/").unwrap().len(), 1);
+
+        let big = "x".repeat(MAX_STATE_VALUE);
+        assert!(db.state_set("main/npm.star", "big", Some(&(big.clone() + "x"))).is_err());
+        for i in 0..MAX_STATE_SCOPE / MAX_STATE_VALUE - 1 {
+            db.state_set("main/npm.star", &format!("k{}", i), Some(&big)).unwrap();
+        }
+        assert!(db.state_set("main/npm.star", "one-more", Some(&big)).is_err());
+
+        db.state_set("main/npm.star", "cursor", None).unwrap();
+        assert_eq!(db.state_get("main/npm.star", "cursor").unwrap(), None);
+        assert_eq!(db.state_clear("main/").unwrap(), MAX_STATE_SCOPE / MAX_STATE_VALUE - 1);
+        assert_eq!(db.state_scan("").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_handles_share_the_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        // A long-running process (a cave shell) keeps its handle.
+        let session = Db::open(tmp.path()).unwrap();
+        session.touch(&tmp.path().join("a")).unwrap();
+
+        let first = Db::open(tmp.path()).unwrap();
+        first.touch(&tmp.path().join("b")).unwrap();
+        drop(first);
+        let second = Db::open(tmp.path()).unwrap();
+        assert!(second.last_use(&tmp.path().join("a")).unwrap().is_some());
+        assert!(second.last_use(&tmp.path().join("b")).unwrap().is_some());
+        second.record_build_home(&tmp.path().join("home"), 7).unwrap();
+        assert_eq!(session.build_homes().unwrap(), vec![(tmp.path().join("home"), 7)]);
+
+        // Waits for an operation of another process to finish.
+        let held = Database::create(tmp.path().join(Db::FILENAME)).unwrap();
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            drop(held);
+        });
+        assert!(session.last_use(&tmp.path().join("a")).unwrap().is_some());
+        release.join().unwrap();
+    }
+}
@@ -1,59 +1,474 @@
 use anyhow::{Context, Result};
 use std::fs::File;
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 use ureq::Agent;
 use ureq::config::IpFamily;
-use crate::utils::crypto::calculate_file_checksum;
+use crate::models::repository::HostPolicy;
+use crate::services::events::{self, Event};
+use crate::services::oci::{self, OciReference};
+use crate::utils::crypto::{calculate_file_checksum, StreamHasher};
+use crate::utils::fs::lock_file;
+use crate::utils::size::format_size;
+
+/// ureq decodes gzip itself; deflate is decoded by `decode_encoding`.
+const ACCEPT_ENCODING: &str = "gzip, deflate";
+
+/// How often a running download logs its progress.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
 
 pub struct Downloader;
 
+static RETRY_POLICY: OnceLock<RetryPolicy> = OnceLock::new();
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Redirects a request may follow (ureq's default).
+const MAX_REDIRECTS: u32 = 10;
+
+/// Longest wait between two attempts, however many failed.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Result of `download_if_changed`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fetched {
+    /// The server answered 304: what the ETag names is still current.
+    NotModified,
+    /// Downloaded, with the ETag of the new content when the server sent one.
+    Downloaded { etag: Option<String> },
+}
+
+/// How `download_to_file` retries transient failures: up to `retries` more
+/// attempts, waiting `delay` before the first and doubling it each time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { retries: 3, delay: Duration::from_secs(1) }
+    }
+}
+
+impl RetryPolicy {
+    /// The wait before retry `attempt` (1-based).
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.delay.saturating_mul(1 << (attempt - 1).min(16)).min(MAX_RETRY_DELAY)
+    }
+}
+
+/// A request of `download_with()`: any method, extra headers and a body.
+#[derive(Clone, Copy)]
+pub struct HttpRequest<'a> {
+    pub url: &'a str,
+    pub method: &'a str,
+    pub headers: &'a [(String, String)],
+    pub body: Option<&'a str>,
+}
+
+impl<'a> HttpRequest<'a> {
+    fn get(url: &'a str) -> Self {
+        Self { url, method: "GET", headers: &[], body: None }
+    }
+}
+
 impl Downloader {
-    pub fn download(url: &str) -> Result<String> {
-        let agent = Self::create_agent();
-        let response = agent.get(url).call()?;
+    /// Fetches `url` into memory, asking for a compressed transfer. The body
+    /// comes back decompressed, its bytes otherwise untouched. `hosts`
+    /// restricts every host the request may reach, redirects included.
+    #[tracing::instrument(skip_all, fields(url = url))]
+    pub fn download(url: &str, hosts: Option<&HostPolicy>) -> Result<Vec<u8>> {
+        let response = Self::send(&HttpRequest::get(url), hosts)?;
+        Self::read_body(url, response, false)
+    }
+
+    /// `download` of text (metadata such as registry indexes): the body is
+    /// converted to UTF-8 per the `Content-Type` charset.
+    #[tracing::instrument(skip_all, fields(url = url))]
+    pub fn download_text(url: &str, hosts: Option<&HostPolicy>) -> Result<Vec<u8>> {
+        let response = Self::send(&HttpRequest::get(url), hosts)?;
+        Self::read_body(url, response, true)
+    }
+
+    /// `download_text` with the method, headers and body of `request`. Header
+    /// values are never logged, they often carry credentials.
+    #[tracing::instrument(skip_all, fields(url = request.url, method = request.method))]
+    pub fn request(request: &HttpRequest, hosts: Option<&HostPolicy>) -> Result<Vec<u8>> {
+        let response = Self::send(request, hosts)?;
+        Self::read_body(request.url, response, true)
+    }
+
+    /// Sends `request`, following redirects. With `hosts` they are followed
+    /// here instead of by ureq, checking each hop's host before contacting
+    /// it; headers are dropped once a hop leaves the original host, and
+    /// 301-303 turn the request into a GET without a body, as browsers do.
+    fn send(request: &HttpRequest, hosts: Option<&HostPolicy>) -> Result<ureq::http::Response<ureq::Body>> {
+        Self::ensure_online(request.url)?;
+        let Some(hosts) = hosts else {
+            return Self::send_once(&Self::create_agent(), request);
+        };
+        hosts.check(request.url)?;
+        let agent = Self::create_agent_without_redirects();
+        let host = |url: &str| url::Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_string));
+        let mut hop = *request;
+        let mut url = request.url.to_string();
+        for _ in 0..=MAX_REDIRECTS {
+            let response = Self::send_once(&agent, &HttpRequest { url: &url, ..hop })?;
+            let location = response.headers().get("location").and_then(|h| h.to_str().ok());
+            let (true, Some(location)) = (response.status().is_redirection(), location) else {
+                return Ok(response);
+            };
+            let next = url::Url::parse(&url)
+                .and_then(|base| base.join(location))
+                .with_context(|| format!("[{}] invalid redirect to {}", url, location))?
+                .to_string();
+            hosts.check(&next).with_context(|| format!("{} redirected", url))?;
+            log::debug!("[{}] redirected to {}", url, next);
+            if host(&next) != host(request.url) {
+                hop.headers = &[];
+            }
+            if matches!(response.status().as_u16(), 301..=303) && !hop.method.eq_ignore_ascii_case("HEAD") {
+                hop.method = "GET";
+                hop.body = None;
+            }
+            url = next;
+        }
+        anyhow::bail!("[{}] more than {} redirects", request.url, MAX_REDIRECTS)
+    }
+
+    fn send_once(agent: &Agent, request: &HttpRequest) -> Result<ureq::http::Response<ureq::Body>> {
+        let method = ureq::http::Method::from_bytes(request.method.to_ascii_uppercase().as_bytes())
+            .with_context(|| format!("invalid HTTP method '{}'", request.method))?;
+        let mut builder = ureq::http::Request::builder()
+            .method(method)
+            .uri(request.url)
+            .header("accept-encoding", ACCEPT_ENCODING);
+        for (name, value) in request.headers {
+            builder = builder.header(name, value);
+        }
+        let response = match request.body {
+            Some(body) => agent.run(builder.body(body.to_string())?),
+            None => agent.run(builder.body(())?),
+        }?;
+        Ok(response)
+    }
+
+    /// The decompressed body of a response, converted to UTF-8 with `text`.
+    fn read_body(url: &str, response: ureq::http::Response<ureq::Body>, text: bool) -> Result<Vec<u8>> {
+        let encoding = response.headers().get("content-encoding")
+            .and_then(|h| h.to_str().ok())
+            .map(|s| s.trim().to_ascii_lowercase());
+        let charset = response.body().charset().map(str::to_string);
         let mut reader = response.into_body().into_reader();
         let mut content = Vec::new();
         reader.read_to_end(&mut content)?;
+        let wire = content.len();
+        let mut content = decode_encoding(content, encoding.as_deref())
+            .with_context(|| format!("[{}] failed to decode response", url))?;
+        if text {
+            content = decode_text(content, charset.as_deref());
+        }
+        log::debug!("[{}] recv {} bytes ({:?}), {} decoded", url, wire, encoding, content.len());
+        Ok(content)
+    }
+
+    /// Size of `url` from a HEAD request's Content-Length, for recipes
+    /// recording artifact sizes.
+    pub fn content_length(url: &str, hosts: Option<&HostPolicy>) -> Result<Option<u64>> {
+        let response = Self::send(&HttpRequest { method: "HEAD", ..HttpRequest::get(url) }, hosts)?;
+        Ok(Self::get_content_length(&response))
+    }
+
+    /// Sets the retry policy of downloads, from the settings; the default
+    /// applies until then.
+    pub fn set_retry_policy(policy: RetryPolicy) {
+        let _ = RETRY_POLICY.set(policy);
+    }
+
+    /// Refuses all network access from now on (`--offline`); `file://`
+    /// URLs still work.
+    pub fn set_offline(offline: bool) {
+        OFFLINE.store(offline, Ordering::Relaxed);
+    }
+
+    pub fn is_offline() -> bool {
+        OFFLINE.load(Ordering::Relaxed)
+    }
 
-        Ok(String::from_utf8(content)?)
+    /// Fails when `url` would need the network and pi runs offline.
+    pub fn ensure_online(url: &str) -> Result<()> {
+        if Self::is_offline() && !url.starts_with("file://") {
+            anyhow::bail!("offline, not fetching {}", url);
+        }
+        Ok(())
     }
 
-    pub fn download_to_file(url: &str, dest: &Path, expected_checksum: Option<&str>) -> Result<()> {
+    /// Downloads `url` to `dest`. `expected_size` (recorded by the recipe)
+    /// stands in for a missing Content-Length in progress reports. Transient
+    /// network failures are retried per the retry policy.
+    #[tracing::instrument(skip_all, fields(url = url))]
+    pub fn download_to_file(url: &str, dest: &Path, expected_checksum: Option<&str>, expected_size: Option<u64>) -> Result<()> {
+        let policy = RETRY_POLICY.get().copied().unwrap_or_default();
+        Self::download_with_retries(url, dest, expected_checksum, expected_size, None, policy).map(|_| ())
+    }
+
+    /// `download_to_file` asking the server to skip content whose ETag is
+    /// still `etag` (If-None-Match). URLs other than http(s) always download.
+    #[tracing::instrument(skip_all, fields(url = url))]
+    pub fn download_if_changed(url: &str, dest: &Path, expected_checksum: Option<&str>, etag: Option<&str>) -> Result<Fetched> {
+        let policy = RETRY_POLICY.get().copied().unwrap_or_default();
+        Self::download_with_retries(url, dest, expected_checksum, None, etag, policy)
+    }
+
+    fn download_with_retries(
+        url: &str,
+        dest: &Path,
+        expected_checksum: Option<&str>,
+        expected_size: Option<u64>,
+        if_none_match: Option<&str>,
+        policy: RetryPolicy,
+    ) -> Result<Fetched> {
         Self::prepare_directory(dest)?;
 
         if Self::is_file_ready(dest, expected_checksum) {
-            return Ok(());
+            return Ok(Fetched::Downloaded { etag: None });
         }
+        Self::ensure_online(url)?;
 
-        let agent = Self::create_agent();
+        let mut attempt = 0;
+        loop {
+            match Self::fetch_to_file(url, dest, expected_checksum, expected_size, if_none_match) {
+                Err(e) if attempt < policy.retries && !url.starts_with("file://") && is_transient(&e) => {
+                    attempt += 1;
+                    let wait = policy.backoff(attempt);
+                    log::warn!("[{}] fetch failed, retry {}/{} in {:?}: {:#}", url, attempt, policy.retries, wait, e);
+                    std::thread::sleep(wait);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// One attempt of `download_to_file`. The body goes to `<dest>.part`,
+    /// renamed to `dest` once verified; a part an interrupted attempt (or
+    /// run) left is continued with a Range request when the server still
+    /// serves the same content (If-Range), or when a checksum will tell.
+    fn fetch_to_file(
+        url: &str,
+        dest: &Path,
+        expected_checksum: Option<&str>,
+        expected_size: Option<u64>,
+        if_none_match: Option<&str>,
+    ) -> Result<Fetched> {
         log::info!("[{}] fetching", url);
-        let response = agent.get(url).call()?;
+        let (part, meta_path) = part_paths(dest);
+        let _lock = lock_part(&meta_path, url)?;
+        if Self::is_file_ready(dest, expected_checksum) {
+            return Ok(Fetched::Downloaded { etag: None });
+        }
+        let validator = std::fs::read_to_string(&meta_path).unwrap_or_default();
+        let offset = std::fs::metadata(&part).map(|m| m.len()).unwrap_or(0);
+        let resume = (offset > 0 && (!validator.is_empty() || expected_checksum.is_some()))
+            .then(|| Resume { offset, validator: Some(validator.as_str()).filter(|v| !v.is_empty()) });
+        let Some(Body { reader, content_length, etag, validator, offset, digest }) = Self::open(url, if_none_match, expected_size, resume)? else {
+            log::debug!("[{}] not modified", url);
+            return Ok(Fetched::NotModified);
+        };
+        let filename = url.split('/').next_back().unwrap_or("unknown");
 
-        let content_length = Self::get_content_length(&response);
-        let filename = url.split('/').last().unwrap_or("unknown");
+        let mut file = if offset > 0 {
+            log::info!("[{}] resuming after {}", url, format_size(offset));
+            std::fs::OpenOptions::new().append(true).open(&part)
+        } else {
+            File::create(&part)
+        }
+        .with_context(|| format!("Failed to open {}", part.display()))?;
+        if offset == 0 {
+            std::fs::write(&meta_path, validator.unwrap_or_default())
+                .with_context(|| format!("Failed to write {}", meta_path.display()))?;
+        }
 
-        // Download to a temporary file in the same directory to ensure atomic rename
-        let parent = dest.parent().context("Destination has no parent directory")?;
-        let mut tmp_file = tempfile::NamedTempFile::new_in(parent)
-            .context("Failed to create temporary download file")?;
+        events::publish(Event::DownloadStarted { url: url.to_string(), total: content_length });
+        let bytes = Self::stream_to_file(reader, &mut file, content_length, url, filename)?;
+        drop(file);
+
+        let verified = Self::verify_checksum(url, &part, digest.as_deref(), filename)
+            .and_then(|()| Self::verify_checksum(url, &part, expected_checksum, filename));
+        if verified.is_ok() {
+            std::fs::rename(&part, dest)
+                .with_context(|| format!("Failed to move the download to {}", dest.display()))?;
+        }
+        let _ = std::fs::remove_file(&part);
+        let _ = std::fs::remove_file(&meta_path);
+        verified?;
+        events::publish(Event::DownloadFinished { url: url.to_string(), bytes });
 
-        Self::stream_to_file(response.into_body().into_reader(), tmp_file.as_file_mut(), content_length, filename)?;
+        Ok(Fetched::Downloaded { etag })
+    }
 
-        Self::verify_checksum(url, tmp_file.path(), expected_checksum, filename)?;
+    /// Opens the body of `url`; None when the server answered 304 to
+    /// `if_none_match`. `resume` asks for the rest of a partial download;
+    /// the body's `offset` says whether the server sent only that. `file://`
+    /// URLs read local artifacts (`dir:` packages) the same way; `oci://`
+    /// blobs come with the digest they must match.
+    fn open(url: &str, if_none_match: Option<&str>, expected_size: Option<u64>, resume: Option<Resume>) -> Result<Option<Body>> {
+        if let Some(path) = url.strip_prefix("file://") {
+            let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+            let len = file.metadata()?.len();
+            return Ok(Some(Body { reader: Box::new(file), content_length: Some(len), etag: None, validator: None, offset: 0, digest: None }));
+        }
+        if let Some(oci) = OciReference::from_url(url) {
+            let blob = oci::resolve(&oci?)?;
+            let reader = oci::open_blob(&blob)?;
+            let content_length = Some(blob.size);
+            return Ok(Some(Body { reader, content_length, etag: None, validator: None, offset: 0, digest: Some(blob.sha256) }));
+        }
+        let mut request = Self::create_agent().get(url);
+        if let Some(tag) = if_none_match {
+            request = request.header("if-none-match", tag);
+        }
+        if let Some(resume) = &resume {
+            request = request.header("range", format!("bytes={}-", resume.offset));
+            if let Some(validator) = resume.validator {
+                request = request.header("if-range", validator);
+            }
+        }
+        let response = match request.call() {
+            // The part is already complete (or longer than the file now is).
+            Err(ureq::Error::StatusCode(416)) if resume.is_some() => return Self::open(url, if_none_match, expected_size, None),
+            response => response?,
+        };
+        if response.status() == 304 {
+            return Ok(None);
+        }
+        let offset = match (&resume, response.status().as_u16()) {
+            (Some(resume), 206) if content_range_start(&response) == Some(resume.offset) => resume.offset,
+            (_, 206) => anyhow::bail!("[{}] the server sent a range that wasn't asked for", url),
+            _ => 0,
+        };
+        let header = |name: &str| response.headers().get(name).and_then(|h| h.to_str().ok()).map(str::to_string);
+        let etag = header("etag");
+        // Weak ETags can't validate a range (RFC 9110 If-Range).
+        let validator = etag.clone().filter(|tag| !tag.starts_with("W/")).or_else(|| header("last-modified"));
+        let content_length = Self::get_content_length(&response).or(expected_size.map(|size| size.saturating_sub(offset)));
+        Ok(Some(Body { reader: Box::new(response.into_body().into_reader()), content_length, etag, validator, offset, digest: None }))
+    }
 
-        // Persist the temporary file to the final destination
-        tmp_file.persist(dest).map_err(|e| {
-            anyhow::anyhow!("Failed to persist download to {}: {}", dest.display(), e.error)
-        })?;
+    /// Tries `urls` in order like `download_from_mirrors`, but hands each
+    /// body to `consume` instead of writing it to a file (`extract()` straight
+    /// from the network). The checksums are computed on the bytes as they
+    /// pass and checked once `consume` is done; what it leaves unread is
+    /// drained first. `consume` starts over on every attempt, so it must not
+    /// rely on what a failed one left.
+    pub fn stream_from_mirrors(
+        urls: &[&str],
+        expected_checksum: Option<&str>,
+        expected_size: Option<u64>,
+        consume: &mut dyn FnMut(&mut dyn Read) -> Result<()>,
+    ) -> Result<Streamed> {
+        let policy = RETRY_POLICY.get().copied().unwrap_or_default();
+        let mut last_err = None;
+        for url in urls {
+            Self::ensure_online(url)?;
+            let mut attempt = 0;
+            let result = loop {
+                match Self::stream_once(url, expected_checksum, expected_size, consume) {
+                    Err(e) if attempt < policy.retries && !url.starts_with("file://") && is_transient(&e) => {
+                        attempt += 1;
+                        let wait = policy.backoff(attempt);
+                        log::warn!("[{}] fetch failed, retry {}/{} in {:?}: {:#}", url, attempt, policy.retries, wait, e);
+                        std::thread::sleep(wait);
+                    }
+                    result => break result,
+                }
+            };
+            match result {
+                Ok((sha256, bytes)) => return Ok(Streamed { source: url.to_string(), sha256, bytes }),
+                Err(e) => {
+                    if urls.len() > 1 {
+                        log::warn!("[{}] fetch failed, trying next mirror: {:#}", url, e);
+                    }
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("No URLs to fetch")))
+    }
 
-        Ok(())
+    /// One attempt of `stream_from_mirrors`; returns the sha256 and size of the body.
+    fn stream_once(
+        url: &str,
+        expected_checksum: Option<&str>,
+        expected_size: Option<u64>,
+        consume: &mut dyn FnMut(&mut dyn Read) -> Result<()>,
+    ) -> Result<(String, u64)> {
+        log::info!("[{}] streaming", url);
+        let body = Self::open(url, None, expected_size, None)?.context("server answered 304 without a conditional request")?;
+        events::publish(Event::DownloadStarted { url: url.to_string(), total: body.content_length });
+        let mut reader = HashingReader {
+            inner: body.reader,
+            sha256: StreamHasher::for_len(64)?,
+            expected: expected_checksum.filter(|c| c.len() != 64).map(|c| StreamHasher::for_len(c.len())).transpose()?,
+            url,
+            total: body.content_length,
+            downloaded: 0,
+            started: Instant::now(),
+            last_event: Instant::now(),
+            last_report: Instant::now(),
+        };
+        consume(&mut reader)?;
+        std::io::copy(&mut reader, &mut std::io::sink())?;
+        let bytes = reader.downloaded;
+        let HashingReader { sha256, expected, .. } = reader;
+        let sha256 = sha256.finish();
+        if let Some(digest) = body.digest.filter(|d| *d != sha256) {
+            anyhow::bail!("[{}] checksum mismatch: got {}, want {}", url, sha256, digest);
+        }
+        if let Some(want) = expected_checksum {
+            let actual = expected.map(StreamHasher::finish).unwrap_or_else(|| sha256.clone());
+            if actual != want {
+                anyhow::bail!("[{}] checksum mismatch: got {}, want {}", url, actual, want);
+            }
+        }
+        events::publish(Event::DownloadFinished { url: url.to_string(), bytes });
+        Ok((sha256, bytes))
+    }
+
+    /// Tries `urls` in order until one downloads and passes the checksum.
+    /// Returns the URL the file was actually fetched from.
+    pub fn download_from_mirrors(urls: &[&str], dest: &Path, expected_checksum: Option<&str>, expected_size: Option<u64>) -> Result<String> {
+        let mut last_err = None;
+        for url in urls {
+            match Self::download_to_file(url, dest, expected_checksum, expected_size) {
+                Ok(()) => return Ok(url.to_string()),
+                Err(e) => {
+                    if urls.len() > 1 {
+                        log::warn!("[{}] fetch failed, trying next mirror: {:#}", url, e);
+                    }
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("No URLs to fetch")))
     }
 
     fn create_agent() -> Agent {
         let config = Agent::config_builder()
             .ip_family(IpFamily::Ipv4Only)
+            .max_redirects(MAX_REDIRECTS)
+            .build();
+        Agent::new_with_config(config)
+    }
+
+    /// An agent handing redirects back, for `send` to check them.
+    fn create_agent_without_redirects() -> Agent {
+        let config = Agent::config_builder()
+            .ip_family(IpFamily::Ipv4Only)
+            .max_redirects(0)
             .build();
         Agent::new_with_config(config)
     }
@@ -66,13 +481,12 @@ impl Downloader {
     }
 
     fn is_file_ready(dest: &Path, expected_checksum: Option<&str>) -> bool {
-        if let (true, Some(expected)) = (dest.exists(), expected_checksum) {
-            if let Ok(actual) = calculate_file_checksum(dest, expected.len()) {
-                if actual == expected {
-                    log::info!("[{}] skip, matches checksum", dest.display());
-                    return true;
-                }
-            }
+        if let (true, Some(expected)) = (dest.exists(), expected_checksum)
+            && let Ok(actual) = calculate_file_checksum(dest, expected.len())
+            && actual == expected
+        {
+            log::info!("[{}] skip, matches checksum", dest.display());
+            return true;
         }
         false
     }
@@ -84,11 +498,13 @@ impl Downloader {
             .and_then(|s: &str| s.parse::<u64>().ok())
     }
 
-    fn stream_to_file(mut reader: impl Read, file: &mut File, total_size: Option<u64>, filename: &str) -> Result<()> {
+    fn stream_to_file(mut reader: impl Read, file: &mut File, total_size: Option<u64>, url: &str, filename: &str) -> Result<u64> {
         let mut buffer = [0; 8192];
         let mut downloaded: u64 = 0;
         let mut last_report = Instant::now();
+        let mut last_event = Instant::now();
         let start_time = Instant::now();
+        let emit_events = events::has_subscribers();
 
         loop {
             let n = reader.read(&mut buffer)?;
@@ -97,21 +513,34 @@ impl Downloader {
             file.write_all(&buffer[..n])?;
             downloaded += n as u64;
 
-            if last_report.elapsed() >= Duration::from_secs(5) {
+            if last_report.elapsed() >= PROGRESS_INTERVAL {
                 Self::report_progress(filename, downloaded, total_size, start_time.elapsed());
                 last_report = Instant::now();
             }
+            if emit_events && last_event.elapsed() >= Duration::from_millis(500) {
+                events::publish(Event::DownloadProgress { url: url.to_string(), downloaded, total: total_size });
+                last_event = Instant::now();
+            }
         }
-        Ok(())
+        Ok(downloaded)
     }
 
+    /// Logs how far a download that has run for a while got, with the
+    /// percentage and ETA when the size is known (Content-Length, or the
+    /// size the recipe recorded).
     fn report_progress(filename: &str, downloaded: u64, total: Option<u64>, elapsed: Duration) {
         let bandwidth = downloaded as f64 / elapsed.as_secs_f64();
-        let total_str = total.map(|t| t.to_string()).unwrap_or_else(|| "???".to_string());
-        log::debug!(
-            "[{}] recv {}/{} ({:.2} KB/s)",
-            filename, downloaded, total_str, bandwidth / 1024.0
-        );
+        let rate = format_size(bandwidth as u64);
+        match total.filter(|t| *t > 0) {
+            Some(total) => {
+                let eta = eta(downloaded, Some(total), bandwidth).map(|s| format!(", eta {}s", s)).unwrap_or_default();
+                log::info!(
+                    "[{}] {} of {} ({}%), {}/s{}",
+                    filename, format_size(downloaded), format_size(total), (downloaded * 100 / total).min(100), rate, eta
+                );
+            }
+            None => log::info!("[{}] {} so far, {}/s", filename, format_size(downloaded), rate),
+        }
     }
 
     fn verify_checksum(url: &str, dest: &Path, expected: Option<&str>, filename: &str) -> Result<()> {
@@ -125,3 +554,366 @@ impl Downloader {
         Ok(())
     }
 }
+
+/// What `stream_from_mirrors` streamed.
+#[derive(Debug)]
+pub struct Streamed {
+    /// The URL the body came from (primary or mirror).
+    pub source: String,
+    pub sha256: String,
+    pub bytes: u64,
+}
+
+/// An opened download: the body and what the source said about it.
+struct Body {
+    reader: Box<dyn Read>,
+    /// Length of the body, only the rest when resuming.
+    content_length: Option<u64>,
+    etag: Option<String>,
+    /// What If-Range can name the content by: a strong ETag or Last-Modified.
+    validator: Option<String>,
+    /// Bytes of the partial download the body continues; 0 for a full body.
+    offset: u64,
+    /// The sha256 an `oci://` blob must match.
+    digest: Option<String>,
+}
+
+/// A partial download to continue, and what its bytes came from.
+struct Resume<'a> {
+    offset: u64,
+    validator: Option<&'a str>,
+}
+
+/// Where a download of `dest` accumulates, `<dest>.part`, and its meta file
+/// `<dest>.part.meta`: the validator of the response the part came from,
+/// locked while an attempt writes the part.
+fn part_paths(dest: &Path) -> (PathBuf, PathBuf) {
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    (dest.with_file_name(format!("{}.part", name)), dest.with_file_name(format!("{}.part.meta", name)))
+}
+
+/// Locks the meta file of a partial download. An attempt that finished
+/// while this one waited has removed the file it locked, so lock the new one.
+fn lock_part(meta_path: &Path, url: &str) -> Result<File> {
+    use std::os::unix::fs::MetadataExt;
+    loop {
+        let file = lock_file(meta_path, false, &format!("[{}] waiting for another download of it", url))?;
+        let locked = file.metadata()?;
+        if std::fs::metadata(meta_path).is_ok_and(|m| (m.dev(), m.ino()) == (locked.dev(), locked.ino())) {
+            return Ok(file);
+        }
+    }
+}
+
+/// The first byte of a 206 response's `Content-Range: bytes <start>-<end>/<len>`.
+fn content_range_start<T>(response: &ureq::http::Response<T>) -> Option<u64> {
+    let range = response.headers().get("content-range")?.to_str().ok()?;
+    range.strip_prefix("bytes ")?.split('-').next()?.trim().parse().ok()
+}
+
+/// A streamed body, hashed and reported as it is read.
+struct HashingReader<'a> {
+    inner: Box<dyn Read>,
+    sha256: StreamHasher,
+    /// For a checksum of another length than sha256's.
+    expected: Option<StreamHasher>,
+    url: &'a str,
+    total: Option<u64>,
+    downloaded: u64,
+    started: Instant,
+    last_event: Instant,
+    last_report: Instant,
+}
+
+impl Read for HashingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.sha256.update(&buf[..n]);
+        if let Some(hasher) = self.expected.as_mut() {
+            hasher.update(&buf[..n]);
+        }
+        self.downloaded += n as u64;
+        if events::has_subscribers() && self.last_event.elapsed() >= Duration::from_millis(500) {
+            events::publish(Event::DownloadProgress { url: self.url.to_string(), downloaded: self.downloaded, total: self.total });
+            self.last_event = Instant::now();
+        }
+        if self.last_report.elapsed() >= PROGRESS_INTERVAL {
+            let filename = self.url.split('/').next_back().unwrap_or("unknown");
+            Downloader::report_progress(filename, self.downloaded, self.total, self.started.elapsed());
+            self.last_report = Instant::now();
+        }
+        Ok(n)
+    }
+}
+
+/// Whether a download failure may pass when tried again: connection
+/// problems, timeouts, server errors and bodies cut short. Checksum
+/// mismatches, client errors (like 404) and local I/O errors are final.
+fn is_transient(err: &anyhow::Error) -> bool {
+    use std::io::ErrorKind;
+    let transient_http = |e: &ureq::Error| match e {
+        ureq::Error::StatusCode(code) => *code >= 500 || *code == 429 || *code == 408,
+        ureq::Error::Io(_) | ureq::Error::Timeout(_) | ureq::Error::HostNotFound | ureq::Error::ConnectionFailed => true,
+        _ => false,
+    };
+    err.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<ureq::Error>() {
+            return transient_http(e);
+        }
+        // Reading a response body fails with the ureq error inside an io::Error.
+        cause.downcast_ref::<std::io::Error>().is_some_and(|e| {
+            e.get_ref().and_then(|inner| inner.downcast_ref::<ureq::Error>()).is_some_and(transient_http)
+                || matches!(e.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::UnexpectedEof | ErrorKind::TimedOut)
+        })
+    })
+}
+
+/// Seconds left at `bandwidth` bytes/s, when the total is known.
+fn eta(downloaded: u64, total: Option<u64>, bandwidth: f64) -> Option<u64> {
+    let left = total?.checked_sub(downloaded)?;
+    (bandwidth > 0.0).then(|| (left as f64 / bandwidth).ceil() as u64)
+}
+
+/// Undoes a `deflate` content encoding (zlib-wrapped per the RFC, raw from
+/// some servers).
+fn decode_encoding(content: Vec<u8>, encoding: Option<&str>) -> Result<Vec<u8>> {
+    Ok(match encoding {
+        Some("deflate") => {
+            let mut out = Vec::new();
+            if flate2::read::ZlibDecoder::new(&content[..]).read_to_end(&mut out).is_err() {
+                out.clear();
+                flate2::read::DeflateDecoder::new(&content[..]).read_to_end(&mut out)
+                    .context("invalid deflate stream")?;
+            }
+            out
+        }
+        _ => content,
+    })
+}
+
+/// Converts a text body to UTF-8: a BOM is dropped and Latin-1 is
+/// transcoded; other charsets pass through with a warning.
+fn decode_text(content: Vec<u8>, charset: Option<&str>) -> Vec<u8> {
+    let content = match content.strip_prefix(b"\xEF\xBB\xBF") {
+        Some(rest) => rest.to_vec(),
+        None => content,
+    };
+    match charset.map(|c| c.trim_matches('"').to_ascii_lowercase()).as_deref() {
+        None | Some("utf-8" | "utf8" | "us-ascii" | "ascii") => content,
+        Some("iso-8859-1" | "latin1" | "latin-1") => content.iter().map(|&b| b as char).collect::<String>().into_bytes(),
+        Some(other) => {
+            log::warn!("[download] unsupported charset {}, using the body as is", other);
+            content
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+
+    fn compress<W: Write>(mut encoder: W, data: &[u8]) -> W {
+        encoder.write_all(data).unwrap();
+        encoder
+    }
+
+    #[test]
+    fn test_download_decodes_compressed_bodies() {
+        let body = b"{\"name\":\"caf\xe9\"}".to_vec();
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let handle = std::thread::spawn(move || {
+            for (encoding, charset) in [("gzip", "utf-8"), ("deflate", "iso-8859-1")] {
+                let request = server.recv().unwrap();
+                let accept = request.headers().iter()
+                    .find(|h| h.field.equiv("accept-encoding"))
+                    .map(|h| h.value.to_string());
+                assert_eq!(accept.as_deref(), Some(ACCEPT_ENCODING));
+                let data = if encoding == "gzip" {
+                    compress(GzEncoder::new(Vec::new(), Compression::default()), b"{\"name\":\"ok\"}").finish().unwrap()
+                } else {
+                    compress(ZlibEncoder::new(Vec::new(), Compression::default()), &body).finish().unwrap()
+                };
+                let header = |k: &str, v: &str| tiny_http::Header::from_bytes(k, v).unwrap();
+                let response = tiny_http::Response::from_data(data)
+                    .with_header(header("Content-Encoding", encoding))
+                    .with_header(header("Content-Type", &format!("application/json; charset={}", charset)));
+                request.respond(response).unwrap();
+            }
+        });
+
+        let url = format!("http://{}/index.json", addr);
+        assert_eq!(Downloader::download(&url, None).unwrap(), br#"{"name":"ok"}"#);
+        assert_eq!(String::from_utf8(Downloader::download_text(&url, None).unwrap()).unwrap(), r#"{"name":"café"}"#);
+        handle.join().unwrap();
+
+        assert_eq!(eta(100, Some(1100), 250.0), Some(4));
+        assert_eq!(eta(100, None, 250.0), None);
+
+        let raw = compress(flate2::write::DeflateEncoder::new(Vec::new(), Compression::default()), b"\xEF\xBB\xBF[]").finish().unwrap();
+        assert_eq!(decode_text(decode_encoding(raw, Some("deflate")).unwrap(), None), b"[]");
+    }
+
+    #[test]
+    fn test_binary_download_keeps_bytes() {
+        let body = b"\xEF\xBB\xBF\x00\xe9\xff".to_vec();
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let data = body.clone();
+        let handle = std::thread::spawn(move || {
+            let request = server.recv().unwrap();
+            let header = tiny_http::Header::from_bytes("Content-Type", "text/plain; charset=iso-8859-1").unwrap();
+            request.respond(tiny_http::Response::from_data(data).with_header(header)).unwrap();
+        });
+
+        assert_eq!(Downloader::download(&format!("http://{}/blob", addr), None).unwrap(), body);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_download_retries_transient_failures() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut paths = Vec::new();
+            for status in [503, 429, 200, 404] {
+                let request = server.recv().unwrap();
+                paths.push(request.url().to_string());
+                request.respond(tiny_http::Response::from_string("artifact").with_status_code(status)).unwrap();
+            }
+            paths
+        });
+
+        let tmp = tempfile::tempdir().unwrap();
+        let policy = RetryPolicy { retries: 2, delay: Duration::ZERO };
+        let dest = tmp.path().join("t.tgz");
+        Downloader::download_with_retries(&format!("http://{}/t.tgz", addr), &dest, None, None, None, policy).unwrap();
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "artifact");
+        let missing = Downloader::download_with_retries(&format!("http://{}/gone.tgz", addr), &tmp.path().join("gone"), None, None, None, policy);
+        assert!(!is_transient(&missing.unwrap_err()));
+        assert_eq!(handle.join().unwrap(), vec!["/t.tgz", "/t.tgz", "/t.tgz", "/gone.tgz"]);
+
+        let policy = RetryPolicy { retries: 20, delay: Duration::from_secs(1) };
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(3), Duration::from_secs(4));
+        assert_eq!(policy.backoff(20), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn test_download_resumes_partial_file() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut asked = Vec::new();
+            for resumed in [true, false] {
+                let request = server.recv().unwrap();
+                let header = |name: &'static str| request.headers().iter().find(|h| h.field.equiv(name)).map(|h| h.value.to_string());
+                asked.push((header("Range"), header("If-Range")));
+                let response = if resumed {
+                    tiny_http::Response::from_string("fact")
+                        .with_status_code(206)
+                        .with_header("Content-Range: bytes 4-7/8".parse::<tiny_http::Header>().unwrap())
+                } else {
+                    tiny_http::Response::from_string("artifact")
+                };
+                request.respond(response).unwrap();
+            }
+            asked
+        });
+
+        let tmp = tempfile::tempdir().unwrap();
+        let policy = RetryPolicy { retries: 0, delay: Duration::ZERO };
+        let url = format!("http://{}/t.tgz", addr);
+        for (name, partial, validator) in [("a.tgz", "arti", "\"v1\""), ("b.tgz", "junk", "\"v0\"")] {
+            let dest = tmp.path().join(name);
+            let (part, meta) = part_paths(&dest);
+            std::fs::write(&part, partial).unwrap();
+            std::fs::write(&meta, validator).unwrap();
+            Downloader::download_with_retries(&url, &dest, None, None, None, policy).unwrap();
+            assert_eq!(std::fs::read_to_string(&dest).unwrap(), "artifact");
+            assert!(!part.exists() && !meta.exists());
+        }
+        let range = |validator: &str| (Some("bytes=4-".to_string()), Some(validator.to_string()));
+        assert_eq!(handle.join().unwrap(), vec![range("\"v1\""), range("\"v0\"")]);
+    }
+
+    #[test]
+    fn test_redirects_are_checked_against_hosts() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let port = server.server_addr().to_ip().unwrap().port();
+        let handle = std::thread::spawn(move || {
+            let mut paths = Vec::new();
+            for _ in 0..3 {
+                let request = server.recv().unwrap();
+                paths.push(request.url().to_string());
+                let response = match request.url() {
+                    "/latest" => tiny_http::Response::from_string("")
+                        .with_status_code(302)
+                        .with_header(format!("Location: http://localhost:{}/v2", port).parse::<tiny_http::Header>().unwrap()),
+                    _ => tiny_http::Response::from_string("v2"),
+                };
+                request.respond(response).unwrap();
+            }
+            paths
+        });
+
+        let url = format!("http://127.0.0.1:{}/latest", port);
+        let policy = |hosts: &[&str]| HostPolicy { repo: "community".to_string(), hosts: hosts.iter().map(|h| h.to_string()).collect() };
+        let err = Downloader::download(&url, Some(&policy(&["127.0.0.1"]))).unwrap_err();
+        assert!(format!("{:#}", err).contains(&format!("http://localhost:{}/v2 blocked", port)), "{:#}", err);
+        assert_eq!(Downloader::download(&url, Some(&policy(&["127.0.0.1", "localhost"]))).unwrap(), b"v2");
+        assert_eq!(handle.join().unwrap(), vec!["/latest", "/latest", "/v2"]);
+    }
+
+    #[test]
+    fn test_request_with_method_headers_and_body() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut request = server.recv().unwrap();
+            let auth = request.headers().iter()
+                .find(|h| h.field.equiv("authorization"))
+                .map(|h| h.value.to_string());
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            let reply = format!("{} {} {}", request.method(), auth.unwrap_or_default(), body);
+            request.respond(tiny_http::Response::from_string(reply)).unwrap();
+        });
+
+        let url = format!("http://{}/graphql", addr);
+        let headers = vec![("Authorization".to_string(), "Bearer t0k".to_string())];
+        let request = HttpRequest { url: &url, method: "post", headers: &headers, body: Some("{\"query\":\"{}\"}") };
+        assert_eq!(Downloader::request(&request, None).unwrap(), br#"POST Bearer t0k {"query":"{}"}"#);
+        handle.join().unwrap();
+
+        let invalid = HttpRequest { url: &url, method: "NOT A METHOD", headers: &[], body: None };
+        assert!(Downloader::request(&invalid, None).is_err());
+    }
+
+    #[test]
+    fn test_stream_hashes_the_whole_body() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("blob");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let url = format!("file://{}", path.display());
+        let sha256 = calculate_file_checksum(&path, 64).unwrap();
+
+        // The consumer reads 4 bytes; the rest still counts.
+        let mut first = Vec::new();
+        let streamed = Downloader::stream_from_mirrors(&[&url], Some(&sha256), None, &mut |body| {
+            first.clear();
+            body.take(4).read_to_end(&mut first)?;
+            Ok(())
+        }).unwrap();
+        assert_eq!(first, b"0123");
+        assert_eq!((streamed.sha256, streamed.bytes, streamed.source), (sha256, 10, url.clone()));
+
+        let sha1 = calculate_file_checksum(&path, 40).unwrap();
+        assert!(Downloader::stream_from_mirrors(&[&url], Some(&sha1), None, &mut |_| Ok(())).is_ok());
+        let wrong = "0".repeat(64);
+        let err = Downloader::stream_from_mirrors(&[&url], Some(&wrong), None, &mut |_| Ok(())).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{:#}", err);
+    }
+}
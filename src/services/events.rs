@@ -0,0 +1,88 @@
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::io::Write;
+use std::sync::OnceLock;
+
+/// Machine-readable progress events published by the resolve, download,
+/// build, sync and run paths. Serialized as `{"event": "<kind>", ...}`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    ResolveStarted { query: String },
+    ResolveFinished { query: String, resolved: Option<String> },
+    DownloadStarted { url: String, total: Option<u64> },
+    DownloadProgress { url: String, downloaded: u64, total: Option<u64> },
+    DownloadFinished { url: String, bytes: u64 },
+    SyncStarted { repo: String },
+    SyncFinished { repo: String, packages: usize, managers: usize },
+    StepStarted { package: String, version: String, index: usize, step: String, name: Option<String> },
+    StepSucceeded { package: String, version: String, index: usize, cached: bool },
+    StepFailed { package: String, version: String, index: usize, error: String },
+    BuildSummary { packages: usize, steps_run: usize, steps_cached: usize, duration_ms: u128 },
+    RunStarted { cave: String, command: Vec<String> },
+}
+
+type Sink = Box<dyn Fn(&Event) + Send + Sync>;
+
+/// Process-wide event bus. Publishing is a no-op until a sink subscribes.
+#[derive(Default)]
+pub struct EventBus {
+    sinks: RwLock<Vec<Sink>>,
+}
+
+static BUS: OnceLock<EventBus> = OnceLock::new();
+
+fn bus() -> &'static EventBus {
+    BUS.get_or_init(EventBus::default)
+}
+
+pub fn subscribe(sink: impl Fn(&Event) + Send + Sync + 'static) {
+    bus().sinks.write().push(Box::new(sink));
+}
+
+pub fn has_subscribers() -> bool {
+    BUS.get().is_some_and(|b| !b.sinks.read().is_empty())
+}
+
+pub fn publish(event: Event) {
+    if let Some(b) = BUS.get() {
+        for sink in b.sinks.read().iter() {
+            sink(&event);
+        }
+    }
+}
+
+/// Subscribes a sink writing each event as one JSON line on stdout (`--progress-json`).
+pub fn subscribe_json_stdout() {
+    subscribe(|event| {
+        let mut value = match serde_json::to_value(event) {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        if let Some(obj) = value.as_object_mut() {
+            obj.insert("ts".to_string(), chrono::Utc::now().to_rfc3339().into());
+        }
+        let mut out = std::io::stdout().lock();
+        let _ = writeln!(out, "{}", value);
+        let _ = out.flush();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_serialization() {
+        let event = Event::StepSucceeded {
+            package: "go".to_string(),
+            version: "1.22.0".to_string(),
+            index: 1,
+            cached: true,
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event"], "step_succeeded");
+        assert_eq!(json["package"], "go");
+        assert_eq!(json["cached"], true);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::services::downloader::Downloader;
+use crate::utils::fs::sanitize_name;
+use anyhow::{Context, Result};
+use sha2::Digest;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Bare clones of the repositories `git_clone()` steps build from, kept in the
+/// download cache so a rebuild or another version fetches only what's new.
+pub struct GitMirror {
+    path: PathBuf,
+}
+
+impl GitMirror {
+    /// `<downloads>/<package>/<repo name>-<url hash>.git`.
+    pub fn for_url(download_dir: &Path, pkgname: &str, url: &str) -> Self {
+        let name = url.trim_end_matches('/').trim_end_matches(".git").rsplit(['/', ':']).next().unwrap_or("repo");
+        let digest = hex::encode(sha2::Sha256::digest(url.as_bytes()));
+        let dir = download_dir.join(sanitize_name(pkgname));
+        Self { path: dir.join(format!("{}-{}.git", sanitize_name(name), &digest[..12])) }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The commit `rev` (tag, branch or commit) names in `url`. Revisions
+    /// fetched before are answered from the mirror unless `refetch`; pinned
+    /// tags and commits don't move.
+    pub fn fetch(&self, url: &str, rev: &str, depth: Option<u32>, refetch: bool) -> Result<String> {
+        if !self.path.join("HEAD").exists() {
+            fs::create_dir_all(&self.path).with_context(|| format!("Failed to create {}", self.path.display()))?;
+            self.git(["init", "--quiet", "--bare"])?;
+        }
+        let local_ref = rev_ref(rev);
+        if !refetch || Downloader::is_offline() {
+            if is_commit_id(rev) && let Ok(commit) = self.rev_parse(rev) {
+                return Ok(commit);
+            }
+            if let Ok(commit) = self.rev_parse(&local_ref) {
+                log::debug!("[git] {} {} already fetched", url, rev);
+                return Ok(commit);
+            }
+        }
+
+        Downloader::ensure_online(url)?;
+        log::info!("[git] fetching {} {}", url, rev);
+        let refspec = format!("+{}:{}", rev, local_ref);
+        let depth = depth.map(|d| format!("--depth={}", d));
+        let mut args = vec!["fetch", "--quiet", "--no-tags"];
+        args.extend(depth.as_deref());
+        args.extend(["--", url, &refspec]);
+        self.git(args).with_context(|| format!("Failed to fetch {} from {}", rev, url))?;
+
+        let commit = self.rev_parse(&local_ref)?;
+        if is_commit_id(rev) && !commit.starts_with(&rev.to_lowercase()) {
+            anyhow::bail!("{} resolved to {}, not the pinned commit", rev, commit);
+        }
+        Ok(commit)
+    }
+
+    /// Checks `commit` out into `dest`, a clone of its own whose `origin` is `url`.
+    /// An existing checkout of the same commit is kept.
+    pub fn checkout(&self, url: &str, commit: &str, dest: &Path) -> Result<()> {
+        if dest.exists() {
+            if head(dest).is_ok_and(|h| h == commit) {
+                log::debug!("[git] {} already has {}", dest.display(), commit);
+                return Ok(());
+            }
+            fs::remove_dir_all(dest).with_context(|| format!("Failed to clear {}", dest.display()))?;
+        }
+        // Not `git clone`: a shallow mirror would only give it branches and tags.
+        fs::create_dir_all(dest).with_context(|| format!("Failed to create {}", dest.display()))?;
+        run_git(Some(dest), ["init", "--quiet"])?;
+        run_git(Some(dest), [OsStr::new("fetch"), OsStr::new("--quiet"), OsStr::new("--no-tags"), self.path.as_os_str(), OsStr::new("+refs/pi/*:refs/pi/*")])?;
+        run_git(Some(dest), ["-c", "advice.detachedHead=false", "checkout", "--quiet", commit])?;
+        run_git(Some(dest), ["remote", "add", "origin", url])?;
+        Ok(())
+    }
+
+    fn rev_parse(&self, rev: &str) -> Result<String> {
+        self.git(["rev-parse", "--verify", "--quiet", &format!("{}^{{commit}}", rev)])
+    }
+
+    fn git<I, S>(&self, args: I) -> Result<String>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut full: Vec<std::ffi::OsString> = vec!["--git-dir".into(), self.path.clone().into()];
+        full.extend(args.into_iter().map(|a| a.as_ref().to_os_string()));
+        run_git(None, full)
+    }
+}
+
+/// The mirror ref `rev` is fetched into, named by a hash of it: branch
+/// names like `a/b` and `a_b` must not share one.
+fn rev_ref(rev: &str) -> String {
+    format!("refs/pi/{}", &hex::encode(sha2::Sha256::digest(rev.as_bytes()))[..16])
+}
+
+/// A shallow clone of the default branch of `url` in `dir`, brought up to
+/// date when it exists; local changes are discarded. Returns the commit.
+pub fn clone_or_pull(url: &str, dir: &Path) -> Result<String> {
+    Downloader::ensure_online(url)?;
+    if dir.join(".git").exists() {
+        log::info!("[git] pulling {}", url);
+        run_git(Some(dir), ["fetch", "--quiet", "--depth=1", "origin"])
+            .with_context(|| format!("Failed to fetch {}", url))?;
+        run_git(Some(dir), ["reset", "--quiet", "--hard", "FETCH_HEAD"])?;
+    } else {
+        log::info!("[git] cloning {}", url);
+        if let Some(parent) = dir.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        run_git(None, [OsStr::new("clone"), OsStr::new("--quiet"), OsStr::new("--depth=1"), OsStr::new("--"), OsStr::new(url), dir.as_os_str()])
+            .with_context(|| format!("Failed to clone {}", url))?;
+    }
+    head(dir)
+}
+
+/// A full 40 (SHA-1) or 64 (SHA-256) hex digit commit id.
+pub fn is_commit_id(rev: &str) -> bool {
+    matches!(rev.len(), 40 | 64) && rev.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn head(dir: &Path) -> Result<String> {
+    run_git(Some(dir), ["rev-parse", "HEAD"])
+}
+
+/// Runs git without prompting for credentials; returns trimmed stdout.
+fn run_git<I, S>(dir: Option<&Path>, args: I) -> Result<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut cmd = Command::new("git");
+    if let Some(dir) = dir {
+        cmd.current_dir(dir);
+    }
+    cmd.args(args).env("GIT_TERMINAL_PROMPT", "0");
+    log::debug!("[git] {:?}", cmd);
+    let output = cmd.output().context("Failed to run git, is it installed?")?;
+    if !output.status.success() {
+        anyhow::bail!("git failed ({}): {}", output.status, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fetch_and_checkout() {
+        let tmp = tempfile::tempdir().unwrap();
+        let upstream = tmp.path().join("tool");
+        fs::create_dir_all(&upstream).unwrap();
+        let git = |args: &[&str]| run_git(Some(&upstream), args).unwrap();
+        git(&["init", "--quiet"]);
+        fs::write(upstream.join("README"), "v1").unwrap();
+        git(&["add", "README"]);
+        git(&["-c", "user.name=t", "-c", "user.email=t@t", "commit", "--quiet", "-m", "v1"]);
+        git(&["tag", "v1.0"]);
+        let v1 = git(&["rev-parse", "HEAD"]);
+        fs::write(upstream.join("README"), "v2").unwrap();
+        git(&["-c", "user.name=t", "-c", "user.email=t@t", "commit", "--quiet", "-am", "v2"]);
+        let v2 = git(&["rev-parse", "HEAD"]);
+
+        let url = format!("file://{}", upstream.display());
+        let mirror = GitMirror::for_url(&tmp.path().join("downloads"), "tool", &url);
+        let name = mirror.path().file_name().unwrap().to_string_lossy().to_string();
+        assert!(mirror.path().parent().unwrap().ends_with("downloads/tool"));
+        assert!(name.starts_with("tool-") && name.ends_with(".git"), "{}", name);
+        assert_eq!(mirror.fetch(&url, "v1.0", Some(1), false).unwrap(), v1);
+        assert_eq!(mirror.fetch(&url, &v2, None, false).unwrap(), v2);
+
+        let dest = tmp.path().join("src");
+        mirror.checkout(&url, &v1, &dest).unwrap();
+        assert_eq!(fs::read_to_string(dest.join("README")).unwrap(), "v1");
+        assert_eq!(run_git(Some(&dest), ["remote", "get-url", "origin"]).unwrap(), url);
+        mirror.checkout(&url, &v2, &dest).unwrap();
+        assert_eq!(fs::read_to_string(dest.join("README")).unwrap(), "v2");
+
+        // Pinned revisions are served from the mirror once fetched.
+        fs::remove_dir_all(&upstream).unwrap();
+        assert_eq!(mirror.fetch(&url, "v1.0", Some(1), false).unwrap(), v1);
+        assert!(mirror.fetch(&url, "v1.0", Some(1), true).is_err());
+        assert!(is_commit_id(&v1) && !is_commit_id("v1.0"));
+    }
+
+    #[test]
+    fn test_rev_refs_are_distinct() {
+        assert_ne!(rev_ref("feature/a"), rev_ref("feature_a"));
+        assert_ne!(rev_ref("v1.0"), rev_ref("v1-0"));
+        assert_eq!(rev_ref("main"), rev_ref("main"));
+        assert!(rev_ref("../../HEAD").strip_prefix("refs/pi/").unwrap().chars().all(|c| c.is_ascii_hexdigit()));
+    }
+}
@@ -0,0 +1,115 @@
+use crate::models::config::Config;
+use crate::models::package_entry::ManagerEntry;
+use crate::models::repository::Repository;
+use crate::models::version_entry::VersionList;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What the lookup cache knows of a managed package (`go:`, `npm:`), keyed
+/// like `State::version_lists`: "<repo>:<manager>:<package>".
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lookup {
+    /// Never synced, or a miss older than the TTL: sync it.
+    Unknown,
+    /// A sync found versions; a stale list is used while it is refreshed.
+    Found { stale: bool },
+    /// A sync within the TTL found no versions: don't run the recipe again.
+    NotFound,
+}
+
+impl Lookup {
+    fn classify(record: Option<(bool, u64)>, ttl: u64, now: u64) -> Self {
+        match record {
+            None => Self::Unknown,
+            Some((true, at)) => Self::Found { stale: now.saturating_sub(at) >= ttl },
+            Some((false, at)) if now.saturating_sub(at) < ttl => Self::NotFound,
+            Some((false, _)) => Self::Unknown,
+        }
+    }
+}
+
+pub fn lookup(config: &Config, key: &str) -> Lookup {
+    let record = config.db().and_then(|db| db.manager_lookup(key).ok().flatten());
+    Lookup::classify(record, config.settings.manager_cache_ttl(), now_secs())
+}
+
+/// Records the outcome of a sync of the managed package `key`.
+pub fn record(config: &Config, key: &str, found: bool) {
+    if let Some(db) = config.db()
+        && let Err(e) = db.record_manager_lookup(key, found, now_secs())
+    {
+        log::debug!("[{}] failed to record the lookup: {:#}", key, e);
+    }
+}
+
+/// Syncs a managed package on a background thread, then replaces its list
+/// in memory; the stale list serves lookups meanwhile. One refresh per key
+/// at a time: the thread drops its key when done, so a long-lived daemon
+/// refreshes the key again once it goes stale again.
+pub fn refresh_in_background(config: &Config, repo: &Repository, mgr: &ManagerEntry, manager_name: &str, package_name: &str) {
+    let key = format!("{}:{}:{}", repo.name, manager_name, package_name);
+    let mut refreshes = config.state.refreshes.lock();
+    if refreshes.contains_key(&key) {
+        return;
+    }
+    log::debug!("[{}] cached list is stale, refreshing in the background", key);
+    let (config, repo, mgr) = (config.clone(), repo.clone(), mgr.clone());
+    let (manager_name, package_name, thread_key) = (manager_name.to_string(), package_name.to_string(), key.clone());
+    let spawned = std::thread::Builder::new()
+        .name("pi-refresh".to_string())
+        .spawn(move || {
+            match crate::services::sync::sync_manager_package(&config, &repo, &mgr, &manager_name, &package_name) {
+                Ok(()) => {
+                    let full_name = format!("{}:{}", manager_name, package_name);
+                    if let Ok(list) = VersionList::load(&config, &repo.name, &full_name) {
+                        config.state.version_lists.insert(thread_key.clone(), Arc::new(list));
+                    }
+                }
+                Err(e) => log::debug!("[{}] background refresh failed: {:#}", thread_key, e),
+            }
+            config.state.refreshes.lock().remove(&thread_key);
+        });
+    match spawned {
+        Ok(handle) => {
+            refreshes.insert(key, handle);
+        }
+        Err(e) => log::debug!("[{}] failed to start the refresh: {}", key, e),
+    }
+}
+
+/// Waits for the background refreshes of this run, so their lists are saved
+/// before pi exits. The command's output is already printed by then.
+pub fn wait_for_refreshes(config: &Config) {
+    let refreshes = std::mem::take(&mut *config.state.refreshes.lock());
+    for (key, handle) in refreshes {
+        if handle.join().is_err() {
+            log::debug!("[{}] background refresh panicked", key);
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_ttl() {
+        assert_eq!(Lookup::classify(None, 100, 1000), Lookup::Unknown);
+        assert_eq!(Lookup::classify(Some((true, 950)), 100, 1000), Lookup::Found { stale: false });
+        assert_eq!(Lookup::classify(Some((true, 900)), 100, 1000), Lookup::Found { stale: true });
+        assert_eq!(Lookup::classify(Some((false, 950)), 100, 1000), Lookup::NotFound);
+        assert_eq!(Lookup::classify(Some((false, 900)), 100, 1000), Lookup::Unknown);
+
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        assert_eq!(lookup(&config, "pi:go:golang.org/x/tools"), Lookup::Unknown);
+        record(&config, "pi:go:golang.org/x/tools", false);
+        assert_eq!(lookup(&config, "pi:go:golang.org/x/tools"), Lookup::NotFound);
+        record(&config, "pi:go:golang.org/x/tools", true);
+        assert_eq!(lookup(&config, "pi:go:golang.org/x/tools"), Lookup::Found { stale: false });
+    }
+}
@@ -1,5 +1,16 @@
 pub mod cache;
+pub mod db;
 pub mod downloader;
+pub mod events;
+pub mod git;
+pub mod manager_cache;
+pub mod oci;
+pub mod patcher;
+pub mod plugins;
+pub mod repo_origin;
+pub mod signature;
+pub mod step_logs;
 pub mod sync;
 pub mod unarchiver;
 pub mod sandbox;
+pub mod venv;
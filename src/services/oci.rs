@@ -0,0 +1,312 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Read;
+use ureq::Agent;
+
+/// Fetch URLs of registry blobs: `oci://<registry>/<repository>[:tag|@digest][#<media type>]`.
+pub const OCI_SCHEME: &str = "oci://";
+
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.oci.image.index.v1+json, \
+    application/vnd.docker.distribution.manifest.v2+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json";
+
+/// A registry artifact as named by `fetch_oci()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OciReference {
+    pub registry: String,
+    pub repository: String,
+    /// A tag or a `sha256:` manifest digest.
+    pub reference: String,
+    /// Media type of the layer to pull; needed when the artifact has several.
+    pub media_type: Option<String>,
+}
+
+impl OciReference {
+    /// Parses `ghcr.io/org/tool:1.0`, `ghcr.io/org/tool@sha256:...` (tag
+    /// `latest` when neither is given), with or without the `oci://` scheme.
+    pub fn parse(reference: &str, media_type: Option<String>) -> Result<Self> {
+        let input = reference.strip_prefix(OCI_SCHEME).unwrap_or(reference);
+        let (registry, rest) = input.split_once('/')
+            .filter(|(registry, _)| registry.contains('.') || registry.contains(':') || *registry == "localhost")
+            .with_context(|| format!("'{}' does not start with a registry host", input))?;
+        let (repository, reference) = match rest.split_once('@') {
+            Some((repository, digest)) => (repository, digest.to_string()),
+            None => match rest.rsplit_once(':').filter(|(_, tag)| !tag.contains('/')) {
+                Some((repository, tag)) => (repository, tag.to_string()),
+                None => (rest, "latest".to_string()),
+            },
+        };
+        if repository.is_empty() || reference.is_empty() {
+            anyhow::bail!("'{}' is not a valid artifact reference", input);
+        }
+        Ok(Self { registry: registry.to_string(), repository: repository.to_string(), reference, media_type })
+    }
+
+    /// Parses a Fetch URL made by `to_url`; None for other URLs.
+    pub fn from_url(url: &str) -> Option<Result<Self>> {
+        let rest = url.strip_prefix(OCI_SCHEME)?;
+        let (reference, media_type) = match rest.split_once('#') {
+            Some((reference, media_type)) => (reference, Some(media_type.to_string())),
+            None => (rest, None),
+        };
+        Some(Self::parse(reference, media_type))
+    }
+
+    pub fn to_url(&self) -> String {
+        let separator = if self.reference.starts_with("sha256:") { '@' } else { ':' };
+        let mut url = format!("{}{}/{}{}{}", OCI_SCHEME, self.registry, self.repository, separator, self.reference);
+        if let Some(media_type) = &self.media_type {
+            url.push('#');
+            url.push_str(media_type);
+        }
+        url
+    }
+
+    /// Download file name when the recipe gives none: the repository's last
+    /// segment and the tag, with an archive extension guessed from the media type.
+    pub fn default_filename(&self) -> String {
+        let name = self.repository.rsplit('/').next().unwrap_or(&self.repository);
+        let tag = self.reference.replace(':', "-");
+        let extension = match self.media_type.as_deref() {
+            Some(t) if t.ends_with("tar+gzip") || t.ends_with(".tar.gz") => ".tar.gz",
+            Some(t) if t.ends_with("tar+xz") => ".tar.xz",
+            Some(t) if t.ends_with("tar+zstd") => ".tar.zst",
+            Some(t) if t.ends_with("zip") => ".zip",
+            _ => "",
+        };
+        format!("{}-{}{}", name, tag, extension)
+    }
+
+    fn api_base(&self) -> String {
+        let host = if self.registry == "docker.io" { "registry-1.docker.io" } else { &self.registry };
+        format!("https://{}/v2/{}", host, self.repository)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: String,
+    digest: String,
+    size: u64,
+    #[serde(default)]
+    platform: Option<DescriptorPlatform>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DescriptorPlatform {
+    os: String,
+    architecture: String,
+}
+
+/// An image manifest (`layers`) or an index of per-platform manifests (`manifests`).
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+}
+
+/// The blob `fetch_oci()` pulls, located through the manifest.
+#[derive(Debug)]
+pub struct OciBlob {
+    pub url: String,
+    /// Hex SHA-256 the content must have.
+    pub sha256: String,
+    pub size: u64,
+    token: Option<String>,
+}
+
+/// A session with one registry, holding the bearer token once the registry
+/// asked for one.
+struct Registry {
+    agent: Agent,
+    token: Option<String>,
+}
+
+impl Registry {
+    fn new() -> Self {
+        let config = Agent::config_builder().http_status_as_error(false).build();
+        Self { agent: Agent::new_with_config(config), token: None }
+    }
+
+    /// GETs `url`, answering a 401 with an anonymous token from the realm its
+    /// `WWW-Authenticate` challenge names.
+    fn get(&mut self, url: &str, accept: &str) -> Result<ureq::http::Response<ureq::Body>> {
+        let response = self.send(url, accept)?;
+        if response.status().as_u16() != 401 || self.token.is_some() {
+            return check_status(url, response);
+        }
+        let challenge = response.headers().get("www-authenticate")
+            .and_then(|h| h.to_str().ok())
+            .with_context(|| format!("[{}] registry requires authentication without a challenge", url))?;
+        self.token = Some(self.fetch_token(challenge)?);
+        check_status(url, self.send(url, accept)?)
+    }
+
+    fn send(&self, url: &str, accept: &str) -> Result<ureq::http::Response<ureq::Body>> {
+        let mut request = self.agent.get(url).header("accept", accept);
+        if let Some(token) = &self.token {
+            request = request.header("authorization", &format!("Bearer {}", token));
+        }
+        Ok(request.call()?)
+    }
+
+    fn fetch_token(&self, challenge: &str) -> Result<String> {
+        let params = parse_challenge(challenge).context("unsupported WWW-Authenticate challenge")?;
+        let realm = params.get("realm").context("WWW-Authenticate challenge has no realm")?;
+        let mut request = self.agent.get(realm);
+        for key in ["service", "scope"] {
+            if let Some(value) = params.get(key) {
+                request = request.query(key, value);
+            }
+        }
+        let mut response = check_status(realm, request.call()?)?;
+        #[derive(Deserialize)]
+        struct Token {
+            token: Option<String>,
+            access_token: Option<String>,
+        }
+        let token: Token = serde_json::from_str(&response.body_mut().read_to_string()?)
+            .with_context(|| format!("[{}] invalid token response", realm))?;
+        token.token.or(token.access_token).with_context(|| format!("[{}] no token in response", realm))
+    }
+}
+
+fn check_status(url: &str, response: ureq::http::Response<ureq::Body>) -> Result<ureq::http::Response<ureq::Body>> {
+    let status = response.status();
+    if !status.is_success() {
+        anyhow::bail!("[{}] registry answered {}", url, status);
+    }
+    Ok(response)
+}
+
+/// Parameters of a `Bearer key="value",...` challenge.
+fn parse_challenge(challenge: &str) -> Option<HashMap<String, String>> {
+    let params = challenge.trim().strip_prefix("Bearer ")?;
+    let mut out = HashMap::new();
+    let mut rest = params.trim();
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_ascii_lowercase();
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"')?,
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        out.insert(key, value.to_string());
+        rest = next.trim_start_matches(',').trim();
+    }
+    Some(out)
+}
+
+/// Finds the blob of `oci`: resolves the manifest (through an index, for
+/// the host platform), checks a pinned manifest digest and picks the layer
+/// with the requested media type, or the only layer.
+pub fn resolve(oci: &OciReference) -> Result<OciBlob> {
+    let mut registry = Registry::new();
+    let base = oci.api_base();
+    let mut manifest_url = format!("{}/manifests/{}", base, oci.reference);
+    let mut manifest = fetch_manifest(&mut registry, &manifest_url, &oci.reference)?;
+    if !manifest.manifests.is_empty() {
+        let entry = platform_manifest(&manifest.manifests)
+            .with_context(|| format!("[{}] no manifest for {}/{}", oci.to_url(), std::env::consts::OS, go_arch()))?;
+        manifest_url = format!("{}/manifests/{}", base, entry.digest);
+        manifest = fetch_manifest(&mut registry, &manifest_url, &entry.digest)?;
+    }
+
+    let layer = select_layer(&manifest.layers, oci.media_type.as_deref())
+        .with_context(|| format!("[{}] artifact layers", oci.to_url()))?;
+    let sha256 = layer.digest.strip_prefix("sha256:")
+        .with_context(|| format!("[{}] unsupported digest {}", oci.to_url(), layer.digest))?
+        .to_string();
+    Ok(OciBlob {
+        url: format!("{}/blobs/{}", base, layer.digest),
+        sha256,
+        size: layer.size,
+        token: registry.token,
+    })
+}
+
+fn fetch_manifest(registry: &mut Registry, url: &str, reference: &str) -> Result<Manifest> {
+    let mut response = registry.get(url, MANIFEST_TYPES)?;
+    let body = response.body_mut().read_to_vec()?;
+    if let Some(expected) = reference.strip_prefix("sha256:") {
+        let actual = hex::encode(<sha2::Sha256 as sha2::Digest>::digest(&body));
+        if actual != expected {
+            anyhow::bail!("[{}] manifest digest mismatch: got sha256:{}", url, actual);
+        }
+    }
+    serde_json::from_slice(&body).with_context(|| format!("[{}] invalid manifest", url))
+}
+
+/// The layer with `media_type`, or the only layer when none is asked for.
+fn select_layer<'a>(layers: &'a [Descriptor], media_type: Option<&str>) -> Result<&'a Descriptor> {
+    let types = || layers.iter().map(|l| l.media_type.as_str()).collect::<Vec<_>>().join(", ");
+    match media_type {
+        Some(wanted) => layers.iter().find(|l| l.media_type == wanted)
+            .with_context(|| format!("no layer of type {} (has {})", wanted, types())),
+        None => match layers {
+            [only] => Ok(only),
+            [] => anyhow::bail!("the manifest has no layers"),
+            _ => anyhow::bail!("several layers ({}), pass media_type", types()),
+        },
+    }
+}
+
+fn platform_manifest(manifests: &[Descriptor]) -> Option<&Descriptor> {
+    manifests.iter().find(|m| {
+        m.platform.as_ref().is_some_and(|p| p.os == std::env::consts::OS && p.architecture == go_arch())
+    })
+}
+
+/// The host architecture as registries name it.
+fn go_arch() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        other => other,
+    }
+}
+
+/// Opens the blob for streaming; registries usually redirect to storage,
+/// which gets no credentials.
+pub fn open_blob(blob: &OciBlob) -> Result<Box<dyn Read>> {
+    let mut registry = Registry::new();
+    registry.token = blob.token.clone();
+    let response = registry.get(&blob.url, "*/*")?;
+    Ok(Box::new(response.into_body().into_reader()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oci_reference() {
+        let oci = OciReference::parse("ghcr.io/org/tools/zig:0.13.0", Some("application/vnd.oci.image.layer.v1.tar+gzip".to_string())).unwrap();
+        assert_eq!((oci.registry.as_str(), oci.repository.as_str(), oci.reference.as_str()), ("ghcr.io", "org/tools/zig", "0.13.0"));
+        assert_eq!(oci.default_filename(), "zig-0.13.0.tar.gz");
+        assert_eq!(OciReference::from_url(&oci.to_url()).unwrap().unwrap(), oci);
+
+        let pinned = OciReference::parse("oci://localhost:5000/zig@sha256:abc", None).unwrap();
+        assert_eq!((pinned.repository.as_str(), pinned.reference.as_str()), ("zig", "sha256:abc"));
+        assert_eq!(pinned.to_url(), "oci://localhost:5000/zig@sha256:abc");
+        assert_eq!(OciReference::parse("localhost:5000/zig", None).unwrap().reference, "latest");
+        assert!(OciReference::parse("zig:0.13.0", None).is_err());
+        assert!(OciReference::from_url("https://x/zig.tar.gz").is_none());
+
+        let params = parse_challenge(r#"Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:org/zig:pull""#).unwrap();
+        assert_eq!(params["realm"], "https://ghcr.io/token");
+        assert_eq!(params["scope"], "repository:org/zig:pull");
+
+        let manifest: Manifest = serde_json::from_str(r#"{"layers": [
+            {"mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "digest": "sha256:aa", "size": 10,
+             "annotations": {"org.opencontainers.image.title": "zig.tar.gz"}},
+            {"mediaType": "text/plain", "digest": "sha256:bb", "size": 2}
+        ]}"#).unwrap();
+        assert_eq!(select_layer(&manifest.layers, Some("text/plain")).unwrap().digest, "sha256:bb");
+        assert!(select_layer(&manifest.layers, None).unwrap_err().to_string().contains("pass media_type"));
+    }
+}
@@ -0,0 +1,195 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Applies unified diffs (as made by `diff -u` or `git diff`) to a tree.
+pub struct Patcher;
+
+impl Patcher {
+    /// Fails unless `diff` parses as a unified diff of at least one file.
+    pub fn validate(diff: &str) -> Result<()> {
+        let files = split_files(diff)?;
+        files.iter().map(|text| parse(text)).collect::<Result<Vec<_>>>()?;
+        Ok(())
+    }
+
+    /// Applies every file of `diff` below `dir`, dropping the first `strip`
+    /// components of the paths in its headers like `patch -p`. Returns the
+    /// number of files changed; any hunk that doesn't apply fails it.
+    pub fn apply(diff: &str, dir: &Path, strip: u32) -> Result<usize> {
+        let files = split_files(diff)?;
+        let patches = files.iter().map(|text| parse(text)).collect::<Result<Vec<_>>>()?;
+        for patch in &patches {
+            let original = patch.original().filter(|p| *p != "/dev/null");
+            let modified = patch.modified().filter(|p| *p != "/dev/null");
+            let header = modified.or(original).context("patch hunk without file names")?;
+            let relative = strip_path(header, strip)?;
+            refuse_symlinks(dir, &relative)?;
+            let path = dir.join(relative);
+
+            let base = match original {
+                Some(_) => fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?,
+                None => String::new(),
+            };
+            let patched = diffy::apply(&base, patch).with_context(|| format!("{} does not apply", header))?;
+            if modified.is_none() {
+                fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, patched).with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+        Ok(patches.len())
+    }
+}
+
+fn parse(text: &str) -> Result<diffy::Patch<'_, str>> {
+    diffy::Patch::from_str(text).map_err(|e| anyhow::anyhow!("{}", e))
+}
+
+/// `header` without its first `strip` components, refused when it would
+/// leave the tree.
+fn strip_path(header: &str, strip: u32) -> Result<PathBuf> {
+    let path: PathBuf = Path::new(header).components().skip(strip as usize).collect();
+    if path.as_os_str().is_empty() {
+        anyhow::bail!("cannot strip {} component(s) from {}", strip, header);
+    }
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        anyhow::bail!("patch path {} leaves the source tree", header);
+    }
+    Ok(path)
+}
+
+/// Refuses `relative` below `dir` when it or a directory on the way is a
+/// symlink: the tree comes from an archive, whose links may point anywhere
+/// on the host.
+fn refuse_symlinks(dir: &Path, relative: &Path) -> Result<()> {
+    let mut path = dir.to_path_buf();
+    for component in relative.components() {
+        path.push(component);
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                anyhow::bail!("patch path {} goes through the symlink {}", relative.display(), path.display());
+            }
+            Ok(_) => {}
+            // Missing from here on: created below `dir`.
+            Err(_) => break,
+        }
+    }
+    Ok(())
+}
+
+/// Splits a diff into the text of each file it changes. Hunks are followed
+/// by their line counts, so removed lines starting with `-- ` aren't taken
+/// for the next file's header; lines between files (`diff --git`, `index`)
+/// are dropped.
+fn split_files(diff: &str) -> Result<Vec<String>> {
+    let mut files: Vec<String> = Vec::new();
+    let (mut old, mut new) = (0usize, 0usize);
+    let mut in_header = false;
+    for line in diff.split_inclusive('\n') {
+        if old > 0 || new > 0 {
+            match line.as_bytes().first() {
+                Some(b' ') | Some(b'\n') => (old, new) = (old.saturating_sub(1), new.saturating_sub(1)),
+                Some(b'-') => old = old.saturating_sub(1),
+                Some(b'+') => new = new.saturating_sub(1),
+                Some(b'\\') => {}
+                _ => anyhow::bail!("truncated hunk before: {}", line.trim_end()),
+            }
+        } else if line.starts_with("--- ") && !in_header {
+            files.push(String::new());
+            in_header = true;
+        } else if line.starts_with("@@ ") {
+            (old, new) = hunk_counts(line).with_context(|| format!("malformed hunk header: {}", line.trim_end()))?;
+            in_header = false;
+        } else if !(line.starts_with("+++ ") && in_header || line.starts_with('\\')) {
+            continue;
+        }
+        files.last_mut().context("hunk before any file header")?.push_str(line);
+    }
+    if old > 0 || new > 0 {
+        anyhow::bail!("patch ends inside a hunk");
+    }
+    if files.is_empty() {
+        anyhow::bail!("patch has no file changes");
+    }
+    Ok(files)
+}
+
+/// Line counts of the old and new side of `@@ -a,b +c,d @@`.
+fn hunk_counts(line: &str) -> Option<(usize, usize)> {
+    let mut ranges = line.strip_prefix("@@ ")?.split(' ');
+    let count = |range: &str| -> Option<usize> {
+        match range.split_once(',') {
+            Some((_, n)) => n.parse().ok(),
+            None => Some(1),
+        }
+    };
+    let old = count(ranges.next()?.strip_prefix('-')?)?;
+    let new = count(ranges.next()?.strip_prefix('+')?)?;
+    Some((old, new))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFF: &str = "\
+diff --git a/src/main.c b/src/main.c
+index 1111111..2222222 100644
+--- a/src/main.c
++++ b/src/main.c
+@@ -1,3 +1,3 @@
+ int main() {
+--- x;
++++ x;
+ }
+--- /dev/null
++++ b/NEWS
+@@ -0,0 +1 @@
++patched
+--- a/obsolete.txt
++++ /dev/null
+@@ -1 +0,0 @@
+-gone
+";
+
+    #[test]
+    fn test_apply() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir_all(tmp.path().join("src")).unwrap();
+        fs::write(tmp.path().join("src/main.c"), "int main() {\n-- x;\n}\n").unwrap();
+        fs::write(tmp.path().join("obsolete.txt"), "gone\n").unwrap();
+
+        assert_eq!(split_files(DIFF).unwrap().len(), 3);
+        assert_eq!(Patcher::apply(DIFF, tmp.path(), 1).unwrap(), 3);
+        assert_eq!(fs::read_to_string(tmp.path().join("src/main.c")).unwrap(), "int main() {\n++ x;\n}\n");
+        assert_eq!(fs::read_to_string(tmp.path().join("NEWS")).unwrap(), "patched\n");
+        assert!(!tmp.path().join("obsolete.txt").exists());
+
+        // Applied twice, the context no longer matches.
+        assert!(Patcher::apply(DIFF, tmp.path(), 1).is_err());
+        assert!(strip_path("a/../../etc/passwd", 1).is_err());
+        assert!(strip_path("main.c", 1).is_err());
+    }
+
+    #[test]
+    fn test_apply_refuses_symlinks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (tree, outside) = (tmp.path().join("tree"), tmp.path().join("home"));
+        fs::create_dir_all(&tree).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join(".bashrc"), "safe\n").unwrap();
+        std::os::unix::fs::symlink(&outside, tree.join("x")).unwrap();
+        std::os::unix::fs::symlink(outside.join(".bashrc"), tree.join("rc")).unwrap();
+
+        for file in ["x/.bashrc", "rc"] {
+            let diff = format!("--- a/{file}\n+++ b/{file}\n@@ -1 +1 @@\n-safe\n+owned\n");
+            let err = Patcher::apply(&diff, &tree, 1).unwrap_err();
+            assert!(err.to_string().contains("goes through the symlink"), "{:#}", err);
+        }
+        assert_eq!(fs::read_to_string(outside.join(".bashrc")).unwrap(), "safe\n");
+    }
+}
@@ -0,0 +1,176 @@
+use crate::services::sandbox::{BindType, Bubblewrap};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+/// Executables in the plugins directory named `pi-step-<kind>` run `step("<kind>")`.
+pub const PLUGIN_PREFIX: &str = "pi-step-";
+/// Version of the JSON exchanged with plugin executables.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// What a plugin step gets to work with, written as one JSON document on the
+/// plugin's stdin.
+#[derive(Debug, Serialize)]
+pub struct StepRequest<'a> {
+    pub protocol: u32,
+    pub step: &'a str,
+    pub params: &'a BTreeMap<String, String>,
+    pub package: &'a str,
+    pub version: &'a str,
+    /// Output of the previous step, if any.
+    pub input: Option<&'a Path>,
+    /// An empty directory the step may write its output to.
+    pub output: &'a Path,
+}
+
+/// The JSON document a plugin prints on stdout when it exits.
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct StepReply {
+    /// The step's output; the input passes through when omitted.
+    #[serde(default)]
+    pub output: Option<PathBuf>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Runs pipeline steps of one kind.
+pub trait StepExecutor: Send + Sync {
+    /// Runs `request` in `sandbox`, prepared like a Run step's sandbox, and
+    /// returns the path of its output.
+    fn execute(&self, request: &StepRequest, sandbox: Bubblewrap) -> Result<PathBuf>;
+}
+
+/// A `pi-step-<kind>` executable speaking the JSON protocol.
+pub struct ExecutablePlugin {
+    program: PathBuf,
+}
+
+impl StepExecutor for ExecutablePlugin {
+    fn execute(&self, request: &StepRequest, mut sandbox: Bubblewrap) -> Result<PathBuf> {
+        sandbox.add_bind(BindType::RoBind, &self.program);
+        sandbox.set_command(&self.program.to_string_lossy(), &[]);
+        let mut cmd = sandbox.build_command();
+        cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::inherit());
+        log::debug!("[plugin] spawning {:?}", cmd);
+        let mut child = cmd.spawn().with_context(|| format!("Failed to spawn {}", self.program.display()))?;
+
+        let input = serde_json::to_vec(request)?;
+        let mut stdin = child.stdin.take().context("plugin stdin unavailable")?;
+        // A plugin that doesn't read its request closes the pipe; its reply still decides.
+        let _ = stdin.write_all(&input);
+        drop(stdin);
+        let mut stdout = String::new();
+        child.stdout.take().context("plugin stdout unavailable")?.read_to_string(&mut stdout)?;
+        let status = child.wait()?;
+
+        let reply = parse_reply(&stdout);
+        match (status.success(), reply) {
+            (_, Ok(StepReply { error: Some(error), .. })) => anyhow::bail!("{} step failed: {}", request.step, error),
+            (true, Ok(StepReply { output: Some(output), .. })) => checked_output(request, &output),
+            (true, Ok(StepReply { output: None, .. })) => request.input.map(Path::to_path_buf)
+                .with_context(|| format!("{} step returned no output and has no input", request.step)),
+            (true, Err(e)) => Err(e.context(format!("{} step sent an invalid reply", request.step))),
+            (false, _) => anyhow::bail!("{} step failed ({})", request.step, status),
+        }
+    }
+}
+
+/// The reply's output, resolved, which must lie in the step's output
+/// directory: a plugin can't hand pi some other part of the filesystem.
+fn checked_output(request: &StepRequest, output: &Path) -> Result<PathBuf> {
+    let resolved = output.canonicalize()
+        .with_context(|| format!("{} step returned output {} which doesn't exist", request.step, output.display()))?;
+    let allowed = request.output.canonicalize()
+        .with_context(|| format!("Failed to resolve {}", request.output.display()))?;
+    if !resolved.starts_with(&allowed) {
+        anyhow::bail!("{} step returned output {} outside its output directory {}", request.step, output.display(), request.output.display());
+    }
+    Ok(resolved)
+}
+
+/// The last non-empty stdout line is the reply; earlier lines are plugin output.
+fn parse_reply(stdout: &str) -> Result<StepReply> {
+    let line = stdout.lines().rev().find(|l| !l.trim().is_empty()).context("empty reply")?;
+    for earlier in stdout.lines().filter(|l| *l != line) {
+        log::info!("[plugin] {}", earlier);
+    }
+    Ok(serde_json::from_str(line)?)
+}
+
+/// Step executors by kind.
+#[derive(Default)]
+pub struct Executors {
+    executors: HashMap<String, Box<dyn StepExecutor>>,
+}
+
+impl Executors {
+    /// The `pi-step-<kind>` executables of `dir`.
+    pub fn discover(dir: &Path) -> Self {
+        let mut executors = Self::default();
+        let Ok(entries) = std::fs::read_dir(dir) else { return executors };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let Some(kind) = name.strip_prefix(PLUGIN_PREFIX) else { continue };
+            let executable = entry.metadata().is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0);
+            if !executable {
+                log::warn!("[plugin] {} is not executable, ignoring it", entry.path().display());
+                continue;
+            }
+            executors.register(kind, Box::new(ExecutablePlugin { program: entry.path() }));
+        }
+        executors
+    }
+
+    pub fn register(&mut self, kind: &str, executor: Box<dyn StepExecutor>) {
+        self.executors.insert(kind.to_string(), executor);
+    }
+
+    pub fn get(&self, kind: &str) -> Option<&dyn StepExecutor> {
+        self.executors.get(kind).map(|e| e.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discover_and_reply() {
+        let tmp = tempfile::tempdir().unwrap();
+        let plugin = tmp.path().join("pi-step-notarize");
+        std::fs::write(&plugin, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&plugin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(tmp.path().join("pi-step-upload"), "").unwrap();
+        std::fs::write(tmp.path().join("README"), "").unwrap();
+        let executors = Executors::discover(tmp.path());
+        assert!(executors.get("notarize").is_some());
+        assert!(executors.get("upload").is_none());
+
+        let reply = parse_reply("signing...\n{\"output\": \"/tmp/out\"}\n\n").unwrap();
+        assert_eq!(reply, StepReply { output: Some(PathBuf::from("/tmp/out")), error: None });
+        assert!(parse_reply("done\n").is_err());
+    }
+
+    #[test]
+    fn test_output_stays_in_output_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let output = tmp.path().join("out");
+        std::fs::create_dir_all(output.join("dist")).unwrap();
+        std::os::unix::fs::symlink(tmp.path(), output.join("escape")).unwrap();
+        let params = BTreeMap::new();
+        let request = StepRequest { protocol: PROTOCOL_VERSION, step: "notarize", params: &params, package: "app", version: "1.0", input: None, output: &output };
+
+        let dist = checked_output(&request, &output.join("dist")).unwrap();
+        assert_eq!(dist, output.canonicalize().unwrap().join("dist"));
+        assert!(checked_output(&request, &output).is_ok());
+        for outside in [tmp.path().to_path_buf(), output.join("dist/../.."), output.join("escape"), PathBuf::from("/etc")] {
+            let err = checked_output(&request, &outside).unwrap_err().to_string();
+            assert!(err.contains("outside its output directory"), "{}: {}", outside.display(), err);
+        }
+        assert!(checked_output(&request, &output.join("missing")).is_err());
+    }
+}
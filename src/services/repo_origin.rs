@@ -0,0 +1,198 @@
+use crate::models::config::Config;
+use crate::models::version_entry::Signature;
+use crate::services::downloader::{Downloader, Fetched};
+use crate::services::{git, signature};
+use crate::services::unarchiver::Unarchiver;
+use crate::utils::fs::sanitize_name;
+use crate::utils::timings::Phase;
+use anyhow::{Context, Result};
+use sha2::Digest;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Archive extensions a repository URL can have; any other URL is cloned with git.
+const ARCHIVE_EXTENSIONS: &[&str] = &[".tar.gz", ".tgz", ".tar.xz", ".tar.zst", ".zip", ".7z"];
+
+/// Where a repository added from a URL comes from (`Repository::origin`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OriginKind {
+    /// A git repository, kept as a shallow clone of its default branch.
+    Git,
+    /// A tarball or zip, unpacked without its single top-level directory.
+    Archive,
+}
+
+impl OriginKind {
+    /// The kind of `location`; None for a local path.
+    pub fn of(location: &str) -> Option<Self> {
+        let remote = ["https://", "http://", "git://", "ssh://", "git@", "file://"].iter().any(|p| location.starts_with(p));
+        if !remote {
+            return None;
+        }
+        let path = location.split(['?', '#']).next().unwrap_or(location);
+        Some(if ARCHIVE_EXTENSIONS.iter().any(|ext| path.ends_with(ext)) { Self::Archive } else { Self::Git })
+    }
+}
+
+/// What an archive must match before it is unpacked, from `pi repo add
+/// --checksum` and `--public-key`. Git origins take neither.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ArchiveCheck<'a> {
+    pub checksum: Option<&'a str>,
+    pub signature: Option<&'a Signature>,
+}
+
+impl ArchiveCheck<'_> {
+    fn is_empty(&self) -> bool {
+        self.checksum.is_none() && self.signature.is_none()
+    }
+}
+
+/// The managed directory of the repository at `origin`:
+/// `<cache_dir>/repos/<name>-<sha256(origin)[..12]>`.
+pub fn checkout_dir(config: &Config, origin: &str) -> PathBuf {
+    let path = origin.split(['?', '#']).next().unwrap_or(origin).trim_end_matches('/');
+    let mut name = path.rsplit(['/', ':']).next().unwrap_or("repo");
+    for ext in ARCHIVE_EXTENSIONS.iter().chain(&[".git"]) {
+        name = name.strip_suffix(ext).unwrap_or(name);
+    }
+    let digest = hex::encode(sha2::Sha256::digest(origin.as_bytes()));
+    config.cache_dir.join("repos").join(format!("{}-{}", sanitize_name(name), &digest[..12]))
+}
+
+/// Clones or downloads `origin` into its managed directory, or updates it;
+/// returns the directory.
+pub fn fetch(config: &Config, origin: &str, check: ArchiveCheck) -> Result<PathBuf> {
+    let kind = OriginKind::of(origin).with_context(|| format!("{} is not a repository URL", origin))?;
+    let dir = checkout_dir(config, origin);
+    let _timer = config.state.timings.scope(Phase::Network);
+    match kind {
+        OriginKind::Git if !check.is_empty() => anyhow::bail!("a checksum or signature only applies to archive URLs"),
+        OriginKind::Git => {
+            let commit = git::clone_or_pull(origin, &dir)?;
+            log::debug!("[repo] {} at {}", origin, commit);
+        }
+        OriginKind::Archive => unpack_archive(origin, &dir, check)?,
+    }
+    Ok(dir)
+}
+
+/// The ETag of the archive unpacked in `dir`, kept beside it.
+fn etag_file(dir: &Path) -> PathBuf {
+    let name = dir.file_name().unwrap_or_default().to_string_lossy();
+    dir.with_file_name(format!("{}.etag", name))
+}
+
+/// Downloads, verifies and unpacks the archive next to `dir`, then replaces
+/// `dir`, so a failed update keeps the previous contents. Nothing is
+/// downloaded while the server reports the ETag of the last download current.
+fn unpack_archive(url: &str, dir: &Path, check: ArchiveCheck) -> Result<()> {
+    let parent = dir.parent().context("repository directory has no parent")?;
+    fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+    let tmp = tempfile::tempdir_in(parent).context("Failed to create a temporary directory")?;
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let ext = ARCHIVE_EXTENSIONS.iter().find(|ext| path.ends_with(*ext)).copied().unwrap_or(".tar.gz");
+    let archive = tmp.path().join(format!("repo{}", ext));
+    let etag = dir.exists().then(|| fs::read_to_string(etag_file(dir)).ok()).flatten();
+    log::info!("[repo] downloading {}", url);
+    let etag = match Downloader::download_if_changed(url, &archive, check.checksum, etag.as_deref())? {
+        Fetched::NotModified => {
+            log::info!("[repo] {} is unchanged", url);
+            return Ok(());
+        }
+        Fetched::Downloaded { etag } => etag,
+    };
+    if let Some(sig) = check.signature {
+        let sig_path = tmp.path().join("repo.sig");
+        Downloader::download_to_file(&sig.url, &sig_path, None, None)
+            .with_context(|| format!("Failed to download the signature {}", sig.url))?;
+        signature::verify(&archive, &sig_path, &sig.public_key)?;
+    }
+    let unpacked = tmp.path().join("unpacked");
+    Unarchiver::unarchive(&archive, &unpacked)?;
+
+    let entries: Vec<PathBuf> = fs::read_dir(&unpacked)?.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+    let root = match entries.as_slice() {
+        [single] if single.is_dir() => single.clone(),
+        _ => unpacked,
+    };
+    if dir.exists() {
+        fs::remove_dir_all(dir).with_context(|| format!("Failed to remove {}", dir.display()))?;
+    }
+    fs::rename(&root, dir).with_context(|| format!("Failed to move {} to {}", root.display(), dir.display()))?;
+    match etag {
+        Some(etag) => fs::write(etag_file(dir), etag).with_context(|| format!("Failed to write {}", etag_file(dir).display())),
+        None => {
+            let _ = fs::remove_file(etag_file(dir));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_origin_kind_and_archive() {
+        assert_eq!(OriginKind::of("https://github.com/org/recipes.git"), Some(OriginKind::Git));
+        assert_eq!(OriginKind::of("git@github.com:org/recipes"), Some(OriginKind::Git));
+        assert_eq!(OriginKind::of("https://example.com/recipes-1.0.tar.gz?download=1"), Some(OriginKind::Archive));
+        assert_eq!(OriginKind::of("./recipes"), None);
+
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().join("pi"));
+        let dir = checkout_dir(&config, "https://github.com/org/recipes.git");
+        let name = dir.file_name().unwrap().to_string_lossy().to_string();
+        assert!(name.starts_with("recipes-") && name.len() == "recipes-".len() + 12, "{}", name);
+
+        // A tarball with a top-level directory, served from a file:// URL.
+        let src = tmp.path().join("src");
+        fs::create_dir_all(src.join("recipes-1.0")).unwrap();
+        fs::write(src.join("recipes-1.0/pi.repo.json"), r#"{"name": "remote"}"#).unwrap();
+        let tarball = tmp.path().join("recipes-1.0.tar.gz");
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(fs::File::create(&tarball).unwrap(), flate2::Compression::default()));
+        builder.append_dir_all("recipes-1.0", src.join("recipes-1.0")).unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let url = format!("file://{}", tarball.display());
+        let dir = fetch(&config, &url, ArchiveCheck::default()).unwrap();
+        assert!(dir.join("pi.repo.json").exists());
+        fs::write(src.join("recipes-1.0/extra.star"), "").unwrap();
+        assert_eq!(fetch(&config, &url, ArchiveCheck::default()).unwrap(), dir);
+        assert!(dir.join("pi.repo.json").exists());
+
+        let checksum = crate::utils::crypto::calculate_file_checksum(&tarball, 64).unwrap();
+        let wrong = "0".repeat(64);
+        assert!(fetch(&config, &url, ArchiveCheck { checksum: Some(&wrong), signature: None }).is_err());
+        assert!(dir.join("pi.repo.json").exists(), "a failed update keeps the checkout");
+        fetch(&config, &url, ArchiveCheck { checksum: Some(&checksum), signature: None }).unwrap();
+        assert!(fetch(&config, "https://example.com/r.git", ArchiveCheck { checksum: Some(&checksum), signature: None }).is_err());
+
+        // Served over http: a second fetch sends the ETag and keeps the checkout on 304.
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let body = fs::read(&tarball).unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut sent = Vec::new();
+            for _ in 0..2 {
+                let request = server.recv().unwrap();
+                let tag = request.headers().iter().find(|h| h.field.equiv("if-none-match")).map(|h| h.value.to_string());
+                let response = match tag {
+                    Some(_) => tiny_http::Response::from_data(Vec::new()).with_status_code(304),
+                    None => tiny_http::Response::from_data(body.clone()),
+                };
+                sent.push(tag);
+                request.respond(response.with_header(tiny_http::Header::from_bytes("ETag", "\"v1\"").unwrap())).unwrap();
+            }
+            sent
+        });
+        let url = format!("http://{}/recipes-1.0.tar.gz", addr);
+        let dir = fetch(&config, &url, ArchiveCheck::default()).unwrap();
+        assert_eq!(fs::read_to_string(etag_file(&dir)).unwrap(), "\"v1\"");
+        fs::write(dir.join("marker"), "").unwrap();
+        fetch(&config, &url, ArchiveCheck::default()).unwrap();
+        assert!(dir.join("marker").exists());
+        assert_eq!(handle.join().unwrap(), vec![None, Some("\"v1\"".to_string())]);
+    }
+}
@@ -1,9 +1,16 @@
-use std::collections::BTreeMap;
-use std::process::Command;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 use std::path::{Path, PathBuf};
 use std::os::unix::process::CommandExt;
 use anyhow::{Context, Result};
-use crate::services::sandbox::types::{BindType, BindPair};
+use crate::services::sandbox::types::{BindType, BindPair, SandboxBackend};
+
+/// Set once from settings in main; bubblewrap until then.
+static BACKEND: OnceLock<SandboxBackend> = OnceLock::new();
 
 pub struct Bubblewrap {
     binds: BTreeMap<PathBuf, BindPair>,
@@ -44,6 +51,7 @@ impl Bubblewrap {
         });
     }
 
+    #[allow(dead_code)]
     pub fn add_binds<P: AsRef<Path>>(&mut self, typ: BindType, paths: &[P]) {
         for path in paths {
             self.add_bind(typ, path);
@@ -73,11 +81,22 @@ impl Bubblewrap {
         self.flags.push(flag.to_string());
     }
 
+    pub fn remove_flag(&mut self, flag: &str) {
+        self.flags.retain(|f| f != flag);
+    }
+
+    #[allow(dead_code)]
     pub fn unset_env(&mut self, name: &str) {
         self.unsets.push(name.to_string());
         self.envs.remove(name);
     }
 
+    /// Starts from an empty environment instead of ours.
+    pub fn clear_env(&mut self) {
+        self.envs.clear();
+        self.add_flag("--clearenv");
+    }
+
     pub fn set_env(&mut self, name: &str, value: &str) {
         self.envs.insert(name.to_string(), value.to_string());
     }
@@ -104,7 +123,22 @@ impl Bubblewrap {
         self.args = args.to_vec();
     }
 
+    pub fn set_backend(backend: SandboxBackend) {
+        if backend == SandboxBackend::None {
+            log::warn!("[sandbox] disabled, commands run directly on the host");
+        }
+        let _ = BACKEND.set(backend);
+    }
+
+    /// The backend set by `set_backend`, bubblewrap until then.
+    pub fn backend() -> SandboxBackend {
+        BACKEND.get().copied().unwrap_or_default()
+    }
+
     pub fn build_command(&self) -> Command {
+        if Self::backend() == SandboxBackend::None {
+            return self.host_command();
+        }
         let mut cmd = Command::new("/usr/bin/bwrap");
 
         for flag in &self.flags {
@@ -133,8 +167,35 @@ impl Bubblewrap {
         cmd
     }
 
+    /// The command run on the host without bwrap (`SandboxBackend::None`):
+    /// the sandbox environment only, with paths of remapped binds (cave home,
+    /// pilocal) in it, the working directory and path arguments translated
+    /// to their host directories.
+    fn host_command(&self) -> Command {
+        let mut cmd = Command::new(self.to_host(self.executable.as_deref().unwrap_or("true")));
+        cmd.args(self.args.iter().map(|arg| self.to_host(arg)));
+        cmd.env_clear();
+        for (key, value) in &self.envs {
+            let value: Vec<String> = value.split(':').map(|part| self.to_host(part)).collect();
+            cmd.env(key, value.join(":"));
+        }
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(self.to_host(&cwd.to_string_lossy()));
+        }
+        cmd
+    }
+
+    /// `value` with its host path when it is an absolute path under a
+    /// remapped bind, else unchanged.
+    fn to_host(&self, value: &str) -> String {
+        if !value.starts_with('/') {
+            return value.to_string();
+        }
+        self.host_path(Path::new(value)).map_or_else(|| value.to_string(), |p| p.to_string_lossy().into_owned())
+    }
+
     fn apply_binds(&self, cmd: &mut Command) {
-        for (_, bind) in &self.binds {
+        for bind in self.binds.values() {
             cmd.arg(bind.bind_type.as_str());
             if let Some(ref source) = bind.host_source {
                 cmd.arg(source);
@@ -152,6 +213,7 @@ impl Bubblewrap {
         }
     }
 
+    #[tracing::instrument(skip_all, fields(hostname = ?self.hostname))]
     pub fn spawn(&self) -> Result<()> {
         let mut cmd = self.build_command();
         log::debug!("Spawning sandbox: {:?}", cmd);
@@ -162,6 +224,87 @@ impl Bubblewrap {
         Ok(())
     }
 
+    /// Like `spawn`, but tees stdout and stderr and keeps their last `lines`
+    /// lines for diagnostics. Only failing to start the process is an error.
+    #[tracing::instrument(skip_all, fields(hostname = ?self.hostname))]
+    pub fn spawn_with_tail(&self, lines: usize, log: Option<File>) -> Result<SpawnOutcome> {
+        let cmd = self.build_command();
+        log::debug!("Spawning sandbox: {:?}", cmd);
+        spawn_command_with_tail(cmd, lines, log).context("Failed to spawn bubblewrap process")
+    }
+
+    /// Runs the command and collects its stdout and stderr; it is killed
+    /// when it runs longer than `timeout`. Only failing to start it or the
+    /// timeout are errors.
+    pub fn output(&self, timeout: Duration) -> Result<Output> {
+        let mut cmd = self.build_command();
+        log::debug!("Spawning sandbox: {:?}", cmd);
+        cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = cmd.spawn().context("Failed to spawn bubblewrap process")?;
+        let read = |pipe: Option<Box<dyn Read + Send>>| std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut buf);
+            }
+            buf
+        });
+        let stdout = read(child.stdout.take().map(|p| Box::new(p) as Box<dyn Read + Send>));
+        let stderr = read(child.stderr.take().map(|p| Box::new(p) as Box<dyn Read + Send>));
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait().context("Failed to wait for process")? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                anyhow::bail!("timed out after {:?}", timeout);
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        };
+        Ok(Output { status, stdout: stdout.join().unwrap_or_default(), stderr: stderr.join().unwrap_or_default() })
+    }
+
+    pub fn envs(&self) -> &BTreeMap<String, String> {
+        &self.envs
+    }
+
+    pub fn cwd(&self) -> Option<&Path> {
+        self.cwd.as_deref()
+    }
+
+    /// One line per mount, e.g. `--ro-bind /usr -> /usr`, ordered by target.
+    pub fn bind_table(&self) -> Vec<String> {
+        self.binds.values()
+            .map(|b| match &b.host_source {
+                Some(source) => format!("{} {} -> {}", b.bind_type.as_str(), source.display(), b.cave_target.display()),
+                None => format!("{} {}", b.bind_type.as_str(), b.cave_target.display()),
+            })
+            .collect()
+    }
+
+    /// Where `program` is inside the sandbox: itself when it contains a `/`,
+    /// otherwise the first match on the sandbox PATH. None when no bound host
+    /// file backs it.
+    pub fn find_program(&self, program: &str) -> Option<PathBuf> {
+        let candidates: Vec<PathBuf> = if program.contains('/') {
+            vec![PathBuf::from(program)]
+        } else {
+            self.envs.get("PATH").map(|path| {
+                path.split(':').filter(|d| !d.is_empty()).map(|d| Path::new(d).join(program)).collect()
+            }).unwrap_or_default()
+        };
+        candidates.into_iter().find(|c| self.host_path(c).is_some_and(|h| h.is_file()))
+    }
+
+    /// The host path behind `cave_path`, through the deepest bind holding it.
+    fn host_path(&self, cave_path: &Path) -> Option<PathBuf> {
+        let bind = self.binds.values()
+            .filter(|b| cave_path.starts_with(&b.cave_target))
+            .max_by_key(|b| b.cave_target.components().count())?;
+        Some(bind.host_source.as_ref()?.join(cave_path.strip_prefix(&bind.cave_target).ok()?))
+    }
+
     pub fn exec(&self) -> Result<()> {
         let mut cmd = self.build_command();
         log::debug!("Exec sandbox: {:?}", cmd);
@@ -170,3 +313,92 @@ impl Bubblewrap {
         Err(anyhow::Error::from(err).context("Failed to exec into bubblewrap"))
     }
 }
+
+/// Exit status and last output lines of `Bubblewrap::spawn_with_tail`.
+pub struct SpawnOutcome {
+    pub status: ExitStatus,
+    pub tail: Vec<String>,
+}
+
+/// Runs `cmd` with its stdout and stderr written to `log`, keeping their
+/// last `lines` lines. They are teed to ours too without a log, or with
+/// `-v`. Only failing to start the process is an error.
+pub fn spawn_command_with_tail(mut cmd: Command, lines: usize, log: Option<File>) -> Result<SpawnOutcome> {
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = cmd.spawn()?;
+
+    let echo = log.is_none() || log::log_enabled!(log::Level::Debug);
+    let log = log.map(|file| Arc::new(parking_lot::Mutex::new(file)));
+    let tail = Arc::new(parking_lot::Mutex::new(VecDeque::with_capacity(lines)));
+    let stdout = child.stdout.take()
+        .map(|out| tee(out, echo.then(std::io::stdout), log.clone(), tail.clone(), lines));
+    let stderr = child.stderr.take()
+        .map(|err| tee(err, echo.then(std::io::stderr), log.clone(), tail.clone(), lines));
+    let status = child.wait().context("Failed to wait for process")?;
+    for handle in [stdout, stderr].into_iter().flatten() {
+        let _ = handle.join();
+    }
+    let tail = std::mem::take(&mut *tail.lock()).into_iter().collect();
+    Ok(SpawnOutcome { status, tail })
+}
+
+/// Copies `from` to `to` and `log` as it arrives, keeping the last `lines`
+/// lines in `tail`.
+fn tee(
+    from: impl Read + Send + 'static,
+    mut to: Option<impl Write + Send + 'static>,
+    log: Option<Arc<parking_lot::Mutex<File>>>,
+    tail: Arc<parking_lot::Mutex<VecDeque<String>>>,
+    lines: usize,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(from);
+        let mut line = Vec::new();
+        while let Ok(n) = reader.read_until(b'\n', &mut line) {
+            if n == 0 {
+                break;
+            }
+            if let Some(to) = &mut to {
+                let _ = to.write_all(&line);
+                let _ = to.flush();
+            }
+            if let Some(log) = &log {
+                let _ = log.lock().write_all(&line);
+            }
+            let mut tail = tail.lock();
+            if tail.len() >= lines.max(1) {
+                tail.pop_front();
+            }
+            tail.push_back(String::from_utf8_lossy(&line).trim_end_matches(['\n', '\r']).to_string());
+            line.clear();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_command_translates_remapped_paths() {
+        let mut b = Bubblewrap::new();
+        b.add_bind(BindType::RoBind, "/usr");
+        b.add_map_bind(BindType::Bind, "/cache/caves/app", "/home/u");
+        b.add_map_bind(BindType::RoBind, "/cache/pilocals/app", "/home/u/.pilocal");
+        b.set_env("PATH", "/home/u/.pilocal/bin:/usr/bin");
+        b.set_env("PI_CAVE", "app");
+        b.set_env("SECRET", "x");
+        b.unset_env("SECRET");
+        b.set_cwd("/home/u/src");
+        b.set_command("/home/u/.pilocal/bin/tool", &["--flag".to_string(), "/home/u/.pilocal/etc".to_string()]);
+
+        let cmd = b.host_command();
+        assert_eq!(cmd.get_program(), "/cache/pilocals/app/bin/tool");
+        assert_eq!(cmd.get_args().collect::<Vec<_>>(), vec!["--flag", "/cache/pilocals/app/etc"]);
+        assert_eq!(cmd.get_current_dir(), Some(Path::new("/cache/caves/app/src")));
+        let envs: BTreeMap<_, _> = cmd.get_envs().filter_map(|(k, v)| Some((k.to_str()?, v?.to_str()?))).collect();
+        assert_eq!(envs.get("PATH"), Some(&"/cache/pilocals/app/bin:/usr/bin"));
+        assert_eq!(envs.get("PI_CAVE"), Some(&"app"));
+        assert!(!envs.contains_key("SECRET"));
+    }
+}
@@ -0,0 +1,100 @@
+use crate::services::sandbox::Bubblewrap;
+use crate::utils::fs::sanitize_name;
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Output lines kept for the diagnostics of a failed step.
+pub const TAIL_LINES: usize = 100;
+
+/// A failed `Run` step, as recorded in its diagnostic bundle.
+pub struct StepFailure<'a> {
+    pub package: &'a str,
+    pub version: &'a str,
+    pub index: usize,
+    pub command: &'a str,
+    pub status: String,
+    pub tail: &'a [String],
+}
+
+/// Writes `<state_dir>/diagnostics/<package>-<version>-step<index>-<time>/`:
+/// `step.json` (what ran and how it ended), `env.txt`, `binds.txt`,
+/// `bwrap.txt` (the full command line) and `output.log` (last output lines).
+/// `pi devel rerun-step` re-executes the step in the same sandbox.
+pub fn write_bundle(state_dir: &Path, failure: &StepFailure, sandbox: &Bubblewrap) -> Result<PathBuf> {
+    let now = chrono::Local::now();
+    let dir = state_dir.join("diagnostics").join(format!(
+        "{}-step{}-{}",
+        sanitize_name(&format!("{}-{}", failure.package, failure.version)),
+        failure.index,
+        now.format("%Y%m%d-%H%M%S"),
+    ));
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    let step = json!({
+        "package": failure.package,
+        "version": failure.version,
+        "index": failure.index,
+        "command": failure.command,
+        "cwd": sandbox.cwd(),
+        "status": failure.status,
+        "time": now.to_rfc3339(),
+    });
+    fs::write(dir.join("step.json"), serde_json::to_string_pretty(&step)?)?;
+
+    let env: String = sandbox.envs().iter().map(|(k, v)| format!("{}={}\n", k, v)).collect();
+    fs::write(dir.join("env.txt"), env)?;
+    fs::write(dir.join("binds.txt"), sandbox.bind_table().join("\n") + "\n")?;
+
+    let cmd = sandbox.build_command();
+    let argv: Vec<String> = std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|a| shell_quote(&a.to_string_lossy()))
+        .collect();
+    fs::write(dir.join("bwrap.txt"), argv.join(" ") + "\n")?;
+
+    let mut output = failure.tail.join("\n");
+    output.push('\n');
+    fs::write(dir.join("output.log"), output)?;
+    Ok(dir)
+}
+
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:@,+".contains(c)) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::sandbox::BindType;
+
+    #[test]
+    fn test_write_bundle() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut b = Bubblewrap::new();
+        b.add_bind(BindType::RoBind, "/usr");
+        b.set_env("PI_TEST", "it's");
+        b.set_cwd("/src");
+        b.set_command("/bin/bash", &["-c".to_string(), "make install".to_string()]);
+        let tail = vec!["make: *** [install] Error 2".to_string()];
+
+        let dir = write_bundle(tmp.path(), &StepFailure {
+            package: "erlang", version: "26.0", index: 2, command: "make install",
+            status: "exit status: 2".to_string(), tail: &tail,
+        }, &b).unwrap();
+
+        assert!(dir.file_name().unwrap().to_string_lossy().starts_with("erlang-26.0-step2-"));
+        let read = |f: &str| fs::read_to_string(dir.join(f)).unwrap();
+        assert!(read("env.txt").contains("PI_TEST=it's\n"));
+        assert_eq!(read("binds.txt"), "--ro-bind /usr -> /usr\n");
+        assert!(read("bwrap.txt").ends_with("--chdir /src -- /bin/bash -c 'make install'\n"));
+        assert_eq!(read("output.log"), "make: *** [install] Error 2\n");
+        let step: serde_json::Value = serde_json::from_str(&read("step.json")).unwrap();
+        assert_eq!(step["cwd"], "/src");
+    }
+}
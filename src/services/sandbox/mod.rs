@@ -1,5 +1,7 @@
 pub mod types;
 pub mod builder;
+pub mod diagnostics;
+pub mod recipe;
 
-pub use types::BindType;
+pub use types::{BindType, SandboxBackend};
 pub use builder::Bubblewrap;
@@ -0,0 +1,86 @@
+use crate::services::sandbox::{BindType, Bubblewrap, SandboxBackend};
+use anyhow::Result;
+use std::path::Path;
+use std::time::Duration;
+
+/// How long a `run_command()` of a recipe may run.
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The sandbox of a recipe's `run_command()`: the host's system directories
+/// read-only, an empty `/tmp` as home and working directory, a minimal
+/// environment, and no network unless `network`.
+pub fn command_sandbox(program: &str, args: &[String], network: bool) -> Bubblewrap {
+    let mut b = Bubblewrap::new();
+    b.clear_env();
+    b.add_flag("--unshare-pid");
+    b.add_flag("--unshare-ipc");
+    b.add_flag("--unshare-uts");
+    b.add_flag("--die-with-parent");
+    if !network {
+        b.add_flag("--unshare-net");
+    }
+    for dir in ["/usr", "/bin", "/sbin", "/lib", "/lib64", "/etc"] {
+        if Path::new(dir).exists() {
+            b.add_bind(BindType::RoBind, dir);
+        }
+    }
+    b.add_virtual(BindType::Proc, "/proc");
+    b.add_virtual(BindType::Dev, "/dev");
+    b.add_virtual(BindType::Tmpfs, "/tmp");
+    b.set_env("PATH", "/usr/local/bin:/usr/bin:/bin:/usr/sbin:/sbin");
+    b.set_env("HOME", "/tmp");
+    b.set_env("LANG", "C.UTF-8");
+    b.set_cwd("/tmp");
+    b.set_command(program, args);
+    b
+}
+
+/// Refuses commands of a `restricted` repo (one with a host policy) when
+/// there is no sandbox: they would run on the host with its network.
+fn check_confined(backend: SandboxBackend, restricted: bool, program: &str) -> Result<()> {
+    if restricted && backend == SandboxBackend::None {
+        anyhow::bail!("refusing to run {} for a restricted repo without a sandbox (sandbox = none)", program);
+    }
+    Ok(())
+}
+
+/// Runs `program` with `args` in the `command_sandbox` and returns its
+/// stdout; exiting non-zero fails with the end of its stderr.
+pub fn run_command(program: &str, args: &[String], network: bool, restricted: bool, timeout: Duration) -> Result<Vec<u8>> {
+    check_confined(Bubblewrap::backend(), restricted, program)?;
+    let output = command_sandbox(program, args, network).output(timeout)?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let tail: Vec<&str> = stderr.lines().rev().take(5).collect();
+        let tail: Vec<&str> = tail.into_iter().rev().collect();
+        anyhow::bail!("{} exited with {}: {}", program, output.status, tail.join("\n"));
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_sandbox() {
+        let b = command_sandbox("git", &["ls-remote".to_string()], false);
+        assert!(b.bind_table().contains(&"--ro-bind /usr -> /usr".to_string()));
+        assert!(!b.bind_table().iter().any(|l| l.starts_with("--bind ")), "{:?}", b.bind_table());
+        assert_eq!(b.envs().keys().collect::<Vec<_>>(), vec!["HOME", "LANG", "PATH"]);
+        let args: Vec<String> = b.build_command().get_args().map(|a| a.to_string_lossy().into_owned()).collect();
+        assert!(args.contains(&"--unshare-net".to_string()) && args.contains(&"--clearenv".to_string()));
+        assert!(args.ends_with(&["--".to_string(), "git".to_string(), "ls-remote".to_string()]));
+
+        let b = command_sandbox("git", &[], true);
+        assert!(!b.build_command().get_args().any(|a| a == "--unshare-net"));
+    }
+
+    #[test]
+    fn test_restricted_commands_need_a_sandbox() {
+        assert!(check_confined(SandboxBackend::Bubblewrap, true, "git").is_ok());
+        assert!(check_confined(SandboxBackend::None, false, "git").is_ok());
+        let err = check_confined(SandboxBackend::None, true, "git").unwrap_err();
+        assert!(err.to_string().contains("without a sandbox"), "{}", err);
+    }
+}
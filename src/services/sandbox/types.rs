@@ -1,6 +1,7 @@
 use std::path::PathBuf;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[allow(dead_code)]
 pub enum BindType {
     Bind,
     BindTry,
@@ -31,6 +32,28 @@ impl BindType {
     }
 }
 
+/// What runs sandboxed commands (`sandbox` setting).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SandboxBackend {
+    /// bwrap with the binds, environment and namespaces of the sandbox.
+    #[default]
+    Bubblewrap,
+    /// Directly on the host, for hosts without user namespaces and for tests:
+    /// remapped paths are translated, nothing is isolated.
+    None,
+}
+
+impl std::str::FromStr for SandboxBackend {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "bubblewrap" => Ok(Self::Bubblewrap),
+            "none" => Ok(Self::None),
+            _ => anyhow::bail!("sandbox must be bubblewrap or none"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BindPair {
     pub cave_target: PathBuf,
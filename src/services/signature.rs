@@ -0,0 +1,125 @@
+use anyhow::{Context, Result};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Kind of a `fetch(public_key=)`, told apart by its text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyKind {
+    /// An ASCII-armored OpenPGP public key, checked with gpg.
+    Gpg,
+    /// A minisign public key (`RW...`, optionally after its comment line),
+    /// checked with minisign.
+    Minisign,
+}
+
+impl KeyKind {
+    pub fn detect(public_key: &str) -> Result<Self> {
+        let key = public_key.trim();
+        if key.starts_with("-----BEGIN PGP PUBLIC KEY BLOCK-----") {
+            return Ok(Self::Gpg);
+        }
+        let line = key.lines().last().unwrap_or("").trim();
+        if line.len() == 56 && line.starts_with("RW") && line.chars().all(|c| c.is_ascii_alphanumeric() || "+/=".contains(c)) {
+            return Ok(Self::Minisign);
+        }
+        anyhow::bail!("public_key must be an ASCII-armored OpenPGP key or a minisign public key")
+    }
+}
+
+/// Checks the detached `signature` of `file` against `public_key`. The
+/// error names the key and the file.
+pub fn verify(file: &Path, signature: &Path, public_key: &str) -> Result<()> {
+    let kind = KeyKind::detect(public_key)?;
+    let tmp = tempfile::tempdir().context("Failed to create a temporary directory")?;
+    let (label, result) = match kind {
+        KeyKind::Gpg => verify_gpg(tmp.path(), file, signature, public_key),
+        KeyKind::Minisign => {
+            let key = public_key.trim().lines().last().unwrap_or("").trim();
+            (format!("minisign key {}", key), verify_minisign(file, signature, key))
+        }
+    };
+    result.with_context(|| format!("signature check of {} with {} failed", file.display(), label))?;
+    log::debug!("[signature] {} verified with {}", file.display(), label);
+    Ok(())
+}
+
+/// Imports the key into a keyring of its own, so only it can vouch for the file.
+fn verify_gpg(home: &Path, file: &Path, signature: &Path, public_key: &str) -> (String, Result<()>) {
+    let key_file = home.join("key.asc");
+    let imported = fs::write(&key_file, public_key).context("Failed to write the key")
+        .and_then(|_| gpg(home, [OsStr::new("--import"), key_file.as_os_str()]));
+    if let Err(e) = imported {
+        return ("gpg key".to_string(), Err(e.context("invalid public key")));
+    }
+    let label = gpg(home, ["--with-colons", "--fingerprint"]).ok()
+        .and_then(|listing| listing.lines().find(|l| l.starts_with("fpr:")).and_then(|l| l.split(':').nth(9)).map(str::to_string))
+        .map(|fpr| format!("gpg key {}", fpr))
+        .unwrap_or_else(|| "gpg key".to_string());
+    let verified = gpg(home, [OsStr::new("--status-fd"), OsStr::new("1"), OsStr::new("--verify"), signature.as_os_str(), file.as_os_str()])
+        .and_then(|status| match status.lines().any(|l| l.starts_with("[GNUPG:] VALIDSIG ")) {
+            true => Ok(()),
+            false => anyhow::bail!("no valid signature"),
+        });
+    (label, verified)
+}
+
+fn verify_minisign(file: &Path, signature: &Path, key: &str) -> Result<()> {
+    let output = Command::new("minisign")
+        .args([OsStr::new("-V"), OsStr::new("-q"), OsStr::new("-P"), OsStr::new(key), OsStr::new("-m"), file.as_os_str(), OsStr::new("-x"), signature.as_os_str()])
+        .output()
+        .context("Failed to run minisign, is it installed?")?;
+    if !output.status.success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
+
+/// Runs gpg in batch mode with `home` as its home; returns stdout.
+fn gpg<I, S>(home: &Path, args: I) -> Result<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let output = Command::new("gpg")
+        .arg("--homedir").arg(home)
+        .args(["--batch", "--no-tty", "--quiet"])
+        .args(args)
+        .output()
+        .context("Failed to run gpg, is it installed?")?;
+    if !output.status.success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_gpg() {
+        assert_eq!(KeyKind::detect("untrusted comment: minisign public key\nRWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3").unwrap(), KeyKind::Minisign);
+        assert!(KeyKind::detect("ssh-ed25519 AAAA").is_err());
+        if Command::new("gpg").arg("--version").output().is_err() {
+            return;
+        }
+        let tmp = tempfile::tempdir().unwrap();
+        let signer = tmp.path().join("signer");
+        fs::create_dir(&signer).unwrap();
+        fs::set_permissions(&signer, std::os::unix::fs::PermissionsExt::from_mode(0o700)).unwrap();
+        gpg(&signer, ["--pinentry-mode", "loopback", "--passphrase", "", "--quick-gen-key", "Release <release@example.com>", "ed25519", "sign", "never"]).unwrap();
+        let key = gpg(&signer, ["--armor", "--export"]).unwrap();
+        let file = tmp.path().join("tool.tar.gz");
+        fs::write(&file, "release").unwrap();
+        let sig = tmp.path().join("tool.tar.gz.asc");
+        gpg(&signer, [OsStr::new("--armor"), OsStr::new("--output"), sig.as_os_str(), OsStr::new("--detach-sign"), file.as_os_str()]).unwrap();
+
+        assert_eq!(KeyKind::detect(&key).unwrap(), KeyKind::Gpg);
+        verify(&file, &sig, &key).unwrap();
+        fs::write(&file, "tampered").unwrap();
+        let err = format!("{:#}", verify(&file, &sig, &key).unwrap_err());
+        assert!(err.contains("tool.tar.gz with gpg key "), "{}", err);
+    }
+}
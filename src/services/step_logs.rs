@@ -0,0 +1,113 @@
+use crate::models::config::Config;
+use crate::services::cache::build::split_build_key;
+use crate::utils::fs::sanitize_name;
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Output lines of a failed step printed with its error; the full output
+/// stays in its log.
+pub const FAILURE_LINES: usize = 20;
+
+/// Logs of the steps of one build of a package, by build key (version and
+/// options): `cache_dir/logs/<pkg>/<build key>/`.
+pub fn dir(config: &Config, pkgname: &str, build_key: &str) -> PathBuf {
+    package_dir(&config.cache_dir, pkgname).join(sanitize_name(build_key))
+}
+
+fn package_dir(cache_dir: &Path, pkgname: &str) -> PathBuf {
+    cache_dir.join("logs").join(sanitize_name(pkgname))
+}
+
+pub fn path(config: &Config, pkgname: &str, build_key: &str, index: usize) -> PathBuf {
+    dir(config, pkgname, build_key).join(format!("step-{}.log", index))
+}
+
+/// Creates the log of a step, replacing the one of an earlier build. It
+/// starts with the command; the step's stdout and stderr follow.
+pub fn create(config: &Config, pkgname: &str, build_key: &str, index: usize, command: &str) -> Result<File> {
+    let path = path(config, pkgname, build_key, index);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    let mut file = File::create(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+    writeln!(file, "$ {}", command)?;
+    Ok(file)
+}
+
+/// The step logs of a build, by step index.
+pub fn list(config: &Config, pkgname: &str, build_key: &str) -> Vec<(usize, PathBuf)> {
+    let mut logs: Vec<(usize, PathBuf)> = fs::read_dir(dir(config, pkgname, build_key))
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().to_string_lossy().into_owned();
+            let index = name.strip_prefix("step-")?.strip_suffix(".log")?.parse().ok()?;
+            Some((index, e.path()))
+        })
+        .collect();
+    logs.sort();
+    logs
+}
+
+/// The build keys of `version` (every version when None) that have logs, sorted.
+pub fn build_keys(cache_dir: &Path, pkgname: &str, version: Option<&str>) -> Vec<String> {
+    let mut keys: Vec<String> = fs::read_dir(package_dir(cache_dir, pkgname))
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .filter(|key| version.is_none_or(|v| split_build_key(key).0 == sanitize_name(v)))
+        .collect();
+    keys.sort();
+    keys
+}
+
+/// Removes the logs of the builds of `version`, or of every build of the
+/// package when None, with the package's log directory once it's empty.
+pub fn remove(cache_dir: &Path, pkgname: &str, version: Option<&str>) {
+    let package = package_dir(cache_dir, pkgname);
+    for key in build_keys(cache_dir, pkgname, version) {
+        let _ = fs::remove_dir_all(package.join(key));
+    }
+    let _ = fs::remove_dir(package);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_and_list() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let mut log = create(&config, "go:golang.org/x/tools", "0.20.0", 3, "go install").unwrap();
+        writeln!(log, "building").unwrap();
+        create(&config, "go:golang.org/x/tools", "0.20.0", 10, "true").unwrap();
+        fs::write(dir(&config, "go:golang.org/x/tools", "0.20.0").join("notes.txt"), "").unwrap();
+
+        let logs = list(&config, "go:golang.org/x/tools", "0.20.0");
+        assert_eq!(logs.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![3, 10]);
+        assert!(logs[0].1.ends_with("logs/go_golang.org_x_tools/0.20.0/step-3.log"));
+        assert_eq!(fs::read_to_string(&logs[0].1).unwrap(), "$ go install\nbuilding\n");
+        assert!(list(&config, "go", "1.0").is_empty());
+    }
+
+    #[test]
+    fn test_logs_per_build_key() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        create(&config, "python", "3.12.1", 2, "make").unwrap();
+        create(&config, "python", "3.12.1#a1b2", 2, "make shared").unwrap();
+        create(&config, "python", "3.12.10", 2, "make").unwrap();
+        assert_eq!(fs::read_to_string(path(&config, "python", "3.12.1", 2)).unwrap(), "$ make\n");
+        assert_eq!(build_keys(&config.cache_dir, "python", Some("3.12.1")), vec!["3.12.1", "3.12.1#a1b2"]);
+
+        remove(&config.cache_dir, "python", Some("3.12.1"));
+        assert_eq!(build_keys(&config.cache_dir, "python", None), vec!["3.12.10"]);
+        remove(&config.cache_dir, "python", None);
+        assert!(!config.cache_dir.join("logs").join("python").exists());
+    }
+}
@@ -3,20 +3,101 @@ use log::{error, info};
 use crate::models::config::Config;
 use crate::models::package_entry::{PackageEntry, ManagerEntry, PackageList, RegistryEntry};
 use crate::models::repository::Repository;
+use crate::services::cache::meta::MetaStore;
+use crate::services::events::{self, Event};
 use crate::models::version_entry::{VersionEntry, VersionList};
-use crate::starlark::runtime::{evaluate_file, execute_function, execute_manager_function, ExecutionOptions};
-use std::path::Path;
+use crate::starlark::runtime::{evaluate_file, execute_dir_function, execute_function, execute_manager_function, ExecutionOptions};
+use parking_lot::{Condvar, Mutex};
+use std::path::{Path, PathBuf};
 use std::collections::HashMap;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 use walkdir::WalkDir;
 
+/// Bounds on evaluating the recipes of a repository sync.
+pub struct SyncLimits {
+    /// Recipes evaluated at once, shared by every repository synced with these limits.
+    slots: Arc<Slots>,
+    /// Wall-clock time one recipe may take; a recipe over it is left out of
+    /// the package list and its evaluation abandoned.
+    pub recipe_timeout: Option<Duration>,
+}
+
+impl SyncLimits {
+    pub fn new(jobs: usize, recipe_timeout: Option<Duration>) -> Self {
+        Self { slots: Arc::new(Slots::new(jobs)), recipe_timeout }
+    }
+}
+
+impl Default for SyncLimits {
+    /// One recipe per worker thread (`--jobs`), no timeout.
+    fn default() -> Self {
+        Self::new(rayon::current_num_threads(), None)
+    }
+}
+
+/// How evaluating one recipe of a repository sync went.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecipeSync {
+    pub repo: String,
+    /// Path relative to the repository.
+    pub recipe: String,
+    /// Wall-clock time of its evaluation, not counting the wait for a slot.
+    pub duration: Duration,
+    pub packages: usize,
+    pub managers: usize,
+    /// Why it was left out of the package list, if it was.
+    pub error: Option<String>,
+}
+
+/// A counting semaphore.
+struct Slots {
+    free: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Slots {
+    fn new(count: usize) -> Self {
+        Self { free: Mutex::new(count.max(1)), freed: Condvar::new() }
+    }
+
+    /// A slot, held until the guard drops; owned, so a thread that outlives
+    /// the sync can keep it.
+    fn acquire(self: &Arc<Self>) -> SlotGuard {
+        let mut free = self.free.lock();
+        while *free == 0 {
+            self.freed.wait(&mut free);
+        }
+        *free -= 1;
+        SlotGuard(self.clone())
+    }
+}
+
+struct SlotGuard(Arc<Slots>);
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        *self.0.free.lock() += 1;
+        self.0.freed.notify_one();
+    }
+}
+
 /// Synchronizes a repository by evaluating all `.star` files and saving the package list.
 pub fn sync_repo(config: &Config, repo: &Repository) -> Result<()> {
+    sync_repo_with(config, repo, &SyncLimits::default()).map(|_| ())
+}
+
+/// `sync_repo` within `limits`; returns how each recipe went, the ones that
+/// failed or timed out with an error.
+#[tracing::instrument(skip_all, fields(repo = %repo.name))]
+pub fn sync_repo_with(config: &Config, repo: &Repository, limits: &SyncLimits) -> Result<Vec<RecipeSync>> {
     info!("[{}] syncing repo", repo.name);
+    events::publish(Event::SyncStarted { repo: repo.name.clone() });
     
     // Clear old cache files and in-memory entries for this repo to ensure a clean slate.
     clear_repo_cache(config, &repo.name)?;
 
-    let (packages, managers) = collect_repo_entries(config, repo);
+    let (packages, managers, recipes) = collect_repo_entries(config, repo, limits);
 
     let package_list = PackageList {
         packages,
@@ -32,7 +113,12 @@ pub fn sync_repo(config: &Config, repo: &Repository) -> Result<()> {
         package_list.packages.len(),
         package_list.managers.len()
     );
-    Ok(())
+    events::publish(Event::SyncFinished {
+        repo: repo.name.clone(),
+        packages: package_list.packages.len(),
+        managers: package_list.managers.len(),
+    });
+    Ok(recipes)
 }
 
 fn clear_repo_cache(config: &Config, repo_name: &str) -> Result<()> {
@@ -40,65 +126,113 @@ fn clear_repo_cache(config: &Config, repo_name: &str) -> Result<()> {
     config.state.package_lists.remove(repo_name);
     config.state.version_lists.retain(|k, _| !k.starts_with(&format!("{}:", repo_name)));
 
-    // 2. Clear version cache files from disk
-    let prefix = format!("version-{}-", repo_name);
-    if let Ok(entries) = std::fs::read_dir(&config.cache_meta_dir) {
-        for entry in entries.filter_map(|e| e.ok()) {
-            if let Some(name) = entry.file_name().to_str() {
-                if name.starts_with(&prefix) && name.ends_with(".json") {
-                    let _ = std::fs::remove_file(entry.path());
-                }
-            }
-        }
-    }
+    // 2. Clear cached version lists from disk
+    MetaStore::remove_prefix(config, &Config::version_cache_prefix(repo_name));
     Ok(())
 }
 
+type Entries = HashMap<String, RegistryEntry>;
+
 /// Iterates through the repository, evaluates Starlark files, and collects package/manager entries.
-fn collect_repo_entries(config: &Config, repo: &Repository) -> (HashMap<String, RegistryEntry>, HashMap<String, RegistryEntry>) {
+/// Files are evaluated concurrently, each on its own thread while it holds a slot of `limits`.
+/// Not on a rayon pool: a recipe over `--recipe-timeout` can't be cancelled,
+/// only abandoned, and an abandoned evaluation would keep a pool worker.
+fn collect_repo_entries(config: &Config, repo: &Repository, limits: &SyncLimits) -> (Entries, Entries, Vec<RecipeSync>) {
     let repo_path = Path::new(&repo.path);
-    WalkDir::new(repo_path)
+    let files: Vec<PathBuf> = WalkDir::new(repo_path)
         .into_iter()
         .filter_map(|e| e.ok())
-        .filter(|e| e.path().extension().map_or(false, |ext| ext == "star"))
-        .fold((HashMap::new(), HashMap::new()), |(mut pkgs, mut mgrs), entry| {
-            let star_file_path = entry.path();
-            match evaluate_file(star_file_path, config) {
-                Ok((found_pkgs, found_mgrs)) => {
-                    let rel_path = star_file_path
-                        .strip_prefix(repo_path)
-                        .unwrap_or(star_file_path)
-                        .to_string_lossy()
-                        .to_string();
-
-                    for mut p in found_pkgs {
-                        p.filename = rel_path.clone();
-                        pkgs.insert(p.name.clone(), p);
-                    }
-                    for mut m in found_mgrs {
-                        m.filename = rel_path.clone();
-                        mgrs.insert(m.name.clone(), m);
-                    }
+        .filter(|e| e.path().extension().is_some_and(|ext| ext == "star"))
+        .map(|e| e.into_path())
+        .collect();
+
+    let results = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for (index, star_file_path) in files.iter().enumerate() {
+            let slot = limits.slots.acquire();
+            let results = &results;
+            scope.spawn(move || {
+                let start = Instant::now();
+                let outcome = evaluate_with_timeout(config, star_file_path, limits.recipe_timeout, slot);
+                let duration = start.elapsed();
+                results.lock().push((index, star_file_path, duration, outcome));
+            });
+        }
+    });
+
+    let mut results = results.into_inner();
+    // Later files win on duplicate names, as when evaluated in walk order.
+    results.sort_by_key(|(index, _, _, _)| *index);
+    let (mut pkgs, mut mgrs, mut recipes) = (HashMap::new(), HashMap::new(), Vec::new());
+    for (_, star_file_path, duration, outcome) in results {
+        let rel_path = star_file_path
+            .strip_prefix(repo_path)
+            .unwrap_or(star_file_path)
+            .to_string_lossy()
+            .to_string();
+        let mut recipe = RecipeSync { repo: repo.name.clone(), recipe: rel_path.clone(), duration, packages: 0, managers: 0, error: None };
+        match outcome {
+            Ok((found_pkgs, found_mgrs)) => {
+                recipe.packages = found_pkgs.len();
+                recipe.managers = found_mgrs.len();
+                for mut p in found_pkgs {
+                    p.filename = rel_path.clone();
+                    pkgs.insert(p.name.clone(), p);
                 }
-                Err(e) => {
-                    error!("[{}] eval failed {}: {}", repo.name, star_file_path.display(), e);
+                for mut m in found_mgrs {
+                    m.filename = rel_path.clone();
+                    mgrs.insert(m.name.clone(), m);
                 }
             }
-            (pkgs, mgrs)
+            Err(e) => {
+                error!("[{}] eval failed {}: {:#}", repo.name, star_file_path.display(), e);
+                recipe.error = Some(format!("{:#}", e));
+            }
+        }
+        recipes.push(recipe);
+    }
+    (pkgs, mgrs, recipes)
+}
+
+/// Evaluates a recipe on a thread of its own, so a recipe over `timeout`
+/// can be given up on; its thread then finishes in the background and its
+/// result is dropped. The thread holds `slot` until it finishes, so
+/// abandoned recipes still count against `--jobs`.
+fn evaluate_with_timeout(config: &Config, path: &Path, timeout: Option<Duration>, slot: SlotGuard) -> Result<(Vec<PackageEntry>, Vec<ManagerEntry>)> {
+    let (tx, rx) = mpsc::channel();
+    let (config, owned_path) = (config.clone(), path.to_path_buf());
+    std::thread::Builder::new()
+        .name("pi-recipe".to_string())
+        .spawn(move || {
+            let _slot = slot;
+            let _ = tx.send(evaluate_file(&owned_path, &config));
         })
+        .context("Failed to start recipe evaluation")?;
+    match timeout {
+        Some(timeout) => match rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => anyhow::bail!("timed out after {:?}", timeout),
+            Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("evaluation panicked"),
+        },
+        None => rx.recv().unwrap_or_else(|_| Err(anyhow::anyhow!("evaluation panicked"))),
+    }
 }
 
 /// Synchronizes a single package by executing its Starlark function and caching the versions.
+#[tracing::instrument(skip_all, fields(repo = %repo.name, package = %pkg.name))]
 pub fn sync_package(config: &Config, repo: &Repository, pkg: &PackageEntry) -> Result<()> {
     info!("{}/{} syncing pkg", repo.name, pkg.name);
 
     let star_path = Path::new(&repo.path).join(&pkg.filename);
+    let known = load_known(config, &repo.name, &pkg.name);
     let versions = execute_function(
         ExecutionOptions {
             path: &star_path,
             function_name: &pkg.function_name,
             config,
             options: None,
+            known: known.as_ref(),
+            cave: None,
         },
         &pkg.name,
     ).with_context(|| format!(
@@ -110,6 +244,7 @@ pub fn sync_package(config: &Config, repo: &Repository, pkg: &PackageEntry) -> R
 }
 
 /// Synchronizes a package managed by a manager (e.g., go:pkg) by executing its manager function.
+#[tracing::instrument(skip_all, fields(repo = %repo.name, manager = manager_name, package = package_name))]
 pub fn sync_manager_package(
     config: &Config,
     repo: &Repository,
@@ -121,23 +256,36 @@ pub fn sync_manager_package(
     info!("{}/{} syncing mgr pkg", repo.name, full_name);
 
     let star_path = Path::new(&repo.path).join(&mgr.filename);
-    let versions = execute_manager_function(
-        ExecutionOptions {
-            path: &star_path,
-            function_name: &mgr.function_name,
-            config,
-            options: None,
-        },
-        manager_name,
-        package_name,
-    ).with_context(|| format!(
+    let known = load_known(config, &repo.name, &full_name);
+    let exec_opts = ExecutionOptions {
+        path: &star_path,
+        function_name: &mgr.function_name,
+        config,
+        options: None,
+        known: known.as_ref(),
+        cave: None,
+    };
+    let versions = match &mgr.dir {
+        Some(source) => execute_dir_function(exec_opts, source, package_name),
+        None => execute_manager_function(exec_opts, manager_name, package_name),
+    }.with_context(|| format!(
         "Failed to execute manager function '{}' in '{}' for package {}/{}", 
         mgr.function_name, star_path.display(), repo.name, full_name
     ))?;
 
+    crate::services::manager_cache::record(config, &format!("{}:{}", repo.name, full_name), !versions.is_empty());
     save_versions(config, &repo.name, &full_name, versions)
 }
 
+/// Loads the saved version list so discover functions can sync incrementally.
+/// A forced sync starts from scratch.
+fn load_known(config: &Config, repo_name: &str, name: &str) -> Option<VersionList> {
+    if config.force {
+        return None;
+    }
+    VersionList::load(config, repo_name, name).ok()
+}
+
 /// Internal helper to save a list of versions to the cache.
 fn save_versions(config: &Config, repo_name: &str, name: &str, versions: Vec<VersionEntry>) -> Result<()> {
     if versions.is_empty() {
@@ -158,3 +306,48 @@ fn save_versions(config: &Config, repo_name: &str, name: &str, versions: Vec<Ver
     );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_sync_repo_skips_failed_and_slow_recipes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().join("pi"));
+        let repo_dir = tmp.path().join("recipes");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(repo_dir.join("tool.star"), "def install(_p):\n    return None\nadd_package(\"tool\", install)\n").unwrap();
+        fs::write(repo_dir.join("broken.star"), "add_package(\n").unwrap();
+        fs::write(repo_dir.join("slow.star"), "def spin():\n    n = 0\n    for i in range(500000):\n        n += i\n    return n\nspin()\n").unwrap();
+        let repo = Repository::new(repo_dir.display().to_string(), "local".to_string());
+
+        let limits = SyncLimits::new(1, Some(Duration::from_millis(500)));
+        let mut recipes = sync_repo_with(&config, &repo, &limits).unwrap();
+        recipes.sort_by(|a, b| a.recipe.cmp(&b.recipe));
+        assert_eq!(recipes.iter().map(|s| s.recipe.as_str()).collect::<Vec<_>>(), vec!["broken.star", "slow.star", "tool.star"]);
+        assert!(recipes[0].error.is_some());
+        assert_eq!(recipes[1].error.as_deref(), Some("timed out after 500ms"));
+        assert!(recipes[1].duration >= Duration::from_millis(500));
+        assert_eq!((recipes[2].packages, recipes[2].error.as_ref()), (1, None));
+        let list = PackageList::load(&config, "local").unwrap();
+        assert!(list.packages.contains_key("tool"));
+    }
+
+    #[test]
+    fn test_timed_out_recipe_keeps_its_slot() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().join("pi"));
+        let slow = tmp.path().join("slow.star");
+        fs::write(&slow, "def spin():\n    n = 0\n    for i in range(200000):\n        n += i\n    return n\nspin()\n").unwrap();
+        let limits = SyncLimits::new(1, Some(Duration::from_millis(10)));
+
+        let err = evaluate_with_timeout(&config, &slow, limits.recipe_timeout, limits.slots.acquire()).unwrap_err();
+        assert_eq!(err.to_string(), "timed out after 10ms");
+        assert_eq!(*limits.slots.free.lock(), 0);
+        // The next recipe waits for the abandoned one to finish.
+        drop(limits.slots.acquire());
+        assert_eq!(*limits.slots.free.lock(), 1);
+    }
+}
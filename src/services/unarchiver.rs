@@ -1,40 +1,289 @@
 use anyhow::{Context, Result};
 use std::fs::{self, File};
-use std::path::Path;
+use std::io::{Cursor, Read, Seek};
+use std::path::{Component, Path};
+use std::str::FromStr;
 use flate2::read::GzDecoder;
 use xz2::read::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
 use tar::Archive;
 use zip::ZipArchive;
 
+/// The archive formats `extract()` understands.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarXz,
+    TarZst,
+    Zip,
+    SevenZ,
+}
+
+impl ArchiveFormat {
+    /// Names accepted as `extract(format=...)`, canonical one first.
+    const NAMES: &[(&[&str], ArchiveFormat)] = &[
+        (&["tar"], Self::Tar),
+        (&["tar.gz", "tgz"], Self::TarGz),
+        (&["tar.xz", "txz"], Self::TarXz),
+        (&["tar.zst", "tzst"], Self::TarZst),
+        (&["zip"], Self::Zip),
+        (&["7z"], Self::SevenZ),
+    ];
+
+    pub fn name(self) -> &'static str {
+        Self::NAMES.iter().find(|(_, f)| *f == self).map(|(names, _)| names[0]).unwrap_or("?")
+    }
+
+    /// The format a file name's extension names, if any.
+    pub fn from_filename(filename: &str) -> Option<Self> {
+        Self::NAMES
+            .iter()
+            .flat_map(|(names, f)| names.iter().map(move |n| (n, *f)))
+            .filter(|(n, _)| filename.ends_with(&format!(".{}", n)))
+            .max_by_key(|(n, _)| n.len())
+            .map(|(_, f)| f)
+    }
+
+    /// The format of an archive by its first bytes. A compressed stream is
+    /// taken to be a tarball, as that is all pi extracts from one.
+    pub fn from_magic(header: &[u8]) -> Option<Self> {
+        if header.starts_with(&[0x1f, 0x8b]) {
+            Some(Self::TarGz)
+        } else if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Self::TarXz)
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Self::TarZst)
+        } else if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+            Some(Self::Zip)
+        } else if header.starts_with(&[b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c]) {
+            Some(Self::SevenZ)
+        } else if header.get(257..262) == Some(b"ustar") {
+            Some(Self::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim_start_matches('.');
+        Self::NAMES
+            .iter()
+            .find(|(names, _)| names.contains(&s))
+            .map(|(_, f)| *f)
+            .with_context(|| {
+                let known: Vec<&str> = Self::NAMES.iter().map(|(names, _)| names[0]).collect();
+                format!("unknown archive format '{}', expected one of: {}", s, known.join(", "))
+            })
+    }
+}
+
 pub struct Unarchiver;
 
 impl Unarchiver {
     pub fn unarchive(src: &Path, dest: &Path) -> Result<()> {
+        Self::unarchive_as(src, dest, None)
+    }
+
+    /// Extracts `src` into `dest` as `format`, or else as the format its
+    /// extension names, or else as the one its first bytes show.
+    #[tracing::instrument(skip_all, fields(src = %src.display()))]
+    pub fn unarchive_as(src: &Path, dest: &Path, format: Option<ArchiveFormat>) -> Result<()> {
         fs::create_dir_all(dest).context("Failed to create destination directory")?;
 
         let filename = src.file_name()
             .and_then(|n| n.to_str())
             .unwrap_or("");
+        let format = match format.or_else(|| ArchiveFormat::from_filename(filename)) {
+            Some(format) => format,
+            None => Self::detect(src)?
+                .with_context(|| format!("Unsupported archive format: {}", filename))?,
+        };
 
-        if filename.ends_with(".tar.gz") || filename.ends_with(".tgz") {
-            let file = File::open(src)?;
-            let tar = GzDecoder::new(file);
-            let mut archive = Archive::new(tar);
-            archive.unpack(dest).context("Failed to unpack tar.gz")?;
-        } else if filename.ends_with(".tar.xz") {
-            let file = File::open(src)?;
-            let tar = XzDecoder::new(file);
-            let mut archive = Archive::new(tar);
-            archive.unpack(dest).context("Failed to unpack tar.xz")?;
-        } else if filename.ends_with(".zip") {
-            let file = File::open(src)?;
-            let mut archive = ZipArchive::new(file).context("Failed to open zip archive")?;
-            archive.extract(dest).context("Failed to extract zip archive")?;
-        } else {
-            return Err(anyhow::anyhow!("Unsupported archive format: {}", filename));
+        let file = File::open(src).with_context(|| format!("Failed to open {}", src.display()))?;
+        match format {
+            ArchiveFormat::Zip => {
+                let mut archive = ZipArchive::new(file).context("Failed to open zip archive")?;
+                archive.extract(dest).context("Failed to extract zip archive")
+            }
+            ArchiveFormat::SevenZ => Self::extract_7z(file, dest).context("Failed to extract 7z archive"),
+            _ => Self::unpack_tar(file, format, dest),
+        }
+        .with_context(|| format!("Failed to unpack {}", format.name()))?;
+
+        log::debug!("[{}] unarchived ({}) to {}", filename, format.name(), dest.display());
+        Ok(())
+    }
+
+    /// Extracts an archive read from `reader` (a download as it arrives)
+    /// into `dest`, its format found like `unarchive_as` does with `name`
+    /// for the file name. Tarballs are unpacked as they stream; zip and 7z
+    /// archives need to seek, so they are spooled to a temporary file next
+    /// to `dest` and returned unextracted: the caller unpacks them with
+    /// `Spooled::unarchive` once the download is verified.
+    pub fn unarchive_stream(reader: &mut dyn Read, name: &str, dest: &Path, format: Option<ArchiveFormat>) -> Result<Option<Spooled>> {
+        fs::create_dir_all(dest).context("Failed to create destination directory")?;
+        let mut header = Vec::with_capacity(262);
+        reader.take(262).read_to_end(&mut header)?;
+        let format = format.or_else(|| ArchiveFormat::from_filename(name)).or_else(|| ArchiveFormat::from_magic(&header))
+            .with_context(|| format!("Unsupported archive format: {}", name))?;
+        let mut reader = Cursor::new(header).chain(reader);
+
+        if matches!(format, ArchiveFormat::Zip | ArchiveFormat::SevenZ) {
+            log::debug!("[{}] {} can't be unpacked as it streams, spooling it", name, format.name());
+            let parent = dest.parent().context("Destination has no parent directory")?;
+            let mut file = tempfile::NamedTempFile::new_in(parent).context("Failed to create spool file")?;
+            std::io::copy(&mut reader, file.as_file_mut())?;
+            return Ok(Some(Spooled { file, format }));
         }
+        Self::unpack_tar(reader, format, dest).with_context(|| format!("Failed to unpack {}", format.name()))?;
+        log::debug!("[{}] unarchived ({}, streamed) to {}", name, format.name(), dest.display());
+        Ok(None)
+    }
 
-        log::debug!("[{}] unarchived to {}", filename, dest.display());
+    fn unpack_tar(reader: impl Read, format: ArchiveFormat, dest: &Path) -> Result<()> {
+        match format {
+            ArchiveFormat::Tar => Archive::new(reader).unpack(dest)?,
+            ArchiveFormat::TarGz => Archive::new(GzDecoder::new(reader)).unpack(dest)?,
+            ArchiveFormat::TarXz => Archive::new(XzDecoder::new(reader)).unpack(dest)?,
+            ArchiveFormat::TarZst => Archive::new(ZstdDecoder::new(reader)?).unpack(dest)?,
+            ArchiveFormat::Zip | ArchiveFormat::SevenZ => anyhow::bail!("{} is not a tarball", format.name()),
+        }
         Ok(())
     }
+
+    /// Extracts a 7z archive like `decompress` would, refusing entries that
+    /// would land outside `dest` (`..`, absolute paths), as zip extraction does.
+    fn extract_7z(file: File, dest: &Path) -> Result<()> {
+        sevenz_rust::decompress_with_extract_fn(file, dest, |entry, reader, path| {
+            if !Path::new(entry.name()).components().all(|c| matches!(c, Component::Normal(_))) {
+                return Err(sevenz_rust::Error::other(format!("entry {} escapes the destination", entry.name())));
+            }
+            sevenz_rust::default_entry_extract_fn(entry, reader, path)
+        })
+        .map_err(|e| anyhow::anyhow!("{}", e))
+    }
+
+    fn detect(src: &Path) -> Result<Option<ArchiveFormat>> {
+        let mut file = File::open(src).with_context(|| format!("Failed to open {}", src.display()))?;
+        let mut header = Vec::with_capacity(262);
+        file.by_ref().take(262).read_to_end(&mut header)?;
+        file.rewind()?;
+        Ok(ArchiveFormat::from_magic(&header))
+    }
+}
+
+/// A zip or 7z download `unarchive_stream` spooled; removed when dropped.
+#[must_use = "a spooled archive is only extracted by `unarchive`"]
+pub struct Spooled {
+    file: tempfile::NamedTempFile,
+    format: ArchiveFormat,
+}
+
+impl Spooled {
+    pub fn unarchive(self, dest: &Path) -> Result<()> {
+        Unarchiver::unarchive_as(self.file.path(), dest, Some(self.format))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tarball() -> Vec<u8> {
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(2);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, "pkg/hi.txt", &b"hi"[..]).unwrap();
+        tar.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_detect_and_extract() {
+        assert_eq!(ArchiveFormat::from_filename("tool-1.0.tar.zst"), Some(ArchiveFormat::TarZst));
+        assert_eq!(ArchiveFormat::from_filename("tool-1.0.tgz"), Some(ArchiveFormat::TarGz));
+        assert_eq!(ArchiveFormat::from_filename("tool-1.0.tar"), Some(ArchiveFormat::Tar));
+        assert_eq!(ArchiveFormat::from_filename("tool-1.0.bin"), None);
+        assert_eq!("tzst".parse::<ArchiveFormat>().unwrap(), ArchiveFormat::TarZst);
+        assert!("rar".parse::<ArchiveFormat>().unwrap_err().to_string().contains("7z"));
+
+        let tmp = tempfile::tempdir().unwrap();
+        // No telling extension: found by magic bytes.
+        let zst = tmp.path().join("download");
+        fs::write(&zst, zstd::encode_all(&tarball()[..], 0).unwrap()).unwrap();
+        Unarchiver::unarchive(&zst, &tmp.path().join("a")).unwrap();
+        assert_eq!(fs::read_to_string(tmp.path().join("a/pkg/hi.txt")).unwrap(), "hi");
+
+        let src = tmp.path().join("src");
+        fs::create_dir_all(src.join("pkg")).unwrap();
+        fs::write(src.join("pkg/hi.txt"), "hi").unwrap();
+        let sevenz = tmp.path().join("tool.7z");
+        sevenz_rust::compress_to_path(&src, &sevenz).unwrap();
+        Unarchiver::unarchive(&sevenz, &tmp.path().join("b")).unwrap();
+        assert_eq!(fs::read_to_string(tmp.path().join("b/pkg/hi.txt")).unwrap(), "hi");
+
+        // An explicit format wins over a misleading extension.
+        let tar = tmp.path().join("tool.zip");
+        fs::write(&tar, tarball()).unwrap();
+        assert!(Unarchiver::unarchive(&tar, &tmp.path().join("c")).is_err());
+        Unarchiver::unarchive_as(&tar, &tmp.path().join("d"), Some(ArchiveFormat::Tar)).unwrap();
+        assert!(tmp.path().join("d/pkg/hi.txt").is_file());
+
+        // Streamed: a tarball by its magic bytes, a 7z spooled first.
+        let gz = {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            std::io::Write::write_all(&mut encoder, &tarball()).unwrap();
+            encoder.finish().unwrap()
+        };
+        assert!(Unarchiver::unarchive_stream(&mut &gz[..], "download", &tmp.path().join("e"), None).unwrap().is_none());
+        assert_eq!(fs::read_to_string(tmp.path().join("e/pkg/hi.txt")).unwrap(), "hi");
+        let mut file = File::open(&sevenz).unwrap();
+        let spooled = Unarchiver::unarchive_stream(&mut file, "tool.7z", &tmp.path().join("f"), None).unwrap().unwrap();
+        spooled.unarchive(&tmp.path().join("f")).unwrap();
+        assert_eq!(fs::read_to_string(tmp.path().join("f/pkg/hi.txt")).unwrap(), "hi");
+        assert!(!fs::read_dir(tmp.path()).unwrap().any(|e| e.unwrap().file_name().to_string_lossy().starts_with(".tmp")), "spool file left");
+    }
+
+    #[test]
+    fn test_7z_traversal_is_refused() {
+        let tmp = tempfile::tempdir().unwrap();
+        let payload = tmp.path().join("payload");
+        fs::write(&payload, "owned").unwrap();
+        for (name, escaped) in [("../evil.txt", tmp.path().join("evil.txt")), ("/tmp/pi-7z-evil.txt", "/tmp/pi-7z-evil.txt".into())] {
+            let archive = tmp.path().join("evil.7z");
+            let mut writer = sevenz_rust::SevenZWriter::create(&archive).unwrap();
+            writer.push_archive_entry(sevenz_rust::SevenZArchiveEntry::from_path(&payload, name.to_string()), Some(File::open(&payload).unwrap())).unwrap();
+            writer.finish().unwrap();
+
+            let err = Unarchiver::unarchive(&archive, &tmp.path().join("out")).unwrap_err();
+            assert!(format!("{:#}", err).contains("escapes the destination"), "{:#}", err);
+            assert!(!escaped.exists(), "{} was written", escaped.display());
+        }
+    }
+
+    #[test]
+    fn test_spooled_archives_wait_for_unarchive() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("pkg/hi.txt", zip::write::SimpleFileOptions::default()).unwrap();
+        std::io::Write::write_all(&mut zip, b"hi").unwrap();
+        let zip = zip.finish().unwrap().into_inner();
+
+        let dest = tmp.path().join("out");
+        let spooled = Unarchiver::unarchive_stream(&mut &zip[..], "tool.zip", &dest, None).unwrap().unwrap();
+        assert!(!dest.join("pkg").exists(), "extracted before the download was verified");
+        // A download failing its checksum drops the spool unextracted.
+        drop(spooled);
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 1, "spool file left");
+
+        let spooled = Unarchiver::unarchive_stream(&mut &zip[..], "tool.zip", &dest, None).unwrap().unwrap();
+        spooled.unarchive(&dest).unwrap();
+        assert_eq!(fs::read_to_string(dest.join("pkg/hi.txt")).unwrap(), "hi");
+    }
 }
@@ -0,0 +1,191 @@
+use crate::models::package_entry::VENV_MANAGER;
+use crate::models::version_entry::{Export, InstallStep, Interpreter, VersionEntry};
+use crate::utils::inspect::inspect_version;
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// Repository name of packages pi knows without a recipe, like `venv:`.
+pub const BUILTIN_REPO: &str = "builtin";
+
+/// Version of every venv package. Its steps carry the requirements, so a
+/// changed list builds a new venv; unpinned requirements are resolved when
+/// the venv is first built (`--rebuild` updates them).
+const VENV_VERSION: &str = "1";
+
+/// Links the console scripts of the requested distributions into `bin/`.
+const PYTHON_ENTRY_POINTS: &str = r#"import importlib.metadata as m, os, sys
+os.makedirs("bin", exist_ok=True)
+for name in sys.argv[1:]:
+    for ep in m.distribution(name).entry_points:
+        if ep.group in ("console_scripts", "gui_scripts"):
+            dest = os.path.join("bin", ep.name)
+            if os.path.lexists(dest):
+                os.remove(dest)
+            os.symlink(os.path.join("..", "venv", "bin", ep.name), dest)
+"#;
+
+/// Links the `bin` entries of the requested packages' package.json into `bin/`.
+const NODE_ENTRY_POINTS: &str = r#"const fs = require("fs"), path = require("path");
+fs.mkdirSync("bin", { recursive: true });
+for (const name of process.argv.slice(1)) {
+  const meta = JSON.parse(fs.readFileSync(path.join("node_modules", name, "package.json")));
+  const bins = typeof meta.bin === "string" ? { [name.split("/").pop()]: meta.bin } : meta.bin || {};
+  for (const [cmd, rel] of Object.entries(bins)) {
+    const dest = path.join("bin", cmd);
+    fs.rmSync(dest, { force: true });
+    fs.symlinkSync(path.join("..", "node_modules", name, rel), dest);
+  }
+}
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VenvKind {
+    /// A virtualenv populated by pip.
+    Python,
+    /// A node_modules populated by npm.
+    Node,
+}
+
+/// A package of a venv, optionally pinned: `black@24.1.0`, `@types/node@20`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Requirement {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl Requirement {
+    fn parse(s: &str) -> Result<Self> {
+        if s.is_empty() || !s.chars().all(|c| c.is_ascii_alphanumeric() || "-_.@/[]".contains(c)) {
+            anyhow::bail!("invalid venv package '{}'", s);
+        }
+        // A leading `@` is a node scope, not a version.
+        match s[1..].rfind('@').map(|i| i + 1) {
+            Some(i) if i + 1 < s.len() => Ok(Self { name: s[..i].to_string(), version: Some(s[i + 1..].to_string()) }),
+            Some(_) => anyhow::bail!("invalid venv package '{}'", s),
+            None => Ok(Self { name: s.to_string(), version: None }),
+        }
+    }
+
+    /// The distribution name, without python extras (`black[d]`).
+    fn dist_name(&self) -> &str {
+        self.name.split('[').next().unwrap_or(&self.name)
+    }
+
+    /// The argument pip or npm installs it with.
+    fn install_arg(&self, kind: VenvKind) -> String {
+        match (&self.version, kind) {
+            (None, _) => self.name.clone(),
+            (Some(v), VenvKind::Python) => format!("{}=={}", self.name, v),
+            (Some(v), VenvKind::Node) => format!("{}@{}", self.name, v),
+        }
+    }
+}
+
+/// The package list of a `venv:<kind>/<package>[,<package>...]` selector.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VenvSpec {
+    pub kind: VenvKind,
+    pub requirements: Vec<Requirement>,
+}
+
+impl VenvSpec {
+    /// Parses what follows `venv:`, e.g. `python/black,ruff@0.4.4`.
+    pub fn parse(package: &str) -> Result<Self> {
+        let (kind, list) = package.split_once('/')
+            .ok_or_else(|| anyhow::anyhow!("venv package '{}' must be <python|node>/<packages>", package))?;
+        let kind = match kind {
+            "python" => VenvKind::Python,
+            "node" => VenvKind::Node,
+            _ => anyhow::bail!("unknown venv kind '{}', expected python or node", kind),
+        };
+        let requirements = list.split(',').map(|r| Requirement::parse(r.trim())).collect::<Result<Vec<_>>>()?;
+        Ok(Self { kind, requirements })
+    }
+
+    /// The venv as a package: one step installing the requirements into the
+    /// package directory and one linking their entry points into its `bin/`,
+    /// which is exported. `python` (Python) or `node` and `npm` (Node)
+    /// options name the interpreter; by default the one on the build PATH,
+    /// where the cave's pilocal comes first.
+    pub fn version_entry(&self, package: &str, options: &HashMap<String, String>) -> VersionEntry {
+        // Options are cave input spliced into a shell command, so quote them like the requirements.
+        let option = |key: &str, default: &str| shell_quote(options.get(key).map(String::as_str).unwrap_or(default));
+        let args = |f: &dyn Fn(&Requirement) -> String| {
+            self.requirements.iter().map(|r| shell_quote(&f(r))).collect::<Vec<_>>().join(" ")
+        };
+        let install = args(&|r| r.install_arg(self.kind));
+        let names = args(&|r| r.dist_name().to_string());
+        let (install, link) = match self.kind {
+            VenvKind::Python => (
+                format!(
+                    "{python} -m venv --clear venv && venv/bin/python -m pip install --disable-pip-version-check --no-input {install}",
+                    python = option("python", "python3"),
+                ),
+                format!("venv/bin/python -c {} {}", shell_quote(PYTHON_ENTRY_POINTS), names),
+            ),
+            VenvKind::Node => (
+                format!("{npm} install --no-fund --no-audit --prefix . {install}", npm = option("npm", "npm")),
+                format!("{} -e {} {}", option("node", "node"), shell_quote(NODE_ENTRY_POINTS), names),
+            ),
+        };
+        let run = |name: &str, command: String| InstallStep::Run {
+            name: Some(name.to_string()),
+            command,
+            cwd: None,
+            shell: Interpreter::default(),
+        };
+        VersionEntry {
+            pkgname: format!("{}:{}", VENV_MANAGER, package),
+            version: inspect_version(VENV_VERSION).version,
+            pipeline: vec![run("install", install), run("entry-points", link)],
+            exports: vec![Export::Link { src: "bin/*".to_string(), dest: "bin".to_string(), chmod: None }],
+            ..Default::default()
+        }
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_venv_spec() {
+        let spec = VenvSpec::parse("node/typescript,@types/node@20, eslint@8.57.0").unwrap();
+        assert_eq!(spec.kind, VenvKind::Node);
+        assert_eq!(spec.requirements[1], Requirement { name: "@types/node".to_string(), version: Some("20".to_string()) });
+        assert_eq!(spec.requirements[2].install_arg(VenvKind::Node), "eslint@8.57.0");
+        assert!(VenvSpec::parse("ruby/rails").is_err());
+        assert!(VenvSpec::parse("python/black;rm").is_err());
+        assert!(VenvSpec::parse("python/black@").is_err());
+
+        let spec = VenvSpec::parse("python/black[d]@24.1.0,ruff").unwrap();
+        let options = HashMap::from([("python".to_string(), "python3.12".to_string())]);
+        let entry = spec.version_entry("python/black[d]@24.1.0,ruff", &options);
+        assert_eq!(entry.pkgname, "venv:python/black[d]@24.1.0,ruff");
+        let InstallStep::Run { command, .. } = &entry.pipeline[0] else { panic!() };
+        assert!(command.starts_with("'python3.12' -m venv --clear venv && "), "{}", command);
+        assert!(command.ends_with("install --disable-pip-version-check --no-input 'black[d]==24.1.0' 'ruff'"), "{}", command);
+        let InstallStep::Run { command, .. } = &entry.pipeline[1] else { panic!() };
+        assert!(command.ends_with(" 'black' 'ruff'"), "{}", command);
+        let InstallStep::Run { command, .. } = &spec.version_entry("python/ruff", &HashMap::new()).pipeline[0] else { panic!() };
+        assert!(command.starts_with("'python3' -m venv "), "{}", command);
+    }
+
+    #[test]
+    fn test_options_are_quoted() {
+        let spec = VenvSpec::parse("node/typescript").unwrap();
+        let options = HashMap::from([
+            ("npm".to_string(), "npm; touch /tmp/pwned".to_string()),
+            ("node".to_string(), "it's node".to_string()),
+        ]);
+        let entry = spec.version_entry("node/typescript", &options);
+        let InstallStep::Run { command, .. } = &entry.pipeline[0] else { panic!() };
+        assert!(command.starts_with("'npm; touch /tmp/pwned' install "), "{}", command);
+        let InstallStep::Run { command, .. } = &entry.pipeline[1] else { panic!() };
+        assert!(command.starts_with(r"'it'\''s node' -e "), "{}", command);
+    }
+}
@@ -0,0 +1,127 @@
+use starlark::any::ProvidesStaticType;
+use starlark::environment::Methods;
+use starlark::environment::MethodsBuilder;
+use starlark::environment::MethodsStatic;
+use starlark::values::{
+    starlark_value, AllocValue, Heap, StarlarkValue, Value, ValueLike,
+};
+use std::fmt::{self, Display};
+use std::io::Read;
+use std::sync::Arc;
+use allocative::{Allocative, Visitor};
+use serde::Serialize;
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+
+/// Opaque binary content returned by `download_binary()`.
+#[derive(Debug, ProvidesStaticType, Clone)]
+pub struct Bytes {
+    pub data: Arc<Vec<u8>>,
+}
+
+impl Bytes {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self { data: Arc::new(data) }
+    }
+}
+
+impl Serialize for Bytes {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&format!("Bytes({})", self.data.len()))
+    }
+}
+
+impl Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Bytes({} bytes)", self.data.len())
+    }
+}
+
+impl Allocative for Bytes {
+    fn visit<'a, 'b: 'a>(&self, visitor: &'a mut Visitor<'b>) {
+        let mut visitor = visitor.enter_self_sized::<Self>();
+        visitor.visit_simple(allocative::Key::new("data"), self.data.len());
+        visitor.exit();
+    }
+}
+
+#[starlark_value(type = "Bytes")]
+impl<'v> StarlarkValue<'v> for Bytes {
+    fn get_methods() -> Option<&'static Methods> {
+        static RES: MethodsStatic = MethodsStatic::new();
+        RES.methods(bytes_methods)
+    }
+
+    fn length(&self) -> starlark::Result<i32> {
+        i32::try_from(self.data.len()).map_err(|e| starlark::Error::new_other(anyhow::anyhow!(e)))
+    }
+}
+
+impl<'v> AllocValue<'v> for Bytes {
+    fn alloc_value(self, heap: &'v Heap) -> Value<'v> {
+        heap.alloc_simple(self)
+    }
+}
+
+/// Decodes `data` as text. Supports utf-8 (strict), latin-1 and ascii.
+fn decode(data: &[u8], encoding: &str) -> anyhow::Result<String> {
+    match encoding.to_ascii_lowercase().replace('_', "-").as_str() {
+        "utf-8" | "utf8" => String::from_utf8(data.to_vec()).context("content is not valid utf-8"),
+        "latin-1" | "latin1" | "iso-8859-1" => Ok(data.iter().map(|&b| b as char).collect()),
+        "ascii" => {
+            if let Some(pos) = data.iter().position(|b| !b.is_ascii()) {
+                anyhow::bail!("content is not ascii (byte {} at offset {})", data[pos], pos);
+            }
+            Ok(data.iter().map(|&b| b as char).collect())
+        }
+        other => anyhow::bail!("unsupported encoding: {}", other),
+    }
+}
+
+#[starlark::starlark_module]
+fn bytes_methods(builder: &mut MethodsBuilder) {
+    /// Decodes the content as text (`utf-8` by default, or `latin-1`, `ascii`).
+    fn decode(this: Value, #[starlark(default = String::from("utf-8"))] encoding: String) -> anyhow::Result<String> {
+        let this = this.downcast_ref::<Bytes>().context("not Bytes")?;
+        decode(&this.data, &encoding)
+    }
+
+    fn len(this: Value) -> anyhow::Result<i32> {
+        let this = this.downcast_ref::<Bytes>().context("not Bytes")?;
+        Ok(i32::try_from(this.data.len())?)
+    }
+
+    /// Hex sha256 digest of the content.
+    fn sha256(this: Value) -> anyhow::Result<String> {
+        let this = this.downcast_ref::<Bytes>().context("not Bytes")?;
+        Ok(hex::encode(Sha256::digest(this.data.as_slice())))
+    }
+
+    /// Decompresses gzip content.
+    fn gunzip<'v>(this: Value<'v>, heap: &'v Heap) -> anyhow::Result<Value<'v>> {
+        let this = this.downcast_ref::<Bytes>().context("not Bytes")?;
+        let mut out = Vec::new();
+        flate2::read::GzDecoder::new(this.data.as_slice())
+            .read_to_end(&mut out)
+            .context("content is not valid gzip")?;
+        Ok(heap.alloc(Bytes::new(out)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let data = [b'p', b'i', 0xe9];
+        assert!(decode(&data, "utf-8").is_err());
+        assert_eq!(decode(&data, "latin-1").unwrap(), "pié");
+        assert!(decode(&data, "ascii").is_err());
+        assert_eq!(decode(b"pi", "UTF_8").unwrap(), "pi");
+        assert!(decode(b"pi", "ebcdic").is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use regex::Regex;
+use starlark::environment::GlobalsBuilder;
+use starlark::eval::Evaluator;
+use starlark::starlark_module;
+use starlark::values::{Value, none::NoneType};
+use std::collections::HashSet;
+use std::sync::{Mutex, OnceLock};
+
+/// Oldest recipe API still evaluated; assumed for files without `pi_api_version()`.
+pub const OLDEST_API_VERSION: u32 = 1;
+/// API version new recipes should declare.
+pub const CURRENT_API_VERSION: u32 = 2;
+
+static DECLARATION_REGEX: OnceLock<Regex> = OnceLock::new();
+static WARNED_FILES: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+/// Finds the `pi_api_version(N)` declaration of a recipe, if any.
+/// The declaration must be a top-level statement so it can be read before evaluation.
+pub fn detect_api_version(content: &str) -> Option<u32> {
+    let re = DECLARATION_REGEX.get_or_init(|| {
+        Regex::new(r"(?m)^pi_api_version\(\s*(\d+)\s*\)").unwrap()
+    });
+    re.captures(content).and_then(|c| c[1].parse().ok())
+}
+
+/// Resolves the API version a recipe is evaluated with.
+/// Unversioned files fall back to the oldest API with a one-time deprecation warning.
+pub fn resolve_api_version(filename: &str, content: &str) -> anyhow::Result<u32> {
+    match detect_api_version(content) {
+        Some(v) if (OLDEST_API_VERSION..=CURRENT_API_VERSION).contains(&v) => Ok(v),
+        Some(v) => anyhow::bail!(
+            "[{}] unsupported pi_api_version({}), supported: {}..={}",
+            filename, v, OLDEST_API_VERSION, CURRENT_API_VERSION
+        ),
+        None => {
+            let file = filename.split(':').next().unwrap_or(filename).to_string();
+            let warned = WARNED_FILES.get_or_init(|| Mutex::new(HashSet::new()));
+            if warned.lock().unwrap().insert(file.clone()) {
+                log::warn!(
+                    "[{}] no pi_api_version() declared, assuming {} (deprecated, declare pi_api_version({}))",
+                    file, OLDEST_API_VERSION, CURRENT_API_VERSION
+                );
+            }
+            Ok(OLDEST_API_VERSION)
+        }
+    }
+}
+
+/// Registers builtins that only exist for older API versions.
+pub fn register_shims(builder: &mut GlobalsBuilder, api_version: u32) {
+    if api_version < 2 {
+        register_v1_shims(builder);
+    }
+}
+
+#[starlark_module]
+pub fn register_api_version_global(builder: &mut GlobalsBuilder) {
+    /// Declares the recipe API version. Must be a top-level statement.
+    fn pi_api_version(version: i32) -> anyhow::Result<NoneType> {
+        let supported = OLDEST_API_VERSION as i32..=CURRENT_API_VERSION as i32;
+        if !supported.contains(&version) {
+            anyhow::bail!("unsupported pi_api_version({})", version);
+        }
+        Ok(NoneType)
+    }
+}
+
+/// API 1: `extract(pattern, text)` was renamed to `re_match` in API 2.
+#[starlark_module]
+fn register_v1_shims(builder: &mut GlobalsBuilder) {
+    fn extract<'v>(
+        pattern: String,
+        text: String,
+        eval: &mut Evaluator<'v, '_, '_>,
+    ) -> anyhow::Result<Value<'v>> {
+        crate::starlark::api::stdlib::match_re_logic(&pattern, &text, eval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_api_version() {
+        assert_eq!(detect_api_version("pi_api_version(2)\ndef f(): pass"), Some(2));
+        assert_eq!(detect_api_version("# header\npi_api_version( 1 )\n"), Some(1));
+        assert_eq!(detect_api_version("def f():\n    pass\n"), None);
+        assert!(resolve_api_version("x.star", "pi_api_version(99)").is_err());
+    }
+}
@@ -81,14 +81,14 @@ impl<'v> StarlarkValue<'v> for DataNode {
     fn at(&self, index: Value<'v>, heap: &'v Heap) -> starlark::Result<Value<'v>> {
         match &self.value {
             serde_json::Value::Array(arr) => {
-                let i = index.unpack_i32().context("index must be an integer").map_err(|e| starlark::Error::new_other(e))?;
+                let i = index.unpack_i32().context("index must be an integer").map_err(starlark::Error::new_other)?;
                 if i < 0 || i as usize >= arr.len() {
                     return Err(starlark::Error::new_other(anyhow::anyhow!("index out of bounds")));
                 }
                 Ok(serde_to_starlark(arr[i as usize].clone(), heap))
             }
             serde_json::Value::Object(obj) => {
-                let key = index.unpack_str().context("index must be a string").map_err(|e| starlark::Error::new_other(e))?;
+                let key = index.unpack_str().context("index must be a string").map_err(starlark::Error::new_other)?;
                 if let Some(val) = obj.get(key) {
                     Ok(serde_to_starlark(val.clone(), heap))
                 } else {
@@ -6,18 +6,19 @@ use starlark::values::{
     starlark_value, AllocValue, Heap, StarlarkValue, Value, ValueLike,
 };
 use std::fmt::{self, Display};
-use scraper::{Html, ElementRef};
 use allocative::{Allocative, Visitor};
 use serde::Serialize;
 use anyhow::Context;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use parking_lot::Mutex;
 use ego_tree::NodeId;
+use crate::utils::html_index::IndexedHtml;
 
 #[derive(Debug, ProvidesStaticType, Clone)]
 pub struct HtmlDocument {
-    /// Shared reference to the parsed HTML tree.
+    /// Shared reference to the parsed HTML tree, with its selector cache and tag index.
     /// Locks are acquired for short durations to select or iterate nodes.
-    pub doc: Arc<Mutex<Html>>,
+    pub doc: Arc<Mutex<IndexedHtml>>,
 }
 
 impl Serialize for HtmlDocument {
@@ -61,8 +62,7 @@ fn html_document_methods(builder: &mut MethodsBuilder) {
     #[starlark(attribute)]
     fn root<'v>(this: Value<'v>, heap: &'v Heap) -> anyhow::Result<Value<'v>> {
         let this = this.downcast_ref::<HtmlDocument>().context("not an HtmlDocument")?;
-        let guard = this.doc.lock().unwrap();
-        let node_id = guard.tree.root().id();
+        let node_id = this.doc.lock().root_id();
         Ok(heap.alloc(HtmlNode {
             doc: this.doc.clone(),
             node_id,
@@ -74,7 +74,7 @@ fn html_document_methods(builder: &mut MethodsBuilder) {
 pub struct HtmlNode {
     /// Shared reference to the root HTML document.
     /// Locks are acquired when accessing node properties or selecting children.
-    pub doc: Arc<Mutex<Html>>,
+    pub doc: Arc<Mutex<IndexedHtml>>,
     pub node_id: NodeId,
 }
 
@@ -83,11 +83,9 @@ impl Serialize for HtmlNode {
     where
         S: serde::Serializer,
     {
-        let guard = self.doc.lock().unwrap();
-        if let Some(node) = guard.tree.get(self.node_id) {
-             if let Some(element) = ElementRef::wrap(node) {
-                 return serializer.serialize_str(&element.html());
-             }
+        let guard = self.doc.lock();
+        if let Some(element) = guard.element(self.node_id) {
+            return serializer.serialize_str(&element.html());
         }
         serializer.serialize_none()
     }
@@ -102,16 +100,24 @@ impl Allocative for HtmlNode {
 
 impl Display for HtmlNode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let guard = self.doc.lock().unwrap();
-        if let Some(node) = guard.tree.get(self.node_id) {
-            if let Some(element) = ElementRef::wrap(node) {
-                return write!(f, "<{} ...>", element.value().name());
-            }
+        let guard = self.doc.lock();
+        if let Some(element) = guard.element(self.node_id) {
+            return write!(f, "<{} ...>", element.value().name());
         }
         write!(f, "<unknown>")
     }
 }
 
+impl HtmlNode {
+    fn select(&self, selector: &str, limit: Option<usize>) -> anyhow::Result<Vec<HtmlNode>> {
+        let ids = self.doc.lock().select(self.node_id, selector, limit)?;
+        Ok(ids
+            .into_iter()
+            .map(|node_id| HtmlNode { doc: self.doc.clone(), node_id })
+            .collect())
+    }
+}
+
 #[starlark_value(type = "HtmlNode")]
 impl<'v> StarlarkValue<'v> for HtmlNode {
     fn get_methods() -> Option<&'static Methods> {
@@ -130,64 +136,28 @@ impl<'v> AllocValue<'v> for HtmlNode {
 fn html_node_methods(builder: &mut MethodsBuilder) {
     fn select_one<'v>(this: Value<'v>, selector: String, heap: &'v Heap) -> anyhow::Result<Value<'v>> {
         let this = this.downcast_ref::<HtmlNode>().context("not an HtmlNode")?;
-        let selector = scraper::Selector::parse(&selector).map_err(|e| anyhow::anyhow!("CSS selector parse error: {:?}", e))?;
-        
-        let guard = this.doc.lock().unwrap();
-        
-        if this.node_id == guard.tree.root().id() {
-            if let Some(el) = guard.select(&selector).next() {
-                return Ok(heap.alloc(HtmlNode {
-                    doc: this.doc.clone(),
-                    node_id: el.id(),
-                }));
-            }
-        } else {
-            let node = guard.tree.get(this.node_id).context("node not found")?;
-            if let Some(element) = ElementRef::wrap(node) {
-                if let Some(el) = element.select(&selector).next() {
-                    return Ok(heap.alloc(HtmlNode {
-                        doc: this.doc.clone(),
-                        node_id: el.id(),
-                    }));
-                }
-            }
+        match this.select(&selector, Some(1))?.into_iter().next() {
+            Some(node) => Ok(heap.alloc(node)),
+            None => Ok(Value::new_none()),
         }
-        Ok(Value::new_none())
     }
 
     fn select<'v>(this: Value<'v>, selector: String, heap: &'v Heap) -> anyhow::Result<Value<'v>> {
         let this = this.downcast_ref::<HtmlNode>().context("not an HtmlNode")?;
-        let selector = scraper::Selector::parse(&selector).map_err(|e| anyhow::anyhow!("CSS selector parse error: {:?}", e))?;
-        
-        let guard = this.doc.lock().unwrap();
-        let mut result = Vec::new();
-
-        if this.node_id == guard.tree.root().id() {
-             for el in guard.select(&selector) {
-                result.push(heap.alloc(HtmlNode {
-                    doc: this.doc.clone(),
-                    node_id: el.id(),
-                }));
-             }
-        } else {
-            let node = guard.tree.get(this.node_id).context("node not found")?;
-            if let Some(element) = ElementRef::wrap(node) {
-                for el in element.select(&selector) {
-                    result.push(heap.alloc(HtmlNode {
-                        doc: this.doc.clone(),
-                        node_id: el.id(),
-                    }));
-                }
-            }
-        }
-        Ok(heap.alloc(result))
+        Ok(heap.alloc(this.select(&selector, None)?))
+    }
+
+    /// Like `select`, but stops after the first `n` matches.
+    fn select_first_n<'v>(this: Value<'v>, selector: String, n: i32, heap: &'v Heap) -> anyhow::Result<Value<'v>> {
+        let this = this.downcast_ref::<HtmlNode>().context("not an HtmlNode")?;
+        let n = usize::try_from(n).map_err(|_| anyhow::anyhow!("n must not be negative"))?;
+        Ok(heap.alloc(this.select(&selector, Some(n))?))
     }
 
     fn attribute<'v>(this: Value<'v>, name: String, heap: &'v Heap) -> anyhow::Result<Value<'v>> {
         let this = this.downcast_ref::<HtmlNode>().context("not an HtmlNode")?;
-        let guard = this.doc.lock().unwrap();
-        let node = guard.tree.get(this.node_id).context("node not found")?;
-        let element = ElementRef::wrap(node).context("not an element")?;
+        let guard = this.doc.lock();
+        let element = guard.element(this.node_id).context("not an element")?;
         if let Some(val) = element.value().attr(&name) {
             Ok(heap.alloc(val.to_string()))
         } else {
@@ -197,9 +167,8 @@ fn html_node_methods(builder: &mut MethodsBuilder) {
 
     fn text(this: Value) -> anyhow::Result<String> {
         let this = this.downcast_ref::<HtmlNode>().context("not an HtmlNode")?;
-        let guard = this.doc.lock().unwrap();
-        let node = guard.tree.get(this.node_id).context("node not found")?;
-        let element = ElementRef::wrap(node).context("not an element")?;
+        let guard = this.doc.lock();
+        let element = guard.element(this.node_id).context("not an element")?;
         let text = element.text().collect::<Vec<_>>().join("");
         Ok(text)
     }
@@ -207,9 +176,8 @@ fn html_node_methods(builder: &mut MethodsBuilder) {
     #[starlark(attribute)]
     fn tag(this: Value) -> anyhow::Result<String> {
         let this = this.downcast_ref::<HtmlNode>().context("not an HtmlNode")?;
-        let guard = this.doc.lock().unwrap();
-        let node = guard.tree.get(this.node_id).context("node not found")?;
-        let element = ElementRef::wrap(node).context("not an element")?;
+        let guard = this.doc.lock();
+        let element = guard.element(this.node_id).context("not an element")?;
         Ok(element.value().name().to_string())
     }
 }
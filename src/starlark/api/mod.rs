@@ -1,13 +1,21 @@
 use starlark::environment::GlobalsBuilder;
 
+pub mod bytes;
+pub mod compat;
 pub mod data;
 pub mod html;
+pub mod state;
 pub mod stdlib;
+pub mod testing;
 pub mod version;
 pub mod xml;
 pub mod utils;
 
-pub fn register_api(builder: &mut GlobalsBuilder) {
+pub fn register_api(builder: &mut GlobalsBuilder, api_version: u32) {
+    compat::register_api_version_global(builder);
     stdlib::register_stdlib(builder);
+    state::register_state(builder);
+    testing::register_testing(builder);
     version::register_version_globals(builder);
+    compat::register_shims(builder, api_version);
 }
@@ -0,0 +1,37 @@
+use crate::starlark::api::utils::get_context;
+use anyhow::Context;
+use starlark::environment::GlobalsBuilder;
+use starlark::eval::Evaluator;
+use starlark::starlark_module;
+use starlark::values::none::{NoneOr, NoneType};
+
+/// `state_get`/`state_set`: string values a recipe keeps between syncs (e.g.
+/// a registry cursor), scoped to the recipe and stored in the state database.
+#[starlark_module]
+pub fn register_state(builder: &mut GlobalsBuilder) {
+    /// The value stored under `key` by an earlier `state_set`, else `default`.
+    /// Fails when the state database can't be used, rather than passing
+    /// `default` off as the stored value.
+    fn state_get(
+        key: &str,
+        #[starlark(default = NoneOr::None)] default: NoneOr<String>,
+        eval: &mut Evaluator,
+    ) -> anyhow::Result<NoneOr<String>> {
+        let context = get_context(eval)?;
+        let db = context.state.db(&context.state_dir).context("state database is unavailable")?;
+        Ok(match db.state_get(&context.state_scope, key)? {
+            Some(v) => NoneOr::Other(v),
+            None => default,
+        })
+    }
+
+    /// Stores `value` under `key`; None removes it. Values are limited to
+    /// 64 KiB and a recipe's state to 1 MiB. Fails when the value can't be saved.
+    fn state_set(key: &str, value: NoneOr<String>, eval: &mut Evaluator) -> anyhow::Result<NoneType> {
+        let context = get_context(eval)?;
+        let db = context.state.db(&context.state_dir)
+            .with_context(|| format!("state database is unavailable, '{}' is not saved", key))?;
+        db.state_set(&context.state_scope, key, value.into_option().as_deref())?;
+        Ok(NoneType)
+    }
+}
@@ -1,9 +1,22 @@
-use crate::models::package_entry::{ManagerEntry, PackageEntry};
+use crate::models::package_entry::{DirSource, ManagerEntry, PackageEntry, DIR_MANAGER};
 use crate::services::cache::Cache;
-use crate::services::downloader::Downloader;
+use crate::services::downloader::{Downloader, HttpRequest};
+use crate::services::sandbox::recipe;
+use crate::utils::crypto::hash_to_string;
+use crate::utils::semver;
+use crate::utils::timings::Phase;
+use starlark::collections::SmallMap;
 use starlark::eval::Evaluator;
 use starlark::values::{Value, none::NoneType};
+use starlark::values::dict::AllocDict;
+use starlark::values::list::{ListRef, UnpackList};
+use starlark::values::structs::AllocStruct;
+use starlark::values::tuple::AllocTuple;
+use anyhow::Context as _;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
+use crate::models::context::Context;
+use crate::starlark::api::bytes;
 use crate::starlark::api::data;
 use crate::starlark::api::xml;
 use crate::starlark::api::html;
@@ -15,7 +28,7 @@ pub fn register_stdlib(builder: &mut GlobalsBuilder) {
     register_stdlib_internal(builder);
 }
 
-fn match_re_logic<'v>(
+pub(crate) fn match_re_logic<'v>(
     pattern: &str,
     text: &str,
     eval: &mut Evaluator<'v, '_, '_>,
@@ -39,16 +52,112 @@ fn match_re_logic<'v>(
     }
 }
 
-#[starlark_module]
-fn register_stdlib_internal(builder: &mut GlobalsBuilder) {
-    fn extract<'v>(
-        pattern: String,
-        text: String,
-        eval: &mut Evaluator<'v, '_, '_>,
-    ) -> anyhow::Result<Value<'v>> {
-        match_re_logic(&pattern, &text, eval)
+/// A query parameter value of `url_encode`: strings as they are, other
+/// scalars as Starlark prints them.
+fn query_value(value: Value) -> anyhow::Result<String> {
+    match value.unpack_str() {
+        Some(s) => Ok(s.to_string()),
+        None if value.is_none() => anyhow::bail!("url_encode: None is not a query value, leave the key out"),
+        None if matches!(value.get_type(), "int" | "bool" | "float") => Ok(value.to_str()),
+        None => anyhow::bail!("url_encode: cannot encode a {} as a query value", value.get_type()),
+    }
+}
+
+/// Downloads text from `url` through the 24h metadata cache, converted to
+/// UTF-8. Returns None (after a warning) when the download fails.
+fn fetch_cached(context: &Context, url: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    fetch_cached_with(context, url, url, || Downloader::download_text(url, context.host_policy.as_ref()))
+}
+
+/// `fetch_cached` of any request: `key` names its cache entry and lock,
+/// `url` is what gets logged.
+fn fetch_cached_with(
+    context: &Context,
+    key: &str,
+    url: &str,
+    fetch: impl FnOnce() -> anyhow::Result<Vec<u8>>,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    context.check_host(url)?;
+    match cached_with(context, key, &format!("fetching: {}", url), Phase::Network, fetch)? {
+        Ok(content) => Ok(Some(content)),
+        Err(e) => {
+            log::warn!("[{}] download failed for {}: {}", context.display_name(), url, e);
+            context.fetch_failed.store(true, Ordering::Relaxed);
+            Ok(None)
+        }
+    }
+}
+
+/// The output of `produce` through the 24h metadata cache under `key`,
+/// produced by one thread at a time and timed as `phase`; `what` is logged.
+/// Failures of `produce` come back as the inner error and aren't cached.
+fn cached_with(
+    context: &Context,
+    key: &str,
+    what: &str,
+    phase: Phase,
+    produce: impl FnOnce() -> anyhow::Result<Vec<u8>>,
+) -> anyhow::Result<anyhow::Result<Vec<u8>>> {
+    let cache = Cache::new(context.meta_dir.clone(), Duration::from_secs(86400)); // 24 hours TTL
+
+    if !context.force
+        && let Some(cached) = cache.read(key)?
+    {
+        log::debug!("[{}] cache hit: {}", context.display_name(), key);
+        return Ok(Ok(cached));
+    }
+
+    // Acquire or create a per-URL download lock to avoid redundant concurrent requests.
+    // We drop the DashMap entry lock quickly by cloning the Arc<Mutex<()>>.
+    let lock = context
+        .state
+        .download_locks
+        .entry(key.to_string())
+        .or_insert_with(|| std::sync::Arc::new(parking_lot::Mutex::new(())))
+        .clone();
+
+    // Hold the Mutex during the download process to ensure only one thread performs it.
+    let _guard = lock.lock();
+
+    if !context.force
+        && let Some(cached) = cache.read(key)?
+    {
+        log::debug!("[{}] cache hit: {}", context.display_name(), key);
+        return Ok(Ok(cached));
     }
 
+    log::info!("[{}] {}", context.display_name(), what);
+    let produced = {
+        let _timer = context.state.timings.scope(phase);
+        produce()
+    };
+    if let Ok(content) = &produced {
+        cache.write(key, content)?;
+    }
+    Ok(produced)
+}
+
+/// The body as text; empty, with a warning, when it isn't UTF-8.
+fn response_text(context: &Context, url: &str, content: Option<Vec<u8>>) -> String {
+    match content.map(String::from_utf8) {
+        Some(Ok(text)) => text,
+        Some(Err(_)) => {
+            log::warn!("[{}] {} is not utf-8 text, use download_binary()", context.display_name(), url);
+            String::new()
+        }
+        None => String::new(),
+    }
+}
+
+/// Cache key of a `download_with()` request: the URL and a hash of the
+/// rest, so credentials in headers never appear in cache file names.
+fn request_key(request: &HttpRequest) -> String {
+    let method = request.method.to_ascii_uppercase();
+    format!("{}#{}", request.url, hash_to_string(&(method, request.headers, request.body)))
+}
+
+#[starlark_module]
+fn register_stdlib_internal(builder: &mut GlobalsBuilder) {
     fn re_match<'v>(
         pattern: String,
         text: String,
@@ -67,6 +176,60 @@ fn register_stdlib_internal(builder: &mut GlobalsBuilder) {
         Ok(context.arch.to_string())
     }
 
+    /// The cave being built, as a read-only struct with `name`, `variant`
+    /// (None for the default), `packages` (the declared selectors) and
+    /// `workspace`. None outside cave builds, e.g. while syncing.
+    fn cave_info<'v>(eval: &mut Evaluator<'v, '_, '_>) -> anyhow::Result<Value<'v>> {
+        let context = get_context(eval)?;
+        let Some(cave) = &context.cave else { return Ok(Value::new_none()) };
+        let heap = eval.heap();
+        let variant = match &cave.variant {
+            Some(v) => heap.alloc(v.as_str()),
+            None => Value::new_none(),
+        };
+        let packages = heap.alloc(AllocTuple(cave.packages.iter().map(|p| p.as_str())));
+        Ok(heap.alloc(AllocStruct([
+            ("name", heap.alloc(cave.name.as_str())),
+            ("variant", variant),
+            ("packages", packages),
+            ("workspace", heap.alloc(cave.workspace.to_string_lossy().as_ref())),
+        ])))
+    }
+
+    /// Path of a file in the recipe's repository for use in `run()` commands,
+    /// e.g. `repo_files("patches/fix.patch")`. Build sandboxes mount the
+    /// repository read-only; the directory itself with no argument.
+    fn repo_files(#[starlark(default = "")] path: &str) -> anyhow::Result<String> {
+        let rel = std::path::Path::new(path);
+        if rel.is_absolute() || rel.components().any(|c| matches!(c, std::path::Component::ParentDir)) {
+            anyhow::bail!("repo_files: '{}' must be a relative path inside the repository", path);
+        }
+        let root = crate::commands::package::build::REPO_PLACEHOLDER;
+        Ok(if path.is_empty() { root.to_string() } else { format!("{}/{}", root, path) })
+    }
+
+    /// Versions already saved for the package being discovered (empty on a full sync).
+    fn known_versions(eval: &mut Evaluator<'_, '_, '_>) -> anyhow::Result<Vec<String>> {
+        let context = get_context(eval)?;
+        Ok(context.known_versions.clone())
+    }
+
+    /// Newest saved version, the high-water mark for delta syncs. None on a full sync.
+    fn known_latest<'v>(eval: &mut Evaluator<'v, '_, '_>) -> anyhow::Result<Value<'v>> {
+        let context = get_context(eval)?;
+        match &context.known_latest {
+            Some(v) => Ok(eval.heap().alloc(v.as_str())),
+            None => Ok(Value::new_none()),
+        }
+    }
+
+    /// Declares that the registered versions are a delta to merge into the saved list.
+    fn set_incremental(eval: &mut Evaluator<'_, '_, '_>) -> anyhow::Result<NoneType> {
+        let context = get_context(eval)?;
+        context.incremental.store(true, std::sync::atomic::Ordering::Relaxed);
+        Ok(NoneType)
+    }
+
     fn add_package<'v>(
         name: String,
         function: Value<'v>,
@@ -79,6 +242,7 @@ fn register_stdlib_internal(builder: &mut GlobalsBuilder) {
             name,
             function_name,
             filename: context.filename.clone(),
+            dir: None,
         });
 
         Ok(NoneType)
@@ -96,6 +260,32 @@ fn register_stdlib_internal(builder: &mut GlobalsBuilder) {
             name,
             function_name,
             filename: context.filename.clone(),
+            dir: None,
+        });
+
+        Ok(NoneType)
+    }
+
+    /// Serves `dir:<name>` from the files in `path` (absolute or relative to
+    /// the recipe) whose names match `pattern`. `function(v, file)` gets a
+    /// version builder already fetching `file`, adds the remaining steps and
+    /// exports, and registers it.
+    fn add_dir_package<'v>(
+        name: String,
+        path: String,
+        pattern: String,
+        function: Value<'v>,
+        eval: &mut Evaluator<'v, '_, '_>,
+    ) -> anyhow::Result<NoneType> {
+        let context = get_context(eval)?;
+        let source = DirSource { path, pattern };
+        source.regex()?;
+
+        context.managers.write().push(ManagerEntry {
+            name: format!("{}:{}", DIR_MANAGER, name),
+            function_name: extract_function_name(function),
+            filename: context.filename.clone(),
+            dir: Some(source),
         });
 
         Ok(NoneType)
@@ -103,44 +293,167 @@ fn register_stdlib_internal(builder: &mut GlobalsBuilder) {
 
     fn download(url: String, eval: &mut Evaluator<'_, '_, '_>) -> anyhow::Result<String> {
         let context = get_context(eval)?;
-        let cache = Cache::new(context.meta_dir.clone(), Duration::from_secs(86400)); // 24 hours TTL
+        Ok(response_text(context, &url, fetch_cached(context, &url)?))
+    }
+
+    /// `download()` with another method, extra headers or a body, e.g. for
+    /// GraphQL APIs or registries that need a token. Responses are cached
+    /// and shared like `download()`'s, keyed by the whole request.
+    fn download_with(
+        url: String,
+        #[starlark(require = named)] headers: Option<SmallMap<String, String>>,
+        #[starlark(require = named, default = "GET")] method: &str,
+        #[starlark(require = named)] body: Option<String>,
+        eval: &mut Evaluator<'_, '_, '_>,
+    ) -> anyhow::Result<String> {
+        let context = get_context(eval)?;
+        let headers: Vec<(String, String)> = headers.map(|h| h.into_iter().collect()).unwrap_or_default();
+        let request = HttpRequest { url: &url, method, headers: &headers, body: body.as_deref() };
+        let content = fetch_cached_with(context, &request_key(&request), &url, || Downloader::request(&request, context.host_policy.as_ref()))?;
+        Ok(response_text(context, &url, content))
+    }
+
+    /// Output of `cmd` with `args`, for version sources only a CLI reaches,
+    /// e.g. `run_command("git", ["ls-remote", "--tags", url])`. It runs in a
+    /// sandbox with the system directories read-only and, unless the repo is
+    /// trusted with every host, no network. Output is cached like `download()`'s;
+    /// a failing command fails the recipe. A restricted repo's commands are
+    /// refused when sandboxing is off.
+    fn run_command(
+        cmd: String,
+        #[starlark(default = UnpackList::default())] args: UnpackList<String>,
+        eval: &mut Evaluator<'_, '_, '_>,
+    ) -> anyhow::Result<String> {
+        let context = get_context(eval)?;
+        let args = args.items;
+        let restricted = context.host_policy.is_some();
+        let network = !restricted && !Downloader::is_offline();
+        let line = std::iter::once(cmd.as_str()).chain(args.iter().map(|a| a.as_str())).collect::<Vec<_>>().join(" ");
+        let key = format!("run:{}#{}", cmd, hash_to_string(&(&cmd, &args, network)));
+        let output = cached_with(context, &key, &format!("running: {}", line), Phase::Execution, || {
+            recipe::run_command(&cmd, &args, network, restricted, recipe::COMMAND_TIMEOUT)
+        })?
+        .map_err(|e| anyhow::anyhow!("run_command({}) failed: {:#}", line, e))?;
+        String::from_utf8(output).with_context(|| format!("output of {} is not utf-8 text", line))
+    }
+
+    /// Binary-safe `download()`: returns a `Bytes` value (empty on failure).
+    fn download_binary<'v>(url: String, eval: &mut Evaluator<'v, '_, '_>) -> anyhow::Result<Value<'v>> {
+        let context = get_context(eval)?;
+        // Cached apart from `download()`'s text, which may be transcoded.
+        let key = format!("binary:{}", url);
+        let content = fetch_cached_with(context, &key, &url, || Downloader::download(&url, context.host_policy.as_ref()))?.unwrap_or_default();
+        Ok(eval.heap().alloc(bytes::Bytes::new(content)))
+    }
 
-        if !context.force {
-            if let Some(cached) = cache.read(&url)? {
-                log::debug!("[{}] cache hit: {}", context.display_name(), url);
-                return Ok(cached);
+    /// Size in bytes of `url` from a HEAD request, or None when the server
+    /// doesn't say; pass it as `fetch(..., size=)`.
+    fn content_length<'v>(url: String, eval: &mut Evaluator<'v, '_, '_>) -> anyhow::Result<Value<'v>> {
+        let context = get_context(eval)?;
+        context.check_host(&url)?;
+        let length = {
+            let _timer = context.state.timings.scope(Phase::Network);
+            Downloader::content_length(&url, context.host_policy.as_ref())
+        };
+        match length {
+            Ok(Some(size)) => Ok(eval.heap().alloc(size)),
+            Ok(None) => Ok(Value::new_none()),
+            Err(e) => {
+                log::warn!("[{}] HEAD {} failed: {:#}", context.display_name(), url, e);
+                context.fetch_failed.store(true, Ordering::Relaxed);
+                Ok(Value::new_none())
             }
         }
+    }
+
+    /// Resolves `path` against `base` the way a browser resolves a link:
+    /// `url_join("https://x.org/api/", "v1/pkg")` is `https://x.org/api/v1/pkg`,
+    /// while a `base` not ending in `/` has its last segment replaced.
+    fn url_join(base: &str, path: &str) -> anyhow::Result<String> {
+        let base = url::Url::parse(base).map_err(|e| anyhow::anyhow!("url_join: invalid base URL '{}': {}", base, e))?;
+        let joined = base.join(path).map_err(|e| anyhow::anyhow!("url_join: cannot join '{}' to {}: {}", path, base, e))?;
+        Ok(joined.to_string())
+    }
 
-        // Acquire or create a per-URL download lock to avoid redundant concurrent requests.
-        // We drop the DashMap entry lock quickly by cloning the Arc<Mutex<()>>.
-        let lock = context
-            .state
-            .download_locks
-            .entry(url.clone())
-            .or_insert_with(|| std::sync::Arc::new(parking_lot::Mutex::new(())))
-            .clone();
-
-        // Hold the Mutex during the download process to ensure only one thread performs it.
-        let _guard = lock.lock();
-
-        if !context.force {
-            if let Some(cached) = cache.read(&url)? {
-                log::debug!("[{}] cache hit: {}", context.display_name(), url);
-                return Ok(cached);
+    /// Form-encodes `params` as a query string (without `?`), in dict order.
+    /// A list value repeats its key: `{"arch": ["x64", "arm64"]}` gives
+    /// `arch=x64&arch=arm64`.
+    fn url_encode<'v>(params: SmallMap<String, Value<'v>>) -> anyhow::Result<String> {
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        for (key, value) in &params {
+            match ListRef::from_value(*value) {
+                Some(list) => for item in list.iter() {
+                    query.append_pair(key, &query_value(item)?);
+                },
+                None => {
+                    query.append_pair(key, &query_value(*value)?);
+                }
             }
         }
+        Ok(query.finish())
+    }
 
-        log::info!("[{}] fetching: {}", context.display_name(), url);
-        let content = match Downloader::download(&url) {
-            Ok(c) => c,
-            Err(e) => {
-                log::warn!("[{}] download failed for {}: {}", context.display_name(), url, e);
-                return Ok(String::new());
-            }
-        };
-        cache.write(&url, &content)?;
-        Ok(content)
+    /// Splits `url` into a struct with `scheme`, `host`, `port` (None unless
+    /// given), `path`, `query` (a dict of decoded parameters; a repeated key
+    /// keeps its last value) and `fragment` (None unless given).
+    fn url_parse<'v>(url: &str, eval: &mut Evaluator<'v, '_, '_>) -> anyhow::Result<Value<'v>> {
+        let parsed = url::Url::parse(url).map_err(|e| anyhow::anyhow!("url_parse: invalid URL '{}': {}", url, e))?;
+        let heap = eval.heap();
+        let query: SmallMap<String, String> = parsed.query_pairs().map(|(k, v)| (k.into_owned(), v.into_owned())).collect();
+        let optional = |v: Option<Value<'v>>| v.unwrap_or_else(Value::new_none);
+        Ok(heap.alloc(AllocStruct([
+            ("scheme", heap.alloc(parsed.scheme())),
+            ("host", heap.alloc(parsed.host_str().unwrap_or(""))),
+            ("port", optional(parsed.port().map(|p| heap.alloc(i32::from(p))))),
+            ("path", heap.alloc(parsed.path())),
+            ("query", heap.alloc(AllocDict(query))),
+            ("fragment", optional(parsed.fragment().map(|f| heap.alloc(f)))),
+        ])))
+    }
+
+    /// Parses a version into a struct with `major`, `minor`, `patch` (ints),
+    /// `pre` and `build` (strings, empty when absent); None when `s` is not a
+    /// version. A leading `v` is allowed and missing components are 0:
+    /// `semver_parse("v1.22").minor` is 22.
+    fn semver_parse<'v>(s: &str, eval: &mut Evaluator<'v, '_, '_>) -> anyhow::Result<Value<'v>> {
+        let Ok(v) = semver::parse(s) else { return Ok(Value::new_none()) };
+        let heap = eval.heap();
+        let int = |n: u64| heap.alloc(i64::try_from(n).unwrap_or(i64::MAX));
+        Ok(heap.alloc(AllocStruct([
+            ("major", int(v.major)),
+            ("minor", int(v.minor)),
+            ("patch", int(v.patch)),
+            ("pre", heap.alloc(v.pre.as_str())),
+            ("build", heap.alloc(v.build.as_str())),
+        ])))
+    }
+
+    /// -1, 0 or 1 as `a` is older than, the same as or newer than `b`, by
+    /// semver precedence (`1.10` is newer than `1.9`, `1.2.0-rc.1` older
+    /// than `1.2.0`). Fails on a value that isn't a version.
+    fn semver_compare(a: &str, b: &str) -> anyhow::Result<i32> {
+        Ok(semver::compare(a, b).map_err(|e| anyhow::anyhow!("semver_compare: {:#}", e))? as i32)
+    }
+
+    /// Whether `version` meets `constraint`: comma-separated comparators such
+    /// as `">=1.20, <2"`, `"^1.2"`, `"~1.2.3"` or `"1.*"`.
+    fn semver_satisfies(version: &str, constraint: &str) -> anyhow::Result<bool> {
+        semver::satisfies(version, constraint).map_err(|e| anyhow::anyhow!("semver_satisfies: {:#}", e))
+    }
+
+    /// `versions` ordered oldest first (newest first with `reverse`), as the
+    /// strings given. Fails on a value that isn't a version; filter with
+    /// `semver_parse` first.
+    fn semver_sort(versions: UnpackList<String>, #[starlark(require = named, default = false)] reverse: bool) -> anyhow::Result<Vec<String>> {
+        let mut parsed = versions.items.into_iter()
+            .map(|s| semver::parse(&s).map(|v| (v, s)))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(|e| anyhow::anyhow!("semver_sort: {:#}", e))?;
+        parsed.sort_by(|a, b| a.0.cmp(&b.0));
+        if reverse {
+            parsed.reverse();
+        }
+        Ok(parsed.into_iter().map(|(_, s)| s).collect())
     }
 
     fn parse_json<'v>(
@@ -182,7 +495,7 @@ fn register_stdlib_internal(builder: &mut GlobalsBuilder) {
         content: String,
         eval: &mut Evaluator<'v, '_, '_>,
     ) -> anyhow::Result<Value<'v>> {
-        let document = std::sync::Arc::new(std::sync::Mutex::new(scraper::Html::parse_document(&content)));
+        let document = std::sync::Arc::new(parking_lot::Mutex::new(crate::utils::html_index::IndexedHtml::parse(&content)));
         let doc_obj = html::HtmlDocument { doc: document };
         Ok(eval.heap().alloc(doc_obj))
     }
@@ -0,0 +1,40 @@
+use starlark::environment::GlobalsBuilder;
+use starlark::starlark_module;
+use starlark::values::none::{NoneOr, NoneType};
+use starlark::values::Value;
+
+/// Prefix of the recipe functions `pi devel check` runs as self-tests.
+pub const TEST_PREFIX: &str = "test_";
+
+#[starlark_module]
+pub fn register_testing(builder: &mut GlobalsBuilder) {
+    /// Fails unless `a == b`, e.g. `assert_eq(parse_tag("v1.2"), "1.2")`.
+    fn assert_eq<'v>(
+        a: Value<'v>,
+        b: Value<'v>,
+        #[starlark(default = NoneOr::None)] msg: NoneOr<String>,
+    ) -> anyhow::Result<NoneType> {
+        if !a.equals(b).map_err(|e| e.into_anyhow())? {
+            anyhow::bail!("{}{} != {}", prefix(msg), a.to_repr(), b.to_repr());
+        }
+        Ok(NoneType)
+    }
+
+    /// Fails unless `cond` is truthy.
+    fn assert_true<'v>(
+        cond: Value<'v>,
+        #[starlark(default = NoneOr::None)] msg: NoneOr<String>,
+    ) -> anyhow::Result<NoneType> {
+        if !cond.to_bool() {
+            anyhow::bail!("{}expected a true value, got {}", prefix(msg), cond.to_repr());
+        }
+        Ok(NoneType)
+    }
+}
+
+fn prefix(msg: NoneOr<String>) -> String {
+    match msg {
+        NoneOr::Other(m) => format!("{}: ", m),
+        NoneOr::None => String::new(),
+    }
+}
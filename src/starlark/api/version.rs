@@ -1,9 +1,18 @@
-use crate::models::version_entry::{VersionEntry, InstallStep, Export, BuildFlag, Dependency, ReleaseType, StructuredVersion};
+use crate::models::version_entry::{VersionEntry, InstallStep, Interpreter, Export, BuildFlag, Dependency, ReleaseType, Signature, StructuredVersion};
+use crate::models::package_entry::DIR_MANAGER;
+use crate::models::types::parse_constraint;
+use crate::services::oci::OciReference;
+use crate::services::patcher::Patcher;
+use crate::services::signature::KeyKind;
+use crate::services::unarchiver::ArchiveFormat;
 use crate::utils::inspect::inspect_version;
+use crate::utils::permissions::parse_mode;
 use anyhow::Context as _;
 use starlark::eval::Evaluator;
+use starlark::collections::SmallMap;
 use starlark::starlark_module;
 use starlark::values::{Value, ValueLike, none::NoneType};
+use starlark::values::list::{ListRef, UnpackList};
 use starlark::any::ProvidesStaticType;
 use starlark::environment::Methods;
 use starlark::environment::MethodsBuilder;
@@ -13,6 +22,7 @@ use starlark::values::{
 };
 use allocative::Allocative;
 use serde::Serialize;
+use std::collections::BTreeMap;
 use std::fmt::{self, Debug, Display};
 use std::sync::Arc;
 use std::str::FromStr;
@@ -20,6 +30,9 @@ use parking_lot::RwLock;
 use crate::starlark::api::utils::get_context;
 use starlark::environment::GlobalsBuilder;
 
+/// Step kinds with their own builder methods, which `step()` can't name.
+const BUILTIN_STEPS: &[&str] = &["fetch", "extract", "git_clone", "run", "patch", "Fetch", "Extract", "GitClone", "Run", "Patch"];
+
 #[derive(Debug, ProvidesStaticType, Clone, Allocative, Serialize)]
 pub struct VersionBuilder {
     pub pkgname: String,
@@ -31,6 +44,32 @@ pub struct VersionBuilder {
     pub exports: Vec<Export>,
     pub flags: Vec<BuildFlag>,
     pub build_dependencies: Vec<Dependency>,
+    pub platforms: Vec<String>,
+    pub requires_host_build: bool,
+    pub sizes: BTreeMap<String, u64>,
+    pub changelog_url: Option<String>,
+    pub notes: Option<String>,
+}
+
+impl VersionBuilder {
+    pub fn new(pkgname: String, version: StructuredVersion) -> Self {
+        Self {
+            pkgname,
+            version,
+            release_date: String::new(),
+            release_type: ReleaseType::default(),
+            stream: String::new(),
+            pipeline: Vec::new(),
+            exports: Vec::new(),
+            flags: Vec::new(),
+            build_dependencies: Vec::new(),
+            platforms: Vec::new(),
+            requires_host_build: false,
+            sizes: BTreeMap::new(),
+            changelog_url: None,
+            notes: None,
+        }
+    }
 }
 
 #[derive(Debug, ProvidesStaticType, Clone, Serialize)]
@@ -98,6 +137,22 @@ fn version_builder_methods(builder: &mut MethodsBuilder) {
         Ok(NoneType)
     }
 
+    /// Release notes of the version: a `url` pi fetches, markdown `notes`, or both.
+    fn set_changelog(
+        this: Value,
+        #[starlark(require = named)] url: Option<String>,
+        #[starlark(require = named)] notes: Option<String>,
+    ) -> anyhow::Result<NoneType> {
+        let this = this.downcast_ref::<StarlarkVersionBuilder>().context("not a VersionBuilder")?;
+        if url.is_none() && notes.is_none() {
+            anyhow::bail!("set_changelog() needs url= or notes=");
+        }
+        let mut b = this.builder.write();
+        b.changelog_url = url;
+        b.notes = notes;
+        Ok(NoneType)
+    }
+
     fn set_version(this: Value, version: String) -> anyhow::Result<NoneType> {
         let this = this.downcast_ref::<StarlarkVersionBuilder>().context("not a VersionBuilder")?;
         let mut b = this.builder.write();
@@ -145,15 +200,68 @@ fn version_builder_methods(builder: &mut MethodsBuilder) {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn fetch(
         this: Value, 
-        url: String, 
+        url: Value, 
         checksum: Option<String>, 
         filename: Option<String>, 
-        name: Option<String>
+        name: Option<String>,
+        mirrors: Option<Value>,
+        size: Option<u64>,
+        signature_url: Option<String>,
+        public_key: Option<String>,
+        #[starlark(require = named, default = false)] stream: bool,
+    ) -> anyhow::Result<NoneType> {
+        let this = this.downcast_ref::<StarlarkVersionBuilder>().context("not a VersionBuilder")?;
+        if stream && signature_url.is_some() {
+            anyhow::bail!("fetch(stream=True) keeps no file to verify a signature on");
+        }
+        let signature = match (signature_url, public_key) {
+            (Some(url), Some(public_key)) => {
+                KeyKind::detect(&public_key)?;
+                Some(Signature { url, public_key })
+            }
+            (None, None) => None,
+            _ => anyhow::bail!("fetch() needs both signature_url and public_key"),
+        };
+        let mut urls = unpack_urls(url)?;
+        if let Some(m) = mirrors {
+            urls.extend(unpack_urls(m)?);
+        }
+        if urls.is_empty() {
+            anyhow::bail!("fetch() requires at least one url");
+        }
+        let url = urls.remove(0);
+        let mut b = this.builder.write();
+        if let Some(size) = size {
+            b.sizes.insert(url.clone(), size);
+        }
+        b.pipeline.push(InstallStep::Fetch { url, mirrors: urls, checksum, filename, name, signature, stream });
+        Ok(NoneType)
+    }
+
+    /// Pulls a blob of an OCI artifact (e.g. pushed with ORAS) like `fetch()`:
+    /// `reference` is `registry/repository[:tag|@sha256:...]`, `media_type`
+    /// picks the layer when there are several.
+    fn fetch_oci(
+        this: Value,
+        reference: String,
+        media_type: Option<String>,
+        checksum: Option<String>,
+        filename: Option<String>,
+        name: Option<String>,
+        size: Option<u64>,
     ) -> anyhow::Result<NoneType> {
         let this = this.downcast_ref::<StarlarkVersionBuilder>().context("not a VersionBuilder")?;
-        this.builder.write().pipeline.push(InstallStep::Fetch { url, checksum, filename, name });
+        let oci = OciReference::parse(&reference, media_type)?;
+        let url = oci.to_url();
+        let filename = Some(filename.unwrap_or_else(|| oci.default_filename()));
+        let mut b = this.builder.write();
+        if let Some(size) = size {
+            b.sizes.insert(url.clone(), size);
+        }
+        b.pipeline.push(InstallStep::Fetch { url, mirrors: Vec::new(), checksum, filename, name, signature: None, stream: false });
         Ok(NoneType)
     }
 
@@ -163,7 +271,33 @@ fn version_builder_methods(builder: &mut MethodsBuilder) {
         name: Option<String>
     ) -> anyhow::Result<NoneType> {
         let this = this.downcast_ref::<StarlarkVersionBuilder>().context("not a VersionBuilder")?;
-        this.builder.write().pipeline.push(InstallStep::Extract { format, name });
+        if let Some(format) = &format {
+            format.parse::<ArchiveFormat>()?;
+        }
+        let mut b = this.builder.write();
+        if matches!(b.pipeline.last(), Some(InstallStep::Fetch { stream: true, .. })) {
+            anyhow::bail!("fetch(stream=True) already extracts, drop the extract() after it");
+        }
+        b.pipeline.push(InstallStep::Extract { format, name });
+        Ok(NoneType)
+    }
+
+    /// Checks out `rev` of the git repository at `url`; pin a tag or commit.
+    fn git_clone(
+        this: Value,
+        url: String,
+        rev: String,
+        depth: Option<u32>,
+        name: Option<String>,
+    ) -> anyhow::Result<NoneType> {
+        let this = this.downcast_ref::<StarlarkVersionBuilder>().context("not a VersionBuilder")?;
+        if rev.is_empty() || rev.starts_with('-') || rev.contains(':') {
+            anyhow::bail!("git_clone() needs a tag, branch or commit as rev, not '{}'", rev);
+        }
+        if depth == Some(0) {
+            anyhow::bail!("git_clone() depth must be at least 1");
+        }
+        this.builder.write().pipeline.push(InstallStep::GitClone { name, url, rev, depth });
         Ok(NoneType)
     }
 
@@ -171,16 +305,72 @@ fn version_builder_methods(builder: &mut MethodsBuilder) {
         this: Value, 
         command: String, 
         cwd: Option<String>, 
-        name: Option<String>
+        name: Option<String>,
+        shell: Option<Value>,
     ) -> anyhow::Result<NoneType> {
         let this = this.downcast_ref::<StarlarkVersionBuilder>().context("not a VersionBuilder")?;
-        this.builder.write().pipeline.push(InstallStep::Run { command, cwd, name });
+        let shell = shell.map(unpack_interpreter).transpose()?.unwrap_or_default();
+        this.builder.write().pipeline.push(InstallStep::Run { command, cwd, name, shell });
         Ok(NoneType)
     }
 
-    fn export_link(this: Value, src: String, dest: String) -> anyhow::Result<NoneType> {
+    /// Applies a unified diff to the source tree before the steps after it,
+    /// e.g. `v.patch("https://example.org/fix-build.patch", checksum = "...")`;
+    /// a multi-line string is the diff itself.
+    fn patch(
+        this: Value,
+        patch: String,
+        strip: Option<u32>,
+        checksum: Option<String>,
+        name: Option<String>,
+    ) -> anyhow::Result<NoneType> {
+        let this = this.downcast_ref::<StarlarkVersionBuilder>().context("not a VersionBuilder")?;
+        if InstallStep::is_inline_patch(&patch) {
+            Patcher::validate(&patch).context("patch() got an invalid diff")?;
+            if checksum.is_some() {
+                anyhow::bail!("patch() takes a checksum only with a URL");
+            }
+        } else if patch.starts_with("file://") {
+            // Only a dir: package's own files; check_local_urls confirms where they are.
+            if !this.builder.read().pkgname.starts_with(&format!("{}:", DIR_MANAGER)) {
+                anyhow::bail!("patch() takes file:// URLs only in dir: packages, not '{}'", patch);
+            }
+        } else if !["https://", "http://"].iter().any(|s| patch.starts_with(s)) {
+            anyhow::bail!("patch() takes a URL or a diff, not '{}'", patch);
+        }
+        let strip = strip.unwrap_or(1);
+        this.builder.write().pipeline.push(InstallStep::Patch { name, url_or_inline: patch, strip, checksum });
+        Ok(NoneType)
+    }
+
+    /// A custom step run by the `pi-step-<kind>` plugin, e.g.
+    /// `v.step("notarize", params = {"identity": "ACME"})`.
+    fn step(
+        this: Value,
+        kind: String,
+        params: Option<SmallMap<String, String>>,
+        name: Option<String>,
+    ) -> anyhow::Result<NoneType> {
+        let this = this.downcast_ref::<StarlarkVersionBuilder>().context("not a VersionBuilder")?;
+        if BUILTIN_STEPS.contains(&kind.as_str()) || kind.is_empty() || kind.contains('/') {
+            anyhow::bail!("step() takes a plugin step kind, not '{}'", kind);
+        }
+        let params = params.map(|p| p.into_iter().collect()).unwrap_or_default();
+        this.builder.write().pipeline.push(InstallStep::Plugin { name, step: kind, params });
+        Ok(NoneType)
+    }
+
+    fn export_link(
+        this: Value,
+        src: String,
+        dest: String,
+        #[starlark(require = named)] chmod: Option<String>,
+    ) -> anyhow::Result<NoneType> {
         let this = this.downcast_ref::<StarlarkVersionBuilder>().context("not a VersionBuilder")?;
-        this.builder.write().exports.push(Export::Link { src, dest });
+        if let Some(mode) = &chmod {
+            parse_mode(mode)?;
+        }
+        this.builder.write().exports.push(Export::Link { src, dest, chmod });
         Ok(NoneType)
     }
 
@@ -196,6 +386,23 @@ fn version_builder_methods(builder: &mut MethodsBuilder) {
         Ok(NoneType)
     }
 
+    /// A daemon for `pi cave service`, e.g.
+    /// `v.export_service("postgres", "postgres -D $HOME/.pgdata", env = {"PGPORT": "5432"})`.
+    fn export_service(
+        this: Value,
+        name: String,
+        command: String,
+        env: Option<SmallMap<String, String>>,
+    ) -> anyhow::Result<NoneType> {
+        let this = this.downcast_ref::<StarlarkVersionBuilder>().context("not a VersionBuilder")?;
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) || name.starts_with('.') {
+            anyhow::bail!("invalid service name '{}'", name);
+        }
+        let env = env.map(|e| e.into_iter().collect()).unwrap_or_default();
+        this.builder.write().exports.push(Export::Service { name, command, env });
+        Ok(NoneType)
+    }
+
     fn require(this: Value, name: String) -> anyhow::Result<NoneType> {
         let this = this.downcast_ref::<StarlarkVersionBuilder>().context("not a VersionBuilder")?;
         this.builder.write().build_dependencies.push(Dependency { name, optional: false });
@@ -215,6 +422,17 @@ fn version_builder_methods(builder: &mut MethodsBuilder) {
         Ok(NoneType)
     }
 
+    /// Restricts the version to platforms like `linux/x86_64`, `linux/*` or `macos`.
+    fn platforms(this: Value, platforms: UnpackList<String>) -> anyhow::Result<NoneType> {
+        let this = this.downcast_ref::<StarlarkVersionBuilder>().context("not a VersionBuilder")?;
+        let platforms = platforms.items;
+        for p in &platforms {
+            parse_constraint(p).with_context(|| format!("invalid platform: {}", p))?;
+        }
+        this.builder.write().platforms = platforms;
+        Ok(NoneType)
+    }
+
     fn register(this: Value, eval: &mut Evaluator<'_, '_, '_>) -> anyhow::Result<NoneType> {
         let context = get_context(eval)?;
         let svb = this.downcast_ref::<StarlarkVersionBuilder>().context("not a VersionBuilder")?;
@@ -230,6 +448,11 @@ fn version_builder_methods(builder: &mut MethodsBuilder) {
             exports: b.exports.clone(),
             flags: b.flags.clone(),
             build_dependencies: b.build_dependencies.clone(),
+            platforms: b.platforms.clone(),
+            requires_host_build: b.requires_host_build,
+            sizes: b.sizes.clone(),
+            changelog_url: b.changelog_url.clone(),
+            notes: b.notes.clone(),
         });
         Ok(NoneType)
     }
@@ -242,6 +465,7 @@ pub fn register_version_globals(builder: &mut GlobalsBuilder) {
         version: Option<String>,
         release_date: Option<String>,
         release_type: Option<String>,
+        #[starlark(require = named, default = false)] requires_host_build: bool,
     ) -> anyhow::Result<StarlarkVersionBuilder> {
         let v = version.unwrap_or_default();
         let rt = release_type
@@ -250,16 +474,42 @@ pub fn register_version_globals(builder: &mut GlobalsBuilder) {
 
         Ok(StarlarkVersionBuilder {
             builder: Arc::new(RwLock::new(VersionBuilder {
-                pkgname,
-                version: StructuredVersion { components: Vec::new(), raw: v },
                 release_date: release_date.unwrap_or_default(),
                 release_type: rt,
-                stream: String::new(),
-                pipeline: Vec::new(),
-                exports: Vec::new(),
-                flags: Vec::new(),
-                build_dependencies: Vec::new(),
+                requires_host_build,
+                ..VersionBuilder::new(pkgname, StructuredVersion { components: Vec::new(), raw: v })
             }))
         })
     }
 }
+
+/// `shell=`: an interpreter name run with `-c`, or an argv the command is
+/// appended to.
+fn unpack_interpreter(value: Value) -> anyhow::Result<Interpreter> {
+    if let Some(s) = value.unpack_str() {
+        if s.is_empty() {
+            anyhow::bail!("run() shell must not be empty");
+        }
+        return Ok(Interpreter::named(s));
+    }
+    if let Some(list) = ListRef::from_value(value) {
+        let argv = list.iter()
+            .map(|v| v.unpack_str().map(|s| s.to_string()).context("run() shell argv must be strings"))
+            .collect::<anyhow::Result<Vec<String>>>()?;
+        return Interpreter::argv(argv);
+    }
+    anyhow::bail!("run() expects shell to be a name or an argv list, got {}", value.get_type())
+}
+
+/// Accepts either a single URL string or a list of URL strings.
+fn unpack_urls(value: Value) -> anyhow::Result<Vec<String>> {
+    if let Some(s) = value.unpack_str() {
+        return Ok(vec![s.to_string()]);
+    }
+    if let Some(list) = ListRef::from_value(value) {
+        return list.iter()
+            .map(|v| v.unpack_str().map(|s| s.to_string()).context("fetch() urls must be strings"))
+            .collect();
+    }
+    anyhow::bail!("fetch() expects a url string or a list of urls, got {}", value.get_type())
+}
@@ -119,10 +119,10 @@ fn xml_node_methods(builder: &mut MethodsBuilder) {
         let this = this.downcast_ref::<XmlNode>().context("not an XmlNode")?;
         let mut result = Vec::new();
         for node in &this.element.children {
-            if let xmltree::XMLNode::Element(el) = node {
-                if el.name == name {
-                    result.push(heap.alloc(XmlNode { element: el.clone() }));
-                }
+            if let xmltree::XMLNode::Element(el) = node
+                && el.name == name
+            {
+                result.push(heap.alloc(XmlNode { element: el.clone() }));
             }
         }
         Ok(heap.alloc(result))
@@ -0,0 +1,228 @@
+use super::{create_globals, parse_ast};
+use crate::starlark::api::compat::resolve_api_version;
+use starlark::analysis::AstModuleLint;
+use starlark::codemap::Span;
+use starlark::syntax::AstModule;
+use starlark_syntax::syntax::ast::{ArgumentP, AstExpr, AstStmt, ExprP, StmtP};
+use starlark_syntax::syntax::module::AstModuleFields;
+use starlark_syntax::syntax::top_level_stmts::top_level_stmts;
+use starlark_syntax::syntax::uniplate::Visit;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// Registration functions, with the position of their function argument.
+const REGISTRATIONS: &[(&str, usize)] = &[("add_package", 1), ("add_manager", 1), ("add_dir_package", 3)];
+
+/// A problem `pi devel lint` found in a recipe file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    /// `file:line:column`, or the file alone when it doesn't parse.
+    pub location: String,
+    /// kebab-case name of the check, as `# starlark-lint-disable` takes it.
+    pub check: String,
+    pub message: String,
+}
+
+/// Lints a recipe file without evaluating it: the starlark linter (which
+/// knows the pi API, so it reports undefined globals), then the pi checks.
+/// `root` is the directory being linted, which `lib/` is relative to.
+pub fn lint_file(root: &Path, path: &Path) -> Vec<Problem> {
+    let filename = path.to_string_lossy().into_owned();
+    let whole_file = |check: &str, message: String| vec![Problem { location: filename.clone(), check: check.to_string(), message }];
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => return whole_file("read", e.to_string()),
+    };
+    let api_version = match resolve_api_version(&filename, &content) {
+        Ok(version) => version,
+        Err(e) => return whole_file("api-version", format!("{:#}", e)),
+    };
+    let ast = match parse_ast(&filename, content) {
+        Ok(ast) => ast,
+        Err(e) => return whole_file("parse", e.to_string()),
+    };
+
+    // All of them: those the evaluator would catch anyway (undefined
+    // variables) are found here without running anything.
+    let mut problems: Vec<Problem> = starlark_lints(&ast, api_version).into_iter()
+        .map(|lint| Problem { location: lint.location.to_string(), check: lint.short_name, message: lint.problem })
+        .collect();
+    problems.extend(pi_checks(&ast, is_library(root, path)));
+    problems
+}
+
+/// Files under a `lib` directory of `root` are shared through `load()`: they
+/// needn't register anything, and their functions are used by the recipes
+/// loading them. Directories above `root` (`/var/lib/...`) don't count; a
+/// file linted on its own is a library when its directory is `lib`.
+fn is_library(root: &Path, path: &Path) -> bool {
+    if root.is_file() {
+        return path.parent().and_then(Path::file_name).is_some_and(|name| name == "lib");
+    }
+    path.strip_prefix(root).ok()
+        .and_then(Path::parent)
+        .is_some_and(|dir| dir.components().any(|c| c.as_os_str() == "lib"))
+}
+
+/// The lints of the starlark linter, with the globals of `api_version` known.
+pub(super) fn starlark_lints(ast: &AstModule, api_version: u32) -> Vec<starlark::analysis::Lint> {
+    let globals = create_globals(api_version);
+    let names: HashSet<String> = globals.names().map(|s| s.as_str().to_string()).collect();
+    ast.lint(Some(&names))
+}
+
+/// Checks the linter can't know about: the file registers something, pi can
+/// call what it registers (a function defined at the top level), and every
+/// top-level function is used. Libraries only get the second check.
+fn pi_checks(ast: &AstModule, library: bool) -> Vec<Problem> {
+    let mut defs: HashMap<&str, Span> = HashMap::new();
+    let mut globals: HashSet<&str> = HashSet::new();
+    for stmt in top_level_stmts(ast.statement()) {
+        match &stmt.node {
+            StmtP::Def(def) => {
+                defs.insert(def.name.ident.as_str(), def.name.span);
+            }
+            StmtP::Assign(assign) => assign.lhs.visit_lvalue(|ident| {
+                globals.insert(ident.ident.as_str());
+            }),
+            _ => {}
+        }
+    }
+
+    let mut problems = Vec::new();
+    let mut problem = |span: Span, check: &str, message: String| {
+        if !ast.is_suppressed(check, span) {
+            problems.push(Problem { location: ast.file_span(span).to_string(), check: check.to_string(), message });
+        }
+    };
+    let mut used: HashSet<&str> = HashSet::new();
+    let mut registered = false;
+    walk_stmt(ast.statement(), &mut |expr| match &expr.node {
+        ExprP::Identifier(ident) => {
+            used.insert(ident.ident.as_str());
+        }
+        ExprP::Call(callee, args) => {
+            let ExprP::Identifier(name) = &callee.node else { return };
+            let Some((register, position)) = REGISTRATIONS.iter().find(|(r, _)| *r == name.ident) else { return };
+            registered = true;
+            let function = args.args.iter()
+                .find(|a| matches!(&a.node, ArgumentP::Named(n, _) if n.node == "function"))
+                .or_else(|| args.args.get(*position).filter(|a| matches!(a.node, ArgumentP::Positional(_))));
+            let Some(function) = function.map(|a| a.expr()) else { return };
+            let reachable = match &function.node {
+                ExprP::Identifier(ident) => defs.contains_key(ident.ident.as_str()) || globals.contains(ident.ident.as_str()),
+                _ => false,
+            };
+            if !reachable {
+                problem(function.span, "unreachable-function", format!(
+                    "{}() needs a function defined at the top level of the file, pi can't call `{}`",
+                    register, ast.codemap().source_span(function.span),
+                ));
+            }
+        }
+        _ => {}
+    });
+
+    if library {
+        return problems;
+    }
+    if !registered {
+        problem(Span::default(), "no-registration", "the file registers nothing, call add_package(), add_manager() or add_dir_package()".to_string());
+    }
+    let mut unused: Vec<(&str, Span)> = defs.into_iter()
+        .filter(|(name, _)| !used.contains(name) && !name.starts_with(crate::starlark::api::testing::TEST_PREFIX))
+        .collect();
+    unused.sort_by_key(|(_, span)| span.begin());
+    for (name, span) in unused {
+        problem(span, "unused-function", format!("function `{}` is never registered nor called", name));
+    }
+    problems
+}
+
+fn walk_stmt<'a>(stmt: &'a AstStmt, f: &mut dyn FnMut(&'a AstExpr)) {
+    stmt.visit_children(|child| match child {
+        Visit::Stmt(stmt) => walk_stmt(stmt, f),
+        Visit::Expr(expr) => walk_expr(expr, f),
+    });
+}
+
+fn walk_expr<'a>(expr: &'a AstExpr, f: &mut dyn FnMut(&'a AstExpr)) {
+    f(expr);
+    expr.visit_expr(|child| walk_expr(child, f));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lint_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let recipe = tmp.path().join("tools.star");
+        fs::write(&recipe, r#"pi_api_version(2)
+
+def tool(_p):
+    v = create_version("tool", "1.0")
+    v.register()
+
+def helper():
+    return 1
+
+def test_helper():
+    assert_eq(helper(), 1)
+
+def leftover():
+    return undefined_name
+
+def make():
+    def inner(_p):
+        pass
+    return inner
+
+add_package("tool", tool)
+add_package("made", make())
+add_manager("lam", lambda _p: None)
+"#).unwrap();
+
+        let checks: Vec<(String, String)> = lint_file(tmp.path(), &recipe).into_iter()
+            .map(|p| (p.check, p.location.rsplit_once(".star:").unwrap().1.split(':').next().unwrap().to_string()))
+            .collect();
+        assert!(checks.contains(&("unused-function".to_string(), "13".to_string())), "{:?}", checks);
+        assert!(checks.iter().any(|(c, _)| c == "using-undefined"), "{:?}", checks);
+        let unreachable: Vec<&str> = checks.iter().filter(|(c, _)| c == "unreachable-function").map(|(_, l)| l.as_str()).collect();
+        assert_eq!(unreachable, vec!["22", "23"]);
+        assert!(!checks.iter().any(|(c, _)| c == "no-registration"));
+
+        fs::write(&recipe, "pi_api_version(2)\n\ndef tool(_p):\n    pass\n").unwrap();
+        let checks: Vec<String> = lint_file(tmp.path(), &recipe).into_iter().map(|p| p.check).collect();
+        assert_eq!(checks, vec!["no-registration", "unused-function"]);
+
+        let lib = tmp.path().join("lib/common.star");
+        fs::create_dir_all(lib.parent().unwrap()).unwrap();
+        fs::write(&lib, "pi_api_version(2)\n\ndef tool(_p):\n    pass\n").unwrap();
+        assert!(lint_file(tmp.path(), &lib).is_empty());
+
+        fs::write(&recipe, "def broken(:\n").unwrap();
+        assert_eq!(lint_file(tmp.path(), &recipe)[0].check, "parse");
+    }
+
+    #[test]
+    fn test_library_is_relative_to_root() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("lib/repo");
+        let recipe = root.join("tools.star");
+        let lib = root.join("lib/common.star");
+        fs::create_dir_all(lib.parent().unwrap()).unwrap();
+        fs::write(&recipe, "pi_api_version(2)\n\ndef tool(_p):\n    pass\n").unwrap();
+        fs::write(&lib, "pi_api_version(2)\n\ndef tool(_p):\n    pass\n").unwrap();
+        assert!(!is_library(&root, &recipe));
+        assert!(is_library(&root, &lib));
+        assert!(!is_library(&recipe, &recipe));
+        assert!(is_library(&lib, &lib));
+
+        let checks: Vec<String> = lint_file(&root, &recipe).into_iter().map(|p| p.check).collect();
+        assert_eq!(checks, vec!["no-registration", "unused-function"]);
+        assert!(lint_file(&root, &lib).is_empty());
+    }
+}
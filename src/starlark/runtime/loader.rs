@@ -0,0 +1,110 @@
+use super::{get_context_from_module, prepare_eval_environment, repo_of, EvalExtras};
+use crate::models::config::Config;
+use anyhow::Context as _;
+use starlark::environment::FrozenModule;
+use starlark::eval::{Evaluator, FileLoader};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+/// Resolves the `load()` statements of a recipe: paths are relative to the
+/// root of its repository (its own directory outside one) and may not leave
+/// it. Each file is evaluated once per loader, which also serves its loads.
+pub(super) struct RepoLoader<'a> {
+    root: PathBuf,
+    config: &'a Config,
+    loaded: RefCell<HashMap<PathBuf, FrozenModule>>,
+    /// Files being evaluated, to report cycles.
+    loading: RefCell<Vec<PathBuf>>,
+}
+
+impl<'a> RepoLoader<'a> {
+    pub(super) fn new(config: &'a Config, recipe: &Path) -> Self {
+        let root = match repo_of(config, recipe) {
+            Some((repo, _)) => PathBuf::from(&repo.path),
+            None => recipe.parent().map(Path::to_path_buf).unwrap_or_default(),
+        };
+        let root = root.canonicalize().unwrap_or(root);
+        Self { root, config, loaded: RefCell::new(HashMap::new()), loading: RefCell::new(Vec::new()) }
+    }
+
+    /// The file `load` names, refused when it is outside the root.
+    fn resolve(&self, load: &str) -> anyhow::Result<PathBuf> {
+        let rel = Path::new(load.strip_prefix("//").unwrap_or(load));
+        if rel.as_os_str().is_empty() || !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+            anyhow::bail!("load(\"{}\"): paths are relative to the repository root and may not leave it", load);
+        }
+        let path = self.root.join(rel);
+        let path = path.canonicalize().with_context(|| format!("load(\"{}\"): {} not found", load, path.display()))?;
+        if !path.starts_with(&self.root) {
+            anyhow::bail!("load(\"{}\") leaves the repository", load);
+        }
+        Ok(path)
+    }
+
+    fn load_module(&self, load: &str) -> anyhow::Result<FrozenModule> {
+        let path = self.resolve(load)?;
+        if let Some(module) = self.loaded.borrow().get(&path) {
+            return Ok(module.clone());
+        }
+        if self.loading.borrow().contains(&path) {
+            anyhow::bail!("load(\"{}\") loads itself back", load);
+        }
+        self.loading.borrow_mut().push(path.clone());
+        let module = self.evaluate(&path);
+        self.loading.borrow_mut().pop();
+        let module = module?;
+        self.loaded.borrow_mut().insert(path, module.clone());
+        Ok(module)
+    }
+
+    /// Evaluates a loaded file. It may call the pi API at its top level,
+    /// but registering there would be lost, so it is refused.
+    fn evaluate(&self, path: &Path) -> anyhow::Result<FrozenModule> {
+        let filename = path.to_string_lossy().into_owned();
+        let (ast, globals, module) = prepare_eval_environment(&filename, path, self.config, EvalExtras::default())?;
+        {
+            let mut eval = Evaluator::new(&module);
+            eval.set_loader(self);
+            eval.eval_module(ast, &globals)
+                .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        }
+        let context = get_context_from_module(&module)?;
+        if !context.packages.read().is_empty() || !context.managers.read().is_empty() {
+            anyhow::bail!("{} registers packages or managers, only the recipe loading it may", filename);
+        }
+        Ok(module.freeze()?)
+    }
+}
+
+impl FileLoader for RepoLoader<'_> {
+    fn load(&self, path: &str) -> starlark::Result<FrozenModule> {
+        self.load_module(path).map_err(starlark::Error::new_other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_resolve() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = tmp.path().join("repo");
+        fs::create_dir_all(repo.join("lib")).unwrap();
+        fs::write(repo.join("lib/common.star"), "").unwrap();
+        fs::write(tmp.path().join("outside.star"), "").unwrap();
+        std::os::unix::fs::symlink(tmp.path().join("outside.star"), repo.join("lib/link.star")).unwrap();
+
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let loader = RepoLoader::new(&config, &repo.join("tools.star"));
+        let common = repo.join("lib/common.star").canonicalize().unwrap();
+        assert_eq!(loader.resolve("lib/common.star").unwrap(), common);
+        assert_eq!(loader.resolve("//lib/common.star").unwrap(), common);
+        assert!(loader.resolve("../outside.star").is_err());
+        assert!(loader.resolve("/etc/passwd").is_err());
+        assert!(loader.resolve("lib/link.star").is_err());
+        assert!(loader.resolve("lib/missing.star").is_err());
+    }
+}
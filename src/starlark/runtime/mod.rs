@@ -1,17 +1,27 @@
 use crate::models::config::Config;
-use crate::models::context::Context;
-use crate::models::package_entry::{ManagerEntry, PackageEntry};
-use crate::models::version_entry::VersionEntry;
+use crate::models::context::{CaveInfo, Context};
+use crate::models::package_entry::{DirSource, ManagerEntry, PackageEntry, DIR_MANAGER};
+use crate::models::version_entry::{InstallStep, VersionEntry, VersionList};
+use crate::models::repository::{Repositories, Repository};
 use crate::starlark::api::register_api;
+use crate::starlark::api::compat::resolve_api_version;
+use crate::starlark::api::version::{StarlarkVersionBuilder, VersionBuilder};
+use crate::utils::timings::Phase;
+use loader::RepoLoader;
+use parking_lot::RwLock;
 use anyhow::Context as _;
-use starlark::analysis::AstModuleLint;
 use starlark::environment::{GlobalsBuilder, LibraryExtension, Module};
 use starlark::eval::Evaluator;
 use starlark::syntax::{AstModule, Dialect};
 use starlark::values::ValueLike;
 use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+mod loader;
+pub mod lint;
 
 /// Options for executing a Starlark function.
 pub struct ExecutionOptions<'a> {
@@ -19,18 +29,26 @@ pub struct ExecutionOptions<'a> {
     pub function_name: &'a str,
     pub config: &'a Config,
     pub options: Option<HashMap<String, String>>,
+    /// The saved version list, exposed to discover functions for delta syncs.
+    pub known: Option<&'a VersionList>,
+    /// The cave being built, for `cave_info()`; None outside cave builds.
+    pub cave: Option<&'a CaveInfo>,
 }
 
 /// Evaluates a Starlark file and returns defined packages and managers.
 /// Example path: "recipes/rust.star"
+#[tracing::instrument(skip_all, fields(path = %path.display()))]
 pub fn evaluate_file(
     path: &Path,
     config: &Config,
 ) -> anyhow::Result<(Vec<PackageEntry>, Vec<ManagerEntry>)> {
+    let _timer = config.state.timings.scope(Phase::Evaluation);
     let filename = path.to_string_lossy().into_owned();
-    let (ast, globals, module) = prepare_eval_environment(&filename, path, config, None)?;
+    let (ast, globals, module) = prepare_eval_environment(&filename, path, config, EvalExtras::default())?;
 
+    let loader = RepoLoader::new(config, path);
     let mut eval = Evaluator::new(&module);
+    eval.set_loader(&loader);
     eval.eval_module(ast, &globals)
         .map_err(|e| anyhow::anyhow!("{:?}", e))?;
 
@@ -40,17 +58,21 @@ pub fn evaluate_file(
 }
 
 /// Executes a manager-specific function in a Starlark recipe.
+#[tracing::instrument(skip_all, fields(function = exec_opts.function_name, manager = manager_name, package = package_name))]
 pub fn execute_manager_function(
     exec_opts: ExecutionOptions,
     manager_name: &str,
     package_name: &str,
 ) -> anyhow::Result<Vec<VersionEntry>> {
+    let _timer = exec_opts.config.state.timings.scope(Phase::Evaluation);
     let filename = exec_opts.path.to_string_lossy().into_owned();
     let ctx_name = format!("{}:exec:{}", filename, manager_name);
 
-    let (ast, globals, module) = prepare_eval_environment(&ctx_name, exec_opts.path, exec_opts.config, exec_opts.options)?;
+    let (ast, globals, module) = prepare_eval_environment(&ctx_name, exec_opts.path, exec_opts.config, EvalExtras { options: exec_opts.options, known: exec_opts.known, cave: exec_opts.cave })?;
 
+    let loader = RepoLoader::new(exec_opts.config, exec_opts.path);
     let mut eval = Evaluator::new(&module);
+    eval.set_loader(&loader);
     eval.eval_module(ast, &globals)
         .map_err(|e| anyhow::anyhow!("{:?}", e))?;
 
@@ -64,20 +86,76 @@ pub fn execute_manager_function(
     eval.eval_function(function, &[mgr_val, pkg_val], &[])
         .map_err(|e| anyhow::anyhow!("{:?}", e))?;
 
-    extract_versions(&module)
+    extract_versions(&module, exec_opts.known)
+}
+
+/// Syncs a `dir:` package: calls its function with a version builder per
+/// matching file, already set to the parsed version, the file's date and a
+/// Fetch of the file.
+#[tracing::instrument(skip_all, fields(function = exec_opts.function_name, package = package_name))]
+pub fn execute_dir_function(
+    exec_opts: ExecutionOptions,
+    source: &DirSource,
+    package_name: &str,
+) -> anyhow::Result<Vec<VersionEntry>> {
+    let _timer = exec_opts.config.state.timings.scope(Phase::Evaluation);
+    let filename = exec_opts.path.to_string_lossy().into_owned();
+    let ctx_name = format!("{}:exec:{}", filename, DIR_MANAGER);
+    let recipe_dir = exec_opts.path.parent().unwrap_or(Path::new("."));
+    let artifacts = source.scan(recipe_dir)?;
+
+    let (ast, globals, module) = prepare_eval_environment(&ctx_name, exec_opts.path, exec_opts.config, EvalExtras { options: exec_opts.options, known: None, cave: exec_opts.cave })?;
+
+    let loader = RepoLoader::new(exec_opts.config, exec_opts.path);
+    let mut eval = Evaluator::new(&module);
+    eval.set_loader(&loader);
+    eval.eval_module(ast, &globals)
+        .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+
+    let function = module.get(exec_opts.function_name).context(format!(
+        "Function '{}' not found in module '{}'",
+        exec_opts.function_name, filename
+    ))?;
+
+    let pkgname = format!("{}:{}", DIR_MANAGER, package_name);
+    for artifact in artifacts {
+        let inspected = crate::utils::inspect::inspect_version(&artifact.version);
+        let mut builder = VersionBuilder::new(pkgname.clone(), inspected.version);
+        builder.release_type = inspected.release_type;
+        builder.release_date = artifact.date;
+        builder.pipeline.push(InstallStep::Fetch {
+            url: format!("file://{}", artifact.path.display()),
+            mirrors: Vec::new(),
+            checksum: None,
+            filename: None,
+            name: None,
+            signature: None,
+            stream: false,
+        });
+        let v = eval.heap().alloc(StarlarkVersionBuilder { builder: Arc::new(RwLock::new(builder)) });
+        let file = eval.heap().alloc(artifact.path.to_string_lossy().as_ref());
+        eval.eval_function(function, &[v, file], &[])
+            .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+    }
+
+    extract_versions(&module, None)
 }
 
 /// Executes a generic package function in a Starlark recipe.
+#[tracing::instrument(skip_all, fields(function = exec_opts.function_name, argument = argument))]
 pub fn execute_function(
     exec_opts: ExecutionOptions,
     argument: &str,
 ) -> anyhow::Result<Vec<VersionEntry>> {
+    let _timer = exec_opts.config.state.timings.scope(Phase::Evaluation);
     let filename = exec_opts.path.to_string_lossy().into_owned();
     let ctx_name = format!("{}:exec", filename);
 
-    let (ast, globals, module) = prepare_eval_environment(&ctx_name, exec_opts.path, exec_opts.config, exec_opts.options)?;
+    let (ast, globals, module) = prepare_eval_environment(&ctx_name, exec_opts.path, exec_opts.config, EvalExtras { options: exec_opts.options, known: exec_opts.known, cave: exec_opts.cave })?;
 
+    let loader = RepoLoader::new(exec_opts.config, exec_opts.path);
     let mut eval = Evaluator::new(&module);
+    eval.set_loader(&loader);
     eval.eval_module(ast, &globals)
         .map_err(|e| anyhow::anyhow!("{:?}", e))?;
 
@@ -90,26 +168,73 @@ pub fn execute_function(
     eval.eval_function(function, &[arg_value], &[])
         .map_err(|e| anyhow::anyhow!("{:?}", e))?;
 
-    extract_versions(&module)
+    extract_versions(&module, exec_opts.known)
+}
+
+/// Outcome of one recipe self-test: its function name and the failure, if any.
+pub type TestOutcome = (String, Result<(), String>);
+
+/// Evaluates a recipe file and calls each of its top-level `test_*`
+/// functions without arguments, in name order. Failing tests don't stop the
+/// others; an evaluation error of the file itself is returned as Err.
+#[tracing::instrument(skip_all, fields(path = %path.display()))]
+pub fn run_recipe_tests(path: &Path, config: &Config) -> anyhow::Result<Vec<TestOutcome>> {
+    let _timer = config.state.timings.scope(Phase::Evaluation);
+    let filename = path.to_string_lossy().into_owned();
+    let (ast, globals, module) = prepare_eval_environment(&filename, path, config, EvalExtras::default())?;
+
+    let loader = RepoLoader::new(config, path);
+    let mut eval = Evaluator::new(&module);
+    eval.set_loader(&loader);
+    eval.eval_module(ast, &globals)
+        .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+
+    let mut names: Vec<String> = module.names()
+        .map(|n| n.as_str().to_string())
+        .filter(|n| n.starts_with(crate::starlark::api::testing::TEST_PREFIX))
+        .collect();
+    names.sort();
+
+    let mut outcomes = Vec::new();
+    for name in names {
+        let Some(function) = module.get(&name) else { continue };
+        if function.get_type() != "function" {
+            continue;
+        }
+        let result = eval.eval_function(function, &[], &[])
+            .map(|_| ())
+            .map_err(|e| format!("{}", e));
+        outcomes.push((name, result));
+    }
+    Ok(outcomes)
+}
+
+/// What a function execution adds to the recipe's Context.
+#[derive(Default)]
+struct EvalExtras<'a> {
+    options: Option<HashMap<String, String>>,
+    known: Option<&'a VersionList>,
+    cave: Option<&'a CaveInfo>,
 }
 
 /// Prepares the common Starlark evaluation environment.
-fn prepare_eval_environment<'a>(
+fn prepare_eval_environment(
     ctx_name: &str,
     path: &Path,
     config: &Config,
-    options: Option<HashMap<String, String>>,
+    extras: EvalExtras,
 ) -> anyhow::Result<(AstModule, starlark::environment::Globals, Module)> {
     let content = fs::read_to_string(path)
         .with_context(|| format!("Failed to read file: {}", path.display()))?;
 
+    let api_version = resolve_api_version(ctx_name, &content)?;
     let ast = parse_ast(ctx_name, content)?;
-    lint_ast(ctx_name, &ast);
+    lint_ast(ctx_name, &ast, api_version);
 
-    let globals = create_globals();
+    let globals = create_globals(api_version);
     let module = Module::new();
 
-    setup_context(&module, ctx_name.to_string(), config, options);
+    setup_context(&module, ctx_name.to_string(), path, config, extras);
 
     Ok((ast, globals, module))
 }
@@ -118,27 +243,20 @@ fn parse_ast(filename: &str, content: String) -> anyhow::Result<AstModule> {
     AstModule::parse(filename, content, &Dialect::Extended).map_err(|e| anyhow::anyhow!("{}", e))
 }
 
-fn lint_ast(filename: &str, ast: &AstModule) {
-    let globals = create_globals();
-    let names: std::collections::HashSet<String> = globals.names().map(|s| s.as_str().to_string()).collect();
-    for lint in ast.lint(Some(&names)) {
+fn lint_ast(filename: &str, ast: &AstModule, api_version: u32) {
+    for lint in lint::starlark_lints(ast, api_version) {
         log::warn!("[{}] lint: {} ({})", filename, lint.problem, lint.location);
     }
 }
 
-fn create_globals() -> starlark::environment::Globals {
+fn create_globals(api_version: u32) -> starlark::environment::Globals {
     let mut builder =
         GlobalsBuilder::extended_by(&[LibraryExtension::Print, LibraryExtension::Json]);
-    register_api(&mut builder);
+    register_api(&mut builder, api_version);
     builder.build()
 }
 
-fn setup_context(
-    module: &Module,
-    filename: String,
-    config: &Config,
-    options: Option<HashMap<String, String>>,
-) {
+fn setup_context(module: &Module, filename: String, path: &Path, config: &Config, extras: EvalExtras) {
     let mut context = Context::new(
         filename,
         config.cache_meta_dir.clone(),
@@ -146,14 +264,44 @@ fn setup_context(
         config.cache_packages_dir.clone(),
         config.force,
         config.state.clone(),
-    );
-    if let Some(opts) = options {
+    )
+    .with_state_scope(config.state_dir.clone(), state_scope(config, path));
+    context.host_policy = repo_of(config, path).and_then(|(repo, _)| repo.host_policy());
+    if let Some(opts) = extras.options {
         context = context.with_options(opts);
     }
+    if let Some(list) = extras.known {
+        context = context.with_known(list);
+    }
+    if let Some(cave) = extras.cave {
+        context.cave = Some(cave.clone());
+    }
+    context.os = config.target.os;
+    context.arch = config.target.arch;
     let context_value = module.heap().alloc_simple(context);
     module.set_extra_value(context_value);
 }
 
+/// "<repo>/<path within the repo>" for recipes of a registered repo,
+/// "local/<file name>" for others.
+fn state_scope(config: &Config, path: &Path) -> String {
+    if let Some((repo, rel)) = repo_of(config, path) {
+        return format!("{}/{}", repo.name, rel.display());
+    }
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    format!("local/{}", name)
+}
+
+/// The registered repo `path` belongs to, and the path within it.
+fn repo_of<'a>(config: &'a Config, path: &Path) -> Option<(&'a Repository, PathBuf)> {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    Repositories::get_all(config).repositories.iter().find_map(|repo| {
+        let root = Path::new(&repo.path);
+        let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+        path.strip_prefix(&root).ok().map(|rel| (repo, rel.to_path_buf()))
+    })
+}
+
 fn extract_packages(module: &Module) -> anyhow::Result<Vec<PackageEntry>> {
     let context = get_context_from_module(module)?;
     Ok(context.packages.read().clone())
@@ -164,9 +312,23 @@ pub fn extract_managers(module: &Module) -> anyhow::Result<Vec<ManagerEntry>> {
     Ok(context.managers.read().clone())
 }
 
-fn extract_versions(module: &Module) -> anyhow::Result<Vec<VersionEntry>> {
+/// Returns the versions collected by a discover function. When the function
+/// called `set_incremental()`, they are merged into the known list.
+fn extract_versions(module: &Module, known: Option<&VersionList>) -> anyhow::Result<Vec<VersionEntry>> {
     let context = get_context_from_module(module)?;
-    Ok(context.versions.read().clone())
+    let versions = context.versions.read().clone();
+    if versions.is_empty() && context.fetch_failed.load(Ordering::Relaxed) {
+        anyhow::bail!("[{}] no versions found after a failed download", context.display_name());
+    }
+    match known {
+        Some(list) if context.incremental.load(Ordering::Relaxed) => {
+            log::debug!("[{}] delta sync: {} new versions", context.display_name(), versions.len());
+            let mut merged = list.clone();
+            merged.merge(versions);
+            Ok(merged.versions)
+        }
+        _ => Ok(versions),
+    }
 }
 
 fn get_context_from_module(module: &Module) -> anyhow::Result<&Context> {
@@ -196,9 +358,19 @@ mod tests {
             cache_download_dir: download_dir,
             cache_packages_dir: packages_dir,
             cache_pilocals_dir: PathBuf::new(),
+            global_dir: PathBuf::new(),
             force: false,
             rebuild: false,
             no_sync: false,
+            offline: false,
+            keep_build_home: false,
+            allow_host_build: false,
+            strict: false,
+            target: Default::default(),
+            sort: None,
+            cave: None,
+            cave_variant: None,
+            settings: Default::default(),
             state: Arc::new(State::default()),
         }
     }
@@ -224,6 +396,8 @@ mod tests {
                 function_name: &packages[0].function_name,
                 config: &config,
                 options: None,
+                known: None,
+                cave: None,
             },
             "vlc-player",
         )
@@ -231,6 +405,164 @@ mod tests {
         assert_eq!(versions.len(), 0);
     }
 
+    #[test]
+    fn test_dir_package() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        std::fs::create_dir_all(tmp.path().join("vendor")).unwrap();
+        std::fs::write(tmp.path().join("vendor/tool-2.1.0-linux.tar.gz"), "x").unwrap();
+        std::fs::write(tmp.path().join("vendor/tool-nightly.tar.gz"), "x").unwrap();
+        let recipe = tmp.path().join("tool.star");
+        std::fs::write(&recipe, r#"
+def tool(v, file):
+    v.extract()
+    v.export_link("bin/tool", "bin/tool")
+    v.register()
+
+add_dir_package("tool", "vendor", r"tool-(?P<version>[0-9.]+)-linux\.tar\.gz", tool)
+"#).unwrap();
+
+        let (_, managers) = evaluate_file(&recipe, &config).unwrap();
+        assert_eq!(managers[0].name, "dir:tool");
+        let source = managers[0].dir.as_ref().unwrap();
+        let versions = execute_dir_function(ExecutionOptions {
+            path: &recipe,
+            function_name: &managers[0].function_name,
+            config: &config,
+            options: None,
+            known: None,
+            cave: None,
+        }, source, "tool").unwrap();
+
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].pkgname, "dir:tool");
+        assert_eq!(versions[0].version.raw, "2.1.0");
+        match &versions[0].pipeline[0] {
+            InstallStep::Fetch { url, .. } => assert!(url.starts_with("file:///") && url.ends_with("/vendor/tool-2.1.0-linux.tar.gz")),
+            step => panic!("unexpected step {:?}", step),
+        }
+        assert!(matches!(versions[0].pipeline[1], InstallStep::Extract { .. }));
+    }
+
+    #[test]
+    fn test_cave_info() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let recipe = tmp.path().join("pip.star");
+        std::fs::write(&recipe, r#"
+def pip(_manager, package):
+    info = cave_info()
+    v = create_version("pip:" + package, "1.0")
+    if info:
+        python = [p for p in info.packages if p.startswith("python")]
+        v.set_stream(info.name + (info.variant or "") + ":" + python[0])
+    v.register()
+
+add_manager("pip", pip)
+"#).unwrap();
+        let run = |cave: Option<&CaveInfo>| execute_manager_function(ExecutionOptions {
+            path: &recipe,
+            function_name: "pip",
+            config: &config,
+            options: None,
+            known: None,
+            cave,
+        }, "pip", "requests").unwrap();
+
+        assert_eq!(run(None)[0].stream, "");
+        let cave = CaveInfo {
+            name: "web".to_string(),
+            variant: Some(":dev".to_string()),
+            packages: vec!["python=3.12".to_string(), "pip:requests".to_string()],
+            workspace: tmp.path().to_path_buf(),
+        };
+        assert_eq!(run(Some(&cave))[0].stream, "web:dev:python=3.12");
+    }
+
+    #[test]
+    fn test_failed_download_is_not_a_miss() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let recipe = tmp.path().join("npm.star");
+        std::fs::write(&recipe, r#"
+def npm(_manager, package):
+    if package == "offline" and not download("http://127.0.0.1:1/" + package):
+        return
+    if package == "left-pad":
+        create_version("npm:" + package, "1.3.0").register()
+
+add_manager("npm", npm)
+"#).unwrap();
+        let run = |package: &str| execute_manager_function(ExecutionOptions {
+            path: &recipe,
+            function_name: "npm",
+            config: &config,
+            options: None,
+            known: None,
+            cave: None,
+        }, "npm", package);
+
+        assert_eq!(run("left-pad").unwrap().len(), 1);
+        assert!(run("missing").unwrap().is_empty());
+        let err = run("offline").unwrap_err();
+        assert!(err.to_string().contains("no versions found after a failed download"));
+    }
+
+    #[test]
+    fn test_file_patches_need_a_dir_package() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let recipe = tmp.path().join("patches.star");
+        std::fs::write(&recipe, "create_version(\"dir:tool\", \"1.0\").patch(\"file:///vendor/fix.patch\")\n").unwrap();
+        evaluate_file(&recipe, &config).unwrap();
+
+        std::fs::write(&recipe, "create_version(\"tool\", \"1.0\").patch(\"file:///etc/passwd\")\n").unwrap();
+        let err = evaluate_file(&recipe, &config).unwrap_err();
+        assert!(format!("{:#}", err).contains("only in dir: packages"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_url_builtins() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let recipe = tmp.path().join("urls.star");
+        std::fs::write(&recipe, r#"
+assert_eq(url_join("https://x.org/api/", "v1/pkg"), "https://x.org/api/v1/pkg")
+assert_eq(url_join("https://x.org/dist/index.html", "go 1.tgz"), "https://x.org/dist/go%201.tgz")
+assert_eq(url_join("https://x.org/a/b", "/c?d=1"), "https://x.org/c?d=1")
+assert_eq(url_encode({"q": "a b&c", "n": 2, "arch": ["x64", "arm64"]}), "q=a+b%26c&n=2&arch=x64&arch=arm64")
+u = url_parse("https://user@registry.example.com:8443/v2/pkg?name=a%20b&tag=1&tag=2#top")
+assert_eq([u.scheme, u.host, u.port, u.path, u.fragment], ["https", "registry.example.com", 8443, "/v2/pkg", "top"])
+assert_eq(u.query, {"name": "a b", "tag": "2"})
+assert_eq(url_parse("https://x.org").port, None)
+"#).unwrap();
+        evaluate_file(&recipe, &config).unwrap();
+
+        std::fs::write(&recipe, "url_encode({\"a\": None})\n").unwrap();
+        assert!(evaluate_file(&recipe, &config).is_err());
+    }
+
+    #[test]
+    fn test_semver_builtins() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        let recipe = tmp.path().join("semver.star");
+        std::fs::write(&recipe, r#"
+v = semver_parse("v1.22-rc.1+linux")
+assert_eq([v.major, v.minor, v.patch, v.pre, v.build], [1, 22, 0, "rc.1", "linux"])
+assert_eq(semver_parse("weekly-2024"), None)
+assert_eq([semver_compare("1.10", "1.9"), semver_compare("1.2.0-rc.1", "1.2"), semver_compare("v2", "2.0.0")], [1, -1, 0])
+assert_true(semver_satisfies("1.21.5", ">=1.20, <2"))
+assert_true(not semver_satisfies("2.0.0", "^1.2"))
+assert_eq(semver_sort(["1.10.0", "v1.9", "1.10.0-beta"]), ["v1.9", "1.10.0-beta", "1.10.0"])
+assert_eq(semver_sort(["1.0", "2.0"], reverse = True), ["2.0", "1.0"])
+"#).unwrap();
+        evaluate_file(&recipe, &config).unwrap();
+
+        std::fs::write(&recipe, "semver_sort([\"1.0\", \"nightly\"])\n").unwrap();
+        assert!(evaluate_file(&recipe, &config).is_err());
+    }
+
     #[test]
     fn test_extract() {
         let config = create_test_config(
@@ -258,6 +590,8 @@ mod tests {
                 function_name: &packages[0].function_name,
                 config: &config,
                 options: None,
+                known: None,
+                cave: None,
             },
             "",
         ).unwrap();
@@ -288,6 +622,8 @@ mod tests {
                 function_name: &packages[0].function_name,
                 config: &config,
                 options: None,
+                known: None,
+                cave: None,
             },
             "",
         ).unwrap();
@@ -321,8 +657,248 @@ mod tests {
                 function_name: &packages[0].function_name,
                 config: &config,
                 options: None,
+                known: None,
+                cave: None,
             },
             "",
         ).unwrap();
     }
+
+    #[test]
+    fn test_fetch_mirrors() {
+        let config = create_test_config(
+            PathBuf::from("/tmp/pi-test-meta-mirrors"),
+            PathBuf::from("/tmp/pi-test-downloads-mirrors"),
+            PathBuf::from("/tmp/pi-test-packages-mirrors")
+        );
+
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "def test(arg):").unwrap();
+        writeln!(file, "    v = create_version('tool')").unwrap();
+        writeln!(file, "    v.inspect('1.0.0')").unwrap();
+        writeln!(file, "    v.fetch(['https://a/t.tgz', 'https://b/t.tgz'], mirrors=['https://c/t.tgz'])").unwrap();
+        writeln!(file, "    v.register()").unwrap();
+        writeln!(file, "add_package('tool', test)").unwrap();
+
+        let (packages, _) = evaluate_file(file.path(), &config).unwrap();
+        let versions = execute_function(
+            ExecutionOptions {
+                path: file.path(),
+                function_name: &packages[0].function_name,
+                config: &config,
+                options: None,
+                known: None,
+                cave: None,
+            },
+            "tool",
+        ).unwrap();
+        match &versions[0].pipeline[0] {
+            crate::models::version_entry::InstallStep::Fetch { url, mirrors, .. } => {
+                assert_eq!(url, "https://a/t.tgz");
+                assert_eq!(mirrors, &vec!["https://b/t.tgz".to_string(), "https://c/t.tgz".to_string()]);
+            }
+            other => panic!("unexpected step: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_api_version_shims() {
+        let config = create_test_config(
+            PathBuf::from("/tmp/pi-test-meta-api"),
+            PathBuf::from("/tmp/pi-test-downloads-api"),
+            PathBuf::from("/tmp/pi-test-packages-api")
+        );
+
+        let mut v2 = NamedTempFile::new().unwrap();
+        writeln!(v2, "pi_api_version(2)").unwrap();
+        writeln!(v2, "ok, name = re_match(r'([a-z]+)', 'abc')").unwrap();
+        assert!(evaluate_file(v2.path(), &config).is_ok());
+
+        let mut v2_old = NamedTempFile::new().unwrap();
+        writeln!(v2_old, "pi_api_version(2)").unwrap();
+        writeln!(v2_old, "ok, name = extract(r'([a-z]+)', 'abc')").unwrap();
+        assert!(evaluate_file(v2_old.path(), &config).is_err());
+
+        let mut unsupported = NamedTempFile::new().unwrap();
+        writeln!(unsupported, "pi_api_version(99)").unwrap();
+        assert!(evaluate_file(unsupported.path(), &config).is_err());
+    }
+
+    #[test]
+    fn test_incremental_sync_merges_known() {
+        let config = create_test_config(
+            PathBuf::from("/tmp/pi-test-meta-delta"),
+            PathBuf::from("/tmp/pi-test-downloads-delta"),
+            PathBuf::from("/tmp/pi-test-packages-delta")
+        );
+
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "def test(arg):").unwrap();
+        writeln!(file, "    if known_latest() != '1.1.0': fail('bad high-water mark: ' + str(known_latest()))").unwrap();
+        writeln!(file, "    set_incremental()").unwrap();
+        writeln!(file, "    for ver in ['1.1.0', '1.2.0']:").unwrap();
+        writeln!(file, "        v = create_version('tool')").unwrap();
+        writeln!(file, "        v.inspect(ver)").unwrap();
+        writeln!(file, "        v.register()").unwrap();
+        writeln!(file, "add_package('tool', test)").unwrap();
+
+        let known_entry = |raw: &str| VersionEntry {
+            pkgname: "tool".to_string(),
+            version: crate::utils::inspect::inspect_version(raw).version,
+            ..Default::default()
+        };
+        let known = VersionList { versions: vec![known_entry("1.1.0"), known_entry("1.0.0")] };
+
+        let (packages, _) = evaluate_file(file.path(), &config).unwrap();
+        let versions = execute_function(
+            ExecutionOptions {
+                path: file.path(),
+                function_name: &packages[0].function_name,
+                config: &config,
+                options: None,
+                known: Some(&known),
+                cave: None,
+            },
+            "tool",
+        ).unwrap();
+        let raws: Vec<&str> = versions.iter().map(|v| v.version.raw.as_str()).collect();
+        assert_eq!(raws, vec!["1.1.0", "1.2.0", "1.0.0"]);
+    }
+
+    #[test]
+    fn test_download_binary_from_cache() {
+        use std::io::Write as _;
+        let meta = PathBuf::from("/tmp/pi-test-meta-bytes");
+        let config = create_test_config(
+            meta.clone(),
+            PathBuf::from("/tmp/pi-test-downloads-bytes"),
+            PathBuf::from("/tmp/pi-test-packages-bytes")
+        );
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(b"hello").unwrap();
+        let data = gz.finish().unwrap();
+        let url = "https://example.invalid/hello.gz";
+        crate::services::cache::Cache::new(meta, std::time::Duration::from_secs(60)).write(&format!("binary:{}", url), &data).unwrap();
+
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "def test(arg):").unwrap();
+        writeln!(file, "    b = download_binary('{}')", url).unwrap();
+        writeln!(file, "    if len(b) != {} or b.len() != {}: fail('bad length')", data.len(), data.len()).unwrap();
+        writeln!(file, "    if b.gunzip().decode() != 'hello': fail('bad content')").unwrap();
+        writeln!(file, "    if b.gunzip().sha256() != '2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824': fail('bad digest')").unwrap();
+        writeln!(file, "add_package('test', test)").unwrap();
+
+        let (packages, _) = evaluate_file(file.path(), &config).unwrap();
+        execute_function(
+            ExecutionOptions {
+                path: file.path(),
+                function_name: &packages[0].function_name,
+                config: &config,
+                options: None,
+                known: None,
+                cave: None,
+            },
+            "",
+        ).unwrap();
+    }
+
+    #[test]
+    fn test_recipe_state() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut config = create_test_config(tmp.path().join("meta"), tmp.path().join("downloads"), tmp.path().join("packages"));
+        config.state_dir = tmp.path().join("state");
+
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "def discover(arg):").unwrap();
+        writeln!(file, "    n = int(state_get('runs', '0'))").unwrap();
+        writeln!(file, "    state_set('runs', str(n + 1))").unwrap();
+        writeln!(file, "    state_set('gone', None)").unwrap();
+        writeln!(file, "add_package('test', discover)").unwrap();
+
+        let (packages, _) = evaluate_file(file.path(), &config).unwrap();
+        for _ in 0..2 {
+            execute_function(
+                ExecutionOptions {
+                    path: file.path(),
+                    function_name: &packages[0].function_name,
+                    config: &config,
+                    options: None,
+                    known: None,
+                    cave: None,
+                },
+                "",
+            ).unwrap();
+        }
+        let scope = format!("local/{}", file.path().file_name().unwrap().to_string_lossy());
+        assert_eq!(config.db().unwrap().state_get(&scope, "runs").unwrap().as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn test_recipe_state_needs_the_db() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = create_test_config(tmp.path().join("meta"), tmp.path().join("downloads"), tmp.path().join("packages"));
+        config.state.db.set(None).unwrap();
+
+        for body in ["state_set('cursor', '1')", "state_get('cursor', '0')"] {
+            let mut file = NamedTempFile::new().unwrap();
+            writeln!(file, "def discover(arg):").unwrap();
+            writeln!(file, "    {}", body).unwrap();
+            writeln!(file, "add_package('test', discover)").unwrap();
+            let (packages, _) = evaluate_file(file.path(), &config).unwrap();
+            let err = execute_function(
+                ExecutionOptions {
+                    path: file.path(),
+                    function_name: &packages[0].function_name,
+                    config: &config,
+                    options: None,
+                    known: None,
+                    cave: None,
+                },
+                "",
+            ).unwrap_err();
+            assert!(format!("{:#}", err).contains("state database is unavailable"), "{:#}", err);
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::new_test(tmp.path().to_path_buf());
+        std::fs::create_dir_all(tmp.path().join("lib")).unwrap();
+        std::fs::write(tmp.path().join("lib/common.star"), r#"pi_api_version(2)
+
+def simple(name, version):
+    v = create_version(name, version)
+    v.export_link("bin/" + name, "bin/" + name)
+    v.register()
+"#).unwrap();
+        std::fs::write(tmp.path().join("lib/registers.star"), "add_package(\"x\", lambda _p: None)\n").unwrap();
+        let recipe = tmp.path().join("tool.star");
+        std::fs::write(&recipe, r#"pi_api_version(2)
+load("lib/common.star", "simple")
+
+def tool(_p):
+    simple("tool", "1.0")
+
+add_package("tool", tool)
+"#).unwrap();
+
+        let (packages, _) = evaluate_file(&recipe, &config).unwrap();
+        let versions = execute_function(ExecutionOptions {
+            path: &recipe,
+            function_name: &packages[0].function_name,
+            config: &config,
+            options: None,
+            known: None,
+            cave: None,
+        }, "").unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].pkgname, "tool");
+
+        for (load, error) in [("../tool.star", "may not leave it"), ("lib/registers.star", "only the recipe loading it may")] {
+            std::fs::write(&recipe, format!("load(\"{}\", \"x\")\n", load)).unwrap();
+            let err = format!("{:#}", evaluate_file(&recipe, &config).unwrap_err());
+            assert!(err.contains(error), "{}", err);
+        }
+    }
 }
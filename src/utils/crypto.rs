@@ -9,47 +9,55 @@ use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 
 pub fn calculate_file_checksum(path: &Path, expected_len: usize) -> Result<String> {
+    let mut hasher = StreamHasher::for_len(expected_len)?;
     let mut file = File::open(path)?;
     let mut buffer = [0; 8192];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher.finish())
+}
+
+/// Incremental checksum, with the algorithm picked by the length of the hex
+/// digest expected, like `calculate_file_checksum`.
+pub enum StreamHasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
 
-    match expected_len {
-        40 => {
-            let mut hasher = Sha1::new();
-            loop {
-                let n = file.read(&mut buffer)?;
-                if n == 0 {
-                    break;
-                }
-                hasher.update(&buffer[..n]);
-            }
-            Ok(hex::encode(hasher.finalize()))
+impl StreamHasher {
+    pub fn for_len(expected_len: usize) -> Result<Self> {
+        match expected_len {
+            40 => Ok(Self::Sha1(Sha1::new())),
+            64 => Ok(Self::Sha256(Sha256::new())),
+            128 => Ok(Self::Sha512(Sha512::new())),
+            _ => Err(anyhow::anyhow!(
+                "Unsupported checksum length: {}. Expected 40 (SHA-1), 64 (SHA-256), or 128 (SHA-512).",
+                expected_len
+            )),
         }
-        64 => {
-            let mut hasher = Sha256::new();
-            loop {
-                let n = file.read(&mut buffer)?;
-                if n == 0 {
-                    break;
-                }
-                hasher.update(&buffer[..n]);
-            }
-            Ok(hex::encode(hasher.finalize()))
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha1(h) => h.update(data),
+            Self::Sha256(h) => h.update(data),
+            Self::Sha512(h) => h.update(data),
         }
-        128 => {
-            let mut hasher = Sha512::new();
-            loop {
-                let n = file.read(&mut buffer)?;
-                if n == 0 {
-                    break;
-                }
-                hasher.update(&buffer[..n]);
-            }
-            Ok(hex::encode(hasher.finalize()))
+    }
+
+    /// The hex digest.
+    pub fn finish(self) -> String {
+        match self {
+            Self::Sha1(h) => hex::encode(h.finalize()),
+            Self::Sha256(h) => hex::encode(h.finalize()),
+            Self::Sha512(h) => hex::encode(h.finalize()),
         }
-        _ => Err(anyhow::anyhow!(
-            "Unsupported checksum length: {}. Expected 40 (SHA-1), 64 (SHA-256), or 128 (SHA-512).",
-            expected_len
-        )),
     }
 }
 
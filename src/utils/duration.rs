@@ -0,0 +1,61 @@
+const MINUTE: u64 = 60;
+const HOUR: u64 = MINUTE * 60;
+const DAY: u64 = HOUR * 24;
+const WEEK: u64 = DAY * 7;
+
+/// Parses a human duration such as "90s", "12h", "30d" or "2w" into seconds.
+pub fn parse_duration(s: &str) -> anyhow::Result<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: u64 = num.parse().map_err(|_| anyhow::anyhow!("Invalid duration: '{}'", s))?;
+    let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "s" => 1,
+        "m" | "min" => MINUTE,
+        "h" => HOUR,
+        "d" => DAY,
+        "w" => WEEK,
+        other => anyhow::bail!("Invalid duration unit '{}' in '{}'", other, s),
+    };
+    Ok(num * multiplier)
+}
+
+/// Formats an age in seconds for humans, using its largest unit, e.g. "3d".
+pub fn format_age(secs: u64) -> String {
+    if secs >= DAY {
+        format!("{}d", secs / DAY)
+    } else if secs >= HOUR {
+        format!("{}h", secs / HOUR)
+    } else if secs >= MINUTE {
+        format!("{}m", secs / MINUTE)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Formats a run time in milliseconds, e.g. "850ms", "1.5s" or "1m01s".
+pub fn format_duration(ms: u128) -> String {
+    if ms < 1000 {
+        format!("{}ms", ms)
+    } else if ms < 60_000 {
+        format!("{:.1}s", ms as f64 / 1000.0)
+    } else {
+        format!("{}m{:02}s", ms / 60_000, ms % 60_000 / 1000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap(), 90);
+        assert_eq!(parse_duration("12h").unwrap(), 12 * HOUR);
+        assert_eq!(parse_duration(" 30d ").unwrap(), 30 * DAY);
+        assert_eq!(parse_duration("2W").unwrap(), 2 * WEEK);
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("3y").is_err());
+        assert_eq!(format_age(3 * DAY + HOUR), "3d");
+    }
+}
@@ -1,3 +1,64 @@
 pub fn sanitize_name(name: &str) -> String {
     name.replace(['/', '\\', ' ', ':'], "_")
 }
+
+/// Free bytes for unprivileged users on the filesystem holding `path` (or
+/// its nearest existing ancestor); None when it can't be determined.
+pub fn available_space(path: &std::path::Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let existing = path.ancestors().find(|p| p.exists())?;
+    let c_path = std::ffi::CString::new(existing.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: c_path is a valid NUL-terminated string and stat is only read after success.
+    if unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let stat = unsafe { stat.assume_init() };
+    // The field types differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    Some((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+/// Opens (creating) `path` and takes an advisory lock on it, shared or
+/// exclusive; it is held until the returned file is dropped. A lock another
+/// process holds is waited for, after logging `waiting`.
+pub fn lock_file(path: &std::path::Path, shared: bool, waiting: &str) -> anyhow::Result<std::fs::File> {
+    use anyhow::Context;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let acquired = if shared { file.try_lock_shared() } else { file.try_lock() };
+    match acquired {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) => {
+            log::info!("{}", waiting);
+            if shared { file.lock_shared() } else { file.lock() }
+                .with_context(|| format!("Failed to lock {}", path.display()))?;
+        }
+        Err(std::fs::TryLockError::Error(e)) => {
+            return Err(e).with_context(|| format!("Failed to lock {}", path.display()));
+        }
+    }
+    Ok(file)
+}
+
+/// Copies the tree at `src` into `dest`, keeping symlinks as links.
+pub fn copy_tree(src: &std::path::Path, dest: &std::path::Path) -> anyhow::Result<()> {
+    use anyhow::Context;
+    for entry in walkdir::WalkDir::new(src) {
+        let entry = entry?;
+        let target = dest.join(entry.path().strip_prefix(src)?);
+        let kind = entry.file_type();
+        if kind.is_dir() {
+            std::fs::create_dir_all(&target)
+        } else if kind.is_symlink() {
+            std::os::unix::fs::symlink(std::fs::read_link(entry.path())?, &target)
+        } else {
+            std::fs::copy(entry.path(), &target).map(|_| ())
+        }
+        .with_context(|| format!("Failed to copy {} to {}", entry.path().display(), target.display()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use ego_tree::NodeId;
+use scraper::{ElementRef, Html, Selector};
+use std::collections::HashMap;
+
+/// A parsed HTML document that remembers compiled selectors and indexes
+/// elements by tag name, so repeated queries on large pages (release
+/// archives with thousands of anchors) don't re-parse or re-walk.
+#[derive(Debug)]
+pub struct IndexedHtml {
+    html: Html,
+    selectors: HashMap<String, Selector>,
+    /// Tag name -> element ids in document order, built on the first plain-tag query.
+    tags: Option<HashMap<String, Vec<NodeId>>>,
+}
+
+impl IndexedHtml {
+    pub fn parse(content: &str) -> Self {
+        Self {
+            html: Html::parse_document(content),
+            selectors: HashMap::new(),
+            tags: None,
+        }
+    }
+
+    pub fn root_id(&self) -> NodeId {
+        self.html.tree.root().id()
+    }
+
+    pub fn element(&self, id: NodeId) -> Option<ElementRef<'_>> {
+        self.html.tree.get(id).and_then(ElementRef::wrap)
+    }
+
+    /// Returns up to `limit` elements under `scope` matching `selector`, in document order.
+    pub fn select(&mut self, scope: NodeId, selector: &str, limit: Option<usize>) -> anyhow::Result<Vec<NodeId>> {
+        let limit = limit.unwrap_or(usize::MAX);
+        if scope == self.root_id() && is_plain_tag(selector) {
+            let index = self.tags.get_or_insert_with(|| build_tag_index(&self.html));
+            let ids = index.get(&selector.to_ascii_lowercase()).map(|v| v.as_slice()).unwrap_or(&[]);
+            return Ok(ids.iter().take(limit).copied().collect());
+        }
+
+        if !self.selectors.contains_key(selector) {
+            let compiled = Selector::parse(selector)
+                .map_err(|e| anyhow::anyhow!("CSS selector parse error: {:?}", e))?;
+            self.selectors.insert(selector.to_string(), compiled);
+        }
+        let compiled = &self.selectors[selector];
+
+        if scope == self.root_id() {
+            return Ok(self.html.select(compiled).take(limit).map(|el| el.id()).collect());
+        }
+        let element = self.element(scope).ok_or_else(|| anyhow::anyhow!("node not found"))?;
+        Ok(element.select(compiled).take(limit).map(|el| el.id()).collect())
+    }
+}
+
+fn is_plain_tag(selector: &str) -> bool {
+    !selector.is_empty() && selector.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+fn build_tag_index(html: &Html) -> HashMap<String, Vec<NodeId>> {
+    let mut index: HashMap<String, Vec<NodeId>> = HashMap::new();
+    for node in html.tree.root().descendants() {
+        if let Some(el) = ElementRef::wrap(node) {
+            index.entry(el.value().name().to_string()).or_default().push(node.id());
+        }
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_indexed_select_matches_scraper() {
+        let content = "<html><body><div><a href='1'>a</a><a href='2'>b</a></div><p><a href='3'>c</a></p></body></html>";
+        let mut doc = IndexedHtml::parse(content);
+        let root = doc.root_id();
+
+        let all = doc.select(root, "a", None).unwrap();
+        let expected: Vec<NodeId> = doc.html.select(&Selector::parse("a").unwrap()).map(|e| e.id()).collect();
+        assert_eq!(all, expected);
+        assert_eq!(doc.select(root, "A", Some(2)).unwrap(), expected[..2].to_vec());
+
+        let div = doc.select(root, "div", None).unwrap()[0];
+        assert_eq!(doc.select(div, "a[href]", None).unwrap().len(), 2);
+        assert_eq!(doc.select(root, "p > a", Some(1)).unwrap(), vec![expected[2]]);
+        assert!(doc.select(root, "[[", None).is_err());
+    }
+}
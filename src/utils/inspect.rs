@@ -3,7 +3,6 @@ use std::sync::OnceLock;
 use crate::models::version_entry::{ReleaseType, StructuredVersion};
 
 static VERSION_REGEX: OnceLock<Regex> = OnceLock::new();
-static COMPONENT_REGEX: OnceLock<Regex> = OnceLock::new();
 
 pub struct InspectedVersion {
     pub version: StructuredVersion,
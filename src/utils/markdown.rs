@@ -0,0 +1,82 @@
+use regex::Regex;
+use std::sync::OnceLock;
+
+static INLINE_REGEX: OnceLock<Regex> = OnceLock::new();
+
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const CYAN: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+/// Renders markdown for a terminal: headings and `**strong**` text are bold,
+/// code is highlighted, list bullets become `•` and links show their target.
+/// Without `color` only the markup is simplified.
+pub fn render(markdown: &str, color: bool) -> String {
+    let style = |code: &'static str, text: &str| {
+        if color { format!("{}{}{}", code, text, RESET) } else { text.to_string() }
+    };
+    let mut out = String::new();
+    let mut in_code = false;
+    for line in markdown.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code = !in_code;
+            continue;
+        }
+        let rendered = if in_code {
+            format!("    {}", style(DIM, line))
+        } else if let Some(heading) = heading(trimmed) {
+            style(BOLD, &inline(heading, color))
+        } else if let Some(item) = ["- ", "* ", "+ "].iter().find_map(|b| trimmed.strip_prefix(b)) {
+            let indent = &line[..line.len() - trimmed.len()];
+            format!("{}  • {}", indent, inline(item, color))
+        } else {
+            inline(line, color)
+        };
+        out.push_str(rendered.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// The text of an ATX heading (`## 1.2.0`).
+fn heading(line: &str) -> Option<&str> {
+    let text = line.trim_start_matches('#');
+    let level = line.len() - text.len();
+    ((1..=6).contains(&level) && (text.is_empty() || text.starts_with(' '))).then(|| text.trim().trim_end_matches('#').trim())
+}
+
+fn inline(text: &str, color: bool) -> String {
+    let re = INLINE_REGEX.get_or_init(|| {
+        Regex::new(r"`([^`]+)`|\*\*([^*]+)\*\*|__([^_]+)__|\[([^\]]+)\]\(([^)\s]+)\)").unwrap()
+    });
+    re.replace_all(text, |caps: &regex::Captures| {
+        let (open, body) = if let Some(code) = caps.get(1) {
+            (CYAN, code.as_str().to_string())
+        } else if let Some(strong) = caps.get(2).or(caps.get(3)) {
+            (BOLD, strong.as_str().to_string())
+        } else if caps[4] == caps[5] {
+            return caps[5].to_string();
+        } else {
+            return format!("{} ({})", &caps[4], &caps[5]);
+        };
+        if color { format!("{}{}{}", open, body, RESET) } else { body }
+    })
+    .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let md = "## Bug fixes ##\n\n- Fix `go vet` crash, see [#123](https://x/123)\n  * **Security**: CVE-1\n```\nlet x;\n```\n#notaheading";
+        assert_eq!(
+            render(md, false),
+            "Bug fixes\n\n  • Fix go vet crash, see #123 (https://x/123)\n    • Security: CVE-1\n    let x;\n#notaheading\n"
+        );
+        assert_eq!(render("# v2", true), "\x1b[1mv2\x1b[0m\n");
+        assert_eq!(render("[https://x](https://x)", false), "https://x\n");
+    }
+}
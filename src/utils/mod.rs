@@ -2,3 +2,13 @@ pub mod crypto;
 pub mod fs;
 pub mod version;
 pub mod inspect;
+pub mod size;
+pub mod subst;
+pub mod duration;
+pub mod html_index;
+pub mod markdown;
+pub mod permissions;
+pub mod semver;
+pub mod table;
+pub mod theme;
+pub mod timings;
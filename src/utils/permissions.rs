@@ -0,0 +1,111 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// What `Extract` does to the permissions of unpacked files (`permissions` setting).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PermissionPolicy {
+    /// No group or world write bits; executables (ELF or `#!`) that lost
+    /// their executable bits get them back; directories are traversable.
+    #[default]
+    Normalize,
+    /// Modes as the archive has them.
+    Keep,
+}
+
+impl std::str::FromStr for PermissionPolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "normalize" => Ok(Self::Normalize),
+            "keep" => Ok(Self::Keep),
+            _ => anyhow::bail!("permissions must be normalize or keep"),
+        }
+    }
+}
+
+/// Files `normalize` changed.
+#[derive(Debug, Default, PartialEq)]
+pub struct Normalized {
+    pub made_executable: usize,
+    pub write_removed: usize,
+}
+
+/// Applies `PermissionPolicy::Normalize` to everything under `dir`. Symlinks
+/// are left alone.
+pub fn normalize(dir: &Path) -> Result<Normalized> {
+    let mut normalized = Normalized::default();
+    for entry in WalkDir::new(dir) {
+        let entry = entry.with_context(|| format!("Failed to walk {}", dir.display()))?;
+        let meta = entry.metadata().with_context(|| format!("Failed to stat {}", entry.path().display()))?;
+        let mode = meta.permissions().mode() & 0o7777;
+        let mut wanted = mode & !0o022;
+        if meta.is_dir() {
+            wanted |= 0o755;
+        } else if meta.is_file() {
+            if mode & 0o111 == 0 && is_executable_format(entry.path()) {
+                wanted |= 0o111;
+                normalized.made_executable += 1;
+            }
+            if mode & 0o022 != 0 {
+                normalized.write_removed += 1;
+            }
+        } else {
+            continue;
+        }
+        if wanted != mode {
+            fs::set_permissions(entry.path(), fs::Permissions::from_mode(wanted))
+                .with_context(|| format!("Failed to chmod {}", entry.path().display()))?;
+        }
+    }
+    Ok(normalized)
+}
+
+/// An ELF binary or a script with a `#!` line.
+fn is_executable_format(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    let Ok(mut file) = File::open(path) else { return false };
+    match file.read(&mut magic) {
+        Ok(n) if n >= 2 => magic.starts_with(b"#!") || (n == 4 && &magic == b"\x7fELF"),
+        _ => false,
+    }
+}
+
+/// An octal mode like `755`, as `export_link(chmod=)` takes it.
+pub fn parse_mode(mode: &str) -> Result<u32> {
+    u32::from_str_radix(mode, 8).ok()
+        .filter(|m| !mode.is_empty() && *m <= 0o7777)
+        .with_context(|| format!("invalid mode '{}', expected octal like 755", mode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mode = |name: &str| fs::metadata(tmp.path().join(name)).unwrap().permissions().mode() & 0o7777;
+        let write = |name: &str, content: &[u8], mode: u32| {
+            fs::write(tmp.path().join(name), content).unwrap();
+            fs::set_permissions(tmp.path().join(name), fs::Permissions::from_mode(mode)).unwrap();
+        };
+        fs::create_dir(tmp.path().join("lib")).unwrap();
+        fs::set_permissions(tmp.path().join("lib"), fs::Permissions::from_mode(0o700)).unwrap();
+        write("tool", b"\x7fELF\x02", 0o644);
+        write("run.sh", b"#!/bin/sh\n", 0o666);
+        write("README", b"hello", 0o666);
+        write("data", b"#", 0o600);
+
+        let normalized = normalize(tmp.path()).unwrap();
+        assert_eq!(normalized, Normalized { made_executable: 2, write_removed: 2 });
+        assert_eq!((mode("tool"), mode("run.sh"), mode("README"), mode("data"), mode("lib")), (0o755, 0o755, 0o644, 0o600, 0o755));
+
+        assert_eq!(parse_mode("755").unwrap(), 0o755);
+        assert!(parse_mode("9").is_err() && parse_mode("").is_err() && parse_mode("17777").is_err());
+        assert_eq!("keep".parse::<PermissionPolicy>().unwrap(), PermissionPolicy::Keep);
+    }
+}
@@ -0,0 +1,59 @@
+use anyhow::Result;
+use semver::{Version, VersionReq};
+use std::cmp::Ordering;
+
+/// Parses a version the way upstreams tag them: an optional leading `v`,
+/// and missing minor or patch components read as 0 (`v1.22` is 1.22.0).
+/// Pre-release and build suffixes follow semver (`1.2.3-rc.1+linux`).
+pub fn parse(s: &str) -> Result<Version> {
+    let trimmed = s.trim();
+    let trimmed = trimmed.strip_prefix(['v', 'V']).unwrap_or(trimmed);
+    let core_end = trimmed.find(['-', '+']).unwrap_or(trimmed.len());
+    let (core, suffix) = trimmed.split_at(core_end);
+    let padded = match core.split('.').count() {
+        1 => format!("{}.0.0{}", core, suffix),
+        2 => format!("{}.0{}", core, suffix),
+        _ => trimmed.to_string(),
+    };
+    Version::parse(&padded).map_err(|e| anyhow::anyhow!("invalid version '{}': {}", s, e))
+}
+
+/// Orders `a` and `b` by semver precedence; build metadata breaks ties so the
+/// order is total.
+pub fn compare(a: &str, b: &str) -> Result<Ordering> {
+    Ok(parse(a)?.cmp(&parse(b)?))
+}
+
+/// Whether `version` meets `constraint`, a comma-separated list of
+/// comparators (`>=1.20, <2`, `^1.2`, `~1.2.3`, `1.*`). Pre-releases only
+/// match comparators naming the same major.minor.patch with a pre-release.
+pub fn satisfies(version: &str, constraint: &str) -> Result<bool> {
+    let req = VersionReq::parse(constraint).map_err(|e| anyhow::anyhow!("invalid constraint '{}': {}", constraint, e))?;
+    Ok(req.matches(&parse(version)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_compare_satisfies() {
+        assert_eq!(parse("v1.22").unwrap(), Version::new(1, 22, 0));
+        assert_eq!(parse("2").unwrap(), Version::new(2, 0, 0));
+        assert_eq!(parse("1.2-rc.1").unwrap().pre.as_str(), "rc.1");
+        assert_eq!(parse("1.2.3+linux").unwrap().build.as_str(), "linux");
+        assert!(parse("latest").is_err());
+        assert!(parse("1.2.3.4").is_err());
+
+        assert_eq!(compare("1.10.0", "1.9.3").unwrap(), Ordering::Greater);
+        assert_eq!(compare("1.2.0-rc.1", "1.2.0").unwrap(), Ordering::Less);
+        assert_eq!(compare("v1.2", "1.2.0").unwrap(), Ordering::Equal);
+
+        assert!(satisfies("1.21.5", ">=1.20, <2").unwrap());
+        assert!(!satisfies("2.0.0", "^1.2").unwrap());
+        assert!(satisfies("1.2.9", "~1.2").unwrap());
+        assert!(!satisfies("1.3.0-beta", ">=1.2").unwrap());
+        assert!(satisfies("v1.4", "1.*").unwrap());
+        assert!(satisfies("1.0", "=>1").is_err());
+    }
+}
@@ -0,0 +1,50 @@
+const KB: u64 = 1024;
+const MB: u64 = KB * 1024;
+const GB: u64 = MB * 1024;
+const TB: u64 = GB * 1024;
+
+/// Formats a byte count for humans, e.g. "1.50 GB".
+pub fn format_size(size: u64) -> String {
+    if size >= GB {
+        format!("{:.2} GB", size as f64 / GB as f64)
+    } else if size >= MB {
+        format!("{:.2} MB", size as f64 / MB as f64)
+    } else if size >= KB {
+        format!("{:.2} KB", size as f64 / KB as f64)
+    } else {
+        format!("{} B", size)
+    }
+}
+
+/// Parses a human size such as "500M", "10G", "1.5GB" or "4096" into bytes.
+pub fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num.parse().map_err(|_| anyhow::anyhow!("Invalid size: '{}'", s))?;
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => KB,
+        "M" | "MB" | "MIB" => MB,
+        "G" | "GB" | "GIB" => GB,
+        "T" | "TB" | "TIB" => TB,
+        other => anyhow::bail!("Invalid size unit '{}' in '{}'", other, s),
+    };
+    Ok((num * multiplier as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("10K").unwrap(), 10 * KB);
+        assert_eq!(parse_size("500MB").unwrap(), 500 * MB);
+        assert_eq!(parse_size("1.5G").unwrap(), GB + GB / 2);
+        assert_eq!(parse_size(" 2 gib ").unwrap(), 2 * GB);
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("10X").is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use anyhow::Context;
+
+/// Tokens a `PathVars` knows, by name.
+const TOKENS: &[&str] = &["PILOCAL", "HOME", "WORKSPACE", "PKG_DIR"];
+
+/// Paths substituted into recipe and cave strings. Tokens without a value
+/// here are left as they are.
+///
+/// Env values (`value`) take `${PILOCAL}`, `${HOME}`, `${WORKSPACE}` and
+/// `${PKG_DIR}` (the packages directory), and the older shorthands `$` /
+/// `$/` (pilocal), `@HOME` and `@PACKAGES_DIR`. `$$` is a literal `$`;
+/// `$NAME` is left for the shell.
+///
+/// Run commands and link sources (`command`, through
+/// `Config::resolve_packages_dir`) only get the `${...}` tokens and
+/// `@PACKAGES_DIR`: any other `$` belongs to the shell. There `$${PILOCAL}`
+/// keeps the token.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PathVars<'a> {
+    pub pilocal: Option<&'a Path>,
+    pub home: Option<&'a Path>,
+    pub workspace: Option<&'a Path>,
+    pub packages: Option<&'a Path>,
+}
+
+impl<'a> PathVars<'a> {
+    fn get(&self, token: &str) -> Option<String> {
+        let path = match token {
+            "PILOCAL" => self.pilocal,
+            "HOME" => self.home,
+            "WORKSPACE" => self.workspace,
+            "PKG_DIR" => self.packages,
+            _ => None,
+        };
+        path.map(|p| p.display().to_string())
+    }
+
+    /// Substitutes an env value.
+    pub fn value(&self, s: &str) -> String {
+        self.substitute(s, true)
+    }
+
+    /// Substitutes a Run command or link source.
+    pub fn command(&self, s: &str) -> String {
+        self.substitute(s, false)
+    }
+
+    /// One pass over `s`, so substituted paths are never scanned again and
+    /// no token can clobber another.
+    fn substitute(&self, s: &str, shorthands: bool) -> String {
+        let mut out = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(c) = rest.chars().next() {
+            let (text, consumed) = self.token_at(rest, shorthands).unwrap_or_else(|| (c.to_string(), c.len_utf8()));
+            out.push_str(&text);
+            rest = &rest[consumed..];
+        }
+        out
+    }
+
+    /// The replacement of a token at the start of `s` and its length.
+    fn token_at(&self, s: &str, shorthands: bool) -> Option<(String, usize)> {
+        if let Some(after) = s.strip_prefix("$$") {
+            return match braced(after) {
+                Some((name, len)) if !shorthands => Some((format!("${{{}}}", name), 2 + len)),
+                _ if shorthands => Some(("$".to_string(), 2)),
+                _ => None,
+            };
+        }
+        if let Some((name, len)) = s.strip_prefix('$').and_then(braced) {
+            return self.get(name).map(|v| (v, 1 + len));
+        }
+        if s.starts_with("@PACKAGES_DIR") {
+            return self.get("PKG_DIR").map(|v| (v, "@PACKAGES_DIR".len()));
+        }
+        if !shorthands {
+            return None;
+        }
+        if s.starts_with("@HOME") {
+            return self.get("HOME").map(|v| (v, "@HOME".len()));
+        }
+        let after = s.strip_prefix('$')?;
+        if after.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '{') {
+            return None;
+        }
+        self.get("PILOCAL").map(|v| (v, 1))
+    }
+}
+
+/// Expands the package references of an exported env value: `{self}` is
+/// the exporting package's directory (`self_root`) and `{pkg:NAME}` that of
+/// package NAME of the same build (`roots`). Either kind is left alone
+/// while its paths are None, so values can be expanded in two passes; a
+/// `{pkg:NAME}` not in `roots` is an error. Directories under `packages_dir`
+/// become `${PKG_DIR}/...`, substituted like any env value. `${self}` is
+/// left for the shell.
+pub fn expand_package_refs(
+    value: &str,
+    packages_dir: &Path,
+    self_root: Option<&Path>,
+    roots: Option<&HashMap<String, PathBuf>>,
+) -> anyhow::Result<String> {
+    let path = |root: &Path| match root.strip_prefix(packages_dir) {
+        Ok(rel) if rel.as_os_str().is_empty() => "${PKG_DIR}".to_string(),
+        Ok(rel) => format!("${{PKG_DIR}}/{}", rel.display()),
+        Err(_) => root.display().to_string(),
+    };
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('{') {
+        let (before, from_brace) = rest.split_at(start);
+        out.push_str(before);
+        let reference = (!before.ends_with('$')).then(|| from_brace[1..].split_once('}')).flatten();
+        let replacement = match reference {
+            Some(("self", _)) => self_root.map(path),
+            Some((name, _)) if name.starts_with("pkg:") => match roots {
+                Some(roots) => {
+                    let pkgname = &name["pkg:".len()..];
+                    let root = roots.get(pkgname)
+                        .with_context(|| format!("{{pkg:{}}} names no package of this build", pkgname))?;
+                    Some(path(root))
+                }
+                None => None,
+            },
+            _ => None,
+        };
+        match (replacement, reference) {
+            (Some(text), Some((name, _))) => {
+                out.push_str(&text);
+                rest = &from_brace[name.len() + 2..];
+            }
+            _ => {
+                out.push('{');
+                rest = &from_brace[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// `{NAME}` of a known token at the start of `s`, and its length.
+fn braced(s: &str) -> Option<(&str, usize)> {
+    let name = s.strip_prefix('{')?.split_once('}')?.0;
+    TOKENS.contains(&name).then_some((name, name.len() + 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_substitute() {
+        let vars = PathVars {
+            pilocal: Some(Path::new("/pl")),
+            home: Some(Path::new("/h")),
+            workspace: Some(Path::new("/w")),
+            packages: Some(Path::new("/pk")),
+        };
+        assert_eq!(vars.value("$/bin:@HOME/x:$"), "/pl/bin:/h/x:/pl");
+        assert_eq!(vars.value("${PILOCAL}/lib:${WORKSPACE}:${HOME}:${PKG_DIR}/go"), "/pl/lib:/w:/h:/pk/go");
+        assert_eq!(vars.value("$PATH:$$/x:${OTHER}"), "$PATH:$/x:${OTHER}");
+        assert_eq!(vars.value("@PACKAGES_DIR/erlang"), "/pk/erlang");
+
+        // A pilocal containing `$` is not substituted again.
+        let odd = PathVars { pilocal: Some(Path::new("/a$b")), ..Default::default() };
+        assert_eq!(odd.value("$/x @HOME"), "/a$b/x @HOME");
+
+        let packages = PathVars { packages: Some(Path::new("/pk")), ..Default::default() };
+        assert_eq!(packages.command("cd ${PKG_DIR}/x && echo $$ $1 ${PILOCAL} $/"), "cd /pk/x && echo $$ $1 ${PILOCAL} $/");
+        assert_eq!(packages.command("echo $${PKG_DIR} @PACKAGES_DIR"), "echo ${PKG_DIR} /pk");
+    }
+
+    #[test]
+    fn test_expand_package_refs() {
+        let pk = Path::new("/pk");
+        let roots = HashMap::from([("go".to_string(), PathBuf::from("/pk/go-1.22-extracted")), ("jdk".to_string(), PathBuf::from("/opt/jdk"))]);
+        let value = "{self}/lib:{pkg:go}/bin:{pkg:jdk}:${self}:{other}";
+        let first = expand_package_refs(value, pk, Some(Path::new("/pk/tool-1.0-step3")), None).unwrap();
+        assert_eq!(first, "${PKG_DIR}/tool-1.0-step3/lib:{pkg:go}/bin:{pkg:jdk}:${self}:{other}");
+        let second = expand_package_refs(&first, pk, None, Some(&roots)).unwrap();
+        assert_eq!(second, "${PKG_DIR}/tool-1.0-step3/lib:${PKG_DIR}/go-1.22-extracted/bin:/opt/jdk:${self}:{other}");
+        assert_eq!(PathVars { packages: Some(pk), ..Default::default() }.value(&second), "/pk/tool-1.0-step3/lib:/pk/go-1.22-extracted/bin:/opt/jdk:${self}:{other}");
+
+        let err = expand_package_refs("{pkg:rust}/bin", pk, None, Some(&roots)).unwrap_err();
+        assert!(err.to_string().contains("{pkg:rust} names no package of this build"), "{}", err);
+    }
+}
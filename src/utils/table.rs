@@ -0,0 +1,160 @@
+use crate::utils::theme::Theme;
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+/// Row order for tables, e.g. "repo,package,-version": comma separated column
+/// names, `-` for descending. A name matches a header case-insensitively,
+/// either as a whole ("release_date") or as one of its words ("date").
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortSpec {
+    keys: Vec<(String, bool)>,
+}
+
+impl FromStr for SortSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let keys = s.split(',')
+            .map(|k| k.trim())
+            .filter(|k| !k.is_empty())
+            .map(|k| match k.strip_prefix('-') {
+                Some(name) => (name.to_ascii_lowercase(), true),
+                None => (k.trim_start_matches('+').to_ascii_lowercase(), false),
+            })
+            .collect::<Vec<_>>();
+        if keys.is_empty() || keys.iter().any(|(name, _)| name.is_empty()) {
+            anyhow::bail!("Invalid sort '{}', expected columns like 'repo,-version'", s);
+        }
+        Ok(Self { keys })
+    }
+}
+
+impl SortSpec {
+    /// Resolves the keys to (column index, descending), skipping unknown columns.
+    fn columns(&self, header: &[String]) -> Vec<(usize, bool)> {
+        self.keys.iter()
+            .filter_map(|(name, desc)| match find_column(header, name) {
+                Some(i) => Some((i, *desc)),
+                None => {
+                    log::warn!("[sort] no column '{}' (columns: {})", name, header.join(", "));
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+fn find_column(header: &[String], name: &str) -> Option<usize> {
+    let normalized: Vec<String> = header.iter().map(|h| h.to_ascii_lowercase()).collect();
+    normalized.iter().position(|h| h.replace(' ', "_") == name)
+        .or_else(|| normalized.iter().position(|h| h.split(' ').any(|w| w == name)))
+}
+
+/// Rows collected before printing, so every table prints in a stable order:
+/// the user's `--sort` first, then the table's canonical order.
+pub struct SortedTable {
+    header: Vec<String>,
+    rows: Vec<Vec<String>>,
+    canonical: SortSpec,
+}
+
+impl SortedTable {
+    /// `canonical` is a `SortSpec` over `header`, e.g. "repo,package,-version".
+    pub fn new(header: &[&str], canonical: &str) -> Self {
+        Self {
+            header: header.iter().map(|h| h.to_string()).collect(),
+            rows: Vec::new(),
+            canonical: canonical.parse().expect("valid canonical sort"),
+        }
+    }
+
+    pub fn add_row(&mut self, row: Vec<String>) {
+        self.rows.push(row);
+    }
+
+    pub fn sort(&mut self, user: Option<&SortSpec>) {
+        let mut columns = user.map(|s| s.columns(&self.header)).unwrap_or_default();
+        columns.extend(self.canonical.columns(&self.header));
+        // Whole-row tie break, so equal keys can't depend on insertion order.
+        columns.extend((0..self.header.len()).map(|i| (i, false)));
+        self.rows.sort_by(|a, b| {
+            columns.iter()
+                .map(|&(i, desc)| {
+                    let ord = natural_cmp(cell(a, i), cell(b, i));
+                    if desc { ord.reverse() } else { ord }
+                })
+                .find(|o| o.is_ne())
+                .unwrap_or(Ordering::Equal)
+        });
+    }
+
+    pub fn print(mut self, user: Option<&SortSpec>) {
+        self.sort(user);
+        let header: Vec<&str> = self.header.iter().map(|h| h.as_str()).collect();
+        let theme = Theme::current();
+        let mut table = theme.table(&header);
+        for row in self.rows {
+            table.add_row(row);
+        }
+        theme.print(&table);
+    }
+}
+
+fn cell(row: &[String], i: usize) -> &str {
+    row.get(i).map(|s| s.as_str()).unwrap_or("")
+}
+
+/// Compares runs of digits numerically and everything else as text, so
+/// "1.10.0" sorts after "1.9.2" and "go10" after "go9".
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        match (a.is_empty(), b.is_empty()) {
+            (true, true) => return Ordering::Equal,
+            (true, false) => return Ordering::Less,
+            (false, true) => return Ordering::Greater,
+            _ => {}
+        }
+        let a_digit = a.starts_with(|c: char| c.is_ascii_digit());
+        let b_digit = b.starts_with(|c: char| c.is_ascii_digit());
+        let split = |s: &str, digits: bool| s.find(|c: char| c.is_ascii_digit() != digits).unwrap_or(s.len());
+        let (a_run, a_rest) = a.split_at(split(a, a_digit));
+        let (b_run, b_rest) = b.split_at(split(b, b_digit));
+        let ord = if a_digit && b_digit {
+            let (a_num, b_num) = (a_run.trim_start_matches('0'), b_run.trim_start_matches('0'));
+            a_num.len().cmp(&b_num.len()).then_with(|| a_num.cmp(b_num))
+        } else {
+            a_run.cmp(b_run)
+        };
+        if ord.is_ne() {
+            return ord;
+        }
+        a = a_rest;
+        b = b_rest;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sorted_table_order() {
+        assert_eq!(natural_cmp("1.10.0", "1.9.2"), Ordering::Greater);
+        assert_eq!(natural_cmp("go9", "go10"), Ordering::Less);
+        assert_eq!(natural_cmp("2024-01-02", "2024-01-10"), Ordering::Less);
+
+        let row = |r: &str, p: &str, v: &str| vec![r.to_string(), p.to_string(), v.to_string()];
+        let mut table = SortedTable::new(&["Repo", "Package", "Version"], "repo,package,-version");
+        table.add_row(row("b", "go", "1.9.2"));
+        table.add_row(row("a", "node", "20.1.0"));
+        table.add_row(row("b", "go", "1.10.0"));
+        table.sort(None);
+        assert_eq!(table.rows, vec![row("a", "node", "20.1.0"), row("b", "go", "1.10.0"), row("b", "go", "1.9.2")]);
+
+        table.sort(Some(&"version".parse().unwrap()));
+        assert_eq!(table.rows[0], row("b", "go", "1.9.2"));
+        assert!("".parse::<SortSpec>().is_err());
+        assert!("repo,-".parse::<SortSpec>().is_err());
+    }
+}
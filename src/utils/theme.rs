@@ -0,0 +1,188 @@
+use comfy_table::presets::{NOTHING, UTF8_FULL};
+use comfy_table::{Cell, Color, ContentArrangement, Table};
+use std::io::{IsTerminal, Write};
+use std::sync::OnceLock;
+
+/// Terminals narrower than this get the compact layout.
+const COMPACT_WIDTH: u16 = 80;
+
+/// `--color`: whether tables and logs use colors.
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// Color when stdout is a terminal, unless NO_COLOR or CLICOLOR=0 say otherwise
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn write_style(self) -> env_logger::WriteStyle {
+        match self {
+            // env_logger honors NO_COLOR and CLICOLOR(_FORCE) itself.
+            Self::Auto => env_logger::WriteStyle::Auto,
+            Self::Always => env_logger::WriteStyle::Always,
+            Self::Never => env_logger::WriteStyle::Never,
+        }
+    }
+}
+
+/// How command output is styled, decided once in main.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Theme {
+    pub color: bool,
+    /// No borders, single-space gutters, cells wrapped to the terminal width.
+    pub compact: bool,
+    /// `--json`: tables print as one JSON object per row.
+    pub json: bool,
+}
+
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+impl Theme {
+    /// The theme for `--color`, `--compact` and `--json`; compact is also
+    /// picked for terminals narrower than 80 columns. JSON is never colored.
+    pub fn detect(choice: ColorChoice, compact: bool, json: bool) -> Self {
+        let tty = std::io::stdout().is_terminal();
+        let color = !json && use_color(choice, tty, |k| std::env::var(k).ok());
+        let narrow = tty && Table::new().width().is_some_and(|w| w < COMPACT_WIDTH);
+        Self { color, compact: compact || narrow, json }
+    }
+
+    pub fn init(self) {
+        let _ = THEME.set(self);
+    }
+
+    /// The theme set by main; plain output when none was set (tests).
+    pub fn current() -> Self {
+        THEME.get().copied().unwrap_or_default()
+    }
+
+    /// A borderless table with `header`, highlighted when colored.
+    pub fn table(&self, header: &[&str]) -> Table {
+        self.build(NOTHING, header)
+    }
+
+    /// A table with borders, for key/value views like `package info`;
+    /// borderless in compact mode.
+    pub fn framed_table(&self, header: &[&str]) -> Table {
+        self.build(if self.compact { NOTHING } else { UTF8_FULL }, header)
+    }
+
+    /// Prints `table`, or with `--json` each of its rows as a line
+    /// `{"event": "row", "<column>": "<cell>", ...}`.
+    pub fn print(&self, table: &Table) {
+        if !self.json {
+            println!("{table}");
+            return;
+        }
+        let mut out = std::io::stdout().lock();
+        for row in json_rows(table) {
+            let _ = writeln!(out, "{}", row);
+        }
+    }
+
+    /// Prints a line of plain output (a summary, a heading), or with `--json`
+    /// `{"event": "message", "text": "<line>"}` so stdout stays JSON.
+    pub fn line(&self, text: &str) {
+        if !self.json {
+            println!("{text}");
+            return;
+        }
+        let _ = writeln!(std::io::stdout().lock(), "{}", json_message(text));
+    }
+
+    fn build(&self, preset: &str, header: &[&str]) -> Table {
+        let mut table = Table::new();
+        table.load_preset(preset);
+        if self.color {
+            table.enforce_styling();
+            table.set_header(header.iter().map(|h| Cell::new(h).fg(Color::Yellow)));
+        } else {
+            table.set_header(header.to_vec());
+        }
+        if self.compact {
+            table.set_content_arrangement(ContentArrangement::Dynamic);
+            for column in table.column_iter_mut() {
+                column.set_padding((0, 1));
+            }
+        }
+        table
+    }
+}
+
+/// The rows of `table` as JSON objects keyed by the lowercased header, spaces
+/// replaced by `_` ("Release Date" -> "release_date").
+fn json_rows(table: &Table) -> Vec<serde_json::Value> {
+    let keys: Vec<String> = table.header()
+        .map(|h| h.cell_iter().map(|c| c.content().to_ascii_lowercase().replace(' ', "_")).collect())
+        .unwrap_or_default();
+    table.row_iter()
+        .map(|row| {
+            let mut object = serde_json::Map::new();
+            object.insert("event".to_string(), "row".into());
+            for (i, cell) in row.cell_iter().enumerate() {
+                let key = keys.get(i).cloned().unwrap_or_else(|| format!("column_{}", i + 1));
+                object.insert(key, cell.content().into());
+            }
+            serde_json::Value::Object(object)
+        })
+        .collect()
+}
+
+/// A plain output line as a JSON event, without the blank lines and
+/// indentation that space it out on a terminal.
+fn json_message(text: &str) -> serde_json::Value {
+    serde_json::json!({"event": "message", "text": text.trim()})
+}
+
+/// NO_COLOR (non-empty) disables automatic colors, CLICOLOR_FORCE (not "0")
+/// forces them, CLICOLOR=0 disables them; otherwise color a terminal.
+fn use_color(choice: ColorChoice, tty: bool, var: impl Fn(&str) -> Option<String>) -> bool {
+    let set = |k: &str| var(k).filter(|v| !v.is_empty());
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            if set("NO_COLOR").is_some() {
+                false
+            } else if set("CLICOLOR_FORCE").is_some_and(|v| v != "0") {
+                true
+            } else {
+                tty && set("CLICOLOR").is_none_or(|v| v != "0")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_color_detection() {
+        let env = |pairs: &'static [(&'static str, &'static str)]| {
+            move |k: &str| pairs.iter().find(|(n, _)| *n == k).map(|(_, v)| v.to_string())
+        };
+        assert!(use_color(ColorChoice::Auto, true, env(&[])));
+        assert!(!use_color(ColorChoice::Auto, false, env(&[])));
+        assert!(!use_color(ColorChoice::Auto, true, env(&[("NO_COLOR", "1")])));
+        assert!(use_color(ColorChoice::Auto, true, env(&[("NO_COLOR", "")])));
+        assert!(!use_color(ColorChoice::Auto, true, env(&[("CLICOLOR", "0")])));
+        assert!(use_color(ColorChoice::Auto, false, env(&[("CLICOLOR_FORCE", "1")])));
+        assert!(use_color(ColorChoice::Always, false, env(&[("NO_COLOR", "1")])));
+        assert!(!use_color(ColorChoice::Never, true, env(&[])));
+
+        let compact = Theme { color: false, compact: true, json: false };
+        let mut table = compact.framed_table(&["Package", "Release Date"]);
+        table.add_row(vec!["go", "2024-02-06"]);
+        assert_eq!(table.to_string().lines().next().unwrap().trim_end(), "Package Release Date");
+        assert_eq!(json_rows(&table), vec![serde_json::json!({"event": "row", "package": "go", "release_date": "2024-02-06"})]);
+    }
+
+    #[test]
+    fn test_json_message() {
+        assert_eq!(json_message("  3 files, 1.2 KiB\n"), serde_json::json!({"event": "message", "text": "3 files, 1.2 KiB"}));
+        assert_eq!(json_message("a \"quoted\" line").to_string(), r#"{"event":"message","text":"a \"quoted\" line"}"#);
+    }
+}
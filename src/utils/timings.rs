@@ -0,0 +1,105 @@
+use crate::utils::duration::format_duration;
+use crate::utils::theme::Theme;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// What a `Timings` scope measures.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    /// Reading repositories.json and the cached package and version lists.
+    RepoLoading,
+    /// Running Starlark: evaluating recipes and calling their functions.
+    Evaluation,
+    /// Downloads and git fetches, including those recipes make.
+    Network,
+    /// Unpacking archives of Extract steps.
+    Extraction,
+    /// Run and plugin steps, in the sandbox or on the host.
+    Execution,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 5] = [Phase::RepoLoading, Phase::Evaluation, Phase::Network, Phase::Extraction, Phase::Execution];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Phase::RepoLoading => "repo loading",
+            Phase::Evaluation => "recipe evaluation",
+            Phase::Network => "network",
+            Phase::Extraction => "extraction",
+            Phase::Execution => "sandbox execution",
+        }
+    }
+}
+
+/// Time spent per phase during this run, kept in `State` and printed by
+/// `--timings`. Scopes on parallel threads add up, and network time of a
+/// recipe also counts as its evaluation, so totals can exceed the wall time.
+#[derive(Debug, Default)]
+pub struct Timings {
+    nanos: [AtomicU64; 5],
+    counts: [AtomicU64; 5],
+}
+
+impl Timings {
+    /// Measures `phase` until the returned guard is dropped.
+    pub fn scope(&self, phase: Phase) -> TimingScope<'_> {
+        TimingScope { timings: self, phase, start: Instant::now() }
+    }
+
+    pub fn add(&self, phase: Phase, elapsed: Duration) {
+        let i = phase as usize;
+        self.nanos[i].fetch_add(elapsed.as_nanos().min(u64::MAX as u128) as u64, Ordering::Relaxed);
+        self.counts[i].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Total time and number of scopes of `phase`.
+    pub fn get(&self, phase: Phase) -> (Duration, u64) {
+        let i = phase as usize;
+        (Duration::from_nanos(self.nanos[i].load(Ordering::Relaxed)), self.counts[i].load(Ordering::Relaxed))
+    }
+
+    /// The breakdown `--timings` prints after the command, with each phase's
+    /// share of the command's `wall` time.
+    pub fn print(&self, wall: Duration) {
+        let mut table = Theme::current().table(&["Phase", "Time", "Count", "Share"]);
+        for phase in Phase::ALL {
+            let (total, count) = self.get(phase);
+            let share = 100.0 * total.as_secs_f64() / wall.as_secs_f64().max(f64::EPSILON);
+            table.add_row(vec![phase.label().to_string(), format_duration(total.as_millis()), count.to_string(), format!("{:.0}%", share)]);
+        }
+        table.add_row(vec!["total (wall)".to_string(), format_duration(wall.as_millis()), String::new(), String::new()]);
+        Theme::current().print(&table);
+    }
+}
+
+pub struct TimingScope<'a> {
+    timings: &'a Timings,
+    phase: Phase,
+    start: Instant,
+}
+
+impl Drop for TimingScope<'_> {
+    fn drop(&mut self) {
+        self.timings.add(self.phase, self.start.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scopes_add_up() {
+        let timings = Timings::default();
+        timings.add(Phase::Network, Duration::from_millis(30));
+        {
+            let _scope = timings.scope(Phase::Network);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let (total, count) = timings.get(Phase::Network);
+        assert_eq!(count, 2);
+        assert!(total >= Duration::from_millis(35), "{:?}", total);
+        assert_eq!(timings.get(Phase::Extraction), (Duration::ZERO, 0));
+    }
+}
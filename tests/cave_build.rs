@@ -0,0 +1,219 @@
+mod common;
+
+use common::Harness;
+use std::fs;
+
+/// Sets up a `demo` cave with the fixture packages and builds it.
+fn build_demo(h: &Harness) -> std::path::PathBuf {
+    let repo = h.fixture_repo();
+    h.pi_ok(&repo, &["repo", "add", repo.to_str().unwrap()]);
+    h.pi_ok(&repo, &["repo", "sync"]);
+    let ws = h.workspace("demo");
+    h.pi_ok(&ws, &["cave", "init"]);
+    h.pi_ok(&ws, &["cave", "add", "hello", "greet"]);
+    h.pi_ok(&ws, &["cave", "build"]);
+    ws
+}
+
+#[test]
+fn cave_build_links_fixture_packages() {
+    let h = Harness::new();
+    let ws = build_demo(&h);
+
+    let bin = h.cache_dir().join("pilocals/demo/bin");
+    for name in ["hello", "greet"] {
+        let link = bin.join(name);
+        let target = fs::read_link(&link).unwrap_or_else(|e| panic!("{}: {}", link.display(), e));
+        assert!(target.starts_with(h.cache_dir().join("packages")), "{} -> {}", name, target.display());
+        assert!(link.is_file(), "{} is dangling", link.display());
+    }
+    assert!(h.cache_dir().join("downloads/hello/hello-1.0.tar.gz").is_file());
+
+    assert_eq!(run_in(&h, &ws, &["hello"]), "hello from demo");
+    assert_eq!(run_in(&h, &ws, &["greet"]), "greetings from demo");
+    // The exported env names the pilocal as the cave sees it, translated to the host without bwrap.
+    assert_eq!(run_in(&h, &ws, &["sh", "-c", "echo $GREET_BIN"]), bin.join("greet").to_str().unwrap());
+    // Package references resolve to the package's directory in the cache.
+    let root = run_in(&h, &ws, &["sh", "-c", "echo $HELLO_ROOT"]);
+    assert!(root.starts_with(h.cache_dir().join("packages").to_str().unwrap()), "{}", root);
+    assert_eq!(run_in(&h, &ws, &["sh", "-c", "$HELLO_BIN"]), "hello from demo");
+    // The patch step ran on the extracted tree.
+    assert_eq!(run_in(&h, &ws, &["sh", "-c", "cat $HELLO_ROOT/hello-1.0/NOTICE"]), "patched by pi");
+}
+
+/// The last output line of `command` run in the cave, after the cave summary.
+fn run_in(h: &Harness, ws: &std::path::Path, command: &[&str]) -> String {
+    let args: Vec<&str> = ["cave", "run", "--"].iter().chain(command).copied().collect();
+    h.pi_ok(ws, &args).lines().last().unwrap_or_default().to_string()
+}
+
+#[test]
+fn cave_rebuild_reuses_downloads() {
+    let h = Harness::new();
+    let ws = build_demo(&h);
+    let requests = h.requests();
+    assert!(requests > 0);
+
+    let output = h.pi_ok(&ws, &["cave", "build", "--force"]);
+    assert!(output.contains("2 built"), "{}", output);
+    assert_eq!(h.requests(), requests, "a rebuild downloaded again:\n{}", output);
+}
+
+#[test]
+fn cave_build_offline_uses_only_the_cache() {
+    let h = Harness::new();
+    let ws = build_demo(&h);
+    let requests = h.requests();
+    h.pi_ok(&ws, &["--offline", "cave", "build", "--force"]);
+    assert_eq!(h.requests(), requests, "an offline build used the network");
+
+    let fresh = Harness::new();
+    let repo = fresh.fixture_repo();
+    fresh.pi_ok(&repo, &["repo", "add", repo.to_str().unwrap()]);
+    let ws = fresh.workspace("demo");
+    fresh.pi_ok(&ws, &["cave", "init"]);
+    fresh.pi_ok(&ws, &["cave", "add", "hello"]);
+    let output = fresh.pi(&ws, &["--offline", "cave", "build"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("not in the download cache"), "{}", stderr);
+    assert_eq!(fresh.requests(), 0);
+}
+
+#[test]
+fn cave_status_reports_drift() {
+    let h = Harness::new();
+    let ws = build_demo(&h);
+    let output = h.pi_ok(&ws, &["cave", "status"]);
+    assert!(output.contains("demo is up to date"), "{}", output);
+
+    h.pi_ok(&ws, &["cave", "add", "hello=0.9"]);
+    let output = h.pi_ok(&ws, &["cave", "status"]);
+    let state = |package: &str| {
+        let row = output.lines().find(|l| l.split_whitespace().next() == Some(package)).unwrap_or_else(|| panic!("{}", output));
+        row.split_whitespace().last().unwrap().to_string()
+    };
+    assert_eq!(state("hello=0.9"), "unresolved", "{}", output);
+    assert_eq!(state("greet"), "needs-build", "{}", output);
+}
+
+#[test]
+fn cave_import_fetches_exported_cave() {
+    let h = Harness::new();
+    let ws = build_demo(&h);
+    let bundle = h.workspace("bundle").join("demo.json");
+    fs::write(&bundle, h.pi_ok(&ws, &["cave", "export"])).unwrap();
+
+    let fresh = Harness::new();
+    let repo = fresh.fixture_repo();
+    fresh.pi_ok(&repo, &["repo", "add", repo.to_str().unwrap()]);
+    let ws = fresh.workspace("copy");
+    fresh.pi_ok(&ws, &["cave", "import", bundle.to_str().unwrap(), "--fetch"]);
+    assert!(ws.join("pi.cave.lock").is_file());
+    let cave = fs::read_to_string(ws.join("pi.cave.json")).unwrap();
+    assert!(cave.contains("hello=1.0"), "{}", cave);
+    assert_eq!(fresh.requests(), 1, "the hello tarball is fetched once");
+
+    // Everything the build needs is now cached.
+    fresh.pi_ok(&ws, &["--offline", "cave", "build"]);
+    assert_eq!(fresh.requests(), 1);
+}
+
+#[test]
+fn recipe_edits_invalidate_cached_steps() {
+    let h = Harness::new();
+    let ws = build_demo(&h);
+    let steps_run = |h: &Harness| {
+        // Touching pi.cave.json gets past the cached environment to the step cache.
+        let cave_file = ws.join("pi.cave.json");
+        fs::write(&cave_file, fs::read(&cave_file).unwrap()).unwrap();
+        let output = h.pi_ok(&ws, &["--progress-json", "cave", "build"]);
+        let line = output.lines().find(|l| l.contains("\"build_summary\"")).unwrap_or_else(|| panic!("{}", output));
+        serde_json::from_str::<serde_json::Value>(line).unwrap()["steps_run"].as_u64().unwrap()
+    };
+    assert_eq!(steps_run(&h), 0);
+
+    // A comment changes no recipe.
+    let recipe = h.fixture_repo().join("tools.star");
+    let content = fs::read_to_string(&recipe).unwrap() + "# edited\n";
+    fs::write(&recipe, &content).unwrap();
+    assert_eq!(steps_run(&h), 0);
+
+    // A new export of greet rebuilds greet only, not hello from the same file.
+    let content = content.replace("    v.export_env(\"GREET_BIN\"", "    v.export_env(\"GREET_EDITED\", \"1\")\n    v.export_env(\"GREET_BIN\"");
+    fs::write(&recipe, content).unwrap();
+    assert_eq!(steps_run(&h), 1);
+    assert_eq!(steps_run(&h), 0);
+
+    h.pi_ok(&ws, &["disk", "clean", "--builds", "greet"]);
+    assert_eq!(steps_run(&h), 1, "only greet's single step reruns");
+}
+
+#[test]
+fn streamed_fetch_keeps_no_archive() {
+    let h = Harness::new();
+    let repo = h.fixture_repo();
+    h.pi_ok(&repo, &["repo", "add", repo.to_str().unwrap()]);
+    let ws = h.workspace("demo");
+    h.pi_ok(&ws, &["cave", "init"]);
+    h.pi_ok(&ws, &["cave", "add", "hello-streamed"]);
+    h.pi_ok(&ws, &["cave", "build"]);
+
+    assert_eq!(run_in(&h, &ws, &["hello-streamed"]), "hello from demo");
+    assert!(!h.cache_dir().join("downloads/hello-streamed").exists());
+    // The stream was hashed into the cave's sums like a download.
+    let sums = fs::read_to_string(ws.join("pi.cave.sums")).unwrap();
+    assert!(sums.contains("/hello-1.0.tar.gz"), "{}", sums);
+}
+
+#[test]
+fn global_install_links_outside_caves() {
+    let h = Harness::new();
+    let repo = h.fixture_repo();
+    h.pi_ok(&repo, &["repo", "add", repo.to_str().unwrap()]);
+    h.pi_ok(&repo, &["repo", "sync"]);
+
+    h.pi_ok(&repo, &["package", "install", "greet"]);
+    let link = h.global_dir().join("bin/greet");
+    let output = std::process::Command::new(&link).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "greetings from");
+    assert!(fs::read_to_string(h.global_dir().join("installed.json")).unwrap().contains("\"greet\""));
+
+    h.pi_ok(&repo, &["package", "uninstall", "greet"]);
+    assert!(!link.exists() && !link.is_symlink());
+    assert!(!h.pi(&repo, &["package", "uninstall", "greet"]).status.success());
+}
+
+#[test]
+fn package_info_prints_machine_readable_entries() {
+    let h = Harness::new();
+    let repo = h.fixture_repo();
+    h.pi_ok(&repo, &["repo", "add", repo.to_str().unwrap()]);
+    h.pi_ok(&repo, &["repo", "sync"]);
+
+    let json: serde_json::Value = serde_json::from_str(&h.pi_ok(&repo, &["package", "info", "hello", "--format", "json"])).unwrap();
+    assert!(json["package"].as_str().unwrap().ends_with("/hello=1.0"), "{}", json);
+    let entry = &json["entry"];
+    assert_eq!(entry["pkgname"], "hello");
+    assert_eq!(entry["pipeline"].as_array().unwrap().len(), 3, "{}", entry);
+    assert_eq!(entry["exports"].as_array().unwrap().len(), 3, "{}", entry);
+
+    let yaml = h.pi_ok(&repo, &["package", "info", "hello", "--format", "yaml"]);
+    assert!(yaml.contains("pkgname: hello") && yaml.contains("pipeline:"), "{}", yaml);
+}
+
+#[test]
+fn cave_build_exports_the_pi_binary() {
+    let h = Harness::new();
+    let ws = build_demo(&h);
+
+    let exported = h.cache_dir().join("pilocals/demo/bin/pi");
+    let exe = std::path::Path::new(env!("CARGO_BIN_EXE_pi"));
+    assert_eq!(fs::read(&exported).unwrap(), fs::read(exe).unwrap(), "{} isn't the running binary", exported.display());
+    let version = h.pi_ok(&ws, &["version"]);
+    let output = std::process::Command::new(&exported).arg("version").output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), version);
+    // Inside the cave it is the `pi` on PATH.
+    assert_eq!(run_in(&h, &ws, &["sh", "-c", "command -v pi"]), exported.to_str().unwrap());
+}
@@ -0,0 +1,131 @@
+//! Drives the real `pi` binary against a fixture recipe repo and artifacts
+//! served by a local HTTP server, in temporary XDG directories, with the
+//! sandbox disabled (`PI_SANDBOX=none`): no network and no bwrap needed.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+pub struct Harness {
+    root: tempfile::TempDir,
+    base_url: String,
+    server: Arc<tiny_http::Server>,
+    requests: Arc<AtomicUsize>,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        let root = tempfile::tempdir().unwrap();
+        for dir in ["home", "config", "cache", "state", "data"] {
+            fs::create_dir_all(root.path().join(dir)).unwrap();
+        }
+        let server = Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+        let base_url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        serve(server.clone(), artifacts(), requests.clone());
+        Self { root, base_url, server, requests }
+    }
+
+    pub fn cache_dir(&self) -> PathBuf {
+        self.root.path().join("cache/pi")
+    }
+
+    /// The prefix of `pi package install`.
+    pub fn global_dir(&self) -> PathBuf {
+        self.root.path().join("data/pi/global")
+    }
+
+    /// HTTP requests served so far.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+
+    /// A copy of `tests/fixtures/repo` with `@BASE_URL@` pointing at the server.
+    pub fn fixture_repo(&self) -> PathBuf {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/repo");
+        let dest = self.root.path().join("repo");
+        fs::create_dir_all(&dest).unwrap();
+        for entry in fs::read_dir(src).unwrap() {
+            let path = entry.unwrap().path();
+            let content = fs::read_to_string(&path).unwrap().replace("@BASE_URL@", &self.base_url);
+            fs::write(dest.join(path.file_name().unwrap()), content).unwrap();
+        }
+        dest
+    }
+
+    /// An empty directory to init a cave in; its name is the cave's.
+    pub fn workspace(&self, name: &str) -> PathBuf {
+        let dir = self.root.path().join("workspaces").join(name);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Runs pi in `cwd` with only the harness environment.
+    pub fn pi(&self, cwd: &Path, args: &[&str]) -> Output {
+        let root = self.root.path();
+        Command::new(env!("CARGO_BIN_EXE_pi"))
+            .args(args)
+            .current_dir(cwd)
+            .env_clear()
+            .env("PATH", std::env::var("PATH").unwrap_or_default())
+            .env("HOME", root.join("home"))
+            .env("XDG_CONFIG_HOME", root.join("config"))
+            .env("XDG_CACHE_HOME", root.join("cache"))
+            .env("XDG_STATE_HOME", root.join("state"))
+            .env("XDG_DATA_HOME", root.join("data"))
+            .env("PI_SANDBOX", "none")
+            .output()
+            .unwrap()
+    }
+
+    /// `pi`, failing the test unless it succeeds; returns its stdout.
+    pub fn pi_ok(&self, cwd: &Path, args: &[&str]) -> String {
+        let output = self.pi(cwd, args);
+        assert!(
+            output.status.success(),
+            "pi {} failed with {}\nstdout:\n{}\nstderr:\n{}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.server.unblock();
+    }
+}
+
+/// Files the server serves, by URL path.
+fn artifacts() -> HashMap<String, Vec<u8>> {
+    let mut tarball = tar::Builder::new(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()));
+    let script = b"#!/bin/sh\necho \"hello from $PI_CAVE\"\n";
+    let mut header = tar::Header::new_gnu();
+    header.set_size(script.len() as u64);
+    header.set_mode(0o755);
+    header.set_cksum();
+    tarball.append_data(&mut header, "hello-1.0/bin/hello", &script[..]).unwrap();
+    let mut gz = tarball.into_inner().unwrap();
+    gz.flush().unwrap();
+    HashMap::from([("/hello-1.0.tar.gz".to_string(), gz.finish().unwrap())])
+}
+
+fn serve(server: Arc<tiny_http::Server>, files: HashMap<String, Vec<u8>>, requests: Arc<AtomicUsize>) {
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            requests.fetch_add(1, Ordering::SeqCst);
+            let response = match files.get(request.url()) {
+                Some(body) => tiny_http::Response::from_data(body.clone()),
+                None => tiny_http::Response::from_data(Vec::new()).with_status_code(404),
+            };
+            let _ = request.respond(response);
+        }
+    });
+}